@@ -0,0 +1,313 @@
+//! Cost per frame of the video pipeline at several resolutions, broken down into capturing,
+//! converting to YUV and encoding, plus all of them together. Encoding 4K is also measured with
+//! several encoder threads, and so is the latency of a frame, from handing it to the encoder until
+//! the fragment of the video holding it is written, with and without the low latency mode. Frames
+//! are generated by the test source so neither a display nor a GPU is required and results are
+//! comparable across runs and machines of the same kind. Converting a synthetic 1080p BGRA frame to
+//! I420 and NV12 in Rust is measured on its own.
+//!
+//! Run via `cargo bench --bench pipeline`. Every run is compared to the previous one, to compare
+//! against a fixed state save it first via `cargo bench --bench pipeline -- --save-baseline master`
+//! and then pass `--baseline master` instead. The run fails if any benchmark got slower by more
+//! than `WEYLUS_BENCH_MAX_REGRESSION` percent, 15 by default.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
+
+use criterion::{BenchmarkId, Criterion, Throughput};
+
+use weylus::capturable::testsrc::TestCapturable;
+use weylus::capturable::{new_recorder, CaptureOptions, Recorder};
+use weylus::video::{
+    alloc_yuv, bgra_to_i420, bgra_to_nv12, ChromaFilter, ColorMatrix, ColorRange,
+    ConversionOptions, EncoderOptions, PixelProvider, VideoCodec, VideoEncoder,
+};
+
+const RESOLUTIONS: [(&str, usize, usize); 3] = [
+    ("720p", 1280, 720),
+    ("1080p", 1920, 1080),
+    ("4k", 3840, 2160),
+];
+
+fn recorder(width: usize, height: usize) -> Box<dyn Recorder> {
+    new_recorder(
+        &TestCapturable::new(width, height),
+        CaptureOptions::default(),
+    )
+    .unwrap()
+}
+
+fn options(threads: u32, low_latency: bool) -> EncoderOptions {
+    // libx264 is available everywhere, hardware encoders would make results depend on the GPU
+    EncoderOptions {
+        try_vaapi: false,
+        try_nvenc: false,
+        try_videotoolbox: false,
+        try_mediafoundation: false,
+        conversion_threads: 0,
+        color_matrix: ColorMatrix::Bt601,
+        color_range: ColorRange::Limited,
+        chroma_filter: ChromaFilter::Box,
+        yuv444: false,
+        grayscale: false,
+        codec: VideoCodec::H264,
+        max_bitrate: None,
+        constant_quality: None,
+        qp_range: None,
+        keyframe_interval: 12,
+        encoder_threads: threads,
+        low_latency,
+    }
+}
+
+fn encoder(width: usize, height: usize, threads: u32) -> Box<VideoEncoder> {
+    VideoEncoder::new(
+        width,
+        height,
+        width,
+        height,
+        |_| {},
+        options(threads, false),
+    )
+    .unwrap()
+}
+
+fn pipeline(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pipeline");
+    // encoding 4K frames takes long enough that the default of 100 samples takes minutes
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(1));
+    for (name, width, height) in RESOLUTIONS {
+        group.bench_function(BenchmarkId::new("capture", name), |b| {
+            let mut r = recorder(width, height);
+            b.iter(|| {
+                r.capture().unwrap();
+            });
+        });
+
+        group.bench_function(BenchmarkId::new("convert", name), |b| {
+            let mut r = recorder(width, height);
+            let mut encoder = encoder(width, height, 1);
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    let frame = r.capture().unwrap();
+                    let start = Instant::now();
+                    encoder.fill(frame).unwrap();
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            });
+        });
+
+        group.bench_function(BenchmarkId::new("encode", name), |b| {
+            let mut r = recorder(width, height);
+            let mut encoder = encoder(width, height, 1);
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    encoder.fill(r.capture().unwrap()).unwrap();
+                    let start = Instant::now();
+                    encoder.encode_filled(start);
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            });
+        });
+
+        group.bench_function(BenchmarkId::new("total", name), |b| {
+            let mut r = recorder(width, height);
+            let mut encoder = encoder(width, height, 1);
+            b.iter(|| encoder.encode(r.capture().unwrap()));
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("encoder_threads");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(1));
+    let (width, height) = (3840, 2160);
+    for threads in [1, 2, 4] {
+        group.bench_function(BenchmarkId::new("encode_4k", threads), |b| {
+            let mut r = recorder(width, height);
+            let mut encoder = encoder(width, height, threads);
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    encoder.fill(r.capture().unwrap()).unwrap();
+                    let start = Instant::now();
+                    encoder.encode_filled(start);
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            });
+        });
+    }
+    group.finish();
+
+    // Frames are encoded back to back, so the delay of frame threads shows up as the time it takes
+    // to encode the frames after, in use it is the time until these are captured.
+    let mut group = c.benchmark_group("latency");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(1));
+    for (name, threads, low_latency) in [
+        ("1_thread", 1, false),
+        ("4_threads", 4, false),
+        ("low_latency", 4, true),
+    ] {
+        group.bench_function(BenchmarkId::new("encode_4k", name), |b| {
+            let mut r = recorder(width, height);
+            let fragments = Rc::new(Cell::new(0usize));
+            let written = fragments.clone();
+            let mut encoder = VideoEncoder::new(
+                width,
+                height,
+                width,
+                height,
+                // every encoded frame is written as fragment of its own
+                move |data| {
+                    if data.get(4..8) == Some(&b"moof"[..]) {
+                        written.set(written.get() + 1);
+                    }
+                },
+                options(threads, low_latency),
+            )
+            .unwrap();
+            let mut pending = VecDeque::new();
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    encoder.fill(r.capture().unwrap()).unwrap();
+                    let start = Instant::now();
+                    pending.push_back(start);
+                    let before = fragments.get();
+                    encoder.encode_filled(start);
+                    for _ in before..fragments.get() {
+                        if let Some(start) = pending.pop_front() {
+                            elapsed += start.elapsed();
+                        }
+                    }
+                }
+                elapsed
+            });
+        });
+    }
+    group.finish();
+}
+
+// converting a synthetic 1080p BGRA frame in Rust, via SSE2 or AVX2 where the CPU supports them
+fn conversion(c: &mut Criterion) {
+    let (width, height) = (1920, 1080);
+    let data: Vec<u8> = (0..width * height * 4)
+        .map(|i| (i * 7 + i / 4093) as u8)
+        .collect();
+    let mut group = c.benchmark_group("conversion");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    for (name, chroma_filter) in [
+        ("box", ChromaFilter::Box),
+        ("weighted", ChromaFilter::Weighted),
+    ] {
+        let opts = ConversionOptions {
+            chroma_filter,
+            ..Default::default()
+        };
+        group.bench_function(BenchmarkId::new("i420_1080p", name), |b| {
+            let (mut y, mut u, mut v, line_sizes) = alloc_yuv(width, height);
+            b.iter(|| {
+                let frame = PixelProvider::BGR0(width, height, &data);
+                bgra_to_i420(frame, [&mut y, &mut u, &mut v], line_sizes, opts).unwrap();
+            });
+        });
+    }
+    group.bench_function("nv12_1080p", |b| {
+        let (mut y, _, _, [y_line_size, chroma_line_size, _]) = alloc_yuv(width, height);
+        // U and V are interleaved, so a row of UV holds twice as many samples as one of U
+        let line_sizes = [y_line_size, 2 * chroma_line_size];
+        let mut uv = vec![0; line_sizes[1] * (height + 1) / 2];
+        b.iter(|| {
+            let frame = PixelProvider::BGR0(width, height, &data);
+            bgra_to_nv12(frame, [&mut y, &mut uv], line_sizes, Default::default()).unwrap();
+        });
+    });
+    group.finish();
+}
+
+// criterion stores the results of each benchmark under the target directory
+fn criterion_dir() -> PathBuf {
+    std::env::var_os("CRITERION_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("CARGO_TARGET_DIR").map(|dir| PathBuf::from(dir).join("criterion"))
+        })
+        .unwrap_or_else(|| PathBuf::from("target/criterion"))
+}
+
+// collect the relative changes of the mean of all benchmarks measured since `since`, the change
+// is only known if there has been a previous run or a baseline to compare to
+fn collect_changes(dir: &Path, since: SystemTime, changes: &mut Vec<(String, f64)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let estimates = path.join("change").join("estimates.json");
+        let modified = fs::metadata(&estimates).and_then(|m| m.modified());
+        if matches!(modified, Ok(modified) if modified >= since) {
+            let change = fs::read_to_string(&estimates)
+                .ok()
+                .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok())
+                .and_then(|v| v["mean"]["confidence_interval"]["lower_bound"].as_f64());
+            if let Some(change) = change {
+                let name = path.strip_prefix(criterion_dir()).unwrap_or(&path);
+                changes.push((name.display().to_string(), change));
+            }
+        }
+        collect_changes(&path, since, changes);
+    }
+}
+
+fn check_regressions(since: SystemTime) {
+    let max_regression: f64 = std::env::var("WEYLUS_BENCH_MAX_REGRESSION")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(15.0);
+    let mut changes = Vec::new();
+    collect_changes(&criterion_dir(), since, &mut changes);
+    // the lower bound of the confidence interval is used so noise does not fail the run
+    let regressions: Vec<_> = changes
+        .into_iter()
+        .filter(|(_, change)| *change * 100.0 > max_regression)
+        .collect();
+    if !regressions.is_empty() {
+        for (name, change) in &regressions {
+            eprintln!(
+                "Regression: {} got slower by at least {:.1}%.",
+                name,
+                change * 100.0
+            );
+        }
+        eprintln!(
+            "{} benchmark(s) got slower by more than {}%!",
+            regressions.len(),
+            max_regression
+        );
+        std::process::exit(1);
+    }
+}
+
+fn main() {
+    let start = SystemTime::now();
+    let mut c = Criterion::default().configure_from_args();
+    pipeline(&mut c);
+    conversion(&mut c);
+    c.final_summary();
+    check_regressions(start);
+}
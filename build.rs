@@ -115,6 +115,20 @@ fn main() {
     println!("cargo:rustc-link-lib={}=avutil", ffmpeg_link_kind);
     println!("cargo:rustc-link-lib={}=postproc", ffmpeg_link_kind);
     println!("cargo:rustc-link-lib={}=x264", ffmpeg_link_kind);
+    // libvpx is optional as it is not built with msvc, VP9 is unavailable without it
+    if ["libvpx.a", "vpx.lib"]
+        .iter()
+        .any(|lib| dist_dir.join("lib").join(lib).exists())
+    {
+        println!("cargo:rustc-link-lib={}=vpx", ffmpeg_link_kind);
+    }
+    // same for libaom and AV1
+    if ["libaom.a", "aom.lib"]
+        .iter()
+        .any(|lib| dist_dir.join("lib").join(lib).exists())
+    {
+        println!("cargo:rustc-link-lib={}=aom", ffmpeg_link_kind);
+    }
     if env::var("CARGO_FEATURE_FFMPEG_SYSTEM").is_err() {
         println!(
             "cargo:rustc-link-search={}",
@@ -132,6 +146,7 @@ fn main() {
     }
 
     if target_os == "windows" {
+        windows();
         println!("cargo:rustc-link-lib=dylib=mfplat");
         println!("cargo:rustc-link-lib=dylib=mfuuid");
         println!("cargo:rustc-link-lib=dylib=ole32");
@@ -142,22 +157,40 @@ fn main() {
     }
 }
 
+fn windows() {
+    println!("cargo:rerun-if-changed=lib/windows/dxgicapture.c");
+
+    cc::Build::new()
+        .file("lib/windows/dxgicapture.c")
+        .compile("windows");
+
+    println!("cargo:rustc-link-lib=dylib=d3d11");
+    println!("cargo:rustc-link-lib=dylib=dxgi");
+    println!("cargo:rustc-link-lib=dylib=dxguid");
+    println!("cargo:rustc-link-lib=dylib=user32");
+}
+
 fn linux() {
     println!("cargo:rerun-if-changed=lib/linux/uniput.c");
+    println!("cargo:rerun-if-changed=lib/linux/clipboard.c");
     println!("cargo:rerun-if-changed=lib/linux/xcapture.c");
     println!("cargo:rerun-if-changed=lib/linux/xhelper.c");
     println!("cargo:rerun-if-changed=lib/linux/xhelper.h");
+    println!("cargo:rerun-if-changed=lib/linux/sched.c");
 
     cc::Build::new()
         .file("lib/linux/uinput.c")
+        .file("lib/linux/clipboard.c")
         .file("lib/linux/xcapture.c")
         .file("lib/linux/xhelper.c")
+        .file("lib/linux/sched.c")
         .compile("linux");
 
     println!("cargo:rustc-link-lib=X11");
     println!("cargo:rustc-link-lib=Xext");
     println!("cargo:rustc-link-lib=Xrandr");
     println!("cargo:rustc-link-lib=Xfixes");
+    println!("cargo:rustc-link-lib=Xdamage");
     println!("cargo:rustc-link-lib=Xcomposite");
     println!("cargo:rustc-link-lib=Xi");
     let va_link_kind = if env::var("CARGO_FEATURE_VA_STATIC").is_ok() {
@@ -56,6 +56,22 @@ if [ "$TARGET_OS" == "windows" ] && [ "$HOST_OS" == "linux" ]; then
     export X264_EXTRA_ARGS="--cross-prefix=x86_64-w64-mingw32- --host=x86_64-w64-mingw32"
 fi
 ./x264.sh
+if [ "$TARGET_OS" != "windows" ] || [ "$HOST_OS" != "windows" ]; then
+    if [ "$TARGET_OS" == "windows" ]; then
+        export CROSS="x86_64-w64-mingw32-"
+        export LIBVPX_EXTRA_ARGS="--target=x86_64-win64-gcc"
+    fi
+    ./libvpx.sh
+    export FFMPEG_EXTRA_ARGS="$FFMPEG_EXTRA_ARGS --enable-libvpx"
+    # AV1 requires cmake and is skipped if it is not available
+    if command -v cmake > /dev/null; then
+        if [ "$TARGET_OS" == "windows" ]; then
+            export LIBAOM_EXTRA_ARGS="-DCMAKE_TOOLCHAIN_FILE=../build/cmake/toolchains/x86_64-mingw-gcc.cmake"
+        fi
+        ./libaom.sh
+        export FFMPEG_EXTRA_ARGS="$FFMPEG_EXTRA_ARGS --enable-libaom"
+    fi
+fi
 if [ "$TARGET_OS" == "linux" ]; then
     ./nv-codec-headers.sh
     ./libva.sh
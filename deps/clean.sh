@@ -1,7 +1,7 @@
 #!/usr/bin/env bash
 
 set -x
-for d in ffmpeg x264 nv-codec-headers libva; do
+for d in ffmpeg x264 libvpx libaom nv-codec-headers libva; do
     test -d "$d" || continue
     (cd "$d" && git clean -dfx && git reset --hard HEAD)
 done
@@ -1,4 +1,4 @@
 #!/usr/bin/env bash
 
 set -ex
-rm -rf ffmpeg x264 nv-codec-headers libva dist*
+rm -rf ffmpeg x264 libvpx libaom nv-codec-headers libva dist*
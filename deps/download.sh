@@ -3,6 +3,11 @@
 set -ex
 
 test -d x264 || git clone --depth 1 -b stable https://code.videolan.org/videolan/x264.git x264
+# libvpx and libaom are not built with msvc
+if [ "$TARGET_OS" != "windows" ] || [ "$HOST_OS" != "windows" ]; then
+    test -d libvpx || git clone --depth 1 -b v1.12.0 https://chromium.googlesource.com/webm/libvpx libvpx
+    test -d libaom || git clone --depth 1 -b v3.5.0 https://aomedia.googlesource.com/aom libaom
+fi
 test -d ffmpeg || git clone --depth 1 -b n5.1 https://git.ffmpeg.org/ffmpeg.git ffmpeg
 if [ "$TARGET_OS" == "linux" ]; then
     test -d nv-codec-headers || git clone --depth 1 https://git.videolan.org/git/ffmpeg/nv-codec-headers.git
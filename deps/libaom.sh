@@ -0,0 +1,22 @@
+#!/usr/bin/env bash
+
+set -ex
+
+mkdir -p libaom/build
+cd libaom/build
+cmake .. \
+	-DCMAKE_INSTALL_PREFIX="$DIST" \
+	-DCMAKE_INSTALL_LIBDIR=lib \
+	-DCMAKE_BUILD_TYPE=Release \
+	-DCMAKE_POSITION_INDEPENDENT_CODE=1 \
+	-DBUILD_SHARED_LIBS=0 \
+	-DENABLE_DOCS=0 \
+	-DENABLE_EXAMPLES=0 \
+	-DENABLE_TESTS=0 \
+	-DENABLE_TOOLS=0 \
+	-DCONFIG_AV1_DECODER=0 \
+	-DCONFIG_REALTIME_ONLY=1 \
+	$LIBAOM_EXTRA_ARGS
+
+make -j$NPROCS
+make install
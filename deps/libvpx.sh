@@ -0,0 +1,21 @@
+#!/usr/bin/env bash
+
+set -ex
+
+cd libvpx
+./configure \
+	--prefix="$DIST" \
+	--enable-static \
+	--disable-shared \
+	--enable-pic \
+	--disable-examples \
+	--disable-tools \
+	--disable-docs \
+	--disable-unit-tests \
+	--disable-vp8 \
+	--disable-vp9-decoder \
+	--enable-realtime-only \
+	$LIBVPX_EXTRA_ARGS
+
+make -j$NPROCS
+make install
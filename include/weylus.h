@@ -0,0 +1,75 @@
+#ifndef WEYLUS_H
+#define WEYLUS_H
+
+/* Generated by cbindgen from src/capi.rs, do not edit by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+// Captures a screen or window and encodes it, created by `weylus_capture_session_new`.
+typedef struct WeylusCaptureSession WeylusCaptureSession;
+
+// Error filled in by C code. The message is stored inline, so there is nothing to free on either
+// side and errors can be created and dropped freely.
+typedef struct CError {
+  int code;
+  char error_str[1024];
+} CError;
+
+// Encoded video returned by `weylus_capture_session_next_packet`.
+typedef struct WeylusPacket {
+  // Fragmented MP4, the first packet and the first one after the size of the video changed
+  // start with the initialization segment.
+  const uint8_t *data;
+  size_t len;
+  unsigned int width;
+  unsigned int height;
+} WeylusPacket;
+
+// Initialize the libraries used for capturing, this has to be called once before creating
+// sessions and before the application spawns any other threads.
+void weylus_init(void);
+
+// Start capturing the screen or window matching `capturable`, which is its id or a part of its
+// name like with `--capturable`, NULL captures the first one found. `options` is JSON like
+// `{"capture_cursor": true, "crop": [0, 0, 1280, 720], "encoder": {"codec": "h264",
+// "max_bitrate": 4000}}`, where `encoder` takes the fields of `EncoderOptions`, NULL uses the
+// defaults. Returns NULL and fills `err` on failure.
+//
+// # Safety
+//
+// `capturable` and `options` have to be NULL or null-terminated strings, `err` NULL or point to
+// a `CError`.
+WeylusCaptureSession *weylus_capture_session_new(const char *capturable,
+                                                 const char *options,
+                                                 CError *err);
+
+// Capture a frame and encode it. Returns 1 and sets `packet` if encoded video is available, 0 if
+// there is none yet, for example because the frame is empty or the encoder delays frames, and -1
+// with `err` filled if capturing or encoding failed. If the capturable does not exist anymore the
+// error code is 102 and the session should be freed. The packet has to be freed via
+// `weylus_packet_free`.
+//
+// # Safety
+//
+// `session` has to be created by `weylus_capture_session_new` and not freed yet, `packet` has to
+// point to a `WeylusPacket*` and `err` has to be NULL or point to a `CError`.
+int weylus_capture_session_next_packet(WeylusCaptureSession *session,
+                                       WeylusPacket **packet,
+                                       CError *err);
+
+// Free a packet returned by `weylus_capture_session_next_packet`, NULL is ignored.
+//
+// # Safety
+//
+// `packet` has to be NULL or returned by `weylus_capture_session_next_packet` and not freed yet.
+void weylus_packet_free(WeylusPacket *packet);
+
+// Stop capturing and free the session, NULL is ignored.
+//
+// # Safety
+//
+// `session` has to be NULL or created by `weylus_capture_session_new` and not freed yet.
+void weylus_capture_session_free(WeylusCaptureSession *session);
+
+#endif /* WEYLUS_H */
@@ -27,12 +27,42 @@
 #include <va/va.h>
 #endif
 
+// keep in sync with ColorMatrix in src/video.rs
+enum ColorMatrix
+{
+	COLOR_MATRIX_BT601 = 0,
+	COLOR_MATRIX_BT709 = 1,
+};
+
+// keep in sync with ColorRange in src/video.rs
+enum ColorRange
+{
+	COLOR_RANGE_LIMITED = 0,
+	COLOR_RANGE_FULL = 1,
+};
+
+// keep in sync with ChromaFilter in src/video.rs
+enum ChromaFilter
+{
+	CHROMA_FILTER_BOX = 0,
+	CHROMA_FILTER_WEIGHTED = 1,
+};
+
+// keep in sync with VideoCodec in src/video.rs
+enum Codec
+{
+	CODEC_H264 = 0,
+	CODEC_VP9 = 1,
+	CODEC_AV1 = 2,
+};
+
 typedef struct VideoContext
 {
 	AVFormatContext* oc;
 	AVCodecContext* c;
 	AVFrame* frame;
 	AVFrame* frame_hw;
+	AVFrame* frame_in;
 	AVPacket* pkt;
 	AVStream* st;
 	AVBufferRef* hw_device_ctx;
@@ -48,6 +78,27 @@ typedef struct VideoContext
 	struct SwsContext* sws_rgb;
 	struct SwsContext* sws_rgb0;
 	struct SwsContext* sws_bgr0;
+	int conversion_threads;
+	int color_matrix;
+	int color_range;
+	int chroma_filter;
+	int yuv444;
+	int grayscale;
+	int codec;
+	int max_bitrate;
+	// quantizer every frame is encoded with instead of the default quality, -1 if not set
+	int constant_quality;
+	// bounds of the quantizer, -1 if not set
+	int qp_min;
+	int qp_max;
+	// crf libx264 has been opened with, set_quality scales from there
+	int crf;
+	int keyframe_interval;
+	int encoder_threads;
+	int low_latency;
+	int force_keyframe;
+	// the fragment currently written holds a keyframe, see fragment_is_keyframe
+	int fragment_keyframe;
 	int initialized;
 	int frame_allocated;
 	int frame_hw_allocated;
@@ -122,13 +173,41 @@ void set_codec_params(VideoContext* ctx)
 	ctx->c->time_base = (AVRational){1, 1000};
 	ctx->c->framerate = (AVRational){0, 1};
 
-	ctx->c->gop_size = 12;
+	// tell the decoder which coefficients have been used to convert from RGB so it can convert back
+	// accordingly
+	ctx->c->color_range =
+		ctx->color_range == COLOR_RANGE_FULL ? AVCOL_RANGE_JPEG : AVCOL_RANGE_MPEG;
+	if (ctx->color_matrix == COLOR_MATRIX_BT709)
+	{
+		ctx->c->colorspace = AVCOL_SPC_BT709;
+		ctx->c->color_primaries = AVCOL_PRI_BT709;
+		ctx->c->color_trc = AVCOL_TRC_BT709;
+	}
+	else
+	{
+		ctx->c->colorspace = AVCOL_SPC_SMPTE170M;
+		ctx->c->color_primaries = AVCOL_PRI_SMPTE170M;
+		ctx->c->color_trc = AVCOL_TRC_SMPTE170M;
+	}
+
+	ctx->c->gop_size = ctx->keyframe_interval;
 	// no B-frames to reduce latency
 	ctx->c->max_b_frames = 0;
+	if (ctx->qp_min >= 0)
+		ctx->c->qmin = ctx->qp_min;
+	if (ctx->qp_max >= 0)
+		ctx->c->qmax = ctx->qp_max;
 	if (ctx->oc->oformat->flags & AVFMT_GLOBALHEADER)
 		ctx->c->flags |= AV_CODEC_FLAG_GLOBAL_HEADER;
 }
 
+// The quantizer given via constant_quality or else the default of the encoder, crf for libx264,
+// libvpx and libaom, cq for NVENC and qp for VAAPI.
+int quantizer(VideoContext* ctx, int default_quantizer)
+{
+	return ctx->constant_quality >= 0 ? ctx->constant_quality : default_quantizer;
+}
+
 void set_hwframe_ctx(VideoContext* ctx, Error* err)
 {
 	AVBufferRef* hw_frames_ref;
@@ -165,6 +244,53 @@ void set_frame_params(VideoContext* ctx)
 	ctx->frame->height = ctx->c->height;
 }
 
+struct SwsContext* create_sws_context(VideoContext* ctx, enum AVPixelFormat src_fmt)
+{
+	struct SwsContext* sws = sws_alloc_context();
+	if (!sws)
+		return NULL;
+	av_opt_set_int(sws, "srcw", ctx->width_in, 0);
+	av_opt_set_int(sws, "srch", ctx->height_in, 0);
+	av_opt_set_int(sws, "src_format", src_fmt, 0);
+	av_opt_set_int(sws, "dstw", ctx->width_out, 0);
+	av_opt_set_int(sws, "dsth", ctx->height_out, 0);
+	av_opt_set_int(sws, "dst_format", ctx->sw_pix_fmt, 0);
+	// fast bilinear only considers neighbouring pixels, which causes aliasing if the frame is
+	// downscaled by a factor of two or more, averaging over the area of the output pixel avoids this
+	int flags = (ctx->width_in >= 2 * ctx->width_out || ctx->height_in >= 2 * ctx->height_out)
+					? SWS_AREA
+					: SWS_FAST_BILINEAR;
+	// by default the colors of horizontally neighbouring pixels are averaged before converting
+	// them, computing chroma for every pixel and filtering it with bicubic weights reduces
+	// aliasing of colored edges
+	if (ctx->chroma_filter == CHROMA_FILTER_WEIGHTED)
+		flags = (flags == SWS_AREA ? SWS_AREA : SWS_BICUBIC) | SWS_FULL_CHR_H_INP |
+				SWS_ACCURATE_RND;
+	av_opt_set_int(sws, "sws_flags", flags, 0);
+	// swscale splits the frame into horizontal slices and converts them in parallel, 0 means one
+	// thread per cpu core
+	av_opt_set_int(sws, "threads", ctx->conversion_threads, 0);
+	if (sws_init_context(sws, NULL, NULL) < 0)
+	{
+		sws_freeContext(sws);
+		return NULL;
+	}
+	// RGB input is full range, YUV output is limited range (Y in 16..235) unless full range is
+	// requested
+	const int* coefficients = sws_getCoefficients(
+		ctx->color_matrix == COLOR_MATRIX_BT709 ? SWS_CS_ITU709 : SWS_CS_ITU601);
+	sws_setColorspaceDetails(
+		sws,
+		coefficients,
+		1,
+		coefficients,
+		ctx->color_range == COLOR_RANGE_FULL,
+		0,
+		1 << 16,
+		1 << 16);
+	return sws;
+}
+
 void open_video(VideoContext* ctx, Error* err)
 {
 	if (ctx->width_out <= 1 || ctx->height_out <= 1)
@@ -186,6 +312,63 @@ void open_video(VideoContext* ctx, Error* err)
 
 	int using_hw = 0;
 
+	if (ctx->codec == CODEC_VP9)
+	{
+		codec = avcodec_find_encoder_by_name("libvpx-vp9");
+		if (!codec)
+			ERROR(err, 103, "Codec 'libvpx-vp9' not found");
+
+		ctx->c = avcodec_alloc_context3(codec);
+		if (!ctx->c)
+			ERROR(err, 1, "Could not allocate video codec context");
+		// profile 1 supports YUV 4:4:4
+		ctx->sw_pix_fmt = ctx->c->pix_fmt = ctx->yuv444 ? AV_PIX_FMT_YUV444P : AV_PIX_FMT_YUV420P;
+		av_opt_set(ctx->c->priv_data, "deadline", "realtime", 0);
+		av_opt_set(ctx->c->priv_data, "cpu-used", "8", 0);
+		av_opt_set(ctx->c->priv_data, "lag-in-frames", "0", 0);
+		av_opt_set(ctx->c->priv_data, "row-mt", "1", 0);
+		av_opt_set(ctx->c->priv_data, "tile-columns", "2", 0);
+		av_opt_set_int(ctx->c->priv_data, "crf", quantizer(ctx, 32), 0);
+		ctx->c->thread_count = 0;
+		set_codec_params(ctx);
+		// constant quality if there is no maximum bitrate, constrained quality otherwise
+		ctx->c->bit_rate = ctx->max_bitrate > 0 ? ctx->max_bitrate * 1000 : 0;
+
+		ret = avcodec_open2(ctx->c, codec, NULL);
+		if (ret < 0)
+			ERROR(err, 1, "Could not open codec: %s", av_err2str(ret));
+	}
+
+	if (ctx->codec == CODEC_AV1)
+	{
+		codec = avcodec_find_encoder_by_name("libaom-av1");
+		if (!codec)
+			ERROR(err, 103, "Codec 'libaom-av1' not found");
+
+		ctx->c = avcodec_alloc_context3(codec);
+		if (!ctx->c)
+			ERROR(err, 1, "Could not allocate video codec context");
+		// the high profile supports YUV 4:4:4
+		ctx->sw_pix_fmt = ctx->c->pix_fmt = ctx->yuv444 ? AV_PIX_FMT_YUV444P : AV_PIX_FMT_YUV420P;
+		// encoding AV1 is expensive, the realtime mode with the fastest preset is the only one
+		// that keeps up with interactive use
+		av_opt_set(ctx->c->priv_data, "usage", "realtime", 0);
+		av_opt_set(ctx->c->priv_data, "cpu-used", "8", 0);
+		av_opt_set(ctx->c->priv_data, "lag-in-frames", "0", 0);
+		av_opt_set(ctx->c->priv_data, "row-mt", "1", 0);
+		av_opt_set(ctx->c->priv_data, "tile-columns", "2", 0);
+		av_opt_set(ctx->c->priv_data, "aq-mode", "3", 0);
+		av_opt_set_int(ctx->c->priv_data, "crf", quantizer(ctx, 35), 0);
+		ctx->c->thread_count = 0;
+		set_codec_params(ctx);
+		// constant quality if there is no maximum bitrate, constrained quality otherwise
+		ctx->c->bit_rate = ctx->max_bitrate > 0 ? ctx->max_bitrate * 1000 : 0;
+
+		ret = avcodec_open2(ctx->c, codec, NULL);
+		if (ret < 0)
+			ERROR(err, 1, "Could not open codec: %s", av_err2str(ret));
+	}
+
 #ifdef HAS_VAAPI
 	char* vaapi_device = getenv("WEYLUS_VAAPI_DEVICE");
 
@@ -201,7 +384,7 @@ void open_video(VideoContext* ctx, Error* err)
 			{
 				ctx->c->pix_fmt = AV_PIX_FMT_VAAPI;
 				av_opt_set(ctx->c->priv_data, "quality", "7", 0);
-				av_opt_set(ctx->c->priv_data, "qp", "23", 0);
+				av_opt_set_int(ctx->c->priv_data, "qp", quantizer(ctx, 23), 0);
 				set_codec_params(ctx);
 				Error err = {0};
 				set_hwframe_ctx(ctx, &err);
@@ -259,6 +442,7 @@ void open_video(VideoContext* ctx, Error* err)
 				}
 				else
 				{
+					log_warn("Failed to open 'h264_vaapi', trying the next encoder.");
 					avcodec_free_context(&ctx->c);
 					av_buffer_unref(&ctx->hw_device_ctx);
 				}
@@ -288,7 +472,8 @@ void open_video(VideoContext* ctx, Error* err)
 					using_hw = 1;
 				else
 				{
-					log_debug("Could not open codec: %s!", av_err2str(ret));
+					log_warn(
+						"Failed to open 'h264_mf', trying the next encoder: %s", av_err2str(ret));
 					avcodec_free_context(&ctx->c);
 				}
 			}
@@ -314,14 +499,20 @@ void open_video(VideoContext* ctx, Error* err)
 				av_opt_set(ctx->c->priv_data, "zerolatency", "1", 0);
 				av_opt_set(ctx->c->priv_data, "tune", "ull", 0);
 				av_opt_set(ctx->c->priv_data, "rc", "vbr", 0);
-				av_opt_set(ctx->c->priv_data, "cq", "21", 0);
+				av_opt_set_int(ctx->c->priv_data, "cq", quantizer(ctx, 21), 0);
+				av_opt_set(ctx->c->priv_data, "forced-idr", "1", 0);
+				// output frames as soon as they are encoded instead of queuing them
+				if (ctx->low_latency)
+					av_opt_set(ctx->c->priv_data, "delay", "0", 0);
 				set_codec_params(ctx);
 				int ret = avcodec_open2(ctx->c, codec, NULL);
 				if (ret == 0)
 					using_hw = 1;
 				else
 				{
-					log_debug("Could not open codec: %s!", av_err2str(ret));
+					log_warn(
+						"Failed to open 'h264_nvenc', trying the next encoder: %s",
+						av_err2str(ret));
 					avcodec_free_context(&ctx->c);
 				}
 			}
@@ -351,13 +542,16 @@ void open_video(VideoContext* ctx, Error* err)
 				if (avcodec_open2(ctx->c, codec, NULL) == 0)
 					using_hw = 1;
 				else
+				{
+					log_warn("Failed to open 'h264_videotoolbox', trying the next encoder.");
 					avcodec_free_context(&ctx->c);
+				}
 			}
 		}
 	}
 #endif
 
-	if (!using_hw)
+	if (!using_hw && ctx->codec == CODEC_H264)
 	{
 		codec = avcodec_find_encoder_by_name("libx264");
 		if (!codec)
@@ -370,11 +564,51 @@ void open_video(VideoContext* ctx, Error* err)
 		{
 			ERROR(err, 1, "Could not allocate video codec context");
 		}
-		ctx->sw_pix_fmt = ctx->c->pix_fmt = AV_PIX_FMT_YUV420P;
+		if (ctx->yuv444)
+		{
+			// no chroma subsampling, this keeps colored text sharp at the cost of bandwidth
+			ctx->sw_pix_fmt = ctx->c->pix_fmt = AV_PIX_FMT_YUV444P;
+			av_opt_set(ctx->c->priv_data, "profile", "high444", 0);
+		}
+		else
+			ctx->sw_pix_fmt = ctx->c->pix_fmt = AV_PIX_FMT_YUV420P;
 		av_opt_set(ctx->c->priv_data, "preset", "ultrafast", 0);
 		av_opt_set(ctx->c->priv_data, "tune", "zerolatency", 0);
-		av_opt_set(ctx->c->priv_data, "crf", "23", 0);
+		ctx->crf = quantizer(ctx, 23);
+		av_opt_set_int(ctx->c->priv_data, "crf", ctx->crf, 0);
+		// make sure forced keyframes are IDR frames so the stream can be decoded from there on
+		av_opt_set(ctx->c->priv_data, "forced-idr", "1", 0);
+		// there is no color to preserve, spend the bits on luma instead
+		if (ctx->grayscale)
+			av_opt_set(ctx->c->priv_data, "chromaoffset", "12", 0);
+		// zerolatency splits every frame into slices encoded in parallel, frame threads encode
+		// several frames at once instead, which scales better at high resolutions but delays the
+		// output by a frame per additional thread. libx264 keeps track of the frames each thread
+		// references, so keyframes and GOPs are the same as with a single thread.
+		if (ctx->encoder_threads > 1 && !ctx->low_latency)
+		{
+			av_opt_set(ctx->c->priv_data, "x264-params", "sliced-threads=0", 0);
+			ctx->c->thread_count = ctx->encoder_threads;
+		}
 		set_codec_params(ctx);
+		// cap the bitrate of constant quality encoding, this can only be changed while encoding if
+		// it has been enabled from the start
+		if (ctx->max_bitrate > 0)
+		{
+			ctx->c->rc_max_rate = ctx->max_bitrate * 1000;
+			// a small buffer keeps single frames from taking long to send at the maximum bitrate
+			ctx->c->rc_buffer_size = ctx->max_bitrate * 1000 / (ctx->low_latency ? 10 : 2);
+		}
+		if (ctx->low_latency)
+		{
+			// periodic keyframes are large and take a while to send, refresh the image by a
+			// column of intra coded blocks moving across the frames instead, so every frame has
+			// about the same size. Keyframes requested by clients are IDR frames nonetheless. Every
+			// frame is a keyframe anyway with an interval of 1.
+			if (ctx->keyframe_interval > 1)
+				av_opt_set(ctx->c->priv_data, "intra-refresh", "1", 0);
+			av_opt_set(ctx->c->priv_data, "rc-lookahead", "0", 0);
+		}
 
 		ret = avcodec_open2(ctx->c, codec, NULL);
 		if (ret < 0)
@@ -414,42 +648,18 @@ void open_video(VideoContext* ctx, Error* err)
 	if (ret < 0)
 		log_warn("Video: failed to write header!");
 	av_dict_free(&opt);
+	// pass the initialization segment on its own instead of together with the first fragment
+	avio_flush(ctx->oc->pb);
 
-	ctx->sws_rgb = sws_getContext(
-		ctx->width_in,
-		ctx->height_in,
-		AV_PIX_FMT_RGB24,
-		ctx->width_out,
-		ctx->height_out,
-		ctx->sw_pix_fmt,
-		SWS_FAST_BILINEAR,
-		NULL,
-		NULL,
-		NULL);
-
-	ctx->sws_rgb0 = sws_getContext(
-		ctx->width_in,
-		ctx->height_in,
-		AV_PIX_FMT_RGB0,
-		ctx->width_out,
-		ctx->height_out,
-		ctx->sw_pix_fmt,
-		SWS_FAST_BILINEAR,
-		NULL,
-		NULL,
-		NULL);
-
-	ctx->sws_bgr0 = sws_getContext(
-		ctx->width_in,
-		ctx->height_in,
-		AV_PIX_FMT_BGR0,
-		ctx->width_out,
-		ctx->height_out,
-		ctx->sw_pix_fmt,
-		SWS_FAST_BILINEAR,
-		NULL,
-		NULL,
-		NULL);
+	ctx->frame_in = av_frame_alloc();
+	if (!ctx->frame_in)
+		ERROR(err, 1, "Could not allocate input video frame");
+
+	ctx->sws_rgb = create_sws_context(ctx, AV_PIX_FMT_RGB24);
+	ctx->sws_rgb0 = create_sws_context(ctx, AV_PIX_FMT_RGB0);
+	ctx->sws_bgr0 = create_sws_context(ctx, AV_PIX_FMT_BGR0);
+	if (!ctx->sws_rgb || !ctx->sws_rgb0 || !ctx->sws_bgr0)
+		ERROR(err, 1, "Failed to create scaling contexts");
 
 	ctx->initialized = 1;
 	log_info(
@@ -466,6 +676,7 @@ void destroy_video_encoder(VideoContext* ctx)
 	{
 		av_write_trailer(ctx->oc);
 		av_frame_free(&ctx->frame);
+		av_frame_free(&ctx->frame_in);
 		if (ctx->using_vaapi)
 			av_frame_free(&ctx->frame_hw);
 		avio_context_free(&ctx->oc->pb);
@@ -488,6 +699,13 @@ void encode_video_frame(VideoContext* ctx, int millis, Error* err)
 	AVFrame* frame = ctx->using_vaapi ? ctx->frame_hw : ctx->frame;
 
 	frame->pts = millis;
+	if (ctx->force_keyframe)
+	{
+		frame->pict_type = AV_PICTURE_TYPE_I;
+		ctx->force_keyframe = 0;
+	}
+	else
+		frame->pict_type = AV_PICTURE_TYPE_NONE;
 
 	ret = avcodec_send_frame(ctx->c, frame);
 	if (ret < 0)
@@ -504,14 +722,19 @@ void encode_video_frame(VideoContext* ctx, int millis, Error* err)
 		}
 
 		av_packet_rescale_ts(ctx->pkt, ctx->c->time_base, ctx->st->time_base);
+		ctx->fragment_keyframe = (ctx->pkt->flags & AV_PKT_FLAG_KEY) != 0;
 		av_write_frame(ctx->oc, ctx->pkt);
 		av_packet_unref(ctx->pkt);
 
 		// new fragment on every frame for lowest latency
 		av_write_frame(ctx->oc, NULL);
+		ctx->fragment_keyframe = 0;
 	}
 }
 
+// Returns 1 while the fragment passed to write_video_packet holds a keyframe and 0 otherwise.
+int fragment_is_keyframe(VideoContext* ctx) { return ctx->fragment_keyframe; }
+
 VideoContext* init_video_encoder(
 	void* rust_ctx,
 	int width_in,
@@ -521,7 +744,21 @@ VideoContext* init_video_encoder(
 	int try_vaapi,
 	int try_nvenc,
 	int try_videotoolbox,
-	int try_mediafoundation)
+	int try_mediafoundation,
+	int conversion_threads,
+	int color_matrix,
+	int color_range,
+	int chroma_filter,
+	int yuv444,
+	int grayscale,
+	int codec,
+	int max_bitrate,
+	int constant_quality,
+	int qp_min,
+	int qp_max,
+	int keyframe_interval,
+	int encoder_threads,
+	int low_latency)
 {
 	VideoContext* ctx = malloc(sizeof(VideoContext));
 	ctx->rust_ctx = rust_ctx;
@@ -534,13 +771,92 @@ VideoContext* init_video_encoder(
 	ctx->frame_allocated = 0;
 	ctx->frame_hw_allocated = 0;
 	ctx->using_vaapi = 0;
-	ctx->try_vaapi = try_vaapi;
-	ctx->try_nvenc = try_nvenc;
-	ctx->try_videotoolbox = try_videotoolbox;
-	ctx->try_mediafoundation = try_mediafoundation;
+	// hardware encoders are only setup for H.264 with YUV 4:2:0, so YUV 4:4:4 always uses libx264,
+	// VP9 libvpx and AV1 libaom, grayscale does so too as some of them take BGR0 frames directly
+	int sw_only = yuv444 || grayscale || codec != CODEC_H264;
+	ctx->try_vaapi = try_vaapi && !sw_only;
+	ctx->try_nvenc = try_nvenc && !sw_only;
+	ctx->try_videotoolbox = try_videotoolbox && !sw_only;
+	ctx->try_mediafoundation = try_mediafoundation && !sw_only;
+	ctx->conversion_threads = conversion_threads;
+	ctx->color_matrix = color_matrix;
+	ctx->color_range = color_range;
+	ctx->chroma_filter = chroma_filter;
+	ctx->yuv444 = yuv444;
+	ctx->grayscale = grayscale;
+	ctx->codec = codec;
+	// frames are encoded at the given quality no matter how many bits that takes
+	ctx->max_bitrate = constant_quality >= 0 ? 0 : max_bitrate;
+	ctx->constant_quality = constant_quality;
+	ctx->qp_min = qp_min;
+	ctx->qp_max = qp_max;
+	ctx->crf = 23;
+	ctx->keyframe_interval = keyframe_interval;
+	ctx->encoder_threads = encoder_threads;
+	ctx->low_latency = low_latency;
+	ctx->force_keyframe = 0;
+	ctx->fragment_keyframe = 0;
 	return ctx;
 }
 
+void request_keyframe(VideoContext* ctx) { ctx->force_keyframe = 1; }
+
+// Only libx264 supports changing the bitrate while encoding, other encoders keep the quality based
+// settings they have been opened with.
+void set_max_bitrate(VideoContext* ctx, int max_bitrate)
+{
+	if (!ctx->initialized || ctx->max_bitrate <= 0 || strcmp(ctx->c->codec->name, "libx264") != 0)
+		return;
+	// libx264 checks for changes of these values on every frame and reconfigures itself
+	ctx->c->rc_max_rate = max_bitrate * 1000;
+	ctx->c->rc_buffer_size = max_bitrate * 1000 / 2;
+}
+
+// Scale the quality of libx264 between its worst at 0 and the quality it has been opened with at
+// 1, other encoders ignore this.
+void set_quality(VideoContext* ctx, float quality)
+{
+	if (!ctx->initialized || strcmp(ctx->c->codec->name, "libx264") != 0)
+		return;
+	if (quality < 0)
+		quality = 0;
+	if (quality > 1)
+		quality = 1;
+	// crf as set on opening, 51 is the worst quality libx264 supports; like the bitrate libx264
+	// picks up changes of crf on the next frame
+	av_opt_set_double(ctx->c->priv_data, "crf", ctx->crf + (1 - quality) * (51 - ctx->crf), 0);
+}
+
+// Name of the ffmpeg encoder in use, NULL if the encoder has not been opened.
+const char* video_encoder_name(VideoContext* ctx)
+{
+	return ctx->initialized ? ctx->c->codec->name : NULL;
+}
+
+// Returns 1 if ffmpeg has been built with the encoder called name and, for hardware encoders, the
+// device it runs on can be opened. Opening the encoder itself may still fail.
+int video_encoder_available(const char* name)
+{
+	if (!avcodec_find_encoder_by_name(name))
+		return 0;
+	enum AVHWDeviceType type = AV_HWDEVICE_TYPE_NONE;
+	const char* device_name = NULL;
+	if (strcmp(name, "h264_vaapi") == 0)
+	{
+		type = AV_HWDEVICE_TYPE_VAAPI;
+		device_name = getenv("WEYLUS_VAAPI_DEVICE");
+	}
+	else if (strcmp(name, "h264_nvenc") == 0)
+		type = AV_HWDEVICE_TYPE_CUDA;
+	if (type == AV_HWDEVICE_TYPE_NONE)
+		return 1;
+	AVBufferRef* device = NULL;
+	if (av_hwdevice_ctx_create(&device, type, device_name, NULL, 0) < 0)
+		return 0;
+	av_buffer_unref(&device);
+	return 1;
+}
+
 void alloc_frame_buffer(VideoContext* ctx, Error* err)
 {
 	int ret = av_frame_get_buffer(ctx->frame, 0);
@@ -566,6 +882,74 @@ void alloc_frame_buffer_hw(VideoContext* ctx, Error* err)
 	ctx->frame_hw_allocated = 1;
 }
 
+#if defined(__clang__) || defined(__GNUC__)
+void free_nothing(__attribute__((unused)) void* _opaque, __attribute__((unused)) uint8_t* _data) {}
+#else
+void free_nothing(void* _opaque, uint8_t* _data) {}
+#endif
+
+// Set U and V to the value meaning no color, in limited as well as full range. Only luma is left,
+// which compresses far better as the constant chroma planes cost next to nothing.
+static void fill_neutral_chroma(AVFrame* frame)
+{
+	const AVPixFmtDescriptor* desc = av_pix_fmt_desc_get(frame->format);
+	int height = AV_CEIL_RSHIFT(frame->height, desc->log2_chroma_h);
+	for (int plane = 1; plane < 3; ++plane)
+		memset(frame->data[plane], 128, frame->linesize[plane] * height);
+}
+
+// With VAAPI the frame filled in software is copied to the frame the encoder takes.
+static void upload_frame(VideoContext* ctx, Error* err)
+{
+	if (!ctx->using_vaapi)
+		return;
+	if (!ctx->frame_hw_allocated)
+	{
+		alloc_frame_buffer_hw(ctx, err);
+		OK_OR_ABORT(err);
+	}
+	av_frame_make_writable(ctx->frame_hw);
+	int ret = av_hwframe_transfer_data(ctx->frame_hw, ctx->frame, 0);
+	if (ret < 0)
+		ERROR(err, 1, "Could not upload video frame to hardware: %s", av_err2str(ret));
+}
+
+void scale_frame(
+	VideoContext* ctx,
+	struct SwsContext* sws,
+	enum AVPixelFormat src_fmt,
+	const void* data,
+	int stride,
+	Error* err)
+{
+	if (!ctx->frame_allocated)
+	{
+		alloc_frame_buffer(ctx, err);
+		OK_OR_ABORT(err);
+	}
+	av_frame_make_writable(ctx->frame);
+
+	// sws_scale_frame requires reference counted frames, so wrap the data without taking ownership
+	// of it, this avoids copying the input
+	AVFrame* src = ctx->frame_in;
+	src->format = src_fmt;
+	src->width = ctx->width_in;
+	src->height = ctx->height_in;
+	src->data[0] = (uint8_t*)data;
+	src->linesize[0] = stride;
+	src->buf[0] = av_buffer_create(
+		(uint8_t*)data, stride * ctx->height_in, free_nothing, NULL, AV_BUFFER_FLAG_READONLY);
+	if (!src->buf[0])
+		ERROR(err, 1, "Out of memory!");
+	int ret = sws_scale_frame(sws, ctx->frame, src);
+	av_frame_unref(src);
+	if (ret < 0)
+		ERROR(err, 1, "Failed to convert video frame: %s", av_err2str(ret));
+	// software encoders use planar YUV, which is the only format used in grayscale mode
+	if (ctx->grayscale)
+		fill_neutral_chroma(ctx->frame);
+}
+
 void fill_bgr0(VideoContext* ctx, const void* data, int stride, Error* err)
 {
 	if (ctx->frame->format == AV_PIX_FMT_BGR0 && ctx->width_in == ctx->width_out &&
@@ -578,88 +962,75 @@ void fill_bgr0(VideoContext* ctx, const void* data, int stride, Error* err)
 	}
 	else
 	{
-		const uint8_t* const* src = (const uint8_t* const*)&data;
-		// 4 colors per pixel
-		const int src_stride[] = {stride, 0, 0, 0};
-		if (!ctx->frame_allocated)
-		{
-			alloc_frame_buffer(ctx, err);
-			OK_OR_ABORT(err);
-		}
-		av_frame_make_writable(ctx->frame);
-		sws_scale(
-			ctx->sws_bgr0,
-			src,
-			src_stride,
-			0,
-			ctx->height_in,
-			ctx->frame->data,
-			ctx->frame->linesize);
-	}
-	if (ctx->using_vaapi)
-	{
-		if (!ctx->frame_hw_allocated)
-		{
-			alloc_frame_buffer_hw(ctx, err);
-			OK_OR_ABORT(err);
-		}
-		av_frame_make_writable(ctx->frame_hw);
-		int ret = av_hwframe_transfer_data(ctx->frame_hw, ctx->frame, 0);
-		if (ret < 0)
-			ERROR(err, 1, "Could not upload video frame to hardware: %s", av_err2str(ret));
+		scale_frame(ctx, ctx->sws_bgr0, AV_PIX_FMT_BGR0, data, stride, err);
+		OK_OR_ABORT(err);
 	}
+	upload_frame(ctx, err);
 }
 
-void fill_rgb(VideoContext* ctx, const void* data, Error* err)
+// keep in sync with enum YuvFormat in src/video.rs
+enum YuvFormat
 {
-	const uint8_t* const* src = (const uint8_t* const*)&data;
-	// 3 colors per pixel
-	const int src_stride[] = {ctx->width_in * 3, 0, 0, 0};
+	YUV_FORMAT_420P = 0,
+	YUV_FORMAT_444P = 1,
+	YUV_FORMAT_NV12 = 2,
+};
+
+// Planes of the frame to convert the next frame into instead of calling one of the fill_*
+// functions, see fill_converted. Returns one of enum YuvFormat, or -1 if the encoder does not take
+// one of these, like NVENC which takes BGR0 directly.
+int frame_planes(VideoContext* ctx, uint8_t* data[3], int linesize[3], Error* err)
+{
+	int format;
+	switch (ctx->frame->format)
+	{
+	case AV_PIX_FMT_YUV420P:
+		format = YUV_FORMAT_420P;
+		break;
+	case AV_PIX_FMT_YUV444P:
+		format = YUV_FORMAT_444P;
+		break;
+	case AV_PIX_FMT_NV12:
+		format = YUV_FORMAT_NV12;
+		break;
+	default:
+		return -1;
+	}
 	if (!ctx->frame_allocated)
 	{
 		alloc_frame_buffer(ctx, err);
-		OK_OR_ABORT(err);
+		if (err->code)
+			return -1;
 	}
 	av_frame_make_writable(ctx->frame);
-	sws_scale(
-		ctx->sws_rgb, src, src_stride, 0, ctx->height_in, ctx->frame->data, ctx->frame->linesize);
-	if (ctx->using_vaapi)
+	for (int i = 0; i < 3; ++i)
 	{
-		if (!ctx->frame_hw_allocated)
-		{
-			alloc_frame_buffer_hw(ctx, err);
-			OK_OR_ABORT(err);
-		}
-		av_frame_make_writable(ctx->frame_hw);
-		int ret = av_hwframe_transfer_data(ctx->frame_hw, ctx->frame, 0);
-		if (ret < 0)
-			ERROR(err, 1, "Could not upload video frame to hardware: %s", av_err2str(ret));
+		data[i] = ctx->frame->data[i];
+		linesize[i] = ctx->frame->linesize[i];
 	}
+	return format;
+}
+
+// Finish a frame that has been converted into the planes returned by frame_planes.
+void fill_converted(VideoContext* ctx, Error* err)
+{
+	if (ctx->grayscale)
+		fill_neutral_chroma(ctx->frame);
+	upload_frame(ctx, err);
+}
+
+void fill_rgb(VideoContext* ctx, const void* data, Error* err)
+{
+	// 3 colors per pixel
+	scale_frame(ctx, ctx->sws_rgb, AV_PIX_FMT_RGB24, data, ctx->width_in * 3, err);
+	OK_OR_ABORT(err);
+	upload_frame(ctx, err);
 }
 
 void fill_rgb0(VideoContext* ctx, const void* data, Error* err)
 {
-	const uint8_t* const* src = (const uint8_t* const*)&data;
 	// 4 colors per pixel
-	const int src_stride[] = {ctx->width_in * 4, 0, 0, 0};
-	if (!ctx->frame_allocated)
-	{
-		alloc_frame_buffer(ctx, err);
-		OK_OR_ABORT(err);
-	}
-	av_frame_make_writable(ctx->frame);
-	sws_scale(
-		ctx->sws_rgb0, src, src_stride, 0, ctx->height_in, ctx->frame->data, ctx->frame->linesize);
-	if (ctx->using_vaapi)
-	{
-		if (!ctx->frame_hw_allocated)
-		{
-			alloc_frame_buffer_hw(ctx, err);
-			OK_OR_ABORT(err);
-		}
-		av_frame_make_writable(ctx->frame_hw);
-		int ret = av_hwframe_transfer_data(ctx->frame_hw, ctx->frame, 0);
-		if (ret < 0)
-			ERROR(err, 1, "Could not upload video frame to hardware: %s", av_err2str(ret));
-	}
+	scale_frame(ctx, ctx->sws_rgb0, AV_PIX_FMT_RGB0, data, ctx->width_in * 4, err);
+	OK_OR_ABORT(err);
+	upload_frame(ctx, err);
 }
@@ -8,4 +8,5 @@ void fill_error(Error* err, int code, const char* fmt, ...)
 	va_list args;
 	va_start(args, fmt);
 	vsnprintf(err->error_str, sizeof(err->error_str), fmt, args);
+	va_end(args);
 }
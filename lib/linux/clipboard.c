@@ -0,0 +1,247 @@
+#include <X11/X.h>
+#include <X11/Xatom.h>
+#include <X11/Xlib.h>
+#include <X11/extensions/Xfixes.h>
+#include <poll.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "../error.h"
+#include "../log.h"
+#include "xhelper.h"
+
+typedef struct ClipboardContext
+{
+	Display* disp;
+	// invisible window that owns the clipboard and receives its contents
+	Window win;
+	Atom clipboard;
+	Atom utf8_string;
+	Atom targets;
+	Atom incr;
+	Atom property;
+	int xfixes_event_base;
+	// value of x11_connection_losses when the display has been opened
+	int losses;
+	size_t max_size;
+	// text offered to other applications while the clipboard is owned by win
+	char* own_text;
+	size_t own_text_len;
+	// text read from the clipboard last
+	char* text;
+	size_t text_len;
+} ClipboardContext;
+
+ClipboardContext* clipboard_init(size_t max_size, Error* err)
+{
+	int losses = x11_connection_losses();
+	Display* disp = XOpenDisplay(NULL);
+	if (!disp)
+	{
+		fill_error(err, 1, "Failed to open X display.");
+		return NULL;
+	}
+	x11_watch_connection(disp);
+	int event_base, error_base;
+	if (!XFixesQueryExtension(disp, &event_base, &error_base))
+	{
+		XCloseDisplay(disp);
+		fill_error(err, 103, "XFixes is not available, can not watch the clipboard!");
+		return NULL;
+	}
+
+	ClipboardContext* ctx = malloc(sizeof(ClipboardContext));
+	memset(ctx, 0, sizeof(ClipboardContext));
+	ctx->disp = disp;
+	ctx->xfixes_event_base = event_base;
+	ctx->losses = losses;
+	ctx->max_size = max_size;
+	ctx->win = XCreateSimpleWindow(disp, DefaultRootWindow(disp), 0, 0, 1, 1, 0, 0, 0);
+	ctx->clipboard = XInternAtom(disp, "CLIPBOARD", False);
+	ctx->utf8_string = XInternAtom(disp, "UTF8_STRING", False);
+	ctx->targets = XInternAtom(disp, "TARGETS", False);
+	ctx->incr = XInternAtom(disp, "INCR", False);
+	ctx->property = XInternAtom(disp, "WEYLUS_CLIPBOARD", False);
+	// get notified if another application takes over the clipboard
+	XFixesSelectSelectionInput(
+		disp, ctx->win, ctx->clipboard, XFixesSetSelectionOwnerNotifyMask);
+	XFlush(disp);
+	return ctx;
+}
+
+void clipboard_destroy(ClipboardContext* ctx)
+{
+	XDestroyWindow(ctx->disp, ctx->win);
+	XCloseDisplay(ctx->disp);
+	free(ctx->own_text);
+	free(ctx->text);
+	free(ctx);
+}
+
+void clipboard_set_text(ClipboardContext* ctx, const char* text, size_t len, Error* err)
+{
+	free(ctx->own_text);
+	ctx->own_text = malloc(len + 1);
+	memcpy(ctx->own_text, text, len);
+	ctx->own_text[len] = '\0';
+	ctx->own_text_len = len;
+	XSetSelectionOwner(ctx->disp, ctx->clipboard, ctx->win, CurrentTime);
+	if (XGetSelectionOwner(ctx->disp, ctx->clipboard) != ctx->win)
+		ERROR(err, 1, "Failed to take ownership of the clipboard.");
+	XFlush(ctx->disp);
+}
+
+// another application wants to paste the text owned by this context
+static void handle_selection_request(ClipboardContext* ctx, XSelectionRequestEvent* req)
+{
+	XSelectionEvent ev;
+	memset(&ev, 0, sizeof(XSelectionEvent));
+	ev.type = SelectionNotify;
+	ev.display = req->display;
+	ev.requestor = req->requestor;
+	ev.selection = req->selection;
+	ev.target = req->target;
+	ev.time = req->time;
+	ev.property = None;
+	// obsolete clients do not specify a property
+	Atom property = req->property == None ? req->target : req->property;
+
+	if (ctx->own_text && req->target == ctx->targets)
+	{
+		Atom targets[] = {ctx->targets, ctx->utf8_string, XA_STRING};
+		XChangeProperty(
+			ctx->disp,
+			req->requestor,
+			property,
+			XA_ATOM,
+			32,
+			PropModeReplace,
+			(unsigned char*)targets,
+			sizeof(targets) / sizeof(Atom));
+		ev.property = property;
+	}
+	else if (ctx->own_text && (req->target == ctx->utf8_string || req->target == XA_STRING))
+	{
+		XChangeProperty(
+			ctx->disp,
+			req->requestor,
+			property,
+			req->target,
+			8,
+			PropModeReplace,
+			(unsigned char*)ctx->own_text,
+			ctx->own_text_len);
+		ev.property = property;
+	}
+	XSendEvent(ctx->disp, req->requestor, False, NoEventMask, (XEvent*)&ev);
+}
+
+// the text requested via XConvertSelection arrived, returns 1 if it has been stored
+static int handle_selection_notify(ClipboardContext* ctx, XSelectionEvent* ev)
+{
+	if (ev->property == None)
+	{
+		// the owner does not offer UTF-8, try again with plain strings
+		if (ev->target == ctx->utf8_string)
+			XConvertSelection(
+				ctx->disp, ctx->clipboard, XA_STRING, ctx->property, ctx->win, ev->time);
+		return 0;
+	}
+
+	Atom type;
+	int format;
+	unsigned long nitems, bytes_after;
+	unsigned char* data = NULL;
+	// the length is given in multiples of 32 bits, fetch one more to detect data that is too large
+	if (XGetWindowProperty(
+			ctx->disp,
+			ctx->win,
+			ctx->property,
+			0,
+			ctx->max_size / 4 + 1,
+			True,
+			AnyPropertyType,
+			&type,
+			&format,
+			&nitems,
+			&bytes_after,
+			&data) != Success)
+	{
+		log_debug("Failed to read clipboard.");
+		return 0;
+	}
+
+	int stored = 0;
+	// INCR is only used for data exceeding the maximum request size, which is certainly larger than
+	// anything that should be synced
+	if (type == ctx->incr || bytes_after > 0 || nitems > ctx->max_size)
+		log_debug("Clipboard content exceeds the size limit of %zu bytes.", ctx->max_size);
+	else if (format == 8)
+	{
+		free(ctx->text);
+		ctx->text = malloc(nitems + 1);
+		memcpy(ctx->text, data, nitems);
+		ctx->text[nitems] = '\0';
+		ctx->text_len = nitems;
+		stored = 1;
+	}
+	if (data)
+		XFree(data);
+	return stored;
+}
+
+// Wait at most timeout_ms for events and process them. Returns 1 if another application changed
+// the clipboard, its text is then pointed to by text and valid until the next call. Returns -1 if
+// the connection to the X server has been lost, the context has to be destroyed then.
+int clipboard_poll(ClipboardContext* ctx, int timeout_ms, char** text, size_t* len)
+{
+	// the connection is not readable anymore and polling would return right away
+	if (x11_connection_losses() != ctx->losses)
+		return -1;
+	if (!XPending(ctx->disp))
+	{
+		struct pollfd pfd = {ConnectionNumber(ctx->disp), POLLIN, 0};
+		if (poll(&pfd, 1, timeout_ms) <= 0)
+			return 0;
+	}
+
+	int changed = 0;
+	while (XPending(ctx->disp))
+	{
+		XEvent ev;
+		XNextEvent(ctx->disp, &ev);
+		if (ev.type == ctx->xfixes_event_base + XFixesSelectionNotify)
+		{
+			XFixesSelectionNotifyEvent* sev = (XFixesSelectionNotifyEvent*)&ev;
+			// changes made by this context are known already
+			if (sev->owner != None && sev->owner != ctx->win)
+				XConvertSelection(
+					ctx->disp,
+					ctx->clipboard,
+					ctx->utf8_string,
+					ctx->property,
+					ctx->win,
+					sev->selection_timestamp);
+			continue;
+		}
+		switch (ev.type)
+		{
+		case SelectionRequest:
+			handle_selection_request(ctx, &ev.xselectionrequest);
+			break;
+		case SelectionClear:
+			free(ctx->own_text);
+			ctx->own_text = NULL;
+			ctx->own_text_len = 0;
+			break;
+		case SelectionNotify:
+			changed |= handle_selection_notify(ctx, &ev.xselection);
+			break;
+		}
+	}
+	XFlush(ctx->disp);
+
+	*text = ctx->text;
+	*len = ctx->text_len;
+	return changed;
+}
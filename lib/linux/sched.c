@@ -0,0 +1,39 @@
+#define _GNU_SOURCE
+#include <errno.h>
+#include <pthread.h>
+#include <sched.h>
+#include <string.h>
+
+#include "../error.h"
+
+// Schedule the calling thread round-robin with the real-time priority given, which requires
+// CAP_SYS_NICE or a sufficient RLIMIT_RTPRIO.
+void set_thread_priority(int priority, Error* err)
+{
+	int min = sched_get_priority_min(SCHED_RR);
+	int max = sched_get_priority_max(SCHED_RR);
+	if (priority < min || priority > max)
+		ERROR(err, 1, "Priority %d is out of range, expected %d to %d.", priority, min, max);
+	struct sched_param param;
+	memset(&param, 0, sizeof(param));
+	param.sched_priority = priority;
+	int ret = pthread_setschedparam(pthread_self(), SCHED_RR, &param);
+	if (ret != 0)
+		ERROR(err, 1, "Failed to set real-time priority: %s", strerror(ret));
+}
+
+// Only run the calling thread on the num_cpus CPUs given.
+void set_thread_affinity(const unsigned int* cpus, int num_cpus, Error* err)
+{
+	cpu_set_t set;
+	CPU_ZERO(&set);
+	for (int i = 0; i < num_cpus; ++i)
+	{
+		if (cpus[i] >= CPU_SETSIZE)
+			ERROR(err, 1, "CPU %u is out of range.", cpus[i]);
+		CPU_SET(cpus[i], &set);
+	}
+	int ret = pthread_setaffinity_np(pthread_self(), sizeof(set), &set);
+	if (ret != 0)
+		ERROR(err, 1, "Failed to set CPU affinity: %s", strerror(ret));
+}
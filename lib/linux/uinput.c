@@ -154,6 +154,10 @@ void init_stylus(int fd, const char* name, Error* err)
 		ERROR(err, 1, "error: ioctl UI_SET_KEYBIT BTN_TOOL_RUBBER");
 	if (ioctl(fd, UI_SET_KEYBIT, BTN_TOUCH) < 0)
 		ERROR(err, 1, "error: ioctl UI_SET_KEYBIT BTN_TOUCH");
+	if (ioctl(fd, UI_SET_KEYBIT, BTN_STYLUS) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_KEYBIT BTN_STYLUS");
+	if (ioctl(fd, UI_SET_KEYBIT, BTN_STYLUS2) < 0)
+		ERROR(err, 1, "error: ioctl UI_SET_KEYBIT BTN_STYLUS2");
 
 	// setup sending timestamps
 	if (ioctl(fd, UI_SET_EVBIT, EV_MSC) < 0)
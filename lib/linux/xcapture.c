@@ -4,15 +4,23 @@
 
 #include <X11/extensions/XShm.h>
 #include <X11/extensions/Xcomposite.h>
+#include <X11/extensions/Xdamage.h>
 #include <X11/extensions/Xfixes.h>
+#include <drm/drm.h>
+#include <errno.h>
+#include <fcntl.h>
+#include <stdio.h>
 #include <stdlib.h>
 #include <string.h>
+#include <sys/ioctl.h>
 #include <sys/ipc.h>
 #include <sys/shm.h>
+#include <unistd.h>
 
 #include <stdint.h>
 
 #include "../error.h"
+#include "../log.h"
 #include "xhelper.h"
 
 int clamp(int x, int lb, int ub)
@@ -27,34 +35,260 @@ int clamp(int x, int lb, int ub)
 struct CaptureContext
 {
 	Capturable cap;
-	XImage* ximg;
-	XShmSegmentInfo shminfo;
+	// captures go alternately into these two images, so the image returned by the previous
+	// capture stays intact while the next one is captured into the other
+	XImage* ximg[2];
+	XShmSegmentInfo shminfo[2];
+	// index of the image the next capture goes into
+	int back;
 	int has_xfixes;
+	int xfixes_event_base;
 	int has_offscreen;
 	int wayland;
 	Bool last_img_return;
+	unsigned char* cursor_buf;
+	size_t cursor_buf_size;
+	// DRM device whose vertical blanks captures are aligned to, -1 if captures are not aligned
+	int vblank_fd;
+	// damage of the drawables captured from, none are tracked unless enabled via set_track_damage
+	int damage_event_base;
+	Damage damage[2];
+	int num_damage;
+	// the cursor changed its shape, set for cursor_changed and image_damaged respectively as both
+	// consume the same events
+	int cursor_shape_changed;
+	int cursor_shape_damaged;
+	// position of the cursor when image_damaged has been called last
+	int cursor_x;
+	int cursor_y;
 };
 
 typedef struct CaptureContext CaptureContext;
 
+// How windows are captured, see set_window_capture
+enum WindowCapture
+{
+	// the focused window from the screen so menus are visible, others from their own contents
+	WINDOW_CAPTURE_AUTO = 0,
+	// always from the contents of the window redirected via the Composite extension
+	WINDOW_CAPTURE_CONTENTS = 1,
+	// always the bounds of the window on screen, so only its visible part
+	WINDOW_CAPTURE_SCREEN = 2,
+};
+
+static int window_capture = WINDOW_CAPTURE_AUTO;
+
+// Set how windows are captured, this applies to captures started afterwards and is meant to be
+// called once before capturing anything.
+void set_window_capture(int mode) { window_capture = mode; }
+
+// Open the first DRM device that reports vertical blanks, returns -1 if there is none or access to
+// it is denied. Only the first CRTC is waited for, usually this drives the primary display.
+static int open_vblank_device()
+{
+	char path[32];
+	for (int i = 0; i < 8; ++i)
+	{
+		snprintf(path, sizeof(path), "/dev/dri/card%d", i);
+		int fd = open(path, O_RDWR | O_CLOEXEC);
+		if (fd < 0)
+			continue;
+		union drm_wait_vblank vbl;
+		memset(&vbl, 0, sizeof(vbl));
+		// waiting for 0 vertical blanks returns right away
+		vbl.request.type = _DRM_VBLANK_RELATIVE;
+		vbl.request.sequence = 0;
+		if (ioctl(fd, DRM_IOCTL_WAIT_VBLANK, &vbl) == 0)
+			return fd;
+		close(fd);
+	}
+	return -1;
+}
+
+// Returns 1 if captures can be aligned to vertical blanks, see set_vsync.
+int vsync_available()
+{
+	int fd = open_vblank_device();
+	if (fd < 0)
+		return 0;
+	close(fd);
+	return 1;
+}
+
+// wait for the next vertical blank, if this fails captures are no longer aligned
+static void wait_vblank(CaptureContext* ctx)
+{
+	union drm_wait_vblank vbl;
+	memset(&vbl, 0, sizeof(vbl));
+	vbl.request.type = _DRM_VBLANK_RELATIVE;
+	vbl.request.sequence = 1;
+	if (ioctl(ctx->vblank_fd, DRM_IOCTL_WAIT_VBLANK, &vbl) != 0 && errno != EINTR)
+	{
+		// for example because the display has been turned off
+		log_debug(
+			"Failed to wait for vertical blank, capturing without vsync from now on: %s",
+			strerror(errno));
+		close(ctx->vblank_fd);
+		ctx->vblank_fd = -1;
+	}
+}
+
+// layout of the pixels of an Image, keep in sync with PIXEL_FORMAT_* in src/capturable/x11.rs
+enum PixelFormat
+{
+	PIXEL_FORMAT_UNKNOWN = 0,
+	// 32 bits per pixel, blue in the first byte
+	PIXEL_FORMAT_BGR0 = 1,
+	// 32 bits per pixel, red in the first byte
+	PIXEL_FORMAT_RGB0 = 2,
+	// 24 bits per pixel, red in the first byte
+	PIXEL_FORMAT_RGB = 3,
+};
+
 struct Image
 {
 	char* data;
 	unsigned int width;
 	unsigned int height;
+	// bytes per row
+	unsigned int stride;
+	// one of PixelFormat
+	int format;
+};
+
+static int pixel_format(XImage* img)
+{
+	// the masks describe pixels as integers, only if the least significant byte comes first they
+	// map to bytes in memory as below
+	if (img->byte_order != LSBFirst)
+		return PIXEL_FORMAT_UNKNOWN;
+	int rgb = img->red_mask == 0xff && img->green_mask == 0xff00 && img->blue_mask == 0xff0000;
+	int bgr = img->red_mask == 0xff0000 && img->green_mask == 0xff00 && img->blue_mask == 0xff;
+	if (img->bits_per_pixel == 32 && bgr)
+		return PIXEL_FORMAT_BGR0;
+	if (img->bits_per_pixel == 32 && rgb)
+		return PIXEL_FORMAT_RGB0;
+	if (img->bits_per_pixel == 24 && rgb)
+		return PIXEL_FORMAT_RGB;
+	return PIXEL_FORMAT_UNKNOWN;
+}
+
+struct CursorImage
+{
+	// BGRA, not premultiplied, owned by the CaptureContext and valid until the next call to
+	// get_cursor_image
+	unsigned char* data;
+	unsigned int width;
+	unsigned int height;
+	int xhot;
+	int yhot;
+	// position of the hotspot relative to the top left corner of the captured area
+	int x;
+	int y;
+	// changes whenever the cursor image changes
+	unsigned long serial;
 };
 
-void* start_capture(Capturable* cap, CaptureContext* ctx, Error* err)
+// Create one of the shared memory images captures of the given size are copied to. Each image has a
+// segment of its own that is marked for removal right away, so it disappears once the X server and
+// this process detached from it, even if either of them dies.
+static void create_image(
+	CaptureContext* ctx, int i, unsigned int width, unsigned int height, Error* err)
 {
+	XShmSegmentInfo* shminfo = &ctx->shminfo[i];
+	ctx->ximg[i] = XShmCreateImage(
+		ctx->cap.disp,
+		DefaultVisualOfScreen(ctx->cap.screen),
+		DefaultDepthOfScreen(ctx->cap.screen),
+		ZPixmap,
+		NULL,
+		shminfo,
+		width,
+		height);
+	if (!ctx->ximg[i])
+		ERROR(err, 1, "Failed to create image of size %ux%u!", width, height);
+
+	shminfo->shmid =
+		shmget(IPC_PRIVATE, ctx->ximg[i]->bytes_per_line * ctx->ximg[i]->height, IPC_CREAT | 0777);
+	if (shminfo->shmid < 0)
+	{
+		XDestroyImage(ctx->ximg[i]);
+		ctx->ximg[i] = NULL;
+		ERROR(err, 1, "Fatal shminfo error!");
+	}
+	shminfo->shmaddr = ctx->ximg[i]->data = (char*)shmat(shminfo->shmid, 0, 0);
+	shminfo->readOnly = False;
+	// errors of XShmAttach are only reported asynchronously, syncing the connection owned by this
+	// context makes sure the segment is attached before it is marked for removal
+	int attached = shminfo->shmaddr != (char*)-1 && XShmAttach(ctx->cap.disp, shminfo);
+	if (attached)
+		XSync(ctx->cap.disp, False);
+	shmctl(shminfo->shmid, IPC_RMID, NULL);
+	if (!attached)
+	{
+		if (shminfo->shmaddr != (char*)-1)
+			shmdt(shminfo->shmaddr);
+		// the data is not owned by xlib
+		ctx->ximg[i]->data = NULL;
+		XDestroyImage(ctx->ximg[i]);
+		ctx->ximg[i] = NULL;
+		ERROR(err, 1, "XShmAttach() failed");
+	}
+}
+
+static void destroy_images(CaptureContext* ctx)
+{
+	for (int i = 0; i < 2; ++i)
+	{
+		if (!ctx->ximg[i])
+			continue;
+		XShmDetach(ctx->cap.disp, &ctx->shminfo[i]);
+		ctx->ximg[i]->data = NULL;
+		XDestroyImage(ctx->ximg[i]);
+		ctx->ximg[i] = NULL;
+		shmdt(ctx->shminfo[i].shmaddr);
+	}
+}
+
+// Create both images, either both or none of them exist afterwards.
+static void create_images(CaptureContext* ctx, unsigned int width, unsigned int height, Error* err)
+{
+	ctx->back = 0;
+	for (int i = 0; i < 2 && !err->code; ++i)
+		create_image(ctx, i, width, height, err);
+	if (err->code)
+		destroy_images(ctx);
+}
+
+// disp is the connection to the X server that is used for capturing, it is owned by the caller and
+// has to stay open until stop_capture is called. Contexts do not share any state, so several of
+// them can capture at the same time from different threads as long as each has its own display.
+// If ctx is given it is reused for the new size of the capturable, it stays valid even if this
+// fails and capture_screen tries again with the next capture.
+void* start_capture(Capturable* cap, CaptureContext* ctx, Display* disp, Error* err)
+{
+	Capturable own_cap = *cap;
+	own_cap.disp = disp;
+	own_cap.screen = ScreenOfDisplay(disp, XScreenNumberOfScreen(cap->screen));
+	cap = &own_cap;
+
 	if (XShmQueryExtension(cap->disp) != True)
 	{
 		fill_error(err, 1, "XShmExtension is not available but required!");
 		return NULL;
 	}
 
-	if (!ctx)
+	int new_ctx = !ctx;
+	if (new_ctx)
 	{
 		ctx = malloc(sizeof(CaptureContext));
+		if (!ctx)
+		{
+			fill_error(err, 1, "Failed to allocate capture context!");
+			return NULL;
+		}
+		ctx->ximg[0] = NULL;
+		ctx->ximg[1] = NULL;
 
 		int major, minor;
 		Bool pixmaps = False;
@@ -68,11 +302,25 @@ void* start_capture(Capturable* cap, CaptureContext* ctx, Error* err)
 			if (ctx->has_offscreen)
 				XCompositeRedirectWindow(cap->disp, cap->c.winfo.win, False);
 		}
+		if (!ctx->has_offscreen && window_capture == WINDOW_CAPTURE_CONTENTS &&
+			cap->type == WINDOW && cap->c.winfo.is_regular_window)
+			log_warn(
+				"Capturing the contents of '%s' requires the Composite extension and "
+				"shared memory pixmaps, capturing the window from the screen instead.",
+				cap->name);
 		const char* session_type = getenv("XDG_SESSION_TYPE");
 		if (session_type && strcmp(session_type, "wayland") == 0)
 			ctx->wayland = 1;
 		else
 			ctx->wayland = 0;
+		ctx->cursor_buf = NULL;
+		ctx->cursor_buf_size = 0;
+		ctx->vblank_fd = -1;
+		ctx->num_damage = 0;
+		ctx->cursor_shape_changed = 0;
+		ctx->cursor_shape_damaged = 0;
+		ctx->cursor_x = -1;
+		ctx->cursor_y = -1;
 	}
 	ctx->cap = *cap;
 	ctx->last_img_return = True;
@@ -82,34 +330,25 @@ void* start_capture(Capturable* cap, CaptureContext* ctx, Error* err)
 
 	int event_base, error_base;
 	ctx->has_xfixes = XFixesQueryExtension(cap->disp, &event_base, &error_base) == True;
+	ctx->xfixes_event_base = event_base;
+	// get notified whenever the cursor changes its shape, see cursor_changed
+	if (ctx->has_xfixes)
+		XFixesSelectCursorInput(
+			cap->disp, RootWindowOfScreen(cap->screen), XFixesDisplayCursorNotifyMask);
 
 	int x, y;
 	unsigned int width, height;
 	get_geometry(cap, &x, &y, &width, &height, err);
-	ctx->ximg = XShmCreateImage(
-		cap->disp,
-		DefaultVisualOfScreen(cap->screen),
-		DefaultDepthOfScreen(cap->screen),
-		ZPixmap,
-		NULL,
-		&ctx->shminfo,
-		width,
-		height);
-
-	ctx->shminfo.shmid =
-		shmget(IPC_PRIVATE, ctx->ximg->bytes_per_line * ctx->ximg->height, IPC_CREAT | 0777);
-	ctx->shminfo.shmaddr = ctx->ximg->data = (char*)shmat(ctx->shminfo.shmid, 0, 0);
-	ctx->shminfo.readOnly = False;
-	if (ctx->shminfo.shmid < 0)
+	if (!err->code)
+		create_images(ctx, width, height, err);
+	if (err->code)
 	{
-		fill_error(err, 1, "Fatal shminfo error!");
-		free(ctx);
-		return NULL;
-	}
-	if (!XShmAttach(cap->disp, &ctx->shminfo))
-	{
-		fill_error(err, 1, "XShmAttach() failed");
-		free(ctx);
+		if (new_ctx)
+		{
+			if (ctx->has_offscreen && cap->type == WINDOW && cap->c.winfo.is_regular_window)
+				XCompositeUnredirectWindow(cap->disp, cap->c.winfo.win, False);
+			free(ctx);
+		}
 		return NULL;
 	}
 
@@ -118,73 +357,154 @@ void* start_capture(Capturable* cap, CaptureContext* ctx, Error* err)
 
 void stop_capture(CaptureContext* ctx, Error* err)
 {
-	XShmDetach(ctx->cap.disp, &ctx->shminfo);
-	XDestroyImage(ctx->ximg);
-	if (shmdt(ctx->shminfo.shmaddr) != 0)
-	{
-		fill_error(err, 1, "Failed to detach shared memory!");
-	}
-	shmctl(ctx->shminfo.shmid, IPC_RMID, NULL);
+	(void)err;
+	destroy_images(ctx);
 	if (ctx->has_offscreen && ctx->cap.type == WINDOW && ctx->cap.c.winfo.is_regular_window)
 		XCompositeUnredirectWindow(ctx->cap.disp, ctx->cap.c.winfo.win, False);
+	free(ctx->cursor_buf);
+	if (ctx->vblank_fd >= 0)
+		close(ctx->vblank_fd);
+	for (int i = 0; i < ctx->num_damage; ++i)
+		XDamageDestroy(ctx->cap.disp, ctx->damage[i]);
 	free(ctx);
 }
 
-void capture_screen(CaptureContext* ctx, struct Image* img, int capture_cursor, Error* err)
+// Returns 1 if the X server supports the Damage extension, see set_track_damage.
+int damage_available(Display* disp)
+{
+	int event_base, error_base;
+	return XDamageQueryExtension(disp, &event_base, &error_base) == True;
+}
+
+// Track damage of the captured area, that is what the X server reports as drawn to, so
+// image_damaged can tell whether capturing would return a new image. Returns 1 if damage is
+// tracked and 0 if not, either because this has been disabled or because the Damage extension is
+// unavailable.
+int set_track_damage(CaptureContext* ctx, int track)
 {
-	Window root = DefaultRootWindow(ctx->cap.disp);
+	if (track && ctx->num_damage == 0)
+	{
+		int error_base;
+		if (XDamageQueryExtension(ctx->cap.disp, &ctx->damage_event_base, &error_base) != True)
+			return 0;
+		// damage of the root window includes that of all windows shown, windows captured off screen
+		// via their pixmap are not part of it
+		ctx->damage[ctx->num_damage++] = XDamageCreate(
+			ctx->cap.disp, RootWindowOfScreen(ctx->cap.screen), XDamageReportNonEmpty);
+		if (ctx->cap.type == WINDOW && ctx->has_offscreen)
+			ctx->damage[ctx->num_damage++] =
+				XDamageCreate(ctx->cap.disp, ctx->cap.c.winfo.win, XDamageReportNonEmpty);
+		// the cursor is not part of the damage, it is compared to its last position instead
+		ctx->cursor_x = -1;
+		ctx->cursor_y = -1;
+		XFlush(ctx->cap.disp);
+	}
+	else if (!track)
+	{
+		for (int i = 0; i < ctx->num_damage; ++i)
+			XDamageDestroy(ctx->cap.disp, ctx->damage[i]);
+		ctx->num_damage = 0;
+	}
+	return ctx->num_damage > 0;
+}
+
+// Align captures to the vertical blanks of the display, compositors swap buffers then, so the
+// image copied right after is complete and does not tear. This delays each capture by up to one
+// refresh interval. Returns 1 if captures are aligned and 0 if not, either because this has been
+// disabled or because no DRM device reports vertical blanks.
+int set_vsync(CaptureContext* ctx, int vsync)
+{
+	if (vsync && ctx->vblank_fd < 0)
+		ctx->vblank_fd = open_vblank_device();
+	else if (!vsync && ctx->vblank_fd >= 0)
+	{
+		close(ctx->vblank_fd);
+		ctx->vblank_fd = -1;
+	}
+	return ctx->vblank_fd >= 0;
+}
+
+// Capture the current image, the cursor is painted into it by the caller, see get_cursor_image.
+// The image is captured into the image not returned by the previous successful call, which stays
+// intact until the call after this one succeeded, so whoever reads it never sees a capture in
+// progress. If capturing fails img is left as it is. Both images are recreated if the size of the
+// capturable changed, which invalidates either of them.
+void capture_screen(CaptureContext* ctx, struct Image* img, Error* err)
+{
+	Window root = RootWindowOfScreen(ctx->cap.screen);
 	int x, y;
 	unsigned int width, height;
 	get_geometry(&ctx->cap, &x, &y, &width, &height, err);
 	OK_OR_ABORT(err);
-	// if window resized, create new cap...
-	if (width != (unsigned int)ctx->ximg->width || height != (unsigned int)ctx->ximg->height)
+	// if window resized or creating the image failed before, create new cap...
+	if (!ctx->ximg[0] || width != (unsigned int)ctx->ximg[0]->width ||
+		height != (unsigned int)ctx->ximg[0]->height)
 	{
-		XShmDetach(ctx->cap.disp, &ctx->shminfo);
-		XDestroyImage(ctx->ximg);
-		shmdt(ctx->shminfo.shmaddr);
-		shmctl(ctx->shminfo.shmid, IPC_RMID, NULL);
-		CaptureContext* new_ctx = start_capture(&ctx->cap, ctx, err);
+		destroy_images(ctx);
+		CaptureContext* new_ctx = start_capture(&ctx->cap, ctx, ctx->cap.disp, err);
 		if (!new_ctx)
 		{
 			return;
 		}
 	}
 
+	XImage* ximg = ctx->ximg[ctx->back];
 	Bool get_img_ret = False;
 
+	if (ctx->vblank_fd >= 0)
+		wait_vblank(ctx);
+
 	switch (ctx->cap.type)
 	{
 	case WINDOW:
 	{
-		Window* active_window;
-		unsigned long size;
-
 		int is_offscreen = ctx->cap.c.winfo.is_regular_window &&
 						   (x < 0 || y < 0 || x + (int)width > ctx->cap.screen->width ||
 							y + (int)height > ctx->cap.screen->height);
 
-		active_window =
-			(Window*)get_property(ctx->cap.disp, root, XA_WINDOW, "_NET_ACTIVE_WINDOW", &size, err);
-		if (!ctx->wayland && *active_window == ctx->cap.c.winfo.win && !is_offscreen)
+		// contents of the window itself, only regular windows are redirected
+		int redirected = ctx->has_offscreen && ctx->cap.c.winfo.is_regular_window;
+		int from_root = 0;
+		switch (window_capture)
+		{
+		case WINDOW_CAPTURE_CONTENTS:
+			from_root = !redirected && !is_offscreen && ctx->cap.c.winfo.is_regular_window;
+			break;
+		case WINDOW_CAPTURE_SCREEN:
+			// parts off screen are not visible anywhere, so these windows need their contents
+			from_root = !is_offscreen;
+			break;
+		default:
+		{
+			unsigned long size;
+			Window* active_window = (Window*)get_property(
+				ctx->cap.disp, root, XA_WINDOW, "_NET_ACTIVE_WINDOW", &size, err);
+			if (!active_window)
+				return;
+			// Don't capture from the root window on (X)Wayland as it is just black there.
+			from_root = !ctx->wayland && *active_window == ctx->cap.c.winfo.win && !is_offscreen;
+			free(active_window);
+			break;
+		}
+		}
+		if (from_root)
 		{
 			// cap window within its root so menus are visible as strictly speaking menus do not
 			// belong to the window itself ...
-			// But don't do this on (X)Wayland as the root window is just black in that case.
-			get_img_ret = XShmGetImage(ctx->cap.disp, root, ctx->ximg, x, y, 0x00ffffff);
+			get_img_ret = XShmGetImage(ctx->cap.disp, root, ximg, x, y, 0x00ffffff);
 		}
 		else
 		{
 			// ... but only if it is the active window as we might be recording the wrong thing
 			// otherwise. If it is not active just record the window itself.
 			// also if pixmaps are supported use those as they support capturing windows even if
-			// they are offscreen
-			if (is_offscreen)
+			// they are offscreen or covered by other windows
+			if (is_offscreen || (window_capture == WINDOW_CAPTURE_CONTENTS && redirected))
 			{
 				if (ctx->has_offscreen)
 				{
 					Pixmap pm = XCompositeNameWindowPixmap(ctx->cap.disp, ctx->cap.c.winfo.win);
-					get_img_ret = XShmGetImage(ctx->cap.disp, pm, ctx->ximg, 0, 0, 0x00ffffff);
+					get_img_ret = XShmGetImage(ctx->cap.disp, pm, ximg, 0, 0, 0x00ffffff);
 					XFreePixmap(ctx->cap.disp, pm);
 				}
 				else
@@ -196,13 +516,12 @@ void capture_screen(CaptureContext* ctx, struct Image* img, int capture_cursor,
 			}
 			else
 				get_img_ret =
-					XShmGetImage(ctx->cap.disp, ctx->cap.c.winfo.win, ctx->ximg, 0, 0, 0x00ffffff);
+					XShmGetImage(ctx->cap.disp, ctx->cap.c.winfo.win, ximg, 0, 0, 0x00ffffff);
 		}
-		free(active_window);
 		break;
 	}
 	case RECT:
-		get_img_ret = XShmGetImage(ctx->cap.disp, root, ctx->ximg, x, y, 0x00ffffff);
+		get_img_ret = XShmGetImage(ctx->cap.disp, root, ximg, x, y, 0x00ffffff);
 		break;
 	}
 
@@ -222,50 +541,167 @@ void capture_screen(CaptureContext* ctx, struct Image* img, int capture_cursor,
 		}
 	}
 
-	// capture cursor if requested and if XFixes is available
-	if (capture_cursor && ctx->has_xfixes)
+	img->width = ximg->width;
+	img->height = ximg->height;
+	img->stride = ximg->bytes_per_line;
+	img->data = ximg->data;
+	img->format = pixel_format(ximg);
+	ctx->back ^= 1;
+}
+
+void get_cursor_image(CaptureContext* ctx, struct CursorImage* img, Error* err)
+{
+	if (!ctx->has_xfixes)
+		ERROR(err, 103, "XFixes is not available, can not get cursor image!");
+
+	int x, y;
+	unsigned int width, height;
+	get_geometry(&ctx->cap, &x, &y, &width, &height, err);
+	OK_OR_ABORT(err);
+
+	XFixesCursorImage* cursor_img = XFixesGetCursorImage(ctx->cap.disp);
+	if (!cursor_img)
+		ERROR(err, 1, "Failed to get cursor image!");
+
+	size_t size = cursor_img->width * cursor_img->height * 4;
+	if (size > ctx->cursor_buf_size)
 	{
-		XFixesCursorImage* cursor_img = XFixesGetCursorImage(ctx->cap.disp);
-		uint32_t* data = (uint32_t*)ctx->ximg->data;
-
-		// coordinates of cursor inside ximg
-		int x0 = cursor_img->x - cursor_img->xhot - x;
-		int y0 = cursor_img->y - cursor_img->yhot - y;
-
-		// clamp part of cursor image to draw to the part of the cursor that is inside
-		// the captured area
-		int i0 = clamp(0, -x0, width - x0);
-		int i1 = clamp(cursor_img->width, -x0, width - x0);
-		int j0 = clamp(0, -y0, height - y0);
-		int j1 = clamp(cursor_img->height, -y0, height - y0);
-		// paint cursor image into captured image
-		for (int j = j0; j < j1; ++j)
-			for (int i = i0; i < i1; ++i)
-			{
-				uint32_t c_pixel = cursor_img->pixels[j * cursor_img->width + i];
-				unsigned char a = (c_pixel & 0xff000000) >> 24;
-				if (a)
-				{
-					uint32_t d_pixel = data[(j + y0) * width + i + x0];
-
-					unsigned char c1 = (c_pixel & 0x00ff0000) >> 16;
-					unsigned char c2 = (c_pixel & 0x0000ff00) >> 8;
-					unsigned char c3 = (c_pixel & 0x000000ff) >> 0;
-					unsigned char d1 = (d_pixel & 0x00ff0000) >> 16;
-					unsigned char d2 = (d_pixel & 0x0000ff00) >> 8;
-					unsigned char d3 = (d_pixel & 0x000000ff) >> 0;
-					// colors from the cursor image are premultiplied with the alpha channel
-					unsigned char f1 = c1 + d1 * (255 - a) / 255;
-					unsigned char f2 = c2 + d2 * (255 - a) / 255;
-					unsigned char f3 = c3 + d3 * (255 - a) / 255;
-					data[(j + y0) * width + i + x0] = (f1 << 16) | (f2 << 8) | (f3 << 0);
-				}
-			}
+		unsigned char* buf = realloc(ctx->cursor_buf, size);
+		if (!buf)
+		{
+			XFree(cursor_img);
+			ERROR(err, 1, "Out of memory!");
+		}
+		ctx->cursor_buf = buf;
+		ctx->cursor_buf_size = size;
+	}
+
+	for (size_t i = 0; i < (size_t)cursor_img->width * cursor_img->height; ++i)
+	{
+		// pixels are stored as unsigned long even though they are only 32 bit ARGB
+		unsigned long c_pixel = cursor_img->pixels[i];
+		unsigned char a = (c_pixel & 0xff000000) >> 24;
+		unsigned char r = (c_pixel & 0x00ff0000) >> 16;
+		unsigned char g = (c_pixel & 0x0000ff00) >> 8;
+		unsigned char b = (c_pixel & 0x000000ff) >> 0;
+		// colors are premultiplied with the alpha channel
+		if (a && a != 255)
+		{
+			r = r * 255 / a;
+			g = g * 255 / a;
+			b = b * 255 / a;
+		}
+		ctx->cursor_buf[4 * i + 0] = b;
+		ctx->cursor_buf[4 * i + 1] = g;
+		ctx->cursor_buf[4 * i + 2] = r;
+		ctx->cursor_buf[4 * i + 3] = a;
+	}
+
+	img->data = ctx->cursor_buf;
+	img->width = cursor_img->width;
+	img->height = cursor_img->height;
+	img->xhot = cursor_img->xhot;
+	img->yhot = cursor_img->yhot;
+	img->x = cursor_img->x - x;
+	img->y = cursor_img->y - y;
+	img->serial = cursor_img->cursor_serial;
+	XFree(cursor_img);
+}
+
+static void check_cursor_shape(CaptureContext* ctx)
+{
+	XEvent ev;
+	while (XCheckTypedEvent(ctx->cap.disp, ctx->xfixes_event_base + XFixesCursorNotify, &ev))
+	{
+		ctx->cursor_shape_changed = 1;
+		ctx->cursor_shape_damaged = 1;
+	}
+}
+
+// Returns 1 if the cursor changed its shape since the last call and 0 if not. The image only has to
+// be fetched again via get_cursor_image then. Returns -1 if XFixes is unavailable and changes are
+// not known.
+int cursor_changed(CaptureContext* ctx)
+{
+	if (!ctx->has_xfixes)
+		return -1;
+	check_cursor_shape(ctx);
+	int changed = ctx->cursor_shape_changed;
+	ctx->cursor_shape_changed = 0;
+	return changed;
+}
 
-		XFree(cursor_img);
+// Returns 1 if the captured area may have changed since the last call and 0 if it certainly did
+// not, -1 if damage is not tracked, see set_track_damage. The damage is cleared by this, so this
+// should be called right before capturing. If capture_cursor is set, moving the cursor or changing
+// its shape counts as damage as well.
+int image_damaged(CaptureContext* ctx, int capture_cursor)
+{
+	if (ctx->num_damage == 0)
+		return -1;
+	int damaged = 0;
+	XEvent ev;
+	while (XCheckTypedEvent(ctx->cap.disp, ctx->damage_event_base + XDamageNotify, &ev))
+		damaged = 1;
+	// no further events are reported until the damage is cleared
+	if (damaged)
+		for (int i = 0; i < ctx->num_damage; ++i)
+			XDamageSubtract(ctx->cap.disp, ctx->damage[i], None, None);
+
+	if (capture_cursor)
+	{
+		if (ctx->has_xfixes)
+		{
+			check_cursor_shape(ctx);
+			damaged |= ctx->cursor_shape_damaged;
+			ctx->cursor_shape_damaged = 0;
+		}
+		Window root, child;
+		int root_x, root_y, win_x, win_y;
+		unsigned int mask;
+		if (XQueryPointer(
+				ctx->cap.disp,
+				RootWindowOfScreen(ctx->cap.screen),
+				&root,
+				&child,
+				&root_x,
+				&root_y,
+				&win_x,
+				&win_y,
+				&mask))
+		{
+			damaged |= root_x != ctx->cursor_x || root_y != ctx->cursor_y;
+			ctx->cursor_x = root_x;
+			ctx->cursor_y = root_y;
+		}
 	}
+	XFlush(ctx->cap.disp);
+	return damaged;
+}
+
+// Position of the hotspot of the cursor relative to the top left corner of the captured area,
+// this is a lot cheaper than getting the whole image via get_cursor_image.
+void get_cursor_position(CaptureContext* ctx, int* x, int* y, Error* err)
+{
+	int cap_x, cap_y;
+	unsigned int width, height;
+	get_geometry(&ctx->cap, &cap_x, &cap_y, &width, &height, err);
+	OK_OR_ABORT(err);
 
-	img->width = ctx->ximg->width;
-	img->height = ctx->ximg->height;
-	img->data = ctx->ximg->data;
+	Window root, child;
+	int root_x, root_y, win_x, win_y;
+	unsigned int mask;
+	if (!XQueryPointer(
+			ctx->cap.disp,
+			RootWindowOfScreen(ctx->cap.screen),
+			&root,
+			&child,
+			&root_x,
+			&root_y,
+			&win_x,
+			&win_y,
+			&mask))
+		ERROR(err, 1, "Failed to query cursor position!");
+	*x = root_x - cap_x;
+	*y = root_y - cap_y;
 }
@@ -1,11 +1,13 @@
 #include <X11/X.h>
 #include <X11/Xlib.h>
+#include <X11/Xresource.h>
 #include <X11/extensions/XInput.h>
 #include <X11/extensions/XInput2.h>
 #include <X11/extensions/Xrandr.h>
 #include <X11/extensions/randr.h>
 #include <stdlib.h>
 #include <string.h>
+#include <unistd.h>
 
 #include "../error.h"
 #include "../log.h"
@@ -21,12 +23,46 @@ int x11_error_handler(Display* disp, XErrorEvent* err)
 	return 0;
 }
 
+// available since libX11 1.7, declared weak so older versions can still be linked against
+extern void XSetIOErrorExitHandler(Display* disp, void (*handler)(Display*, void*), void* user_data)
+	__attribute__((weak));
+
+// number of connections to the X server lost so far
+static int x11_losses = 0;
+
+int x11_io_error_handler(Display* disp)
+{
+	log_warn("Lost connection to X server %s.", DisplayString(disp));
+	__atomic_add_fetch(&x11_losses, 1, __ATOMIC_SEQ_CST);
+	return 0;
+}
+
+// called after x11_io_error_handler, xlib exits the process if there is no such handler or it
+// returns, so this returns only to the caller of the failed request
+static void x11_io_error_exit_handler(Display* disp, void* user_data)
+{
+	(void)disp;
+	(void)user_data;
+}
+
 void x11_set_error_handler() {
 	// setting an error handler is required as otherwise xlib may just exit the process, even though
 	// the error was recoverable.
 	XSetErrorHandler(x11_error_handler);
+	XSetIOErrorHandler(x11_io_error_handler);
+	if (!XSetIOErrorExitHandler)
+		log_warn("libX11 is older than 1.7, losing the connection to the X server will terminate "
+				 "Weylus.");
+}
+
+void x11_watch_connection(Display* disp)
+{
+	if (XSetIOErrorExitHandler)
+		XSetIOErrorExitHandler(disp, x11_io_error_exit_handler, NULL);
 }
 
+int x11_connection_losses() { return __atomic_load_n(&x11_losses, __ATOMIC_SEQ_CST); }
+
 int locale_to_utf8(char* src, char* dest, size_t size)
 {
 	iconv_t icd = iconv_open("UTF-8//IGNORE", "");
@@ -150,17 +186,16 @@ char* get_window_title(Display* disp, Window win, Error* err)
 	return title_utf8;
 }
 
-Window* get_client_list(Display* disp, unsigned long* size, Error* err)
+Window* get_client_list(Display* disp, Window root, unsigned long* size, Error* err)
 {
 	Window* client_list;
 	Error err_net;
 	Error err_win;
 	if ((client_list = (Window*)get_property(
-			 disp, DefaultRootWindow(disp), XA_WINDOW, "_NET_CLIENT_LIST", size, &err_net)) == NULL)
+			 disp, root, XA_WINDOW, "_NET_CLIENT_LIST", size, &err_net)) == NULL)
 	{
 		if ((client_list = (Window*)get_property(
-				 disp, DefaultRootWindow(disp), XA_CARDINAL, "_WIN_CLIENT_LIST", size, &err_win)) ==
-			NULL)
+				 disp, root, XA_CARDINAL, "_WIN_CLIENT_LIST", size, &err_win)) == NULL)
 		{
 			fill_error(
 				err,
@@ -176,16 +211,90 @@ Window* get_client_list(Display* disp, unsigned long* size, Error* err)
 	return client_list;
 }
 
-int create_capturables(Display* disp, Capturable** capturables, int* num_monitors, int size, Error* err)
+double get_monitor_refresh_rate(Display* disp, XRRScreenResources* res, XRRMonitorInfo* m)
+{
+	if (!res || m->noutput < 1)
+		return 0.0;
+	double rate = 0.0;
+	XRROutputInfo* output = XRRGetOutputInfo(disp, res, m->outputs[0]);
+	if (!output)
+		return 0.0;
+	if (output->crtc)
+	{
+		XRRCrtcInfo* crtc = XRRGetCrtcInfo(disp, res, output->crtc);
+		if (crtc)
+		{
+			for (int k = 0; k < res->nmode; ++k)
+			{
+				XRRModeInfo* mode = &res->modes[k];
+				if (mode->id == crtc->mode && mode->hTotal && mode->vTotal)
+				{
+					rate = (double)mode->dotClock / ((double)mode->hTotal * (double)mode->vTotal);
+					break;
+				}
+			}
+			XRRFreeCrtcInfo(crtc);
+		}
+	}
+	XRRFreeOutputInfo(output);
+	return rate;
+}
+
+// Whether the client owning the window runs on this machine according to WM_CLIENT_MACHINE, its
+// pid is meaningless otherwise. Windows that do not tell are assumed to be local.
+int is_local_window(Display* disp, Window win)
+{
+	Error err;
+	char* machine = get_property(disp, win, XA_STRING, "WM_CLIENT_MACHINE", NULL, &err);
+	if (!machine)
+		return 1;
+	char hostname[256] = {0};
+	// if the hostname is unknown, the window can not be told apart from local ones either
+	int local = gethostname(hostname, sizeof(hostname) - 1) != 0 || strcmp(machine, hostname) == 0;
+	free(machine);
+	return local;
+}
+
+// _NET_WM_PID of the window, set by most applications, -1 if unknown
+int get_window_pid(Display* disp, Window win)
+{
+	Error err;
+	unsigned long size = 0;
+	unsigned long* pid =
+		(unsigned long*)get_property(disp, win, XA_CARDINAL, "_NET_WM_PID", &size, &err);
+	if (!pid)
+		return -1;
+	int ret = size >= sizeof(unsigned long) ? (int)*pid : -1;
+	free(pid);
+	return ret;
+}
+
+// Capturables of the given screen of the display, the whole screen first, then its monitors and
+// finally its windows. With more than one screen names are prefixed with the number of the screen,
+// as are the ids of capturables not on the first screen.
+int create_capturables(
+	Display* disp, int screen, Capturable** capturables, int* num_monitors, int size, Error* err)
 {
+	*num_monitors = 0;
 	if (size <= 0)
 		return 0;
+	if (screen < 0 || screen >= ScreenCount(disp))
+	{
+		fill_error(
+			err, 1, "Screen %d does not exist, the display has %d.", screen, ScreenCount(disp));
+		return 0;
+	}
 
-	int screen = DefaultScreen(disp);
 	Window root = RootWindow(disp, screen);
+	// "Screen 1: " and "x11:screen1:" but nothing for a display with a single screen
+	char name_prefix[32] = "";
+	char id_prefix[32] = "x11:";
+	if (ScreenCount(disp) > 1)
+		snprintf(name_prefix, sizeof(name_prefix), "Screen %d: ", screen);
+	if (screen > 0)
+		snprintf(id_prefix, sizeof(id_prefix), "x11:screen%d:", screen);
 
 	int event_base, error_base, major, minor;
-	*num_monitors = 0;
 	XRRMonitorInfo* monitors = NULL;
 	if (XRRQueryExtension(disp, &event_base, &error_base) && XRRQueryVersion(disp, &major, &minor))
 	{
@@ -204,37 +313,64 @@ int create_capturables(Display* disp, Capturable** capturables, int* num_monitor
 	Window* client_list;
 	unsigned long client_list_size;
 
-	size_t num_windows = ((client_list = get_client_list(disp, &client_list_size, err)) == NULL)
+	size_t num_windows = ((client_list = get_client_list(disp, root, &client_list_size, err)) == NULL)
 							 ? 0
 							 : client_list_size / sizeof(Window);
 
+	// not every window manager provides the stacking order, windows are listed without it then
+	Error err_stacking;
+	unsigned long stacking_size = 0;
+	Window* stacking = (Window*)get_property(
+		disp, root, XA_WINDOW, "_NET_CLIENT_LIST_STACKING", &stacking_size, &err_stacking);
+	size_t num_stacking = stacking ? stacking_size / sizeof(Window) : 0;
+
 	size_t i = 0;
 	Capturable* c = malloc(sizeof(Capturable));
 	capturables[i] = c;
 	c->disp = disp;
 	c->screen = ScreenOfDisplay(disp, screen);
-	strncpy(c->name, "Desktop", sizeof(c->name) - 1);
+	snprintf(c->name, sizeof(c->name) - 1, "%sDesktop", name_prefix);
+	snprintf(c->id, sizeof(c->id) - 1, "%sdesktop", id_prefix);
 	c->type = WINDOW;
 	c->c.winfo.win = root;
 	c->c.winfo.is_regular_window = 0;
+	c->c.winfo.stacking = -1;
+	c->c.winfo.pid = -1;
+	c->c.winfo.refresh_rate = 0;
 	++i;
 
-	for (; i < (size_t)*num_monitors + 1 && i < (size_t)size; ++i)
+	XRRScreenResources* resources = monitors ? XRRGetScreenResourcesCurrent(disp, root) : NULL;
+	int num_monitors_total = *num_monitors;
+	*num_monitors = 0;
+	double max_refresh_rate = 0;
+	for (int k = 0; k < num_monitors_total && i < (size_t)size; ++k)
 	{
+		XRRMonitorInfo* m = &monitors[k];
+		// skip outputs that do not actually show anything
+		if (m->width <= 0 || m->height <= 0)
+			continue;
 		Capturable* c = malloc(sizeof(Capturable));
 		capturables[i] = c;
-		XRRMonitorInfo* m = &monitors[i - 1];
 		c->disp = disp;
 		c->screen = ScreenOfDisplay(disp, screen);
 		char* name = XGetAtomName(disp, m->name);
-		snprintf(c->name, sizeof(c->name) - 1, "Monitor: %s", name);
+		snprintf(c->name, sizeof(c->name) - 1, "%sMonitor: %s", name_prefix, name);
+		snprintf(c->id, sizeof(c->id) - 1, "%smonitor:%s", id_prefix, name);
 		XFree(name);
 		c->type = RECT;
 		c->c.rinfo.x = m->x;
 		c->c.rinfo.y = m->y;
 		c->c.rinfo.width = m->width;
 		c->c.rinfo.height = m->height;
+		c->c.rinfo.refresh_rate = get_monitor_refresh_rate(disp, resources, m);
+		if (c->c.rinfo.refresh_rate > max_refresh_rate)
+			max_refresh_rate = c->c.rinfo.refresh_rate;
+		++i;
+		++*num_monitors;
 	}
+	if (resources)
+		XRRFreeScreenResources(resources);
+	capturables[0]->c.winfo.refresh_rate = max_refresh_rate;
 
 	for (; i < num_windows + *num_monitors + 1 && i < (size_t)size; ++i)
 	{
@@ -251,16 +387,86 @@ int create_capturables(Display* disp, Capturable** capturables, int* num_monitor
 		c->disp = disp;
 		c->screen = ScreenOfDisplay(disp, screen);
 		c->type = WINDOW;
-		strncpy(c->name, title_utf8, sizeof(c->name) - 1);
+		snprintf(c->name, sizeof(c->name) - 1, "%s%s", name_prefix, title_utf8);
+		// window ids are only unique while the X server is running, so combine them with the class
+		// of the window
+		XClassHint class_hint;
+		if (XGetClassHint(disp, client_list[j], &class_hint))
+		{
+			snprintf(
+				c->id,
+				sizeof(c->id) - 1,
+				"%swindow:%s:%lu",
+				id_prefix,
+				class_hint.res_class ? class_hint.res_class : "",
+				client_list[j]);
+			XFree(class_hint.res_name);
+			XFree(class_hint.res_class);
+		}
+		else
+			snprintf(c->id, sizeof(c->id) - 1, "%swindow::%lu", id_prefix, client_list[j]);
 		c->c.winfo.win = client_list[j];
 		c->c.winfo.is_regular_window = 1;
+		c->c.winfo.stacking = -1;
+		c->c.winfo.refresh_rate = max_refresh_rate;
+		for (size_t k = 0; k < num_stacking; ++k)
+			if (stacking[k] == client_list[j])
+			{
+				c->c.winfo.stacking = k;
+				break;
+			}
+		c->c.winfo.pid =
+			is_local_window(disp, client_list[j]) ? get_window_pid(disp, client_list[j]) : -1;
 		free(title_utf8);
 	}
 	free(client_list);
+	free(stacking);
 	XRRFreeMonitors(monitors);
 	return i;
 }
 
+// Capturable showing the rectangle at x, y of size width x height of the whole virtual screen,
+// which may span several monitors. Fails if the rectangle does not lie within the screen.
+Capturable* create_region_capturable(
+	Display* disp, int screen, int x, int y, unsigned int width, unsigned int height, Error* err)
+{
+	if (screen < 0 || screen >= ScreenCount(disp))
+	{
+		fill_error(
+			err, 1, "Screen %d does not exist, the display has %d.", screen, ScreenCount(disp));
+		return NULL;
+	}
+	Screen* s = ScreenOfDisplay(disp, screen);
+	if (width == 0 || height == 0 || x < 0 || y < 0 || x + width > (unsigned int)s->width ||
+		y + height > (unsigned int)s->height)
+	{
+		fill_error(
+			err,
+			1,
+			"Region %ux%u+%d+%d does not lie within the screen of size %dx%d.",
+			width,
+			height,
+			x,
+			y,
+			s->width,
+			s->height);
+		return NULL;
+	}
+	Capturable* c = malloc(sizeof(Capturable));
+	memset(c, 0, sizeof(Capturable));
+	c->disp = disp;
+	c->screen = s;
+	snprintf(c->name, sizeof(c->name) - 1, "Region: %ux%u+%d+%d", width, height, x, y);
+	snprintf(c->id, sizeof(c->id) - 1, "x11:region:%ux%u+%d+%d", width, height, x, y);
+	c->type = RECT;
+	c->c.rinfo.x = x;
+	c->c.rinfo.y = y;
+	c->c.rinfo.width = width;
+	c->c.rinfo.height = height;
+	c->c.rinfo.refresh_rate = 0;
+	return c;
+}
+
 void* clone_capturable(Capturable* c)
 {
 	Capturable* c2 = malloc(sizeof(Capturable));
@@ -285,7 +491,8 @@ void get_window_geometry(
 	unsigned int bw, depth;
 	if (!XGetGeometry(disp, win, &junkroot, &junkx, &junky, width, height, &bw, &depth))
 	{
-		ERROR(err, 1, "Failed to get window geometry!");
+		// this only fails with BadDrawable or BadWindow, that is the window does not exist anymore
+		ERROR(err, 102, "Failed to get window geometry, the window may have been closed!");
 	}
 	XTranslateCoordinates(disp, win, junkroot, 0, 0, x, y, &junkroot);
 }
@@ -322,6 +529,7 @@ void get_geometry_relative(
 
 void client_msg(
 	Display* disp,
+	Window root,
 	Window win,
 	char* msg,
 	unsigned long data0,
@@ -346,13 +554,13 @@ void client_msg(
 	event.xclient.data.l[3] = data3;
 	event.xclient.data.l[4] = data4;
 
-	if (!XSendEvent(disp, DefaultRootWindow(disp), False, mask, &event))
+	if (!XSendEvent(disp, root, False, mask, &event))
 	{
 		ERROR(err, 1, "Cannot send %s event.", msg);
 	}
 }
 
-void activate_window(Display* disp, WindowInfo* winfo, Error* err)
+void activate_window(Display* disp, Window root, WindowInfo* winfo, Error* err)
 {
 	// do not activate windows like the root window or root windows of a screen
 	if (!winfo->is_regular_window)
@@ -362,7 +570,7 @@ void activate_window(Display* disp, WindowInfo* winfo, Error* err)
 	unsigned long size;
 
 	active_window = (Window*)get_property(
-		disp, DefaultRootWindow(disp), XA_WINDOW, "_NET_ACTIVE_WINDOW", &size, err);
+		disp, root, XA_WINDOW, "_NET_ACTIVE_WINDOW", &size, err);
 	if (*active_window == winfo->win)
 	{
 		// nothing to do window is active already
@@ -382,21 +590,40 @@ void activate_window(Display* disp, WindowInfo* winfo, Error* err)
 			ERROR(err, 1, "Cannot find desktop ID of the window.");
 		}
 	}
-	client_msg(disp, DefaultRootWindow(disp), "_NET_CURRENT_DESKTOP", *desktop, 0, 0, 0, 0, err);
+	client_msg(disp, root, root, "_NET_CURRENT_DESKTOP", *desktop, 0, 0, 0, 0, err);
 	free(desktop);
 	OK_OR_ABORT(err);
 
-	client_msg(disp, winfo->win, "_NET_ACTIVE_WINDOW", 0, 0, 0, 0, 0, err);
+	client_msg(disp, root, winfo->win, "_NET_ACTIVE_WINDOW", 0, 0, 0, 0, 0, err);
 	OK_OR_ABORT(err);
 	XMapRaised(disp, winfo->win);
 }
 
+// Window the window manager reports as focused on the given screen via _NET_ACTIVE_WINDOW, 0 if
+// no window has the focus. Fills err if the window manager does not tell.
+unsigned long get_active_window(Display* disp, int screen, Error* err)
+{
+	if (screen < 0 || screen >= ScreenCount(disp))
+	{
+		fill_error(err, 1, "Screen %d does not exist.", screen);
+		return 0;
+	}
+	unsigned long size;
+	Window* active_window = (Window*)get_property(
+		disp, RootWindow(disp, screen), XA_WINDOW, "_NET_ACTIVE_WINDOW", &size, err);
+	if (!active_window)
+		return 0;
+	Window win = size >= sizeof(Window) ? *active_window : 0;
+	free(active_window);
+	return win;
+}
+
 void capturable_before_input(Capturable* cap, Error* err)
 {
 	switch (cap->type)
 	{
 	case WINDOW:
-		activate_window(cap->disp, &cap->c.winfo, err);
+		activate_window(cap->disp, RootWindowOfScreen(cap->screen), &cap->c.winfo, err);
 		break;
 	case RECT:
 		break;
@@ -405,6 +632,65 @@ void capturable_before_input(Capturable* cap, Error* err)
 
 const char* get_capturable_name(Capturable* c) { return c->name; }
 
+const char* get_capturable_id(Capturable* c) { return c->id; }
+
+double get_capturable_refresh_rate(Capturable* c)
+{
+	return c->type == RECT ? c->c.rinfo.refresh_rate : c->c.winfo.refresh_rate;
+}
+
+int get_capturable_stacking(Capturable* c) { return c->type == WINDOW ? c->c.winfo.stacking : -1; }
+
+int get_capturable_pid(Capturable* c) { return c->type == WINDOW ? c->c.winfo.pid : -1; }
+
+// Whether the window is currently minimized or hidden otherwise, for example because it is on
+// another virtual desktop, according to _NET_WM_STATE_HIDDEN. Returns 1 if it is hidden, 0 if not
+// or if the window manager does not tell and -1 for capturables that are not windows.
+int capturable_is_minimized(Capturable* c)
+{
+	if (c->type != WINDOW || !c->c.winfo.is_regular_window)
+		return -1;
+	Error err;
+	unsigned long size = 0;
+	Atom* state =
+		(Atom*)get_property(c->disp, c->c.winfo.win, XA_ATOM, "_NET_WM_STATE", &size, &err);
+	if (!state)
+		return 0;
+	Atom hidden = XInternAtom(c->disp, "_NET_WM_STATE_HIDDEN", False);
+	int minimized = 0;
+	for (size_t i = 0; i < size / sizeof(Atom); ++i)
+		if (state[i] == hidden)
+			minimized = 1;
+	free(state);
+	return minimized;
+}
+
+// Scale factor applications lay out their contents with, taken from Xft.dpi as this is how desktop
+// environments configure HiDPI on X11. The X server itself knows nothing about logical pixels, so
+// this applies to all capturables alike. Returns 0 if Xft.dpi is not set.
+double get_capturable_scale_factor(Capturable* c)
+{
+	char* resources = XResourceManagerString(c->disp);
+	if (!resources)
+		return 0.0;
+	XrmInitialize();
+	XrmDatabase db = XrmGetStringDatabase(resources);
+	if (!db)
+		return 0.0;
+	char* type;
+	XrmValue value;
+	double scale_factor = 0.0;
+	if (XrmGetResource(db, "Xft.dpi", "Xft.Dpi", &type, &value) && value.addr)
+	{
+		double dpi = atof(value.addr);
+		// 96 dpi is the default that corresponds to no scaling
+		if (dpi > 0.0)
+			scale_factor = dpi / 96.0;
+	}
+	XrmDestroyDatabase(db);
+	return scale_factor;
+}
+
 void map_input_device_to_entire_screen(Display* disp, const char* device_name, int pen, Error* err)
 {
 
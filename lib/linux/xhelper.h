@@ -17,6 +17,13 @@ typedef struct WindowInfo
 {
 	Window win;
 	int is_regular_window;
+	// position in the stacking order from bottom to top when the window has been listed, -1 if
+	// unknown
+	int stacking;
+	// process that created the window, _NET_WM_PID, -1 if unknown
+	int pid;
+	// in Hz of the fastest monitor of the screen, the window can not change faster, 0 if unknown
+	double refresh_rate;
 } WindowInfo;
 
 typedef struct RectInfo
@@ -25,6 +32,8 @@ typedef struct RectInfo
 	int y;
 	unsigned int width;
 	unsigned int height;
+	// in Hz, 0 if unknown
+	double refresh_rate;
 } RectInfo;
 
 typedef enum CaptureType
@@ -37,6 +46,8 @@ typedef struct Capturable
 {
 	CaptureType type;
 	char name[128];
+	// stable identifier that allows to find the same capturable again after a restart
+	char id[192];
 	Display* disp;
 	Screen* screen;
 	union
@@ -46,6 +57,15 @@ typedef struct Capturable
 	} c;
 } Capturable;
 
+// Keep the process running if the connection to the X server is lost, libX11 exits otherwise. The
+// display is unusable afterwards and all requests fail, a new connection has to be opened instead.
+void x11_watch_connection(Display* disp);
+
+// Number of connections to the X server lost since starting, a connection opened while this was n
+// has been lost once it is larger than n. All connections go to the same server, so they are lost
+// together.
+int x11_connection_losses();
+
 char* get_property(
 	Display* disp, Window win, Atom xa_prop_type, char* prop_name, unsigned long* size, Error* err);
 
@@ -0,0 +1,334 @@
+#define COBJMACROS
+#include <windows.h>
+
+#include <d3d11.h>
+#include <dxgi1_2.h>
+
+#include <stdlib.h>
+#include <string.h>
+
+#include "../error.h"
+
+// keep in sync with COutputInfo in src/capturable/dxgi.rs
+struct OutputInfo
+{
+	unsigned int adapter;
+	unsigned int output;
+	// for example \\.\DISPLAY1, UTF-8 encoded
+	char name[128];
+	// position and size on the virtual desktop
+	int x;
+	int y;
+	unsigned int width;
+	unsigned int height;
+};
+
+struct Image
+{
+	char* data;
+	unsigned int width;
+	unsigned int height;
+	// bytes per row
+	unsigned int stride;
+};
+
+struct DxgiCaptureContext
+{
+	unsigned int adapter;
+	unsigned int output;
+	ID3D11Device* device;
+	ID3D11DeviceContext* device_ctx;
+	IDXGIOutputDuplication* duplication;
+	// CPU readable copy of the last frame
+	ID3D11Texture2D* staging;
+	unsigned int width;
+	unsigned int height;
+	int mapped;
+	int has_frame;
+	int last_acquire_failed;
+};
+
+typedef struct DxgiCaptureContext DxgiCaptureContext;
+
+int get_outputs(struct OutputInfo* infos, int max_infos, Error* err)
+{
+	IDXGIFactory1* factory;
+	HRESULT hr = CreateDXGIFactory1(&IID_IDXGIFactory1, (void**)&factory);
+	if (FAILED(hr))
+	{
+		fill_error(err, 1, "Failed to create DXGI factory, HRESULT: 0x%lx", hr);
+		return 0;
+	}
+
+	int num_infos = 0;
+	IDXGIAdapter1* adapter;
+	for (UINT i = 0; num_infos < max_infos &&
+					 IDXGIFactory1_EnumAdapters1(factory, i, &adapter) != DXGI_ERROR_NOT_FOUND;
+		 ++i)
+	{
+		IDXGIOutput* output;
+		for (UINT j = 0; num_infos < max_infos &&
+						 IDXGIAdapter1_EnumOutputs(adapter, j, &output) != DXGI_ERROR_NOT_FOUND;
+			 ++j)
+		{
+			DXGI_OUTPUT_DESC desc;
+			if (SUCCEEDED(IDXGIOutput_GetDesc(output, &desc)) && desc.AttachedToDesktop)
+			{
+				RECT r = desc.DesktopCoordinates;
+				// skip outputs that are not used
+				if (r.right > r.left && r.bottom > r.top)
+				{
+					struct OutputInfo* info = &infos[num_infos++];
+					info->adapter = i;
+					info->output = j;
+					info->x = r.left;
+					info->y = r.top;
+					info->width = r.right - r.left;
+					info->height = r.bottom - r.top;
+					if (WideCharToMultiByte(
+							CP_UTF8,
+							0,
+							desc.DeviceName,
+							-1,
+							info->name,
+							sizeof(info->name),
+							NULL,
+							NULL) == 0)
+						snprintf(info->name, sizeof(info->name), "%u:%u", i, j);
+				}
+			}
+			IDXGIOutput_Release(output);
+		}
+		IDXGIAdapter1_Release(adapter);
+	}
+	IDXGIFactory1_Release(factory);
+	return num_infos;
+}
+
+static void release_staging(DxgiCaptureContext* ctx)
+{
+	if (ctx->mapped)
+	{
+		ID3D11DeviceContext_Unmap(ctx->device_ctx, (ID3D11Resource*)ctx->staging, 0);
+		ctx->mapped = 0;
+	}
+	if (ctx->staging)
+	{
+		ID3D11Texture2D_Release(ctx->staging);
+		ctx->staging = NULL;
+	}
+	ctx->has_frame = 0;
+}
+
+static void init_duplication(DxgiCaptureContext* ctx, Error* err)
+{
+	IDXGIDevice* dxgi_device;
+	IDXGIAdapter* adapter;
+	IDXGIOutput* output;
+	IDXGIOutput1* output1;
+
+	HRESULT hr = ID3D11Device_QueryInterface(ctx->device, &IID_IDXGIDevice, (void**)&dxgi_device);
+	if (FAILED(hr))
+		ERROR(err, 1, "Failed to get DXGI device, HRESULT: 0x%lx", hr);
+	hr = IDXGIDevice_GetAdapter(dxgi_device, &adapter);
+	IDXGIDevice_Release(dxgi_device);
+	if (FAILED(hr))
+		ERROR(err, 1, "Failed to get DXGI adapter, HRESULT: 0x%lx", hr);
+	hr = IDXGIAdapter_EnumOutputs(adapter, ctx->output, &output);
+	IDXGIAdapter_Release(adapter);
+	if (FAILED(hr))
+		ERROR(err, 102, "Output %u not found, HRESULT: 0x%lx", ctx->output, hr);
+	hr = IDXGIOutput_QueryInterface(output, &IID_IDXGIOutput1, (void**)&output1);
+	IDXGIOutput_Release(output);
+	if (FAILED(hr))
+		ERROR(err, 103, "Desktop duplication requires at least Windows 8.");
+	hr = IDXGIOutput1_DuplicateOutput(output1, (IUnknown*)ctx->device, &ctx->duplication);
+	IDXGIOutput1_Release(output1);
+	if (hr == DXGI_ERROR_UNSUPPORTED)
+		ERROR(err, 103, "Desktop duplication is not supported for this output.");
+	// E_ACCESSDENIED happens while the secure desktop (UAC prompts, lock screen) is shown and
+	// DXGI_ERROR_NOT_CURRENTLY_AVAILABLE if too many applications duplicate the output, both may
+	// go away again
+	if (FAILED(hr))
+		ERROR(err, 2, "Failed to duplicate output, HRESULT: 0x%lx", hr);
+}
+
+DxgiCaptureContext* start_dxgi_capture(unsigned int adapter_idx, unsigned int output_idx, Error* err)
+{
+	IDXGIFactory1* factory;
+	IDXGIAdapter1* adapter;
+	HRESULT hr = CreateDXGIFactory1(&IID_IDXGIFactory1, (void**)&factory);
+	if (FAILED(hr))
+	{
+		fill_error(err, 1, "Failed to create DXGI factory, HRESULT: 0x%lx", hr);
+		return NULL;
+	}
+	hr = IDXGIFactory1_EnumAdapters1(factory, adapter_idx, &adapter);
+	IDXGIFactory1_Release(factory);
+	if (FAILED(hr))
+	{
+		fill_error(err, 102, "Adapter %u not found, HRESULT: 0x%lx", adapter_idx, hr);
+		return NULL;
+	}
+
+	DxgiCaptureContext* ctx = malloc(sizeof(DxgiCaptureContext));
+	if (!ctx)
+	{
+		IDXGIAdapter1_Release(adapter);
+		fill_error(err, 1, "Failed to allocate capture context!");
+		return NULL;
+	}
+	memset(ctx, 0, sizeof(DxgiCaptureContext));
+	ctx->adapter = adapter_idx;
+	ctx->output = output_idx;
+
+	// the driver type has to be unknown if an adapter is given
+	hr = D3D11CreateDevice(
+		(IDXGIAdapter*)adapter,
+		D3D_DRIVER_TYPE_UNKNOWN,
+		NULL,
+		0,
+		NULL,
+		0,
+		D3D11_SDK_VERSION,
+		&ctx->device,
+		NULL,
+		&ctx->device_ctx);
+	IDXGIAdapter1_Release(adapter);
+	if (FAILED(hr))
+	{
+		fill_error(err, 1, "Failed to create D3D11 device, HRESULT: 0x%lx", hr);
+		free(ctx);
+		return NULL;
+	}
+
+	init_duplication(ctx, err);
+	if (err->code)
+	{
+		ID3D11DeviceContext_Release(ctx->device_ctx);
+		ID3D11Device_Release(ctx->device);
+		free(ctx);
+		return NULL;
+	}
+	return ctx;
+}
+
+void capture_dxgi(DxgiCaptureContext* ctx, struct Image* img, Error* err)
+{
+	if (ctx->mapped)
+	{
+		ID3D11DeviceContext_Unmap(ctx->device_ctx, (ID3D11Resource*)ctx->staging, 0);
+		ctx->mapped = 0;
+	}
+
+	if (!ctx->duplication)
+	{
+		// duplication has been lost previously and could not be recreated yet
+		init_duplication(ctx, err);
+		OK_OR_ABORT(err);
+	}
+
+	DXGI_OUTDUPL_FRAME_INFO frame_info;
+	IDXGIResource* resource;
+	// only wait for a new frame if there is no old frame to fall back to
+	HRESULT hr = IDXGIOutputDuplication_AcquireNextFrame(
+		ctx->duplication, ctx->has_frame ? 0 : 500, &frame_info, &resource);
+	if (hr == DXGI_ERROR_ACCESS_LOST)
+	{
+		// happens if the resolution changes, a fullscreen application starts or the desktop
+		// switches, the duplication has to be recreated and the new frame may have a different size
+		IDXGIOutputDuplication_Release(ctx->duplication);
+		ctx->duplication = NULL;
+		release_staging(ctx);
+		ERROR(err, 2, "Access to the duplicated output has been lost, reinitializing.");
+	}
+	if (hr == DXGI_ERROR_WAIT_TIMEOUT)
+	{
+		// nothing changed on screen
+		if (!ctx->has_frame)
+			ERROR(err, 2, "Timeout while waiting for the first frame.");
+	}
+	else if (FAILED(hr))
+	{
+		int last_acquire_failed = ctx->last_acquire_failed;
+		ctx->last_acquire_failed = 1;
+		// only print an error once to avoid spamming the logs
+		ERROR(
+			err,
+			last_acquire_failed ? 2 : 1,
+			"Failed to acquire next frame, HRESULT: 0x%lx",
+			hr);
+	}
+	else
+	{
+		ctx->last_acquire_failed = 0;
+		ID3D11Texture2D* tex;
+		hr = IDXGIResource_QueryInterface(resource, &IID_ID3D11Texture2D, (void**)&tex);
+		IDXGIResource_Release(resource);
+		if (FAILED(hr))
+		{
+			IDXGIOutputDuplication_ReleaseFrame(ctx->duplication);
+			ERROR(err, 1, "Failed to get texture of frame, HRESULT: 0x%lx", hr);
+		}
+
+		D3D11_TEXTURE2D_DESC desc;
+		ID3D11Texture2D_GetDesc(tex, &desc);
+		if (desc.Format != DXGI_FORMAT_B8G8R8A8_UNORM)
+		{
+			ID3D11Texture2D_Release(tex);
+			IDXGIOutputDuplication_ReleaseFrame(ctx->duplication);
+			ERROR(err, 103, "Unsupported pixel format: %d, only BGRA is supported.", desc.Format);
+		}
+
+		if (!ctx->staging || desc.Width != ctx->width || desc.Height != ctx->height)
+		{
+			release_staging(ctx);
+			desc.MipLevels = 1;
+			desc.ArraySize = 1;
+			desc.SampleDesc.Count = 1;
+			desc.SampleDesc.Quality = 0;
+			desc.Usage = D3D11_USAGE_STAGING;
+			desc.BindFlags = 0;
+			desc.CPUAccessFlags = D3D11_CPU_ACCESS_READ;
+			desc.MiscFlags = 0;
+			hr = ID3D11Device_CreateTexture2D(ctx->device, &desc, NULL, &ctx->staging);
+			if (FAILED(hr))
+			{
+				ctx->staging = NULL;
+				ID3D11Texture2D_Release(tex);
+				IDXGIOutputDuplication_ReleaseFrame(ctx->duplication);
+				ERROR(err, 1, "Failed to create staging texture, HRESULT: 0x%lx", hr);
+			}
+			ctx->width = desc.Width;
+			ctx->height = desc.Height;
+		}
+
+		ID3D11DeviceContext_CopyResource(
+			ctx->device_ctx, (ID3D11Resource*)ctx->staging, (ID3D11Resource*)tex);
+		ID3D11Texture2D_Release(tex);
+		IDXGIOutputDuplication_ReleaseFrame(ctx->duplication);
+		ctx->has_frame = 1;
+	}
+
+	D3D11_MAPPED_SUBRESOURCE mapped;
+	hr = ID3D11DeviceContext_Map(
+		ctx->device_ctx, (ID3D11Resource*)ctx->staging, 0, D3D11_MAP_READ, 0, &mapped);
+	if (FAILED(hr))
+		ERROR(err, 1, "Failed to map staging texture, HRESULT: 0x%lx", hr);
+	ctx->mapped = 1;
+
+	img->data = mapped.pData;
+	img->width = ctx->width;
+	img->height = ctx->height;
+	img->stride = mapped.RowPitch;
+}
+
+void stop_dxgi_capture(DxgiCaptureContext* ctx)
+{
+	release_staging(ctx);
+	if (ctx->duplication)
+		IDXGIOutputDuplication_Release(ctx->duplication);
+	ID3D11DeviceContext_Release(ctx->device_ctx);
+	ID3D11Device_Release(ctx->device);
+	free(ctx);
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// What a client may control. Every client receives the video, input it is not permitted to send
+/// is ignored by the server.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum InputPermission {
+    /// Watch only.
+    Viewer,
+    /// Mouse, touch, stylus and scrolling but neither keyboard nor clipboard.
+    Pointer,
+    /// All input.
+    #[default]
+    Full,
+}
+
+impl FromStr for InputPermission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "viewer" => Ok(Self::Viewer),
+            "pointer" => Ok(Self::Pointer),
+            "full" => Ok(Self::Full),
+            _ => Err(format!(
+                "Unknown input permission: '{}', expected 'viewer', 'pointer' or 'full'.",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for InputPermission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Viewer => "viewer",
+            Self::Pointer => "pointer",
+            Self::Full => "full",
+        })
+    }
+}
+
+/// Single-use access tokens with an expiry that can be handed out instead of the access code.
+///
+/// A token stays valid until it expires, is revoked or is used to authenticate a websocket
+/// connection. The tokens are kept in memory only, so restarting Weylus revokes all of them.
+#[derive(Clone, Default)]
+pub struct AccessTokens {
+    tokens: Arc<Mutex<HashMap<String, (Instant, InputPermission)>>>,
+}
+
+impl AccessTokens {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new token that is valid for `ttl`, clients connecting with it get `permission`.
+    /// Fails if the token would expire too far in the future to be represented.
+    pub fn create(&self, ttl: Duration, permission: InputPermission) -> Result<String, String> {
+        let expiry = Instant::now()
+            .checked_add(ttl)
+            .ok_or_else(|| format!("Time to live of {} seconds is too long.", ttl.as_secs()))?;
+        let token = random_token();
+        let mut tokens = self.tokens.lock().unwrap();
+        Self::sweep(&mut tokens);
+        tokens.insert(token.clone(), (expiry, permission));
+        Ok(token)
+    }
+
+    /// Check if the token is valid without using it up.
+    pub fn is_valid(&self, token: &str) -> bool {
+        let mut tokens = self.tokens.lock().unwrap();
+        Self::sweep(&mut tokens);
+        tokens.contains_key(token)
+    }
+
+    /// Check if the token is valid and revoke it, so it can not be used again. Returns the
+    /// permission the token grants if it was valid.
+    pub fn consume(&self, token: &str) -> Option<InputPermission> {
+        let mut tokens = self.tokens.lock().unwrap();
+        Self::sweep(&mut tokens);
+        tokens.remove(token).map(|(_, permission)| permission)
+    }
+
+    /// Revoke the token, returns false if there is no such token.
+    pub fn revoke(&self, token: &str) -> bool {
+        self.consume(token).is_some()
+    }
+
+    // remove all expired tokens
+    fn sweep(tokens: &mut HashMap<String, (Instant, InputPermission)>) {
+        let now = Instant::now();
+        tokens.retain(|_, (expiry, _)| *expiry > now);
+    }
+}
+
+/// Input permission of every connected client. This is shared between the websocket server, which
+/// enforces it, and the webserver, which allows changing it while clients are connected.
+#[derive(Clone, Default)]
+pub struct ClientPermissions {
+    clients: Arc<Mutex<HashMap<SocketAddr, InputPermission>>>,
+}
+
+impl ClientPermissions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Permission of the client, clients that are not known may not send any input.
+    pub fn get(&self, client: &SocketAddr) -> InputPermission {
+        self.clients
+            .lock()
+            .unwrap()
+            .get(client)
+            .copied()
+            .unwrap_or(InputPermission::Viewer)
+    }
+
+    /// Add a client that just authenticated.
+    pub fn insert(&self, client: SocketAddr, permission: InputPermission) {
+        self.clients.lock().unwrap().insert(client, permission);
+    }
+
+    /// Change the permission of a connected client, returns false if there is no such client.
+    pub fn set(&self, client: &SocketAddr, permission: InputPermission) -> bool {
+        match self.clients.lock().unwrap().get_mut(client) {
+            Some(p) => {
+                *p = permission;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Keep the permission of a client that resumed its session with a new connection.
+    pub fn moved(&self, from: &SocketAddr, to: SocketAddr) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(permission) = clients.remove(from) {
+            clients.insert(to, permission);
+        }
+    }
+
+    pub fn remove(&self, client: &SocketAddr) {
+        self.clients.lock().unwrap().remove(client);
+    }
+
+    /// All clients with their permission, sorted by address.
+    pub fn list(&self) -> Vec<(SocketAddr, InputPermission)> {
+        let mut clients: Vec<_> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(client, permission)| (*client, *permission))
+            .collect();
+        clients.sort();
+        clients
+    }
+}
+
+/// Random alphanumeric string that is hard to guess.
+pub fn random_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_ttl_overflow() {
+        let tokens = AccessTokens::new();
+        assert!(tokens.create(Duration::MAX, InputPermission::Full).is_err());
+        // the tokens are still usable afterwards
+        let token = tokens
+            .create(Duration::from_secs(300), InputPermission::Viewer)
+            .unwrap();
+        assert_eq!(tokens.consume(&token), Some(InputPermission::Viewer));
+        assert!(!tokens.is_valid(&token));
+    }
+}
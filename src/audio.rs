@@ -0,0 +1,245 @@
+//! Capturing the sound played on the host, see `--audio`. It is captured via PulseAudio, which
+//! PipeWire provides as well, encoded as Opus by GStreamer and handed out packet by packet to be
+//! sent alongside the video.
+
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_app::AppSink;
+use tracing::{debug, info, warn};
+
+pub const SAMPLE_RATE: u32 = 48000;
+pub const CHANNELS: u32 = 2;
+// in bit/s, enough for music in stereo
+const BITRATE: i32 = 96000;
+// how long to wait before capturing again after the source failed or disappeared
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// how long pulling a packet blocks, this bounds how long stopping takes
+const PULL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// What to capture, given by the server's config.
+#[derive(Debug, Clone)]
+pub struct AudioOptions {
+    /// PulseAudio source, the monitor of the default output if none, so whatever the host plays.
+    pub device: Option<String>,
+    /// Factor the sound is amplified by, 1 keeps it as it is.
+    pub volume: f64,
+}
+
+/// Whether the GStreamer elements required for capturing audio are installed.
+pub fn audio_available() -> bool {
+    [
+        "pulsesrc",
+        "audioconvert",
+        "audioresample",
+        "volume",
+        "opusenc",
+        "appsink",
+    ]
+    .iter()
+    .all(|name| gst::ElementFactory::find(name).is_some())
+}
+
+pub enum AudioEvent<'a> {
+    // capturing (re)started, packets follow
+    Started,
+    // Opus packet of 20 ms of sound captured at the given time
+    Packet(&'a [u8], Instant),
+    // the source failed or disappeared, capturing is retried after a while
+    Stopped(String),
+}
+
+#[derive(Debug)]
+pub struct AudioError(String);
+
+impl std::fmt::Display for AudioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self(s) = self;
+        write!(f, "{}", s)
+    }
+}
+
+impl Error for AudioError {}
+
+struct AudioPipeline {
+    pipeline: gst::Pipeline,
+    appsink: AppSink,
+}
+
+impl AudioPipeline {
+    fn new(options: &AudioOptions) -> Result<Self, Box<dyn Error>> {
+        let pipeline = gst::Pipeline::new(None);
+
+        let src = gst::ElementFactory::make("pulsesrc", None)?;
+        src.set_property(
+            "device",
+            &options.device.as_deref().unwrap_or("@DEFAULT_MONITOR@"),
+        );
+        let convert = gst::ElementFactory::make("audioconvert", None)?;
+        let resample = gst::ElementFactory::make("audioresample", None)?;
+        let volume = gst::ElementFactory::make("volume", None)?;
+        volume.set_property("volume", &options.volume);
+        let filter = gst::ElementFactory::make("capsfilter", None)?;
+        filter.set_property(
+            "caps",
+            &gst::Caps::builder("audio/x-raw")
+                .field("rate", SAMPLE_RATE as i32)
+                .field("channels", CHANNELS as i32)
+                .build(),
+        );
+        let encoder = gst::ElementFactory::make("opusenc", None)?;
+        encoder.set_property("bitrate", &BITRATE);
+        let sink = gst::ElementFactory::make("appsink", None)?;
+        // the source is live already, waiting for the clock only adds latency
+        sink.set_property("sync", &false);
+        sink.set_property("max-buffers", &50u32);
+
+        let elements = [&src, &convert, &resample, &volume, &filter, &encoder, &sink];
+        pipeline.add_many(&elements)?;
+        gst::Element::link_many(&elements)?;
+        let appsink = sink
+            .dynamic_cast::<AppSink>()
+            .map_err(|_| AudioError("Sink element is expected to be an appsink!".into()))?;
+
+        pipeline.set_state(gst::State::Playing)?;
+        Ok(Self { pipeline, appsink })
+    }
+
+    // The timestamp of buffers is the running time of the pipeline at which they have been
+    // captured, the difference to the current running time is their age.
+    fn buffer_age(&self, buf: &gst::BufferRef) -> Option<Duration> {
+        let running_time = self
+            .pipeline
+            .clock()?
+            .time()?
+            .checked_sub(self.pipeline.base_time()?)?;
+        let age = running_time.checked_sub(buf.pts()?)?;
+        Some(Duration::from_nanos(age.nseconds()))
+    }
+
+    // errors or the end of the stream posted by the pipeline, for example because the source
+    // disappeared
+    fn check_bus(&self) -> Result<(), AudioError> {
+        let bus = match self.pipeline.bus() {
+            Some(bus) => bus,
+            None => return Ok(()),
+        };
+        match bus.pop_filtered(&[gst::MessageType::Error, gst::MessageType::Eos]) {
+            Some(msg) => match msg.view() {
+                gst::MessageView::Error(err) => Err(AudioError(err.error().to_string())),
+                _ => Err(AudioError("The audio source stopped.".into())),
+            },
+            None => Ok(()),
+        }
+    }
+
+    // hand out packets until stopped or the pipeline fails
+    fn run(&self, stop: &AtomicBool, notify: &mut dyn FnMut(AudioEvent)) -> Result<(), AudioError> {
+        while !stop.load(Ordering::Relaxed) {
+            self.check_bus()?;
+            let sample = match self.appsink.try_pull_sample(gst::ClockTime::from_mseconds(
+                PULL_TIMEOUT.as_millis() as u64,
+            )) {
+                Some(sample) => sample,
+                None if self.appsink.is_eos() => {
+                    self.check_bus()?;
+                    return Err(AudioError("The audio source stopped.".into()));
+                }
+                None => continue,
+            };
+            let buf = match sample.buffer() {
+                Some(buf) => buf,
+                None => continue,
+            };
+            let now = Instant::now();
+            let captured_at = self
+                .buffer_age(buf)
+                .and_then(|age| now.checked_sub(age))
+                .unwrap_or(now);
+            let map = buf
+                .map_readable()
+                .map_err(|_| AudioError("Failed to map buffer.".into()))?;
+            notify(AudioEvent::Packet(map.as_slice(), captured_at));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AudioPipeline {
+    fn drop(&mut self) {
+        if let Err(err) = self.pipeline.set_state(gst::State::Null) {
+            warn!("Failed to stop GStreamer pipeline: {}.", err);
+        }
+    }
+}
+
+/// Captures and encodes audio in a thread of its own until this is dropped. If the source fails
+/// or disappears, for example because the sound server restarted, capturing is retried every few
+/// seconds while the video goes on.
+pub struct AudioStream {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AudioStream {
+    /// Start capturing, `notify` is called from the thread of the stream.
+    pub fn start(
+        options: AudioOptions,
+        mut notify: impl FnMut(AudioEvent) + Send + 'static,
+    ) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            spawn(move || {
+                // only tell about failures once until capturing works again
+                let mut failed = false;
+                while !stop.load(Ordering::Relaxed) {
+                    let res = AudioPipeline::new(&options).map_err(|err| err.to_string());
+                    let err = match res {
+                        Ok(pipeline) => {
+                            info!("Capturing audio.");
+                            failed = false;
+                            notify(AudioEvent::Started);
+                            match pipeline.run(&stop, &mut notify) {
+                                Ok(()) => return,
+                                Err(err) => err.to_string(),
+                            }
+                        }
+                        Err(err) => err,
+                    };
+                    if failed {
+                        debug!("Failed to capture audio again: {}", err);
+                    } else {
+                        warn!("Failed to capture audio, retrying in a while: {}", err);
+                        notify(AudioEvent::Stopped(err));
+                        failed = true;
+                    }
+                    let retry_at = Instant::now() + RETRY_INTERVAL;
+                    while !stop.load(Ordering::Relaxed) && Instant::now() < retry_at {
+                        sleep(PULL_TIMEOUT);
+                    }
+                }
+            })
+        };
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for AudioStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("Audio thread panicked.");
+            }
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! C API to embed capturing and encoding into applications not written in Rust, enabled by the
+//! `capi` feature. The header `include/weylus.h` is generated from this module via
+//! `cbindgen --config cbindgen.toml --output include/weylus.h`.
+//!
+//! A session captures a screen or window and encodes every frame pulled from it as fragmented MP4:
+//!
+//! ```c
+//! CError err;
+//! weylus_init();
+//! WeylusCaptureSession* session = weylus_capture_session_new("HDMI-1", NULL, &err);
+//! if (!session) {
+//!     fprintf(stderr, "%s\n", err.error_str);
+//!     return 1;
+//! }
+//! for (;;) {
+//!     WeylusPacket* packet;
+//!     int ret = weylus_capture_session_next_packet(session, &packet, &err);
+//!     if (ret < 0)
+//!         break;
+//!     if (ret > 0) {
+//!         fwrite(packet->data, 1, packet->len, out);
+//!         weylus_packet_free(packet);
+//!     }
+//! }
+//! weylus_capture_session_free(session);
+//! ```
+//!
+//! Sessions are not thread safe, all calls on a session have to be made from the thread that
+//! created it.
+
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_uint};
+use std::rc::Rc;
+use std::time::Instant;
+
+use serde::Deserialize;
+
+#[cfg(target_os = "linux")]
+use crate::capturable::CaptureBackend;
+use crate::capturable::{find_capturable, get_capturables, new_recorder, CaptureOptions, Recorder};
+use crate::cerror::{CError, CErrorCode};
+use crate::video::{EncoderOptions, VideoEncoder};
+
+/// Options of a session, passed as JSON to `weylus_capture_session_new`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SessionOptions {
+    capture_cursor: bool,
+    // x, y, width and height in pixels
+    crop: Option<(u32, u32, u32, u32)>,
+    encoder: EncoderOptions,
+}
+
+/// Captures a screen or window and encodes it, created by `weylus_capture_session_new`.
+pub struct WeylusCaptureSession {
+    recorder: Box<dyn Recorder>,
+    encoder: Option<Box<VideoEncoder>>,
+    options: EncoderOptions,
+    // written by the encoder since the last packet has been returned
+    written: Rc<RefCell<Vec<u8>>>,
+}
+
+/// Encoded video returned by `weylus_capture_session_next_packet`.
+#[repr(C)]
+pub struct WeylusPacket {
+    /// Fragmented MP4, the first packet and the first one after the size of the video changed
+    /// start with the initialization segment.
+    pub data: *const u8,
+    pub len: usize,
+    pub width: c_uint,
+    pub height: c_uint,
+}
+
+fn set_error(err: *mut CError, e: CError) {
+    if !err.is_null() {
+        unsafe { std::ptr::write(err, e) };
+    }
+}
+
+/// Initialize the libraries used for capturing, this has to be called once before creating
+/// sessions and before the application spawns any other threads.
+#[no_mangle]
+pub extern "C" fn weylus_init() {
+    crate::init();
+}
+
+/// Start capturing the screen or window matching `capturable`, which is its id or a part of its
+/// name like with `--capturable`, NULL captures the first one found. `options` is JSON like
+/// `{"capture_cursor": true, "crop": [0, 0, 1280, 720], "encoder": {"codec": "h264",
+/// "max_bitrate": 4000}}`, where `encoder` takes the fields of `EncoderOptions`, NULL uses the
+/// defaults. Returns NULL and fills `err` on failure.
+///
+/// # Safety
+///
+/// `capturable` and `options` have to be NULL or null-terminated strings, `err` NULL or point to
+/// a `CError`.
+#[no_mangle]
+pub unsafe extern "C" fn weylus_capture_session_new(
+    capturable: *const c_char,
+    options: *const c_char,
+    err: *mut CError,
+) -> *mut WeylusCaptureSession {
+    set_error(err, CError::new());
+    let options: SessionOptions = if options.is_null() {
+        SessionOptions::default()
+    } else {
+        let parsed = CStr::from_ptr(options)
+            .to_str()
+            .map_err(|e| e.to_string())
+            .and_then(|options| serde_json::from_str(options).map_err(|e| e.to_string()));
+        match parsed {
+            Ok(options) => options,
+            Err(e) => {
+                set_error(
+                    err,
+                    CError::with_message(
+                        CErrorCode::GenericError,
+                        &format!("Invalid options: {}", e),
+                    ),
+                );
+                return std::ptr::null_mut();
+            }
+        }
+    };
+
+    let capturables = get_capturables(
+        #[cfg(target_os = "linux")]
+        CaptureBackend::Auto,
+        #[cfg(target_os = "linux")]
+        false,
+        #[cfg(target_os = "linux")]
+        options.capture_cursor,
+    );
+    let index = if capturable.is_null() {
+        (!capturables.is_empty()).then_some(0)
+    } else {
+        find_capturable(&capturables, &CStr::from_ptr(capturable).to_string_lossy())
+    };
+    let capturable = match index {
+        Some(i) => &capturables[i],
+        None => {
+            set_error(
+                err,
+                CError::with_message(CErrorCode::GenericError, "No matching capturable found."),
+            );
+            return std::ptr::null_mut();
+        }
+    };
+    let recorder = new_recorder(
+        capturable.as_ref(),
+        CaptureOptions {
+            capture_cursor: options.capture_cursor,
+            crop: options.crop,
+            ..Default::default()
+        },
+    );
+    match recorder {
+        Ok(recorder) => Box::into_raw(Box::new(WeylusCaptureSession {
+            recorder,
+            encoder: None,
+            options: options.encoder,
+            written: Rc::new(RefCell::new(Vec::new())),
+        })),
+        Err(e) => {
+            set_error(
+                err,
+                CError::with_message(
+                    CErrorCode::GenericError,
+                    &format!("Failed to start capturing: {}", e),
+                ),
+            );
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Capture a frame and encode it. Returns 1 and sets `packet` if encoded video is available, 0 if
+/// there is none yet, for example because the frame is empty or the encoder delays frames, and -1
+/// with `err` filled if capturing or encoding failed. If the capturable does not exist anymore the
+/// error code is 102 and the session should be freed. The packet has to be freed via
+/// `weylus_packet_free`.
+///
+/// # Safety
+///
+/// `session` has to be created by `weylus_capture_session_new` and not freed yet, `packet` has to
+/// point to a `WeylusPacket*` and `err` has to be NULL or point to a `CError`.
+#[no_mangle]
+pub unsafe extern "C" fn weylus_capture_session_next_packet(
+    session: *mut WeylusCaptureSession,
+    packet: *mut *mut WeylusPacket,
+    err: *mut CError,
+) -> c_int {
+    set_error(err, CError::new());
+    if session.is_null() || packet.is_null() {
+        set_error(
+            err,
+            CError::with_message(
+                CErrorCode::GenericError,
+                "Session and packet must not be NULL.",
+            ),
+        );
+        return -1;
+    }
+    *packet = std::ptr::null_mut();
+    let session = &mut *session;
+
+    let pixel_provider = match session.recorder.capture() {
+        Ok(pixel_provider) => pixel_provider,
+        Err(e) => {
+            let code = if session.recorder.is_gone() {
+                CErrorCode::CapturableGone
+            } else {
+                CErrorCode::GenericError
+            };
+            set_error(
+                err,
+                CError::with_message(code, &format!("Failed to capture frame: {}", e)),
+            );
+            return -1;
+        }
+    };
+    let (width, height) = pixel_provider.size();
+    if width == 0 || height == 0 {
+        return 0;
+    }
+    if !session.encoder.as_ref().map_or(false, |encoder| {
+        encoder.check_size(width, height, width, height)
+    }) {
+        // data the old encoder writes while shutting down does not belong to the new video
+        session.encoder = None;
+        session.written.borrow_mut().clear();
+        let written = session.written.clone();
+        match VideoEncoder::new(
+            width,
+            height,
+            width,
+            height,
+            move |data| written.borrow_mut().extend_from_slice(data),
+            session.options,
+        ) {
+            Ok(encoder) => session.encoder = Some(encoder),
+            Err(e) => {
+                set_error(err, e);
+                return -1;
+            }
+        }
+    }
+    let encoder = session.encoder.as_mut().unwrap();
+    if let Err(e) = encoder.fill(pixel_provider) {
+        set_error(err, e);
+        return -1;
+    }
+    encoder.encode_filled(Instant::now());
+
+    let data = std::mem::take(&mut *session.written.borrow_mut());
+    if data.is_empty() {
+        return 0;
+    }
+    let len = data.len();
+    let data = Box::into_raw(data.into_boxed_slice());
+    *packet = Box::into_raw(Box::new(WeylusPacket {
+        data: data as *const u8,
+        len,
+        width: width as c_uint,
+        height: height as c_uint,
+    }));
+    1
+}
+
+/// Free a packet returned by `weylus_capture_session_next_packet`, NULL is ignored.
+///
+/// # Safety
+///
+/// `packet` has to be NULL or returned by `weylus_capture_session_next_packet` and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn weylus_packet_free(packet: *mut WeylusPacket) {
+    if packet.is_null() {
+        return;
+    }
+    let packet = Box::from_raw(packet);
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+        packet.data as *mut u8,
+        packet.len,
+    )));
+}
+
+/// Stop capturing and free the session, NULL is ignored.
+///
+/// # Safety
+///
+/// `session` has to be NULL or created by `weylus_capture_session_new` and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn weylus_capture_session_free(session: *mut WeylusCaptureSession) {
+    if !session.is_null() {
+        drop(Box::from_raw(session));
+    }
+}
@@ -18,6 +18,9 @@ impl Capturable for AutoPilotCapturable {
     fn name(&self) -> String {
         "Desktop (autopilot)".into()
     }
+    fn id(&self) -> String {
+        "autopilot:desktop".into()
+    }
     fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
         Ok((0.0, 0.0, 1.0, 1.0))
     }
@@ -0,0 +1,237 @@
+//! Combine several capturables into a single frame, for example to show a few windows side by side
+//! while presenting.
+
+use std::error::Error;
+
+use tracing::debug;
+
+use crate::capturable::{Capturable, Recorder};
+use crate::video::{blend_over, Color, PixelProvider};
+
+/// How the captured images are arranged on the composited frame. Space not covered by any image
+/// shows the background.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Layout {
+    /// Next to each other from left to right, aligned at the top.
+    Horizontal,
+    /// Below each other from top to bottom, aligned at the left.
+    Vertical,
+    /// Row by row with the given number of columns. Each column is as wide as its widest image and
+    /// each row as high as its highest image.
+    Grid(usize),
+    /// Top left corner of each image in pixels, images without a position are not shown. Images
+    /// overlapping each other are drawn in order.
+    Positions(Vec<(usize, usize)>),
+}
+
+impl Layout {
+    // position of every image given its size and the size of the whole frame
+    fn arrange(&self, sizes: &[(usize, usize)]) -> (Vec<(usize, usize)>, (usize, usize)) {
+        let columns = match self {
+            Layout::Horizontal => sizes.len().max(1),
+            Layout::Vertical => 1,
+            Layout::Grid(columns) => (*columns).max(1),
+            Layout::Positions(positions) => {
+                let positions: Vec<_> = positions.iter().copied().take(sizes.len()).collect();
+                let size = positions
+                    .iter()
+                    .zip(sizes)
+                    .map(|((x, y), (w, h))| (x + w, y + h))
+                    .fold((0, 0), |(w, h), (x, y)| (w.max(x), h.max(y)));
+                return (positions, size);
+            }
+        };
+        let mut widths = vec![0; columns];
+        let mut heights = vec![0; (sizes.len() + columns - 1) / columns];
+        for (i, (w, h)) in sizes.iter().enumerate() {
+            widths[i % columns] = widths[i % columns].max(*w);
+            heights[i / columns] = heights[i / columns].max(*h);
+        }
+        let positions = (0..sizes.len())
+            .map(|i| {
+                (
+                    widths[..i % columns].iter().sum(),
+                    heights[..i / columns].iter().sum(),
+                )
+            })
+            .collect();
+        (positions, (widths.iter().sum(), heights.iter().sum()))
+    }
+}
+
+/// Several capturables shown on one frame. Every capturable is captured at the rate frames are
+/// requested, if capturing one of them fails its last image is shown. Images may differ in size
+/// and change their size, the layout is updated accordingly.
+///
+/// Input is not supported as positions on the composited frame do not map to a single screen.
+#[derive(Clone)]
+pub struct CompositeCapturable {
+    capturables: Vec<Box<dyn Capturable>>,
+    layout: Layout,
+    background: Color,
+    blend_alpha: bool,
+}
+
+impl CompositeCapturable {
+    /// Composite on a black background, ignoring the alpha channel of the images.
+    pub fn new(capturables: Vec<Box<dyn Capturable>>, layout: Layout) -> Self {
+        Self {
+            capturables,
+            layout,
+            background: Color::BLACK,
+            blend_alpha: false,
+        }
+    }
+
+    pub fn with_background(mut self, background: Color) -> Self {
+        self.background = background;
+        self
+    }
+
+    /// Blend images onto the background and the images below them according to their alpha
+    /// channel, so transparent parts of windows like rounded corners do not show black. This
+    /// should only be enabled if all captured windows have an alpha channel, windows without one
+    /// may leave it zero and would not show up at all.
+    pub fn with_alpha_blending(mut self, blend_alpha: bool) -> Self {
+        self.blend_alpha = blend_alpha;
+        self
+    }
+}
+
+impl Capturable for CompositeCapturable {
+    fn name(&self) -> String {
+        let names: Vec<_> = self.capturables.iter().map(|c| c.name()).collect();
+        format!("Composite: {}", names.join(" + "))
+    }
+
+    fn id(&self) -> String {
+        let ids: Vec<_> = self.capturables.iter().map(|c| c.id()).collect();
+        format!("composite:{}", ids.join("+"))
+    }
+
+    fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
+        Err("Composited capturables do not support input.".into())
+    }
+
+    fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn refresh_rate(&self) -> Option<f64> {
+        self.capturables
+            .iter()
+            .filter_map(|c| c.refresh_rate())
+            .reduce(f64::max)
+    }
+
+    fn scale_factor(&self) -> Option<f64> {
+        // the images are copied pixel by pixel, so the scale factor is only known if it is the same
+        // for all of them
+        let scale_factor = self.capturables.first()?.scale_factor()?;
+        self.capturables
+            .iter()
+            .all(|c| c.scale_factor() == Some(scale_factor))
+            .then_some(scale_factor)
+    }
+
+    fn recorder(&self, capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        let recorders = self
+            .capturables
+            .iter()
+            .map(|c| {
+                Ok(SubRecorder {
+                    recorder: c.recorder(capture_cursor)?,
+                    frame: Vec::new(),
+                    size: (0, 0),
+                })
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+        Ok(Box::new(CompositeRecorder {
+            recorders,
+            layout: self.layout.clone(),
+            background: self.background.bgra(),
+            blend_alpha: self.blend_alpha,
+            sizes: Vec::new(),
+            canvas: Vec::new(),
+        }))
+    }
+}
+
+struct SubRecorder {
+    recorder: Box<dyn Recorder>,
+    // last image captured as BGRA without padding
+    frame: Vec<u8>,
+    size: (usize, usize),
+}
+
+pub struct CompositeRecorder {
+    recorders: Vec<SubRecorder>,
+    layout: Layout,
+    background: [u8; 4],
+    blend_alpha: bool,
+    // sizes of the images the canvas has last been cleared for
+    sizes: Vec<(usize, usize)>,
+    canvas: Vec<u8>,
+}
+
+impl Recorder for CompositeRecorder {
+    fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+        let mut captured = false;
+        let mut last_err = None;
+        for sub in &mut self.recorders {
+            // the image is only replaced if capturing succeeds, so the last one is kept otherwise
+            match sub.recorder.capture_into(&mut sub.frame) {
+                Ok(size) => {
+                    sub.size = size;
+                    captured = true;
+                }
+                Err(err) => {
+                    debug!("Failed to capture part of composited frame: {}", err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        if !captured {
+            return Err(last_err.unwrap_or_else(|| "Nothing to composite.".into()));
+        }
+
+        let sizes: Vec<_> = self.recorders.iter().map(|sub| sub.size).collect();
+        let (positions, (width, height)) = self.layout.arrange(&sizes);
+        // images only ever overwrite their own area, so space left by images that got smaller or
+        // moved has to be cleared, blended images show what is below them and are drawn onto a
+        // clear canvas every time
+        if sizes != self.sizes || self.blend_alpha {
+            self.canvas.clear();
+            self.canvas
+                .extend(self.background.iter().cycle().take(width * height * 4));
+            self.sizes = sizes;
+        }
+        for (sub, (x, y)) in self.recorders.iter().zip(positions) {
+            let (w, h) = sub.size;
+            // nothing has been captured yet or the image is empty
+            if w == 0 || h == 0 {
+                continue;
+            }
+            for (row, src) in sub.frame.chunks_exact(w * 4).take(h).enumerate() {
+                let start = ((y + row) * width + x) * 4;
+                let dst = &mut self.canvas[start..start + w * 4];
+                if self.blend_alpha {
+                    blend_over(dst, src);
+                } else {
+                    dst.copy_from_slice(src);
+                }
+            }
+        }
+        Ok(PixelProvider::BGR0(width, height, &self.canvas))
+    }
+
+    fn set_capture_cursor(&mut self, capture_cursor: bool) {
+        for sub in &mut self.recorders {
+            sub.recorder.set_capture_cursor(capture_cursor);
+        }
+    }
+
+    fn is_gone(&self) -> bool {
+        self.recorders.iter().all(|sub| sub.recorder.is_gone())
+    }
+}
@@ -19,7 +19,32 @@ use core_graphics::{
     window::CGWindowID,
 };
 
-use crate::capturable::{Capturable, Recorder};
+use crate::capturable::{Capturable, CapturableKind, CaptureError, Recorder};
+
+#[link(name = "CoreGraphics", kind = "framework")]
+extern "C" {
+    // available since macOS 10.15
+    fn CGPreflightScreenCaptureAccess() -> bool;
+    fn CGRequestScreenCaptureAccess() -> bool;
+}
+
+/// Check if Weylus has been granted the screen recording permission, if not ask the user to grant
+/// it. Without the permission CoreGraphics only captures the desktop background and windows of
+/// Weylus itself.
+fn check_screen_capture_access() -> Result<(), CaptureError> {
+    if unsafe { CGPreflightScreenCaptureAccess() } {
+        return Ok(());
+    }
+    // this shows a dialog that points the user to the system preferences, the permission only
+    // becomes effective once Weylus has been restarted
+    unsafe { CGRequestScreenCaptureAccess() };
+    Err(CaptureError::CaptureStart(
+        "Screen recording permission has not been granted, please allow Weylus to record the \
+        screen in System Preferences > Security & Privacy > Privacy > Screen Recording and \
+        restart Weylus."
+            .into(),
+    ))
+}
 
 #[derive(Debug)]
 pub struct CGError(String);
@@ -52,6 +77,12 @@ impl Capturable for CGDisplayCapturable {
             self.display.pixels_high()
         )
     }
+    fn id(&self) -> String {
+        format!("cg:display:{}", self.display.id)
+    }
+    fn kind(&self) -> Option<CapturableKind> {
+        Some(CapturableKind::Monitor)
+    }
     fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
         let bounds = self.display.bounds();
         let (x0, y0, w, h) = screen_coordsys()?;
@@ -65,7 +96,17 @@ impl Capturable for CGDisplayCapturable {
     fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
         Ok(())
     }
+    fn scale_factor(&self) -> Option<f64> {
+        // bounds are given in points, which are logical pixels
+        let width = self.display.bounds().size.width;
+        if width > 0.0 {
+            Some(self.display.pixels_wide() as f64 / width)
+        } else {
+            None
+        }
+    }
     fn recorder(&self, capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        check_screen_capture_access()?;
         Ok(Box::new(RecorderCGDisplay::new(
             self.display,
             capture_cursor,
@@ -133,6 +174,10 @@ impl Recorder for RecorderCGDisplay {
             )))
         }
     }
+
+    fn set_capture_cursor(&mut self, capture_cursor: bool) {
+        self.capture_cursor = capture_cursor;
+    }
 }
 
 #[derive(Clone)]
@@ -175,6 +220,12 @@ impl Capturable for CGWindowCapturable {
     fn name(&self) -> String {
         self.name.clone()
     }
+    fn id(&self) -> String {
+        format!("cg:window:{}", self.id)
+    }
+    fn kind(&self) -> Option<CapturableKind> {
+        Some(CapturableKind::Window)
+    }
     fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
         Ok(self.geometry_relative)
     }
@@ -182,6 +233,7 @@ impl Capturable for CGWindowCapturable {
         self.update_geometry()
     }
     fn recorder(&self, capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        check_screen_capture_access()?;
         Ok(Box::new(RecorderCGWindow {
             img_data: None,
             capture_cursor,
@@ -229,6 +281,10 @@ impl Recorder for RecorderCGWindow {
             )))
         }
     }
+
+    fn set_capture_cursor(&mut self, capture_cursor: bool) {
+        self.capture_cursor = capture_cursor;
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,208 @@
+use std::error::Error;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::slice::from_raw_parts;
+
+use crate::capturable::{Capturable, CapturableKind, CaptureError, Recorder};
+use crate::cerror::CError;
+use crate::video::PixelProvider;
+
+use tracing::debug;
+
+extern "C" {
+    fn get_outputs(infos: *mut COutputInfo, max_infos: c_int, err: *mut CError) -> c_int;
+    fn start_dxgi_capture(adapter: c_uint, output: c_uint, err: *mut CError) -> *mut c_void;
+    fn capture_dxgi(handle: *mut c_void, img: *mut CImage, err: *mut CError);
+    fn stop_dxgi_capture(handle: *mut c_void);
+}
+
+const MAX_OUTPUTS: usize = 16;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct COutputInfo {
+    adapter: c_uint,
+    output: c_uint,
+    name: [c_char; 128],
+    x: c_int,
+    y: c_int,
+    width: c_uint,
+    height: c_uint,
+}
+
+#[derive(Clone)]
+pub struct DxgiCapturable {
+    adapter: u32,
+    output: u32,
+    // device name of the output, for example \\.\DISPLAY1
+    device_name: String,
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+    geometry_relative: (f64, f64, f64, f64),
+}
+
+impl Capturable for DxgiCapturable {
+    fn name(&self) -> String {
+        format!(
+            "Monitor {} (DXGI, {}x{})",
+            self.device_name.trim_start_matches("\\\\.\\"),
+            self.width,
+            self.height
+        )
+    }
+
+    fn id(&self) -> String {
+        format!("dxgi:{}", self.device_name)
+    }
+
+    fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
+        Ok(self.geometry_relative)
+    }
+
+    fn geometry_virtual_desktop(&self) -> Option<(i32, i32, u32, u32)> {
+        Some((self.x, self.y, self.width, self.height))
+    }
+
+    fn kind(&self) -> Option<CapturableKind> {
+        Some(CapturableKind::Monitor)
+    }
+
+    fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn recorder(&self, _capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        match RecorderDxgi::new(self.adapter, self.output) {
+            Ok(recorder) => Ok(Box::new(recorder)),
+            Err(err) => Err(Box::new(CaptureError::CaptureStart(err.to_string()))),
+        }
+    }
+}
+
+/// List all outputs that are part of the desktop.
+pub fn get_capturables() -> Result<Vec<DxgiCapturable>, CError> {
+    let mut err = CError::new();
+    let mut infos = [COutputInfo {
+        adapter: 0,
+        output: 0,
+        name: [0; 128],
+        x: 0,
+        y: 0,
+        width: 0,
+        height: 0,
+    }; MAX_OUTPUTS];
+    let num_infos = unsafe { get_outputs(infos.as_mut_ptr(), MAX_OUTPUTS as c_int, &mut err) };
+    if err.is_err() {
+        return Err(err);
+    }
+    let infos = &infos[..num_infos as usize];
+
+    // bounding box of the virtual desktop
+    let x0 = infos.iter().map(|i| i.x).min().unwrap_or(0) as f64;
+    let y0 = infos.iter().map(|i| i.y).min().unwrap_or(0) as f64;
+    let x1 = infos
+        .iter()
+        .map(|i| i.x + i.width as i32)
+        .max()
+        .unwrap_or(1) as f64;
+    let y1 = infos
+        .iter()
+        .map(|i| i.y + i.height as i32)
+        .max()
+        .unwrap_or(1) as f64;
+    let (w, h) = (x1 - x0, y1 - y0);
+
+    Ok(infos
+        .iter()
+        .map(|info| DxgiCapturable {
+            adapter: info.adapter,
+            output: info.output,
+            device_name: unsafe { CStr::from_ptr(info.name.as_ptr()) }
+                .to_string_lossy()
+                .into(),
+            x: info.x,
+            y: info.y,
+            width: info.width,
+            height: info.height,
+            geometry_relative: (
+                (info.x as f64 - x0) / w,
+                (info.y as f64 - y0) / h,
+                info.width as f64 / w,
+                info.height as f64 / h,
+            ),
+        })
+        .collect())
+}
+
+#[repr(C)]
+struct CImage {
+    data: *const u8,
+    width: c_uint,
+    height: c_uint,
+    stride: c_uint,
+}
+
+/// Captures an output using the DXGI Desktop Duplication API, the cursor is never part of the
+/// captured image.
+pub struct RecorderDxgi {
+    handle: *mut c_void,
+    img: CImage,
+}
+
+impl RecorderDxgi {
+    pub fn new(adapter: u32, output: u32) -> Result<Self, CError> {
+        let mut err = CError::new();
+        let handle = unsafe { start_dxgi_capture(adapter, output, &mut err) };
+        if err.is_err() {
+            Err(err)
+        } else {
+            Ok(Self {
+                handle,
+                img: CImage {
+                    data: std::ptr::null(),
+                    width: 0,
+                    height: 0,
+                    stride: 0,
+                },
+            })
+        }
+    }
+}
+
+impl Drop for RecorderDxgi {
+    fn drop(&mut self) {
+        unsafe { stop_dxgi_capture(self.handle) };
+    }
+}
+
+impl Recorder for RecorderDxgi {
+    fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+        let mut err = CError::new();
+        unsafe { capture_dxgi(self.handle, &mut self.img, &mut err) };
+        if err.is_err() {
+            self.img.data = std::ptr::null();
+            // a changed resolution is picked up by the next successful capture as the frame size
+            // changes
+            return Err(Box::new(CaptureError::from(err)));
+        }
+        if self.img.data.is_null() {
+            return Err(Box::new(CaptureError::CaptureFrame(
+                "Captured image is empty!".into(),
+            )));
+        }
+        let (width, height, stride) = (
+            self.img.width as usize,
+            self.img.height as usize,
+            self.img.stride as usize,
+        );
+        if width == 0 || height == 0 {
+            debug!("Captured image is empty.");
+            return Ok(PixelProvider::BGR0(0, 0, &[]));
+        }
+        Ok(PixelProvider::BGR0S(width, height, stride, unsafe {
+            from_raw_parts(self.img.data, stride * height)
+        }))
+    }
+}
@@ -1,22 +1,298 @@
-pub mod autopilot;
+//! Screens and windows that can be captured ([`Capturable`]) and recording them ([`Recorder`]).
+
+pub(crate) mod autopilot;
 use std::boxed::Box;
+use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use image::codecs::png::PngEncoder;
+use image::ColorType;
+#[cfg(target_os = "linux")]
+use tracing::debug;
 use tracing::warn;
 
+use serde::{Deserialize, Serialize};
+
+use crate::cerror::{CError, CErrorCode};
+use crate::video::PixelProvider;
+
+pub mod composite;
 #[cfg(target_os = "macos")]
-pub mod core_graphics;
+pub(crate) mod core_graphics;
+#[cfg(target_os = "windows")]
+pub(crate) mod dxgi;
 #[cfg(target_os = "linux")]
-pub mod pipewire;
+pub(crate) mod pipewire;
 #[cfg(target_os = "linux")]
-pub mod pipewire_dbus;
+pub(crate) mod pipewire_dbus;
+pub mod shared;
+pub mod stream;
 pub mod testsrc;
 #[cfg(target_os = "linux")]
-pub mod x11;
+pub(crate) mod x11;
+
+#[cfg(target_os = "linux")]
+pub use x11::CaptureRegion;
 
+/// Errors returned by recorders.
+#[derive(Debug, Clone)]
+pub enum CaptureError {
+    /// Setting up the recorder failed.
+    CaptureStart(String),
+    /// Capturing a frame failed, this may be temporary.
+    CaptureFrame(String),
+    /// The capturable does not exist anymore, for example because the window has been closed.
+    WindowGone(String),
+    /// The operation is not supported by the recorder or the system.
+    Unsupported(String),
+    /// The connection to the display server has been lost, capturing may work again with a new
+    /// capturable once the server is back.
+    ConnectionLost(String),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::CaptureStart(s) => write!(f, "Failed to start capturing: {}", s),
+            CaptureError::CaptureFrame(s) => write!(f, "Failed to capture frame: {}", s),
+            CaptureError::WindowGone(s) => write!(f, "Capturable does not exist anymore: {}", s),
+            CaptureError::Unsupported(s) => write!(f, "Unsupported: {}", s),
+            CaptureError::ConnectionLost(s) => {
+                write!(f, "Lost connection to the display server: {}", s)
+            }
+        }
+    }
+}
+
+impl Error for CaptureError {}
+
+impl From<CError> for CaptureError {
+    fn from(err: CError) -> Self {
+        match err.to_enum() {
+            CErrorCode::CapturableGone => CaptureError::WindowGone(err.to_string()),
+            CErrorCode::Unsupported => CaptureError::Unsupported(err.to_string()),
+            CErrorCode::ConnectionLost => CaptureError::ConnectionLost(err.to_string()),
+            _ => CaptureError::CaptureFrame(err.to_string()),
+        }
+    }
+}
+
+/// Image and position of the cursor.
+#[derive(Clone)]
+pub struct CursorImage {
+    /// BGRA, not premultiplied with the alpha channel
+    pub data: Vec<u8>,
+    pub width: usize,
+    pub height: usize,
+    pub hotspot_x: i32,
+    pub hotspot_y: i32,
+    /// position of the hotspot in pixels relative to the top left corner of the captured image
+    pub x: i32,
+    pub y: i32,
+    /// changes whenever the cursor image changes
+    pub serial: u64,
+}
+
+/// Clamp the crop x, y, width, height in pixels to an image of `width`x`height` pixels, `None` if
+/// the crop lies outside of the image or is empty, in which case recorders capture the whole image.
+pub fn clamp_crop(
+    (x, y, crop_width, crop_height): (u32, u32, u32, u32),
+    (width, height): (u32, u32),
+) -> Option<(u32, u32, u32, u32)> {
+    if x < width && y < height && crop_width > 0 && crop_height > 0 {
+        Some((x, y, crop_width.min(width - x), crop_height.min(height - y)))
+    } else {
+        None
+    }
+}
+
+/// Records images of a [`Capturable`], created by [`Capturable::recorder`].
 pub trait Recorder {
+    /// Capture a new image. Errors are returned to the caller instead of being logged, this allows
+    /// to distinguish temporary failures from permanent ones (see `is_gone`) and to give up or
+    /// restart the recorder after capturing failed repeatedly, for example:
+    ///
+    /// ```ignore
+    /// let mut failures = 0;
+    /// loop {
+    ///     match recorder.capture() {
+    ///         Ok(pixel_provider) => {
+    ///             failures = 0;
+    ///             encoder.encode(pixel_provider);
+    ///         }
+    ///         Err(err) if recorder.is_gone() || failures >= 10 => return Err(err),
+    ///         Err(_) => failures += 1,
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// An image of width or height 0, for example of a minimized window, is not an error. There is
+    /// nothing to encode in that case and such frames should be skipped.
     fn capture(&mut self) -> Result<crate::video::PixelProvider, Box<dyn Error>>;
+
+    /// Capture a new image and copy it into `dst` as tightly packed BGRA, that is 4 bytes per
+    /// pixel in the order blue, green, red, alpha (always 255) and `4 * width` bytes per row.
+    /// Contrary to the data returned by `capture`, `dst` is owned by the caller and stays valid,
+    /// which allows passing it to other threads. Returns width and height of the image.
+    fn capture_into(&mut self, dst: &mut Vec<u8>) -> Result<(usize, usize), Box<dyn Error>> {
+        let pixel_provider = self.capture()?;
+        Ok(copy_bgra(pixel_provider, dst))
+    }
+
+    /// Like `capture` but also return when the image has been captured. This differs from the time
+    /// `capture` is called if images are captured asynchronously and the image returned may have
+    /// been captured earlier, for example because there is no new one yet.
+    fn capture_with_time(&mut self) -> Result<(PixelProvider, Instant), Box<dyn Error>> {
+        let capture_time = Instant::now();
+        Ok((self.capture()?, capture_time))
+    }
+
+    /// Only capture the rectangle x, y, width, height given in pixels relative to the top left
+    /// corner of the captured image. `None` captures the whole image. Recorders that do not support
+    /// cropping ignore this.
+    fn set_crop(&mut self, _crop: Option<(u32, u32, u32, u32)>) {}
+
+    /// The crop the next capture applies as set by `set_crop`, before it is clamped to the captured
+    /// image, see [`clamp_crop`]. `None` if the whole image is captured.
+    fn crop(&self) -> Option<(u32, u32, u32, u32)> {
+        None
+    }
+
+    /// Change whether the cursor is part of the captured image while capturing. Recorders that can
+    /// only decide this when they are created ignore this.
+    fn set_capture_cursor(&mut self, _capture_cursor: bool) {}
+
+    /// Wait for the vertical blank of the display before capturing, so images are complete instead
+    /// of showing parts of two frames, at the cost of delaying captures by up to a refresh interval.
+    /// Recorders that can not do this or never tear ignore this.
+    fn set_vsync(&mut self, _vsync: bool) {}
+
+    /// Keep track of which parts of the screen the display server reports as changed, so
+    /// `damaged` can tell whether capturing would return a new image. Recorders that can not do
+    /// this ignore this.
+    fn set_track_damage(&mut self, _track: bool) {}
+
+    /// Scale the cursor painted into captured images by this factor around its hotspot, 1.0 paints
+    /// it as is. Recorders that can not scale the cursor ignore this.
+    fn set_cursor_scale(&mut self, _scale: f64) {}
+
+    /// Return whether the image may have changed since this has been called last, `None` if this
+    /// is not known because damage is not tracked, see `set_track_damage`. Changes are only
+    /// tracked anew once this returned, so call this right before capturing.
+    fn damaged(&mut self) -> Option<bool> {
+        None
+    }
+
+    /// Width and height of the last captured image before cropping, `None` if nothing has been
+    /// captured yet or the recorder does not support cropping.
+    fn size_uncropped(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    /// Return the last captured image as BGRA/BGR0 data together with its width and height. The
+    /// data is valid until the next call to `capture`. Returns `None` if nothing has been captured
+    /// yet or if the recorder does not provide raw image data.
+    fn frame_bgra(&self) -> Option<(&[u8], usize, usize)> {
+        None
+    }
+
+    /// Encode the image captured last losslessly as PNG, for example to grab a still of a session
+    /// without the artifacts of the video. This relies on `frame_bgra` and fails like it, use
+    /// `capture_into` together with [`encode_png`] for recorders without raw image data.
+    fn snapshot(&self) -> Result<Vec<u8>, CError> {
+        let (data, width, height) = self.frame_bgra().ok_or_else(|| {
+            CError::with_message(
+                CErrorCode::Unsupported,
+                "Nothing has been captured yet or the recorder does not provide raw image data.",
+            )
+        })?;
+        encode_png(data, width, height)
+    }
+
+    /// Return true if the recorded capturable does not exist anymore, for example because the
+    /// window has been closed. In that case capturing will never succeed again.
+    fn is_gone(&self) -> bool {
+        false
+    }
+
+    /// Return the current cursor image and position independently of whether the cursor is
+    /// painted into captured images or not. Returns `None` if the recorder does not support this.
+    fn cursor(&mut self) -> Result<Option<CursorImage>, Box<dyn Error>> {
+        Ok(None)
+    }
 }
 
+// Copy the image into `dst` as tightly packed BGRA, see `Recorder::capture_into`.
+pub(crate) fn copy_bgra(pixel_provider: PixelProvider, dst: &mut Vec<u8>) -> (usize, usize) {
+    let (width, height) = pixel_provider.size();
+    dst.clear();
+    dst.reserve(width * height * 4);
+    match pixel_provider {
+        PixelProvider::BGR0(_, _, data) => {
+            dst.extend_from_slice(&data[..width * height * 4]);
+            dst.chunks_exact_mut(4).for_each(|p| p[3] = 255);
+        }
+        PixelProvider::BGR0S(_, _, stride, data) => {
+            for row in data.chunks(stride).take(height) {
+                dst.extend_from_slice(&row[..width * 4]);
+            }
+            dst.chunks_exact_mut(4).for_each(|p| p[3] = 255);
+        }
+        PixelProvider::RGB0(_, _, data) => {
+            for p in data.chunks_exact(4).take(width * height) {
+                dst.extend_from_slice(&[p[2], p[1], p[0], 255]);
+            }
+        }
+        PixelProvider::RGB(_, _, data) => {
+            for p in data.chunks_exact(3).take(width * height) {
+                dst.extend_from_slice(&[p[2], p[1], p[0], 255]);
+            }
+        }
+    }
+    (width, height)
+}
+
+/// Encode an image of tightly packed BGRA or BGR0, see [`Recorder::capture_into`], as PNG. The
+/// alpha channel is dropped as not all recorders fill it.
+pub fn encode_png(frame: &[u8], width: usize, height: usize) -> Result<Vec<u8>, CError> {
+    if width == 0 || height == 0 {
+        return Err(CError::with_message(
+            CErrorCode::GenericError,
+            "Can not encode an empty image as PNG.",
+        ));
+    }
+    if frame.len() < width * height * 4 {
+        return Err(CError::with_message(
+            CErrorCode::GenericError,
+            &format!(
+                "Image of {} bytes is too small for {}x{} pixels.",
+                frame.len(),
+                width,
+                height
+            ),
+        ));
+    }
+    let rgb: Vec<u8> = frame
+        .chunks_exact(4)
+        .take(width * height)
+        .flat_map(|p| [p[2], p[1], p[0]])
+        .collect();
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .encode(&rgb, width as u32, height as u32, ColorType::Rgb8)
+        .map_err(|err| {
+            CError::with_message(
+                CErrorCode::GenericError,
+                &format!("Failed to encode PNG: {}", err),
+            )
+        })?;
+    Ok(png)
+}
+
+/// Allows cloning boxed capturables, this is implemented for all capturables that implement
+/// `Clone`.
 pub trait BoxCloneCapturable {
     fn box_clone(&self) -> Box<dyn Capturable>;
 }
@@ -30,20 +306,163 @@ where
     }
 }
 
+/// Something that can be captured: a screen, a window or the whole desktop.
 pub trait Capturable: Send + BoxCloneCapturable {
     /// Name of the Capturable, for example the window title, if it is a window.
     fn name(&self) -> String;
 
+    /// Identifier of the Capturable that stays the same across restarts of Weylus as far as
+    /// possible, this allows clients to select the same Capturable again once they reconnect.
+    fn id(&self) -> String;
+
     /// Return x, y, width, height of the Capturable as floats relative to the absolute size of the
     /// screen. For example x=0.5, y=0.0, width=0.5, height=1.0 means the right half of the screen.
     fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>>;
 
+    /// Return x, y, width, height of the Capturable in pixels on the virtual desktop spanning all
+    /// monitors, if known. The origin may be anywhere, for example monitors left of the primary
+    /// monitor have negative coordinates on Windows. Input devices prefer this over
+    /// `geometry_relative` if they can move the pointer across the whole virtual desktop.
+    fn geometry_virtual_desktop(&self) -> Option<(i32, i32, u32, u32)> {
+        None
+    }
+
     /// Callback that is called right before input is simulated.
     /// Useful to focus the window on input.
     fn before_input(&mut self) -> Result<(), Box<dyn Error>>;
 
+    /// Refresh rate of the display showing the Capturable in Hz, if known.
+    fn refresh_rate(&self) -> Option<f64> {
+        None
+    }
+
+    /// Number of physical pixels per logical pixel, for example 2.0 on a HiDPI screen scaled by
+    /// 200%, if known. Captured images are always in physical pixels, their logical size is their
+    /// size divided by this. Input is mapped via `geometry_relative`, which is the same in
+    /// logical and physical coordinates.
+    fn scale_factor(&self) -> Option<f64> {
+        None
+    }
+
+    /// Class of the window, WM_CLASS on X11, if the Capturable is a window and the class is known.
+    fn window_class(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether the Capturable is a monitor, a window and so on, if known.
+    fn kind(&self) -> Option<CapturableKind> {
+        None
+    }
+
+    /// Id of the process the window belongs to, _NET_WM_PID on X11, if known.
+    fn pid(&self) -> Option<u32> {
+        None
+    }
+
+    /// Name of the process the window belongs to, if known.
+    fn process_name(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether the window is minimized or hidden otherwise right now, if known. This is queried
+    /// each time and may change while the Capturable exists.
+    fn minimized(&self) -> Option<bool> {
+        None
+    }
+
+    /// Position of the window in the stacking order from bottom to top at the time it has been
+    /// listed, if known.
+    fn stacking(&self) -> Option<u32> {
+        None
+    }
+
     /// Return a Recorder that can record the current capturable.
     fn recorder(&self, capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>>;
+
+    /// Capture a single image scaled down so neither side exceeds `max_size` pixels, for example
+    /// to preview capturables before selecting one. This sets up a recorder for just one image,
+    /// see [`ThumbnailCache`] to avoid doing so repeatedly.
+    fn thumbnail(&self, max_size: usize) -> Result<Thumbnail, Box<dyn Error>> {
+        let mut recorder = self.recorder(false)?;
+        let mut frame = Vec::new();
+        let (width, height) = recorder.capture_into(&mut frame)?;
+        Ok(Thumbnail::scaled(&frame, width, height, max_size))
+    }
+}
+
+/// Small image of a capturable as tightly packed BGRA, see [`Recorder::capture_into`].
+#[derive(Debug, Clone)]
+pub struct Thumbnail {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl Thumbnail {
+    // nearest neighbor scaling, details are lost anyway at the size of a thumbnail
+    pub(crate) fn scaled(frame: &[u8], width: usize, height: usize, max_size: usize) -> Self {
+        let max_size = max_size.max(1);
+        let (width_out, height_out) = if width == 0 || height == 0 {
+            (0, 0)
+        } else if width <= max_size && height <= max_size {
+            (width, height)
+        } else if width >= height {
+            (max_size, (height * max_size / width).max(1))
+        } else {
+            ((width * max_size / height).max(1), max_size)
+        };
+        let mut data = Vec::with_capacity(width_out * height_out * 4);
+        for y in 0..height_out {
+            let row = &frame[y * height / height_out * width * 4..];
+            for x in 0..width_out {
+                let p = x * width / width_out * 4;
+                data.extend_from_slice(&row[p..p + 4]);
+            }
+        }
+        Self {
+            width: width_out,
+            height: height_out,
+            data,
+        }
+    }
+}
+
+/// Thumbnails of capturables taken within the last `max_age`, failures are kept as well so
+/// refreshing a list of capturables does not capture all of them again.
+pub struct ThumbnailCache {
+    max_age: Duration,
+    thumbnails: HashMap<(String, usize), (Instant, Result<Thumbnail, String>)>,
+}
+
+impl ThumbnailCache {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            thumbnails: HashMap::new(),
+        }
+    }
+
+    pub fn get(
+        &mut self,
+        capturable: &dyn Capturable,
+        max_size: usize,
+    ) -> Result<&Thumbnail, &str> {
+        let max_age = self.max_age;
+        self.thumbnails
+            .retain(|_, (taken_at, _)| taken_at.elapsed() < max_age);
+        let (_, thumbnail) = self
+            .thumbnails
+            .entry((capturable.id(), max_size))
+            .or_insert_with(|| {
+                (
+                    Instant::now(),
+                    capturable
+                        .thumbnail(max_size)
+                        .map_err(|err| err.to_string()),
+                )
+            });
+        thumbnail.as_ref().map_err(String::as_str)
+    }
 }
 
 impl Clone for Box<dyn Capturable> {
@@ -52,13 +471,228 @@ impl Clone for Box<dyn Capturable> {
     }
 }
 
+/// Options for recording a [`Capturable`].
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct CaptureOptions {
+    /// Paint the cursor into the captured images, not all backends support this.
+    pub capture_cursor: bool,
+    /// Only capture this part of the image, see [`Recorder::set_crop`].
+    pub crop: Option<(u32, u32, u32, u32)>,
+    /// Align captures with vertical blanks, see [`Recorder::set_vsync`].
+    pub vsync: bool,
+    /// Track changes of the screen, see [`Recorder::set_track_damage`].
+    pub track_damage: bool,
+}
+
+/// Create a recorder for the capturable, the backend is the one the capturable has been listed by.
+/// Code outside of this module should create recorders only via this function.
+pub fn new_recorder(
+    capturable: &dyn Capturable,
+    options: CaptureOptions,
+) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+    let mut recorder = capturable.recorder(options.capture_cursor)?;
+    recorder.set_crop(options.crop);
+    if options.vsync {
+        recorder.set_vsync(true);
+    }
+    if options.track_damage {
+        recorder.set_track_damage(true);
+    }
+    Ok(recorder)
+}
+
+/// What a [`Capturable`] shows, see [`Capturable::kind`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CapturableKind {
+    /// Everything on all monitors.
+    Desktop,
+    Monitor,
+    Window,
+    /// Rectangle of the desktop, see `--capture-region`.
+    Region,
+}
+
+/// When frames are captured.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureMode {
+    /// Whenever a client requests a frame.
+    #[default]
+    Poll,
+    /// Only once the display server reports that the screen changed, plus a frame every now and
+    /// then as heartbeat. Recorders that can not track changes capture as with `Poll`.
+    Damage,
+}
+
+impl CaptureMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Poll => "poll",
+            Self::Damage => "damage",
+        }
+    }
+}
+
+impl std::str::FromStr for CaptureMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "poll" => Ok(Self::Poll),
+            "damage" => Ok(Self::Damage),
+            _ => Err(format!(
+                "Unknown capture mode: '{}', expected 'poll' or 'damage'.",
+                s
+            )),
+        }
+    }
+}
+
+/// Which backend screens and windows are captured with on Linux.
+#[cfg(target_os = "linux")]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptureBackend {
+    /// X11, plus PipeWire if Wayland support is enabled.
+    #[default]
+    Auto,
+    /// Only X11, this captures XWayland windows only if running on Wayland.
+    X11,
+    /// Only PipeWire via the desktop portal, even if Wayland support is disabled.
+    Wayland,
+}
+
+#[cfg(target_os = "linux")]
+impl CaptureBackend {
+    /// Check that the backend can be used at all, a backend that has been selected explicitly is
+    /// never replaced by another one, so this should fail early instead.
+    pub fn check_available(self) -> Result<(), String> {
+        match self {
+            Self::Auto => Ok(()),
+            Self::X11 => x11::X11Context::new().map(|_| ()).ok_or_else(|| {
+                "The X11 capture backend has been selected but the X server can not be reached, \
+                is DISPLAY set?"
+                    .to_string()
+            }),
+            Self::Wayland => {
+                let wayland = std::env::var_os("WAYLAND_DISPLAY").is_some()
+                    || std::env::var("XDG_SESSION_TYPE").map_or(false, |s| s == "wayland");
+                if wayland {
+                    Ok(())
+                } else {
+                    Err(
+                        "The Wayland capture backend has been selected but Weylus is not \
+                        running inside a Wayland session."
+                            .to_string(),
+                    )
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl std::str::FromStr for CaptureBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "x11" => Ok(Self::X11),
+            "wayland" => Ok(Self::Wayland),
+            _ => Err(format!(
+                "Unknown capture backend: '{}', expected 'x11', 'wayland' or 'auto'.",
+                s
+            )),
+        }
+    }
+}
+
+/// How windows are captured via X11.
+#[cfg(target_os = "linux")]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WindowCapture {
+    /// The focused window from the screen, so its menus are visible, other windows from their own
+    /// contents.
+    #[default]
+    Auto,
+    /// Always the contents of the window itself, redirected via the Composite extension, so
+    /// they stay live while the window is covered by others. Menus and popups are separate
+    /// windows and not visible then. Falls back to the window's bounds on screen if Composite is
+    /// not available.
+    Contents,
+    /// Always the bounds of the window on screen, so only the part of it which is visible, with
+    /// windows covering it included.
+    Screen,
+}
+
+#[cfg(target_os = "linux")]
+impl std::str::FromStr for WindowCapture {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "contents" => Ok(Self::Contents),
+            "screen" => Ok(Self::Screen),
+            _ => Err(format!(
+                "Unknown window capture: '{}', expected 'auto', 'contents' or 'screen'.",
+                s
+            )),
+        }
+    }
+}
+
+/// How the cursor is painted into frames captured via X11, cursors of X11 may have partially
+/// transparent pixels, for example at antialiased edges or in their shadows.
+#[cfg(target_os = "linux")]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CursorBlend {
+    /// Blend the cursor onto the frame according to its alpha channel, scaling it interpolates
+    /// between its pixels, which keeps its edges smooth.
+    #[default]
+    Alpha,
+    /// Draw the pixels of the cursor that are at least half opaque as they are and leave out all
+    /// others, scaling it picks the nearest pixel, which keeps its edges sharp.
+    Key,
+}
+
+#[cfg(target_os = "linux")]
+impl std::str::FromStr for CursorBlend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "alpha" => Ok(Self::Alpha),
+            "key" => Ok(Self::Key),
+            _ => Err(format!(
+                "Unknown cursor blend: '{}', expected 'alpha' or 'key'.",
+                s
+            )),
+        }
+    }
+}
+
+/// List everything that can be captured, backends that fail to list their capturables are
+/// skipped with a warning. On Linux `backend` selects the backends asked, with
+/// [`CaptureBackend::Auto`] PipeWire is only asked if `wayland_support` is set.
 pub fn get_capturables(
+    #[cfg(target_os = "linux")] backend: CaptureBackend,
     #[cfg(target_os = "linux")] wayland_support: bool,
     #[cfg(target_os = "linux")] capture_cursor: bool,
 ) -> Vec<Box<dyn Capturable>> {
     let mut capturables: Vec<Box<dyn Capturable>> = vec![];
     #[cfg(target_os = "linux")]
     {
+        let (wayland_support, x11_support) = match backend {
+            CaptureBackend::Auto => (wayland_support, true),
+            CaptureBackend::X11 => (false, true),
+            CaptureBackend::Wayland => (true, false),
+        };
         if wayland_support {
             use crate::capturable::pipewire::get_capturables as get_capturables_pw;
             match get_capturables_pw(capture_cursor) {
@@ -74,18 +708,25 @@ pub fn get_capturables(
             }
         }
 
-        use crate::capturable::x11::X11Context;
-        let x11ctx = X11Context::new();
-        if let Some(mut x11ctx) = x11ctx {
-            match x11ctx.capturables() {
+        use crate::capturable::x11::{FocusedWindowCapturable, X11CapturableCache};
+        if x11_support {
+            match X11CapturableCache::global().refresh(false) {
                 Ok(captrs) => {
+                    let focused = FocusedWindowCapturable::for_screens(&captrs);
                     for c in captrs {
                         capturables.push(Box::new(c));
                     }
+                    for c in focused {
+                        capturables.push(Box::new(c));
+                    }
+                }
+                // there is no X server, for example on Wayland without XWayland
+                Err(err) if matches!(err.to_enum(), CErrorCode::ConnectionLost) => {
+                    debug!("{}", err)
                 }
                 Err(err) => warn!("Failed to get list of capturables via X11: {}", err),
             }
-        };
+        }
     }
 
     #[cfg(target_os = "macos")]
@@ -112,6 +753,19 @@ pub fn get_capturables(
         }
     }
 
+    #[cfg(target_os = "windows")]
+    {
+        use crate::capturable::dxgi::get_capturables as get_capturables_dxgi;
+        match get_capturables_dxgi() {
+            Ok(captrs) => {
+                for c in captrs {
+                    capturables.push(Box::new(c));
+                }
+            }
+            Err(err) => warn!("Failed to get list of outputs via DXGI: {}", err),
+        }
+    }
+
     use crate::capturable::autopilot::AutoPilotCapturable;
     capturables.push(Box::new(AutoPilotCapturable::new()));
 
@@ -126,12 +780,97 @@ pub fn get_capturables(
         ]
         .iter()
         {
-            capturables.push(Box::new(testsrc::TestCapturable {
-                width: *width,
-                height: *height,
-            }));
+            capturables.push(Box::new(testsrc::TestCapturable::new(*width, *height)));
+        }
+    }
+
+    capturables
+}
+
+/// Narrows down the capturables offered on cluttered desktops, given as a part of the title or
+/// window class ignoring case, `title:<part>` or `class:<part>` only look at one of them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct CapturableFilter {
+    // lowercase
+    pattern: String,
+    title: bool,
+    class: bool,
+}
+
+impl CapturableFilter {
+    /// Whether a capturable with the given title and window class matches.
+    pub fn matches_metadata(&self, title: &str, class: Option<&str>) -> bool {
+        (self.title && title.to_lowercase().contains(&self.pattern))
+            || (self.class
+                && class.map_or(false, |class| class.to_lowercase().contains(&self.pattern)))
+    }
+
+    pub fn matches(&self, capturable: &dyn Capturable) -> bool {
+        self.matches_metadata(&capturable.name(), capturable.window_class().as_deref())
+    }
+
+    /// Drop the capturables not matching, returns how many there have been before.
+    pub fn apply(&self, capturables: &mut Vec<Box<dyn Capturable>>) -> usize {
+        let total = capturables.len();
+        capturables.retain(|c| self.matches(c.as_ref()));
+        total
+    }
+}
+
+impl std::str::FromStr for CapturableFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, title, class) = if let Some(pattern) = s.strip_prefix("title:") {
+            (pattern, true, false)
+        } else if let Some(pattern) = s.strip_prefix("class:") {
+            (pattern, false, true)
+        } else {
+            (s, true, true)
+        };
+        if pattern.is_empty() {
+            return Err(format!(
+                "Invalid capturable filter: '{}', expected a part of a title or class.",
+                s
+            ));
+        }
+        Ok(Self {
+            pattern: pattern.to_lowercase(),
+            title,
+            class,
+        })
+    }
+}
+
+impl TryFrom<String> for CapturableFilter {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CapturableFilter> for String {
+    fn from(filter: CapturableFilter) -> Self {
+        match (filter.title, filter.class) {
+            (true, false) => format!("title:{}", filter.pattern),
+            (false, true) => format!("class:{}", filter.pattern),
+            _ => filter.pattern,
         }
     }
+}
 
+/// Find the capturable matching `selector`, which is either its id or a part of its name ignoring
+/// case. An exact id takes precedence over names.
+pub fn find_capturable(capturables: &[Box<dyn Capturable>], selector: &str) -> Option<usize> {
+    let selector_lowercase = selector.to_lowercase();
     capturables
+        .iter()
+        .position(|c| c.id() == selector)
+        .or_else(|| {
+            capturables
+                .iter()
+                .position(|c| c.name().to_lowercase().contains(&selector_lowercase))
+        })
 }
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::os::unix::io::AsRawFd;
 use std::sync::{atomic::AtomicBool, Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::{debug, trace, warn};
 
 use dbus::{
@@ -96,6 +96,11 @@ impl Capturable for PipeWireCapturable {
         format!("Pipewire {}, path: {}", type_str, self.path)
     }
 
+    fn id(&self) -> String {
+        // the portal does not provide anything more stable than the node of the stream
+        format!("pipewire:{}:{}", self.source_type, self.path)
+    }
+
     fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
         Ok((0.0, 0.0, 1.0, 1.0))
     }
@@ -118,6 +123,8 @@ pub struct PipeWireRecorder {
     appsink: AppSink,
     width: usize,
     height: usize,
+    // when the image in buffer has been captured
+    capture_time: Option<Instant>,
 }
 
 impl PipeWireRecorder {
@@ -162,12 +169,24 @@ impl PipeWireRecorder {
             height: 0,
             buffer_cropped: vec![],
             is_cropped: false,
+            capture_time: None,
         })
     }
-}
 
-impl Recorder for PipeWireRecorder {
-    fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+    // The timestamp of buffers is the running time of the pipeline at which they have been
+    // captured, the difference to the current running time is their age.
+    fn buffer_age(&self, buf: &gst::BufferRef) -> Option<Duration> {
+        let running_time = self
+            .pipeline
+            .clock()?
+            .time()?
+            .checked_sub(self.pipeline.base_time()?)?;
+        let age = running_time.checked_sub(buf.pts()?)?;
+        Some(Duration::from_nanos(age.nseconds()))
+    }
+
+    // take the latest buffer from the pipeline, if there is none the previous one is kept
+    fn pull(&mut self) -> Result<(), Box<dyn Error>> {
         if let Some(sample) = self
             .appsink
             .try_pull_sample(gst::ClockTime::from_mseconds(33))
@@ -181,6 +200,11 @@ impl Recorder for PipeWireRecorder {
             let buf = sample
                 .buffer_owned()
                 .ok_or_else(|| GStreamerError("Failed to get owned buffer.".into()))?;
+            let now = Instant::now();
+            let capture_time = self
+                .buffer_age(&buf)
+                .and_then(|age| now.checked_sub(age))
+                .unwrap_or(now);
             let mut crop = buf
                 .meta::<gstreamer_video::VideoCropMeta>()
                 .map(|m| m.rect());
@@ -228,6 +252,7 @@ impl Recorder for PipeWireRecorder {
                 }
                 self.is_cropped = crop.is_some();
                 self.buffer = Some(buf);
+                self.capture_time = Some(capture_time);
             }
         } else {
             trace!("No new buffer available, falling back to previous one.");
@@ -235,19 +260,37 @@ impl Recorder for PipeWireRecorder {
         if self.buffer.is_none() {
             return Err(Box::new(GStreamerError("No buffer available!".into())));
         }
+        Ok(())
+    }
+
+    // the current buffer, pull has to have succeeded before
+    fn frame(&self) -> PixelProvider {
         let buf = if self.is_cropped {
             self.buffer_cropped.as_slice()
         } else {
             self.buffer.as_ref().unwrap().as_slice()
         };
         match self.pix_fmt.as_str() {
-            "BGRx" => Ok(PixelProvider::BGR0(self.width, self.height, buf)),
-            "RGBx" => Ok(PixelProvider::RGB0(self.width, self.height, buf)),
+            "BGRx" => PixelProvider::BGR0(self.width, self.height, buf),
+            "RGBx" => PixelProvider::RGB0(self.width, self.height, buf),
             _ => unreachable!(),
         }
     }
 }
 
+impl Recorder for PipeWireRecorder {
+    fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+        self.pull()?;
+        Ok(self.frame())
+    }
+
+    fn capture_with_time(&mut self) -> Result<(PixelProvider, Instant), Box<dyn Error>> {
+        self.pull()?;
+        let capture_time = self.capture_time.unwrap_or_else(Instant::now);
+        Ok((self.frame(), capture_time))
+    }
+}
+
 impl Drop for PipeWireRecorder {
     fn drop(&mut self) {
         if let Err(err) = self.pipeline.set_state(gst::State::Null) {
@@ -0,0 +1,754 @@
+//! Capturing a capturable once for all clients showing it, see [`SharedCaptures`].
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, warn};
+
+use crate::capturable::{
+    clamp_crop, new_recorder, Capturable, CaptureError, CaptureOptions, CursorImage, Recorder,
+};
+use crate::video::{OwnedPixels, PixelProvider};
+
+/// An image captured less than this long ago is handed to a recorder asking for one instead of
+/// capturing a new one, so clients asking at slightly different times share captures. This is half
+/// the refresh interval of a 60 Hz screen.
+const MAX_FRAME_AGE: Duration = Duration::from_millis(8);
+/// How long a recorder waits for the thread capturing before it gives up on an image or the
+/// cursor.
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of buffers of images no recorder shows anymore that are kept for the next captures.
+const SPARE_FRAMES: usize = 2;
+
+// What has to match for recorders to share captures, each of them applies its own crop.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ShareKey {
+    id: String,
+    capture_cursor: bool,
+    vsync: bool,
+    track_damage: bool,
+}
+
+impl ShareKey {
+    fn new(capturable: &dyn Capturable, options: CaptureOptions) -> Self {
+        Self {
+            id: capturable.id(),
+            capture_cursor: options.capture_cursor,
+            vsync: options.vsync,
+            track_damage: options.track_damage,
+        }
+    }
+}
+
+// image captured by the thread of a share, kept until every recorder moved on to a newer one
+struct SharedFrame {
+    pixels: OwnedPixels,
+    captured_at: Instant,
+}
+
+#[derive(Default)]
+struct ShareState {
+    // number of recorders using the share, the thread stops once there are none left
+    recorders: usize,
+    // the thread stopped or failed to start, a new share has to be created
+    stopped: bool,
+    capture_requested: bool,
+    capturing: bool,
+    // number of captures so far, successful or not, recorders wait for this to change
+    captures: u64,
+    frame: Option<Arc<SharedFrame>>,
+    // why the last capture failed
+    error: Option<CaptureError>,
+    gone: bool,
+    spare_frames: Vec<Vec<u8>>,
+    cursor_requested: bool,
+    // number of times the cursor has been queried so far
+    cursors: u64,
+    // relative to the whole image
+    cursor: Option<CursorImage>,
+    // why querying the cursor failed the last time
+    cursor_error: Option<String>,
+    // to be applied by the thread before the next capture
+    cursor_scale: Option<f64>,
+}
+
+struct Share {
+    state: Mutex<ShareState>,
+    changed: Condvar,
+}
+
+impl Share {
+    fn lock(&self) -> MutexGuard<'_, ShareState> {
+        self.state.lock().unwrap()
+    }
+
+    // wait until `done` returns true, false if this timed out
+    fn wait<'a>(
+        &self,
+        mut state: MutexGuard<'a, ShareState>,
+        mut done: impl FnMut(&ShareState) -> bool,
+    ) -> (MutexGuard<'a, ShareState>, bool) {
+        let deadline = Instant::now() + CAPTURE_TIMEOUT;
+        while !done(&state) {
+            let now = Instant::now();
+            if now >= deadline {
+                return (state, false);
+            }
+            state = self.changed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        (state, true)
+    }
+}
+
+// Owns the recorder of a share and captures whenever a recorder of the share asks for an image or
+// the cursor, until no recorder is left.
+fn run_share(
+    share: Arc<Share>,
+    capturable: Box<dyn Capturable>,
+    options: CaptureOptions,
+    started: mpsc::Sender<Result<(), String>>,
+) {
+    let mut recorder = match new_recorder(capturable.as_ref(), options) {
+        Ok(recorder) => {
+            let _ = started.send(Ok(()));
+            recorder
+        }
+        Err(err) => {
+            share.lock().stopped = true;
+            let _ = started.send(Err(err.to_string()));
+            return;
+        }
+    };
+    debug!("Started shared capture of {}.", capturable.name());
+    let mut state = share.lock();
+    loop {
+        if state.recorders == 0 {
+            state.stopped = true;
+            break;
+        }
+        if let Some(scale) = state.cursor_scale.take() {
+            recorder.set_cursor_scale(scale);
+        }
+        if std::mem::take(&mut state.cursor_requested) {
+            drop(state);
+            let cursor = recorder.cursor();
+            state = share.lock();
+            match cursor {
+                Ok(cursor) => {
+                    state.cursor = cursor;
+                    state.cursor_error = None;
+                }
+                Err(err) => {
+                    state.cursor = None;
+                    state.cursor_error = Some(err.to_string());
+                }
+            }
+            state.cursors += 1;
+            share.changed.notify_all();
+            continue;
+        }
+        if std::mem::take(&mut state.capture_requested) {
+            // nothing changed, so the image captured last is still current
+            let unchanged = state.frame.is_some() && recorder.damaged() == Some(false);
+            if !unchanged {
+                state.capturing = true;
+                let spare = state.spare_frames.pop().unwrap_or_default();
+                drop(state);
+                let frame = recorder
+                    .capture_with_time()
+                    .map(|(pixel_provider, captured_at)| SharedFrame {
+                        pixels: OwnedPixels::copy_from(&pixel_provider, spare),
+                        captured_at,
+                    });
+                let gone = frame.is_err() && recorder.is_gone();
+                state = share.lock();
+                state.capturing = false;
+                state.gone = gone;
+                match frame {
+                    Ok(frame) => {
+                        state.frame = Some(Arc::new(frame));
+                        state.error = None;
+                    }
+                    Err(err) => {
+                        state.error = Some(match err.downcast::<CaptureError>() {
+                            Ok(err) => *err,
+                            Err(err) if gone => CaptureError::WindowGone(err.to_string()),
+                            Err(err) => CaptureError::CaptureFrame(err.to_string()),
+                        })
+                    }
+                }
+            }
+            state.captures += 1;
+            share.changed.notify_all();
+            continue;
+        }
+        state = share.changed.wait(state).unwrap();
+    }
+    drop(state);
+    // the recorder is dropped on the thread that created it
+    drop(recorder);
+    debug!("Stopped shared capture of {}.", capturable.name());
+}
+
+/// Captures shared by the clients of a server. Every screen or window is captured by a single
+/// recorder running on a thread of its own, however many clients show it, and each client encodes
+/// the images for itself. Clones share the same captures.
+#[derive(Clone)]
+pub struct SharedCaptures {
+    shares: Arc<Mutex<HashMap<ShareKey, Arc<Share>>>>,
+    // see MAX_FRAME_AGE
+    max_frame_age: Duration,
+}
+
+impl SharedCaptures {
+    pub fn new() -> Self {
+        Self {
+            shares: Arc::new(Mutex::new(HashMap::new())),
+            max_frame_age: MAX_FRAME_AGE,
+        }
+    }
+
+    /// A recorder of `capturable` that shares its images with all other recorders created here for
+    /// the same capturable with the same options, apart from the crop, which every recorder
+    /// applies for itself. If none exists, a recorder is created via [`new_recorder`] on a thread
+    /// of its own, which stops once all recorders sharing it have been dropped.
+    ///
+    /// Recorders only capture a new image if the one captured last is older than a few
+    /// milliseconds, so clients asking for images at the same rate share nearly every capture. If
+    /// changes are tracked, an image is only captured if the screen changed, but contrary to other
+    /// recorders whether it changed is not reported via [`Recorder::damaged`].
+    pub fn recorder(
+        &self,
+        capturable: &dyn Capturable,
+        options: CaptureOptions,
+    ) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        let share = self.subscribe(capturable, options)?;
+        Ok(Box::new(SharedRecorder {
+            captures: self.clone(),
+            capturable: capturable.box_clone(),
+            options,
+            share,
+            frame: None,
+            cropped_frame: Vec::new(),
+        }))
+    }
+
+    fn subscribe(
+        &self,
+        capturable: &dyn Capturable,
+        options: CaptureOptions,
+    ) -> Result<Subscription, Box<dyn Error>> {
+        let key = ShareKey::new(capturable, options);
+        let started = {
+            let mut shares = self.shares.lock().unwrap();
+            shares.retain(|_, share| !share.lock().stopped);
+            if let Some(share) = shares.get(&key) {
+                share.lock().recorders += 1;
+                return Ok(Subscription(share.clone()));
+            }
+            let share = Arc::new(Share {
+                state: Mutex::new(ShareState {
+                    recorders: 1,
+                    ..Default::default()
+                }),
+                changed: Condvar::new(),
+            });
+            shares.insert(key, share.clone());
+            let (sender, started) = mpsc::channel();
+            {
+                let share = share.clone();
+                let capturable = capturable.box_clone();
+                // every recorder crops for itself
+                let options = CaptureOptions {
+                    crop: None,
+                    ..options
+                };
+                spawn(move || run_share(share, capturable, options, sender));
+            }
+            (share, started)
+        };
+        // recorders of other capturables can be created while this one starts, which may take a
+        // while, for example if the user is asked to select a screen
+        let (share, started) = started;
+        match started.recv() {
+            Ok(Ok(())) => Ok(Subscription(share)),
+            Ok(Err(err)) => Err(Box::new(CaptureError::CaptureStart(err))),
+            Err(_) => Err(Box::new(CaptureError::CaptureStart(
+                "Shared capture stopped unexpectedly.".into(),
+            ))),
+        }
+    }
+}
+
+impl Default for SharedCaptures {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// a recorder's claim on a share, the share stops once all of them have been dropped
+struct Subscription(Arc<Share>);
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut state = self.0.lock();
+        state.recorders -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+// Crop the image to x, y, width, height in pixels, which have to lie within the image. BGR0
+// images are cropped without copying them.
+fn crop<'a>(
+    pixel_provider: PixelProvider<'a>,
+    (x, y, width, height): (usize, usize, usize, usize),
+    buf: &'a mut Vec<u8>,
+) -> PixelProvider<'a> {
+    let bpp = pixel_provider.bytes_per_pixel();
+    let stride = pixel_provider.stride();
+    let data = pixel_provider.data();
+    let start = y * stride + x * bpp;
+    match pixel_provider {
+        PixelProvider::BGR0(..) | PixelProvider::BGR0S(..) => {
+            PixelProvider::BGR0S(width, height, stride, &data[start..])
+        }
+        PixelProvider::RGB(..) | PixelProvider::RGB0(..) => {
+            buf.clear();
+            for row in data[start..].chunks(stride).take(height) {
+                buf.extend_from_slice(&row[..width * bpp]);
+            }
+            if bpp == 3 {
+                PixelProvider::RGB(width, height, buf)
+            } else {
+                PixelProvider::RGB0(width, height, buf)
+            }
+        }
+    }
+}
+
+/// Recorder showing the images of a capture shared with other recorders, see
+/// [`SharedCaptures::recorder`].
+struct SharedRecorder {
+    captures: SharedCaptures,
+    capturable: Box<dyn Capturable>,
+    // the crop is the one of this recorder, the share captures whole images
+    options: CaptureOptions,
+    share: Subscription,
+    // image shown at the moment, it is kept until the next one is taken
+    frame: Option<Arc<SharedFrame>>,
+    // copy of the cropped image for pixel formats that can not be cropped as they are
+    cropped_frame: Vec<u8>,
+}
+
+impl SharedRecorder {
+    fn share(&self) -> &Share {
+        &self.share.0
+    }
+
+    // an image newer than the one shown at the moment
+    fn next_frame(&self) -> Result<Arc<SharedFrame>, Box<dyn Error>> {
+        let share = self.share();
+        let state = share.lock();
+        if let Some(frame) = &state.frame {
+            let shown = self.frame.as_ref().map_or(false, |f| Arc::ptr_eq(f, frame));
+            if !shown && frame.captured_at.elapsed() < self.captures.max_frame_age {
+                return Ok(frame.clone());
+            }
+        }
+        let mut state = state;
+        // a capture that already started is as current as a new one would be
+        if !state.capturing {
+            state.capture_requested = true;
+            share.changed.notify_all();
+        }
+        let captures = state.captures;
+        let (state, captured) = share.wait(state, |state| state.captures != captures);
+        if !captured {
+            return Err(Box::new(CaptureError::CaptureFrame(
+                "Timed out waiting for the shared capture.".into(),
+            )));
+        }
+        match (&state.error, &state.frame) {
+            (Some(err), _) => Err(Box::new(err.clone())),
+            (None, Some(frame)) => Ok(frame.clone()),
+            (None, None) => Err(Box::new(CaptureError::CaptureFrame(
+                "Nothing has been captured yet.".into(),
+            ))),
+        }
+    }
+
+    // crop applied to the image shown at the moment, none if it is shown as a whole
+    fn frame_crop(&self) -> Option<(u32, u32, u32, u32)> {
+        let (width, height) = self.size_uncropped()?;
+        clamp_crop(self.options.crop?, (width as u32, height as u32))
+    }
+}
+
+impl Recorder for SharedRecorder {
+    fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+        Ok(self.capture_with_time()?.0)
+    }
+
+    fn capture_with_time(&mut self) -> Result<(PixelProvider, Instant), Box<dyn Error>> {
+        let frame = self.next_frame()?;
+        // the buffer of an image no recorder shows anymore is reused by the next capture
+        if let Some(old) = self
+            .frame
+            .replace(frame)
+            .and_then(|f| Arc::try_unwrap(f).ok())
+        {
+            let mut state = self.share().lock();
+            if state.spare_frames.len() < SPARE_FRAMES {
+                state.spare_frames.push(old.pixels.into_data());
+            }
+        }
+        let region = self.frame_crop();
+        let frame = self.frame.as_ref().unwrap();
+        let pixel_provider = match region {
+            Some((x, y, w, h)) => crop(
+                frame.pixels.pixels(),
+                (x as usize, y as usize, w as usize, h as usize),
+                &mut self.cropped_frame,
+            ),
+            None => frame.pixels.pixels(),
+        };
+        Ok((pixel_provider, frame.captured_at))
+    }
+
+    fn set_crop(&mut self, crop: Option<(u32, u32, u32, u32)>) {
+        self.options.crop = crop;
+    }
+
+    fn crop(&self) -> Option<(u32, u32, u32, u32)> {
+        self.options.crop
+    }
+
+    // switches to the share of recorders with the same setting
+    fn set_capture_cursor(&mut self, capture_cursor: bool) {
+        if capture_cursor == self.options.capture_cursor {
+            return;
+        }
+        let options = CaptureOptions {
+            capture_cursor,
+            ..self.options
+        };
+        match self.captures.subscribe(self.capturable.as_ref(), options) {
+            Ok(share) => {
+                self.share = share;
+                self.options = options;
+                self.frame = None;
+            }
+            Err(err) => warn!("Failed to change whether the cursor is captured: {}", err),
+        }
+    }
+
+    // the cursor scale is the same for all clients of a server
+    fn set_cursor_scale(&mut self, scale: f64) {
+        self.share().lock().cursor_scale = Some(scale);
+    }
+
+    fn size_uncropped(&self) -> Option<(usize, usize)> {
+        self.frame
+            .as_ref()
+            .map(|frame| frame.pixels.pixels().size())
+    }
+
+    fn frame_bgra(&self) -> Option<(&[u8], usize, usize)> {
+        match (self.frame.as_ref()?.pixels.pixels(), self.options.crop) {
+            (PixelProvider::BGR0(width, height, data), None) => Some((data, width, height)),
+            _ => None,
+        }
+    }
+
+    fn is_gone(&self) -> bool {
+        self.share().lock().gone
+    }
+
+    fn cursor(&mut self) -> Result<Option<CursorImage>, Box<dyn Error>> {
+        let share = self.share();
+        let mut state = share.lock();
+        state.cursor_requested = true;
+        share.changed.notify_all();
+        let cursors = state.cursors;
+        let (state, queried) = share.wait(state, |state| state.cursors != cursors);
+        if !queried {
+            return Err("Timed out waiting for the shared capture.".into());
+        }
+        if let Some(err) = &state.cursor_error {
+            return Err(err.clone().into());
+        }
+        let mut cursor = state.cursor.clone();
+        drop(state);
+        // like the image, the position is relative to the cropped image
+        if let (Some(cursor), Some((x, y, _, _))) = (cursor.as_mut(), self.frame_crop()) {
+            cursor.x -= x as i32;
+            cursor.y -= y as i32;
+        }
+        Ok(cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread::sleep;
+
+    use super::*;
+    use crate::capturable::testsrc::TestCapturable;
+
+    // color bars counting the images captured and the recorders alive
+    #[derive(Clone)]
+    struct CountingCapturable {
+        source: TestCapturable,
+        captures: Arc<AtomicUsize>,
+        recorders: Arc<AtomicUsize>,
+        fail: Arc<AtomicBool>,
+    }
+
+    impl CountingCapturable {
+        fn new() -> Self {
+            Self {
+                // high enough for the stripe of the color bars to move from image to image
+                source: TestCapturable::new(64, 240),
+                captures: Arc::new(AtomicUsize::new(0)),
+                recorders: Arc::new(AtomicUsize::new(0)),
+                fail: Arc::new(AtomicBool::new(false)),
+            }
+        }
+
+        fn captures(&self) -> usize {
+            self.captures.load(Ordering::SeqCst)
+        }
+
+        fn recorders(&self) -> usize {
+            self.recorders.load(Ordering::SeqCst)
+        }
+
+        // wait for shares without recorders to stop
+        fn wait_for_recorders(&self, recorders: usize) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while self.recorders() != recorders && Instant::now() < deadline {
+                sleep(Duration::from_millis(1));
+            }
+            assert_eq!(self.recorders(), recorders);
+        }
+    }
+
+    impl Capturable for CountingCapturable {
+        fn name(&self) -> String {
+            self.source.name()
+        }
+        fn id(&self) -> String {
+            self.source.id()
+        }
+        fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
+            self.source.geometry_relative()
+        }
+        fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
+            Ok(())
+        }
+        fn recorder(&self, capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err("No recorder for you!".into());
+            }
+            self.recorders.fetch_add(1, Ordering::SeqCst);
+            Ok(Box::new(CountingRecorder {
+                recorder: self.source.recorder(capture_cursor)?,
+                capturable: self.clone(),
+            }))
+        }
+    }
+
+    struct CountingRecorder {
+        recorder: Box<dyn Recorder>,
+        capturable: CountingCapturable,
+    }
+
+    impl Recorder for CountingRecorder {
+        fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+            self.capturable.captures.fetch_add(1, Ordering::SeqCst);
+            self.recorder.capture()
+        }
+
+        fn cursor(&mut self) -> Result<Option<CursorImage>, Box<dyn Error>> {
+            Ok(Some(CursorImage {
+                data: vec![255; 4],
+                width: 1,
+                height: 1,
+                hotspot_x: 0,
+                hotspot_y: 0,
+                x: 10,
+                y: 20,
+                serial: 1,
+            }))
+        }
+    }
+
+    impl Drop for CountingRecorder {
+        fn drop(&mut self) {
+            self.capturable.recorders.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    // images are shared however long it takes to ask for them
+    fn shared_captures() -> SharedCaptures {
+        SharedCaptures {
+            max_frame_age: Duration::from_secs(3600),
+            ..SharedCaptures::new()
+        }
+    }
+
+    #[test]
+    fn captured_once() {
+        let capturable = CountingCapturable::new();
+        let captures = shared_captures();
+        let mut first = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .unwrap();
+        let mut second = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .unwrap();
+        assert_eq!(capturable.recorders(), 1);
+
+        let checksum = first.capture().unwrap().checksum();
+        assert_eq!(second.capture().unwrap().checksum(), checksum);
+        assert_eq!(capturable.captures(), 1);
+        // the first recorder shows the image already, so it asks for a new one
+        let next = first.capture().unwrap().checksum();
+        assert_ne!(next, checksum);
+        assert_eq!(second.capture().unwrap().checksum(), next);
+        assert_eq!(capturable.captures(), 2);
+    }
+
+    #[test]
+    fn new_images_once_old() {
+        let capturable = CountingCapturable::new();
+        let captures = SharedCaptures {
+            max_frame_age: Duration::ZERO,
+            ..SharedCaptures::new()
+        };
+        let mut first = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .unwrap();
+        let mut second = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .unwrap();
+        first.capture().unwrap();
+        second.capture().unwrap();
+        assert_eq!(capturable.captures(), 2);
+    }
+
+    #[test]
+    fn crop_per_recorder() {
+        let capturable = CountingCapturable::new();
+        let captures = shared_captures();
+        let mut whole = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .unwrap();
+        let mut cropped = captures
+            .recorder(
+                &capturable,
+                CaptureOptions {
+                    crop: Some((2, 3, 10, 5)),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let image = whole.capture().unwrap();
+        assert_eq!(image.size(), (64, 240));
+        let image = image.data().to_vec();
+
+        let part = cropped.capture().unwrap();
+        assert_eq!(part.size(), (10, 5));
+        for y in 0..5 {
+            let row = &part.data()[y * part.stride()..][..40];
+            assert_eq!(row, &image[((y + 3) * 64 + 2) * 4..][..40]);
+        }
+        assert_eq!(capturable.captures(), 1);
+        assert_eq!(cropped.size_uncropped(), Some((64, 240)));
+        assert_eq!(cropped.crop(), Some((2, 3, 10, 5)));
+        let cursor = cropped.cursor().unwrap().unwrap();
+        assert_eq!((cursor.x, cursor.y), (8, 17));
+        let cursor = whole.cursor().unwrap().unwrap();
+        assert_eq!((cursor.x, cursor.y), (10, 20));
+
+        cropped.set_crop(None);
+        assert_eq!(cropped.capture().unwrap().size(), (64, 240));
+    }
+
+    #[test]
+    fn crop_copies_rgb() {
+        let data: Vec<u8> = (0..24).collect();
+        let mut buf = Vec::new();
+        let part = crop(PixelProvider::RGB(4, 2, &data), (1, 0, 2, 2), &mut buf);
+        assert!(matches!(part, PixelProvider::RGB(2, 2, _)));
+        assert_eq!(part.data(), &[3, 4, 5, 6, 7, 8, 15, 16, 17, 18, 19, 20]);
+    }
+
+    #[test]
+    fn stops_once_unused() {
+        let capturable = CountingCapturable::new();
+        let captures = shared_captures();
+        let first = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .unwrap();
+        let mut second = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .unwrap();
+        drop(first);
+        second.capture().unwrap();
+        assert_eq!(capturable.recorders(), 1);
+        drop(second);
+        capturable.wait_for_recorders(0);
+
+        let mut third = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .unwrap();
+        assert_eq!(capturable.recorders(), 1);
+        third.capture().unwrap();
+    }
+
+    #[test]
+    fn capture_cursor_changes_share() {
+        let capturable = CountingCapturable::new();
+        let captures = shared_captures();
+        let mut first = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .unwrap();
+        let mut second = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .unwrap();
+        first.set_capture_cursor(true);
+        assert_eq!(capturable.recorders(), 2);
+        first.capture().unwrap();
+        second.capture().unwrap();
+        assert_eq!(capturable.captures(), 2);
+        second.set_capture_cursor(true);
+        capturable.wait_for_recorders(1);
+    }
+
+    #[test]
+    fn failed_start() {
+        let capturable = CountingCapturable::new();
+        let captures = shared_captures();
+        capturable.fail.store(true, Ordering::SeqCst);
+        let err = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<CaptureError>(),
+            Some(CaptureError::CaptureStart(_))
+        ));
+        // the failed share is not reused
+        capturable.fail.store(false, Ordering::SeqCst);
+        let mut recorder = captures
+            .recorder(&capturable, CaptureOptions::default())
+            .unwrap();
+        recorder.capture().unwrap();
+    }
+}
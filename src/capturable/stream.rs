@@ -0,0 +1,91 @@
+//! Frames of a [`Capturable`] as an asynchronous [`Stream`], for applications embedding Weylus that
+//! run on an async runtime such as tokio.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::capturable::{copy_bgra, new_recorder, Capturable, CaptureError, CaptureOptions};
+
+/// An image captured by a [`FrameStream`].
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    /// Tightly packed BGRA, see [`crate::capturable::Recorder::capture_into`].
+    pub data: Vec<u8>,
+    pub captured_at: Instant,
+}
+
+/// Captures a capturable at a fixed rate on a thread of its own, as recorders can neither be moved
+/// between threads nor block an async runtime. A frame is only captured once the previous one has
+/// been taken from the stream, so a slow consumer lowers the rate instead of piling up frames.
+///
+/// Errors capturing a frame are yielded as well, capturing continues afterwards. The stream ends
+/// if the recorder can not be created or the capturable is gone. Dropping the stream stops
+/// capturing and drops the recorder.
+pub struct FrameStream {
+    receiver: mpsc::Receiver<Result<Frame, CaptureError>>,
+}
+
+impl FrameStream {
+    /// Capture `capturable` at up to `max_fps` frames per second.
+    pub fn new(capturable: Box<dyn Capturable>, options: CaptureOptions, max_fps: f64) -> Self {
+        let (sender, receiver) = mpsc::channel(1);
+        let frame_duration = Duration::from_secs_f64(1.0 / max_fps.max(0.1));
+        spawn(move || {
+            let mut recorder = match new_recorder(capturable.as_ref(), options) {
+                Ok(recorder) => recorder,
+                Err(err) => {
+                    let _ = sender.blocking_send(Err(CaptureError::CaptureStart(err.to_string())));
+                    return;
+                }
+            };
+            let mut next_frame = Instant::now();
+            while !sender.is_closed() {
+                let now = Instant::now();
+                if now < next_frame {
+                    sleep(next_frame - now);
+                }
+                // do not try to catch up on frames missed because the consumer has been slow
+                next_frame = next_frame.max(now) + frame_duration;
+                let frame = recorder
+                    .capture_with_time()
+                    .map(|(pixel_provider, captured_at)| {
+                        let mut data = Vec::new();
+                        let (width, height) = copy_bgra(pixel_provider, &mut data);
+                        Frame {
+                            width,
+                            height,
+                            data,
+                            captured_at,
+                        }
+                    });
+                let gone = frame.is_err() && recorder.is_gone();
+                let frame = frame.map_err(|err| match err.downcast::<CaptureError>() {
+                    Ok(err) => *err,
+                    Err(err) if gone => CaptureError::WindowGone(err.to_string()),
+                    Err(err) => CaptureError::CaptureFrame(err.to_string()),
+                });
+                if sender.blocking_send(frame).is_err() || gone {
+                    break;
+                }
+            }
+            debug!("Frame stream stopped.");
+        });
+        Self { receiver }
+    }
+}
+
+impl Stream for FrameStream {
+    type Item = Result<Frame, CaptureError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
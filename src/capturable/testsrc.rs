@@ -1,11 +1,62 @@
+//! Synthetic capturables generating deterministic frames, these allow testing encoding and
+//! streaming without a display.
+//!
+//! Frames can be checked via [`PixelProvider::checksum`], which stays the same across runs, so
+//! tests can assert the exact frame delivered, for example:
+//!
+//! ```ignore
+//! let mut recorder = TestCapturable::new(64, 64).recorder(false)?;
+//! let checksum = recorder.capture()?.checksum();
+//! assert_eq!(checksum, expected);
+//! ```
+
 use crate::capturable::{Capturable, Recorder};
 use crate::video::PixelProvider;
 use std::error::Error;
+use std::sync::Arc;
+
+/// A frame of BGR0 data, 4 bytes per pixel without padding at the end of rows.
+#[derive(Debug, Clone)]
+pub struct TestFrame {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+/// What the frames of a [`TestCapturable`] show.
+#[derive(Debug, Clone)]
+pub enum TestPattern {
+    /// Color bars with a stripe of moving colors running down.
+    ColorBars,
+    /// A white box moving across a black background, only the box changes between frames.
+    MovingBox,
+    /// The given frames one after another, starting again with the first once all have been
+    /// captured. The size of the frames overrides the size of the capturable and may change from
+    /// frame to frame, which allows testing how size changes are handled.
+    Frames(Arc<Vec<TestFrame>>),
+}
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct TestCapturable {
     pub width: usize,
     pub height: usize,
+    pub pattern: TestPattern,
+}
+
+impl TestCapturable {
+    /// Capturable showing color bars.
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pattern: TestPattern::ColorBars,
+        }
+    }
+
+    pub fn with_pattern(mut self, pattern: TestPattern) -> Self {
+        self.pattern = pattern;
+        self
+    }
 }
 
 pub struct TestRecorder {
@@ -22,40 +73,40 @@ fn set_default_pixel(buf: &mut [u8], w: usize, x: usize, y: usize) {
     buf[pos + 2] = if i & 4 != 0 { 255 } else { 0 };
 }
 
+// fill the square of size `size` at `x`, `y` with `value`
+fn fill_box(buf: &mut [u8], w: usize, (x, y): (usize, usize), size: usize, value: u8) {
+    for y in y..y + size {
+        let pos = (x + y * w) * 4;
+        buf[pos..pos + size * 4].fill(value);
+    }
+}
+
 impl TestRecorder {
     fn new(capturable: TestCapturable) -> Self {
-        let mut buf = vec![0; capturable.width * capturable.height * 4];
-        let buf_ref = buf.as_mut();
-        for y in 0..capturable.height {
-            for x in 0..capturable.width {
-                set_default_pixel(buf_ref, capturable.width, x, y);
+        let (width, height) = (capturable.width, capturable.height);
+        let buf = match capturable.pattern {
+            TestPattern::ColorBars => {
+                let mut buf = vec![0; width * height * 4];
+                let buf_ref = buf.as_mut();
+                for y in 0..height {
+                    for x in 0..width {
+                        set_default_pixel(buf_ref, width, x, y);
+                    }
+                }
+                buf
             }
-        }
+            TestPattern::MovingBox => vec![0; width * height * 4],
+            // the frames are used directly
+            TestPattern::Frames(_) => Vec::new(),
+        };
         Self {
             capturable,
             buf,
             i: 0,
         }
     }
-}
-
-impl Capturable for TestCapturable {
-    fn name(&self) -> String {
-        format!("Test Source {}x{}", self.width, self.height)
-    }
-    fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
-        Ok((1.0, 1.0, 1.0, 1.0))
-    }
-    fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
-        Ok(())
-    }
-    fn recorder(&self, _: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
-        Ok(Box::new(TestRecorder::new(*self)))
-    }
-}
 
-impl Recorder for TestRecorder {
-    fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+    fn capture_color_bars(&mut self) {
         const N: usize = 120;
         let dh = self.capturable.height / N;
         let buf_ref = self.buf.as_mut();
@@ -74,6 +125,67 @@ impl Recorder for TestRecorder {
                 buf_ref[pos + 2] = ((self.i + N * x / w + 2 * N / 3) % N * 256 / N) as u8;
             }
         }
+    }
+
+    fn next_frame(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+        let frames = match &self.capturable.pattern {
+            TestPattern::Frames(frames) => frames,
+            _ => unreachable!(),
+        };
+        if frames.is_empty() {
+            return Err("No test frames given!".into());
+        }
+        let frame = &frames[self.i % frames.len()];
+        self.i = (self.i + 1) % frames.len();
+        if frame.data.len() < frame.width * frame.height * 4 {
+            return Err("Test frame is smaller than its size suggests!".into());
+        }
+        Ok(PixelProvider::BGR0(frame.width, frame.height, &frame.data))
+    }
+
+    fn capture_moving_box(&mut self) {
+        let (w, h) = (self.capturable.width, self.capturable.height);
+        let size = w.min(h) / 8;
+        // the box moves diagonally, bouncing back once it reaches the edge
+        let position = |i: usize| {
+            let (range_x, range_y) = ((w - size).max(1), (h - size).max(1));
+            let (x, y) = (i % (2 * range_x), i % (2 * range_y));
+            (
+                if x < range_x { x } else { 2 * range_x - x },
+                if y < range_y { y } else { 2 * range_y - y },
+            )
+        };
+        fill_box(&mut self.buf, w, position(self.i), size, 0);
+        self.i += size.max(1) / 4 + 1;
+        fill_box(&mut self.buf, w, position(self.i), size, 255);
+    }
+}
+
+impl Capturable for TestCapturable {
+    fn name(&self) -> String {
+        format!("Test Source {}x{}", self.width, self.height)
+    }
+    fn id(&self) -> String {
+        format!("test:{}x{}", self.width, self.height)
+    }
+    fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
+        Ok((1.0, 1.0, 1.0, 1.0))
+    }
+    fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+    fn recorder(&self, _: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        Ok(Box::new(TestRecorder::new(self.clone())))
+    }
+}
+
+impl Recorder for TestRecorder {
+    fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+        match self.capturable.pattern {
+            TestPattern::ColorBars => self.capture_color_bars(),
+            TestPattern::MovingBox => self.capture_moving_box(),
+            TestPattern::Frames(_) => return self.next_frame(),
+        }
         Ok(PixelProvider::BGR0(
             self.capturable.width,
             self.capturable.height,
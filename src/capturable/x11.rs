@@ -1,13 +1,19 @@
-use crate::capturable::{Capturable, Recorder};
-use crate::cerror::CError;
+use crate::capturable::{
+    clamp_crop, Capturable, CapturableKind, CaptureError, CursorBlend, CursorImage, Recorder,
+    WindowCapture,
+};
+use crate::cerror::{CError, CErrorCode};
 use crate::video::PixelProvider;
 use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_float, c_int, c_uint, c_void};
+use std::os::raw::{c_char, c_double, c_float, c_int, c_uint, c_ulong, c_void};
 use std::slice::from_raw_parts;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 use std::{error::Error, fmt};
 
-use tracing::debug;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
 extern "C" {
     fn XOpenDisplay(name: *const c_char) -> *mut c_void;
@@ -15,21 +21,43 @@ extern "C" {
     fn XInitThreads() -> c_int;
     fn XLockDisplay(disp: *mut c_void);
     fn XUnlockDisplay(disp: *mut c_void);
+    fn XScreenCount(disp: *mut c_void) -> c_int;
+    fn XDefaultScreen(disp: *mut c_void) -> c_int;
 
     fn x11_set_error_handler();
+    fn x11_watch_connection(disp: *mut c_void);
+    fn x11_connection_losses() -> c_int;
 
     fn create_capturables(
         disp: *mut c_void,
+        screen: c_int,
         handles: *mut *mut c_void,
         num_monitors: *mut c_int,
         size: c_int,
         err: *mut CError,
     ) -> c_int;
 
+    fn create_region_capturable(
+        disp: *mut c_void,
+        screen: c_int,
+        x: c_int,
+        y: c_int,
+        width: c_uint,
+        height: c_uint,
+        err: *mut CError,
+    ) -> *mut c_void;
+
     fn clone_capturable(handle: *const c_void) -> *mut c_void;
     fn destroy_capturable(handle: *mut c_void);
     fn get_capturable_name(handle: *const c_void) -> *const c_char;
+    fn get_capturable_id(handle: *const c_void) -> *const c_char;
+    fn get_capturable_refresh_rate(handle: *const c_void) -> c_double;
+    fn get_capturable_stacking(handle: *const c_void) -> c_int;
+    fn get_capturable_pid(handle: *const c_void) -> c_int;
+    fn capturable_is_minimized(handle: *const c_void) -> c_int;
+    fn get_capturable_scale_factor(handle: *const c_void) -> c_double;
     fn capturable_before_input(handle: *mut c_void, err: *mut CError);
+    fn get_active_window(disp: *mut c_void, screen: c_int, err: *mut CError) -> c_ulong;
     fn get_geometry_relative(
         handle: *const c_void,
         x: *mut c_float,
@@ -45,13 +73,22 @@ extern "C" {
         libinput: c_int,
         err: *mut CError,
     );
-    fn start_capture(handle: *const c_void, ctx: *mut c_void, err: *mut CError) -> *mut c_void;
-    fn capture_screen(
-        handle: *mut c_void,
-        img: *mut CImage,
-        capture_cursor: c_int,
+    fn start_capture(
+        handle: *const c_void,
+        ctx: *mut c_void,
+        disp: *mut c_void,
         err: *mut CError,
-    );
+    ) -> *mut c_void;
+    fn capture_screen(handle: *mut c_void, img: *mut CImage, err: *mut CError);
+    fn get_cursor_image(handle: *mut c_void, img: *mut CCursorImage, err: *mut CError);
+    fn cursor_changed(handle: *mut c_void) -> c_int;
+    fn get_cursor_position(handle: *mut c_void, x: *mut c_int, y: *mut c_int, err: *mut CError);
+    fn set_vsync(handle: *mut c_void, vsync: c_int) -> c_int;
+    fn vsync_available() -> c_int;
+    fn set_track_damage(handle: *mut c_void, track: c_int) -> c_int;
+    fn image_damaged(handle: *mut c_void, capture_cursor: c_int) -> c_int;
+    fn damage_available(disp: *mut c_void) -> c_int;
+    fn set_window_capture(mode: c_int);
     fn stop_capture(handle: *mut c_void, err: *mut CError);
 }
 
@@ -62,6 +99,42 @@ pub fn x11_init() {
     }
 }
 
+/// Whether captures can be aligned to vertical blanks, see [`Recorder::set_vsync`]. This requires
+/// access to a DRM device, /dev/dri/card0 for example.
+pub fn vsync_capture_available() -> bool {
+    unsafe { vsync_available() != 0 }
+}
+
+/// Set how windows are captured, see `--x11-window-capture`. This applies to recorders created
+/// afterwards and should be set once before capturing anything.
+pub fn set_window_capture_mode(mode: WindowCapture) {
+    let mode = match mode {
+        WindowCapture::Auto => 0,
+        WindowCapture::Contents => 1,
+        WindowCapture::Screen => 2,
+    };
+    unsafe { set_window_capture(mode) };
+}
+
+static CURSOR_BLEND: Mutex<CursorBlend> = Mutex::new(CursorBlend::Alpha);
+
+// The mutexes of the capture path only guard plain data, which stays usable if a thread panicked
+// while holding the lock. Carry on instead of panicking in every thread that captures afterwards,
+// which includes dropping recorders while shutting down.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Select how recorders paint the cursor into captured images.
+pub fn set_cursor_blend(blend: CursorBlend) {
+    *lock(&CURSOR_BLEND) = blend;
+}
+
+/// Whether the X server reports changes of the screen, see [`Recorder::set_track_damage`].
+pub fn damage_tracking_available() -> bool {
+    XDisplay::new().map_or(false, |disp| unsafe { damage_available(disp.handle) != 0 })
+}
+
 pub struct X11Capturable {
     handle: *mut c_void,
     // keep a reference to the display so it is not closed while a capturable still exists
@@ -84,6 +157,22 @@ impl X11Capturable {
     pub unsafe fn handle(&mut self) -> *mut c_void {
         self.handle
     }
+
+    // X11 id of the window captured, `None` for monitors, the desktop and regions
+    fn window(&self) -> Option<c_ulong> {
+        let id = self.id();
+        let (_, window) = window_part(&id)?.rsplit_once(':')?;
+        window.parse().ok()
+    }
+}
+
+// `<class>:<window id>` of the id of a window, which is x11:window:<class>:<window id> or, if not
+// on the first screen, x11:screen<n>:window:<class>:<window id>
+fn window_part(id: &str) -> Option<&str> {
+    id.strip_prefix("x11:window:").or_else(|| {
+        let (_, window) = id.strip_prefix("x11:screen")?.split_once(":window:")?;
+        Some(window)
+    })
 }
 
 impl Capturable for X11Capturable {
@@ -95,6 +184,14 @@ impl Capturable for X11Capturable {
         }
     }
 
+    fn id(&self) -> String {
+        unsafe {
+            CStr::from_ptr(get_capturable_id(self.handle))
+                .to_string_lossy()
+                .into()
+        }
+    }
+
     fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
         let mut x: c_float = 0.0;
         let mut y: c_float = 0.0;
@@ -131,6 +228,78 @@ impl Capturable for X11Capturable {
         }
     }
 
+    fn refresh_rate(&self) -> Option<f64> {
+        let rate = unsafe { get_capturable_refresh_rate(self.handle) };
+        if rate > 0.0 {
+            Some(rate)
+        } else {
+            None
+        }
+    }
+
+    fn scale_factor(&self) -> Option<f64> {
+        self.disp.lock();
+        let scale_factor = unsafe { get_capturable_scale_factor(self.handle) };
+        self.disp.unlock();
+        if scale_factor > 0.0 {
+            Some(scale_factor)
+        } else {
+            None
+        }
+    }
+
+    fn window_class(&self) -> Option<String> {
+        let id = self.id();
+        let (class, _) = window_part(&id)?.rsplit_once(':')?;
+        (!class.is_empty()).then(|| class.to_string())
+    }
+
+    fn kind(&self) -> Option<CapturableKind> {
+        let id = self.id();
+        let id = id.strip_prefix("x11:")?;
+        // capturables not on the first screen are prefixed by screen<n>:
+        let id = match id.strip_prefix("screen") {
+            Some(id) => id.split_once(':')?.1,
+            None => id,
+        };
+        if id == "desktop" {
+            Some(CapturableKind::Desktop)
+        } else if id.starts_with("monitor:") {
+            Some(CapturableKind::Monitor)
+        } else if id.starts_with("window:") {
+            Some(CapturableKind::Window)
+        } else if id.starts_with("region:") {
+            Some(CapturableKind::Region)
+        } else {
+            None
+        }
+    }
+
+    fn pid(&self) -> Option<u32> {
+        let pid = unsafe { get_capturable_pid(self.handle) };
+        (pid > 0).then_some(pid as u32)
+    }
+
+    // windows of clients on other machines report their pid as well, which is why the name is
+    // only looked up if there is such a process here
+    fn process_name(&self) -> Option<String> {
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", self.pid()?)).ok()?;
+        let name = comm.trim_end();
+        (!name.is_empty()).then(|| name.to_string())
+    }
+
+    fn minimized(&self) -> Option<bool> {
+        self.disp.lock();
+        let minimized = unsafe { capturable_is_minimized(self.handle) };
+        self.disp.unlock();
+        (minimized >= 0).then_some(minimized == 1)
+    }
+
+    fn stacking(&self) -> Option<u32> {
+        let stacking = unsafe { get_capturable_stacking(self.handle) };
+        (stacking >= 0).then_some(stacking as u32)
+    }
+
     fn recorder(&self, capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
         match RecorderX11::new(self.clone(), capture_cursor) {
             Ok(recorder) => Ok(Box::new(recorder)),
@@ -155,15 +324,25 @@ impl Drop for X11Capturable {
 
 struct XDisplay {
     handle: *mut c_void,
+    // connections lost before this one has been opened
+    losses: c_int,
 }
 
 impl XDisplay {
     pub fn new() -> Option<Self> {
+        let losses = unsafe { x11_connection_losses() };
         let handle = unsafe { XOpenDisplay(std::ptr::null()) };
         if handle.is_null() {
             return None;
         }
-        Some(Self { handle })
+        unsafe { x11_watch_connection(handle) };
+        Some(Self { handle, losses })
+    }
+
+    /// The connection to the X server has been lost, every request on this display fails and a
+    /// new one has to be opened once the server is back.
+    pub fn is_lost(&self) -> bool {
+        unsafe { x11_connection_losses() != self.losses }
     }
 
     pub fn lock(&self) {
@@ -183,8 +362,127 @@ impl Drop for XDisplay {
     }
 }
 
+/// Rectangle of the whole desktop given as `WIDTHxHEIGHT+X+Y` like X11 geometries, see
+/// [`X11Context::region`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct CaptureRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for CaptureRegion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid region: '{}', expected 'WIDTHxHEIGHT+X+Y'.", s);
+        let (size, position) = s.split_once('+').ok_or_else(invalid)?;
+        let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+        let (x, y) = position.split_once('+').ok_or_else(invalid)?;
+        let parse = |v: &str| v.trim().parse::<u32>().map_err(|_| invalid());
+        Ok(Self {
+            x: parse(x)?,
+            y: parse(y)?,
+            width: parse(width)?,
+            height: parse(height)?,
+        })
+    }
+}
+
+impl TryFrom<String> for CaptureRegion {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CaptureRegion> for String {
+    fn from(r: CaptureRegion) -> Self {
+        format!("{}x{}+{}+{}", r.width, r.height, r.x, r.y)
+    }
+}
+
+/// Capturables listed again within this time are taken from the [`X11CapturableCache`].
+const CAPTURABLES_TTL: Duration = Duration::from_secs(1);
+
+static CAPTURABLE_CACHE: Mutex<X11CapturableCache> = Mutex::new(X11CapturableCache::new());
+
+/// Capturables of the X server listed last. Listing them queries the X server for every window,
+/// which is slow on busy desktops, so clients refreshing their lists at the same time or in quick
+/// succession share the result.
+pub struct X11CapturableCache {
+    capturables: Vec<X11Capturable>,
+    listed_at: Option<Instant>,
+    screen: Option<u32>,
+}
+
+impl X11CapturableCache {
+    const fn new() -> Self {
+        Self {
+            capturables: Vec::new(),
+            listed_at: None,
+            screen: None,
+        }
+    }
+
+    /// The cache shared by everything listing capturables.
+    pub fn global() -> MutexGuard<'static, Self> {
+        lock(&CAPTURABLE_CACHE)
+    }
+
+    /// The current capturables, these are listed again if `force` is set, the cached ones are
+    /// older than `CAPTURABLES_TTL` or the connection to the X server has been lost.
+    pub fn refresh(&mut self, force: bool) -> Result<Vec<X11Capturable>, CError> {
+        let fresh = self
+            .listed_at
+            .map_or(false, |listed_at| listed_at.elapsed() < CAPTURABLES_TTL);
+        let lost = self.capturables.iter().any(|c| c.disp.is_lost());
+        if force || !fresh || lost {
+            let mut x11ctx = X11Context::new().ok_or_else(|| {
+                CError::with_message(CErrorCode::ConnectionLost, "Failed to open X display.")
+            })?;
+            x11ctx.screen = self.screen;
+            let capturables = x11ctx.capturables()?;
+            let ids: Vec<String> = capturables.iter().map(|c| c.id()).collect();
+            let old_ids: Vec<String> = self.capturables.iter().map(|c| c.id()).collect();
+            let added: Vec<&String> = ids.iter().filter(|id| !old_ids.contains(id)).collect();
+            let removed: Vec<&String> = old_ids.iter().filter(|id| !ids.contains(id)).collect();
+            if !added.is_empty() || !removed.is_empty() {
+                debug!(
+                    "Capturables changed, added: {:?}, removed: {:?}",
+                    added, removed
+                );
+            }
+            self.capturables = capturables;
+            self.listed_at = Some(Instant::now());
+        }
+        Ok(self.capturables.clone())
+    }
+
+    /// Drop the cached capturables, their connection to the X server is closed once no recorder
+    /// or client uses them anymore.
+    pub fn clear(&mut self) {
+        self.capturables.clear();
+        self.listed_at = None;
+    }
+
+    /// Only list capturables of the given screen of the X display, see `--x11-screen`, instead of
+    /// those of all screens.
+    pub fn set_screen(&mut self, screen: Option<u32>) {
+        if self.screen != screen {
+            self.screen = screen;
+            self.clear();
+        }
+    }
+}
+
 pub struct X11Context {
     disp: Arc<XDisplay>,
+    // screen of the display to list capturables of, all screens if none
+    screen: Option<u32>,
 }
 
 impl X11Context {
@@ -192,46 +490,132 @@ impl X11Context {
         let disp = XDisplay::new()?;
         Some(Self {
             disp: Arc::new(disp),
+            screen: None,
         })
     }
 
+    /// Number of screens of the X display, :0.0 and :0.1 are two screens for example. These are
+    /// not to be confused with monitors, a single screen may span several of them.
+    pub fn screen_count(&self) -> u32 {
+        self.disp.lock();
+        let count = unsafe { XScreenCount(self.disp.handle) };
+        self.disp.unlock();
+        count.max(0) as u32
+    }
+
+    /// Restrict capturables to those of the given screen, fails if the display has no such screen.
+    pub fn set_screen(&mut self, screen: Option<u32>) -> Result<(), String> {
+        if let Some(screen) = screen {
+            let count = self.screen_count();
+            if screen >= count {
+                return Err(format!(
+                    "X11 screen {} does not exist, the display has {} screen{} numbered from 0.",
+                    screen,
+                    count,
+                    if count == 1 { "" } else { "s" }
+                ));
+            }
+        }
+        self.screen = screen;
+        Ok(())
+    }
+
     pub fn capturables(&mut self) -> Result<Vec<X11Capturable>, CError> {
-        let mut err = CError::new();
+        let screens = match self.screen {
+            Some(screen) => screen..screen + 1,
+            None => 0..self.screen_count(),
+        };
         let mut handles = [std::ptr::null_mut::<c_void>(); 128];
-        let mut num_monitors: c_int = 0;
+        let mut capturables: Vec<X11Capturable> = Vec::new();
+        for screen in screens {
+            let mut err = CError::new();
+            let mut num_monitors: c_int = 0;
+            let handles = &mut handles[capturables.len()..];
+            self.disp.lock();
+            let size = unsafe {
+                create_capturables(
+                    self.disp.handle,
+                    screen as c_int,
+                    handles.as_mut_ptr(),
+                    &mut num_monitors,
+                    handles.len() as c_int,
+                    &mut err,
+                )
+            };
+            self.disp.unlock();
+            if err.is_err() {
+                if err.code() == 2 {
+                    debug!("{}", err);
+                } else {
+                    return Err(err);
+                }
+            }
+            let mut group: Vec<X11Capturable> = handles[0..size as usize]
+                .iter()
+                .map(|handle| X11Capturable {
+                    handle: *handle,
+                    disp: self.disp.clone(),
+                })
+                .collect();
+            // Every screen is listed on its own: The first capturable is always the whole screen,
+            // after that there is num_monitors monitors and finally windows, the topmost first if
+            // the stacking order is known.
+            if let Some(win) = group.get_mut((num_monitors as usize + 1)..) {
+                win.sort_by(|a, b| {
+                    b.stacking()
+                        .cmp(&a.stacking())
+                        .then_with(|| a.name().to_lowercase().cmp(&b.name().to_lowercase()))
+                });
+            }
+            capturables.append(&mut group);
+        }
+        Ok(capturables)
+    }
+
+    /// Capturable showing a rectangle of the whole desktop, which may span several monitors, unlike
+    /// cropping a window or monitor. Input is mapped relative to the rectangle. Fails if the
+    /// rectangle does not lie within the desktop. The desktop is that of the screen set via
+    /// [`X11Context::set_screen`] or else the default screen of the display.
+    pub fn region(&mut self, region: CaptureRegion) -> Result<X11Capturable, CError> {
+        let mut err = CError::new();
         self.disp.lock();
-        let size = unsafe {
-            create_capturables(
+        let handle = unsafe {
+            create_region_capturable(
                 self.disp.handle,
-                handles.as_mut_ptr(),
-                &mut num_monitors,
-                handles.len() as c_int,
+                match self.screen {
+                    Some(screen) => screen as c_int,
+                    None => XDefaultScreen(self.disp.handle),
+                },
+                region.x.min(c_int::MAX as u32) as c_int,
+                region.y.min(c_int::MAX as u32) as c_int,
+                region.width,
+                region.height,
                 &mut err,
             )
         };
         self.disp.unlock();
         if err.is_err() {
-            if err.code() == 2 {
-                debug!("{}", err);
-            } else {
-                return Err(err);
-            }
+            return Err(err);
         }
-        let mut capturables: Vec<X11Capturable> = handles[0..size as usize]
-            .iter()
-            .map(|handle| X11Capturable {
-                handle: *handle,
-                disp: self.disp.clone(),
-            })
-            .collect();
-        // The first capturable is always the whole desktop, after that there is num_monitors
-        // monitors and finally windows.
-        let win = &mut capturables[(num_monitors as usize + 1)..(size as usize)];
-        win.sort_by(|a, b| a.name().to_lowercase().cmp(&b.name().to_lowercase()));
-        Ok(capturables)
+        Ok(X11Capturable {
+            handle,
+            disp: self.disp.clone(),
+        })
     }
 
     pub fn map_input_device_to_entire_screen(&mut self, device_name: &str, pen: bool) -> CError {
+        // devices are kept for a long time, reconnect instead of failing forever
+        if self.disp.is_lost() {
+            match XDisplay::new() {
+                Some(disp) => self.disp = Arc::new(disp),
+                None => {
+                    return CError::with_message(
+                        CErrorCode::ConnectionLost,
+                        "Lost connection to the X server.",
+                    )
+                }
+            }
+        }
         let mut err = CError::new();
         let device_name_c_str = CString::new(device_name).unwrap();
         self.disp.lock();
@@ -251,11 +635,20 @@ impl X11Context {
     }
 }
 
+// pixel formats of CImage, keep in sync with enum PixelFormat in lib/linux/xcapture.c
+const PIXEL_FORMAT_BGR0: c_int = 1;
+const PIXEL_FORMAT_RGB0: c_int = 2;
+const PIXEL_FORMAT_RGB: c_int = 3;
+
 #[repr(C)]
 struct CImage {
     data: *const u8,
     width: c_uint,
     height: c_uint,
+    // bytes per row, 0 if the rows are tightly packed
+    stride: c_uint,
+    // one of PIXEL_FORMAT_*, 0 if the format is not known
+    format: c_int,
 }
 
 impl CImage {
@@ -264,79 +657,886 @@ impl CImage {
             data: std::ptr::null(),
             width: 0,
             height: 0,
+            stride: 0,
+            format: 0,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        if self.format == PIXEL_FORMAT_RGB {
+            3
+        } else {
+            4
+        }
+    }
+
+    pub fn stride(&self) -> usize {
+        if self.stride == 0 {
+            self.width as usize * self.bytes_per_pixel()
+        } else {
+            self.stride as usize
         }
     }
 
     pub fn size(&self) -> usize {
-        (self.width * self.height * 4) as usize
+        self.stride() * self.height as usize
     }
 
-    pub fn data(&self) -> &[u8] {
-        unsafe { from_raw_parts(self.data, self.size()) }
+    /// Image data in the pixel format given by `format`, `None` if there is no valid image.
+    pub fn data(&self) -> Option<&[u8]> {
+        if self.data.is_null() || self.size() == 0 {
+            return None;
+        }
+        Some(unsafe { from_raw_parts(self.data, self.size()) })
+    }
+
+    /// Like [`CImage::data`], the image belongs to the capture context and may be modified until
+    /// the next capture.
+    pub fn data_mut(&mut self) -> Option<&mut [u8]> {
+        if self.data.is_null() || self.size() == 0 {
+            return None;
+        }
+        Some(unsafe { std::slice::from_raw_parts_mut(self.data as *mut u8, self.size()) })
+    }
+}
+
+#[repr(C)]
+struct CCursorImage {
+    data: *const u8,
+    width: c_uint,
+    height: c_uint,
+    xhot: c_int,
+    yhot: c_int,
+    x: c_int,
+    y: c_int,
+    serial: c_ulong,
+}
+
+impl CCursorImage {
+    pub fn new() -> Self {
+        Self {
+            data: std::ptr::null(),
+            width: 0,
+            height: 0,
+            xhot: 0,
+            yhot: 0,
+            x: 0,
+            y: 0,
+            serial: 0,
+        }
     }
 }
 
 pub struct RecorderX11 {
     handle: *mut c_void,
+    // connection to the X server used only by this recorder, capturing does not need to take the
+    // lock of the display shared with input handling and the capturables this way
+    disp: XDisplay,
     // keep a reference to the capturable so it is not destroyed until we are done
     #[allow(dead_code)]
     capturable: X11Capturable,
-    img: CImage,
+    // the capture context captures alternately into two images, `images[current]` is the last
+    // complete capture and stays intact while the next one is captured into the other
+    images: [CImage; 2],
+    current: usize,
     capture_cursor: bool,
+    crop: Option<(u32, u32, u32, u32)>,
+    gone: bool,
+    // holds frames whose pixel format does not support padding at the end of rows
+    buf: Vec<u8>,
+    warned_format: bool,
+    // cursor fetched last, its image is only fetched again once the cursor changed its shape
+    cursor: Option<CursorImage>,
+    // factor the cursor is scaled by when painting it into captured images
+    cursor_scale: f64,
+    track_damage: bool,
+    // crop or cursor changed since the last capture, which changes the image without damage
+    settings_changed: bool,
 }
 
 impl RecorderX11 {
-    pub fn new(mut capturable: X11Capturable, capture_cursor: bool) -> Result<Self, CError> {
+    pub fn new(mut capturable: X11Capturable, capture_cursor: bool) -> Result<Self, CaptureError> {
+        let disp = XDisplay::new()
+            .ok_or_else(|| CaptureError::CaptureStart("Failed to open X display.".into()))?;
         let mut err = CError::new();
-        capturable.disp.lock();
-        let handle = unsafe { start_capture(capturable.handle(), std::ptr::null_mut(), &mut err) };
-        capturable.disp.unlock();
+        let handle = unsafe {
+            start_capture(
+                capturable.handle(),
+                std::ptr::null_mut(),
+                disp.handle,
+                &mut err,
+            )
+        };
         if err.is_err() {
-            Err(err)
+            Err(CaptureError::CaptureStart(err.to_string()))
         } else {
             Ok(Self {
                 handle,
+                disp,
                 capturable,
-                img: CImage::new(),
+                images: [CImage::new(), CImage::new()],
+                current: 0,
                 capture_cursor,
+                crop: None,
+                gone: false,
+                buf: Vec::new(),
+                warned_format: false,
+                cursor: None,
+                cursor_scale: 1.0,
+                track_damage: false,
+                settings_changed: false,
             })
         }
     }
+
+    // Fetch the position of the cursor, and its image if it changed its shape.
+    fn update_cursor(&mut self) -> Result<(), Box<dyn Error>> {
+        let (crop_x, crop_y) = self
+            .crop
+            .map_or((0, 0), |(x, y, _, _)| (x as i32, y as i32));
+        let changed = unsafe { cursor_changed(self.handle) };
+        if let (0, Some(cursor)) = (changed, self.cursor.as_mut()) {
+            let (mut x, mut y) = (0, 0);
+            let mut err = CError::new();
+            unsafe { get_cursor_position(self.handle, &mut x, &mut y, &mut err) };
+            if err.is_err() {
+                return Err(Box::new(CaptureError::from(err)));
+            }
+            cursor.x = x - crop_x;
+            cursor.y = y - crop_y;
+            return Ok(());
+        }
+
+        let mut err = CError::new();
+        let mut img = CCursorImage::new();
+        unsafe {
+            get_cursor_image(self.handle, &mut img, &mut err);
+        }
+        if err.is_err() {
+            self.cursor = None;
+            return Err(Box::new(CaptureError::from(err)));
+        }
+        if img.data.is_null() {
+            self.cursor = None;
+            return Ok(());
+        }
+        let size = img.width as usize * img.height as usize * 4;
+        self.cursor = Some(CursorImage {
+            data: unsafe { from_raw_parts(img.data, size) }.to_vec(),
+            width: img.width as usize,
+            height: img.height as usize,
+            hotspot_x: img.xhot,
+            hotspot_y: img.yhot,
+            x: img.x - crop_x,
+            y: img.y - crop_y,
+            serial: img.serial as u64,
+        });
+        Ok(())
+    }
+
+    fn image(&self) -> &CImage {
+        &self.images[self.current]
+    }
+
+    // Paint the cursor into the image at the index, which has 4 bytes per pixel. Without XFixes
+    // there is no cursor to paint and the image is left as it is.
+    fn paint_cursor(&mut self, index: usize, rgb: bool) {
+        if self.update_cursor().is_err() {
+            return;
+        }
+        let (crop_x, crop_y) = self
+            .crop
+            .map_or((0, 0), |(x, y, _, _)| (x as i32, y as i32));
+        let img = &mut self.images[index];
+        let (width, height, stride) = (img.width as usize, img.height as usize, img.stride());
+        let blend = *lock(&CURSOR_BLEND);
+        if let (Some(cursor), Some(data)) = (self.cursor.as_ref(), img.data_mut()) {
+            paint_cursor(
+                data,
+                (width, height, stride),
+                rgb,
+                cursor,
+                (cursor.x + crop_x, cursor.y + crop_y),
+                self.cursor_scale,
+                blend,
+            );
+        }
+    }
+}
+
+// Premultiplied color of the cursor at `u`, `v` in pixels of the cursor image, interpolated between
+// the four pixels around it. Everything outside of the cursor image is transparent, which keeps the
+// edges of the cursor smooth.
+fn sample_cursor(cursor: &CursorImage, u: f64, v: f64) -> [f64; 4] {
+    let (u0, v0) = (u.floor(), v.floor());
+    let (fu, fv) = (u - u0, v - v0);
+    let mut color = [0.0; 4];
+    for (dx, dy, weight) in [
+        (0, 0, (1.0 - fu) * (1.0 - fv)),
+        (1, 0, fu * (1.0 - fv)),
+        (0, 1, (1.0 - fu) * fv),
+        (1, 1, fu * fv),
+    ] {
+        let (px, py) = (u0 as i64 + dx, v0 as i64 + dy);
+        if weight == 0.0
+            || px < 0
+            || py < 0
+            || px >= cursor.width as i64
+            || py >= cursor.height as i64
+        {
+            continue;
+        }
+        let i = (py as usize * cursor.width + px as usize) * 4;
+        let pixel = &cursor.data[i..i + 4];
+        let alpha = pixel[3] as f64;
+        for (c, p) in color[..3].iter_mut().zip(&pixel[..3]) {
+            *c += weight * *p as f64 * alpha / 255.0;
+        }
+        color[3] += weight * alpha;
+    }
+    color
+}
+
+// Paint `cursor` with its hotspot at `position` into an image of the given width, height and
+// stride with 4 bytes per pixel, BGR0 or RGB0 if `rgb` is set. The cursor is scaled around its
+// hotspot, which keeps pointing at the same pixel.
+fn paint_cursor(
+    data: &mut [u8],
+    (width, height, stride): (usize, usize, usize),
+    rgb: bool,
+    cursor: &CursorImage,
+    (x, y): (i32, i32),
+    scale: f64,
+    blend: CursorBlend,
+) {
+    if cursor.width == 0 || cursor.height == 0 {
+        return;
+    }
+    // size of the cursor once scaled and its top left corner in the image
+    let scaled_width = (cursor.width as f64 * scale).round() as i64;
+    let scaled_height = (cursor.height as f64 * scale).round() as i64;
+    let x0 = x as i64 - (cursor.hotspot_x as f64 * scale).round() as i64;
+    let y0 = y as i64 - (cursor.hotspot_y as f64 * scale).round() as i64;
+    // only the part of the cursor inside the image is painted
+    for j in (-y0).max(0)..scaled_height.min(height as i64 - y0) {
+        let row = (y0 + j) as usize * stride;
+        for i in (-x0).max(0)..scaled_width.min(width as i64 - x0) {
+            // centers of the pixels of the scaled cursor, relative to the cursor image
+            let u = (i as f64 + 0.5) / scale;
+            let v = (j as f64 + 0.5) / scale;
+            let [b, g, r, a] = match blend {
+                CursorBlend::Alpha => sample_cursor(cursor, u - 0.5, v - 0.5),
+                CursorBlend::Key => {
+                    let ci = (u as usize).min(cursor.width - 1);
+                    let cj = (v as usize).min(cursor.height - 1);
+                    let k = (cj * cursor.width + ci) * 4;
+                    let pixel = &cursor.data[k..k + 4];
+                    if pixel[3] < 128 {
+                        continue;
+                    }
+                    [pixel[0] as f64, pixel[1] as f64, pixel[2] as f64, 255.0]
+                }
+            };
+            if a <= 0.0 {
+                continue;
+            }
+            let k = row + (x0 + i) as usize * 4;
+            let colors = if rgb { [r, g, b] } else { [b, g, r] };
+            for (d, c) in data[k..k + 3].iter_mut().zip(colors) {
+                *d = (c + *d as f64 * (255.0 - a) / 255.0).round().min(255.0) as u8;
+            }
+        }
+    }
 }
 
 impl Drop for RecorderX11 {
     fn drop(&mut self) {
         let mut err = CError::new();
-        self.capturable.disp.lock();
+        // the display is closed after this as fields are dropped only afterwards
         unsafe {
             stop_capture(self.handle, &mut err);
         }
-        self.capturable.disp.unlock();
     }
 }
 
 impl Recorder for RecorderX11 {
     fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
         let mut err = CError::new();
-        self.capturable.disp.lock();
+        let back = 1 - self.current;
         unsafe {
-            capture_screen(
-                self.handle,
-                &mut self.img,
-                self.capture_cursor.into(),
-                &mut err,
-            );
+            capture_screen(self.handle, &mut self.images[back], &mut err);
         }
-        self.capturable.disp.unlock();
+        self.settings_changed = false;
         if err.is_err() {
-            self.img.data = std::ptr::null();
-            Err(err.into())
+            // the images may have been recreated for a new size, so neither is valid anymore
+            self.images = [CImage::new(), CImage::new()];
+            if self.disp.is_lost() {
+                // there is no way to continue with the same display, the window may be restored
+                // once the X server is back but this is up to whoever created the recorder
+                self.gone = true;
+                return Err(Box::new(CaptureError::ConnectionLost(err.to_string())));
+            }
+            if let CErrorCode::CapturableGone = err.to_enum() {
+                self.gone = true;
+            }
+            return Err(Box::new(CaptureError::from(err)));
+        }
+        // the cursor is painted before the image becomes the current one, so it is complete once
+        // it can be read
+        let (width, height) = (self.images[back].width, self.images[back].height);
+        if width == 0 || height == 0 {
+            self.current = back;
+            debug!("Captured image is empty.");
+            return Ok(PixelProvider::BGR0(0, 0, &[]));
+        }
+        let format = match self.images[back].format {
+            PIXEL_FORMAT_BGR0 | PIXEL_FORMAT_RGB0 | PIXEL_FORMAT_RGB => self.images[back].format,
+            format => {
+                if !self.warned_format {
+                    warn!(
+                        "Captured image has an unsupported pixel format ({}), assuming BGR0, \
+                        colors may be wrong.",
+                        format
+                    );
+                    self.warned_format = true;
+                }
+                PIXEL_FORMAT_BGR0
+            }
+        };
+        // there is no room for alpha in RGB images
+        if self.capture_cursor && format != PIXEL_FORMAT_RGB {
+            self.paint_cursor(back, format == PIXEL_FORMAT_RGB0);
+        }
+        self.current = back;
+        let img = &self.images[self.current];
+        let bytes_per_pixel = img.bytes_per_pixel();
+        let stride = img.stride();
+        let data = match img.data() {
+            Some(data) => data,
+            None => {
+                return Err(Box::new(CaptureError::CaptureFrame(
+                    "Captured image is empty!".into(),
+                )))
+            }
+        };
+        let (mut x0, mut y0, mut w, mut h) = (0, 0, width as usize, height as usize);
+        if let Some(crop) = self.crop {
+            match clamp_crop(crop, (width, height)) {
+                Some((x, y, crop_w, crop_h)) => {
+                    (x0, y0) = (x as usize, y as usize);
+                    (w, h) = (crop_w as usize, crop_h as usize);
+                }
+                None => {
+                    warn!(
+                        "Crop {:?} is outside of the captured image of size {}x{}, capturing the \
+                        whole image instead.",
+                        crop, width, height
+                    );
+                    self.crop = None;
+                }
+            }
+        }
+        let start = y0 * stride + x0 * bytes_per_pixel;
+        let end = start + (h - 1) * stride + w * bytes_per_pixel;
+        let mut data = &data[start..end];
+        if format == PIXEL_FORMAT_BGR0 {
+            if stride == w * 4 {
+                return Ok(PixelProvider::BGR0(w, h, data));
+            }
+            return Ok(PixelProvider::BGR0S(w, h, stride, data));
+        }
+        // only BGR0 supports padding at the end of rows, copy the rows of other formats
+        if stride != w * bytes_per_pixel {
+            self.buf.clear();
+            for row in data.chunks(stride) {
+                self.buf.extend_from_slice(&row[..w * bytes_per_pixel]);
+            }
+            data = &self.buf;
+        }
+        if format == PIXEL_FORMAT_RGB0 {
+            Ok(PixelProvider::RGB0(w, h, data))
         } else {
-            Ok(PixelProvider::BGR0(
-                self.img.width as usize,
-                self.img.height as usize,
-                self.img.data(),
-            ))
+            Ok(PixelProvider::RGB(w, h, data))
+        }
+    }
+
+    fn set_crop(&mut self, crop: Option<(u32, u32, u32, u32)>) {
+        self.settings_changed |= crop != self.crop;
+        self.crop = crop;
+    }
+
+    fn crop(&self) -> Option<(u32, u32, u32, u32)> {
+        self.crop
+    }
+
+    fn set_capture_cursor(&mut self, capture_cursor: bool) {
+        self.settings_changed |= capture_cursor != self.capture_cursor;
+        self.capture_cursor = capture_cursor;
+    }
+
+    fn set_vsync(&mut self, vsync: bool) {
+        let aligned = unsafe { set_vsync(self.handle, vsync.into()) } != 0;
+        if vsync && !aligned {
+            warn!(
+                "Can not wait for vertical blanks, captures are not aligned to them. This requires \
+                access to a DRM device like /dev/dri/card0, usually granted to the video group."
+            );
+        }
+    }
+
+    fn set_track_damage(&mut self, track: bool) {
+        self.track_damage = unsafe { set_track_damage(self.handle, track.into()) } != 0;
+        if track && !self.track_damage {
+            warn!(
+                "The X server does not support the Damage extension, capturing frames \
+                continuously instead of only once the screen changed."
+            );
+        }
+    }
+
+    fn set_cursor_scale(&mut self, scale: f64) {
+        self.cursor_scale = if scale > 0.0 { scale } else { 1.0 };
+        self.settings_changed = true;
+    }
+
+    fn damaged(&mut self) -> Option<bool> {
+        if !self.track_damage {
+            return None;
+        }
+        match unsafe { image_damaged(self.handle, self.capture_cursor.into()) } {
+            damaged if damaged < 0 => None,
+            damaged => Some(damaged > 0 || self.settings_changed || self.image().data().is_none()),
+        }
+    }
+
+    fn size_uncropped(&self) -> Option<(usize, usize)> {
+        let img = self.image();
+        img.data()?;
+        Some((img.width as usize, img.height as usize))
+    }
+
+    fn is_gone(&self) -> bool {
+        self.gone
+    }
+
+    fn cursor(&mut self) -> Result<Option<CursorImage>, Box<dyn Error>> {
+        self.update_cursor()?;
+        Ok(self.cursor.clone())
+    }
+
+    fn frame_bgra(&self) -> Option<(&[u8], usize, usize)> {
+        // rows with padding can not be represented here, unknown formats are assumed to be BGR0
+        let img = self.image();
+        let bgr0 = !matches!(img.format, PIXEL_FORMAT_RGB0 | PIXEL_FORMAT_RGB);
+        if !bgr0 || img.stride() != img.width as usize * 4 {
+            return None;
+        }
+        Some((img.data()?, img.width as usize, img.height as usize))
+    }
+}
+
+/// How often the recorder of a [`FocusedWindowCapturable`] asks which window has the focus.
+const FOCUS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// A window has to keep the focus this long before it is captured, so cycling through windows via
+/// alt-tab does not flash each of them. Closed windows are replaced right away.
+const FOCUS_DEBOUNCE: Duration = Duration::from_millis(400);
+
+/// Captures whichever window has the focus, for example to present several applications without
+/// selecting each of them. While no window has the focus the whole screen is captured. Input is
+/// mapped to the window shown, which is shared by all clients capturing the same screen.
+#[derive(Clone)]
+pub struct FocusedWindowCapturable {
+    desktop: X11Capturable,
+    screen: c_int,
+    // window captured at the moment, the desktop if none
+    current: Arc<Mutex<X11Capturable>>,
+}
+
+impl FocusedWindowCapturable {
+    /// One per screen listed, `capturables` as returned by [`X11CapturableCache::refresh`].
+    pub fn for_screens(capturables: &[X11Capturable]) -> Vec<Self> {
+        capturables
+            .iter()
+            .filter_map(|c| {
+                let id = c.id();
+                let screen = match id.strip_suffix("desktop")? {
+                    "x11:" => 0,
+                    prefix => prefix
+                        .strip_prefix("x11:screen")?
+                        .strip_suffix(':')?
+                        .parse()
+                        .ok()?,
+                };
+                Some(Self {
+                    desktop: c.clone(),
+                    screen,
+                    current: Arc::new(Mutex::new(c.clone())),
+                })
+            })
+            .collect()
+    }
+
+    // what comes before "desktop" in the id of the desktop, "x11:" or "x11:screen<n>:"
+    fn id_prefix(&self) -> String {
+        let id = self.desktop.id();
+        id.strip_suffix("desktop").unwrap_or(&id).to_string()
+    }
+
+    // the window with the given X11 id on the screen, the list of windows is only refreshed if
+    // `refresh` is set, for example because the window may have been opened just now
+    fn find_window(&self, window: c_ulong, refresh: bool) -> Option<X11Capturable> {
+        let prefix = format!("{}window:", self.id_prefix());
+        let capturables = match X11CapturableCache::global().refresh(refresh) {
+            Ok(capturables) => capturables,
+            Err(err) => {
+                debug!("Failed to list windows: {}", err);
+                return None;
+            }
+        };
+        capturables
+            .into_iter()
+            .find(|c| c.id().starts_with(&prefix) && c.window() == Some(window))
+    }
+}
+
+impl Capturable for FocusedWindowCapturable {
+    fn name(&self) -> String {
+        let name = self.desktop.name();
+        format!(
+            "{}Focused window",
+            name.strip_suffix("Desktop").unwrap_or("")
+        )
+    }
+
+    fn id(&self) -> String {
+        format!("{}focused", self.id_prefix())
+    }
+
+    fn geometry_relative(&self) -> Result<(f64, f64, f64, f64), Box<dyn Error>> {
+        lock(&self.current).geometry_relative()
+    }
+
+    fn before_input(&mut self) -> Result<(), Box<dyn Error>> {
+        lock(&self.current).before_input()
+    }
+
+    fn refresh_rate(&self) -> Option<f64> {
+        self.desktop.refresh_rate()
+    }
+
+    fn scale_factor(&self) -> Option<f64> {
+        self.desktop.scale_factor()
+    }
+
+    fn kind(&self) -> Option<CapturableKind> {
+        Some(CapturableKind::Window)
+    }
+
+    fn recorder(&self, capture_cursor: bool) -> Result<Box<dyn Recorder>, Box<dyn Error>> {
+        let disp = XDisplay::new()
+            .ok_or_else(|| CaptureError::CaptureStart("Failed to open X display.".into()))?;
+        let recorder = RecorderX11::new(self.desktop.clone(), capture_cursor)?;
+        let mut recorder = FocusedWindowRecorder {
+            capturable: self.clone(),
+            disp,
+            recorder,
+            window: None,
+            candidate: None,
+            not_found: None,
+            polled_at: None,
+            switched: false,
+            capture_cursor,
+            crop: None,
+            vsync: false,
+            track_damage: false,
+            cursor_scale: 1.0,
+        };
+        // the window focused when starting is captured right away
+        if let Some(window) = recorder.active_window() {
+            recorder.switch_to(Some(window));
+        }
+        Ok(Box::new(recorder))
+    }
+}
+
+pub struct FocusedWindowRecorder {
+    capturable: FocusedWindowCapturable,
+    // connection used to ask for the focused window
+    disp: XDisplay,
+    recorder: RecorderX11,
+    // window captured, `None` for the desktop
+    window: Option<c_ulong>,
+    // window that got the focus and since when, it is captured once it kept it for FOCUS_DEBOUNCE
+    candidate: Option<(c_ulong, Instant)>,
+    // focused window that is not listed, like docks or the desktop of some window managers, it is
+    // not searched for again as long as it keeps the focus
+    not_found: Option<c_ulong>,
+    polled_at: Option<Instant>,
+    // another window has been captured since the last call to damaged
+    switched: bool,
+    // settings applied to the recorder of every window
+    capture_cursor: bool,
+    crop: Option<(u32, u32, u32, u32)>,
+    vsync: bool,
+    track_damage: bool,
+    cursor_scale: f64,
+}
+
+impl FocusedWindowRecorder {
+    fn active_window(&self) -> Option<c_ulong> {
+        let mut err = CError::new();
+        let window =
+            unsafe { get_active_window(self.disp.handle, self.capturable.screen, &mut err) };
+        if err.is_err() {
+            debug!("Failed to get the focused window: {}", err);
+        }
+        (window != 0).then_some(window)
+    }
+
+    // capture the given window or, if none, the desktop, keeps the current one on failure
+    fn switch_to(&mut self, window: Option<c_ulong>) {
+        let capturable = match window {
+            Some(window) => match self.capturable.find_window(window, true) {
+                Some(capturable) => capturable,
+                None => {
+                    debug!("Focused window {} is not listed, ignoring it.", window);
+                    self.not_found = Some(window);
+                    return;
+                }
+            },
+            None => self.capturable.desktop.clone(),
+        };
+        match RecorderX11::new(capturable.clone(), self.capture_cursor) {
+            Ok(mut recorder) => {
+                debug!("Capturing focused window: {}", capturable.name());
+                recorder.set_crop(self.crop);
+                if self.vsync {
+                    recorder.set_vsync(true);
+                }
+                if self.track_damage {
+                    recorder.set_track_damage(true);
+                }
+                if self.cursor_scale != 1.0 {
+                    recorder.set_cursor_scale(self.cursor_scale);
+                }
+                self.recorder = recorder;
+                self.window = window;
+                self.switched = true;
+                *lock(&self.capturable.current) = capturable;
+            }
+            Err(err) => warn!("Failed to capture focused window: {}", err),
+        }
+    }
+
+    fn follow_focus(&mut self) {
+        let gone = self.recorder.is_gone();
+        if !gone
+            && self
+                .polled_at
+                .map_or(false, |polled_at| polled_at.elapsed() < FOCUS_POLL_INTERVAL)
+        {
+            return;
         }
+        self.polled_at = Some(Instant::now());
+        let active = self.active_window();
+        if active != self.not_found {
+            self.not_found = None;
+        }
+        let active = active.filter(|window| Some(*window) != self.not_found);
+        if gone {
+            // the window has been closed, show what has the focus now without waiting
+            self.candidate = None;
+            self.switch_to(active.filter(|window| Some(*window) != self.window));
+            return;
+        }
+        let window = match active {
+            Some(window) if Some(window) != self.window => window,
+            _ => {
+                self.candidate = None;
+                return;
+            }
+        };
+        match self.candidate {
+            Some((candidate, since)) if candidate == window => {
+                if since.elapsed() >= FOCUS_DEBOUNCE {
+                    self.candidate = None;
+                    self.switch_to(Some(window));
+                }
+            }
+            _ => self.candidate = Some((window, Instant::now())),
+        }
+    }
+}
+
+impl Recorder for FocusedWindowRecorder {
+    fn capture(&mut self) -> Result<PixelProvider, Box<dyn Error>> {
+        self.follow_focus();
+        self.recorder.capture()
+    }
+
+    fn set_crop(&mut self, crop: Option<(u32, u32, u32, u32)>) {
+        self.crop = crop;
+        self.recorder.set_crop(crop);
+    }
+
+    fn crop(&self) -> Option<(u32, u32, u32, u32)> {
+        self.recorder.crop()
+    }
+
+    fn set_capture_cursor(&mut self, capture_cursor: bool) {
+        self.capture_cursor = capture_cursor;
+        self.recorder.set_capture_cursor(capture_cursor);
+    }
+
+    fn set_vsync(&mut self, vsync: bool) {
+        self.vsync = vsync;
+        self.recorder.set_vsync(vsync);
+    }
+
+    fn set_track_damage(&mut self, track: bool) {
+        self.track_damage = track;
+        self.recorder.set_track_damage(track);
+    }
+
+    fn set_cursor_scale(&mut self, scale: f64) {
+        self.cursor_scale = scale;
+        self.recorder.set_cursor_scale(scale);
+    }
+
+    fn damaged(&mut self) -> Option<bool> {
+        // the focus is only followed while capturing, so it is checked here as well
+        self.follow_focus();
+        let damaged = self.recorder.damaged()?;
+        Some(damaged || std::mem::take(&mut self.switched))
+    }
+
+    fn size_uncropped(&self) -> Option<(usize, usize)> {
+        self.recorder.size_uncropped()
+    }
+
+    fn is_gone(&self) -> bool {
+        // closed windows are replaced, only losing the X server ends capturing
+        self.disp.is_lost()
+    }
+
+    fn cursor(&mut self) -> Result<Option<CursorImage>, Box<dyn Error>> {
+        self.recorder.cursor()
+    }
+
+    fn frame_bgra(&self) -> Option<(&[u8], usize, usize)> {
+        self.recorder.frame_bgra()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // capturables of the X server given by DISPLAY, the screen comes first
+    fn capturables() -> Vec<X11Capturable> {
+        x11_init();
+        let mut x11ctx = X11Context::new().expect("Failed to connect to the X server.");
+        x11ctx.capturables().unwrap()
+    }
+
+    #[test]
+    fn lock_recovers_from_poisoning() {
+        let mutex = Arc::new(Mutex::new(1));
+        {
+            let mutex = mutex.clone();
+            let res = std::thread::spawn(move || {
+                let _guard = mutex.lock().unwrap();
+                panic!("Poisoning the mutex.");
+            })
+            .join();
+            assert!(res.is_err());
+        }
+        assert!(mutex.is_poisoned());
+        *lock(&mutex) += 1;
+        assert_eq!(*lock(&mutex), 2);
+    }
+
+    // create, capture from and drop recorders of several capturables at the same time, each of
+    // them sets up and tears down its own shared memory image and connection to the X server
+    #[test]
+    #[ignore = "needs an X server given by DISPLAY"]
+    fn recorders_concurrently() {
+        let capturables: Vec<_> = capturables().into_iter().take(4).collect();
+        for _ in 0..10 {
+            let threads: Vec<_> = (0..8)
+                .map(|i| {
+                    let capturable = capturables[i % capturables.len()].clone();
+                    std::thread::spawn(move || {
+                        let mut recorder = capturable.recorder(true).unwrap();
+                        for _ in 0..3 {
+                            let frame = recorder.capture().unwrap();
+                            let (width, height) = frame.size();
+                            assert!(width > 0 && height > 0);
+                            let len =
+                                frame.stride() * (height - 1) + width * frame.bytes_per_pixel();
+                            assert!(frame.data().len() >= len);
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+        }
+    }
+
+    // another thread keeps reading the last frame while the next one is captured, which goes into
+    // the other image, so the reader never sees the frame change
+    #[test]
+    #[ignore = "needs an X server given by DISPLAY"]
+    fn no_torn_reads() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let capturable = capturables().remove(0);
+        let mut recorder = capturable.recorder(true).unwrap();
+        for _ in 0..20 {
+            let frame = recorder.capture().unwrap();
+            let expected = frame.data().to_vec();
+            // the next capture borrows the recorder, so the reader only gets the address of the
+            // frame, which stays mapped as long as the size of the capturable does not change
+            let (addr, len) = (frame.data().as_ptr() as usize, frame.data().len());
+            let done = Arc::new(AtomicBool::new(false));
+            let reader = {
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    let data = unsafe { from_raw_parts(addr as *const u8, len) };
+                    let mut reads = 0;
+                    loop {
+                        assert!(data == &expected[..], "Frame changed while being read.");
+                        reads += 1;
+                        if done.load(Ordering::Acquire) {
+                            return reads;
+                        }
+                    }
+                })
+            };
+            let next = recorder.capture().unwrap();
+            assert_ne!(next.data().as_ptr() as usize, addr);
+            // the capture after this one goes into the image being read
+            done.store(true, Ordering::Release);
+            assert!(reader.join().unwrap() > 0);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an X server given by DISPLAY"]
+    fn capture_and_drop_after_panic() {
+        let capturable = capturables().remove(0);
+        // a thread panicked while selecting how to paint the cursor
+        let res = std::thread::spawn(|| {
+            let _guard = CURSOR_BLEND.lock().unwrap();
+            panic!("Poisoning the cursor blend mode.");
+        })
+        .join();
+        assert!(res.is_err());
+        set_cursor_blend(CursorBlend::Alpha);
+        let mut recorder = capturable.recorder(true).unwrap();
+        recorder.capture().unwrap();
+        drop(recorder);
     }
 }
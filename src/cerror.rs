@@ -1,9 +1,10 @@
 use std::error::Error;
-use std::ffi::CStr;
 use std::fmt;
 
 use std::os::raw::{c_char, c_int};
 
+/// Error filled in by C code. The message is stored inline, so there is nothing to free on either
+/// side and errors can be created and dropped freely.
 #[repr(C)]
 pub struct CError {
     code: c_int,
@@ -14,6 +15,10 @@ pub enum CErrorCode {
     NoError,
     GenericError,
     UInputNotAccessible,
+    CapturableGone,
+    Unsupported,
+    /// The connection to the display server has been lost, a new one may succeed once it is back.
+    ConnectionLost,
 }
 
 impl CError {
@@ -24,6 +29,24 @@ impl CError {
         }
     }
 
+    /// Error detected in Rust before calling into C, `msg` is truncated to fit.
+    pub fn with_message(code: CErrorCode, msg: &str) -> Self {
+        let mut err = Self::new();
+        err.code = match code {
+            CErrorCode::NoError => 0,
+            CErrorCode::GenericError => 1,
+            CErrorCode::UInputNotAccessible => 101,
+            CErrorCode::CapturableGone => 102,
+            CErrorCode::Unsupported => 103,
+            CErrorCode::ConnectionLost => 104,
+        };
+        // keep the terminating null byte
+        for (c, b) in err.error_str[..1023].iter_mut().zip(msg.bytes()) {
+            *c = b as c_char;
+        }
+        err
+    }
+
     pub fn is_err(&self) -> bool {
         self.code != 0
     }
@@ -32,10 +55,25 @@ impl CError {
         self.code as i32
     }
 
+    /// Copy of the message, which is read up to the first null byte but never past the end of the
+    /// buffer, even if C code failed to terminate it.
+    pub fn message(&self) -> String {
+        let bytes: Vec<u8> = self
+            .error_str
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as u8)
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
     pub fn to_enum(&self) -> CErrorCode {
         match self.code {
             0 => CErrorCode::NoError,
             101 => CErrorCode::UInputNotAccessible,
+            102 => CErrorCode::CapturableGone,
+            103 => CErrorCode::Unsupported,
+            104 => CErrorCode::ConnectionLost,
             _ => CErrorCode::GenericError,
         }
     }
@@ -43,9 +81,7 @@ impl CError {
 
 impl fmt::Display for CError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "CError: code: {} message: {}", self.code, unsafe {
-            CStr::from_ptr(self.error_str.as_ptr()).to_string_lossy()
-        })
+        write!(f, "CError: code: {} message: {}", self.code, self.message())
     }
 }
 
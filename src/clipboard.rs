@@ -0,0 +1,88 @@
+use std::os::raw::{c_char, c_int, c_void};
+use std::slice::from_raw_parts;
+use std::time::Duration;
+
+use crate::cerror::{CError, CErrorCode};
+
+extern "C" {
+    fn clipboard_init(max_size: usize, err: *mut CError) -> *mut c_void;
+    fn clipboard_destroy(handle: *mut c_void);
+    fn clipboard_set_text(handle: *mut c_void, text: *const c_char, len: usize, err: *mut CError);
+    fn clipboard_poll(
+        handle: *mut c_void,
+        timeout_ms: c_int,
+        text: *mut *const c_char,
+        len: *mut usize,
+    ) -> c_int;
+}
+
+/// Reads and writes text of the X11 CLIPBOARD selection. This uses its own connection to the
+/// X server and has to stay on the thread it has been created on.
+pub struct X11Clipboard {
+    handle: *mut c_void,
+}
+
+impl X11Clipboard {
+    /// Text larger than `max_size` bytes is ignored.
+    pub fn new(max_size: usize) -> Result<Self, CError> {
+        let mut err = CError::new();
+        let handle = unsafe { clipboard_init(max_size, &mut err) };
+        if err.is_err() {
+            Err(err)
+        } else {
+            Ok(Self { handle })
+        }
+    }
+
+    /// Take ownership of the clipboard and offer the text to other applications.
+    pub fn set_text(&mut self, text: &str) -> Result<(), CError> {
+        let mut err = CError::new();
+        unsafe {
+            clipboard_set_text(
+                self.handle,
+                text.as_ptr() as *const c_char,
+                text.len(),
+                &mut err,
+            )
+        };
+        if err.is_err() {
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Serve requests of other applications for up to `timeout` and return the text of the
+    /// clipboard if another application changed it in the meantime. Fails with
+    /// `CErrorCode::ConnectionLost` once the connection to the X server is gone, a new clipboard
+    /// has to be created then.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Option<String>, CError> {
+        let mut text: *const c_char = std::ptr::null();
+        let mut len = 0;
+        let changed = unsafe {
+            clipboard_poll(
+                self.handle,
+                timeout.as_millis() as c_int,
+                &mut text,
+                &mut len,
+            )
+        };
+        if changed < 0 {
+            return Err(CError::with_message(
+                CErrorCode::ConnectionLost,
+                "Lost connection to the X server.",
+            ));
+        }
+        if changed == 0 || text.is_null() {
+            return Ok(None);
+        }
+        let text = unsafe { from_raw_parts(text as *const u8, len) };
+        Ok(Some(String::from_utf8_lossy(text).into_owned()))
+    }
+}
+
+impl Drop for X11Clipboard {
+    fn drop(&mut self) {
+        unsafe { clipboard_destroy(self.handle) };
+    }
+}
@@ -1,21 +1,77 @@
 use std::fs;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use structopt::clap::ArgMatches;
 use structopt::StructOpt;
-use tracing::warn;
+use tracing::{error, info, warn};
+
+use crate::capturable::{CapturableFilter, CaptureMode};
+#[cfg(target_os = "linux")]
+use crate::capturable::{CaptureBackend, CaptureRegion, CursorBlend, WindowCapture};
+#[cfg(target_os = "linux")]
+use crate::sched::CpuList;
+use crate::video::{ChromaFilter, Color, ColorMatrix, ColorRange, QualityPreset, VideoCodec};
+use crate::websocket::CongestionStrategy;
 
 #[derive(Serialize, Deserialize, StructOpt, Debug, Clone)]
 #[structopt(name = "weylus")]
 pub struct Config {
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Read the configuration from this TOML file, or JSON file if it ends with .json, \
+        instead of weylus.toml in the configuration directory. Options given on the command line \
+        override the values of the file."
+    )]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
     #[structopt(long, help = "Access code")]
     pub access_code: Option<String>,
-    #[structopt(long, default_value = "0.0.0.0", help = "Bind address")]
-    pub bind_address: IpAddr,
+    #[structopt(
+        long,
+        default_value = "0.0.0.0",
+        number_of_values = 1,
+        help = "Address to listen at, may be given several times to listen at more than one, for \
+        example only at the address of a VPN interface. IPv6 addresses are supported, :: usually \
+        accepts IPv4 connections as well. Link-local IPv6 addresses need the interface, like \
+        fe80::1%tun0. Weylus fails to start if it can not listen at any of them."
+    )]
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind_address: Vec<BindAddress>,
     #[structopt(long, default_value = "1701", help = "Web port")]
     pub web_port: u16,
     #[structopt(long, default_value = "9001", help = "Websocket port")]
     pub websocket_port: u16,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Address the websocket server listens at instead of --bind-address, may be given \
+        several times like it. This allows to only accept websocket connections from a proxy \
+        running on this computer, by listening at 127.0.0.1, while the web server is reachable by \
+        other computers."
+    )]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub websocket_bind_address: Vec<BindAddress>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Serve the web page via HTTPS and the websocket via WSS using this certificate in \
+        PEM format, which may be followed by the certificates of intermediate CAs. Requires \
+        --tls-key."
+    )]
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Private key of the certificate given by --tls-cert in PEM format."
+    )]
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
     #[cfg(target_os = "linux")]
     #[structopt(
         long,
@@ -23,9 +79,21 @@ pub struct Config {
     )]
     pub try_vaapi: bool,
     #[cfg(any(target_os = "linux", target_os = "windows"))]
-    #[structopt(long, help = "Try to use Nvidia's NVENC to encode the video via GPU.")]
+    #[structopt(
+        long,
+        help = "Try to use Nvidia's NVENC to encode the video via GPU. This is enabled \
+        automatically if NVENC works on this machine, see --no-nvenc."
+    )]
     #[serde(default)]
     pub try_nvenc: bool,
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    #[structopt(
+        long,
+        help = "Do not use NVENC just because it works on this machine, --try-nvenc still \
+        enables it."
+    )]
+    #[serde(default)]
+    pub no_nvenc: bool,
     #[cfg(target_os = "macos")]
     #[structopt(
         long,
@@ -40,16 +108,640 @@ pub struct Config {
     )]
     #[serde(default)]
     pub try_mediafoundation: bool,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Number of threads used to convert and scale video frames, 0 picks one thread per \
+        CPU core."
+    )]
+    #[serde(default)]
+    pub conversion_threads: usize,
+    #[structopt(
+        long,
+        default_value = "bt601",
+        possible_values = &["bt601", "bt709"],
+        help = "Color coefficients used to convert frames from RGB to YUV."
+    )]
+    #[serde(default)]
+    pub color_matrix: ColorMatrix,
+    #[structopt(
+        long,
+        default_value = "limited",
+        possible_values = &["limited", "full"],
+        help = "Range of the YUV values of the video, try full range if black looks gray or dark \
+        colors look crushed."
+    )]
+    #[serde(default)]
+    pub color_range: ColorRange,
+    #[structopt(
+        long,
+        default_value = "box",
+        possible_values = &["box", "weighted"],
+        help = "How colors are subsampled when converting frames to YUV 4:2:0 in software, box \
+        averages neighbouring pixels while weighted takes more pixels into account, which reduces \
+        artifacts along colored edges but is slower."
+    )]
+    #[serde(default)]
+    pub chroma_filter: ChromaFilter,
+    #[structopt(
+        long,
+        help = "Encode video without chroma subsampling (YUV 4:4:4), this keeps colored text \
+        and thin lines sharp but requires more bandwidth, always encodes in software and is not \
+        supported by all browsers."
+    )]
+    #[serde(default)]
+    pub yuv444: bool,
+    #[structopt(
+        long,
+        help = "Encode video in grayscale, this greatly reduces the bandwidth required for text \
+        and always encodes in software. Clients can enable this for themselves as well."
+    )]
+    #[serde(default)]
+    pub grayscale: bool,
+    #[structopt(
+        long,
+        default_value = "h264",
+        possible_values = &["h264", "vp9", "av1"],
+        help = "Preferred video codec, VP9 and even more so AV1 give better quality at the same \
+        bitrate but are always encoded in software. AV1 is very CPU intensive and best suited for \
+        slow connections. Clients that can not decode the preferred codec get H.264."
+    )]
+    #[serde(default)]
+    pub codec: VideoCodec,
+    #[structopt(
+        long,
+        help = "Do not encode and send frames that did not change since the last frame. Frames \
+        are compared by a hash, which is cheap compared to encoding them."
+    )]
+    #[serde(default)]
+    pub skip_unchanged_frames: bool,
+    #[structopt(
+        long,
+        help = "If unchanged frames are skipped, still send every n-th unchanged frame as a \
+        keyframe, this lets clients recover from a broken video even if nothing changes."
+    )]
+    pub unchanged_frames_refresh: Option<u32>,
+    #[structopt(
+        long,
+        default_value = "poll",
+        possible_values = &["poll", "damage"],
+        help = "When to capture frames: poll captures one for every frame requested by a client, \
+        damage only once the X server reports that the screen changed, which saves almost all CPU \
+        time while nothing changes. Captures via PipeWire and X servers without the Damage \
+        extension fall back to poll."
+    )]
+    #[serde(default)]
+    pub capture_mode: CaptureMode,
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "With --capture-mode damage, still send this many frames per second as keyframes \
+        while the screen does not change, this lets clients recover from a broken video."
+    )]
+    #[serde(default = "default_heartbeat_fps")]
+    pub heartbeat_fps: f64,
+    #[structopt(
+        long,
+        help = "Send a frame as keyframe if at least this fraction of it, between 0 and 1, changed \
+        compared to the previous frame, for example 0.6. This shows new content like another \
+        virtual desktop in full quality right away. Disabled by default."
+    )]
+    pub scene_change_threshold: Option<f64>,
+    #[structopt(
+        long,
+        default_value = "2000",
+        help = "Milliseconds after a keyframe sent because of a scene change during which further \
+        scene changes do not cause keyframes, so rapidly changing content does not flood the \
+        connection with them."
+    )]
+    #[serde(default = "default_scene_change_cooldown")]
+    pub scene_change_cooldown: u64,
+    #[structopt(
+        long,
+        default_value = "1000",
+        help = "Minimum milliseconds between two keyframes sent because a client failed to decode \
+        the video and asked to resync. Requests in between are answered with a single keyframe \
+        once this time passed, so a client that keeps failing does not flood the connection with \
+        keyframes. Each client only resyncs its own video."
+    )]
+    #[serde(default = "default_keyframe_request_cooldown")]
+    pub keyframe_request_cooldown: u64,
+    #[structopt(
+        long,
+        help = "Reduce the colors of captured frames to the given number of bits per channel, \
+        from 1 to 7, before encoding them. Around 6 bits turn the subtle gradients of desktop \
+        interfaces into flat areas that compress a lot better at the price of slight banding."
+    )]
+    pub quantize_bits: Option<u8>,
+    #[structopt(
+        long,
+        help = "Reduce captured frames to 16 bit colors, 5 bits for red and blue and 6 for green, \
+        like the low color mode of RDP, while keeping grays neutral. Chroma is always \
+        subsampled then, --yuv444 is ignored. Meant for very slow connections, see also \
+        --quality-preset lowcolor."
+    )]
+    #[serde(default)]
+    pub low_color: bool,
+    #[structopt(
+        long,
+        help = "Flatten the alpha channel of captured frames onto this color, given as #rrggbb, \
+        so transparent parts of windows like rounded corners show it instead of black. Only use \
+        this if the captured windows have an alpha channel, windows without one may show up \
+        entirely in this color."
+    )]
+    pub alpha_background: Option<Color>,
+    #[structopt(
+        long,
+        help = "Replace fully transparent pixels of captured frames with this color, given as \
+        #rrggbb, while leaving all others as they are. This cleans up areas of composited \
+        desktops nothing has been drawn to, like a missing wallpaper. As with \
+        --alpha-background, frames without an alpha channel may show up entirely in this color."
+    )]
+    pub transparent_fill: Option<Color>,
+    #[structopt(
+        long,
+        help = "Keep the aspect ratio of the video if it differs from the one of the screen of the \
+        client, adding bars of this color, given as #rrggbb, at the top and bottom or left and \
+        right. By default the client stretches the video."
+    )]
+    pub letterbox: Option<Color>,
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "Scale the cursor by this factor, both the one painted into the video and the one \
+        drawn by clients on top of it. This keeps the cursor visible on clients with a high pixel \
+        density or if the video is scaled down, the hotspot keeps pointing at the same pixel."
+    )]
+    #[serde(default = "default_cursor_scale")]
+    pub cursor_scale: f64,
+    #[structopt(
+        long,
+        help = "Largest width or height of the video in pixels, larger frames are scaled down \
+        preserving their aspect ratio. Clients may lower this further if they can not decode \
+        large videos, the video never exceeds 3840x2160."
+    )]
+    pub max_video_size: Option<usize>,
+    #[structopt(
+        long,
+        help = "Scale captured frames by this factor between 0.1 and 4, for example 0.75 or 1.5 to \
+        match the resolution of a client exactly, instead of fitting them into the screen of \
+        the client. Frames are resampled bilinearly or, when shrinking to half the size or \
+        less, by averaging, and the size of the video is rounded to even numbers. \
+        --max-video-size and the limits of the client's decoder still apply."
+    )]
+    pub capture_scale: Option<f64>,
+    #[structopt(
+        long,
+        help = "Limit the number of frames captured per second, by default this is only limited \
+        by the rate at which clients request frames."
+    )]
+    pub max_fps: Option<f64>,
+    #[structopt(
+        long,
+        help = "Never capture faster than the display refreshes, even if --max-fps, the quality \
+        preset or the client allow more. Frames captured in between could only repeat the previous \
+        one and waste time encoding it. If the refresh rate is unknown, only the configured frame \
+        rate applies."
+    )]
+    #[serde(default)]
+    pub sync_to_refresh_rate: bool,
+    #[structopt(
+        long,
+        default_value = "70",
+        help = "Quality from 1 to 100 of the JPEG images sent to clients that can not decode the \
+        video stream."
+    )]
+    #[serde(default = "default_jpeg_quality")]
+    pub jpeg_quality: u8,
+    #[structopt(
+        long,
+        default_value = "10",
+        help = "Limit the number of JPEG images sent per second to clients that can not decode \
+        the video stream, each image is a full frame and requires a lot of bandwidth."
+    )]
+    #[serde(default = "default_jpeg_max_fps")]
+    pub jpeg_max_fps: f64,
+    #[structopt(
+        long,
+        help = "Adapt the bitrate of the video to the throughput of the connection, while never \
+        exceeding the given bitrate in kbit/s. Only supported by the software encoder (libx264)."
+    )]
+    pub max_bitrate: Option<u32>,
+    #[structopt(
+        long,
+        default_value = "500",
+        help = "Lower limit in kbit/s for the adaptive bitrate, see --max-bitrate."
+    )]
+    #[serde(default = "default_min_bitrate")]
+    pub min_bitrate: u32,
+    #[structopt(
+        long,
+        help = "Encode every frame at this quantizer instead of adapting the bitrate, lower is \
+        better: 0 to 51 for H.264, 0 to 63 for VP9 and AV1. The quality stays the same while the \
+        bitrate follows what is shown, busy screens can take a lot of bandwidth and --max-bitrate \
+        is ignored. Sets crf of the software encoders, cq of NVENC and qp of VAAPI."
+    )]
+    pub constant_quality: Option<u32>,
+    #[structopt(
+        long,
+        help = "Smallest quantizer frames are encoded with, which caps their quality and keeps \
+        static screens from taking more bits than necessary."
+    )]
+    pub qp_min: Option<u32>,
+    #[structopt(
+        long,
+        help = "Largest quantizer frames are encoded with, which keeps the quality from dropping \
+        below a level at the cost of exceeding the bitrate on busy screens."
+    )]
+    pub qp_max: Option<u32>,
+    #[structopt(
+        long,
+        help = "Hard limit in kbit/s for the video sent to each client, measured over the last \
+        second, for example on metered connections. Frames that would exceed it are not sent and \
+        quality is lowered while that happens, for video and JPEG images alike. Staying under the \
+        limit takes precedence over a smooth video."
+    )]
+    pub bandwidth_cap: Option<u32>,
+    #[structopt(
+        long,
+        default_value = "12",
+        help = "Maximum number of frames between two keyframes, must be at least 1. Short \
+        intervals let clients recover faster from lost or corrupted frames, long intervals save \
+        bandwidth."
+    )]
+    #[serde(default = "default_keyframe_interval")]
+    pub keyframe_interval: NonZeroU32,
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "Number of frames libx264 encodes in parallel. More threads help to keep up with \
+        high frame rates at 4K but delay the video by a frame per additional thread, if unchanged \
+        frames are skipped the last changes may only show up with the next frame."
+    )]
+    #[serde(default = "default_encoder_threads")]
+    pub encoder_threads: NonZeroU32,
+    #[structopt(
+        long,
+        help = "Configure the encoder for the lowest latency at the cost of a higher bitrate, for \
+        example for drawing. Frames are encoded one at a time ignoring --encoder-threads and \
+        without lookahead, and libx264 refreshes the image gradually instead of sending periodic \
+        keyframes that take long to transmit. For the lowest latency possible combine this with \
+        --keyframe-interval 1, which encodes every frame on its own."
+    )]
+    #[serde(default)]
+    pub low_latency: bool,
+    #[structopt(
+        long,
+        possible_values = &["lowcolor", "low", "medium", "high"],
+        help = "Preset for resolution, frame rate, codec and bitrate that overrides the individual \
+        options, clients can switch presets while connected. lowcolor: 50% of the resolution \
+        requested by the client, 10 fps, VP9, 500 kbit/s and 16 bit colors as with --low-color; \
+        low: 50%, 15 fps, VP9, 1000 kbit/s; medium: 75%, 30 fps, H.264, 3000 kbit/s; high: \
+        100%, 60 fps, H.264, 8000 kbit/s."
+    )]
+    pub quality_preset: Option<QualityPreset>,
+    #[structopt(
+        long,
+        help = "Maximum number of clients that may be connected at the same time, clients only \
+        count once they authenticated."
+    )]
+    pub max_clients: Option<usize>,
+    #[structopt(
+        long,
+        help = "Disconnect clients after they have been connected for this many seconds, for \
+        example to free a shared device for the next user. Clients are warned a minute before, \
+        sessions can be extended or ended early via --control."
+    )]
+    pub max_session_duration: Option<u64>,
+    #[structopt(
+        long,
+        help = "Only allow a single client to control input, the first client that sends input \
+        keeps control until it disconnects. All other clients can only view the screen."
+    )]
+    #[serde(default)]
+    pub exclusive_input: bool,
+    #[structopt(long, help = "Invert the scroll direction of wheel events.")]
+    #[serde(default)]
+    pub invert_scroll: bool,
+    #[structopt(
+        long,
+        default_value = "8",
+        help = "Milliseconds within which pointer movements are merged into a single one if \
+        clients send them faster than they can be processed, presses and releases are never \
+        merged. 0 processes every single movement."
+    )]
+    #[serde(default = "default_input_coalescing_window")]
+    pub input_coalescing_window: u64,
+    #[structopt(
+        long,
+        default_value = "block",
+        possible_values = &["block", "drop"],
+        help = "What to do if a client can not keep up with the video: block sends every frame \
+        and keeps the video smooth, drop skips frames while the send buffer is fuller than \
+        --send-buffer-threshold and keeps latency low. Dropping frames is only supported on Linux \
+        and macOS."
+    )]
+    #[serde(default)]
+    pub congestion_strategy: CongestionStrategy,
+    #[structopt(
+        long,
+        default_value = "512",
+        help = "Amount of unsent video data in KiB above which frames are dropped if the \
+        congestion strategy is drop."
+    )]
+    #[serde(default = "default_send_buffer_threshold")]
+    pub send_buffer_threshold: usize,
+    #[structopt(
+        long,
+        default_value = "30",
+        help = "Seconds the session of a client that lost its connection is kept alive. A client \
+        reconnecting in time keeps its input device and what is captured, 0 disables resuming \
+        sessions."
+    )]
+    #[serde(default = "default_session_grace_period")]
+    pub session_grace_period: u64,
+    #[structopt(
+        long,
+        default_value = "60",
+        help = "Seconds the video of a client can be paused, for example because its browser is in \
+        the background, before the screen capture and the encoder are stopped to free their \
+        resources. 0 keeps them until the client disconnects."
+    )]
+    #[serde(default = "default_pause_release_timeout")]
+    pub pause_release_timeout: u64,
+    #[structopt(
+        long,
+        help = "Keep the screen capture and the encoder of a client that lost its connection \
+        running while its session waits to be resumed, see --session-grace-period. This makes \
+        reconnecting faster, by default they are stopped and set up again once the client is \
+        back, which may ask to select the screen again on Wayland."
+    )]
+    #[serde(default)]
+    pub keep_capture_warm: bool,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Restart the screen capture if the captured image did not change for this many \
+        seconds, this recovers from drivers that stop delivering new images without reporting an \
+        error. The capture is restarted on a static screen as well, so this should not be too \
+        short. 0 disables this."
+    )]
+    #[serde(default)]
+    pub capture_stall_timeout: u64,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Milliseconds to hold back frames after the screen capture started while they are \
+        all black, as some captures deliver black images before the actual content, which clients \
+        would show until the next frame. A frame is sent as soon as it shows something or \
+        differs from the first one, after this time frames are sent anyway. 0 sends every \
+        frame right away."
+    )]
+    #[serde(default)]
+    pub content_timeout: u64,
+    #[structopt(
+        long,
+        default_value = "0",
+        help = "Encode video on a thread of its own with up to this many captured frames waiting \
+        for the encoder. If the encoder falls behind, the oldest frame is dropped so the video \
+        stays current. 0 encodes each frame right after capturing it."
+    )]
+    #[serde(default)]
+    pub encode_queue: usize,
+    #[structopt(
+        long,
+        help = "Capture each screen or window once for all clients showing it and encode the \
+        frames for every client on its own, instead of capturing for every client. Clients \
+        asking for frames within a few milliseconds of each other get the same frame. Changes of \
+        the screen are still tracked with --capture-mode damage, but every client is sent frames \
+        at the rate it asks for them."
+    )]
+    #[serde(default)]
+    pub share_capture: bool,
+    #[structopt(
+        long,
+        help = "Start sending video to newly connected clients at this fraction between 0 and 1 of \
+        the configured quality and raise it as long as frames are sent without congestion. This \
+        gives instant feedback on slow connections. By default video starts at full quality."
+    )]
+    pub quality_ramp_start: Option<f64>,
+    #[structopt(
+        long,
+        default_value = "0.1",
+        help = "Fraction of the configured quality added each time the quality is raised, see \
+        --quality-ramp-start."
+    )]
+    #[serde(default = "default_quality_ramp_step")]
+    pub quality_ramp_step: f64,
+    #[structopt(
+        long,
+        default_value = "500",
+        help = "Milliseconds frames have to be sent without congestion before the quality is \
+        raised, see --quality-ramp-start."
+    )]
+    #[serde(default = "default_quality_ramp_interval")]
+    pub quality_ramp_interval: u64,
     #[structopt(long, help = "Start Weylus server immediately on program start.")]
     #[serde(default)]
     pub auto_start: bool,
     #[structopt(long, help = "Run Weylus without gui and start immediately.")]
     #[serde(default)]
     pub no_gui: bool,
+    #[structopt(
+        long,
+        env = "WEYLUS_CAPTURABLE",
+        help = "Screen or window to capture for clients that have not selected one yet, given by \
+        its id or a part of its name. Useful together with --no-gui, for example on a server \
+        accessed via SSH. Weylus exits if nothing matches."
+    )]
+    #[serde(default)]
+    pub capturable: Option<String>,
+    #[structopt(
+        long,
+        help = "Only offer screens and windows whose title or window class contains this, ignoring \
+        case. 'title:<part>' and 'class:<part>' only look at one of them, 'class:firefox' lists \
+        only Firefox windows for example. Clients can narrow the list down further."
+    )]
+    #[serde(default)]
+    pub capturable_filter: Option<CapturableFilter>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Record the video sent to clients to this file as fragmented MP4, whatever the \
+        codec. The video restarts if its size changes and every client has its own, further \
+        videos get a number appended to the file name, video.mp4, video-2.mp4 and so on. \
+        Existing files are overwritten."
+    )]
+    #[serde(default)]
+    pub record: Option<PathBuf>,
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Read commands reconfiguring connected clients line by line from this named pipe, \
+        or from stdin if this is '-', for example 'capturable Firefox', 'crop 0 0 0.5 0.5' or \
+        'quality low'. Every command is answered with a line on stdout starting with 'ok' or \
+        'error', see the Readme for all commands."
+    )]
+    #[serde(default)]
+    pub control: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Serve /health, which checks capturing, encoding and input, to other computers as \
+        well if they know the access code. By default it is only served to this computer."
+    )]
+    #[serde(default)]
+    pub remote_health: bool,
+    #[structopt(
+        long,
+        help = "Do not advertise Weylus on the local network via mDNS/DNS-SD."
+    )]
+    #[serde(default)]
+    pub no_mdns: bool,
+    #[structopt(
+        long,
+        help = "Name Weylus is advertised as via mDNS/DNS-SD, defaults to a name derived from the \
+        host name."
+    )]
+    #[serde(default)]
+    pub mdns_name: Option<String>,
     #[cfg(target_os = "linux")]
-    #[structopt(long, help = "Wayland/PipeWire Support.")]
+    #[structopt(
+        long,
+        help = "Share the clipboard (text only) of the X server with clients: text copied on a \
+        client can be pasted on the host and vice versa."
+    )]
+    #[serde(default)]
+    pub clipboard_sync: bool,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        default_value = "1024",
+        help = "Maximum size in KiB of text that is synced between clipboards, larger text is \
+        ignored."
+    )]
+    #[serde(default = "default_clipboard_max_size")]
+    pub clipboard_max_size: usize,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        help = "Wayland/PipeWire Support. This is enabled automatically inside Wayland sessions, \
+        see --no-wayland-support."
+    )]
     #[serde(default)]
     pub wayland_support: bool,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        help = "Do not enable Wayland/PipeWire support automatically inside Wayland sessions, \
+        --wayland-support still enables it."
+    )]
+    #[serde(default)]
+    pub no_wayland_support: bool,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        default_value = "auto",
+        possible_values = &["auto", "x11", "wayland"],
+        help = "Capture only via X11 or only via Wayland/PipeWire instead of choosing \
+        automatically, for example to find out which one works better on XWayland. Weylus fails \
+        to start if the chosen backend is not available. auto captures via X11 and, with \
+        --wayland-support, via PipeWire."
+    )]
+    #[serde(default)]
+    pub capture_backend: CaptureBackend,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        help = "Offer a rectangle of the whole X11 desktop as additional capturable, given as \
+        WIDTHxHEIGHT+X+Y, for example 1920x1080+960+0 to capture parts of two monitors. Weylus \
+        fails to start if it does not lie within the desktop."
+    )]
+    pub capture_region: Option<CaptureRegion>,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        help = "Only offer the X11 screen with this number, for example 1 for :0.1, instead of \
+        those of all screens. These are separate screens of the X display, not monitors, which \
+        are offered for each screen anyway. The capture region is part of this screen as well. \
+        Weylus fails to start if the display has no such screen."
+    )]
+    pub x11_screen: Option<u32>,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        default_value = "auto",
+        possible_values = &["auto", "contents", "screen"],
+        help = "How windows are captured via X11. contents captures what the window itself \
+        draws via the Composite extension, which stays live while other windows cover it but \
+        leaves out menus. screen captures the window's bounds on screen, so only its visible \
+        part. auto captures the focused window like screen and others like contents. Without \
+        Composite contents falls back to screen."
+    )]
+    #[serde(default)]
+    pub x11_window_capture: WindowCapture,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        default_value = "alpha",
+        possible_values = &["alpha", "key"],
+        help = "How the cursor is painted into frames captured via X11. alpha blends it according \
+        to its transparency, so antialiased edges and shadows look as on screen. key draws only \
+        the pixels that are at least half opaque, which keeps edges sharp but jagged."
+    )]
+    #[serde(default)]
+    pub cursor_blend: CursorBlend,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        help = "Wait for the vertical blank of the display before capturing via X11, so frames \
+        never show parts of two images, for example while scrolling. This delays each capture by up \
+        to one refresh interval and requires access to a DRM device like /dev/dri/card0, usually \
+        granted to the video group. If unavailable frames are captured as usual."
+    )]
+    #[serde(default)]
+    pub vsync_capture: bool,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        help = "Real-time priority between 1 and 99 for the threads capturing and encoding \
+        frames, so they are woken up in time on busy hosts. This requires CAP_SYS_NICE or a \
+        sufficient real-time priority limit, otherwise Weylus warns and continues with the \
+        normal priority. Unchanged by default."
+    )]
+    pub capture_priority: Option<u8>,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        help = "CPUs the threads capturing and encoding frames may run on, for example 0,2-3. \
+        Unchanged by default."
+    )]
+    pub capture_cpus: Option<CpuList>,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        help = "Capture the sound played on the host via PulseAudio, which PipeWire provides as \
+        well, and send it as Opus to clients that enable audio. Requires the GStreamer plugins \
+        pulsesrc and opusenc."
+    )]
+    #[serde(default)]
+    pub audio: bool,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        help = "PulseAudio source to capture with --audio, for example a microphone, `pactl list \
+        short sources` lists them. Defaults to the monitor of the default output, so whatever the \
+        host plays."
+    )]
+    pub audio_device: Option<String>,
+    #[cfg(target_os = "linux")]
+    #[structopt(
+        long,
+        default_value = "1",
+        help = "Amplify the sound captured with --audio by this factor, between 0 and 10. Clients \
+        set their own volume on top of it."
+    )]
+    #[serde(default = "default_audio_volume")]
+    pub audio_volume: f64,
 
     #[structopt(long, help = "Print template of index.html served by Weylus.")]
     #[serde(skip)]
@@ -85,61 +777,642 @@ pub struct Config {
     pub completions: Option<structopt::clap::Shell>,
 }
 
-pub fn read_config() -> Option<Config> {
-    if let Some(mut config_path) = dirs::config_dir() {
-        config_path.push("weylus");
-        config_path.push("weylus.toml");
-        match fs::read_to_string(&config_path) {
-            Ok(s) => match toml::from_str(&s) {
-                Ok(c) => Some(c),
-                Err(e) => {
-                    warn!("Failed to read configuration file: {}", e);
-                    None
-                }
-            },
-            Err(err) => {
-                warn!("Failed to read configuration file: {}", err);
-                None
+/// Address to listen at, an IP address followed by the interface for link-local IPv6 addresses,
+/// `fe80::1%tun0` for example. The interface is given by its name or index, brackets around IPv6
+/// addresses are optional.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct BindAddress {
+    pub ip: IpAddr,
+    // interface as given
+    scope: Option<String>,
+    scope_id: u32,
+}
+
+impl BindAddress {
+    /// Address to listen at on the given port.
+    pub fn socket_addr(&self, port: u16) -> SocketAddr {
+        match self.ip {
+            IpAddr::V4(ip) => SocketAddr::V4(SocketAddrV4::new(ip, port)),
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, port, 0, self.scope_id)),
+        }
+    }
+}
+
+impl From<IpAddr> for BindAddress {
+    fn from(ip: IpAddr) -> Self {
+        Self {
+            ip,
+            scope: None,
+            scope_id: 0,
+        }
+    }
+}
+
+// index of the network interface given by its name or index
+fn interface_index(interface: &str) -> Option<u32> {
+    if let Ok(index) = interface.parse() {
+        return Some(index);
+    }
+    #[cfg(not(target_os = "windows"))]
+    return pnet_datalink::interfaces()
+        .into_iter()
+        .find(|iface| iface.name == interface)
+        .map(|iface| iface.index);
+    #[cfg(target_os = "windows")]
+    None
+}
+
+impl FromStr for BindAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let unbracketed = trimmed
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .unwrap_or(trimmed);
+        let (ip, scope) = match unbracketed.split_once('%') {
+            Some((ip, scope)) => (ip, Some(scope)),
+            None => (unbracketed, None),
+        };
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|_| format!("Invalid bind address: '{}', expected an IP address.", s))?;
+        let scope_id = match (ip, scope) {
+            (_, None) => 0,
+            (IpAddr::V4(_), Some(_)) => {
+                return Err(format!(
+                    "Invalid bind address: '{}', only IPv6 addresses have an interface.",
+                    s
+                ))
+            }
+            (IpAddr::V6(_), Some(scope)) => interface_index(scope).ok_or_else(|| {
+                format!("Invalid bind address: '{}', no interface '{}'.", s, scope)
+            })?,
+        };
+        if let (IpAddr::V6(ip), None) = (ip, scope) {
+            // fe80::/10
+            if ip.segments()[0] & 0xffc0 == 0xfe80 {
+                return Err(format!(
+                    "Invalid bind address: '{}', link-local addresses need the interface, like \
+                    {}%eth0.",
+                    s, ip
+                ));
             }
         }
-    } else {
-        None
+        Ok(Self {
+            ip,
+            scope: scope.map(|scope| scope.to_string()),
+            scope_id,
+        })
     }
 }
 
-pub fn write_config(conf: &Config) {
-    match dirs::config_dir() {
-        Some(mut config_path) => {
-            config_path.push("weylus");
-            if !config_path.exists() {
-                if let Err(err) = fs::create_dir_all(&config_path) {
-                    warn!("Failed create directory for configuration: {}", err);
-                    return;
-                }
-            }
-            config_path.push("weylus.toml");
-            if let Err(err) = fs::write(
-                config_path,
-                &toml::to_string_pretty(&conf).expect("Failed to encode config to toml."),
-            ) {
-                warn!("Failed to write configuration file: {}", err);
+impl TryFrom<String> for BindAddress {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<BindAddress> for String {
+    fn from(a: BindAddress) -> Self {
+        match a.scope {
+            Some(scope) => format!("{}%{}", a.ip, scope),
+            None => a.ip.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for BindAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from(self.clone()))
+    }
+}
+
+// configuration files written by earlier versions hold a single address instead of a list
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn default_send_buffer_threshold() -> usize {
+    512
+}
+
+fn default_session_grace_period() -> u64 {
+    30
+}
+
+fn default_pause_release_timeout() -> u64 {
+    60
+}
+
+fn default_input_coalescing_window() -> u64 {
+    8
+}
+
+#[cfg(target_os = "linux")]
+fn default_clipboard_max_size() -> usize {
+    1024
+}
+
+fn default_min_bitrate() -> u32 {
+    500
+}
+
+fn default_jpeg_quality() -> u8 {
+    70
+}
+
+fn default_jpeg_max_fps() -> f64 {
+    10.0
+}
+
+fn default_heartbeat_fps() -> f64 {
+    1.0
+}
+
+fn default_cursor_scale() -> f64 {
+    1.0
+}
+
+#[cfg(target_os = "linux")]
+fn default_audio_volume() -> f64 {
+    1.0
+}
+
+fn default_scene_change_cooldown() -> u64 {
+    2000
+}
+
+fn default_keyframe_request_cooldown() -> u64 {
+    1000
+}
+
+fn default_quality_ramp_step() -> f64 {
+    0.1
+}
+
+fn default_quality_ramp_interval() -> u64 {
+    500
+}
+
+fn default_encoder_threads() -> NonZeroU32 {
+    NonZeroU32::new(1).unwrap()
+}
+
+fn default_keyframe_interval() -> NonZeroU32 {
+    NonZeroU32::new(12).unwrap()
+}
+
+impl Config {
+    /// Check the configuration for invalid values and options that contradict each other, returns
+    /// a description of every problem found. Options that are merely ignored because of others are
+    /// only warned about.
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        if self.bind_address.is_empty() {
+            errors.push("At least one --bind-address is required.".to_string());
+        }
+        for (i, address) in self.bind_address.iter().enumerate() {
+            if self.bind_address[..i].contains(address) {
+                errors.push(format!("--bind-address {} is given twice.", address));
+            }
+        }
+        for (i, address) in self.websocket_bind_address.iter().enumerate() {
+            if self.websocket_bind_address[..i].contains(address) {
+                errors.push(format!(
+                    "--websocket-bind-address {} is given twice.",
+                    address
+                ));
+            }
+        }
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), None) => errors.push("--tls-cert requires --tls-key.".to_string()),
+            (None, Some(_)) => errors.push("--tls-key requires --tls-cert.".to_string()),
+            _ => (),
+        }
+        if self.web_port == self.websocket_port {
+            errors.push(format!(
+                "The web port and the websocket port are both {}, they have to differ.",
+                self.web_port
+            ));
+        }
+        if let Some(bits) = self.quantize_bits {
+            if !(1..=7).contains(&bits) {
+                errors.push(format!(
+                    "--quantize-bits has to be between 1 and 7, got {}.",
+                    bits
+                ));
             }
         }
+        if !(1..=100).contains(&self.jpeg_quality) {
+            errors.push(format!(
+                "--jpeg-quality has to be between 1 and 100, got {}.",
+                self.jpeg_quality
+            ));
+        }
+        if self.jpeg_max_fps.is_nan() || self.jpeg_max_fps <= 0.0 {
+            errors.push(format!(
+                "--jpeg-max-fps has to be greater than 0, got {}.",
+                self.jpeg_max_fps
+            ));
+        }
+        if self.heartbeat_fps.is_nan() || self.heartbeat_fps <= 0.0 {
+            errors.push(format!(
+                "--heartbeat-fps has to be greater than 0, got {}.",
+                self.heartbeat_fps
+            ));
+        }
+        if !(self.cursor_scale > 0.0 && self.cursor_scale <= 8.0) {
+            errors.push(format!(
+                "--cursor-scale has to be greater than 0 and at most 8, got {}.",
+                self.cursor_scale
+            ));
+        }
+        #[cfg(target_os = "linux")]
+        if !(0.0..=10.0).contains(&self.audio_volume) {
+            errors.push(format!(
+                "--audio-volume has to be between 0 and 10, got {}.",
+                self.audio_volume
+            ));
+        }
+        if let Some(scale) = self.capture_scale {
+            if !(0.1..=4.0).contains(&scale) {
+                errors.push(format!(
+                    "--capture-scale has to be between 0.1 and 4, got {}.",
+                    scale
+                ));
+            }
+        }
+        if let Some(threshold) = self.scene_change_threshold {
+            if !(threshold > 0.0 && threshold <= 1.0) {
+                errors.push(format!(
+                    "--scene-change-threshold has to be greater than 0 and at most 1, got {}.",
+                    threshold
+                ));
+            }
+        }
+        if let Some(max_fps) = self.max_fps {
+            if max_fps.is_nan() || max_fps <= 0.0 {
+                errors.push(format!(
+                    "--max-fps has to be greater than 0, got {}.",
+                    max_fps
+                ));
+            }
+        }
+        if let Some(max_bitrate) = self.max_bitrate {
+            if self.min_bitrate > max_bitrate {
+                errors.push(format!(
+                    "--min-bitrate {} is larger than --max-bitrate {}.",
+                    self.min_bitrate, max_bitrate
+                ));
+            }
+        }
+        if self.bandwidth_cap == Some(0) {
+            errors.push("--bandwidth-cap has to be greater than 0.".to_string());
+        }
+        let max_quantizer = self.codec.max_quantizer();
+        for (name, quantizer) in [
+            ("--constant-quality", self.constant_quality),
+            ("--qp-min", self.qp_min),
+            ("--qp-max", self.qp_max),
+        ] {
+            if let Some(quantizer) = quantizer.filter(|q| *q > max_quantizer) {
+                errors.push(format!(
+                    "{} has to be between 0 and {} for {}, got {}.",
+                    name,
+                    max_quantizer,
+                    self.codec.name(),
+                    quantizer
+                ));
+            }
+        }
+        if let (Some(qp_min), Some(qp_max)) = (self.qp_min, self.qp_max) {
+            if qp_min > qp_max {
+                errors.push(format!(
+                    "--qp-min {} is larger than --qp-max {}.",
+                    qp_min, qp_max
+                ));
+            }
+        }
+        if let Some(start) = self.quality_ramp_start {
+            if !(start > 0.0 && start <= 1.0) {
+                errors.push(format!(
+                    "--quality-ramp-start has to be greater than 0 and at most 1, got {}.",
+                    start
+                ));
+            }
+        }
+        if !(self.quality_ramp_step > 0.0 && self.quality_ramp_step <= 1.0) {
+            errors.push(format!(
+                "--quality-ramp-step has to be greater than 0 and at most 1, got {}.",
+                self.quality_ramp_step
+            ));
+        }
+        if self.max_video_size == Some(0) {
+            errors.push("--max-video-size has to be greater than 0.".to_string());
+        }
+        if self.max_clients == Some(0) {
+            errors.push("--max-clients has to be greater than 0.".to_string());
+        }
+        if self.max_session_duration == Some(0) {
+            errors.push("--max-session-duration has to be greater than 0.".to_string());
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(priority) = self.capture_priority {
+            if !(1..=99).contains(&priority) {
+                errors.push(format!(
+                    "--capture-priority has to be between 1 and 99, got {}.",
+                    priority
+                ));
+            }
+        }
+
+        if self.quality_preset.is_some() && (self.max_fps.is_some() || self.max_bitrate.is_some()) {
+            warn!(
+                "--quality-preset overrides --max-fps and --max-bitrate, they are ignored unless \
+                clients choose the server's settings."
+            );
+        }
+        if self.yuv444 || self.grayscale {
+            #[cfg(target_os = "linux")]
+            let hw_accel = self.try_vaapi || self.try_nvenc;
+            #[cfg(target_os = "windows")]
+            let hw_accel = self.try_nvenc || self.try_mediafoundation;
+            #[cfg(target_os = "macos")]
+            let hw_accel = self.try_videotoolbox;
+            if hw_accel {
+                warn!(
+                    "--yuv444 and --grayscale always encode in software, hardware acceleration is \
+                    not used."
+                );
+            }
+        }
+        if let Some(quality) = self.constant_quality {
+            if self.max_bitrate.is_some() {
+                warn!("--constant-quality does not limit the bitrate, --max-bitrate is ignored.");
+            }
+            if self.bandwidth_cap.is_some() {
+                warn!(
+                    "--constant-quality does not limit the bitrate, --bandwidth-cap is kept by \
+                    lowering the quality and dropping frames instead."
+                );
+            }
+            if self.qp_min.map_or(false, |q| quality < q)
+                || self.qp_max.map_or(false, |q| quality > q)
+            {
+                warn!(
+                    "--constant-quality {} lies outside of --qp-min and --qp-max, frames are \
+                    encoded at the closest quantizer allowed.",
+                    quality
+                );
+            }
+        }
+        if self.low_latency && self.encoder_threads.get() > 1 {
+            warn!("--low-latency encodes one frame at a time, --encoder-threads is ignored.");
+        }
+        #[cfg(target_os = "linux")]
+        if self.vsync_capture && self.capture_backend == CaptureBackend::Wayland {
+            warn!("--vsync-capture only applies to capturing via X11, PipeWire does not tear.");
+        }
+        #[cfg(target_os = "linux")]
+        if self.capture_mode == CaptureMode::Damage
+            && self.capture_backend == CaptureBackend::Wayland
+        {
+            warn!("--capture-mode damage only applies to capturing via X11, frames are polled.");
+        }
+        #[cfg(target_os = "linux")]
+        if self.x11_window_capture != WindowCapture::Auto
+            && self.capture_backend == CaptureBackend::Wayland
+        {
+            warn!("--x11-window-capture only applies to capturing via X11.");
+        }
+        #[cfg(target_os = "linux")]
+        if !self.audio && (self.audio_device.is_some() || self.audio_volume != 1.0) {
+            warn!("--audio-device and --audio-volume only apply with --audio.");
+        }
+        #[cfg(target_os = "linux")]
+        if self.cursor_blend != CursorBlend::Alpha
+            && self.capture_backend == CaptureBackend::Wayland
+        {
+            warn!("--cursor-blend only applies to capturing via X11.");
+        }
+        if let Some(path) = &self.record {
+            let extension = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or_default();
+            if !extension.eq_ignore_ascii_case("mp4") {
+                warn!(
+                    "Recordings are always fragmented MP4, consider naming {} *.mp4.",
+                    path.display()
+                );
+            }
+        }
+        if let Some(path) = self.control.as_ref().filter(|path| path.as_os_str() != "-") {
+            if !path.exists() {
+                errors.push(format!(
+                    "--control {} does not exist, create a named pipe with mkfifo first.",
+                    path.display()
+                ));
+            }
+        }
+        if self.low_color && self.yuv444 {
+            warn!("--low-color subsamples chroma, --yuv444 is ignored.");
+        }
+        if self.unchanged_frames_refresh.is_some() && !self.skip_unchanged_frames {
+            warn!("--unchanged-frames-refresh has no effect without --skip-unchanged-frames.");
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Whether Weylus runs inside a Wayland session, where capturing via X11 only yields black images.
+#[cfg(target_os = "linux")]
+pub fn wayland_session() -> bool {
+    std::env::var("XDG_SESSION_TYPE").map_or(false, |session| session == "wayland")
+}
+
+fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|mut config_path| {
+        config_path.push("weylus");
+        config_path.push("weylus.toml");
+        config_path
+    })
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "json")
+}
+
+// read the values of a configuration file, it may contain only some of the options
+fn read_config_file(path: &Path) -> Result<toml::value::Table, String> {
+    let s = fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let value: toml::Value = if is_json(path) {
+        serde_json::from_str(&s).map_err(|err| err.to_string())?
+    } else {
+        toml::from_str(&s).map_err(|err| err.to_string())?
+    };
+    match value {
+        toml::Value::Table(table) => Ok(table),
+        _ => Err("Expected a table of options.".to_string()),
+    }
+}
+
+pub fn write_config(conf: &Config) {
+    let config_path = match conf.config.clone().or_else(default_config_path) {
+        Some(config_path) => config_path,
         None => {
             warn!("Failed to find configuration directory!");
+            return;
+        }
+    };
+    if let Some(dir) = config_path.parent() {
+        if !dir.as_os_str().is_empty() && !dir.exists() {
+            if let Err(err) = fs::create_dir_all(dir) {
+                warn!("Failed create directory for configuration: {}", err);
+                return;
+            }
+        }
+    }
+    let s = if is_json(&config_path) {
+        serde_json::to_string_pretty(&conf).expect("Failed to encode config to json.")
+    } else {
+        toml::to_string_pretty(&conf).expect("Failed to encode config to toml.")
+    };
+    if let Err(err) = fs::write(config_path, &s) {
+        warn!("Failed to write configuration file: {}", err);
+    }
+}
+
+// Options given on the command line take precedence over the file, the file over the defaults.
+// Only options that occur on the command line count as given, clap does not tell default values
+// apart from values taken from the environment though, so these are checked separately.
+fn merge_config(
+    cli: Config,
+    matches: &ArgMatches,
+    mut file: toml::value::Table,
+) -> Result<Config, String> {
+    let cli_values = match toml::Value::try_from(&cli).map_err(|err| err.to_string())? {
+        toml::Value::Table(table) => table,
+        _ => unreachable!("Config is always encoded as table."),
+    };
+    for (key, value) in cli_values {
+        // structopt names arguments after their long option
+        let arg = key.replace('_', "-");
+        let given = matches.occurrences_of(&arg) > 0
+            || (key == "capturable" && std::env::var_os("WEYLUS_CAPTURABLE").is_some());
+        if given || !file.contains_key(&key) {
+            file.insert(key, value);
         }
     }
+    let merged: Config = toml::Value::Table(file)
+        .try_into()
+        .map_err(|err| err.to_string())?;
+    // these are never read from a file
+    Ok(Config {
+        config: cli.config,
+        print_index_html: cli.print_index_html,
+        print_access_html: cli.print_access_html,
+        print_style_css: cli.print_style_css,
+        print_lib_js: cli.print_lib_js,
+        custom_index_html: cli.custom_index_html,
+        custom_access_html: cli.custom_access_html,
+        custom_style_css: cli.custom_style_css,
+        custom_lib_js: cli.custom_lib_js,
+        completions: cli.completions,
+        ..merged
+    })
 }
 
+/// Parse the command line and merge it with the configuration file given by `--config`, or
+/// weylus.toml in the configuration directory if it exists. Exits if the file given can not be
+/// read or the merged configuration is invalid.
 pub fn get_config() -> Config {
-    // TODO: once https://github.com/clap-rs/clap/issues/748 is resolved use
-    // the configfile to provide default values that override hardcoded defaults
+    let matches = Config::clap().get_matches();
+    let cli = Config::from_clap(&matches);
 
-    // read config from file if no args are specified
-    if std::env::args().len() == 1 {
-        // (ab)use parsing an empty args array to provide a default config
-        read_config().unwrap_or_else(crate::config::Config::from_args)
+    let file = match &cli.config {
+        Some(path) => match read_config_file(path) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                error!(
+                    "Failed to read configuration file {}: {}",
+                    path.display(),
+                    err
+                );
+                std::process::exit(1);
+            }
+        },
+        None => default_config_path().and_then(|path| match read_config_file(&path) {
+            Ok(file) => Some(file),
+            Err(_) if !path.exists() => None,
+            Err(err) => {
+                warn!("Failed to read configuration file: {}", err);
+                None
+            }
+        }),
+    };
+    let conf = match file {
+        Some(file) => match merge_config(cli.clone(), &matches, file) {
+            Ok(conf) => conf,
+            Err(err) if cli.config.is_some() => {
+                error!("Invalid configuration file: {}", err);
+                std::process::exit(1);
+            }
+            Err(err) => {
+                warn!("Ignoring invalid configuration file: {}", err);
+                cli
+            }
+        },
+        None => cli,
+    };
+
+    // only if capturing is left to Weylus, choosing a backend decides whether PipeWire is used
+    #[cfg(target_os = "linux")]
+    let conf = if !conf.wayland_support
+        && !conf.no_wayland_support
+        && conf.capture_backend == CaptureBackend::Auto
+        && wayland_session()
+    {
+        info!(
+            "Running inside a Wayland session, enabling Wayland/PipeWire support. Pass \
+            --no-wayland-support to capture via X11 only."
+        );
+        Config {
+            wayland_support: true,
+            ..conf
+        }
     } else {
-        crate::config::Config::from_args()
+        conf
+    };
+
+    if let Err(errors) = conf.validate() {
+        for err in errors {
+            error!("{}", err);
+        }
+        std::process::exit(1);
     }
+    conf
 }
@@ -0,0 +1,395 @@
+//! Reconfiguring connected clients from scripts, see `--control`. Commands are read line by line
+//! from stdin or a named pipe, each of them is answered by a single line on stdout.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::Duration;
+
+use tracing::{debug, warn};
+
+use crate::protocol::{AxisMapping, InputMapping, QualitySettings};
+use crate::session_limit::{SessionCommand, SessionHandle};
+use crate::video::QualityPreset;
+
+/// Command sent to the clients, it is applied like the corresponding message of a client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlCommand {
+    // screen or window to capture given by its id or a part of its name
+    Capturable(String),
+    // region of the capturable relative to its size, none shows all of it
+    Crop(Option<(f64, f64, f64, f64)>),
+    // none returns to the options given on the command line
+    QualityPreset(Option<QualityPreset>),
+    SetQuality(QualitySettings),
+    Keyframe,
+    // none maps pointer positions one to one again
+    InputMapping(Option<InputMapping>),
+    // applied right away instead of before the next message, only for clients whose session is
+    // limited by --max-session-duration
+    Session(SessionCommand),
+}
+
+/// A line read by the control channel, see the Readme for the grammar.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlRequest {
+    // list the addresses of connected clients
+    Clients,
+    // send the command to the client at the address or to all clients
+    Command(Option<SocketAddr>, ControlCommand),
+}
+
+// a day, longer extensions are most likely typos
+const MAX_SESSION_EXTENSION: u64 = 24 * 60 * 60;
+
+fn parse_number<T: FromStr>(name: &str, value: Option<&str>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} is missing", name))?;
+    value
+        .parse()
+        .map_err(|_| format!("{} '{}' is not a valid number", name, value))
+}
+
+impl FromStr for ControlRequest {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut words = line.split_whitespace().peekable();
+        let target = match words.peek().copied().and_then(|w| w.strip_prefix('@')) {
+            Some(address) => {
+                let address = address.parse::<SocketAddr>().map_err(|_| {
+                    format!("'{}' is not an address like 192.168.1.2:40000", address)
+                })?;
+                words.next();
+                Some(address)
+            }
+            None => None,
+        };
+        let command = words.next().ok_or("command is missing")?;
+        let command = match command.to_lowercase().as_str() {
+            "clients" => {
+                if target.is_some() {
+                    return Err("clients lists all clients, it can not be sent to one".into());
+                }
+                if let Some(word) = words.next() {
+                    return Err(format!("unexpected '{}'", word));
+                }
+                return Ok(Self::Clients);
+            }
+            "capturable" => {
+                let selector = words.collect::<Vec<&str>>().join(" ");
+                if selector.is_empty() {
+                    return Err("capturable needs the id or a part of the name".into());
+                }
+                return Ok(Self::Command(target, ControlCommand::Capturable(selector)));
+            }
+            "crop" => match words.peek().map(|w| w.to_lowercase()) {
+                Some(w) if w == "off" => {
+                    words.next();
+                    ControlCommand::Crop(None)
+                }
+                _ => {
+                    let x: f64 = parse_number("x", words.next())?;
+                    let y: f64 = parse_number("y", words.next())?;
+                    let width: f64 = parse_number("width", words.next())?;
+                    let height: f64 = parse_number("height", words.next())?;
+                    let valid = [x, y, width, height].iter().all(|v| v.is_finite())
+                        && x >= 0.0
+                        && y >= 0.0
+                        && width > 0.0
+                        && height > 0.0
+                        && x + width <= 1.0
+                        && y + height <= 1.0;
+                    if !valid {
+                        return Err(
+                            "the crop has to be given relative to the capturable, between 0 and 1"
+                                .into(),
+                        );
+                    }
+                    ControlCommand::Crop(Some((x, y, width, height)))
+                }
+            },
+            "quality" => match words.next() {
+                Some("default") => ControlCommand::QualityPreset(None),
+                Some(preset) => ControlCommand::QualityPreset(Some(preset.parse()?)),
+                None => return Err("quality needs a preset or 'default'".into()),
+            },
+            "bitrate" => ControlCommand::SetQuality(QualitySettings {
+                bitrate: Some(parse_number("bitrate", words.next())?),
+                ..Default::default()
+            }),
+            "fps" => ControlCommand::SetQuality(QualitySettings {
+                fps: Some(parse_number("fps", words.next())?),
+                ..Default::default()
+            }),
+            "scale" => ControlCommand::SetQuality(QualitySettings {
+                scale: Some(parse_number("scale", words.next())?),
+                ..Default::default()
+            }),
+            "keyframe" => ControlCommand::Keyframe,
+            "session" => match words.next().map(|w| w.to_lowercase()).as_deref() {
+                Some("extend") => {
+                    let seconds = parse_number("seconds", words.next())?;
+                    if seconds > MAX_SESSION_EXTENSION {
+                        return Err(format!(
+                            "sessions can be extended by at most {} seconds at once",
+                            MAX_SESSION_EXTENSION
+                        ));
+                    }
+                    ControlCommand::Session(SessionCommand::Extend(Duration::from_secs(seconds)))
+                }
+                Some("end") => ControlCommand::Session(SessionCommand::End),
+                _ => return Err("session needs 'extend <seconds>' or 'end'".into()),
+            },
+            "mapping" => match words.peek().map(|w| w.to_lowercase()) {
+                Some(w) if w == "off" => {
+                    words.next();
+                    ControlCommand::InputMapping(None)
+                }
+                _ => {
+                    let x = AxisMapping {
+                        scale: parse_number("x scale", words.next())?,
+                        offset: parse_number("x offset", words.next())?,
+                    };
+                    let y = AxisMapping {
+                        scale: parse_number("y scale", words.next())?,
+                        offset: parse_number("y offset", words.next())?,
+                    };
+                    let clamp = match words.peek().map(|w| w.to_lowercase()) {
+                        Some(w) if w == "noclamp" => {
+                            words.next();
+                            false
+                        }
+                        _ => true,
+                    };
+                    let mapping = InputMapping { x, y, clamp };
+                    if !mapping.is_valid() {
+                        return Err("the scales must not be 0 and all values must be finite".into());
+                    }
+                    ControlCommand::InputMapping(Some(mapping))
+                }
+            },
+            _ => return Err(format!("unknown command '{}'", command)),
+        };
+        match words.next() {
+            Some(word) => Err(format!("unexpected '{}'", word)),
+            None => Ok(Self::Command(target, command)),
+        }
+    }
+}
+
+struct ClientControl {
+    commands: mpsc::Sender<ControlCommand>,
+    // none if the session of the client is not limited
+    session: Option<SessionHandle>,
+}
+
+impl ClientControl {
+    fn send(&self, command: ControlCommand) -> bool {
+        match (command, &self.session) {
+            (ControlCommand::Session(command), Some(session)) => session.send(command),
+            (ControlCommand::Session(_), None) => false,
+            (command, _) => self.commands.send(command).is_ok(),
+        }
+    }
+}
+
+/// Connected clients commands can be sent to, shared between the websocket server and the control
+/// channel.
+#[derive(Clone, Default)]
+pub struct ClientControls {
+    clients: Arc<Mutex<HashMap<SocketAddr, ClientControl>>>,
+    // selected by the control channel for clients that connect later
+    capturable: Arc<Mutex<Option<String>>>,
+    // the server has been stopped, the control channel stops reading
+    closed: Arc<AtomicBool>,
+}
+
+impl ClientControls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a client, commands sent to it can be taken from the receiver except for those changing
+    /// its session, which go to the session if it is limited.
+    pub fn insert(
+        &self,
+        client: SocketAddr,
+        session: Option<SessionHandle>,
+    ) -> mpsc::Receiver<ControlCommand> {
+        let (commands, receiver) = mpsc::channel();
+        self.clients
+            .lock()
+            .unwrap()
+            .insert(client, ClientControl { commands, session });
+        receiver
+    }
+
+    /// The client resumed its session from another address.
+    pub fn moved(&self, from: &SocketAddr, to: SocketAddr) {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(control) = clients.remove(from) {
+            clients.insert(to, control);
+        }
+    }
+
+    pub fn remove(&self, client: &SocketAddr) {
+        self.clients.lock().unwrap().remove(client);
+    }
+
+    /// Screen or window selected via the control channel, it takes precedence over `--capturable`.
+    pub fn capturable(&self) -> Option<String> {
+        self.capturable.lock().unwrap().clone()
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    fn addresses(&self) -> Vec<SocketAddr> {
+        let mut addresses: Vec<SocketAddr> = self.clients.lock().unwrap().keys().copied().collect();
+        addresses.sort();
+        addresses
+    }
+
+    // Send the command to the client or to all of them, returns the number of clients reached.
+    fn send(&self, target: Option<SocketAddr>, command: ControlCommand) -> Result<usize, String> {
+        let clients = self.clients.lock().unwrap();
+        match target {
+            Some(address) => match clients.get(&address) {
+                Some(ClientControl { session: None, .. })
+                    if matches!(command, ControlCommand::Session(_)) =>
+                {
+                    Err(format!("the session of {} is not limited", address))
+                }
+                Some(control) => {
+                    if control.send(command) {
+                        Ok(1)
+                    } else {
+                        Err(format!("client {} disconnected", address))
+                    }
+                }
+                None => Err(format!("no client connected from {}", address)),
+            },
+            None => {
+                if let ControlCommand::Capturable(selector) = &command {
+                    *self.capturable.lock().unwrap() = Some(selector.clone());
+                }
+                Ok(clients
+                    .values()
+                    .filter(|control| control.send(command.clone()))
+                    .count())
+            }
+        }
+    }
+
+    fn answer(&self, line: &str) -> String {
+        match line.parse::<ControlRequest>() {
+            Ok(ControlRequest::Clients) => {
+                let addresses: Vec<String> =
+                    self.addresses().iter().map(|a| a.to_string()).collect();
+                format!("ok {}", addresses.join(" ")).trim_end().to_string()
+            }
+            Ok(ControlRequest::Command(target, command)) => match self.send(target, command) {
+                Ok(n) => format!("ok {}", n),
+                Err(err) => format!("error {}", err),
+            },
+            Err(err) => format!("error {}", err),
+        }
+    }
+}
+
+/// Where commands are read from, `-` stands for stdin.
+#[derive(Debug, Clone)]
+pub enum ControlSource {
+    Stdin,
+    Path(PathBuf),
+}
+
+impl ControlSource {
+    pub fn new(path: &Path) -> Self {
+        if path == Path::new("-") {
+            Self::Stdin
+        } else {
+            Self::Path(path.to_path_buf())
+        }
+    }
+}
+
+// Answer all lines of the reader, returns false once the server has been stopped.
+fn answer_lines(reader: impl BufRead, controls: &ClientControls) -> bool {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                warn!("Failed to read control command: {}", err);
+                break;
+            }
+        };
+        if controls.closed.load(Ordering::Relaxed) {
+            return false;
+        }
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let answer = controls.answer(line);
+        debug!(command = %line, %answer, "Got control command.");
+        let mut stdout = std::io::stdout().lock();
+        if writeln!(stdout, "{}", answer)
+            .and_then(|_| stdout.flush())
+            .is_err()
+        {
+            warn!("Failed to answer control command '{}'.", line);
+        }
+    }
+    true
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    std::fs::metadata(path).map_or(false, |m| m.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_: &Path) -> bool {
+    false
+}
+
+/// Read commands in the background until the source is exhausted. A named pipe is opened again
+/// whenever the script writing to it closed it, so it can be written to any number of times.
+/// Reading blocks, so the thread only notices that the server has been stopped with the next
+/// command and is not meant to be joined.
+pub fn run(source: ControlSource, controls: ClientControls) -> JoinHandle<()> {
+    spawn(move || match source {
+        ControlSource::Stdin => {
+            answer_lines(std::io::stdin().lock(), &controls);
+        }
+        ControlSource::Path(path) => {
+            let fifo = is_fifo(&path);
+            loop {
+                // opening a named pipe blocks until something opens it for writing
+                let file = match File::open(&path) {
+                    Ok(file) => file,
+                    Err(err) => {
+                        warn!(
+                            "Failed to open {}, stopping to read control commands: {}",
+                            path.display(),
+                            err
+                        );
+                        return;
+                    }
+                };
+                if !answer_lines(BufReader::new(file), &controls) || !fifo {
+                    return;
+                }
+            }
+        }
+    })
+}
@@ -18,7 +18,11 @@ use fltk::{
 #[cfg(not(target_os = "windows"))]
 use pnet_datalink as datalink;
 
-use crate::config::{write_config, Config};
+#[cfg(target_os = "linux")]
+use crate::config::wayland_session;
+use crate::config::{write_config, BindAddress, Config};
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+use crate::video::nvenc_available;
 use crate::websocket::Ws2UiMessage;
 
 pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
@@ -51,7 +55,15 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
         .with_size(width, height)
         .below_of(&input_access_code, padding)
         .with_label("Bind Address");
-    input_bind_addr.set_value(&config.bind_address.to_string());
+    input_bind_addr.set_value(
+        &config
+            .bind_address
+            .iter()
+            .map(|address| address.to_string())
+            .collect::<Vec<String>>()
+            .join(", "),
+    );
+    input_bind_addr.set_tooltip("Addresses to listen at, separated by commas.");
 
     let mut input_port = IntInput::default()
         .with_size(width, height)
@@ -130,8 +142,11 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
         .with_label("NVENC");
     check_nvenc.set_tooltip("Try to use Nvidia's NVENC to encode the video via GPU.");
 
+    // NVENC is used by default where it works, see --no-nvenc
+    #[cfg(any(target_os = "linux", target_os = "windows"))]
+    let nvenc_available = nvenc_available();
     #[cfg(any(target_os = "linux", target_os = "windows"))]
-    check_nvenc.set_checked(config.try_nvenc);
+    check_nvenc.set_checked(config.try_nvenc || (nvenc_available && !config.no_nvenc));
 
     #[cfg(not(any(target_os = "linux", target_os = "windows")))]
     {
@@ -191,7 +206,12 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
                         "" => None,
                         code => Some(code),
                     };
-                    let bind_addr: IpAddr = input_bind_addr.value().parse()?;
+                    let bind_addr = input_bind_addr
+                        .value()
+                        .split(',')
+                        .filter(|address| !address.trim().is_empty())
+                        .map(|address| address.parse())
+                        .collect::<Result<Vec<BindAddress>, String>>()?;
                     let web_port: u16 = input_port.value().parse()?;
                     let ws_port: u16 = input_ws_port.value().parse()?;
 
@@ -204,10 +224,14 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
                     {
                         config.try_vaapi = check_native_hw_accel.is_checked();
                         config.wayland_support = check_wayland.is_checked();
+                        // otherwise it would be enabled again on the next start
+                        config.no_wayland_support = !config.wayland_support && wayland_session();
                     }
                     #[cfg(any(target_os = "linux", target_os = "windows"))]
                     {
                         config.try_nvenc = check_nvenc.is_checked();
+                        // otherwise it would be used again on the next start
+                        config.no_nvenc = !config.try_nvenc && nvenc_available;
                     }
                     #[cfg(target_os = "macos")]
                     {
@@ -218,6 +242,9 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
                         config.try_mediafoundation = check_native_hw_accel.is_checked();
                     }
                 }
+                if let Err(errors) = config.validate() {
+                    return Err(errors.join(" ").into());
+                }
                 if !weylus.start(
                     &config,
                     |_| {},
@@ -251,7 +278,12 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
 
                 write_config(&config);
 
-                let mut web_sock = SocketAddr::new(config.bind_address, config.web_port);
+                let mut web_sock = config.bind_address[0].socket_addr(config.web_port);
+                let scheme = if config.tls_cert.is_some() {
+                    "https"
+                } else {
+                    "http"
+                };
 
                 #[cfg(not(target_os = "windows"))]
                 {
@@ -279,7 +311,7 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
                             info!("Found more than one IP address for browsers to connect to,");
                             info!("other urls are:");
                             for ip in &ips[1..] {
-                                info!("http://{}", SocketAddr::new(*ip, config.web_port));
+                                info!("{}://{}", scheme, SocketAddr::new(*ip, config.web_port));
                             }
                         }
                     }
@@ -289,7 +321,7 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
                 {
                     use image::Luma;
                     use qrcode::QrCode;
-                    let addr_string = format!("http://{}", web_sock);
+                    let addr_string = format!("{}://{}", scheme, web_sock);
                     output_server_addr.set_value(&addr_string);
                     let mut url_string = addr_string;
                     if let Some(access_code) = &config.access_code {
@@ -322,9 +354,13 @@ pub fn run(config: &Config, log_receiver: mpsc::Receiver<String>) {
                 #[cfg(target_os = "windows")]
                 {
                     if web_sock.ip().is_unspecified() {
-                        output_server_addr.set_value("http://<your ip address>");
+                        output_server_addr.set_value(&format!("{}://<your ip address>", scheme));
                     } else {
-                        output_server_addr.set_value(&format!("http://{}", web_sock.to_string()));
+                        output_server_addr.set_value(&format!(
+                            "{}://{}",
+                            scheme,
+                            web_sock.to_string()
+                        ));
                     }
                 }
                 output_server_addr.show();
@@ -0,0 +1,318 @@
+//! Self-check of the pipeline served at `/health`. Capturing, encoding and setting up input devices
+//! report whether they worked, which is as cheap as updating the metrics, and the report turns the
+//! most recent of these signals into a verdict with a hint on what to do about it.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::video::PixelProvider;
+
+// capturing or encoding is considered to be running if it happened at most this long ago
+const RECENT: Duration = Duration::from_secs(5);
+// frames that did not change for this long are reported, the screen may be frozen
+const STALE: Duration = Duration::from_secs(60);
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_millis() as u64)
+        // 0 means never
+        .max(1)
+}
+
+fn ago(ms: u64) -> Duration {
+    Duration::from_millis(now_ms().saturating_sub(ms))
+}
+
+/// When something last worked and when it last failed and why.
+pub struct Signal {
+    // milliseconds since the unix epoch, 0 if never
+    ok_at: AtomicU64,
+    failed_at: AtomicU64,
+    error: Mutex<Option<String>>,
+}
+
+enum SignalState {
+    Never,
+    Ok(Duration),
+    Failed(String, Duration),
+}
+
+impl Signal {
+    const fn new() -> Self {
+        Self {
+            ok_at: AtomicU64::new(0),
+            failed_at: AtomicU64::new(0),
+            error: Mutex::new(None),
+        }
+    }
+
+    pub fn ok(&self) {
+        self.ok_at.store(now_ms(), Ordering::Relaxed);
+    }
+
+    pub fn failed(&self, error: impl ToString) {
+        *self.error.lock().unwrap() = Some(error.to_string());
+        self.failed_at.store(now_ms(), Ordering::Relaxed);
+    }
+
+    fn state(&self) -> SignalState {
+        let ok_at = self.ok_at.load(Ordering::Relaxed);
+        let failed_at = self.failed_at.load(Ordering::Relaxed);
+        if failed_at > ok_at {
+            let error = self.error.lock().unwrap().clone().unwrap_or_default();
+            SignalState::Failed(error, ago(failed_at))
+        } else if ok_at > 0 {
+            SignalState::Ok(ago(ok_at))
+        } else {
+            SignalState::Never
+        }
+    }
+}
+
+/// Signals of all clients, whichever reported last wins.
+pub struct Health {
+    pub capture: Signal,
+    pub encode: Signal,
+    pub input: Signal,
+    // the last frame captured was black
+    blank: AtomicBool,
+    // last time a captured frame differed from the one captured before by the same client
+    changed_at: AtomicU64,
+}
+
+pub static HEALTH: Health = Health {
+    capture: Signal::new(),
+    encode: Signal::new(),
+    input: Signal::new(),
+    blank: AtomicBool::new(false),
+    changed_at: AtomicU64::new(0),
+};
+
+// pixels sampled in each direction to tell if a frame changed or is black, this is cheap enough for
+// every frame but misses changes in between the samples
+const SAMPLES: usize = 64;
+
+/// Sampled pixels of the frames captured by one client, to tell whether they change.
+#[derive(Default)]
+pub struct FrameSampler {
+    last: Option<u64>,
+}
+
+impl FrameSampler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a frame that has just been captured successfully.
+    pub fn sample(&mut self, pixel_provider: &PixelProvider) {
+        let (width, height) = pixel_provider.size();
+        let (bpp, stride, data) = (
+            pixel_provider.bytes_per_pixel(),
+            pixel_provider.stride(),
+            pixel_provider.data(),
+        );
+        let mut hash = Xxh3::new();
+        let mut blank = true;
+        for j in 0..SAMPLES.min(height) {
+            let row = j * height / SAMPLES.min(height) * stride;
+            for i in 0..SAMPLES.min(width) {
+                let k = row + i * width / SAMPLES.min(width) * bpp;
+                // the fourth byte of pixels with four bytes may contain anything
+                if let Some(pixel) = data.get(k..k + 3) {
+                    blank &= pixel.iter().all(|v| *v == 0);
+                    hash.update(pixel);
+                }
+            }
+        }
+        HEALTH.capture.ok();
+        HEALTH.blank.store(blank, Ordering::Relaxed);
+        let hash = hash.digest();
+        if self.last != Some(hash) {
+            self.last = Some(hash);
+            HEALTH.changed_at.store(now_ms(), Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pass,
+    // nothing is wrong as far as is known, but the check could not be made, for example because
+    // no client is connected
+    Warn,
+    Fail,
+}
+
+#[derive(Serialize)]
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    // what to do about it, only given if the check did not pass
+    hint: Option<&'static str>,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: String, hint: &'static str) -> Self {
+        Self {
+            name,
+            status,
+            detail,
+            hint: (status != Status::Pass).then_some(hint),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct HealthReport {
+    /// none of the checks failed
+    pub healthy: bool,
+    checks: Vec<Check>,
+}
+
+impl Health {
+    /// Check the signals reported so far, `clients` is the number of connected clients.
+    pub fn report(&self, clients: usize) -> HealthReport {
+        let checks = vec![
+            check_clients(clients),
+            self.check_capture(),
+            self.check_encoder(),
+            self.check_input(),
+        ];
+        HealthReport {
+            healthy: checks.iter().all(|c| c.status != Status::Fail),
+            checks,
+        }
+    }
+
+    fn check_capture(&self) -> Check {
+        match self.capture.state() {
+            SignalState::Failed(error, ago) => Check::new(
+                "capture",
+                Status::Fail,
+                format!("Capturing failed {:.0}s ago: {}", ago.as_secs_f64(), error),
+                "Select the screen or window again, it may have been closed. Under Wayland \
+                enable --wayland-support, capturing via X11 does not work there.",
+            ),
+            SignalState::Ok(since) if since <= RECENT => {
+                let unchanged = ago(self.changed_at.load(Ordering::Relaxed));
+                if self.blank.load(Ordering::Relaxed) {
+                    Check::new(
+                        "capture",
+                        Status::Fail,
+                        "Captured frames are black.".into(),
+                        "Under Wayland enable --wayland-support. Windows that are minimized or \
+                        covered may be black, try --x11-window-capture contents or capture the \
+                        whole screen.",
+                    )
+                } else if unchanged > STALE {
+                    Check::new(
+                        "capture",
+                        Status::Warn,
+                        format!(
+                            "Captured frames did not change for {:.0}s.",
+                            unchanged.as_secs_f64()
+                        ),
+                        "The screen may just be idle, otherwise capturing is stuck and \
+                        --capture-stall-timeout restarts it automatically.",
+                    )
+                } else {
+                    Check::new("capture", Status::Pass, "Frames are captured.".into(), "")
+                }
+            }
+            SignalState::Ok(since) => Check::new(
+                "capture",
+                Status::Warn,
+                format!("No frame captured for {:.0}s.", since.as_secs_f64()),
+                "Frames are only captured while a client shows the video.",
+            ),
+            SignalState::Never => Check::new(
+                "capture",
+                Status::Warn,
+                "No frame has been captured yet.".into(),
+                "Frames are only captured while a client shows the video.",
+            ),
+        }
+    }
+
+    fn check_encoder(&self) -> Check {
+        match self.encode.state() {
+            SignalState::Failed(error, ago) => Check::new(
+                "encoder",
+                Status::Fail,
+                format!("Encoding failed {:.0}s ago: {}", ago.as_secs_f64(), error),
+                "Hardware encoders may not work, try without --try-vaapi, --try-nvenc and the \
+                like, /capabilities lists the encoders found.",
+            ),
+            SignalState::Ok(since) if since <= RECENT => {
+                Check::new("encoder", Status::Pass, "Frames are encoded.".into(), "")
+            }
+            SignalState::Ok(since) => Check::new(
+                "encoder",
+                Status::Warn,
+                format!("No frame encoded for {:.0}s.", since.as_secs_f64()),
+                "Frames are only encoded while a client shows the video, with \
+                --skip-unchanged-frames only if the screen changes.",
+            ),
+            SignalState::Never => Check::new(
+                "encoder",
+                Status::Warn,
+                "No frame has been encoded yet.".into(),
+                "Frames are only encoded while a client shows the video.",
+            ),
+        }
+    }
+
+    fn check_input(&self) -> Check {
+        match self.input.state() {
+            SignalState::Failed(error, ago) => Check::new(
+                "input",
+                Status::Fail,
+                format!(
+                    "Setting up input failed {:.0}s ago: {}",
+                    ago.as_secs_f64(),
+                    error
+                ),
+                "With uinput the user running Weylus needs access to /dev/uinput, see the log \
+                of Weylus. Disabling uinput on the client controls the mouse instead.",
+            ),
+            SignalState::Ok(_) => Check::new(
+                "input",
+                Status::Pass,
+                "Input devices have been created.".into(),
+                "",
+            ),
+            SignalState::Never => Check::new(
+                "input",
+                Status::Warn,
+                "No input device has been created yet.".into(),
+                "Input devices are created once a client selects what to capture.",
+            ),
+        }
+    }
+}
+
+fn check_clients(clients: usize) -> Check {
+    if clients > 0 {
+        Check::new(
+            "clients",
+            Status::Pass,
+            format!("{} connected.", clients),
+            "",
+        )
+    } else {
+        Check::new(
+            "clients",
+            Status::Warn,
+            "No client is connected.".into(),
+            "Open the address shown by Weylus in the browser of the device, if the page does not \
+            load make sure no firewall blocks the web and websocket ports.",
+        )
+    }
+}
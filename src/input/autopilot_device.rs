@@ -10,6 +10,26 @@ use crate::protocol::{Button, KeyboardEvent, KeyboardEventType, PointerEvent, Wh
 
 use crate::capturable::Capturable;
 
+#[cfg(target_os = "windows")]
+extern "system" {
+    // takes coordinates on the virtual desktop, contrary to autopilot which can only move the
+    // cursor on the primary monitor
+    fn SetCursorPos(x: std::os::raw::c_int, y: std::os::raw::c_int) -> std::os::raw::c_int;
+}
+
+// pixel on the virtual desktop at `x`, `y` relative to the capturable at `geometry`, as given by
+// Capturable::geometry_virtual_desktop
+#[cfg(any(target_os = "windows", test))]
+fn virtual_desktop_position(
+    (x, y, width, height): (i32, i32, u32, u32),
+    x_rel: f64,
+    y_rel: f64,
+) -> (i32, i32) {
+    let x = x as f64 + x_rel.clamp(0.0, 1.0) * (width.max(1) - 1) as f64;
+    let y = y as f64 + y_rel.clamp(0.0, 1.0) * (height.max(1) - 1) as f64;
+    (x.round() as i32, y.round() as i32)
+}
+
 pub struct AutoPilotDevice {
     capturable: Box<dyn Capturable>,
 }
@@ -18,14 +38,29 @@ impl AutoPilotDevice {
     pub fn new(capturable: Box<dyn Capturable>) -> Self {
         Self { capturable }
     }
+
+    fn toggle_buttons(&self, event: &PointerEvent) {
+        match event.button {
+            Button::PRIMARY => {
+                mouse::toggle(mouse::Button::Left, event.buttons.contains(event.button))
+            }
+            Button::AUXILARY => {
+                mouse::toggle(mouse::Button::Middle, event.buttons.contains(event.button))
+            }
+            Button::SECONDARY => {
+                mouse::toggle(mouse::Button::Right, event.buttons.contains(event.button))
+            }
+            _ => (),
+        }
+    }
 }
 
 impl InputDevice for AutoPilotDevice {
     fn send_wheel_event(&mut self, event: &WheelEvent) {
-        match event.dy {
-            1..=i32::MAX => mouse::scroll(ScrollDirection::Up, 1),
-            i32::MIN..=-1 => mouse::scroll(ScrollDirection::Down, 1),
-            0 => {}
+        if event.dy > 0.0 {
+            mouse::scroll(ScrollDirection::Up, 1);
+        } else if event.dy < 0.0 {
+            mouse::scroll(ScrollDirection::Down, 1);
         }
     }
 
@@ -37,6 +72,15 @@ impl InputDevice for AutoPilotDevice {
             warn!("Failed to activate window, sending no input ({})", err);
             return;
         }
+        #[cfg(target_os = "windows")]
+        if let Some(geometry) = self.capturable.geometry_virtual_desktop() {
+            let (x, y) = virtual_desktop_position(geometry, event.x, event.y);
+            if unsafe { SetCursorPos(x, y) } == 0 {
+                warn!("Could not move mouse!");
+            }
+            self.toggle_buttons(event);
+            return;
+        }
         let (x_rel, y_rel, width_rel, height_rel) = match self.capturable.geometry_relative() {
             Ok(g) => g,
             Err(e) => {
@@ -60,18 +104,7 @@ impl InputDevice for AutoPilotDevice {
         )) {
             warn!("Could not move mouse: {}", err);
         }
-        match event.button {
-            Button::PRIMARY => {
-                mouse::toggle(mouse::Button::Left, event.buttons.contains(event.button))
-            }
-            Button::AUXILARY => {
-                mouse::toggle(mouse::Button::Middle, event.buttons.contains(event.button))
-            }
-            Button::SECONDARY => {
-                mouse::toggle(mouse::Button::Right, event.buttons.contains(event.button))
-            }
-            _ => (),
-        }
+        self.toggle_buttons(event);
     }
 
     fn send_keyboard_event(&mut self, event: &KeyboardEvent) {
@@ -164,3 +197,34 @@ impl InputDevice for AutoPilotDevice {
         InputDeviceType::AutoPilotDevice
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_monitors() {
+        // primary monitor of 1920x1080 and one of 1280x1024 left of it, aligned at the bottom
+        let primary = (0, 0, 1920, 1080);
+        let left = (-1280, 56, 1280, 1024);
+        assert_eq!(virtual_desktop_position(primary, 0.0, 0.0), (0, 0));
+        assert_eq!(virtual_desktop_position(primary, 1.0, 1.0), (1919, 1079));
+        assert_eq!(virtual_desktop_position(left, 0.0, 0.0), (-1280, 56));
+        assert_eq!(virtual_desktop_position(left, 0.25, 0.25), (-960, 312));
+        assert_eq!(virtual_desktop_position(left, 1.0, 1.0), (-1, 1079));
+        // points outside of the capturable stay on its monitor
+        assert_eq!(virtual_desktop_position(left, 1.5, -0.5), (-1, 56));
+    }
+
+    #[test]
+    fn stacked_monitors() {
+        // 2560x1440 above the primary monitor of 1920x1080, centered
+        let top = (-320, -1440, 2560, 1440);
+        assert_eq!(virtual_desktop_position(top, 0.0, 0.0), (-320, -1440));
+        assert_eq!(virtual_desktop_position(top, 0.25, 1.0), (320, -1));
+        assert_eq!(
+            virtual_desktop_position((0, 0, 1920, 1080), 0.25, 0.0),
+            (480, 0)
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use crate::capturable::Capturable;
-use crate::protocol::{KeyboardEvent, PointerEvent, WheelEvent};
+use crate::protocol::{KeyboardEvent, PointerEvent, StylusButtons, WheelEvent};
 
 #[derive(PartialEq, Eq)]
 pub enum InputDeviceType {
@@ -12,5 +12,7 @@ pub trait InputDevice {
     fn send_pointer_event(&mut self, event: &PointerEvent);
     fn send_keyboard_event(&mut self, event: &KeyboardEvent);
     fn set_capturable(&mut self, capturable: Box<dyn Capturable>);
+    // only devices that emulate a stylus can remap its buttons
+    fn set_stylus_buttons(&mut self, _buttons: StylusButtons) {}
     fn device_type(&self) -> InputDeviceType;
 }
@@ -1,13 +1,15 @@
-use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
+use std::fs::OpenOptions;
 use std::os::raw::{c_char, c_int};
+use std::sync::{Arc, Mutex};
 
 use crate::capturable::x11::X11Context;
 use crate::capturable::Capturable;
 use crate::input::device::{InputDevice, InputDeviceType};
 use crate::protocol::{
     Button, KeyboardEvent, KeyboardEventType, KeyboardLocation, PointerEvent, PointerEventType,
-    PointerType, WheelEvent,
+    PointerType, StylusAction, StylusButtons, StylusTool, WheelEvent,
 };
 
 use crate::cerror::CError;
@@ -33,7 +35,21 @@ pub struct UInputDevice {
     mouse_fd: c_int,
     touch_fd: c_int,
     touches: [Option<MultiTouch>; 5],
-    tool_pen_active: bool,
+    // keys currently held down on the virtual keyboard
+    pressed_keys: HashSet<c_int>,
+    // scroll distance in high resolution wheel units (1/120 of a notch) that has not been sent
+    // yet, vertical and horizontal
+    scroll_remainder: (f64, f64),
+    // high resolution wheel units sent since the last full notch, vertical and horizontal
+    scroll_partial_notch: (i32, i32),
+    stylus_buttons: StylusButtons,
+    // tool of the stylus currently in proximity, pen or eraser, and the keys of the stylus held
+    // down, this allows sending only changes as buttons are remapped
+    stylus_tool: Option<c_int>,
+    stylus_keys: HashSet<c_int>,
+    // the client reports hovering, so lifting the pen keeps it in proximity until it leaves the
+    // range of the screen, other clients only report pens touching the screen
+    stylus_hover: bool,
     capturable: Box<dyn Capturable>,
     x: f64,
     y: f64,
@@ -99,7 +115,13 @@ impl UInputDevice {
             mouse_fd,
             touch_fd,
             touches: Default::default(),
-            tool_pen_active: false,
+            pressed_keys: HashSet::new(),
+            scroll_remainder: (0.0, 0.0),
+            scroll_partial_notch: (0, 0),
+            stylus_buttons: StylusButtons::default(),
+            stylus_tool: None,
+            stylus_keys: HashSet::new(),
+            stylus_hover: false,
             capturable,
             x: 0.0,
             y: 0.0,
@@ -125,14 +147,185 @@ impl UInputDevice {
         y as i32
     }
 
-    fn transform_pressure(&self, p: f64) -> i32 {
-        (p * ABS_MAX) as i32
+    // Devices without pressure support may report a pressure of 0 even while in contact, treat
+    // them as binary touch with full pressure.
+    fn transform_pressure(&self, p: f64, in_contact: bool) -> i32 {
+        let p = if in_contact && p <= 0.0 { 1.0 } else { p };
+        (p.clamp(0.0, 1.0) * ABS_MAX) as i32
+    }
+
+    fn transform_tilt(&self, tilt: i32) -> i32 {
+        tilt.clamp(-90, 90)
     }
 
     fn transform_touch_size(&self, s: f64) -> i32 {
         (s * ABS_MAX) as i32
     }
 
+    fn release_touches(&mut self) {
+        if self.touches.iter().all(Option::is_none) {
+            return;
+        }
+        for slot in 0..self.touches.len() {
+            if self.touches[slot].take().is_some() {
+                self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_SLOT, slot as i32);
+                self.send(self.touch_fd, ET_ABSOLUTE, EC_ABS_MT_TRACKING_ID, -1);
+            }
+        }
+        for key in [
+            EC_KEY_TOUCH,
+            EC_KEY_TOOL_FINGER,
+            EC_KEY_TOOL_DOUBLETAP,
+            EC_KEY_TOOL_TRIPLETAP,
+            EC_KEY_TOOL_QUADTAP,
+            EC_KEY_TOOL_QUINTTAP,
+        ] {
+            self.send(self.touch_fd, ET_KEY, key, 0);
+        }
+        self.send(self.touch_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+    }
+
+    // tool and keys of the stylus given the buttons and tool reported by the client and their
+    // mapping, the eraser end counts like the eraser button
+    fn stylus_state(&self, buttons: Button, tool: Option<StylusTool>) -> (c_int, HashSet<c_int>) {
+        let eraser = match tool {
+            Some(tool) => tool == StylusTool::Eraser,
+            None => buttons.contains(Button::ERASER),
+        };
+        let mut tool = EC_KEY_TOOL_PEN;
+        let mut keys = HashSet::new();
+        if buttons.contains(Button::PRIMARY) {
+            keys.insert(EC_KEY_TOUCH);
+        }
+        for (button, active, action) in [
+            (
+                Button::SECONDARY,
+                buttons.contains(Button::SECONDARY),
+                self.stylus_buttons.barrel,
+            ),
+            (Button::ERASER, eraser, self.stylus_buttons.eraser),
+        ] {
+            if !active {
+                continue;
+            }
+            match action {
+                StylusAction::None => (),
+                StylusAction::Left => {
+                    keys.insert(EC_KEY_TOUCH);
+                }
+                StylusAction::Right => {
+                    keys.insert(EC_KEY_STYLUS2);
+                }
+                StylusAction::Middle => {
+                    keys.insert(EC_KEY_STYLUS);
+                }
+                StylusAction::Eraser => {
+                    tool = EC_KEY_TOOL_RUBBER;
+                    // the eraser end of a pen is reported while it touches the screen
+                    if button == Button::ERASER {
+                        keys.insert(EC_KEY_TOUCH);
+                    }
+                }
+            }
+        }
+        (tool, keys)
+    }
+
+    fn update_stylus(&mut self, tool: c_int, keys: HashSet<c_int>) {
+        let released: Vec<c_int> = self.stylus_keys.difference(&keys).copied().collect();
+        for key in released {
+            self.send(self.stylus_fd, ET_KEY, key, 0);
+        }
+        if self.stylus_tool != Some(tool) {
+            // switching between tip and eraser: the old tool leaves proximity with all keys it
+            // held before the new one enters, otherwise applications see both tools at once
+            if self.stylus_tool.is_some() {
+                self.release_stylus();
+                self.send(self.stylus_fd, ET_ABSOLUTE, EC_ABSOLUTE_PRESSURE, 0);
+                self.send(self.stylus_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+            }
+            self.send(self.stylus_fd, ET_KEY, tool, 1);
+            self.stylus_tool = Some(tool);
+        }
+        for key in keys.difference(&self.stylus_keys) {
+            self.send(self.stylus_fd, ET_KEY, *key, 1);
+        }
+        self.stylus_keys = keys;
+    }
+
+    fn release_stylus(&mut self) {
+        for key in self.stylus_keys.drain().collect::<Vec<_>>() {
+            self.send(self.stylus_fd, ET_KEY, key, 0);
+        }
+        if let Some(tool) = self.stylus_tool.take() {
+            self.send(self.stylus_fd, ET_KEY, tool, 0);
+        }
+    }
+
+    fn send_key(&mut self, key_code: c_int, state: c_int) {
+        self.send(self.keyboard_fd, ET_KEY, key_code, state);
+        self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+        if state == 0 {
+            self.pressed_keys.remove(&key_code);
+        } else {
+            self.pressed_keys.insert(key_code);
+        }
+    }
+
+    // Bring the modifiers of the virtual keyboard in line with the modifiers reported by the
+    // client, they may be out of sync if a modifier was pressed before the page got focus or
+    // released while it did not have focus.
+    fn sync_modifiers(&mut self, event: &KeyboardEvent, key_code: c_int) {
+        use crate::input::uinput_keys::*;
+        // browsers on Windows report AltGr, used by many non-US layouts, as Ctrl + Alt, pressing
+        // Ctrl as well would turn the third level symbol into a shortcut
+        let alt_gr = key_code == KEY_RIGHTALT || self.pressed_keys.contains(&KEY_RIGHTALT);
+        for (active, left, right) in [
+            (event.ctrl && !alt_gr, KEY_LEFTCTRL, KEY_RIGHTCTRL),
+            (event.alt, KEY_LEFTALT, KEY_RIGHTALT),
+            (event.meta, KEY_LEFTMETA, KEY_RIGHTMETA),
+            (event.shift, KEY_LEFTSHIFT, KEY_RIGHTSHIFT),
+        ] {
+            // the event of the modifier itself sets its state
+            if key_code == left || key_code == right {
+                continue;
+            }
+            let pressed = self.pressed_keys.contains(&left) || self.pressed_keys.contains(&right);
+            if active && !pressed {
+                self.send_key(left, 1);
+            } else if !active && pressed && !(alt_gr && left == KEY_LEFTCTRL) {
+                for key in [left, right] {
+                    if self.pressed_keys.contains(&key) {
+                        self.send_key(key, 0);
+                    }
+                }
+            }
+        }
+    }
+
+    fn release_keys(&mut self) {
+        let keys: Vec<c_int> = self.pressed_keys.drain().collect();
+        for key in keys {
+            self.send(self.keyboard_fd, ET_KEY, key, 0);
+        }
+        self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+    }
+
+    // Release everything the client may still hold down, for example because it disconnected in
+    // the middle of a drag, so nothing gets stuck.
+    fn release_all(&mut self) {
+        self.release_touches();
+        self.release_keys();
+        self.release_stylus();
+        self.send(self.stylus_fd, ET_ABSOLUTE, EC_ABSOLUTE_PRESSURE, 0);
+        self.send(self.stylus_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+        // mouse buttons are not tracked, releasing buttons that are up already has no effect
+        for button in [EC_KEY_MOUSE_LEFT, EC_KEY_MOUSE_RIGHT, EC_KEY_MOUSE_MIDDLE] {
+            self.send(self.mouse_fd, ET_KEY, button, 0);
+        }
+        self.send(self.mouse_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+    }
+
     fn find_slot(&self, id: i64) -> Option<usize> {
         self.touches
             .iter()
@@ -160,8 +353,15 @@ impl UInputDevice {
     }
 }
 
+// The connection to the X server is only used for mapping the devices to the screen, by whichever
+// thread currently owns the device.
+unsafe impl Send for UInputDevice {}
+
 impl Drop for UInputDevice {
     fn drop(&mut self) {
+        // the client may disconnect while touches are still down, lift them so they do not get
+        // stuck
+        self.release_all();
         unsafe {
             destroy_uinput_device(self.keyboard_fd);
             destroy_uinput_device(self.stylus_fd);
@@ -171,6 +371,129 @@ impl Drop for UInputDevice {
     }
 }
 
+/// Check whether virtual input devices can be created, that is whether /dev/uinput can be opened
+/// for writing.
+pub fn uinput_accessible() -> std::io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open("/dev/uinput")
+        .map(|_| ())
+}
+
+/// How to make /dev/uinput accessible if opening it failed with `err`.
+pub fn uinput_fix(err: &std::io::Error) -> &'static str {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => {
+            "The uinput kernel module is not loaded. Load it by running `sudo modprobe uinput`, \
+            to load it on every boot run \
+            `echo uinput | sudo tee /etc/modules-load.d/weylus.conf`."
+        }
+        _ => include_str!("../strings/uinput_error.txt"),
+    }
+}
+
+/// Virtual input devices kept across connections, keyed by the name of the client they have
+/// been created for. The host enumerates new devices and applies its input configuration to them,
+/// for example via xinput, so recreating them on every reconnect causes needless churn. Devices are
+/// created for the first client with a name and destroyed once the pool and all devices taken from
+/// it are gone, which is when the server shuts down.
+#[derive(Clone, Default)]
+pub struct UInputPool {
+    idle: Arc<Mutex<HashMap<Option<String>, Vec<UInputDevice>>>>,
+}
+
+impl UInputPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take an idle device created for a client named `name` or create a new one if there is none,
+    /// for example because another client with the same name is connected.
+    pub fn get(
+        &self,
+        capturable: Box<dyn Capturable>,
+        name: &Option<String>,
+    ) -> Result<PooledUInputDevice, CError> {
+        let idle = self
+            .idle
+            .lock()
+            .unwrap()
+            .get_mut(name)
+            .and_then(|devices| devices.pop());
+        let device = match idle {
+            Some(mut device) => {
+                debug!("Reusing uinput device.");
+                device.set_capturable(capturable);
+                device.stylus_buttons = StylusButtons::default();
+                device.stylus_hover = false;
+                device
+            }
+            None => UInputDevice::new(capturable, name)?,
+        };
+        Ok(PooledUInputDevice {
+            device: Some(device),
+            name: name.clone(),
+            pool: self.clone(),
+        })
+    }
+}
+
+/// Device taken from a [`UInputPool`]. Once dropped, everything still held down is released and
+/// the device goes back to the pool.
+pub struct PooledUInputDevice {
+    // only none while dropping
+    device: Option<UInputDevice>,
+    name: Option<String>,
+    pool: UInputPool,
+}
+
+impl PooledUInputDevice {
+    fn device(&mut self) -> &mut UInputDevice {
+        self.device.as_mut().unwrap()
+    }
+}
+
+impl InputDevice for PooledUInputDevice {
+    fn send_wheel_event(&mut self, event: &WheelEvent) {
+        self.device().send_wheel_event(event);
+    }
+
+    fn send_pointer_event(&mut self, event: &PointerEvent) {
+        self.device().send_pointer_event(event);
+    }
+
+    fn send_keyboard_event(&mut self, event: &KeyboardEvent) {
+        self.device().send_keyboard_event(event);
+    }
+
+    fn set_capturable(&mut self, capturable: Box<dyn Capturable>) {
+        self.device().set_capturable(capturable);
+    }
+
+    fn set_stylus_buttons(&mut self, buttons: StylusButtons) {
+        self.device().set_stylus_buttons(buttons);
+    }
+
+    fn device_type(&self) -> InputDeviceType {
+        InputDeviceType::UInputDevice
+    }
+}
+
+impl Drop for PooledUInputDevice {
+    fn drop(&mut self) {
+        if let Some(mut device) = self.device.take() {
+            device.release_all();
+            self.pool
+                .idle
+                .lock()
+                .unwrap()
+                .entry(self.name.take())
+                .or_default()
+                .push(device);
+        }
+    }
+}
+
 // Event Types
 const ET_SYNC: c_int = 0x00;
 const ET_KEY: c_int = 0x01;
@@ -187,6 +510,8 @@ const EC_KEY_MOUSE_MIDDLE: c_int = 0x112;
 const EC_KEY_TOOL_PEN: c_int = 0x140;
 const EC_KEY_TOOL_RUBBER: c_int = 0x141;
 const EC_KEY_TOUCH: c_int = 0x14a;
+const EC_KEY_STYLUS: c_int = 0x14b;
+const EC_KEY_STYLUS2: c_int = 0x14c;
 const EC_KEY_TOOL_FINGER: c_int = 0x145;
 const EC_KEY_TOOL_DOUBLETAP: c_int = 0x14d;
 const EC_KEY_TOOL_TRIPLETAP: c_int = 0x14e;
@@ -200,6 +525,11 @@ const EC_REL_WHEEL: c_int = 0x08;
 const EC_REL_WHEEL_HI_RES: c_int = 0x0b;
 const EC_REL_HWHEEL_HI_RES: c_int = 0x0c;
 
+// high resolution wheel units per notch of a regular mouse wheel as defined by the kernel
+const HI_RES_PER_NOTCH: i32 = 120;
+// distance in pixels reported by browsers for a notch of a regular mouse wheel
+const SCROLL_PIXELS_PER_NOTCH: f64 = 100.0;
+
 const EC_ABSOLUTE_X: c_int = 0x00;
 const EC_ABSOLUTE_Y: c_int = 0x01;
 const EC_ABSOLUTE_PRESSURE: c_int = 0x18;
@@ -244,28 +574,36 @@ impl InputDevice for UInputDevice {
             return;
         }
 
-        fn direction(d: i32) -> i32 {
-            match d.cmp(&0) {
-                Ordering::Equal => 0,
-                Ordering::Less => -1,
-                Ordering::Greater => 1,
-            }
+        // Accumulate the scroll distance so small deltas from smooth scrolling are not lost:
+        // high resolution events are sent for every full unit, regular wheel events for every
+        // full notch. The horizontal axis is flipped to match the vertical one, which scrolls up
+        // for positive deltas.
+        let (rem_v, rem_h) = self.scroll_remainder;
+        let v = rem_v + event.dy * HI_RES_PER_NOTCH as f64 / SCROLL_PIXELS_PER_NOTCH;
+        let h = rem_h - event.dx * HI_RES_PER_NOTCH as f64 / SCROLL_PIXELS_PER_NOTCH;
+        let (v_hi_res, h_hi_res) = (v.trunc() as i32, h.trunc() as i32);
+        self.scroll_remainder = (v.fract(), h.fract());
+        if v_hi_res == 0 && h_hi_res == 0 {
+            return;
         }
 
-        self.send(
-            self.mouse_fd,
-            ET_RELATIVE,
-            EC_REL_WHEEL,
-            direction(event.dy),
-        );
-        self.send(
-            self.mouse_fd,
-            ET_RELATIVE,
-            EC_REL_HWHEEL,
-            direction(event.dy),
-        );
-        self.send(self.mouse_fd, ET_RELATIVE, EC_REL_WHEEL_HI_RES, event.dx);
-        self.send(self.mouse_fd, ET_RELATIVE, EC_REL_HWHEEL_HI_RES, event.dx);
+        let (partial_v, partial_h) = self.scroll_partial_notch;
+        let (partial_v, partial_h) = (partial_v + v_hi_res, partial_h + h_hi_res);
+        let (v_notches, h_notches) = (partial_v / HI_RES_PER_NOTCH, partial_h / HI_RES_PER_NOTCH);
+        self.scroll_partial_notch = (partial_v % HI_RES_PER_NOTCH, partial_h % HI_RES_PER_NOTCH);
+
+        if v_hi_res != 0 {
+            self.send(self.mouse_fd, ET_RELATIVE, EC_REL_WHEEL_HI_RES, v_hi_res);
+        }
+        if h_hi_res != 0 {
+            self.send(self.mouse_fd, ET_RELATIVE, EC_REL_HWHEEL_HI_RES, h_hi_res);
+        }
+        if v_notches != 0 {
+            self.send(self.mouse_fd, ET_RELATIVE, EC_REL_WHEEL, v_notches);
+        }
+        if h_notches != 0 {
+            self.send(self.mouse_fd, ET_RELATIVE, EC_REL_HWHEEL, h_notches);
+        }
 
         self.send(
             self.mouse_fd,
@@ -356,7 +694,7 @@ impl InputDevice for UInputDevice {
                             self.touch_fd,
                             ET_ABSOLUTE,
                             EC_ABS_MT_PRESSURE,
-                            self.transform_pressure(event.pressure),
+                            self.transform_pressure(event.pressure, true),
                         );
                         let major: i32;
                         let minor: i32;
@@ -433,6 +771,8 @@ impl InputDevice for UInputDevice {
                             self.touches[slot] = None;
                         }
                     }
+                    // only pens hover
+                    PointerEventType::HOVER | PointerEventType::LEAVE => (),
                 };
             }
             PointerType::Pen => {
@@ -443,20 +783,15 @@ impl InputDevice for UInputDevice {
                     self.num_touch_mapping_tries += 1;
                 }
                 match event.event_type {
-                    PointerEventType::DOWN | PointerEventType::MOVE => {
-                        if let PointerEventType::DOWN = event.event_type {
-                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOUCH, 1);
-                        }
-                        if !self.tool_pen_active && !event.buttons.contains(Button::ERASER) {
-                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_PEN, 1);
-                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_RUBBER, 0);
-                            self.tool_pen_active = true;
-                        }
-                        if let Button::ERASER = event.button {
-                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_PEN, 0);
-                            self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_RUBBER, 1);
-                            self.tool_pen_active = false;
+                    PointerEventType::DOWN | PointerEventType::MOVE | PointerEventType::HOVER => {
+                        let hover = matches!(event.event_type, PointerEventType::HOVER);
+                        let (tool, mut keys) = self.stylus_state(event.buttons, event.tool);
+                        if hover {
+                            // barrel buttons may be held while hovering but the pen does not touch
+                            self.stylus_hover = true;
+                            keys.remove(&EC_KEY_TOUCH);
                         }
+                        self.update_stylus(tool, keys);
                         self.send(
                             self.stylus_fd,
                             ET_ABSOLUTE,
@@ -473,27 +808,40 @@ impl InputDevice for UInputDevice {
                             self.stylus_fd,
                             ET_ABSOLUTE,
                             EC_ABSOLUTE_PRESSURE,
-                            self.transform_pressure(event.pressure),
+                            if hover {
+                                0
+                            } else {
+                                self.transform_pressure(
+                                    event.pressure,
+                                    self.stylus_keys.contains(&EC_KEY_TOUCH),
+                                )
+                            },
                         );
                         self.send(
                             self.stylus_fd,
                             ET_ABSOLUTE,
                             EC_ABSOLUTE_TILT_X,
-                            event.tilt_x,
+                            self.transform_tilt(event.tilt_x),
                         );
                         self.send(
                             self.stylus_fd,
                             ET_ABSOLUTE,
                             EC_ABSOLUTE_TILT_Y,
-                            event.tilt_y,
+                            self.transform_tilt(event.tilt_y),
                         );
                     }
-                    PointerEventType::UP | PointerEventType::CANCEL => {
-                        self.send(self.stylus_fd, ET_KEY, EC_KEY_TOUCH, 0);
-                        self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_PEN, 0);
-                        self.send(self.stylus_fd, ET_KEY, EC_KEY_TOOL_RUBBER, 0);
+                    // the pen hovers again after lifting it, only the tip is released
+                    PointerEventType::UP if self.stylus_hover => {
+                        let mut keys = self.stylus_keys.clone();
+                        keys.remove(&EC_KEY_TOUCH);
+                        if let Some(tool) = self.stylus_tool {
+                            self.update_stylus(tool, keys);
+                        }
+                        self.send(self.stylus_fd, ET_ABSOLUTE, EC_ABSOLUTE_PRESSURE, 0);
+                    }
+                    PointerEventType::UP | PointerEventType::CANCEL | PointerEventType::LEAVE => {
+                        self.release_stylus();
                         self.send(self.stylus_fd, ET_ABSOLUTE, EC_ABSOLUTE_PRESSURE, 0);
-                        self.tool_pen_active = false;
                     }
                 }
                 self.send(
@@ -512,7 +860,7 @@ impl InputDevice for UInputDevice {
                     self.num_touch_mapping_tries += 1;
                 }
                 match event.event_type {
-                    PointerEventType::DOWN | PointerEventType::MOVE => {
+                    PointerEventType::DOWN | PointerEventType::MOVE | PointerEventType::HOVER => {
                         if let PointerEventType::DOWN = event.event_type {
                             match event.button {
                                 Button::PRIMARY => {
@@ -540,6 +888,7 @@ impl InputDevice for UInputDevice {
                             self.transform_y(event.y),
                         );
                     }
+                    PointerEventType::LEAVE => (),
                     PointerEventType::UP | PointerEventType::CANCEL => match event.button {
                         Button::PRIMARY => self.send(self.mouse_fd, ET_KEY, EC_KEY_MOUSE_LEFT, 0),
                         Button::SECONDARY => {
@@ -807,31 +1156,18 @@ impl InputDevice for UInputDevice {
             return;
         }
 
-        if event.ctrl {
-            self.send(self.keyboard_fd, ET_KEY, KEY_LEFTCTRL, state);
-            self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
-        }
-        if event.alt {
-            self.send(self.keyboard_fd, ET_KEY, KEY_LEFTALT, state);
-            self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
-        }
-        if event.meta {
-            self.send(self.keyboard_fd, ET_KEY, KEY_LEFTMETA, state);
-            self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
-        }
-        if event.shift {
-            self.send(self.keyboard_fd, ET_KEY, KEY_LEFTSHIFT, state);
-            self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
-        }
-
-        self.send(self.keyboard_fd, ET_KEY, key_code, state);
-        self.send(self.keyboard_fd, ET_SYNC, EC_SYNC_REPORT, 0);
+        self.sync_modifiers(event, key_code);
+        self.send_key(key_code, state);
     }
 
     fn set_capturable(&mut self, capturable: Box<dyn Capturable>) {
         self.capturable = capturable;
     }
 
+    fn set_stylus_buttons(&mut self, buttons: StylusButtons) {
+        self.stylus_buttons = buttons;
+    }
+
     fn device_type(&self) -> InputDeviceType {
         InputDeviceType::UInputDevice
     }
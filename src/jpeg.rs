@@ -0,0 +1,71 @@
+use image::codecs::jpeg::JpegEncoder as ImageJpegEncoder;
+use image::{ColorType, ImageResult};
+
+use crate::video::PixelProvider;
+
+/// Encodes frames as individual JPEG images, this is a fallback for clients that can not decode
+/// the video stream. Frames are read directly from the captured RGB/BGR data without converting
+/// them to YUV first.
+pub struct JpegEncoder {
+    quality: u8,
+    // packed RGB of the scaled frame
+    rgb: Vec<u8>,
+    jpeg: Vec<u8>,
+}
+
+impl JpegEncoder {
+    /// `quality` ranges from 1 (smallest images) to 100 (best quality).
+    pub fn new(quality: u8) -> Self {
+        Self {
+            quality: quality.clamp(1, 100),
+            rgb: Vec::new(),
+            jpeg: Vec::new(),
+        }
+    }
+
+    /// Change the quality of the following images, see `new`.
+    pub fn set_quality(&mut self, quality: u8) {
+        self.quality = quality.clamp(1, 100);
+    }
+
+    /// Encode a frame scaled to `width_out`x`height_out`, the frame is only ever scaled down.
+    pub fn encode(
+        &mut self,
+        pixel_provider: PixelProvider,
+        width_out: usize,
+        height_out: usize,
+    ) -> ImageResult<&[u8]> {
+        let (width_in, height_in) = pixel_provider.size();
+        let width_out = width_out.clamp(1, width_in.max(1));
+        let height_out = height_out.clamp(1, height_in.max(1));
+        // bytes per pixel, row stride and offsets of red, green and blue
+        let (bpp, stride, [r, g, b]) = match pixel_provider {
+            PixelProvider::RGB(w, _, _) => (3, w * 3, [0, 1, 2]),
+            PixelProvider::RGB0(w, _, _) => (4, w * 4, [0, 1, 2]),
+            PixelProvider::BGR0(w, _, _) => (4, w * 4, [2, 1, 0]),
+            PixelProvider::BGR0S(_, _, stride, _) => (4, stride, [2, 1, 0]),
+        };
+        let data = pixel_provider.data();
+
+        // nearest neighbor scaling is good enough for a fallback and does not cost more than
+        // copying the frame
+        self.rgb.clear();
+        self.rgb.reserve(width_out * height_out * 3);
+        for y in 0..height_out {
+            let row = &data[y * height_in / height_out * stride..];
+            for x in 0..width_out {
+                let p = &row[x * width_in / width_out * bpp..];
+                self.rgb.extend_from_slice(&[p[r], p[g], p[b]]);
+            }
+        }
+
+        self.jpeg.clear();
+        ImageJpegEncoder::new_with_quality(&mut self.jpeg, self.quality).encode(
+            &self.rgb,
+            width_out as u32,
+            height_out as u32,
+            ColorType::Rgb8,
+        )?;
+        Ok(&self.jpeg)
+    }
+}
@@ -0,0 +1,375 @@
+#![cfg_attr(feature = "bench", feature(test))]
+//! Weylus turns a tablet or smartphone into a graphic tablet or touch screen for a computer. The
+//! `weylus` binary is a thin wrapper around this library, which can be used to embed Weylus into
+//! other applications:
+//!
+//! * [`WeylusServer`] runs the web server serving the client and the websocket server streaming
+//!   the video to clients and forwarding their input.
+//! * [`capturable`] lists the screens and windows that can be captured and records them.
+//! * [`video`] encodes the recorded frames, [`video::EncoderOptions`] configures the encoder.
+//!
+//! ```no_run
+//! weylus::init();
+//! let mut server = weylus::WeylusServer::builder()
+//!     .access_code("secret")
+//!     .capturable("HDMI-1")
+//!     .start()
+//!     .expect("Failed to start Weylus.");
+//! server.wait();
+//! ```
+#[cfg(feature = "bench")]
+extern crate test;
+
+#[macro_use]
+extern crate bitflags;
+
+mod access_tokens;
+#[cfg(target_os = "linux")]
+mod audio;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod capturable;
+mod cerror;
+#[cfg(target_os = "linux")]
+mod clipboard;
+pub mod config;
+mod control;
+pub mod gui;
+mod health;
+mod input;
+mod jpeg;
+pub mod log;
+mod mdns;
+mod metrics;
+mod protocol;
+mod recording;
+#[cfg(target_os = "linux")]
+mod sched;
+mod session_limit;
+mod tls;
+pub mod triple_buffer;
+pub mod video;
+mod web;
+mod websocket;
+mod weylus;
+
+pub use crate::web::{ACCESS_HTML, INDEX_HTML, LIB_JS, STYLE_CSS};
+pub use crate::websocket::Ws2UiMessage;
+pub use crate::weylus::{StartError, WeylusServer, WeylusServerBuilder};
+
+/// Initialize the libraries used for capturing the screen, this has to be called before any
+/// other threads are spawned.
+pub fn init() {
+    #[cfg(target_os = "linux")]
+    {
+        // make sure XInitThreads is called before any threading is done
+        crate::capturable::x11::x11_init();
+
+        if let Err(err) = gstreamer::init() {
+            tracing::error!(
+                "Failed to initialize gstreamer, screen capturing will most likely not work \
+                 on Wayland: {}",
+                err
+            );
+        }
+    }
+}
+
+#[cfg(feature = "bench")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capturable::{Capturable, Recorder};
+    use test::Bencher;
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_capture_x11(b: &mut Bencher) {
+        let mut x11ctx = capturable::x11::X11Context::new().unwrap();
+        let root = x11ctx.capturables().unwrap().remove(0);
+        let mut r = root.recorder(false).unwrap();
+        b.iter(|| {
+            r.capture().unwrap();
+        });
+    }
+
+    // capture from several threads while the display shared with the capturables is in use on
+    // the main thread, every recorder has its own connection to the X server
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_capture_x11_threads(b: &mut Bencher) {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        capturable::x11::x11_init();
+        let mut x11ctx = capturable::x11::X11Context::new().unwrap();
+        let root = x11ctx.capturables().unwrap().remove(0);
+        let stop = Arc::new(AtomicBool::new(false));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let root = root.clone();
+                let stop = stop.clone();
+                std::thread::spawn(move || {
+                    let mut r = root.recorder(false).unwrap();
+                    while !stop.load(Ordering::Relaxed) {
+                        r.capture().unwrap();
+                    }
+                })
+            })
+            .collect();
+        b.iter(|| root.geometry_relative().unwrap());
+        stop.store(true, Ordering::Relaxed);
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_video_x11(b: &mut Bencher) {
+        let mut x11ctx = capturable::x11::X11Context::new().unwrap();
+        let root = x11ctx.capturables().unwrap().remove(0);
+        let mut r = root.recorder(false).unwrap();
+        let (width, height) = r.capture().unwrap().size();
+
+        let opts = video::EncoderOptions {
+            try_vaapi: true,
+            try_nvenc: true,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            conversion_threads: 0,
+            color_matrix: video::ColorMatrix::Bt601,
+            color_range: video::ColorRange::Limited,
+            chroma_filter: video::ChromaFilter::Box,
+            yuv444: false,
+            grayscale: false,
+            codec: video::VideoCodec::H264,
+            max_bitrate: None,
+            constant_quality: None,
+            qp_range: None,
+            keyframe_interval: 12,
+            encoder_threads: 1,
+            low_latency: false,
+        };
+        let mut encoder =
+            video::VideoEncoder::new(width, height, width, height, |_| {}, opts).unwrap();
+        b.iter(|| encoder.encode(r.capture().unwrap()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_capture_wayland(b: &mut Bencher) {
+        gstreamer::init().unwrap();
+        let root = capturable::pipewire::get_capturables(false)
+            .unwrap()
+            .remove(0);
+        let mut r = root.recorder(false).unwrap();
+        let _ = r.capture();
+        b.iter(|| {
+            r.capture().unwrap();
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_video_wayland(b: &mut Bencher) {
+        gstreamer::init().unwrap();
+        let root = capturable::pipewire::get_capturables(false)
+            .unwrap()
+            .remove(0);
+        let mut r = root.recorder(false).unwrap();
+        let (width, height) = r.capture().unwrap().size();
+
+        let opts = video::EncoderOptions {
+            try_vaapi: true,
+            try_nvenc: true,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            conversion_threads: 0,
+            color_matrix: video::ColorMatrix::Bt601,
+            color_range: video::ColorRange::Limited,
+            chroma_filter: video::ChromaFilter::Box,
+            yuv444: false,
+            grayscale: false,
+            codec: video::VideoCodec::H264,
+            max_bitrate: None,
+            constant_quality: None,
+            qp_range: None,
+            keyframe_interval: 12,
+            encoder_threads: 1,
+            low_latency: false,
+        };
+        let mut encoder =
+            video::VideoEncoder::new(width, height, width, height, |_| {}, opts).unwrap();
+        b.iter(|| encoder.encode(r.capture().unwrap()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_video_vaapi(b: &mut Bencher) {
+        const WIDTH: usize = 1920;
+        const HEIGHT: usize = 1080;
+        const N: usize = 60;
+        let mut bufs = vec![vec![0u8; SIZE]; N];
+        for i in 0..N {
+            for j in 0..SIZE {
+                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
+            }
+        }
+
+        let opts = video::EncoderOptions {
+            try_vaapi: true,
+            try_nvenc: false,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            conversion_threads: 0,
+            color_matrix: video::ColorMatrix::Bt601,
+            color_range: video::ColorRange::Limited,
+            chroma_filter: video::ChromaFilter::Box,
+            yuv444: false,
+            grayscale: false,
+            codec: video::VideoCodec::H264,
+            max_bitrate: None,
+            constant_quality: None,
+            qp_range: None,
+            keyframe_interval: 12,
+            encoder_threads: 1,
+            low_latency: false,
+        };
+        let mut encoder =
+            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
+        const SIZE: usize = WIDTH * HEIGHT * 4;
+        let mut i = 0;
+        b.iter(|| {
+            encoder.encode(video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]));
+            i += 1;
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_video_x264(b: &mut Bencher) {
+        const WIDTH: usize = 1920;
+        const HEIGHT: usize = 1080;
+        const N: usize = 60;
+        let mut bufs = vec![vec![0u8; SIZE]; N];
+        for i in 0..N {
+            for j in 0..SIZE {
+                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
+            }
+        }
+
+        let opts = video::EncoderOptions {
+            try_vaapi: false,
+            try_nvenc: false,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            conversion_threads: 0,
+            color_matrix: video::ColorMatrix::Bt601,
+            color_range: video::ColorRange::Limited,
+            chroma_filter: video::ChromaFilter::Box,
+            yuv444: false,
+            grayscale: false,
+            codec: video::VideoCodec::H264,
+            max_bitrate: None,
+            constant_quality: None,
+            qp_range: None,
+            keyframe_interval: 12,
+            encoder_threads: 1,
+            low_latency: false,
+        };
+        let mut encoder =
+            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
+        const SIZE: usize = WIDTH * HEIGHT * 4;
+        let mut i = 0;
+        b.iter(|| {
+            encoder.encode(video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]));
+            i += 1;
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_convert_bgr0_padded(b: &mut Bencher) {
+        const WIDTH: usize = 1920;
+        const HEIGHT: usize = 1080;
+        // rows padded to a multiple of 256 bytes like some GPU capture paths do
+        const STRIDE: usize = (WIDTH * 4 + 255) / 256 * 256 + 256;
+        let mut buf = vec![0u8; STRIDE * HEIGHT];
+        for j in 0..HEIGHT {
+            for i in 0..STRIDE {
+                // fill padding with garbage, it must never end up in the video
+                buf[j * STRIDE + i] = if i < WIDTH * 4 { (i % 256) as u8 } else { 0xff };
+            }
+        }
+
+        let opts = video::EncoderOptions {
+            try_vaapi: false,
+            try_nvenc: false,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            conversion_threads: 0,
+            color_matrix: video::ColorMatrix::Bt601,
+            color_range: video::ColorRange::Limited,
+            chroma_filter: video::ChromaFilter::Box,
+            yuv444: false,
+            grayscale: false,
+            codec: video::VideoCodec::H264,
+            max_bitrate: None,
+            constant_quality: None,
+            qp_range: None,
+            keyframe_interval: 12,
+            encoder_threads: 1,
+            low_latency: false,
+        };
+        let mut encoder =
+            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
+        b.iter(|| {
+            encoder
+                .fill(video::PixelProvider::BGR0S(WIDTH, HEIGHT, STRIDE, &buf))
+                .unwrap();
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[bench]
+    fn bench_video_nvenc(b: &mut Bencher) {
+        const WIDTH: usize = 1920;
+        const HEIGHT: usize = 1080;
+        const N: usize = 60;
+        let mut bufs = vec![vec![0u8; SIZE]; N];
+        for i in 0..N {
+            for j in 0..SIZE {
+                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
+            }
+        }
+
+        let opts = video::EncoderOptions {
+            try_vaapi: false,
+            try_nvenc: true,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            conversion_threads: 0,
+            color_matrix: video::ColorMatrix::Bt601,
+            color_range: video::ColorRange::Limited,
+            chroma_filter: video::ChromaFilter::Box,
+            yuv444: false,
+            grayscale: false,
+            codec: video::VideoCodec::H264,
+            max_bitrate: None,
+            constant_quality: None,
+            qp_range: None,
+            keyframe_interval: 12,
+            encoder_threads: 1,
+            low_latency: false,
+        };
+        let mut encoder =
+            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
+        const SIZE: usize = WIDTH * HEIGHT * 4;
+        let mut i = 0;
+        b.iter(|| {
+            encoder.encode(video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]));
+            i += 1;
+        });
+    }
+}
@@ -3,6 +3,7 @@ use std::io::Write;
 use std::os::raw::c_char;
 use std::sync::mpsc;
 use tracing::{debug, error, info, trace, warn};
+use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::layer::SubscriberExt;
 
 extern "C" {
@@ -59,6 +60,8 @@ pub fn setup_logging(sender: mpsc::SyncSender<String>) {
         let logger = tracing_subscriber::fmt()
             .json()
             .with_max_level(get_log_level())
+            // timings of capturing and encoding frames are logged once their spans close
+            .with_span_events(FmtSpan::CLOSE)
             .with_writer(std::io::stdout)
             .finish()
             .with(
@@ -73,6 +76,7 @@ pub fn setup_logging(sender: mpsc::SyncSender<String>) {
     } else {
         let logger = tracing_subscriber::fmt()
             .with_max_level(get_log_level())
+            .with_span_events(FmtSpan::CLOSE)
             .with_writer(std::io::stderr)
             .finish()
             .with(
@@ -1,28 +1,11 @@
-#![cfg_attr(feature = "bench", feature(test))]
-#[cfg(feature = "bench")]
-extern crate test;
-
-#[macro_use]
-extern crate bitflags;
-
 use tracing::{error, warn};
 
 use std::sync::mpsc;
 
-use config::{get_config, Config};
 use structopt::StructOpt;
-
-mod capturable;
-mod cerror;
-mod config;
-mod gui;
-mod input;
-mod log;
-mod protocol;
-mod video;
-mod web;
-mod websocket;
-mod weylus;
+use weylus::capturable;
+use weylus::config::{get_config, Config};
+use weylus::{gui, log, WeylusServer, Ws2UiMessage};
 
 fn main() {
     let (sender, receiver) = mpsc::sync_channel::<String>(100);
@@ -37,209 +20,89 @@ fn main() {
     }
 
     if conf.print_index_html {
-        print!("{}", web::INDEX_HTML);
+        print!("{}", weylus::INDEX_HTML);
         return;
     }
     if conf.print_access_html {
-        print!("{}", web::ACCESS_HTML);
+        print!("{}", weylus::ACCESS_HTML);
         return;
     }
     if conf.print_style_css {
-        print!("{}", web::STYLE_CSS);
+        print!("{}", weylus::STYLE_CSS);
         return;
     }
     if conf.print_lib_js {
-        print!("{}", web::LIB_JS);
+        print!("{}", weylus::LIB_JS);
         return;
     }
 
-    #[cfg(target_os = "linux")]
-    {
-        // make sure XInitThreads is called before any threading is done
-        crate::capturable::x11::x11_init();
+    weylus::init();
 
-        if let Err(err) = gstreamer::init() {
+    // fail early instead of letting clients find out that there is nothing to capture, listing
+    // capturables via the desktop portal requires user interaction though, and a capture region is
+    // only set up on start but may be what is selected
+    #[cfg(target_os = "linux")]
+    let check_capturable = conf.capture_region.is_none()
+        && match conf.capture_backend {
+            capturable::CaptureBackend::Auto => !conf.wayland_support,
+            capturable::CaptureBackend::X11 => true,
+            capturable::CaptureBackend::Wayland => false,
+        };
+    #[cfg(not(target_os = "linux"))]
+    let check_capturable = true;
+    if let (Some(selector), true) = (&conf.capturable, check_capturable) {
+        let mut capturables = capturable::get_capturables(
+            #[cfg(target_os = "linux")]
+            capturable::CaptureBackend::X11,
+            #[cfg(target_os = "linux")]
+            false,
+            #[cfg(target_os = "linux")]
+            false,
+        );
+        if let Some(filter) = &conf.capturable_filter {
+            filter.apply(&mut capturables);
+        }
+        if capturable::find_capturable(&capturables, selector).is_none() {
+            let available: Vec<String> = capturables
+                .iter()
+                .map(|c| match c.scale_factor() {
+                    Some(scale_factor) if scale_factor != 1.0 => {
+                        format!(
+                            "  {} ({}, scaled by {:.0}%)",
+                            c.id(),
+                            c.name(),
+                            scale_factor * 100.0
+                        )
+                    }
+                    _ => format!("  {} ({})", c.id(), c.name()),
+                })
+                .collect();
             error!(
-                "Failed to initialize gstreamer, screen capturing will most likely not work \
-                 on Wayland: {}",
-                err
+                "No screen or window matches '{}', available are:\n{}",
+                selector,
+                available.join("\n")
             );
+            std::process::exit(1);
         }
     }
 
     if !conf.no_gui {
         gui::run(&conf, receiver);
     } else {
-        let mut weylus = crate::weylus::Weylus::new();
-        weylus.start(
-            &conf,
-            |_| {},
-            |msg| {
-                if let crate::websocket::Ws2UiMessage::UInputInaccessible = msg {
+        let server = WeylusServer::builder()
+            .config(conf)
+            .on_message(|msg| {
+                if let Ws2UiMessage::UInputInaccessible = msg {
                     warn!(std::include_str!("strings/uinput_error.txt"));
                 }
-            },
-        );
-        weylus.wait();
-    }
-}
-
-#[cfg(feature = "bench")]
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use capturable::{Capturable, Recorder};
-    use test::Bencher;
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_capture_x11(b: &mut Bencher) {
-        let mut x11ctx = capturable::x11::X11Context::new().unwrap();
-        let root = x11ctx.capturables().unwrap().remove(0);
-        let mut r = root.recorder(false).unwrap();
-        b.iter(|| {
-            r.capture().unwrap();
-        });
-    }
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_video_x11(b: &mut Bencher) {
-        let mut x11ctx = capturable::x11::X11Context::new().unwrap();
-        let root = x11ctx.capturables().unwrap().remove(0);
-        let mut r = root.recorder(false).unwrap();
-        let (width, height) = r.capture().unwrap().size();
-
-        let opts = video::EncoderOptions {
-            try_vaapi: true,
-            try_nvenc: true,
-            try_videotoolbox: false,
-            try_mediafoundation: false,
-        };
-        let mut encoder =
-            video::VideoEncoder::new(width, height, width, height, |_| {}, opts).unwrap();
-        b.iter(|| encoder.encode(r.capture().unwrap()));
-    }
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_capture_wayland(b: &mut Bencher) {
-        gstreamer::init().unwrap();
-        let root = capturable::pipewire::get_capturables(false)
-            .unwrap()
-            .remove(0);
-        let mut r = root.recorder(false).unwrap();
-        let _ = r.capture();
-        b.iter(|| {
-            r.capture().unwrap();
-        });
-    }
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_video_wayland(b: &mut Bencher) {
-        gstreamer::init().unwrap();
-        let root = capturable::pipewire::get_capturables(false)
-            .unwrap()
-            .remove(0);
-        let mut r = root.recorder(false).unwrap();
-        let (width, height) = r.capture().unwrap().size();
-
-        let opts = video::EncoderOptions {
-            try_vaapi: true,
-            try_nvenc: true,
-            try_videotoolbox: false,
-            try_mediafoundation: false,
-        };
-        let mut encoder =
-            video::VideoEncoder::new(width, height, width, height, |_| {}, opts).unwrap();
-        b.iter(|| encoder.encode(r.capture().unwrap()));
-    }
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_video_vaapi(b: &mut Bencher) {
-        const WIDTH: usize = 1920;
-        const HEIGHT: usize = 1080;
-        const N: usize = 60;
-        let mut bufs = vec![vec![0u8; SIZE]; N];
-        for i in 0..N {
-            for j in 0..SIZE {
-                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
+            })
+            .start();
+        match server {
+            Ok(mut server) => server.wait(),
+            Err(err) => {
+                error!("{}", err);
+                std::process::exit(1);
             }
         }
-
-        let opts = video::EncoderOptions {
-            try_vaapi: true,
-            try_nvenc: false,
-            try_videotoolbox: false,
-            try_mediafoundation: false,
-        };
-        let mut encoder =
-            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
-        const SIZE: usize = WIDTH * HEIGHT * 4;
-        let mut i = 0;
-        b.iter(|| {
-            encoder.encode(video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]));
-            i += 1;
-        });
-    }
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_video_x264(b: &mut Bencher) {
-        const WIDTH: usize = 1920;
-        const HEIGHT: usize = 1080;
-        const N: usize = 60;
-        let mut bufs = vec![vec![0u8; SIZE]; N];
-        for i in 0..N {
-            for j in 0..SIZE {
-                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
-            }
-        }
-
-        let opts = video::EncoderOptions {
-            try_vaapi: false,
-            try_nvenc: false,
-            try_videotoolbox: false,
-            try_mediafoundation: false,
-        };
-        let mut encoder =
-            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
-        const SIZE: usize = WIDTH * HEIGHT * 4;
-        let mut i = 0;
-        b.iter(|| {
-            encoder.encode(video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]));
-            i += 1;
-        });
-    }
-
-    #[cfg(target_os = "linux")]
-    #[bench]
-    fn bench_video_nvenc(b: &mut Bencher) {
-        const WIDTH: usize = 1920;
-        const HEIGHT: usize = 1080;
-        const N: usize = 60;
-        let mut bufs = vec![vec![0u8; SIZE]; N];
-        for i in 0..N {
-            for j in 0..SIZE {
-                bufs[i][j] = ((i * SIZE + j) % 256) as u8;
-            }
-        }
-
-        let opts = video::EncoderOptions {
-            try_vaapi: false,
-            try_nvenc: true,
-            try_videotoolbox: false,
-            try_mediafoundation: false,
-        };
-        let mut encoder =
-            video::VideoEncoder::new(WIDTH, HEIGHT, WIDTH, HEIGHT, |_| {}, opts).unwrap();
-        const SIZE: usize = WIDTH * HEIGHT * 4;
-        let mut i = 0;
-        b.iter(|| {
-            encoder.encode(video::PixelProvider::BGR0(WIDTH, HEIGHT, &bufs[i % N]));
-            i += 1;
-        });
     }
 }
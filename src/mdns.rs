@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tracing::{debug, info, warn};
+
+const SERVICE_TYPE: &str = "_weylus._tcp.local.";
+
+/// Advertises a running instance of Weylus via mDNS/DNS-SD as `_weylus._tcp` so clients can
+/// discover it on the local network. The advertisement is withdrawn once this is dropped.
+pub struct MdnsAdvertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl MdnsAdvertisement {
+    pub fn new(
+        instance_name: &str,
+        web_port: u16,
+        websocket_port: u16,
+        access_code_required: bool,
+    ) -> Result<Self, mdns_sd::Error> {
+        let daemon = ServiceDaemon::new()?;
+        let properties: HashMap<String, String> = [
+            ("version", env!("CARGO_PKG_VERSION").to_string()),
+            ("websocket_port", websocket_port.to_string()),
+            ("access_code", access_code_required.to_string()),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.clone()))
+        .collect();
+        // the host name has to be unique on the network as well, but it is only used to resolve
+        // the addresses of this instance
+        let host_name = format!(
+            "{}-{}.local.",
+            instance_name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+                .collect::<String>(),
+            web_port
+        );
+        // the addresses are picked from the network interfaces and updated automatically if
+        // interfaces go up or down
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            instance_name,
+            &host_name,
+            "",
+            web_port,
+            Some(properties),
+        )?
+        .enable_addr_auto();
+        let fullname = service.get_fullname().to_string();
+        daemon.register(service)?;
+        info!("Advertising Weylus via mDNS as '{}'.", fullname);
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for MdnsAdvertisement {
+    fn drop(&mut self) {
+        debug!("Withdrawing mDNS advertisement '{}'.", self.fullname);
+        match self.daemon.unregister(&self.fullname) {
+            // wait until the goodbye packets have been sent before stopping the daemon
+            Ok(receiver) => {
+                let _ = receiver.recv_timeout(Duration::from_secs(1));
+            }
+            Err(err) => warn!("Failed to withdraw mDNS advertisement: {}", err),
+        }
+        if let Err(err) = self.daemon.shutdown() {
+            warn!("Failed to shutdown mDNS daemon: {}", err);
+        }
+    }
+}
+
+/// Name this instance of Weylus is advertised as if none is configured.
+pub fn default_instance_name() -> String {
+    ["COMPUTERNAME", "HOSTNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|name| !name.is_empty()))
+        .map_or_else(|| "Weylus".into(), |host| format!("Weylus on {}", host))
+}
@@ -0,0 +1,246 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Total time and number of calls of an instrumented operation.
+pub struct Timing {
+    nanos: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Timing {
+    const fn new() -> Self {
+        Self {
+            nanos: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        self.nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+// f64 stored as its bit pattern
+pub struct Gauge(AtomicU64);
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Performance metrics of the video pipeline summed up over all clients, these are cheap to
+/// update and can be scraped in the Prometheus text format from `/metrics`.
+pub struct Metrics {
+    pub capture: Timing,
+    pub conversion: Timing,
+    pub encode: Timing,
+    pub send: Timing,
+    // time from receiving input events until they have been injected
+    pub input: Timing,
+    // time from capturing a frame until it has been encoded
+    pub frame_age: Timing,
+    pub frames_encoded: Counter,
+    // frames requested by a client while the previous one was still being processed
+    pub frames_dropped_busy: Counter,
+    // frames skipped because the connection is congested
+    pub frames_dropped_congestion: Counter,
+    // frames dropped from the encode queue because the encoder fell behind
+    pub frames_dropped_queue: Counter,
+    // frames not sent as they would exceed the bandwidth cap
+    pub frames_dropped_bandwidth: Counter,
+    pub frames_unchanged: Counter,
+    // keyframes sent because most of the frame changed at once
+    pub scene_changes: Counter,
+    // keyframes sent because a client failed to decode the video and asked to resync
+    pub keyframes_requested: Counter,
+    pub capture_fps: Gauge,
+    // frames waiting in the encode queue of the client that captured last
+    pub encode_queue_depth: Gauge,
+    // kbit/s sent to the client that captured last, measured by its bandwidth governor
+    pub bandwidth_kbps: Gauge,
+    // bytes sent to all clients including the framing of the websocket protocol
+    pub bytes_sent_video: Counter,
+    pub bytes_sent_control: Counter,
+}
+
+pub static METRICS: Metrics = Metrics {
+    capture: Timing::new(),
+    conversion: Timing::new(),
+    encode: Timing::new(),
+    send: Timing::new(),
+    input: Timing::new(),
+    frame_age: Timing::new(),
+    frames_encoded: Counter::new(),
+    frames_dropped_busy: Counter::new(),
+    frames_dropped_congestion: Counter::new(),
+    frames_dropped_queue: Counter::new(),
+    frames_dropped_bandwidth: Counter::new(),
+    frames_unchanged: Counter::new(),
+    scene_changes: Counter::new(),
+    keyframes_requested: Counter::new(),
+    capture_fps: Gauge::new(),
+    encode_queue_depth: Gauge::new(),
+    bandwidth_kbps: Gauge::new(),
+    bytes_sent_video: Counter::new(),
+    bytes_sent_control: Counter::new(),
+};
+
+impl Metrics {
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut s = String::new();
+        for (name, help, timing) in [
+            ("capture", "Time spent capturing frames.", &self.capture),
+            (
+                "conversion",
+                "Time spent converting frames to YUV.",
+                &self.conversion,
+            ),
+            ("encode", "Time spent encoding frames.", &self.encode),
+            ("send", "Time spent sending video data.", &self.send),
+            (
+                "input",
+                "Time from receiving input events until they have been injected.",
+                &self.input,
+            ),
+            (
+                "frame_age",
+                "Time from capturing frames until they have been encoded.",
+                &self.frame_age,
+            ),
+        ] {
+            let nanos = timing.nanos.load(Ordering::Relaxed);
+            let count = timing.count.load(Ordering::Relaxed);
+            let _ = writeln!(s, "# HELP weylus_{}_seconds {}", name, help);
+            let _ = writeln!(s, "# TYPE weylus_{}_seconds summary", name);
+            let _ = writeln!(s, "weylus_{}_seconds_sum {}", name, nanos as f64 * 1e-9);
+            let _ = writeln!(s, "weylus_{}_seconds_count {}", name, count);
+        }
+        let _ = writeln!(
+            s,
+            "# HELP weylus_frames_encoded_total Number of frames encoded."
+        );
+        let _ = writeln!(s, "# TYPE weylus_frames_encoded_total counter");
+        let _ = writeln!(
+            s,
+            "weylus_frames_encoded_total {}",
+            self.frames_encoded.0.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            s,
+            "# HELP weylus_frames_dropped_total Number of frames not sent to clients."
+        );
+        let _ = writeln!(s, "# TYPE weylus_frames_dropped_total counter");
+        for (reason, counter) in [
+            ("busy", &self.frames_dropped_busy),
+            ("congestion", &self.frames_dropped_congestion),
+            ("queue", &self.frames_dropped_queue),
+            ("bandwidth", &self.frames_dropped_bandwidth),
+            ("unchanged", &self.frames_unchanged),
+        ] {
+            let _ = writeln!(
+                s,
+                "weylus_frames_dropped_total{{reason=\"{}\"}} {}",
+                reason,
+                counter.0.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(
+            s,
+            "# HELP weylus_scene_changes_total Number of keyframes sent because most of the frame \
+            changed."
+        );
+        let _ = writeln!(s, "# TYPE weylus_scene_changes_total counter");
+        let _ = writeln!(
+            s,
+            "weylus_scene_changes_total {}",
+            self.scene_changes.0.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            s,
+            "# HELP weylus_keyframes_requested_total Number of keyframes sent because a client \
+            failed to decode the video."
+        );
+        let _ = writeln!(s, "# TYPE weylus_keyframes_requested_total counter");
+        let _ = writeln!(
+            s,
+            "weylus_keyframes_requested_total {}",
+            self.keyframes_requested.0.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            s,
+            "# HELP weylus_capture_fps Frames per second captured for the client that captured \
+            last."
+        );
+        let _ = writeln!(s, "# TYPE weylus_capture_fps gauge");
+        let _ = writeln!(
+            s,
+            "weylus_capture_fps {}",
+            f64::from_bits(self.capture_fps.0.load(Ordering::Relaxed))
+        );
+        let _ = writeln!(
+            s,
+            "# HELP weylus_encode_queue_depth Frames waiting to be encoded for the client that \
+            captured last."
+        );
+        let _ = writeln!(s, "# TYPE weylus_encode_queue_depth gauge");
+        let _ = writeln!(
+            s,
+            "weylus_encode_queue_depth {}",
+            f64::from_bits(self.encode_queue_depth.0.load(Ordering::Relaxed))
+        );
+        let _ = writeln!(
+            s,
+            "# HELP weylus_bandwidth_kbps Kbit/s sent over the last second to the client that \
+            captured last, only measured if a bandwidth cap is set."
+        );
+        let _ = writeln!(s, "# TYPE weylus_bandwidth_kbps gauge");
+        let _ = writeln!(
+            s,
+            "weylus_bandwidth_kbps {}",
+            f64::from_bits(self.bandwidth_kbps.0.load(Ordering::Relaxed))
+        );
+        let _ = writeln!(
+            s,
+            "# HELP weylus_bytes_sent_total Bytes sent to clients including the framing of the \
+            websocket protocol."
+        );
+        let _ = writeln!(s, "# TYPE weylus_bytes_sent_total counter");
+        for (kind, counter) in [
+            ("video", &self.bytes_sent_video),
+            ("control", &self.bytes_sent_control),
+        ] {
+            let _ = writeln!(
+                s,
+                "weylus_bytes_sent_total{{kind=\"{}\"}} {}",
+                kind,
+                counter.0.load(Ordering::Relaxed)
+            );
+        }
+        s
+    }
+}
@@ -1,6 +1,150 @@
 use serde::{Deserialize, Deserializer, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::capturable::CapturableKind;
+use crate::video::{ColorMatrix, ColorRange, PrivacyRegion, QualityPreset, Rotation};
+
+/// Version of the protocol spoken over the websocket, only increased on incompatible changes.
+/// Additions are announced via the features of the handshake instead.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Handshake sent by the client right after authenticating, before its first `Config`. Unknown
+/// fields are ignored and every field but the version is optional, so clients and servers of
+/// different age can still talk to each other.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientHello {
+    pub version: u32,
+    // video formats the client can decode: "h264", "vp9", "av1" and "jpeg" for the JPEG fallback,
+    // unknown ones are ignored
+    #[serde(default)]
+    pub decoders: Vec<String>,
+    // largest width or height of a video the client can decode and show
+    #[serde(default)]
+    pub max_decode_size: Option<usize>,
+    // pointer types the client sends events for: "mouse", "pen" and "touch"
+    #[serde(default)]
+    pub pointer_types: Vec<String>,
+    // features of the server the client can not work without, see `ServerHello::features`, the
+    // handshake is rejected if the server does not support all of them
+    #[serde(default)]
+    pub required: Vec<String>,
+    // prefix binary messages with a `FrameHeader`, requires the "frame_header" feature
+    #[serde(default)]
+    pub frame_header: bool,
+}
+
+impl ClientHello {
+    pub fn decodes(&self, decoder: &str) -> bool {
+        self.decoders.iter().any(|d| d == decoder)
+    }
+}
+
+/// What a running server supports, served as JSON at `/capabilities` so clients can adapt before
+/// connecting and users can find out why something is not offered. Versioned like the handshake,
+/// fields are only ever added.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Capabilities {
+    pub version: u32,
+    // video formats the server can encode, named like `ClientHello::decoders`
+    pub codecs: Vec<String>,
+    // ffmpeg encoders that are available, hardware encoders only if their device can be opened
+    pub encoders: Vec<String>,
+    // backends that can capture screens and windows, for example "x11" or "wayland"
+    pub capture_backends: Vec<String>,
+    // input can be sent via virtual input devices that support pressure, tilt and multitouch
+    pub uinput: bool,
+    pub clipboard: bool,
+    // the web and the websocket server are served via HTTPS and WSS, see `--tls-cert`
+    pub tls: bool,
+    // screens and windows captured via X11 can be aligned to vertical blanks to avoid tearing
+    pub vsync_capture: bool,
+    // screens and windows captured via X11 can be captured only once they changed
+    pub damage_tracking: bool,
+    // the sound of the host can be sent alongside the video, see `--audio`
+    pub audio: bool,
+    // Nvidia's NVENC can encode the video, it is used then unless `--no-nvenc` is given
+    pub nvenc: bool,
+}
+
+/// Reply of the server to `ClientHello` if it accepted the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerHello {
+    pub version: u32,
+    // format of the video chosen from the decoders of the client, quality presets may switch to
+    // another one the client supports
+    pub codec: String,
+    // largest width or height of the video the server sends
+    pub max_video_size: Option<usize>,
+    pub color_matrix: ColorMatrix,
+    pub color_range: ColorRange,
+    // input is sent via virtual input devices that support pressure, tilt and multitouch
+    pub uinput: bool,
+    // optional features supported by the server, for example "clipboard" or "crop"
+    pub features: Vec<String>,
+}
+
+/// What a binary message carrying video holds, see `FrameHeader`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FrameType {
+    /// Initialization segment of a new video, sent right after `NewVideo`.
+    Init = 0,
+    /// Fragment of the video that can be decoded without the ones before, clients that dropped
+    /// fragments can continue from here.
+    Keyframe = 1,
+    /// Fragment of the video that requires the ones before to be decoded.
+    Delta = 2,
+    /// Image of the JPEG fallback.
+    Jpeg = 3,
+    /// Opus packet of the audio, only sent to clients that enabled audio. Its timestamp is on the
+    /// clock of the current video, so it is played along with the frame of the same timestamp.
+    /// Width and height are 0.
+    Audio = 4,
+}
+
+/// Header binary messages start with if the client asked for it via `ClientHello::frame_header`.
+/// It is `FrameHeader::LEN` bytes long, numbers are little endian:
+///
+/// | Offset | Size | Content                                                   |
+/// |--------|------|-----------------------------------------------------------|
+/// | 0      | 4    | magic bytes `WYLF`                                        |
+/// | 4      | 1    | version of the header, `FrameHeader::VERSION`             |
+/// | 5      | 1    | type of the payload, see `FrameType`                      |
+/// | 6      | 2    | length of the header, the payload starts after it         |
+/// | 8      | 4    | timestamp in milliseconds since the video started         |
+/// | 12     | 4    | width of the video in pixels                              |
+/// | 16     | 4    | height of the video in pixels                             |
+///
+/// Later versions only append fields, so clients skip headers of any version by their length.
+/// Large fragments may be split into several messages with the same header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub frame_type: FrameType,
+    pub timestamp_ms: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FrameHeader {
+    pub const MAGIC: [u8; 4] = *b"WYLF";
+    pub const VERSION: u8 = 1;
+    pub const LEN: usize = 20;
+
+    /// Write the header followed by the payload to `buf`, replacing its content.
+    pub fn encode(&self, payload: &[u8], buf: &mut Vec<u8>) {
+        buf.clear();
+        buf.reserve(Self::LEN + payload.len());
+        buf.extend_from_slice(&Self::MAGIC);
+        buf.push(Self::VERSION);
+        buf.push(self.frame_type as u8);
+        buf.extend_from_slice(&(Self::LEN as u16).to_le_bytes());
+        buf.extend_from_slice(&self.timestamp_ms.to_le_bytes());
+        buf.extend_from_slice(&self.width.to_le_bytes());
+        buf.extend_from_slice(&self.height.to_le_bytes());
+        buf.extend_from_slice(payload);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientConfiguration {
     #[cfg(target_os = "linux")]
     pub uinput_support: bool,
@@ -8,11 +152,49 @@ pub struct ClientConfiguration {
     pub capture_cursor: bool,
     pub max_width: usize,
     pub max_height: usize,
+    // largest width or height of a video the client can decode and show, for example its maximum
+    // texture size
+    #[serde(default)]
+    pub max_decode_size: Option<usize>,
     pub client_name: Option<String>,
+    // x, y, width, height in pixels of the capturable, only capture this part of it
+    #[serde(default)]
+    pub crop: Option<(u32, u32, u32, u32)>,
+    // move the cropped region along with the cursor, only supported if capturing via X11
+    #[serde(default)]
+    pub follow_cursor: bool,
+    // rotate the video clockwise and mirror it afterwards, pointer events are relative to the
+    // transformed video
+    #[serde(default)]
+    pub rotation: Rotation,
+    #[serde(default)]
+    pub hflip: bool,
+    #[serde(default)]
+    pub vflip: bool,
+    // send the cursor separately so the client can draw it on top of the video
+    #[serde(default)]
+    pub cursor_overlay: bool,
+    // the client can decode VP9 in MP4
+    #[serde(default)]
+    pub vp9_support: bool,
+    // the client can decode AV1 in MP4
+    #[serde(default)]
+    pub av1_support: bool,
+    // the client can not decode the video stream at all and wants a series of JPEG images instead
+    #[serde(default)]
+    pub jpeg_fallback: bool,
+    // drop the colors of the video to save bandwidth, the server may enforce this anyway
+    #[serde(default)]
+    pub grayscale: bool,
+    // send the sound of the host as well, requires the "audio" feature and frame headers
+    #[serde(default)]
+    pub audio: bool,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum MessageInbound {
+    // capabilities of the client, the server replies with Hello or HelloRejected
+    Hello(ClientHello),
     PointerEvent(PointerEvent),
     WheelEvent(WheelEvent),
     KeyboardEvent(KeyboardEvent),
@@ -20,16 +202,353 @@ pub enum MessageInbound {
     // like this the client can partially control the framerate by sending requests at some given
     // rate. However, the server may drop a request if encoding is too slow.
     TryGetFrame,
+    // request that the next frame is a keyframe, for example because the client failed to decode
+    // the video and dropped the frames it had buffered, the server limits how often this happens
+    // with --keyframe-request-cooldown
+    #[serde(alias = "Resync")]
+    RequestKeyframe,
     GetCapturableList,
     Config(ClientConfiguration),
+    // change how the pressure of a stylus is mapped, this takes effect immediately without
+    // restarting the video
+    PressureCurve(PressureCurve),
+    // scale and move pointer positions on the capturable, null maps them one to one again, this
+    // takes effect immediately
+    InputMapping(Option<InputMapping>),
+    // text the client copied, written to the clipboard of the host if clipboard sync is enabled
+    Clipboard(String),
+    // resume the session with the given token after reconnecting, this has to be the first message
+    // and replaces the access code
+    ResumeSession(String),
+    // only list capturables whose title or window class contains this, see CapturableFilter,
+    // null lists all again, the server replies like to GetCapturableList and keeps the filter for
+    // later lists
+    FilterCapturables(Option<String>),
+    // switch to a preset of video settings, null goes back to the settings of the server, this
+    // restarts the video
+    QualityPreset(Option<QualityPreset>),
+    // change single video settings on top of the current ones, values out of range are clamped,
+    // the server restarts the video and replies with Quality
+    SetQuality(QualitySettings),
+    // show or hide the cursor in the captured image without restarting the video, this has no
+    // effect if the cursor can only be chosen when starting to capture, as with PipeWire
+    CaptureCursor(bool),
+    // stop capturing and encoding until the video is resumed, for example because the page is in
+    // the background, input and the connection are not affected
+    PauseVideo,
+    // continue a paused video, starting with a keyframe
+    ResumeVideo,
+    // change what the buttons of a stylus do, this takes effect immediately
+    StylusButtons(StylusButtons),
+    // request a preview of the capturable with the given index in the capturable list
+    GetThumbnail(usize),
+    // zoom into the rectangle x, y, width, height of the video, given relative to its size, null
+    // shows the whole capturable again, pointer events stay relative to the video
+    Crop(Option<(f64, f64, f64, f64)>),
+    // parts of the capturable to black out or blur in the video, replacing those given before, an
+    // empty list shows everything again
+    PrivacyRegions(Vec<PrivacyRegion>),
+}
+
+/// Settings of the video a client can change while it is running. Unset values are left as they
+/// are when sent by the client, when sent by the server they are not limited.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct QualitySettings {
+    // maximum bitrate in kbit/s
+    #[serde(default)]
+    pub bitrate: Option<u32>,
+    // maximum frames per second
+    #[serde(default)]
+    pub fps: Option<f64>,
+    // scale of the resolution requested by the client, between 0 and 1
+    #[serde(default)]
+    pub scale: Option<f64>,
+}
+
+/// How the video of a client fared since the previous report, sent periodically while frames are
+/// requested.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VideoStats {
+    // frames sent per second
+    pub fps: f64,
+    // fraction of the frames requested by the client that have not been sent, between 0 and 1
+    pub drop_rate: f64,
+    // video data sent in kbit/s
+    pub bitrate: u32,
+    // "damage" if frames are only captured once the screen changed, "poll" otherwise
+    pub capture_mode: String,
+    // ffmpeg encoder of the video, for example "h264_nvenc", none before the video started and for
+    // the JPEG fallback
+    #[serde(default)]
+    pub encoder: Option<String>,
+    // bytes actually sent over the connection per second including the framing of the websocket
+    // protocol, video is the video data and everything else is control
+    #[serde(default)]
+    pub video_bytes_per_second: u64,
+    #[serde(default)]
+    pub control_bytes_per_second: u64,
+    // bytes sent since the client connected, this starts from zero once it reconnects
+    #[serde(default)]
+    pub total_bytes: u64,
+}
+
+/// Timing of an input event sent by the client, which can tell the latency of the network from the
+/// time the server needs to inject events: the round trip is the time since `sent_at`, of which
+/// `processing` is spent on the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct InputLatency {
+    // `sent_at` of the event as given by the client
+    pub sent_at: f64,
+    // milliseconds from receiving the event until it has been injected
+    pub processing: f64,
+}
+
+/// Response curve applied to the pressure of a stylus before it is sent to the input device,
+/// defaults to linear, which leaves the pressure untouched.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub enum PressureCurve {
+    #[default]
+    Linear,
+    // pressure^gamma, a gamma above 1 makes the pen feel heavier, below 1 lighter
+    Gamma(f64),
+    // control points (input, output) with values between 0 and 1, the pressure is linearly
+    // interpolated between them, (0, 0) and (1, 1) are used as end points if missing
+    Points(Vec<(f64, f64)>),
+}
+
+/// What a button of a stylus does on the host.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StylusAction {
+    // the button is ignored
+    None,
+    // touch the screen like the tip of the pen, which is a left click for most applications
+    Left,
+    Right,
+    Middle,
+    // switch to the eraser tool while the button is held
+    Eraser,
+}
+
+/// Mapping of the buttons of a stylus reported by the client to actions on the host, the tip
+/// always touches the screen. Defaults to the barrel button touching the screen like the tip and
+/// the eraser end of the pen erasing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StylusButtons {
+    #[serde(default = "default_barrel_action")]
+    pub barrel: StylusAction,
+    #[serde(default = "default_eraser_action")]
+    pub eraser: StylusAction,
+}
+
+fn default_barrel_action() -> StylusAction {
+    StylusAction::Left
+}
+
+fn default_eraser_action() -> StylusAction {
+    StylusAction::Eraser
+}
+
+impl Default for StylusButtons {
+    fn default() -> Self {
+        Self {
+            barrel: default_barrel_action(),
+            eraser: default_eraser_action(),
+        }
+    }
+}
+
+impl PressureCurve {
+    pub fn apply(&self, pressure: f64) -> f64 {
+        let p = pressure.clamp(0.0, 1.0);
+        let p = match self {
+            Self::Linear => p,
+            Self::Gamma(gamma) if *gamma > 0.0 => p.powf(*gamma),
+            Self::Gamma(_) => p,
+            Self::Points(points) => {
+                let mut points: Vec<(f64, f64)> = points
+                    .iter()
+                    .filter(|(x, y)| x.is_finite() && y.is_finite())
+                    .map(|(x, y)| (x.clamp(0.0, 1.0), y.clamp(0.0, 1.0)))
+                    .collect();
+                points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+                if points.first().map_or(true, |(x, _)| *x > 0.0) {
+                    points.insert(0, (0.0, 0.0));
+                }
+                if points.last().map_or(true, |(x, _)| *x < 1.0) {
+                    points.push((1.0, 1.0));
+                }
+                points.windows(2).find(|w| p <= w[1].0).map_or(p, |w| {
+                    let ((x0, y0), (x1, y1)) = (w[0], w[1]);
+                    if x1 > x0 {
+                        y0 + (p - x0) / (x1 - x0) * (y1 - y0)
+                    } else {
+                        y1
+                    }
+                })
+            }
+        };
+        p.clamp(0.0, 1.0)
+    }
+}
+
+/// Affine mapping of one axis, the position x becomes offset + scale * x.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AxisMapping {
+    #[serde(default = "default_axis_scale")]
+    pub scale: f64,
+    #[serde(default)]
+    pub offset: f64,
+}
+
+fn default_axis_scale() -> f64 {
+    1.0
+}
+
+impl Default for AxisMapping {
+    fn default() -> Self {
+        Self {
+            scale: default_axis_scale(),
+            offset: 0.0,
+        }
+    }
+}
+
+/// Mapping of pointer positions applied after they have been rotated, flipped and cropped back to
+/// positions on the capturable, so both axes are those of the capturable whatever the video looks
+/// like. A scale of 2 with an offset of -0.5 maps the middle half of the tablet to the whole
+/// capturable, a scale of -1 with an offset of 1 inverts the axis.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct InputMapping {
+    #[serde(default)]
+    pub x: AxisMapping,
+    #[serde(default)]
+    pub y: AxisMapping,
+    // keep mapped positions on the capturable, otherwise positions beyond its edges are passed on
+    // to the input device
+    #[serde(default = "default_clamp")]
+    pub clamp: bool,
+}
+
+fn default_clamp() -> bool {
+    true
+}
+
+impl InputMapping {
+    /// Whether the scales and offsets are finite and neither scale is 0, which would map
+    /// everything to a single line.
+    pub fn is_valid(&self) -> bool {
+        [self.x, self.y]
+            .iter()
+            .all(|a| a.scale.is_finite() && a.offset.is_finite() && a.scale != 0.0)
+    }
+
+    pub fn apply(&self, event: &mut PointerEvent) {
+        event.x = self.x.offset + self.x.scale * event.x;
+        event.y = self.y.offset + self.y.scale * event.y;
+        if self.clamp {
+            event.x = event.x.clamp(0.0, 1.0);
+            event.y = event.y.clamp(0.0, 1.0);
+        }
+        event.movement_x = (event.movement_x as f64 * self.x.scale) as i64;
+        event.movement_y = (event.movement_y as f64 * self.y.scale) as i64;
+        // the pen tilts the other way on an inverted axis
+        event.tilt_x *= self.x.scale.signum() as i32;
+        event.tilt_y *= self.y.scale.signum() as i32;
+        event.width *= self.x.scale.abs();
+        event.height *= self.y.scale.abs();
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CapturableInfo {
+    pub id: String,
+    pub name: String,
+    // x, y, width, height relative to the whole screen, see Capturable::geometry_relative
+    pub geometry: Option<(f64, f64, f64, f64)>,
+    // physical pixels per logical pixel, see Capturable::scale_factor
+    pub scale_factor: Option<f64>,
+    // selected via the command line, clients that have not selected anything yet capture this
+    pub preselected: bool,
+    // the following are None if unknown, see the respective methods of Capturable
+    pub kind: Option<CapturableKind>,
+    pub pid: Option<u32>,
+    pub process_name: Option<String>,
+    pub minimized: Option<bool>,
+    // position in the stacking order from bottom to top, the list is sorted topmost first already
+    pub stacking: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CursorInfo {
+    // position of the hotspot relative to the video, 0.0 is left/top, 1.0 is right/bottom
+    pub x: f64,
+    pub y: f64,
+    // size of the captured image in pixels, the cursor image is in the same scale
+    pub frame_width: usize,
+    pub frame_height: usize,
+    pub hotspot_x: i32,
+    pub hotspot_y: i32,
+    pub width: usize,
+    pub height: usize,
+    // factor the image is drawn scaled by around the hotspot, width, height and hotspot are those
+    // of the image before scaling
+    pub scale: f64,
+    // RGBA, only sent if the image changed
+    pub image: Option<Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum MessageOutbound {
-    CapturableList(Vec<String>),
+    /// The server accepted the handshake of the client.
+    Hello(ServerHello),
+    /// The server does not support the protocol version or a feature required by the client.
+    HelloRejected(String),
+    CapturableList(Vec<CapturableInfo>),
+    /// Number of capturables listed and of those there are without the filter of the client, sent
+    /// after `CapturableList`.
+    CapturableCount(usize, usize),
+    /// MIME type of the following video, sent right before `NewVideo`.
+    VideoMimeType(String),
     NewVideo,
+    /// Width and height of the video after scaling it down, sent whenever they change.
+    VideoSize(usize, usize),
+    Cursor(CursorInfo),
+    /// Current maximum bitrate of the video in kbit/s, only sent if the bitrate is adaptive.
+    Bitrate(u32),
+    /// Video settings in effect after `SetQuality`, after clamping them.
+    Quality(QualitySettings),
+    Stats(VideoStats),
+    /// Reply to an input event carrying `sent_at`, sent once it has been injected.
+    InputLatency(InputLatency),
+    /// Text of the clipboard of the host, sent whenever it changes if clipboard sync is enabled.
+    Clipboard(String),
+    /// Token to resume the session with after losing the connection.
+    Session(String),
+    /// Preview of the capturable with the given index in the capturable list as data URL of a
+    /// JPEG image, only sent if requested via `GetThumbnail` and capturing succeeded.
+    Thumbnail(usize, String),
+    SessionResumed,
+    /// The session to resume does not exist anymore, the client has to authenticate and set up a
+    /// new one.
+    SessionExpired,
+    /// Seconds left until the session ends if it is limited by `--max-session-duration`, sent once
+    /// the client is set up and whenever the session has been extended.
+    SessionRemaining(u64),
+    /// The session ends in the given number of seconds.
+    SessionEnding(u64),
+    /// The session reached its end, the server closes the connection right after this.
+    SessionEnded,
     ConfigOk,
     ConfigError(String),
+    /// Sample rate and number of channels of the audio that follows, sent whenever capturing audio
+    /// (re)starts.
+    AudioStarted(u32, u32),
+    /// Capturing audio failed, for example as the source disappeared, it is retried after a
+    /// while.
+    AudioStopped(String),
+    /// Input of the client is ignored because no input device could be set up, for example as
+    /// /dev/uinput is not accessible. The client can still view the screen.
+    InputDisabled(String),
     Error(String),
 }
 
@@ -45,6 +564,14 @@ pub enum PointerType {
     Touch,
 }
 
+/// End of a pen in use, pens with an eraser end report it when turned around.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StylusTool {
+    Pen,
+    Eraser,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum PointerEventType {
     #[serde(rename = "pointerdown")]
@@ -55,6 +582,13 @@ pub enum PointerEventType {
     CANCEL,
     #[serde(rename = "pointermove")]
     MOVE,
+    /// A pen moved within range of the screen without touching it, clients only send this if the
+    /// server supports the `stylus_hover` feature and send `MOVE` for pens touching the screen.
+    #[serde(rename = "pointerhover")]
+    HOVER,
+    /// A pen left the range of the screen, see `HOVER`.
+    #[serde(rename = "pointerleave")]
+    LEAVE,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -120,6 +654,9 @@ pub struct KeyboardEvent {
     pub ctrl: bool,
     pub shift: bool,
     pub meta: bool,
+    // see `PointerEvent::sent_at`
+    #[serde(default)]
+    pub sent_at: Option<f64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -138,16 +675,28 @@ pub struct PointerEvent {
     pub movement_x: i64,
     pub movement_y: i64,
     pub pressure: f64,
+    #[serde(default)]
     pub tilt_x: i32,
+    #[serde(default)]
     pub tilt_y: i32,
     pub twist: i32,
     pub width: f64,
     pub height: f64,
+    // end of the pen in use, only for pens, if not given the eraser button decides
+    #[serde(default)]
+    pub tool: Option<StylusTool>,
+    // time the client sent the event at in milliseconds of its own clock, if set the server
+    // replies with InputLatency once the event has been injected
+    #[serde(default)]
+    pub sent_at: Option<f64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct WheelEvent {
-    pub dx: i32,
-    pub dy: i32,
+    pub dx: f64,
+    pub dy: f64,
     pub timestamp: u64,
+    // see `PointerEvent::sent_at`
+    #[serde(default)]
+    pub sent_at: Option<f64>,
 }
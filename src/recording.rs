@@ -0,0 +1,88 @@
+//! Recording the video sent to clients to files, see `--record`. The encoded video is written as
+//! it is sent, so recording does not encode frames a second time.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tracing::{info, warn};
+
+/// Writes every video to a file of its own, as a video restarts whenever its size changes and each
+/// client has its own video. The first video goes to the path given, later ones get a number
+/// appended to the name of the file, `demo.mp4`, `demo-2.mp4`, `demo-3.mp4` and so on.
+#[derive(Debug)]
+pub struct SessionRecorder {
+    path: PathBuf,
+    // videos recorded so far
+    segments: AtomicUsize,
+}
+
+impl SessionRecorder {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            segments: AtomicUsize::new(0),
+        }
+    }
+
+    fn segment_path(&self, n: usize) -> PathBuf {
+        if n == 1 {
+            return self.path.clone();
+        }
+        let stem = self
+            .path
+            .file_stem()
+            .map_or_else(|| "recording".into(), |stem| stem.to_string_lossy());
+        let name = match self.path.extension() {
+            Some(extension) => format!("{}-{}.{}", stem, n, extension.to_string_lossy()),
+            None => format!("{}-{}", stem, n),
+        };
+        self.path.with_file_name(name)
+    }
+
+    /// Create the file for the next video, existing files are overwritten.
+    pub fn next_segment(&self) -> std::io::Result<RecordingSegment> {
+        let n = self.segments.fetch_add(1, Ordering::Relaxed) + 1;
+        let path = self.segment_path(n);
+        let file = File::create(&path)?;
+        info!("Recording video to {}.", path.display());
+        Ok(RecordingSegment {
+            file: Some(BufWriter::new(file)),
+            path,
+        })
+    }
+}
+
+/// File a single video is recorded to, it is complete once this is dropped.
+pub struct RecordingSegment {
+    // none once writing failed
+    file: Option<BufWriter<File>>,
+    path: PathBuf,
+}
+
+impl RecordingSegment {
+    /// Append encoded video, if this fails recording stops but the video is still sent.
+    pub fn write(&mut self, data: &[u8]) {
+        if let Some(file) = &mut self.file {
+            if let Err(err) = file.write_all(data) {
+                warn!(
+                    "Failed to write recording {}, stopping to record: {}",
+                    self.path.display(),
+                    err
+                );
+                self.file = None;
+            }
+        }
+    }
+}
+
+impl Drop for RecordingSegment {
+    fn drop(&mut self) {
+        if let Some(file) = &mut self.file {
+            if let Err(err) = file.flush() {
+                warn!("Failed to write recording {}: {}", self.path.display(), err);
+            }
+        }
+    }
+}
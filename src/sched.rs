@@ -0,0 +1,104 @@
+//! Scheduling of the threads capturing and encoding frames, on a busy host they may otherwise be
+//! woken up late, which shows as uneven frame pacing.
+
+use std::os::raw::{c_int, c_uint};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::cerror::CError;
+
+extern "C" {
+    fn set_thread_priority(priority: c_int, err: *mut CError);
+    fn set_thread_affinity(cpus: *const c_uint, num_cpus: c_int, err: *mut CError);
+}
+
+/// CPUs given as comma separated list of numbers and ranges, for example `0,2-3`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct CpuList(pub Vec<u32>);
+
+impl FromStr for CpuList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid list of CPUs: '{}', expected for example '0,2-3'.",
+                s
+            )
+        };
+        let mut cpus = Vec::new();
+        for part in s.split(',') {
+            let part = part.trim();
+            let (first, last) = part.split_once('-').unwrap_or((part, part));
+            let first: u32 = first.trim().parse().map_err(|_| invalid())?;
+            let last: u32 = last.trim().parse().map_err(|_| invalid())?;
+            if first > last {
+                return Err(invalid());
+            }
+            cpus.extend(first..=last);
+        }
+        Ok(Self(cpus))
+    }
+}
+
+impl TryFrom<String> for CpuList {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<CpuList> for String {
+    fn from(cpus: CpuList) -> Self {
+        let cpus: Vec<_> = cpus.0.iter().map(|cpu| cpu.to_string()).collect();
+        cpus.join(",")
+    }
+}
+
+/// How the capture and encode threads are scheduled, `None` leaves the setting unchanged.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThreadScheduling {
+    /// Real-time priority between 1 and 99, threads are scheduled round-robin with it.
+    pub priority: Option<u8>,
+    /// CPUs the threads may run on.
+    pub cpus: Option<CpuList>,
+}
+
+impl ThreadScheduling {
+    /// Apply to the calling thread, failures are logged and the thread keeps its scheduling, most
+    /// likely the process is not allowed to raise its priority.
+    pub fn apply(&self, thread: &str) {
+        if let Some(priority) = self.priority {
+            let mut err = CError::new();
+            unsafe { set_thread_priority(priority as c_int, &mut err) };
+            if err.is_err() {
+                warn!(
+                    "Failed to raise priority of {} thread, this requires CAP_SYS_NICE or a \
+                    sufficient real-time priority limit: {}",
+                    thread, err
+                );
+            } else {
+                debug!(
+                    "Set real-time priority of {} thread to {}.",
+                    thread, priority
+                );
+            }
+        }
+        if let Some(CpuList(cpus)) = &self.cpus {
+            let mut err = CError::new();
+            unsafe { set_thread_affinity(cpus.as_ptr(), cpus.len() as c_int, &mut err) };
+            if err.is_err() {
+                warn!(
+                    "Failed to pin {} thread to CPUs {:?}: {}",
+                    thread, cpus, err
+                );
+            } else {
+                debug!("Pinned {} thread to CPUs {:?}.", thread, cpus);
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Ending sessions after a maximum duration, see `--max-session-duration`, for example to free a
+//! shared device for the next user after a while.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::spawn;
+use std::time::{Duration, Instant};
+
+// clients are warned this long before their session ends, at most after half of the session
+const WARNING: Duration = Duration::from_secs(60);
+// sessions end at most this long from now however long they have been extended
+const MAX_REMAINING: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+// `deadline` moved back by `by` without overflowing, at most `MAX_REMAINING` from now
+fn extended(deadline: Instant, by: Duration) -> Instant {
+    let limit = Instant::now() + MAX_REMAINING;
+    deadline
+        .checked_add(by)
+        .map_or(limit, |deadline| deadline.min(limit))
+}
+
+/// Change the end of a running session, sent via the control channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionCommand {
+    Extend(Duration),
+    // end the session right away
+    End,
+}
+
+/// What the client of a limited session is told.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    // the session has been extended and ends after this
+    Remaining(Duration),
+    // the session is about to end after this
+    Ending(Duration),
+    // the session is over, the client has to be disconnected
+    Ended,
+}
+
+/// Sends [`SessionCommand`]s to the timer of a session, it does nothing once the session is gone.
+#[derive(Debug, Clone)]
+pub struct SessionHandle(mpsc::Sender<SessionCommand>);
+
+impl SessionHandle {
+    /// Returns false if the session is gone.
+    pub fn send(&self, command: SessionCommand) -> bool {
+        self.0.send(command).is_ok()
+    }
+}
+
+/// Counts down the time left of a session in a thread of its own, which stops once this is
+/// dropped. The countdown starts right away and keeps running while the client is disconnected.
+pub struct SessionTimer {
+    commands: mpsc::Sender<SessionCommand>,
+    deadline: Arc<Mutex<Instant>>,
+    expired: Arc<AtomicBool>,
+}
+
+impl SessionTimer {
+    /// Start the countdown, `notify` is called from the thread of the timer.
+    pub fn start(
+        duration: Duration,
+        mut notify: impl FnMut(SessionEvent) + Send + 'static,
+    ) -> Self {
+        let (commands, receiver) = mpsc::channel();
+        let deadline = Arc::new(Mutex::new(extended(Instant::now(), duration)));
+        let expired = Arc::new(AtomicBool::new(false));
+        let warning = WARNING.min(duration / 2);
+        {
+            let deadline = deadline.clone();
+            let expired = expired.clone();
+            spawn(move || {
+                let mut warned = false;
+                loop {
+                    let remaining = deadline
+                        .lock()
+                        .unwrap()
+                        .saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        expired.store(true, Ordering::Relaxed);
+                        notify(SessionEvent::Ended);
+                        return;
+                    }
+                    if !warned && remaining <= warning {
+                        warned = true;
+                        notify(SessionEvent::Ending(remaining));
+                    }
+                    let wait = if warned {
+                        remaining
+                    } else {
+                        remaining - warning
+                    };
+                    match receiver.recv_timeout(wait) {
+                        Ok(SessionCommand::Extend(by)) => {
+                            let remaining = {
+                                let mut deadline = deadline.lock().unwrap();
+                                *deadline = extended(*deadline, by);
+                                deadline.saturating_duration_since(Instant::now())
+                            };
+                            // warn again once the extended session is about to end
+                            warned = remaining <= warning;
+                            notify(SessionEvent::Remaining(remaining));
+                        }
+                        Ok(SessionCommand::End) => *deadline.lock().unwrap() = Instant::now(),
+                        Err(mpsc::RecvTimeoutError::Timeout) => (),
+                        Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            });
+        }
+        Self {
+            commands,
+            deadline,
+            expired,
+        }
+    }
+
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle(self.commands.clone())
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.deadline
+            .lock()
+            .unwrap()
+            .saturating_duration_since(Instant::now())
+    }
+
+    /// Whether the session is over, this is set before the client is told so and disconnected.
+    pub fn is_expired(&self) -> bool {
+        self.expired.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extend_saturates() {
+        let (sender, events) = mpsc::channel();
+        let timer = SessionTimer::start(Duration::from_secs(3600), move |event| {
+            let _ = sender.send(event);
+        });
+        assert!(timer.handle().send(SessionCommand::Extend(Duration::MAX)));
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            SessionEvent::Remaining(remaining) => assert!(remaining <= MAX_REMAINING),
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        assert!(timer.remaining() > Duration::from_secs(3600));
+        assert!(!timer.is_expired());
+    }
+}
@@ -0,0 +1,224 @@
+//! Handing the newest value, for example the last frame captured, from one thread to another
+//! without either of them waiting for the other, see [`triple_buffer`].
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+
+// set in `middle` if the buffer there has been published and not been taken by the consumer yet
+const FRESH: u8 = 0b100;
+
+struct Shared<T> {
+    buffers: [UnsafeCell<T>; 3],
+    // index of the buffer owned by neither producer nor consumer, plus FRESH
+    middle: AtomicU8,
+}
+
+// Each buffer is only ever accessed by the side whose index points to it, indices are handed over
+// by swapping them with `middle`, which also orders the accesses to the buffers.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+/// Side of a triple buffer writing values, see [`triple_buffer`].
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+    back: u8,
+}
+
+/// Side of a triple buffer reading values, see [`triple_buffer`].
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+    front: u8,
+}
+
+/// Create a triple buffer, all three buffers start out as clones of `initial`.
+///
+/// The producer writes into the back buffer and publishes it, which swaps it with the buffer in
+/// the middle. The consumer swaps the front buffer with the one in the middle once something has
+/// been published, so it always reads the newest complete value. Neither side ever blocks or takes
+/// a lock, a value that has been published but not been read before the next one is published is
+/// overwritten. Buffers are reused rather than reallocated, so writing a frame of the same size as
+/// the one before does not allocate.
+pub fn triple_buffer<T: Clone>(initial: T) -> (Producer<T>, Consumer<T>) {
+    let shared = Arc::new(Shared {
+        buffers: [
+            UnsafeCell::new(initial.clone()),
+            UnsafeCell::new(initial.clone()),
+            UnsafeCell::new(initial),
+        ],
+        middle: AtomicU8::new(1),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+            back: 0,
+        },
+        Consumer { shared, front: 2 },
+    )
+}
+
+impl<T> Producer<T> {
+    /// The buffer the next value is written to, it holds whatever has been written to it the last
+    /// time it has been the back buffer.
+    pub fn back(&mut self) -> &mut T {
+        unsafe { &mut *self.shared.buffers[self.back as usize].get() }
+    }
+
+    /// Hand the back buffer over to the consumer. Returns whether the value published before has
+    /// been overwritten without the consumer reading it, for example to count dropped frames.
+    pub fn publish(&mut self) -> bool {
+        let old = self.shared.middle.swap(self.back | FRESH, Ordering::AcqRel);
+        self.back = old & !FRESH;
+        old & FRESH != 0
+    }
+
+    /// Write `value` to the back buffer and publish it, see [`Producer::publish`].
+    pub fn write(&mut self, value: T) -> bool {
+        *self.back() = value;
+        self.publish()
+    }
+
+    /// Whether the consumer has been dropped, so nothing published is going to be read anymore.
+    pub fn is_abandoned(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Take the newest published value as front buffer if there is one that has not been taken
+    /// yet, returns whether the front buffer changed.
+    pub fn update(&mut self) -> bool {
+        if self.shared.middle.load(Ordering::Acquire) & FRESH == 0 {
+            return false;
+        }
+        // the producer may have published again in the meantime, the swap takes whatever is newest
+        let old = self.shared.middle.swap(self.front, Ordering::AcqRel);
+        self.front = old & !FRESH;
+        true
+    }
+
+    /// The newest value taken via [`Consumer::update`], or the initial one if nothing has been
+    /// taken yet.
+    pub fn front(&self) -> &T {
+        unsafe { &*self.shared.buffers[self.front as usize].get() }
+    }
+
+    /// Like [`Consumer::front`], for example to take out a value instead of cloning it.
+    pub fn front_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.shared.buffers[self.front as usize].get() }
+    }
+
+    /// The newest value if one has been published since the last call, see [`Consumer::update`].
+    pub fn read(&mut self) -> Option<&T> {
+        if self.update() {
+            Some(self.front())
+        } else {
+            None
+        }
+    }
+
+    /// Whether the producer has been dropped, so nothing is going to be published anymore.
+    pub fn is_abandoned(&self) -> bool {
+        Arc::strong_count(&self.shared) == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+
+    use super::*;
+
+    const VALUES: u64 = 2000;
+
+    // Publishes the values 1 to VALUES, each filling a whole buffer, and reads them on the current
+    // thread. Every value has to be read in one piece and in order, and each one is either read or
+    // reported as overwritten.
+    fn hand_over(producer_delay: Duration, consumer_delay: Duration) -> (u64, u64) {
+        let (mut producer, mut consumer) = triple_buffer(vec![0u64; 4096]);
+        let producer = spawn(move || {
+            let mut overwritten = 0;
+            for value in 1..=VALUES {
+                producer.back().iter_mut().for_each(|v| *v = value);
+                if producer.publish() {
+                    overwritten += 1;
+                }
+                sleep(producer_delay);
+            }
+            overwritten
+        });
+        let mut read = 0;
+        let mut last = 0;
+        let mut check = |values: &Vec<u64>| {
+            let value = values[0];
+            assert!(values.iter().all(|v| *v == value), "torn value");
+            assert!(value > last, "{} read after {}", value, last);
+            last = value;
+            read += 1;
+        };
+        while !consumer.is_abandoned() {
+            if let Some(values) = consumer.read() {
+                check(values);
+            }
+            sleep(consumer_delay);
+        }
+        let overwritten = producer.join().unwrap();
+        if let Some(values) = consumer.read() {
+            check(values);
+        }
+        assert_eq!(last, VALUES);
+        assert_eq!(read + overwritten, VALUES);
+        (read, overwritten)
+    }
+
+    #[test]
+    fn producer_faster() {
+        let (read, overwritten) = hand_over(Duration::ZERO, Duration::from_micros(200));
+        assert!(overwritten > 0);
+        assert!(read > 0);
+    }
+
+    #[test]
+    fn consumer_faster() {
+        hand_over(Duration::from_micros(200), Duration::ZERO);
+    }
+
+    #[test]
+    fn same_rate() {
+        hand_over(Duration::from_micros(50), Duration::from_micros(50));
+    }
+
+    #[test]
+    fn newest_value() {
+        let (mut producer, mut consumer) = triple_buffer(0);
+        assert_eq!(consumer.read(), None);
+        assert_eq!(*consumer.front(), 0);
+        assert!(!producer.write(1));
+        assert!(producer.write(2));
+        assert_eq!(consumer.read(), Some(&2));
+        assert_eq!(consumer.read(), None);
+        assert_eq!(*consumer.front(), 2);
+        assert!(!producer.write(3));
+        assert!(!producer.is_abandoned());
+        drop(consumer);
+        assert!(producer.is_abandoned());
+    }
+
+    #[test]
+    fn buffers_are_reused() {
+        let (mut producer, mut consumer) = triple_buffer(vec![0; 16]);
+        let mut seen = Vec::new();
+        for value in 0..10 {
+            let back = producer.back();
+            if !seen.contains(&back.as_ptr()) {
+                seen.push(back.as_ptr());
+            }
+            back[0] = value;
+            producer.publish();
+            if value % 3 == 0 {
+                consumer.update();
+            }
+        }
+        assert_eq!(seen.len(), 3);
+    }
+}
@@ -1,9 +1,17 @@
-use std::os::raw::{c_int, c_uchar, c_void};
-use std::time::Instant;
+//! Encoding captured frames as video.
 
-use tracing::warn;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_float, c_int, c_uchar, c_void};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::cerror::CError;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, debug_span, field, warn};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::cerror::{CError, CErrorCode};
+use crate::metrics::METRICS;
 
 extern "C" {
     fn init_video_encoder(
@@ -16,62 +24,2293 @@ extern "C" {
         try_nvenc: c_int,
         try_videotoolbox: c_int,
         try_mediafoundation: c_int,
+        conversion_threads: c_int,
+        color_matrix: c_int,
+        color_range: c_int,
+        chroma_filter: c_int,
+        yuv444: c_int,
+        grayscale: c_int,
+        codec: c_int,
+        max_bitrate: c_int,
+        constant_quality: c_int,
+        qp_min: c_int,
+        qp_max: c_int,
+        keyframe_interval: c_int,
+        encoder_threads: c_int,
+        low_latency: c_int,
     ) -> *mut c_void;
     fn open_video(handle: *mut c_void, err: *mut CError);
     fn destroy_video_encoder(handle: *mut c_void);
-    fn encode_video_frame(handle: *mut c_void, micros: c_int, err: *mut CError);
+    fn encode_video_frame(handle: *mut c_void, millis: c_int, err: *mut CError);
+    fn request_keyframe(handle: *mut c_void);
+    fn fragment_is_keyframe(handle: *mut c_void) -> c_int;
+    fn set_max_bitrate(handle: *mut c_void, max_bitrate: c_int);
+    fn set_quality(handle: *mut c_void, quality: c_float);
+    fn video_encoder_available(name: *const c_char) -> c_int;
+    fn video_encoder_name(handle: *mut c_void) -> *const c_char;
+
+    fn fill_rgb(ctx: *mut c_void, data: *const u8, err: *mut CError);
+    fn fill_rgb0(ctx: *mut c_void, data: *const u8, err: *mut CError);
+    fn fill_bgr0(ctx: *mut c_void, data: *const u8, stride: c_int, err: *mut CError);
+    fn frame_planes(
+        ctx: *mut c_void,
+        data: *mut *mut u8,
+        linesize: *mut c_int,
+        err: *mut CError,
+    ) -> c_int;
+    fn fill_converted(ctx: *mut c_void, err: *mut CError);
+}
+
+// this is used as callback in lib/encode_video.c via ffmpegs AVIOContext
+#[no_mangle]
+fn write_video_packet(video_encoder: *mut c_void, buf: *const c_uchar, buf_size: c_int) -> c_int {
+    let video_encoder = unsafe { (video_encoder as *mut VideoEncoder).as_mut().unwrap() };
+    let chunk = match video_encoder.chunk {
+        VideoChunk::Delta(pts) if unsafe { fragment_is_keyframe(video_encoder.handle) } != 0 => {
+            VideoChunk::Keyframe(pts)
+        }
+        chunk => chunk,
+    };
+    let start = Instant::now();
+    (video_encoder.write_data)(
+        unsafe { std::slice::from_raw_parts(buf as *const u8, buf_size as usize) },
+        chunk,
+    );
+    let elapsed = start.elapsed();
+    video_encoder.write_time += elapsed;
+    METRICS.send.record(elapsed);
+    0
+}
+
+/// Pixel data of a captured frame, the variant gives the pixel format.
+pub enum PixelProvider<'a> {
+    // 8 bits per color
+    RGB(usize, usize, &'a [u8]),
+    RGB0(usize, usize, &'a [u8]),
+    BGR0(usize, usize, &'a [u8]),
+    // width, height, stride
+    BGR0S(usize, usize, usize, &'a [u8]),
+}
+
+impl<'a> PixelProvider<'a> {
+    pub fn size(&self) -> (usize, usize) {
+        match self {
+            PixelProvider::RGB(w, h, _) => (*w, *h),
+            PixelProvider::RGB0(w, h, _) => (*w, *h),
+            PixelProvider::BGR0(w, h, _) => (*w, *h),
+            PixelProvider::BGR0S(w, h, _, _) => (*w, *h),
+        }
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        match self {
+            PixelProvider::RGB(_, _, data) => data,
+            PixelProvider::RGB0(_, _, data) => data,
+            PixelProvider::BGR0(_, _, data) => data,
+            PixelProvider::BGR0S(_, _, _, data) => data,
+        }
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelProvider::RGB(..) => 3,
+            _ => 4,
+        }
+    }
+
+    /// Bytes from the start of one row to the start of the next.
+    pub fn stride(&self) -> usize {
+        match self {
+            PixelProvider::BGR0S(_, _, stride, _) => *stride,
+            _ => self.size().0 * self.bytes_per_pixel(),
+        }
+    }
+
+    /// Checksum of the frame that allows telling frames apart without comparing them pixel by
+    /// pixel, for example to check the exact frame a test source delivered.
+    ///
+    /// This is the 64 bit XXH3 hash with seed 0 of width and height, each as little endian u64,
+    /// followed by the pixel data row by row without padding. The algorithm is fixed, so the same
+    /// frame always has the same checksum, across runs and machines. Only the bytes are hashed, not
+    /// the pixel format, so the same image in RGB and BGR0 has different checksums while BGR0 and
+    /// BGR0S frames showing the same image have the same one.
+    pub fn checksum(&self) -> u64 {
+        let (width, height) = self.size();
+        let mut hasher = Xxh3::new();
+        hasher.update(&(width as u64).to_le_bytes());
+        hasher.update(&(height as u64).to_le_bytes());
+        // padding at the end of rows may contain anything
+        let row_len = width * self.bytes_per_pixel();
+        for row in self.data().chunks(self.stride().max(1)).take(height) {
+            hasher.update(&row[..row_len.min(row.len())]);
+        }
+        hasher.digest()
+    }
+
+    // make sure the data covers all pixels, the last row does not need to be padded
+    fn check_data_len(&self) -> Result<(), CError> {
+        let (width, height) = self.size();
+        let (bpp, stride) = (self.bytes_per_pixel(), self.stride());
+        let len = match height {
+            0 => 0,
+            h => stride * (h - 1) + width * bpp,
+        };
+        if stride < width * bpp || self.data().len() < len {
+            return Err(CError::with_message(
+                CErrorCode::GenericError,
+                &format!(
+                    "Frame data of {} bytes is too small for {}x{} pixels with a stride of {} \
+                    bytes.",
+                    self.data().len(),
+                    width,
+                    height,
+                    stride
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum PixelFormat {
+    Rgb,
+    Rgb0,
+    Bgr0,
+    Bgr0S(usize),
+}
+
+/// Copy of a captured frame that outlives the buffer of the recorder, for example to encode it on
+/// another thread while the next frame is captured.
+#[derive(Clone)]
+pub struct OwnedPixels {
+    format: PixelFormat,
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+impl OwnedPixels {
+    /// Copy the pixels of `pixel_provider` into `data`, reusing its allocation.
+    pub fn copy_from(pixel_provider: &PixelProvider, mut data: Vec<u8>) -> Self {
+        let (width, height) = pixel_provider.size();
+        let format = match pixel_provider {
+            PixelProvider::RGB(..) => PixelFormat::Rgb,
+            PixelProvider::RGB0(..) => PixelFormat::Rgb0,
+            PixelProvider::BGR0(..) => PixelFormat::Bgr0,
+            PixelProvider::BGR0S(_, _, stride, _) => PixelFormat::Bgr0S(*stride),
+        };
+        data.clear();
+        data.extend_from_slice(pixel_provider.data());
+        Self {
+            format,
+            width,
+            height,
+            data,
+        }
+    }
+
+    pub fn pixels(&self) -> PixelProvider {
+        let (w, h, data) = (self.width, self.height, self.data.as_slice());
+        match self.format {
+            PixelFormat::Rgb => PixelProvider::RGB(w, h, data),
+            PixelFormat::Rgb0 => PixelProvider::RGB0(w, h, data),
+            PixelFormat::Bgr0 => PixelProvider::BGR0(w, h, data),
+            PixelFormat::Bgr0S(stride) => PixelProvider::BGR0S(w, h, stride, data),
+        }
+    }
+
+    /// Give back the buffer so it can be reused for the next copy.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Clockwise rotation applied to captured frames, for example to show a landscape screen upright
+/// on a tablet held in portrait orientation.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    #[serde(rename = "0")]
+    Deg0,
+    #[serde(rename = "90")]
+    Deg90,
+    #[serde(rename = "180")]
+    Deg180,
+    #[serde(rename = "270")]
+    Deg270,
+}
+
+impl Rotation {
+    /// Size of a frame of `width`x`height` pixels after rotating it. As width and height are just
+    /// swapped this gives the size before rotating as well.
+    pub fn size(self, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Rotation::Deg0 | Rotation::Deg180 => (width, height),
+            Rotation::Deg90 | Rotation::Deg270 => (height, width),
+        }
+    }
+
+    /// Position of the pixel at `x`, `y` of a `width`x`height` image after rotating it.
+    pub fn rotate_pixel(self, x: i32, y: i32, width: i32, height: i32) -> (i32, i32) {
+        match self {
+            Rotation::Deg0 => (x, y),
+            Rotation::Deg90 => (height - 1 - y, x),
+            Rotation::Deg180 => (width - 1 - x, height - 1 - y),
+            Rotation::Deg270 => (y, width - 1 - x),
+        }
+    }
+
+    /// Undo the rotation of a vector, for example the tilt of a pen.
+    pub fn unrotate_vector(self, dx: f64, dy: f64) -> (f64, f64) {
+        match self {
+            Rotation::Deg0 => (dx, dy),
+            Rotation::Deg90 => (dy, -dx),
+            Rotation::Deg180 => (-dx, -dy),
+            Rotation::Deg270 => (-dy, dx),
+        }
+    }
+}
+
+/// Rotation and mirroring applied to captured frames, frames are rotated first and flipped
+/// afterwards, so flipping horizontally always mirrors left and right of the video as shown by the
+/// client.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTransform {
+    pub rotation: Rotation,
+    pub hflip: bool,
+    pub vflip: bool,
+}
+
+impl FrameTransform {
+    pub fn is_identity(self) -> bool {
+        self == Self::default()
+    }
+
+    /// Size of a frame of `width`x`height` pixels after transforming it, this is the size before
+    /// transforming it as well.
+    pub fn size(self, width: usize, height: usize) -> (usize, usize) {
+        self.rotation.size(width, height)
+    }
+
+    /// Transform the frame, the resulting pixels are written to `buf`. The pixel format is kept
+    /// but padding at the end of rows is removed.
+    pub fn apply<'a>(
+        self,
+        pixel_provider: PixelProvider<'a>,
+        buf: &'a mut Vec<u8>,
+    ) -> PixelProvider<'a> {
+        if self.is_identity() {
+            return pixel_provider;
+        }
+        let (width, height) = pixel_provider.size();
+        let (bpp, stride) = (pixel_provider.bytes_per_pixel(), pixel_provider.stride());
+        let data = pixel_provider.data();
+        let (width_out, height_out) = self.size(width, height);
+        buf.clear();
+        buf.reserve(width_out * height_out * bpp);
+        for y_out in 0..height_out {
+            // position on the rotated frame before flipping it
+            let y_rot = if self.vflip {
+                height_out - 1 - y_out
+            } else {
+                y_out
+            };
+            for x_out in 0..width_out {
+                let x_rot = if self.hflip {
+                    width_out - 1 - x_out
+                } else {
+                    x_out
+                };
+                let (x, y) = match self.rotation {
+                    Rotation::Deg0 => (x_rot, y_rot),
+                    Rotation::Deg90 => (y_rot, height - 1 - x_rot),
+                    Rotation::Deg180 => (width - 1 - x_rot, height - 1 - y_rot),
+                    Rotation::Deg270 => (width - 1 - y_rot, x_rot),
+                };
+                let i = y * stride + x * bpp;
+                buf.extend_from_slice(&data[i..i + bpp]);
+            }
+        }
+        let buf: &'a Vec<u8> = buf;
+        match pixel_provider {
+            PixelProvider::RGB(..) => PixelProvider::RGB(width_out, height_out, buf),
+            PixelProvider::RGB0(..) => PixelProvider::RGB0(width_out, height_out, buf),
+            PixelProvider::BGR0(..) | PixelProvider::BGR0S(..) => {
+                PixelProvider::BGR0(width_out, height_out, buf)
+            }
+        }
+    }
+
+    /// Position of the pixel at `x`, `y` of a `width`x`height` image after transforming it.
+    pub fn transform_pixel(self, x: i32, y: i32, width: i32, height: i32) -> (i32, i32) {
+        let (x, y) = self.rotation.rotate_pixel(x, y, width, height);
+        let (width, height) = self.size(width as usize, height as usize);
+        (
+            if self.hflip { width as i32 - 1 - x } else { x },
+            if self.vflip { height as i32 - 1 - y } else { y },
+        )
+    }
+
+    /// Undo the transformation of a vector, for example the tilt of a pen.
+    pub fn untransform_vector(self, dx: f64, dy: f64) -> (f64, f64) {
+        let dx = if self.hflip { -dx } else { dx };
+        let dy = if self.vflip { -dy } else { dy };
+        self.rotation.unrotate_vector(dx, dy)
+    }
+
+    /// Map a point on the transformed frame, given relative to its size with 0.0 being left/top
+    /// and 1.0 right/bottom, to the same point on the frame as captured.
+    pub fn untransform_point(self, x: f64, y: f64) -> (f64, f64) {
+        let (dx, dy) = self.untransform_vector(x - 0.5, y - 0.5);
+        (dx + 0.5, dy + 0.5)
+    }
+}
+
+/// Result of [`ChangeDetector::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameChange {
+    Changed,
+    Unchanged,
+    /// Unchanged, but enough unchanged frames have been skipped that this one should be sent
+    /// anyway.
+    Refresh,
+}
+
+/// Detects frames that are identical to the previous one, so converting and encoding them can be
+/// skipped. Only the checksum of the last frame is kept, which is cheap to compute compared to
+/// converting a frame.
+pub struct ChangeDetector {
+    last_hash: Option<u64>,
+    unchanged: u32,
+    refresh_interval: Option<u32>,
+}
+
+impl ChangeDetector {
+    /// After `refresh_interval` unchanged frames in a row the next one is reported as
+    /// [`FrameChange::Refresh`].
+    pub fn new(refresh_interval: Option<u32>) -> Self {
+        Self {
+            last_hash: None,
+            unchanged: 0,
+            refresh_interval: refresh_interval.filter(|n| *n > 0),
+        }
+    }
+
+    /// Compare the frame to the one checked last.
+    pub fn check(&mut self, pixel_provider: &PixelProvider) -> FrameChange {
+        let hash = pixel_provider.checksum();
+        if self.last_hash.replace(hash) != Some(hash) {
+            self.unchanged = 0;
+            return FrameChange::Changed;
+        }
+        self.unchanged += 1;
+        match self.refresh_interval {
+            Some(n) if self.unchanged > n => {
+                self.unchanged = 0;
+                FrameChange::Refresh
+            }
+            _ => FrameChange::Unchanged,
+        }
+    }
+
+    /// Forget the last frame, the next one is reported as changed.
+    pub fn reset(&mut self) {
+        self.last_hash = None;
+        self.unchanged = 0;
+    }
+}
+
+/// Detects a recorder that keeps returning the same image without failing, which happens for
+/// example if the driver stops updating the buffers captured from. The screen may just as well
+/// not have changed, so restarting the recorder once it is reported as stalled should be cheap.
+pub struct StallWatchdog {
+    timeout: Duration,
+    last_hash: Option<u64>,
+    last_change: Instant,
+}
+
+impl StallWatchdog {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_hash: None,
+            last_change: Instant::now(),
+        }
+    }
+
+    /// Return for how long the image has not changed if that exceeds the timeout, the watchdog
+    /// starts over in that case.
+    pub fn check(&mut self, pixel_provider: &PixelProvider) -> Option<Duration> {
+        let hash = pixel_provider.checksum();
+        if self.last_hash.replace(hash) != Some(hash) {
+            self.last_change = Instant::now();
+            return None;
+        }
+        let stalled_for = self.last_change.elapsed();
+        if stalled_for < self.timeout {
+            return None;
+        }
+        self.reset();
+        Some(stalled_for)
+    }
+
+    /// Start over, for example after restarting the recorder or while no frames are captured.
+    pub fn reset(&mut self) {
+        self.last_hash = None;
+        self.last_change = Instant::now();
+    }
+}
+
+/// Holds back the frames a recorder delivers right after it started until they show something, as
+/// some recorders return black or empty images at first, which clients would show until the next
+/// frame. A frame shows something once it is not all black or differs from the first frame held
+/// back, after the timeout frames are delivered in any case, so a black screen is shown as well.
+pub struct ContentWaiter {
+    timeout: Duration,
+    // none once content arrived or the timeout passed
+    started_at: Option<Instant>,
+    first_hash: Option<u64>,
+}
+
+impl ContentWaiter {
+    /// The waiter starts waiting right away, as a recorder is started along with it.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            started_at: Some(Instant::now()),
+            first_hash: None,
+        }
+    }
+
+    /// Wait for content again, for example after the recorder has been restarted.
+    pub fn start(&mut self) {
+        self.started_at = Some(Instant::now());
+        self.first_hash = None;
+    }
+
+    /// Whether the frame should be delivered.
+    pub fn check(&mut self, pixel_provider: &PixelProvider) -> bool {
+        let started_at = match self.started_at {
+            Some(started_at) => started_at,
+            None => return true,
+        };
+        let hash = pixel_provider.checksum();
+        let first_hash = *self.first_hash.get_or_insert(hash);
+        if !is_blank(pixel_provider) || hash != first_hash {
+            debug!(
+                "Captured content after {:.0}ms.",
+                started_at.elapsed().as_secs_f64() * 1000.0
+            );
+        } else if started_at.elapsed() >= self.timeout {
+            debug!(
+                "Captured nothing but blank frames for {:.0}ms, delivering them anyway.",
+                self.timeout.as_secs_f64() * 1000.0
+            );
+        } else {
+            return false;
+        }
+        self.started_at = None;
+        true
+    }
+}
+
+// the frame is empty or all of its pixels are black, padding at the end of rows and the fourth byte
+// of pixels with four bytes are left out as they may contain anything
+fn is_blank(pixel_provider: &PixelProvider) -> bool {
+    let (width, height) = pixel_provider.size();
+    let bpp = pixel_provider.bytes_per_pixel();
+    pixel_provider
+        .data()
+        .chunks(pixel_provider.stride().max(1))
+        .take(height)
+        .all(|row| {
+            row[..(width * bpp).min(row.len())]
+                .chunks_exact(bpp)
+                .all(|pixel| pixel[..3].iter().all(|v| *v == 0))
+        })
+}
+
+// frames are split into this many tiles horizontally and vertically to detect scene changes
+const SCENE_CHANGE_TILES: usize = 16;
+
+/// Detects scene changes, that is frames that differ from the previous one in most of their area,
+/// for example after switching virtual desktops or opening a fullscreen application. Sending those
+/// as keyframes shows the new content in full quality right away instead of refining it over many
+/// frames. Only a checksum per tile of the last frame is kept.
+pub struct SceneChangeDetector {
+    threshold: f64,
+    cooldown: Duration,
+    size: (usize, usize),
+    tiles: Vec<u64>,
+    last_change: Option<Instant>,
+}
+
+impl SceneChangeDetector {
+    /// A scene changed if at least the fraction `threshold` of the frame changed, within
+    /// `cooldown` after one further changes are not reported.
+    pub fn new(threshold: f64, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            size: (0, 0),
+            tiles: Vec::new(),
+            last_change: None,
+        }
+    }
+
+    /// Compare the frame to the one checked last. A frame of another size is no scene change as
+    /// the encoder starts over with a keyframe anyway.
+    pub fn check(&mut self, pixel_provider: &PixelProvider) -> bool {
+        let size = pixel_provider.size();
+        let previous = std::mem::replace(&mut self.tiles, tile_checksums(pixel_provider));
+        if std::mem::replace(&mut self.size, size) != size || previous.is_empty() {
+            return false;
+        }
+        let changed = previous
+            .iter()
+            .zip(&self.tiles)
+            .filter(|(a, b)| a != b)
+            .count();
+        if (changed as f64) < self.threshold * self.tiles.len() as f64
+            || self
+                .last_change
+                .map_or(false, |last_change| last_change.elapsed() < self.cooldown)
+        {
+            return false;
+        }
+        self.last_change = Some(Instant::now());
+        true
+    }
+
+    /// Forget the last frame, for example after restarting the recorder.
+    pub fn reset(&mut self) {
+        self.tiles.clear();
+    }
+}
+
+// checksums of the tiles of the frame row by row, fewer tiles than SCENE_CHANGE_TILES per row or
+// column if the frame is tiny
+fn tile_checksums(pixel_provider: &PixelProvider) -> Vec<u64> {
+    let (width, height) = pixel_provider.size();
+    let (columns, rows) = (
+        SCENE_CHANGE_TILES.min(width),
+        SCENE_CHANGE_TILES.min(height),
+    );
+    let bpp = pixel_provider.bytes_per_pixel();
+    let stride = pixel_provider.stride().max(1);
+    let data = pixel_provider.data();
+    let mut tiles = Vec::with_capacity(columns * rows);
+    for tile_y in 0..rows {
+        let mut hashers: Vec<Xxh3> = (0..columns).map(|_| Xxh3::new()).collect();
+        for y in tile_y * height / rows..(tile_y + 1) * height / rows {
+            let row = match data.get(y * stride..) {
+                Some(row) => row,
+                None => break,
+            };
+            for (tile_x, hasher) in hashers.iter_mut().enumerate() {
+                let start = tile_x * width / columns * bpp;
+                let end = ((tile_x + 1) * width / columns * bpp).min(row.len());
+                hasher.update(&row[start.min(end)..end]);
+            }
+        }
+        tiles.extend(hashers.iter().map(|hasher| hasher.digest()));
+    }
+    tiles
+}
+
+/// Reduces the number of bits per color channel of frames before encoding them. Subtle gradients,
+/// for example from shadows and blur drawn by the compositor, become flat areas, which compress a
+/// lot better at the cost of some banding.
+#[derive(Clone)]
+pub struct Quantizer {
+    // quantized value of every possible channel value
+    lut: [u8; 256],
+}
+
+impl Quantizer {
+    /// Keep `bits` bits per channel, from 1 to 8. Values are rounded to the nearest of the levels
+    /// left, which are spread across the whole range so black and white are kept.
+    pub fn new(bits: u8) -> Self {
+        let levels = (1u32 << bits.clamp(1, 8)) - 1;
+        let mut lut = [0; 256];
+        for (v, q) in lut.iter_mut().enumerate() {
+            let level = (v as u32 * levels + 127) / 255;
+            *q = ((level * 255 + levels / 2) / levels) as u8;
+        }
+        Self { lut }
+    }
+
+    /// Quantize the frame, the resulting pixels are written to `buf`. Pixel format and padding are
+    /// kept.
+    pub fn apply<'a>(
+        &self,
+        pixel_provider: PixelProvider<'a>,
+        buf: &'a mut Vec<u8>,
+    ) -> PixelProvider<'a> {
+        buf.clear();
+        buf.extend(pixel_provider.data().iter().map(|v| self.lut[*v as usize]));
+        let buf: &'a Vec<u8> = buf;
+        match pixel_provider {
+            PixelProvider::RGB(w, h, _) => PixelProvider::RGB(w, h, buf),
+            PixelProvider::RGB0(w, h, _) => PixelProvider::RGB0(w, h, buf),
+            PixelProvider::BGR0(w, h, _) => PixelProvider::BGR0(w, h, buf),
+            PixelProvider::BGR0S(w, h, stride, _) => PixelProvider::BGR0S(w, h, stride, buf),
+        }
+    }
+}
+
+/// Reduces frames to 16 bit colors like the low color mode of RDP, 5 bits for red and blue and 6
+/// bits for green. Contrary to [`Quantizer`] the palette differs per channel, so grays, which
+/// would be tinted green by the finer steps of green, are reduced to 5 bits in all channels
+/// instead and stay neutral.
+#[derive(Clone)]
+pub struct ColorDepthReducer {
+    lut5: [u8; 256],
+    lut6: [u8; 256],
+}
+
+impl Default for ColorDepthReducer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ColorDepthReducer {
+    pub fn new() -> Self {
+        Self {
+            lut5: Quantizer::new(5).lut,
+            lut6: Quantizer::new(6).lut,
+        }
+    }
+
+    // reduce a pixel given by its channels, red and blue may be swapped
+    fn reduce(&self, c0: u8, g: u8, c2: u8) -> [u8; 3] {
+        if c0 == g && g == c2 {
+            let v = self.lut5[g as usize];
+            return [v, v, v];
+        }
+        [
+            self.lut5[c0 as usize],
+            self.lut6[g as usize],
+            self.lut5[c2 as usize],
+        ]
+    }
+
+    /// Reduce the colors of the frame, the resulting pixels are written to `buf`. Pixel format and
+    /// padding are kept.
+    pub fn apply<'a>(
+        &self,
+        pixel_provider: PixelProvider<'a>,
+        buf: &'a mut Vec<u8>,
+    ) -> PixelProvider<'a> {
+        let bpp = pixel_provider.bytes_per_pixel();
+        buf.clear();
+        buf.extend_from_slice(pixel_provider.data());
+        // green is the second byte in all formats, padding at the end of rows is left alone
+        let (width, _) = pixel_provider.size();
+        for row in buf.chunks_mut(pixel_provider.stride().max(1)) {
+            let row_len = (width * bpp).min(row.len());
+            for p in row[..row_len].chunks_exact_mut(bpp) {
+                let [c0, g, c2] = self.reduce(p[0], p[1], p[2]);
+                p[0] = c0;
+                p[1] = g;
+                p[2] = c2;
+            }
+        }
+        let buf: &'a Vec<u8> = buf;
+        match pixel_provider {
+            PixelProvider::RGB(w, h, _) => PixelProvider::RGB(w, h, buf),
+            PixelProvider::RGB0(w, h, _) => PixelProvider::RGB0(w, h, buf),
+            PixelProvider::BGR0(w, h, _) => PixelProvider::BGR0(w, h, buf),
+            PixelProvider::BGR0S(w, h, stride, _) => PixelProvider::BGR0S(w, h, stride, buf),
+        }
+    }
+}
+
+/// Order of the channels of the pixels of a [`Frame`], `0` is a padding byte, which is usually 255
+/// but should not be relied on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    Rgb,
+    Rgb0,
+    Bgr0,
+}
+
+/// Frame handed to a [`FrameProcessor`], `width`x`height` pixels with rows `stride` bytes apart.
+/// Rows may be padded, the padding is not shown.
+pub struct Frame<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub stride: usize,
+    pub format: FrameFormat,
+    pub data: &'a mut [u8],
+}
+
+/// Modifies frames before they are encoded, for example to add a watermark or to blur sensitive
+/// parts of the screen, see
+/// [`WeylusServerBuilder::frame_processor`](crate::WeylusServerBuilder::frame_processor). Frames
+/// are processed after rotating, cropping and adding bars, so they have the size of the video.
+/// Frames that did not change are processed as well, as processors may add changing content like
+/// a clock.
+///
+/// `process` runs on the video thread of every client for every frame captured, which waits for it
+/// before encoding, so it has to be fast: a couple of milliseconds at most to keep up with 60 FPS.
+/// Each frame is copied for processing, without a processor there is no overhead at all.
+pub trait FrameProcessor: Send + Sync {
+    fn process(&self, frame: Frame<'_>);
+}
+
+impl<F: Fn(Frame<'_>) + Send + Sync> FrameProcessor for F {
+    fn process(&self, frame: Frame<'_>) {
+        self(frame)
+    }
+}
+
+/// Copy the frame to `buf` and let `processor` modify it there.
+pub fn process_frame<'a>(
+    processor: &dyn FrameProcessor,
+    pixel_provider: PixelProvider<'a>,
+    buf: &'a mut Vec<u8>,
+) -> PixelProvider<'a> {
+    buf.clear();
+    buf.extend_from_slice(pixel_provider.data());
+    let (width, height) = pixel_provider.size();
+    let format = match pixel_provider {
+        PixelProvider::RGB(..) => FrameFormat::Rgb,
+        PixelProvider::RGB0(..) => FrameFormat::Rgb0,
+        PixelProvider::BGR0(..) | PixelProvider::BGR0S(..) => FrameFormat::Bgr0,
+    };
+    processor.process(Frame {
+        width,
+        height,
+        stride: pixel_provider.stride(),
+        format,
+        data: buf,
+    });
+    let buf: &'a Vec<u8> = buf;
+    match pixel_provider {
+        PixelProvider::RGB(w, h, _) => PixelProvider::RGB(w, h, buf),
+        PixelProvider::RGB0(w, h, _) => PixelProvider::RGB0(w, h, buf),
+        PixelProvider::BGR0(w, h, _) => PixelProvider::BGR0(w, h, buf),
+        PixelProvider::BGR0S(w, h, stride, _) => PixelProvider::BGR0S(w, h, stride, buf),
+    }
+}
+
+/// Layout of the planes of a [`YuvFrame`], which depends on the encoder.
+// keep in sync with enum YuvFormat in lib/encode_video.c
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum YuvFormat {
+    /// Y, U and V planes, U and V have half the width and height of Y.
+    Yuv420p = 0,
+    /// Y, U and V planes of the same size, see [`EncoderOptions::yuv444`].
+    Yuv444p = 1,
+    /// Y plane and a plane of interleaved U and V with half the width and height, used by VAAPI.
+    /// The third plane is empty.
+    Nv12 = 2,
+}
+
+impl YuvFormat {
+    fn from_c(format: c_int) -> Option<Self> {
+        match format {
+            0 => Some(Self::Yuv420p),
+            1 => Some(Self::Yuv444p),
+            2 => Some(Self::Nv12),
+            _ => None,
+        }
+    }
+}
+
+/// Frame a [`YuvConverter`] converts into, `width`x`height` pixels, which is the size of the
+/// video. Rows of each plane are `linesizes` bytes apart, the colors have to be converted with
+/// `color_matrix` and `color_range` as that is what clients decode them with.
+pub struct YuvFrame<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub format: YuvFormat,
+    pub color_matrix: ColorMatrix,
+    pub color_range: ColorRange,
+    pub planes: [&'a mut [u8]; 3],
+    pub linesizes: [usize; 3],
+}
+
+/// Replaces the conversion of captured frames to YUV via swscale, for example with a faster one
+/// using SIMD or the GPU, see
+/// [`WeylusServerBuilder::yuv_converter`](crate::WeylusServerBuilder::yuv_converter).
+///
+/// `convert` runs on the video thread of every client for every frame encoded. It returns false to
+/// leave the frame to the built-in conversion, for example if `dst` is in a format the converter
+/// does not support or has another size than `src`, as converting then includes scaling. Encoders
+/// that take BGR0 directly, like NVENC, do not convert at all and never call the converter. In
+/// grayscale mode the chroma planes are overwritten afterwards.
+pub trait YuvConverter: Send + Sync {
+    fn convert(&self, src: &PixelProvider<'_>, dst: YuvFrame<'_>) -> bool;
+}
+
+impl<F: Fn(&PixelProvider<'_>, YuvFrame<'_>) -> bool + Send + Sync> YuvConverter for F {
+    fn convert(&self, src: &PixelProvider<'_>, dst: YuvFrame<'_>) -> bool {
+        self(src, dst)
+    }
+}
+
+// `data` has to point to `rows` rows of `linesize` bytes or be null
+unsafe fn plane<'a>(data: *mut u8, linesize: c_int, rows: usize) -> &'a mut [u8] {
+    if data.is_null() {
+        &mut []
+    } else {
+        std::slice::from_raw_parts_mut(data, linesize.max(0) as usize * rows)
+    }
+}
+
+/// Solid color given as `#rrggbb` or `rrggbb`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Self = Self { r: 0, g: 0, b: 0 };
+
+    /// The color as pixel of a frame in BGR0 format, which is what captured frames mostly are.
+    pub fn bgra(&self) -> [u8; 4] {
+        [self.b, self.g, self.r, 255]
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("Invalid color: '{}', expected '#rrggbb'.", s));
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        Ok(Self {
+            r: channel(0),
+            g: channel(2),
+            b: channel(4),
+        })
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Color> for String {
+    fn from(c: Color) -> Self {
+        format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b)
+    }
+}
+
+// Blend the pixels of `src` over the opaque pixels of `dst`, both with 4 bytes per pixel and alpha
+// last. Colors are premultiplied by alpha, as they are on X11 and with PipeWire. The result is
+// opaque again.
+pub(crate) fn blend_over(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+        let transparency = 255 - s[3] as u32;
+        for i in 0..3 {
+            let v = s[i] as u32 + (d[i] as u32 * transparency + 127) / 255;
+            d[i] = v.min(255) as u8;
+        }
+        d[3] = 255;
+    }
+}
+
+/// Flattens the alpha channel of frames onto a background color. Transparent parts of windows,
+/// for example rounded corners or shadows, then show the background instead of what happens to be
+/// in their color channels, which is usually black.
+///
+/// Only frames that actually carry alpha should be flattened: windows without an alpha channel
+/// may leave it zero and would disappear entirely.
+#[derive(Clone)]
+pub struct AlphaFlattener {
+    background: Color,
+}
+
+impl AlphaFlattener {
+    pub fn new(background: Color) -> Self {
+        Self { background }
+    }
+
+    /// Flatten the frame, the resulting pixels are written to `buf`. Pixel format and padding are
+    /// kept, RGB frames have no alpha channel and are returned as they are.
+    pub fn apply<'a>(
+        &self,
+        pixel_provider: PixelProvider<'a>,
+        buf: &'a mut Vec<u8>,
+    ) -> PixelProvider<'a> {
+        let c = self.background;
+        let background = match pixel_provider {
+            PixelProvider::RGB(..) => return pixel_provider,
+            PixelProvider::RGB0(..) => [c.r, c.g, c.b, 255],
+            _ => c.bgra(),
+        };
+        let data = pixel_provider.data();
+        buf.clear();
+        buf.extend(background.iter().cycle().take(data.len()));
+        blend_over(buf, data);
+        let buf: &'a Vec<u8> = buf;
+        match pixel_provider {
+            PixelProvider::RGB(w, h, _) => PixelProvider::RGB(w, h, buf),
+            PixelProvider::RGB0(w, h, _) => PixelProvider::RGB0(w, h, buf),
+            PixelProvider::BGR0(w, h, _) => PixelProvider::BGR0(w, h, buf),
+            PixelProvider::BGR0S(w, h, stride, _) => PixelProvider::BGR0S(w, h, stride, buf),
+        }
+    }
+}
+
+/// Replaces fully transparent pixels with a color and keeps all others. Parts of a composited
+/// desktop nothing has been drawn to, for example if there is no wallpaper, may be transparent
+/// with arbitrary colors, which then show as a clean background.
+///
+/// As with [`AlphaFlattener`], frames without an alpha channel may leave it zero and would be
+/// filled entirely.
+#[derive(Clone)]
+pub struct TransparentFill {
+    color: Color,
+}
+
+impl TransparentFill {
+    pub fn new(color: Color) -> Self {
+        Self { color }
+    }
+
+    /// Fill the frame, the resulting pixels are written to `buf`. Pixel format and padding are
+    /// kept, RGB frames have no alpha channel and are returned as they are.
+    pub fn apply<'a>(
+        &self,
+        pixel_provider: PixelProvider<'a>,
+        buf: &'a mut Vec<u8>,
+    ) -> PixelProvider<'a> {
+        let c = self.color;
+        let color = match pixel_provider {
+            PixelProvider::RGB(..) => return pixel_provider,
+            PixelProvider::RGB0(..) => [c.r, c.g, c.b, 255],
+            _ => c.bgra(),
+        };
+        buf.clear();
+        buf.extend_from_slice(pixel_provider.data());
+        for pixel in buf.chunks_exact_mut(4) {
+            if pixel[3] == 0 {
+                pixel.copy_from_slice(&color);
+            }
+        }
+        let buf: &'a Vec<u8> = buf;
+        match pixel_provider {
+            PixelProvider::RGB(w, h, _) => PixelProvider::RGB(w, h, buf),
+            PixelProvider::RGB0(w, h, _) => PixelProvider::RGB0(w, h, buf),
+            PixelProvider::BGR0(w, h, _) => PixelProvider::BGR0(w, h, buf),
+            PixelProvider::BGR0S(w, h, stride, _) => PixelProvider::BGR0S(w, h, stride, buf),
+        }
+    }
+}
+
+/// How a [`PrivacyRegion`] is hidden.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivacyStyle {
+    /// Paint it black.
+    #[default]
+    Fill,
+    /// Blur it strongly enough that text can not be read anymore.
+    Blur,
+}
+
+/// Part of a capturable that is hidden in every frame sent, for example a password manager or
+/// where notifications show up.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PrivacyRegion {
+    /// x, y, width and height relative to the size of the capturable.
+    pub rect: (f64, f64, f64, f64),
+    #[serde(default)]
+    pub style: PrivacyStyle,
+}
+
+impl PrivacyRegion {
+    // the pixels x0..x1, y0..y1 of a frame of the given size showing the part `visible` of the
+    // capturable that are covered by the region, rounded outwards so nothing of it shows
+    fn to_pixels(
+        &self,
+        (vx, vy, vw, vh): (f64, f64, f64, f64),
+        (width, height): (usize, usize),
+    ) -> Option<(usize, usize, usize, usize)> {
+        let (x, y, w, h) = self.rect;
+        if ![x, y, w, h].iter().all(|v| v.is_finite()) || w <= 0.0 || h <= 0.0 {
+            return None;
+        }
+        let axis = |start: f64, len: f64, visible_start: f64, visible_len: f64, size: usize| {
+            let size = size as f64;
+            let start = (start - visible_start) / visible_len * size;
+            let end = start + len / visible_len * size;
+            (
+                start.floor().clamp(0.0, size) as usize,
+                end.ceil().clamp(0.0, size) as usize,
+            )
+        };
+        let (x0, x1) = axis(x, w, vx, vw, width);
+        let (y0, y1) = axis(y, h, vy, vh, height);
+        Some((x0, y0, x1, y1)).filter(|_| x0 < x1 && y0 < y1)
+    }
+}
+
+// radius of the box blur of privacy regions in pixels, applied three times in either direction
+// which comes close to a gaussian blur
+const PRIVACY_BLUR_RADIUS: usize = 12;
+const PRIVACY_BLUR_PASSES: usize = 3;
+
+/// Hides [`PrivacyRegion`]s of captured frames. This is applied before frames are rotated,
+/// scaled or bars are added, so the regions stay in place whatever the video looks like.
+#[derive(Clone, Default)]
+pub struct PrivacyMask {
+    regions: Vec<PrivacyRegion>,
+}
+
+impl PrivacyMask {
+    pub fn new(regions: Vec<PrivacyRegion>) -> Self {
+        Self { regions }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Hide the regions, the resulting pixels are written to `buf`. `visible` is the part of the
+    /// capturable the frame shows, x, y, width and height relative to its size, which is all of it
+    /// unless the frame has been cropped. Pixel format and padding are kept.
+    pub fn apply<'a>(
+        &self,
+        pixel_provider: PixelProvider<'a>,
+        visible: (f64, f64, f64, f64),
+        buf: &'a mut Vec<u8>,
+    ) -> PixelProvider<'a> {
+        let size = pixel_provider.size();
+        let bpp = pixel_provider.bytes_per_pixel();
+        let stride = pixel_provider.stride();
+        let black: &[u8] = match pixel_provider {
+            PixelProvider::RGB(..) => &[0, 0, 0],
+            _ => &[0, 0, 0, 255],
+        };
+        buf.clear();
+        buf.extend_from_slice(pixel_provider.data());
+        if visible.2 > 0.0 && visible.3 > 0.0 {
+            for region in &self.regions {
+                let (x0, y0, x1, y1) = match region.to_pixels(visible, size) {
+                    Some(rect) => rect,
+                    None => continue,
+                };
+                let rows = buf[y0 * stride..].chunks_mut(stride).take(y1 - y0);
+                match region.style {
+                    PrivacyStyle::Fill => {
+                        for row in rows {
+                            for pixel in row[x0 * bpp..x1 * bpp].chunks_exact_mut(bpp) {
+                                pixel.copy_from_slice(black);
+                            }
+                        }
+                    }
+                    PrivacyStyle::Blur => {
+                        let (w, h) = (x1 - x0, y1 - y0);
+                        let mut pixels = Vec::with_capacity(w * h * bpp);
+                        let rows: Vec<&mut [u8]> = rows.collect();
+                        for row in &rows {
+                            pixels.extend_from_slice(&row[x0 * bpp..x1 * bpp]);
+                        }
+                        box_blur(&mut pixels, w, h, bpp);
+                        for (row, blurred) in rows.into_iter().zip(pixels.chunks_exact(w * bpp)) {
+                            row[x0 * bpp..x1 * bpp].copy_from_slice(blurred);
+                        }
+                    }
+                }
+            }
+        }
+        let buf: &'a Vec<u8> = buf;
+        match pixel_provider {
+            PixelProvider::RGB(w, h, _) => PixelProvider::RGB(w, h, buf),
+            PixelProvider::RGB0(w, h, _) => PixelProvider::RGB0(w, h, buf),
+            PixelProvider::BGR0(w, h, _) => PixelProvider::BGR0(w, h, buf),
+            PixelProvider::BGR0S(w, h, stride, _) => PixelProvider::BGR0S(w, h, stride, buf),
+        }
+    }
+}
+
+// blur tightly packed pixels of `bpp` bytes each in place
+fn box_blur(pixels: &mut [u8], width: usize, height: usize, bpp: usize) {
+    let radius = PRIVACY_BLUR_RADIUS.min(width.max(height) / 2).max(1);
+    let mut tmp = vec![0; pixels.len()];
+    for _ in 0..PRIVACY_BLUR_PASSES {
+        // rows, then columns
+        box_blur_lines(pixels, &mut tmp, width, height, bpp, width * bpp, radius);
+        box_blur_lines(&tmp, pixels, height, width, width * bpp, bpp, radius);
+    }
+}
+
+// blur `lines` lines of `len` pixels each, where pixels of a line are `step` bytes apart and lines
+// start `line_step` bytes apart, pixels beyond the ends repeat the ones at the ends
+fn box_blur_lines(
+    src: &[u8],
+    dst: &mut [u8],
+    len: usize,
+    lines: usize,
+    step: usize,
+    line_step: usize,
+    radius: usize,
+) {
+    // one of both steps is the size of a pixel
+    let bpp = step.min(line_step);
+    let window = 2 * radius as u32 + 1;
+    for line in 0..lines {
+        for channel in 0..bpp {
+            let base = line * line_step + channel;
+            let at = |i: usize| src[base + i.min(len - 1) * step] as u32;
+            let mut sum: u32 = at(0) * radius as u32 + (0..=radius).map(at).sum::<u32>();
+            for i in 0..len {
+                dst[base + i * step] = (sum / window) as u8;
+                sum += at(i + radius + 1);
+                sum -= at(i.saturating_sub(radius));
+            }
+        }
+    }
+}
+
+/// Where a frame lies within a frame padded to another aspect ratio by [`Letterbox`], in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LetterboxLayout {
+    /// Size of the padded frame.
+    pub width: usize,
+    pub height: usize,
+    /// Position of the frame within the padded one.
+    pub x: usize,
+    pub y: usize,
+    pub content_width: usize,
+    pub content_height: usize,
+}
+
+impl LetterboxLayout {
+    /// Center a frame of `content_width` x `content_height` within the smallest frame of the
+    /// aspect ratio of `target_width` x `target_height` that holds it, which adds bars at the top
+    /// and bottom or left and right.
+    pub fn new(
+        content_width: usize,
+        content_height: usize,
+        target_width: usize,
+        target_height: usize,
+    ) -> Self {
+        let (mut width, mut height) = (content_width, content_height);
+        if target_width > 0 && target_height > 0 {
+            // compare content_width / content_height to target_width / target_height exactly
+            if content_width * target_height > target_width * content_height {
+                height = (content_width * target_height + target_width / 2) / target_width;
+            } else {
+                width = (content_height * target_width + target_height / 2) / target_height;
+            }
+        }
+        let (width, height) = (width.max(content_width), height.max(content_height));
+        Self {
+            width,
+            height,
+            x: (width - content_width) / 2,
+            y: (height - content_height) / 2,
+            content_width,
+            content_height,
+        }
+    }
+
+    pub fn is_padded(&self) -> bool {
+        (self.width, self.height) != (self.content_width, self.content_height)
+    }
+
+    /// x, y, width and height of the frame relative to the size of the padded frame.
+    pub fn content_relative(&self) -> (f64, f64, f64, f64) {
+        let (w, h) = (self.width as f64, self.height as f64);
+        (
+            self.x as f64 / w,
+            self.y as f64 / h,
+            self.content_width as f64 / w,
+            self.content_height as f64 / h,
+        )
+    }
+}
+
+/// Pads frames with bars of a color to the aspect ratio of the screen they are shown on, so they
+/// keep their own aspect ratio instead of being stretched. The bars go to the top and bottom of
+/// frames wider than the screen and to the left and right of narrower ones.
+#[derive(Clone)]
+pub struct Letterbox {
+    color: Color,
+}
+
+impl Letterbox {
+    pub fn new(color: Color) -> Self {
+        Self { color }
+    }
+
+    /// Pad the frame to the aspect ratio of `target_width` x `target_height`, the resulting
+    /// pixels are written to `buf`. Frames of that aspect ratio already are returned as they are,
+    /// others keep their pixel format but lose the padding at the end of rows.
+    pub fn apply<'a>(
+        &self,
+        pixel_provider: PixelProvider<'a>,
+        (target_width, target_height): (usize, usize),
+        buf: &'a mut Vec<u8>,
+    ) -> (PixelProvider<'a>, LetterboxLayout) {
+        let (width, height) = pixel_provider.size();
+        let layout = LetterboxLayout::new(width, height, target_width, target_height);
+        if !layout.is_padded() {
+            return (pixel_provider, layout);
+        }
+        let c = self.color;
+        let bgra = c.bgra();
+        let rgba = [c.r, c.g, c.b, 255];
+        let color: &[u8] = match pixel_provider {
+            PixelProvider::RGB(..) => &rgba[..3],
+            PixelProvider::RGB0(..) => &rgba,
+            PixelProvider::BGR0(..) | PixelProvider::BGR0S(..) => &bgra,
+        };
+        let bpp = color.len();
+        let (row_len, padded_row_len) = (width * bpp, layout.width * bpp);
+        let bar = color.repeat(layout.width);
+        buf.clear();
+        buf.reserve(padded_row_len * layout.height);
+        for _ in 0..layout.height {
+            buf.extend_from_slice(&bar);
+        }
+        let (data, stride) = (pixel_provider.data(), pixel_provider.stride());
+        for y in 0..height {
+            let start = (layout.y + y) * padded_row_len + layout.x * bpp;
+            buf[start..start + row_len].copy_from_slice(&data[y * stride..y * stride + row_len]);
+        }
+        let buf: &'a Vec<u8> = buf;
+        let (w, h) = (layout.width, layout.height);
+        let pixel_provider = match pixel_provider {
+            PixelProvider::RGB(..) => PixelProvider::RGB(w, h, buf),
+            PixelProvider::RGB0(..) => PixelProvider::RGB0(w, h, buf),
+            PixelProvider::BGR0(..) | PixelProvider::BGR0S(..) => PixelProvider::BGR0(w, h, buf),
+        };
+        (pixel_provider, layout)
+    }
+}
+
+/// Coefficients used to convert from RGB to YUV, this is also signaled in the video stream so
+/// that clients decode the colors accordingly.
+// keep in sync with enum ColorMatrix in lib/encode_video.c
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMatrix {
+    #[default]
+    Bt601 = 0,
+    Bt709 = 1,
+}
+
+impl FromStr for ColorMatrix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bt601" => Ok(Self::Bt601),
+            "bt709" => Ok(Self::Bt709),
+            _ => Err(format!(
+                "Unknown color matrix: '{}', expected 'bt601' or 'bt709'.",
+                s
+            )),
+        }
+    }
+}
+
+/// Range of the YUV values, limited range maps black and white to Y = 16 and Y = 235, full range to
+/// Y = 0 and Y = 255. This is signaled in the video stream as well.
+// keep in sync with enum ColorRange in lib/encode_video.c
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorRange {
+    #[default]
+    Limited = 0,
+    Full = 1,
+}
+
+impl FromStr for ColorRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "limited" => Ok(Self::Limited),
+            "full" => Ok(Self::Full),
+            _ => Err(format!(
+                "Unknown color range: '{}', expected 'limited' or 'full'.",
+                s
+            )),
+        }
+    }
+}
+
+/// How chroma samples are computed from the pixels they cover when subsampling to YUV 4:2:0.
+// keep in sync with enum ChromaFilter in lib/encode_video.c
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChromaFilter {
+    /// Average of the 2x2 pixels covered, this is fast but colored edges show aliasing.
+    #[default]
+    Box = 0,
+    /// Weighted average over 4x4 pixels around the sample, which smooths colored edges at the cost
+    /// of converting frames slower.
+    Weighted = 1,
+}
+
+impl FromStr for ChromaFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "box" => Ok(Self::Box),
+            "weighted" => Ok(Self::Weighted),
+            _ => Err(format!(
+                "Unknown chroma filter: '{}', expected 'box' or 'weighted'.",
+                s
+            )),
+        }
+    }
+}
+
+/// Options of [`bgra_to_i420`] and [`bgra_to_nv12`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConversionOptions {
+    pub color_matrix: ColorMatrix,
+    pub color_range: ColorRange,
+    pub chroma_filter: ChromaFilter,
+}
+
+// make sure the plane called `name` holds `w`x`h` samples of one byte
+fn check_plane(
+    name: &str,
+    plane: &[u8],
+    line_size: usize,
+    (w, h): (usize, usize),
+) -> Result<(), CError> {
+    if line_size < w || plane.len() < line_size * (h - 1) + w {
+        return Err(CError::with_message(
+            CErrorCode::GenericError,
+            &format!(
+                "{} plane of {} bytes with a line size of {} bytes is too small for {}x{} samples.",
+                name,
+                plane.len(),
+                line_size,
+                w,
+                h
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Line sizes computed by [`plane_line_sizes`] are multiples of this many bytes, so every row of a
+/// plane starts as aligned as the plane itself. This suits SIMD code up to AVX2 and encoders like
+/// x264 and libvpx, which want rows aligned to at least 16 bytes.
+pub const LINE_SIZE_ALIGNMENT: usize = 32;
+
+/// Line sizes of the Y, U and V planes of an I420 frame `width` pixels wide as expected by
+/// [`bgra_to_i420`], each rounded up to a multiple of [`LINE_SIZE_ALIGNMENT`].
+pub fn plane_line_sizes(width: usize) -> [usize; 3] {
+    let align =
+        |n: usize| (n + LINE_SIZE_ALIGNMENT - 1) / LINE_SIZE_ALIGNMENT * LINE_SIZE_ALIGNMENT;
+    let chroma = align((width + 1) / 2);
+    [align(width), chroma, chroma]
+}
+
+/// Allocate the Y, U and V planes of an I420 frame of `width` x `height` pixels filled with zeros
+/// along with their line sizes, see [`plane_line_sizes`], ready to be passed to [`bgra_to_i420`].
+/// Only the line sizes are aligned, where the planes start is up to the allocator, which usually
+/// aligns buffers this large to 16 bytes but does not guarantee it. Copy them to memory aligned as
+/// required if an encoder relies on this.
+pub fn alloc_yuv(width: usize, height: usize) -> (Vec<u8>, Vec<u8>, Vec<u8>, [usize; 3]) {
+    let line_sizes = plane_line_sizes(width);
+    let chroma_height = (height + 1) / 2;
+    (
+        vec![0; line_sizes[0] * height],
+        vec![0; line_sizes[1] * chroma_height],
+        vec![0; line_sizes[2] * chroma_height],
+        line_sizes,
+    )
+}
+
+/// Y, U and V planes of I420 frames that are reused from one frame to the next, so converting
+/// frames of the same size does not allocate. The planes are only reallocated when the size of the
+/// frames changes.
+#[derive(Default)]
+pub struct YuvPlanes {
+    size: (usize, usize),
+    planes: [Vec<u8>; 3],
+    line_sizes: [usize; 3],
+}
+
+impl YuvPlanes {
+    /// Planes without any frame, they are allocated by the first call of [`Self::convert`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Convert `frame` via [`bgra_to_i420`], reallocating the planes before if its size differs
+    /// from the frame converted last.
+    pub fn convert(&mut self, frame: PixelProvider, opts: ConversionOptions) -> Result<(), CError> {
+        let size = frame.size();
+        if size != self.size {
+            let (y, u, v, line_sizes) = alloc_yuv(size.0, size.1);
+            self.planes = [y, u, v];
+            self.line_sizes = line_sizes;
+            self.size = size;
+        }
+        let [y, u, v] = &mut self.planes;
+        bgra_to_i420(frame, [y, u, v], self.line_sizes, opts)
+    }
+
+    /// Size of the frame converted last.
+    pub fn size(&self) -> (usize, usize) {
+        self.size
+    }
+
+    /// Line sizes of the planes, see [`plane_line_sizes`].
+    pub fn line_sizes(&self) -> [usize; 3] {
+        self.line_sizes
+    }
+
+    /// The Y, U and V planes holding the frame converted last, including the padding at the end
+    /// of rows.
+    pub fn yuv_planes(&self) -> (&[u8], &[u8], &[u8]) {
+        let [y, u, v] = &self.planes;
+        (y, u, v)
+    }
+}
+
+/// Convert a frame to I420, that is planar YUV 4:2:0 with chroma planes of half the width and
+/// height rounded up, in Rust without going through the [`VideoEncoder`]. This allows passing
+/// frames to other encoders. Despite its name all formats of [`PixelProvider`] are supported.
+///
+/// `planes` are the Y, U and V planes and `line_sizes` the bytes from the start of one row of a
+/// plane to the start of the next, [`alloc_yuv`] allocates planes and line sizes that fit. Fails
+/// naming the plane if any of them is too small for the size of the frame, which happens if planes
+/// have not been reallocated after the size changed.
+///
+/// Chroma samples are computed as selected by [`ConversionOptions::chroma_filter`].
+pub fn bgra_to_i420(
+    frame: PixelProvider,
+    planes: [&mut [u8]; 3],
+    line_sizes: [usize; 3],
+    opts: ConversionOptions,
+) -> Result<(), CError> {
+    frame.check_data_len()?;
+    let (width, height) = frame.size();
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    let chroma_size = ((width + 1) / 2, (height + 1) / 2);
+    let plane_sizes = [
+        ("Y", (width, height)),
+        ("U", chroma_size),
+        ("V", chroma_size),
+    ];
+    for ((plane, line_size), (name, size)) in planes.iter().zip(line_sizes).zip(plane_sizes) {
+        check_plane(name, plane, line_size, size)?;
+    }
+    let [y_plane, u_plane, v_plane] = planes;
+    bgra_to_yuv420(frame, y_plane, line_sizes[0], opts, true, |cx, cy, u, v| {
+        u_plane[cy * line_sizes[1] + cx] = u;
+        v_plane[cy * line_sizes[2] + cx] = v;
+    });
+    Ok(())
+}
+
+/// Convert a frame to NV12, that is YUV 4:2:0 with a luma plane followed by a single plane of
+/// interleaved U and V samples, as preferred by most hardware encoders. Apart from the layout of
+/// the chroma samples this is the same as [`bgra_to_i420`]. The [`VideoEncoder`] picks the format
+/// its encoder prefers on its own.
+///
+/// `planes` are the Y and UV planes, each row of the UV plane holds U and V of half the width
+/// rounded up samples alternately, starting with U.
+pub fn bgra_to_nv12(
+    frame: PixelProvider,
+    planes: [&mut [u8]; 2],
+    line_sizes: [usize; 2],
+    opts: ConversionOptions,
+) -> Result<(), CError> {
+    frame.check_data_len()?;
+    let (width, height) = frame.size();
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    let plane_sizes = [
+        ("Y", (width, height)),
+        ("UV", ((width + 1) / 2 * 2, (height + 1) / 2)),
+    ];
+    for ((plane, line_size), (name, size)) in planes.iter().zip(line_sizes).zip(plane_sizes) {
+        check_plane(name, plane, line_size, size)?;
+    }
+    let [y_plane, uv_plane] = planes;
+    bgra_to_yuv420(frame, y_plane, line_sizes[0], opts, true, |cx, cy, u, v| {
+        let i = cy * line_sizes[1] + 2 * cx;
+        uv_plane[i] = u;
+        uv_plane[i + 1] = v;
+    });
+    Ok(())
+}
+
+// Convert a non-empty frame whose size has been checked to YUV 4:2:0, luma is written to `y_plane`
+// and the U and V sample of every chroma position is handed to `store_chroma` along with its x and
+// y coordinate. With `simd` frames of 4 bytes per pixel are converted via SSE2 or AVX2 where the
+// CPU supports them, which yields the same samples give or take one.
+fn bgra_to_yuv420(
+    frame: PixelProvider,
+    y_plane: &mut [u8],
+    y_line_size: usize,
+    opts: ConversionOptions,
+    simd: bool,
+    mut store_chroma: impl FnMut(usize, usize, u8, u8),
+) {
+    let (width, height) = frame.size();
+    debug_assert!(
+        check_plane("Y", y_plane, y_line_size, (width, height)).is_ok(),
+        "Y plane has not been checked before converting."
+    );
+    let (bpp, stride, data) = (frame.bytes_per_pixel(), frame.stride(), frame.data());
+    // byte offsets of red, green and blue within a pixel
+    let (ri, gi, bi) = match frame {
+        PixelProvider::RGB(..) | PixelProvider::RGB0(..) => (0, 1, 2),
+        PixelProvider::BGR0(..) | PixelProvider::BGR0S(..) => (2, 1, 0),
+    };
+    let rgb = |x: usize, y: usize| {
+        let i = y * stride + x * bpp;
+        (
+            data[i + ri] as f32 / 255.0,
+            data[i + gi] as f32 / 255.0,
+            data[i + bi] as f32 / 255.0,
+        )
+    };
+    let (kr, kb) = match opts.color_matrix {
+        ColorMatrix::Bt601 => (0.299, 0.114),
+        ColorMatrix::Bt709 => (0.2126, 0.0722),
+    };
+    let kg = 1.0 - kr - kb;
+    let (y_offset, y_scale, c_scale) = match opts.color_range {
+        ColorRange::Limited => (16.0, 219.0, 224.0),
+        ColorRange::Full => (0.0, 255.0, 255.0),
+    };
+    let to_u8 = |v: f32| (v + 0.5).clamp(0.0, 255.0) as u8;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let simd = simd
+        .then(x86::Simd::detect)
+        .flatten()
+        .filter(|_| bpp == 4)
+        .map(|simd| {
+            let shifts = [ri, gi, bi].map(|i| 8 * i as i32);
+            // the same as the scalar code below with the weights of pixels, the scaling of the
+            // range and the division by 255 folded into one weight per channel
+            let luma = x86::Weights {
+                offset: y_offset + 0.5,
+                r: y_scale * kr / 255.0,
+                g: y_scale * kg / 255.0,
+                b: y_scale * kb / 255.0,
+            };
+            // chroma samples are computed from the sums of the channels over 2x2 pixels
+            let (u_scale, v_scale) = (
+                c_scale / (2.0 * (1.0 - kb) * 4.0 * 255.0),
+                c_scale / (2.0 * (1.0 - kr) * 4.0 * 255.0),
+            );
+            let u = x86::Weights {
+                offset: 128.5,
+                r: -kr * u_scale,
+                g: -kg * u_scale,
+                b: (1.0 - kb) * u_scale,
+            };
+            let v = x86::Weights {
+                offset: 128.5,
+                r: (1.0 - kr) * v_scale,
+                g: -kg * v_scale,
+                b: -kb * v_scale,
+            };
+            (simd, shifts, luma, [u, v])
+        });
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let _ = simd;
+
+    for y in 0..height {
+        #[allow(unused_mut)]
+        let mut done = 0;
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some((simd, shifts, luma, _)) = simd {
+            let row = &data[y * stride..][..4 * width];
+            done = simd.luma_row(row, &mut y_plane[y * y_line_size..][..width], shifts, luma);
+        }
+        for x in done..width {
+            let (r, g, b) = rgb(x, y);
+            y_plane[y * y_line_size + x] = to_u8(y_offset + y_scale * (kr * r + kg * g + kb * b));
+        }
+    }
+    let (chroma_width, chroma_height) = ((width + 1) / 2, (height + 1) / 2);
+    // pixels and their weights a chroma sample at c on an axis of len pixels is computed from,
+    // pixels past the edges are replaced by the ones at the edge, so the last sample of odd sizes
+    // only covers a single pixel
+    let taps = |c: usize, len: usize| -> [(usize, f32); 4] {
+        let at = |i: isize| i.clamp(0, len as isize - 1) as usize;
+        let c = 2 * c as isize;
+        match opts.chroma_filter {
+            ChromaFilter::Box => [(at(c), 0.5), (at(c + 1), 0.5), (0, 0.0), (0, 0.0)],
+            // a tent centered between the two pixels covered by the sample
+            ChromaFilter::Weighted => [
+                (at(c - 1), 0.125),
+                (at(c), 0.375),
+                (at(c + 1), 0.375),
+                (at(c + 2), 0.125),
+            ],
+        }
+    };
+    for cy in 0..chroma_height {
+        let ys = taps(cy, height);
+        #[allow(unused_mut)]
+        let mut done = 0;
+        // the SIMD code only covers chroma samples of the box filter made up of 2x2 pixels, that is
+        // all but those of the last row or column of odd sizes
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some((simd, shifts, _, uv)) = simd {
+            if opts.chroma_filter == ChromaFilter::Box && 2 * cy + 1 < height {
+                let rows = [2 * cy, 2 * cy + 1].map(|y| &data[y * stride..][..4 * width]);
+                done = simd.chroma_rows(rows, width / 2, shifts, uv, &mut |cx, u, v| {
+                    store_chroma(cx, cy, u, v)
+                });
+            }
+        }
+        for cx in done..chroma_width {
+            let xs = taps(cx, width);
+            let (mut r, mut g, mut b) = (0.0, 0.0, 0.0);
+            for (y, wy) in ys {
+                for (x, wx) in xs {
+                    if wx * wy == 0.0 {
+                        continue;
+                    }
+                    let (pr, pg, pb) = rgb(x, y);
+                    r += pr * wx * wy;
+                    g += pg * wx * wy;
+                    b += pb * wx * wy;
+                }
+            }
+            let luma = kr * r + kg * g + kb * b;
+            store_chroma(
+                cx,
+                cy,
+                to_u8(128.0 + c_scale * (b - luma) / (2.0 * (1.0 - kb))),
+                to_u8(128.0 + c_scale * (r - luma) / (2.0 * (1.0 - kr))),
+            );
+        }
+    }
+}
+
+// SSE2 and AVX2 versions of the parts of `bgra_to_yuv420` that take the most time, for frames of 4
+// bytes per pixel. The Y plane is converted 4 or 8 pixels at a time and the chroma samples of the
+// box filter 4 or 8 samples, that is 8 or 16 pixels of two rows, at a time.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    // a sample is offset + r * red + g * green + b * blue clamped to 0..=255 and truncated, so
+    // the offset includes the 0.5 for rounding
+    #[derive(Clone, Copy)]
+    pub struct Weights {
+        pub offset: f32,
+        pub r: f32,
+        pub g: f32,
+        pub b: f32,
+    }
+
+    #[derive(Clone, Copy)]
+    pub enum Simd {
+        Sse2,
+        Avx2,
+    }
+
+    impl Simd {
+        // the widest instruction set supported by the CPU, if any
+        pub fn detect() -> Option<Self> {
+            if is_x86_feature_detected!("avx2") {
+                Some(Self::Avx2)
+            } else if is_x86_feature_detected!("sse2") {
+                Some(Self::Sse2)
+            } else {
+                None
+            }
+        }
+
+        // Write the luma of the leading pixels of `row` to `out`, which holds a sample per pixel,
+        // and return how many have been converted. `shifts` are the offsets in bits of red, green
+        // and blue within a pixel read as little endian u32.
+        pub fn luma_row(self, row: &[u8], out: &mut [u8], shifts: [i32; 3], w: Weights) -> usize {
+            assert!(row.len() >= 4 * out.len());
+            // Safety: the instruction set has been detected by detect and the functions stay
+            // within the bounds of row and out
+            unsafe {
+                match self {
+                    Self::Sse2 => luma_sse2(row, out, shifts, w),
+                    Self::Avx2 => luma_avx2(row, out, shifts, w),
+                }
+            }
+        }
+
+        // Hand the U and V samples of the leading of the `count` chroma samples covering the 2x2
+        // pixels of `rows` to `store` along with their x coordinate and return how many have been
+        // computed.
+        pub fn chroma_rows(
+            self,
+            rows: [&[u8]; 2],
+            count: usize,
+            shifts: [i32; 3],
+            uv: [Weights; 2],
+            store: &mut impl FnMut(usize, u8, u8),
+        ) -> usize {
+            assert!(rows.iter().all(|row| row.len() >= 8 * count));
+            // Safety: see luma_row
+            unsafe {
+                match self {
+                    Self::Sse2 => chroma_sse2(rows, count, shifts, uv, store),
+                    Self::Avx2 => chroma_avx2(rows, count, shifts, uv, store),
+                }
+            }
+        }
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn channels_sse2(px: __m128i, shifts: [i32; 3]) -> [__m128i; 3] {
+        let mask = _mm_set1_epi32(0xff);
+        let [r, g, b] = shifts;
+        [
+            _mm_and_si128(_mm_srl_epi32(px, _mm_cvtsi32_si128(r)), mask),
+            _mm_and_si128(_mm_srl_epi32(px, _mm_cvtsi32_si128(g)), mask),
+            _mm_and_si128(_mm_srl_epi32(px, _mm_cvtsi32_si128(b)), mask),
+        ]
+    }
+
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn weigh_sse2([r, g, b]: [__m128; 3], w: Weights) -> __m128i {
+        let sum = _mm_add_ps(
+            _mm_add_ps(_mm_set1_ps(w.offset), _mm_mul_ps(r, _mm_set1_ps(w.r))),
+            _mm_add_ps(
+                _mm_mul_ps(g, _mm_set1_ps(w.g)),
+                _mm_mul_ps(b, _mm_set1_ps(w.b)),
+            ),
+        );
+        _mm_cvttps_epi32(_mm_min_ps(
+            _mm_max_ps(sum, _mm_setzero_ps()),
+            _mm_set1_ps(255.0),
+        ))
+    }
+
+    // red, green and blue of the pixels at x to x + 3 of both rows added up
+    #[inline]
+    #[target_feature(enable = "sse2")]
+    unsafe fn column_sums_sse2(rows: [&[u8]; 2], x: usize, shifts: [i32; 3]) -> [__m128i; 3] {
+        let [r0, g0, b0] = channels_sse2(
+            _mm_loadu_si128(rows[0].as_ptr().add(4 * x) as *const __m128i),
+            shifts,
+        );
+        let [r1, g1, b1] = channels_sse2(
+            _mm_loadu_si128(rows[1].as_ptr().add(4 * x) as *const __m128i),
+            shifts,
+        );
+        [
+            _mm_add_epi32(r0, r1),
+            _mm_add_epi32(g0, g1),
+            _mm_add_epi32(b0, b1),
+        ]
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn luma_sse2(row: &[u8], out: &mut [u8], shifts: [i32; 3], w: Weights) -> usize {
+        let mut x = 0;
+        while x + 4 <= out.len() {
+            let px = _mm_loadu_si128(row.as_ptr().add(4 * x) as *const __m128i);
+            let [r, g, b] = channels_sse2(px, shifts);
+            let y = weigh_sse2(
+                [_mm_cvtepi32_ps(r), _mm_cvtepi32_ps(g), _mm_cvtepi32_ps(b)],
+                w,
+            );
+            let y = _mm_packus_epi16(_mm_packs_epi32(y, y), _mm_setzero_si128());
+            out[x..x + 4].copy_from_slice(&_mm_cvtsi128_si32(y).to_le_bytes());
+            x += 4;
+        }
+        x
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn chroma_sse2(
+        rows: [&[u8]; 2],
+        count: usize,
+        shifts: [i32; 3],
+        [u_weights, v_weights]: [Weights; 2],
+        store: &mut impl FnMut(usize, u8, u8),
+    ) -> usize {
+        let mut cx = 0;
+        while cx + 4 <= count {
+            let mut sums = [_mm_setzero_ps(); 3];
+            // the first and last 4 of the 8 pixels covered by the samples, summed up vertically
+            let lo = column_sums_sse2(rows, 2 * cx, shifts);
+            let hi = column_sums_sse2(rows, 2 * cx + 4, shifts);
+            for (c, sum) in sums.iter_mut().enumerate() {
+                let (lo, hi) = (_mm_cvtepi32_ps(lo[c]), _mm_cvtepi32_ps(hi[c]));
+                // add up horizontally neighbouring pixels
+                *sum = _mm_add_ps(
+                    _mm_shuffle_ps::<0b10_00_10_00>(lo, hi),
+                    _mm_shuffle_ps::<0b11_01_11_01>(lo, hi),
+                );
+            }
+            let (mut u, mut v) = ([0i32; 4], [0i32; 4]);
+            _mm_storeu_si128(u.as_mut_ptr() as *mut __m128i, weigh_sse2(sums, u_weights));
+            _mm_storeu_si128(v.as_mut_ptr() as *mut __m128i, weigh_sse2(sums, v_weights));
+            for i in 0..4 {
+                store(cx + i, u[i] as u8, v[i] as u8);
+            }
+            cx += 4;
+        }
+        cx
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn channels_avx2(px: __m256i, shifts: [i32; 3]) -> [__m256i; 3] {
+        let mask = _mm256_set1_epi32(0xff);
+        let [r, g, b] = shifts;
+        [
+            _mm256_and_si256(_mm256_srl_epi32(px, _mm_cvtsi32_si128(r)), mask),
+            _mm256_and_si256(_mm256_srl_epi32(px, _mm_cvtsi32_si128(g)), mask),
+            _mm256_and_si256(_mm256_srl_epi32(px, _mm_cvtsi32_si128(b)), mask),
+        ]
+    }
+
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn weigh_avx2([r, g, b]: [__m256; 3], w: Weights) -> __m256i {
+        let sum = _mm256_add_ps(
+            _mm256_add_ps(
+                _mm256_set1_ps(w.offset),
+                _mm256_mul_ps(r, _mm256_set1_ps(w.r)),
+            ),
+            _mm256_add_ps(
+                _mm256_mul_ps(g, _mm256_set1_ps(w.g)),
+                _mm256_mul_ps(b, _mm256_set1_ps(w.b)),
+            ),
+        );
+        _mm256_cvttps_epi32(_mm256_min_ps(
+            _mm256_max_ps(sum, _mm256_setzero_ps()),
+            _mm256_set1_ps(255.0),
+        ))
+    }
+
+    // red, green and blue of the pixels at x to x + 7 of both rows added up
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn column_sums_avx2(rows: [&[u8]; 2], x: usize, shifts: [i32; 3]) -> [__m256i; 3] {
+        let [r0, g0, b0] = channels_avx2(
+            _mm256_loadu_si256(rows[0].as_ptr().add(4 * x) as *const __m256i),
+            shifts,
+        );
+        let [r1, g1, b1] = channels_avx2(
+            _mm256_loadu_si256(rows[1].as_ptr().add(4 * x) as *const __m256i),
+            shifts,
+        );
+        [
+            _mm256_add_epi32(r0, r1),
+            _mm256_add_epi32(g0, g1),
+            _mm256_add_epi32(b0, b1),
+        ]
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn luma_avx2(row: &[u8], out: &mut [u8], shifts: [i32; 3], w: Weights) -> usize {
+        let mut x = 0;
+        while x + 8 <= out.len() {
+            let px = _mm256_loadu_si256(row.as_ptr().add(4 * x) as *const __m256i);
+            let [r, g, b] = channels_avx2(px, shifts);
+            let y = weigh_avx2(
+                [
+                    _mm256_cvtepi32_ps(r),
+                    _mm256_cvtepi32_ps(g),
+                    _mm256_cvtepi32_ps(b),
+                ],
+                w,
+            );
+            let (lo, hi) = (_mm256_castsi256_si128(y), _mm256_extracti128_si256::<1>(y));
+            let y = _mm_packus_epi16(_mm_packs_epi32(lo, hi), _mm_setzero_si128());
+            _mm_storel_epi64(out.as_mut_ptr().add(x) as *mut __m128i, y);
+            x += 8;
+        }
+        x
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn chroma_avx2(
+        rows: [&[u8]; 2],
+        count: usize,
+        shifts: [i32; 3],
+        [u_weights, v_weights]: [Weights; 2],
+        store: &mut impl FnMut(usize, u8, u8),
+    ) -> usize {
+        let mut cx = 0;
+        while cx + 8 <= count {
+            let mut sums = [_mm256_setzero_ps(); 3];
+            // the first and last 8 of the 16 pixels covered by the samples, summed up vertically
+            let lo = column_sums_avx2(rows, 2 * cx, shifts);
+            let hi = column_sums_avx2(rows, 2 * cx + 8, shifts);
+            for (c, sum) in sums.iter_mut().enumerate() {
+                let (lo, hi) = (_mm256_cvtepi32_ps(lo[c]), _mm256_cvtepi32_ps(hi[c]));
+                // add up horizontally neighbouring pixels, shuffles work within each half of the
+                // registers, so this yields the samples 0, 1, 4, 5, 2, 3, 6 and 7 which are then
+                // put in order
+                let pairs = _mm256_add_ps(
+                    _mm256_shuffle_ps::<0b10_00_10_00>(lo, hi),
+                    _mm256_shuffle_ps::<0b11_01_11_01>(lo, hi),
+                );
+                *sum = _mm256_castpd_ps(_mm256_permute4x64_pd::<0b11_01_10_00>(_mm256_castps_pd(
+                    pairs,
+                )));
+            }
+            let (mut u, mut v) = ([0i32; 8], [0i32; 8]);
+            _mm256_storeu_si256(u.as_mut_ptr() as *mut __m256i, weigh_avx2(sums, u_weights));
+            _mm256_storeu_si256(v.as_mut_ptr() as *mut __m256i, weigh_avx2(sums, v_weights));
+            for i in 0..8 {
+                store(cx + i, u[i] as u8, v[i] as u8);
+            }
+            cx += 8;
+        }
+        cx
+    }
+}
+
+// ffmpeg encoders the VideoEncoder may pick on this platform
+#[cfg(target_os = "linux")]
+const ENCODERS: &[&str] = &[
+    "libx264",
+    "h264_vaapi",
+    "h264_nvenc",
+    "libvpx-vp9",
+    "libaom-av1",
+];
+#[cfg(target_os = "windows")]
+const ENCODERS: &[&str] = &[
+    "libx264",
+    "h264_mf",
+    "h264_nvenc",
+    "libvpx-vp9",
+    "libaom-av1",
+];
+#[cfg(target_os = "macos")]
+const ENCODERS: &[&str] = &["libx264", "h264_videotoolbox", "libvpx-vp9", "libaom-av1"];
+#[cfg(not(any(target_os = "linux", target_os = "windows", target_os = "macos")))]
+const ENCODERS: &[&str] = &["libx264", "libvpx-vp9", "libaom-av1"];
+
+/// Names of the ffmpeg encoders the [`VideoEncoder`] may pick that are available, hardware encoders
+/// are only listed if the device they run on can be opened. This opens devices and should not be
+/// called per frame.
+pub fn available_encoders() -> Vec<&'static str> {
+    ENCODERS
+        .iter()
+        .copied()
+        .filter(|name| encoder_available(name))
+        .collect()
+}
+
+/// Whether Nvidia's NVENC can encode on this machine, that is ffmpeg has h264_nvenc and a CUDA
+/// device can be opened. Like [`available_encoders`] this opens the device.
+#[cfg(any(target_os = "linux", target_os = "windows"))]
+pub fn nvenc_available() -> bool {
+    encoder_available("h264_nvenc")
+}
+
+fn encoder_available(name: &str) -> bool {
+    let name = CString::new(name).unwrap();
+    unsafe { video_encoder_available(name.as_ptr()) != 0 }
+}
+
+/// Codec of the video stream, the stream is always muxed as fragmented MP4.
+// keep in sync with enum Codec in lib/encode_video.c
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    #[default]
+    H264 = 0,
+    Vp9 = 1,
+    Av1 = 2,
+}
+
+impl VideoCodec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::H264 => "H.264",
+            Self::Vp9 => "VP9",
+            Self::Av1 => "AV1",
+        }
+    }
+
+    /// Largest quantizer the encoders of this codec support, quantizers start at 0 which is the
+    /// best quality.
+    pub fn max_quantizer(&self) -> u32 {
+        match self {
+            Self::H264 => 51,
+            Self::Vp9 | Self::Av1 => 63,
+        }
+    }
 
-    fn fill_rgb(ctx: *mut c_void, data: *const u8, err: *mut CError);
-    fn fill_rgb0(ctx: *mut c_void, data: *const u8, err: *mut CError);
-    fn fill_bgr0(ctx: *mut c_void, data: *const u8, stride: c_int, err: *mut CError);
+    /// Whether any of `encoders`, as returned by [`available_encoders`], encodes this codec.
+    pub fn is_encoded_by(&self, encoders: &[&str]) -> bool {
+        encoders.iter().any(|encoder| match self {
+            Self::H264 => *encoder == "libx264" || encoder.starts_with("h264_"),
+            Self::Vp9 => *encoder == "libvpx-vp9",
+            Self::Av1 => *encoder == "libaom-av1",
+        })
+    }
+
+    /// MIME type of the video stream, as expected by `MediaSource.addSourceBuffer`.
+    pub fn mime_type(&self, yuv444: bool) -> &'static str {
+        match (self, yuv444) {
+            (Self::H264, _) => "video/mp4; codecs=\"avc1.4D403D\"",
+            (Self::Vp9, false) => "video/mp4; codecs=\"vp09.00.10.08\"",
+            (Self::Vp9, true) => "video/mp4; codecs=\"vp09.01.10.08\"",
+            (Self::Av1, false) => "video/mp4; codecs=\"av01.0.08M.08\"",
+            (Self::Av1, true) => "video/mp4; codecs=\"av01.1.08M.08\"",
+        }
+    }
 }
 
-// this is used as callback in lib/encode_video.c via ffmpegs AVIOContext
-#[no_mangle]
-fn write_video_packet(video_encoder: *mut c_void, buf: *const c_uchar, buf_size: c_int) -> c_int {
-    let video_encoder = unsafe { (video_encoder as *mut VideoEncoder).as_mut().unwrap() };
-    (video_encoder.write_data)(unsafe {
-        std::slice::from_raw_parts(buf as *const u8, buf_size as usize)
-    });
-    0
+impl FromStr for VideoCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "h264" => Ok(Self::H264),
+            "vp9" => Ok(Self::Vp9),
+            "av1" => Ok(Self::Av1),
+            _ => Err(format!(
+                "Unknown video codec: '{}', expected 'h264', 'vp9' or 'av1'.",
+                s
+            )),
+        }
+    }
 }
 
-pub enum PixelProvider<'a> {
-    // 8 bits per color
-    RGB(usize, usize, &'a [u8]),
-    RGB0(usize, usize, &'a [u8]),
-    BGR0(usize, usize, &'a [u8]),
-    // width, height, stride
-    BGR0S(usize, usize, usize, &'a [u8]),
+/// Bundles resolution, frame rate, codec and bitrate of the video into a single setting.
+///
+/// | Preset   | Resolution | Max FPS | Codec | Max bitrate  |
+/// |----------|------------|---------|-------|--------------|
+/// | lowcolor | 50%        | 10      | VP9   | 500 kbit/s   |
+/// | low      | 50%        | 15      | VP9   | 1000 kbit/s  |
+/// | medium   | 75%        | 30      | H.264 | 3000 kbit/s  |
+/// | high     | 100%       | 60      | H.264 | 8000 kbit/s  |
+///
+/// The resolution is relative to the maximum resolution requested by the client. Clients that can
+/// not decode VP9 get H.264 and the bitrate is only limited by encoders supporting it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QualityPreset {
+    /// Like `Low` but with less bandwidth still, colors are reduced to 16 bit, see
+    /// [`ColorDepthReducer`].
+    LowColor,
+    Low,
+    Medium,
+    High,
 }
 
-impl<'a> PixelProvider<'a> {
-    pub fn size(&self) -> (usize, usize) {
+impl QualityPreset {
+    pub fn resolution_scale(&self) -> f64 {
         match self {
-            PixelProvider::RGB(w, h, _) => (*w, *h),
-            PixelProvider::RGB0(w, h, _) => (*w, *h),
-            PixelProvider::BGR0(w, h, _) => (*w, *h),
-            PixelProvider::BGR0S(w, h, _, _) => (*w, *h),
+            Self::LowColor | Self::Low => 0.5,
+            Self::Medium => 0.75,
+            Self::High => 1.0,
+        }
+    }
+
+    pub fn max_fps(&self) -> f64 {
+        match self {
+            Self::LowColor => 10.0,
+            Self::Low => 15.0,
+            Self::Medium => 30.0,
+            Self::High => 60.0,
+        }
+    }
+
+    /// Reduce frames to 16 bit colors before encoding them.
+    pub fn low_color(&self) -> bool {
+        *self == Self::LowColor
+    }
+
+    /// Override codec and bitrate of `options`, which also ends constant quality, with 16 bit colors
+    /// chroma is always subsampled.
+    pub fn encoder_options(&self, options: EncoderOptions) -> EncoderOptions {
+        let (codec, max_bitrate) = match self {
+            Self::LowColor => (VideoCodec::Vp9, 500),
+            Self::Low => (VideoCodec::Vp9, 1000),
+            Self::Medium => (VideoCodec::H264, 3000),
+            Self::High => (VideoCodec::H264, 8000),
+        };
+        EncoderOptions {
+            codec,
+            max_bitrate: Some(max_bitrate),
+            constant_quality: None,
+            yuv444: options.yuv444 && !self.low_color(),
+            ..options
+        }
+    }
+}
+
+impl FromStr for QualityPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "lowcolor" => Ok(Self::LowColor),
+            "low" => Ok(Self::Low),
+            "medium" => Ok(Self::Medium),
+            "high" => Ok(Self::High),
+            _ => Err(format!(
+                "Unknown quality preset: '{}', expected 'lowcolor', 'low', 'medium' or 'high'.",
+                s
+            )),
         }
     }
 }
 
-#[derive(Clone, Copy)]
+/// Options of the [`VideoEncoder`], hardware accelerated encoders are only tried if enabled and
+/// fall back to encoding in software.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
 pub struct EncoderOptions {
     pub try_vaapi: bool,
     pub try_nvenc: bool,
     pub try_videotoolbox: bool,
     pub try_mediafoundation: bool,
+    /// Number of threads used to convert and scale frames, 0 selects the number automatically.
+    pub conversion_threads: usize,
+    pub color_matrix: ColorMatrix,
+    pub color_range: ColorRange,
+    /// How colors are subsampled when converting to YUV 4:2:0 in software, hardware encoders that
+    /// take BGR0 frames directly ignore this.
+    pub chroma_filter: ChromaFilter,
+    /// Encode with YUV 4:4:4 instead of 4:2:0, this disables hardware encoders.
+    pub yuv444: bool,
+    /// Only encode brightness and drop all colors, this saves bandwidth if there is mostly text to
+    /// show. Disables hardware encoders.
+    pub grayscale: bool,
+    /// VP9 and AV1 are always encoded in software.
+    pub codec: VideoCodec,
+    /// Upper limit for the bitrate in kbit/s, if set the limit can be changed while encoding.
+    /// Currently only supported by libx264.
+    pub max_bitrate: Option<u32>,
+    /// Encode every frame at this quantizer, from 0 up to [`VideoCodec::max_quantizer`], instead
+    /// of the default quality. Lower is better and takes more bits, which are not limited then:
+    /// `max_bitrate` is ignored. This sets crf of libx264, VP9 and AV1, cq of NVENC and qp of
+    /// VAAPI, MediaFoundation and VideoToolbox ignore it.
+    pub constant_quality: Option<u32>,
+    /// Smallest and largest quantizer frames are encoded with, which bounds their quality and
+    /// size with either rate control.
+    pub qp_range: Option<(u32, u32)>,
+    /// Maximum number of frames between two keyframes, must be at least 1. Keyframes requested by
+    /// clients, for example after joining, are sent in addition.
+    pub keyframe_interval: u32,
+    /// Number of frames libx264 encodes in parallel, 1 encodes one frame at a time split into
+    /// slices. More threads raise the throughput at high resolutions but delay the video by a frame
+    /// per additional thread. Other encoders ignore this.
+    pub encoder_threads: u32,
+    /// Trade bitrate for latency: frames are encoded one at a time regardless of
+    /// `encoder_threads`, without lookahead and, with libx264, without periodic keyframes, which
+    /// are replaced by intra refresh. The bitrate is allowed to exceed its average by less.
+    pub low_latency: bool,
+}
+
+impl Default for EncoderOptions {
+    /// Encode H.264 in software with the defaults of the command line options.
+    fn default() -> Self {
+        Self {
+            try_vaapi: false,
+            try_nvenc: false,
+            try_videotoolbox: false,
+            try_mediafoundation: false,
+            conversion_threads: 0,
+            color_matrix: ColorMatrix::default(),
+            color_range: ColorRange::default(),
+            chroma_filter: ChromaFilter::default(),
+            yuv444: false,
+            grayscale: false,
+            codec: VideoCodec::default(),
+            max_bitrate: None,
+            constant_quality: None,
+            qp_range: None,
+            keyframe_interval: 12,
+            encoder_threads: 1,
+            low_latency: false,
+        }
+    }
 }
 
+impl EncoderOptions {
+    /// Options that pick the next encoder after `encoder`, as returned by
+    /// [`VideoEncoder::encoder_name`], failed. Hardware encoders are skipped one after another until
+    /// only libx264 is left, VP9 and AV1 fall back to H.264. `None` if there is nothing left to try.
+    pub fn without_encoder(&self, encoder: &str) -> Option<Self> {
+        let mut options = *self;
+        match encoder {
+            "h264_vaapi" => options.try_vaapi = false,
+            "h264_nvenc" => options.try_nvenc = false,
+            "h264_videotoolbox" => options.try_videotoolbox = false,
+            "h264_mf" => options.try_mediafoundation = false,
+            "libvpx-vp9" | "libaom-av1" => options.codec = VideoCodec::H264,
+            _ => return None,
+        }
+        Some(options)
+    }
+}
+
+/// What the data passed to the callback of [`VideoEncoder::with_chunks`] holds. Large fragments
+/// may be passed in several pieces, all of them with the same `VideoChunk`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoChunk {
+    /// Initialization segment the video starts with.
+    Init,
+    /// Fragment with a frame that can be decoded on its own, given its presentation timestamp in
+    /// milliseconds since the encoder has been created.
+    Keyframe(u32),
+    /// Fragment with a frame that depends on earlier ones, or the trailer once the video ends.
+    Delta(u32),
+}
+
+/// Number of frames in a row that failed to be converted or encoded after which
+/// [`VideoEncoder::has_failed`] gives up on the encoder.
+const MAX_ENCODE_FAILURES: u32 = 5;
+
+/// Encodes frames as fragmented MP4 which is passed to the callback given to
+/// [`VideoEncoder::new`].
 pub struct VideoEncoder {
     handle: *mut c_void,
     width_in: usize,
     height_in: usize,
     width_out: usize,
     height_out: usize,
-    write_data: Box<dyn Fn(&[u8])>,
+    write_data: Box<dyn Fn(&[u8], VideoChunk)>,
+    // what is written while writing data, keyframes are only known once they have been encoded
+    chunk: VideoChunk,
+    // time spent in write_data while encoding the current frame
+    write_time: Duration,
     start_time: Instant,
+    // presentation timestamp of the last frame in milliseconds since start_time
+    last_pts: Option<c_int>,
+    // frames in a row that failed to be filled or encoded
+    failures: u32,
+    yuv_converter: Option<Arc<dyn YuvConverter>>,
+    color_matrix: ColorMatrix,
+    color_range: ColorRange,
 }
 
 impl VideoEncoder {
@@ -82,6 +2321,25 @@ impl VideoEncoder {
         height_out: usize,
         write_data: impl Fn(&[u8]) + 'static,
         options: EncoderOptions,
+    ) -> Result<Box<Self>, CError> {
+        Self::with_chunks(
+            width_in,
+            height_in,
+            width_out,
+            height_out,
+            move |data, _| write_data(data),
+            options,
+        )
+    }
+
+    /// Like [`VideoEncoder::new`] but also tell the callback what the data it gets holds.
+    pub fn with_chunks(
+        width_in: usize,
+        height_in: usize,
+        width_out: usize,
+        height_out: usize,
+        write_data: impl Fn(&[u8], VideoChunk) + 'static,
+        options: EncoderOptions,
     ) -> Result<Box<Self>, CError> {
         let mut video_encoder = Box::new(Self {
             handle: std::ptr::null_mut(),
@@ -89,8 +2347,15 @@ impl VideoEncoder {
             height_in,
             width_out,
             height_out,
-            write_data: Box::new(move |data| write_data(data)),
+            write_data: Box::new(write_data),
+            chunk: VideoChunk::Init,
+            write_time: Duration::ZERO,
             start_time: Instant::now(),
+            last_pts: None,
+            failures: 0,
+            yuv_converter: None,
+            color_matrix: options.color_matrix,
+            color_range: options.color_range,
         });
         let handle = unsafe {
             init_video_encoder(
@@ -103,6 +2368,20 @@ impl VideoEncoder {
                 options.try_nvenc.into(),
                 options.try_videotoolbox.into(),
                 options.try_mediafoundation.into(),
+                options.conversion_threads as c_int,
+                options.color_matrix as c_int,
+                options.color_range as c_int,
+                options.chroma_filter as c_int,
+                options.yuv444.into(),
+                options.grayscale.into(),
+                options.codec as c_int,
+                options.max_bitrate.unwrap_or(0) as c_int,
+                options.constant_quality.map_or(-1, |q| q as c_int),
+                options.qp_range.map_or(-1, |(min, _)| min as c_int),
+                options.qp_range.map_or(-1, |(_, max)| max as c_int),
+                options.keyframe_interval as c_int,
+                options.encoder_threads.max(1) as c_int,
+                options.low_latency.into(),
             )
         };
         video_encoder.handle = handle;
@@ -115,7 +2394,32 @@ impl VideoEncoder {
         Ok(video_encoder)
     }
 
-    pub fn encode(&mut self, pixel_provider: PixelProvider) {
+    /// Convert the pixels provided into the pixel format required by the encoder without encoding
+    /// them yet.
+    pub fn fill(&mut self, pixel_provider: PixelProvider) -> Result<(), CError> {
+        // the C side reads width_in x height_in pixels no matter what has been provided
+        let (width, height) = pixel_provider.size();
+        if width == 0 || height == 0 {
+            debug!("Frame is empty, keeping the previous frame.");
+            return Ok(());
+        }
+        if (width, height) != (self.width_in, self.height_in) {
+            return Err(CError::with_message(
+                CErrorCode::GenericError,
+                &format!(
+                    "Frame of size {}x{} does not match the encoder's input size {}x{}.",
+                    width, height, self.width_in, self.height_in
+                ),
+            ));
+        }
+        pixel_provider.check_data_len()?;
+
+        if let Some(converter) = self.yuv_converter.clone() {
+            if self.fill_custom(converter.as_ref(), &pixel_provider)? {
+                return Ok(());
+            }
+        }
+
         let mut err = CError::new();
         match pixel_provider {
             PixelProvider::BGR0(w, _, bgr0) => unsafe {
@@ -132,20 +2436,179 @@ impl VideoEncoder {
             },
         }
         if err.is_err() {
-            warn!("Failed to fill video frame: {}", err);
-            return;
+            return Err(err);
         }
-        unsafe {
-            encode_video_frame(
+        Ok(())
+    }
+
+    /// Convert frames with `converter` instead of swscale, see [`YuvConverter`].
+    pub fn set_yuv_converter(&mut self, converter: Option<Arc<dyn YuvConverter>>) {
+        self.yuv_converter = converter;
+    }
+
+    // returns false if the converter left the frame to the built-in conversion
+    fn fill_custom(
+        &mut self,
+        converter: &dyn YuvConverter,
+        pixel_provider: &PixelProvider,
+    ) -> Result<bool, CError> {
+        let mut data = [std::ptr::null_mut(); 3];
+        let mut linesize = [0; 3];
+        let mut err = CError::new();
+        let format = unsafe {
+            frame_planes(
                 self.handle,
-                (Instant::now() - self.start_time).as_millis() as c_int,
+                data.as_mut_ptr(),
+                linesize.as_mut_ptr(),
                 &mut err,
-            );
+            )
+        };
+        if err.is_err() {
+            return Err(err);
+        }
+        let format = match YuvFormat::from_c(format) {
+            Some(format) => format,
+            None => return Ok(false),
+        };
+        let chroma_rows = match format {
+            YuvFormat::Yuv444p => self.height_out,
+            YuvFormat::Yuv420p | YuvFormat::Nv12 => (self.height_out + 1) / 2,
+        };
+        // the planes are owned by the encoder and not touched by it until fill_converted
+        let frame = unsafe {
+            YuvFrame {
+                width: self.width_out,
+                height: self.height_out,
+                format,
+                color_matrix: self.color_matrix,
+                color_range: self.color_range,
+                planes: [
+                    plane(data[0], linesize[0], self.height_out),
+                    plane(data[1], linesize[1], chroma_rows),
+                    plane(data[2], linesize[2], chroma_rows),
+                ],
+                linesizes: linesize.map(|l| l.max(0) as usize),
+            }
+        };
+        if !converter.convert(pixel_provider, frame) {
+            return Ok(false);
+        }
+        unsafe { fill_converted(self.handle, &mut err) };
+        if err.is_err() {
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    /// Convert and encode a frame that has just been captured, empty frames are skipped.
+    pub fn encode(&mut self, pixel_provider: PixelProvider) {
+        self.encode_at(pixel_provider, Instant::now());
+    }
+
+    /// Convert and encode a frame captured at `captured_at`, which is used as the frame's
+    /// presentation timestamp. This keeps playback smooth if frames have been captured at irregular
+    /// intervals or some have been dropped.
+    pub fn encode_at(&mut self, pixel_provider: PixelProvider, captured_at: Instant) {
+        let (width, height) = pixel_provider.size();
+        if width == 0 || height == 0 {
+            debug!("Skipping empty frame.");
+            return;
         }
+        let span = debug_span!("fill_yuv", width, height, duration_us = field::Empty);
+        let start = Instant::now();
+        if let Err(err) = span.in_scope(|| self.fill(pixel_provider)) {
+            warn!("Failed to fill video frame: {}", err);
+            self.failures += 1;
+            return;
+        }
+        let elapsed = start.elapsed();
+        METRICS.conversion.record(elapsed);
+        span.record("duration_us", &(elapsed.as_micros() as u64));
+        drop(span);
+        self.encode_filled(captured_at);
+    }
+
+    /// Encode the frame passed to [`VideoEncoder::fill`] last, see [`VideoEncoder::encode_at`].
+    pub fn encode_filled(&mut self, captured_at: Instant) {
+        // frames captured before the encoder has been created get timestamp 0 and timestamps have
+        // to increase strictly, even if two frames have been captured within one millisecond
+        let pts = captured_at
+            .saturating_duration_since(self.start_time)
+            .as_millis() as c_int;
+        let pts = self.last_pts.map_or(pts, |last_pts| pts.max(last_pts + 1));
+        self.last_pts = Some(pts);
+        let span = debug_span!(
+            "encode",
+            width = self.width_out,
+            height = self.height_out,
+            pts_ms = pts,
+            duration_us = field::Empty
+        );
+        let start = Instant::now();
+        self.write_time = Duration::ZERO;
+        self.chunk = VideoChunk::Delta(pts as u32);
+        let mut err = CError::new();
+        span.in_scope(|| unsafe {
+            encode_video_frame(self.handle, pts, &mut err);
+        });
+        // sending the encoded data is measured separately
+        let elapsed = start.elapsed().saturating_sub(self.write_time);
+        METRICS.encode.record(elapsed);
+        span.record("duration_us", &(elapsed.as_micros() as u64));
+        drop(span);
         if err.is_err() {
             warn!("Failed to encode video frame: {}", err);
+            self.failures += 1;
             return;
         }
+        self.failures = 0;
+        METRICS.frames_encoded.inc();
+        METRICS.frame_age.record(captured_at.elapsed());
+    }
+
+    /// Name of the ffmpeg encoder in use, like those returned by [`available_encoders`], for
+    /// example `h264_nvenc`.
+    pub fn encoder_name(&self) -> &'static str {
+        let name = unsafe { video_encoder_name(self.handle) };
+        if name.is_null() {
+            return "unknown";
+        }
+        let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+        ENCODERS
+            .iter()
+            .copied()
+            .find(|encoder| *encoder == name)
+            .unwrap_or("unknown")
+    }
+
+    /// Encoding failed for several frames in a row, for example because the GPU has been reset,
+    /// and the encoder should be replaced, see [`EncoderOptions::without_encoder`].
+    pub fn has_failed(&self) -> bool {
+        self.failures >= MAX_ENCODE_FAILURES
+    }
+
+    /// Make sure the next frame encoded is a keyframe, that is a frame that can be decoded without
+    /// knowing any preceding frames.
+    pub fn request_keyframe(&mut self) {
+        unsafe { request_keyframe(self.handle) };
+    }
+
+    /// Change the maximum bitrate in kbit/s, this has no effect if no maximum bitrate has been set
+    /// via `EncoderOptions`.
+    pub fn set_max_bitrate(&mut self, max_bitrate: u32) {
+        unsafe { set_max_bitrate(self.handle, max_bitrate as c_int) };
+    }
+
+    /// Scale the quality between the worst the encoder supports at 0 and the quality it has been
+    /// created with at 1, the change applies from the next frame on. Currently only supported by
+    /// libx264, other encoders keep their quality.
+    pub fn set_quality(&mut self, quality: f64) {
+        unsafe { set_quality(self.handle, quality as c_float) };
+    }
+
+    /// Timestamps of the video are in milliseconds since this.
+    pub fn start_time(&self) -> Instant {
+        self.start_time
     }
 
     pub fn check_size(
@@ -165,7 +2628,1044 @@ impl VideoEncoder {
 impl Drop for VideoEncoder {
     fn drop(&mut self) {
         if !self.handle.is_null() {
+            // the trailer is written like another fragment, even if no frame has been encoded
+            self.chunk = VideoChunk::Delta(self.last_pts.unwrap_or(0) as u32);
             unsafe { destroy_video_encoder(self.handle) }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    // BGR0 frame of `width` x `height` pixels colored by `rgb`
+    fn bgr0(width: usize, height: usize, mut rgb: impl FnMut(usize, usize) -> [u8; 3]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                let [r, g, b] = rgb(x, y);
+                data.extend_from_slice(&[b, g, r, 255]);
+            }
+        }
+        data
+    }
+
+    // the Y, U and V planes of the frame without padding
+    fn to_i420(frame: PixelProvider, opts: ConversionOptions) -> [Vec<u8>; 3] {
+        let (width, height) = frame.size();
+        let (mut y, mut u, mut v, line_sizes) = alloc_yuv(width, height);
+        bgra_to_i420(frame, [&mut y, &mut u, &mut v], line_sizes, opts).unwrap();
+        let chroma = ((width + 1) / 2, (height + 1) / 2);
+        let sizes = [(width, height), chroma, chroma];
+        let mut planes = [y, u, v];
+        for ((plane, line_size), (w, h)) in planes.iter_mut().zip(line_sizes).zip(sizes) {
+            *plane = plane
+                .chunks(line_size)
+                .take(h)
+                .flat_map(|row| &row[..w])
+                .copied()
+                .collect();
+        }
+        planes
+    }
+
+    fn opts(color_matrix: ColorMatrix, color_range: ColorRange) -> ConversionOptions {
+        ConversionOptions {
+            color_matrix,
+            color_range,
+            ..Default::default()
+        }
+    }
+
+    const COMBINATIONS: [(ColorMatrix, ColorRange); 4] = [
+        (ColorMatrix::Bt601, ColorRange::Limited),
+        (ColorMatrix::Bt601, ColorRange::Full),
+        (ColorMatrix::Bt709, ColorRange::Limited),
+        (ColorMatrix::Bt709, ColorRange::Full),
+    ];
+
+    // Y, U and V of a color as given by the definitions of BT.601 and BT.709 and the scaling of the
+    // ranges, written down independently of the conversion
+    fn reference(rgb: [f64; 3], matrix: ColorMatrix, range: ColorRange) -> [f64; 3] {
+        let [r, g, b] = rgb.map(|c| c / 255.0);
+        let (luma, pb, pr) = match matrix {
+            ColorMatrix::Bt601 => {
+                let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+                (luma, (b - luma) / 1.772, (r - luma) / 1.402)
+            }
+            ColorMatrix::Bt709 => {
+                let luma = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+                (luma, (b - luma) / 1.8556, (r - luma) / 1.5748)
+            }
+        };
+        let [y, u, v] = match range {
+            ColorRange::Limited => [16.0 + 219.0 * luma, 128.0 + 224.0 * pb, 128.0 + 224.0 * pr],
+            ColorRange::Full => [255.0 * luma, 128.0 + 255.0 * pb, 128.0 + 255.0 * pr],
+        };
+        [y, u, v].map(|c| c.clamp(0.0, 255.0))
+    }
+
+    // deterministic pseudo random bytes, xorshift
+    fn random_bytes(seed: u32) -> impl FnMut() -> u8 {
+        let mut state = seed.max(1);
+        move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 24) as u8
+        }
+    }
+
+    #[test]
+    fn known_colors() {
+        // red, green, blue, white, black, purple
+        let colors = [
+            [255, 0, 0],
+            [0, 255, 0],
+            [0, 0, 255],
+            [255, 255, 255],
+            [0, 0, 0],
+            [128, 64, 192],
+        ];
+        let expected: [[[u8; 3]; 6]; 4] = [
+            [
+                [81, 90, 240],
+                [145, 54, 34],
+                [41, 240, 110],
+                [235, 128, 128],
+                [16, 128, 128],
+                [100, 175, 147],
+            ],
+            [
+                [76, 85, 255],
+                [150, 44, 21],
+                [29, 255, 107],
+                [255, 128, 128],
+                [0, 128, 128],
+                [98, 181, 150],
+            ],
+            [
+                [63, 102, 240],
+                [173, 42, 26],
+                [32, 240, 118],
+                [235, 128, 128],
+                [16, 128, 128],
+                [91, 178, 151],
+            ],
+            [
+                [54, 99, 255],
+                [182, 30, 12],
+                [18, 255, 116],
+                [255, 128, 128],
+                [0, 128, 128],
+                [87, 185, 154],
+            ],
+        ];
+        for ((matrix, range), expected) in COMBINATIONS.into_iter().zip(expected) {
+            for (rgb, yuv) in colors.into_iter().zip(expected) {
+                let data = bgr0(2, 2, |_, _| rgb);
+                let planes = to_i420(PixelProvider::BGR0(2, 2, &data), opts(matrix, range));
+                assert_eq!(
+                    planes[0], [yuv[0]; 4],
+                    "Y of {:?} in {:?} {:?}",
+                    rgb, matrix, range
+                );
+                assert_eq!(
+                    planes[1],
+                    [yuv[1]],
+                    "U of {:?} in {:?} {:?}",
+                    rgb,
+                    matrix,
+                    range
+                );
+                assert_eq!(
+                    planes[2],
+                    [yuv[2]],
+                    "V of {:?} in {:?} {:?}",
+                    rgb,
+                    matrix,
+                    range
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn matches_reference() {
+        let mut random = random_bytes(0x5eed);
+        let mut images: Vec<(usize, usize, Vec<u8>)> = Vec::new();
+        for (width, height) in [(16, 16), (33, 17), (1, 1), (7, 3), (2, 9)] {
+            let data = bgr0(width, height, |_, _| [random(), random(), random()]);
+            images.push((width, height, data));
+        }
+        // gradients and alternating extremes, which maximize the rounding error and the
+        // difference between neighbouring pixels averaged into chroma samples
+        images.push((
+            256,
+            3,
+            bgr0(256, 3, |x, y| [x as u8, 255 - x as u8, (x * y) as u8]),
+        ));
+        images.push((
+            9,
+            5,
+            bgr0(9, 5, |x, y| {
+                if (x + y) % 2 == 0 {
+                    [255, 255, 255]
+                } else {
+                    [0, 0, 0]
+                }
+            }),
+        ));
+        images.push((
+            6,
+            6,
+            bgr0(6, 6, |x, y| [255 * (x % 2) as u8, 255 * (y % 2) as u8, 255]),
+        ));
+
+        for (width, height, data) in &images {
+            let (width, height) = (*width, *height);
+            let rgb = |x: usize, y: usize| {
+                let i = (y * width + x) * 4;
+                [data[i + 2], data[i + 1], data[i]].map(f64::from)
+            };
+            for (matrix, range) in COMBINATIONS {
+                let planes = to_i420(
+                    PixelProvider::BGR0(width, height, data),
+                    opts(matrix, range),
+                );
+                let mut max_error = [0.0f64; 3];
+                for y in 0..height {
+                    for x in 0..width {
+                        let expected = reference(rgb(x, y), matrix, range)[0];
+                        let error = (planes[0][y * width + x] as f64 - expected).abs();
+                        max_error[0] = max_error[0].max(error);
+                    }
+                }
+                // chroma samples are computed from the average of the 2x2 pixels they cover,
+                // pixels beyond the edges repeat those at the edges
+                let chroma_width = (width + 1) / 2;
+                for cy in 0..(height + 1) / 2 {
+                    for cx in 0..chroma_width {
+                        let mut sum = [0.0; 3];
+                        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                            let p =
+                                rgb((2 * cx + dx).min(width - 1), (2 * cy + dy).min(height - 1));
+                            for c in 0..3 {
+                                sum[c] += p[c] / 4.0;
+                            }
+                        }
+                        let expected = reference(sum, matrix, range);
+                        for plane in 1..3 {
+                            let actual = planes[plane][cy * chroma_width + cx] as f64;
+                            max_error[plane] =
+                                max_error[plane].max((actual - expected[plane]).abs());
+                        }
+                    }
+                }
+                // the reference is not rounded, so being off by half a step is exact
+                for (plane, error) in ["Y", "U", "V"].into_iter().zip(max_error) {
+                    assert!(
+                        error <= 1.0,
+                        "{} of a {}x{} image in {:?} {:?} is off by {}",
+                        plane,
+                        width,
+                        height,
+                        matrix,
+                        range,
+                        error
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nv12_interleaves_i420() {
+        let mut random = random_bytes(85);
+        for (width, height) in [(8, 4), (5, 3), (1, 1)] {
+            let data = bgr0(width, height, |_, _| [random(), random(), random()]);
+            let frame = || PixelProvider::BGR0(width, height, &data);
+            let [y, u, v] = to_i420(frame(), Default::default());
+            // line sizes larger than needed to check they are respected
+            let chroma = ((width + 1) / 2, (height + 1) / 2);
+            let line_sizes = [width + 3, chroma.0 * 2 + 5];
+            let mut y_plane = vec![0; line_sizes[0] * height];
+            let mut uv_plane = vec![0; line_sizes[1] * chroma.1];
+            bgra_to_nv12(
+                frame(),
+                [&mut y_plane, &mut uv_plane],
+                line_sizes,
+                Default::default(),
+            )
+            .unwrap();
+            for row in 0..height {
+                let start = row * line_sizes[0];
+                assert_eq!(
+                    y_plane[start..start + width],
+                    y[row * width..(row + 1) * width]
+                );
+            }
+            for cy in 0..chroma.1 {
+                for cx in 0..chroma.0 {
+                    let i = cy * line_sizes[1] + 2 * cx;
+                    assert_eq!(uv_plane[i], u[cy * chroma.0 + cx], "U at {}, {}", cx, cy);
+                    assert_eq!(
+                        uv_plane[i + 1],
+                        v[cy * chroma.0 + cx],
+                        "V at {}, {}",
+                        cx,
+                        cy
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn odd_sizes() {
+        for (width, height) in [(3, 3), (5, 7), (1, 2), (7, 1)] {
+            let data = bgr0(width, height, |x, y| [255 * ((x + y) % 2) as u8; 3]);
+            let [y, u, v] = to_i420(
+                PixelProvider::BGR0(width, height, &data),
+                Default::default(),
+            );
+            let chroma = ((width + 1) / 2) * ((height + 1) / 2);
+            assert_eq!(
+                (y.len(), u.len(), v.len()),
+                (width * height, chroma, chroma)
+            );
+            // black and white, whatever the chroma samples at the edges cover stays gray
+            for (i, &luma) in y.iter().enumerate() {
+                let expected = if (i % width + i / width) % 2 == 0 {
+                    16
+                } else {
+                    235
+                };
+                assert_eq!(luma, expected, "Y of {}x{} at {}", width, height, i);
+            }
+            assert!(u.iter().chain(&v).all(|c| (127..=129).contains(c)));
+        }
+    }
+
+    #[test]
+    fn too_small_frames_and_planes() {
+        let data = bgr0(5, 7, |_, _| [0, 0, 0]);
+        // convert to planes allocated for a frame of `luma_rows` and one of `chroma_rows` rows
+        let convert = |frame, luma_rows, chroma_rows| {
+            let (mut y, _, _, line_sizes) = alloc_yuv(5, luma_rows);
+            let (_, mut u, mut v, _) = alloc_yuv(5, chroma_rows);
+            bgra_to_i420(
+                frame,
+                [&mut y, &mut u, &mut v],
+                line_sizes,
+                Default::default(),
+            )
+        };
+        assert!(convert(PixelProvider::BGR0(5, 7, &data), 7, 7).is_ok());
+        assert!(
+            convert(PixelProvider::BGR0(5, 7, &data), 7, 8).is_ok(),
+            "8 rows have as many rows of chroma as 7"
+        );
+        assert!(convert(PixelProvider::BGR0(5, 7, &data[..data.len() - 1]), 7, 7).is_err());
+        assert!(convert(PixelProvider::BGR0(5, 7, &data), 6, 7).is_err());
+        assert!(convert(PixelProvider::BGR0(5, 7, &data), 7, 6).is_err());
+        assert!(
+            convert(PixelProvider::BGR0S(5, 7, 16, &data), 7, 7).is_err(),
+            "stride is smaller than a row"
+        );
+    }
+
+    #[test]
+    fn padded_rows() {
+        let mut random = random_bytes(20);
+        let (width, height) = (5, 3);
+        let data = bgr0(width, height, |_, _| [random(), random(), random()]);
+        let stride = width * 4 + 12;
+        // the padding of the last row may be missing
+        let mut padded = Vec::new();
+        for row in data.chunks(width * 4) {
+            padded.extend_from_slice(row);
+            padded.extend((0..12).map(|_| random()));
+        }
+        padded.truncate(padded.len() - 12);
+        assert_eq!(
+            to_i420(
+                PixelProvider::BGR0S(width, height, stride, &padded),
+                Default::default()
+            ),
+            to_i420(
+                PixelProvider::BGR0(width, height, &data),
+                Default::default()
+            )
+        );
+    }
+
+    #[test]
+    fn rgb_formats() {
+        let mut random = random_bytes(98);
+        let (width, height) = (7, 4);
+        let data = bgr0(width, height, |_, _| [random(), random(), random()]);
+        let rgb0: Vec<u8> = data
+            .chunks(4)
+            .flat_map(|p| [p[2], p[1], p[0], random()])
+            .collect();
+        let rgb: Vec<u8> = data.chunks(4).flat_map(|p| [p[2], p[1], p[0]]).collect();
+        for (matrix, range) in COMBINATIONS {
+            let bgr0 = to_i420(
+                PixelProvider::BGR0(width, height, &data),
+                opts(matrix, range),
+            );
+            let rgb0 = to_i420(
+                PixelProvider::RGB0(width, height, &rgb0),
+                opts(matrix, range),
+            );
+            let rgb = to_i420(PixelProvider::RGB(width, height, &rgb), opts(matrix, range));
+            assert_eq!(rgb0, bgr0, "RGB0 in {:?} {:?}", matrix, range);
+            assert_eq!(rgb, bgr0, "RGB in {:?} {:?}", matrix, range);
+        }
+    }
+
+    #[test]
+    fn empty_frames() {
+        for (width, height) in [(0, 0), (0, 3), (4, 0)] {
+            let frame = || PixelProvider::BGR0(width, height, &[]);
+            let (mut y, mut u, mut v, line_sizes) = alloc_yuv(width, height);
+            bgra_to_i420(
+                frame(),
+                [&mut y, &mut u, &mut v],
+                line_sizes,
+                Default::default(),
+            )
+            .unwrap();
+            bgra_to_nv12(
+                frame(),
+                [&mut y, &mut u],
+                [line_sizes[0]; 2],
+                Default::default(),
+            )
+            .unwrap();
+            let transform = FrameTransform {
+                rotation: Rotation::Deg90,
+                hflip: true,
+                vflip: false,
+            };
+            let mut buf = Vec::new();
+            let rotated = transform.apply(frame(), &mut buf);
+            assert_eq!(rotated.size(), (height, width));
+            assert!(rotated.data().is_empty());
+        }
+    }
+
+    #[test]
+    fn yuv_planes_reused() {
+        let mut planes = YuvPlanes::new();
+        let data = bgr0(6, 4, |x, y| [(x * 40) as u8, (y * 60) as u8, 128]);
+        planes
+            .convert(PixelProvider::BGR0(6, 4, &data), Default::default())
+            .unwrap();
+        let first = planes.yuv_planes().0.as_ptr();
+        let other = bgr0(6, 4, |x, y| [(y * 60) as u8, 255, (x * 40) as u8]);
+        planes
+            .convert(PixelProvider::BGR0(6, 4, &other), Default::default())
+            .unwrap();
+        assert_eq!(planes.yuv_planes().0.as_ptr(), first);
+        let [y, u, v] = to_i420(PixelProvider::BGR0(6, 4, &other), Default::default());
+        let line_sizes = planes.line_sizes();
+        let (y_plane, u_plane, v_plane) = planes.yuv_planes();
+        assert_eq!(&y_plane[line_sizes[0]..line_sizes[0] + 6], &y[6..12]);
+        assert_eq!(&u_plane[line_sizes[1]..line_sizes[1] + 3], &u[3..6]);
+        assert_eq!(&v_plane[..3], &v[..3]);
+
+        // planes grow along with the frames
+        let large = bgr0(70, 9, |_, _| [0, 0, 0]);
+        planes
+            .convert(PixelProvider::BGR0(70, 9, &large), Default::default())
+            .unwrap();
+        assert_eq!(planes.size(), (70, 9));
+        assert_eq!(planes.line_sizes(), plane_line_sizes(70));
+        assert!(planes.yuv_planes().2.len() >= planes.line_sizes()[2] * 5);
+    }
+
+    #[test]
+    fn weighted_chroma_filter() {
+        // red left of blue, the edge falls between two chroma samples
+        let data = bgr0(8, 4, |x, _| if x < 4 { [255, 0, 0] } else { [0, 0, 255] });
+        let convert = |chroma_filter| {
+            let opts = ConversionOptions {
+                chroma_filter,
+                ..Default::default()
+            };
+            to_i420(PixelProvider::BGR0(8, 4, &data), opts)
+        };
+        let [y_box, u_box, v_box] = convert(ChromaFilter::Box);
+        let [y_weighted, u_weighted, v_weighted] = convert(ChromaFilter::Weighted);
+        assert_eq!(
+            y_box, y_weighted,
+            "luma does not depend on the chroma filter"
+        );
+        // far from the edge both filters agree, close to it the weighted one blends the colors
+        // while the box filter does not
+        for row in 0..2 {
+            let u_box = &u_box[row * 4..row * 4 + 4];
+            let u_weighted = &u_weighted[row * 4..row * 4 + 4];
+            let v_weighted = &v_weighted[row * 4..row * 4 + 4];
+            assert_eq!(u_box, [90, 90, 240, 240]);
+            assert_eq!(&v_box[row * 4..row * 4 + 4], [240, 240, 110, 110]);
+            assert_eq!((u_weighted[0], u_weighted[3]), (90, 240));
+            assert!(u_weighted[0] < u_weighted[1] && u_weighted[1] < u_weighted[2]);
+            assert!(u_weighted[2] < u_weighted[3]);
+            assert!(v_weighted[1] < 240 && v_weighted[2] > 110);
+        }
+    }
+
+    #[test]
+    fn simd_matches_scalar() {
+        // Y, U and V planes of equal line sizes as computed by the scalar code or SIMD code
+        fn convert(frame: PixelProvider, opts: ConversionOptions, simd: bool) -> [Vec<u8>; 3] {
+            let (width, height) = frame.size();
+            let chroma_width = (width + 1) / 2;
+            let mut y = vec![0; width * height];
+            let mut u = vec![0; chroma_width * (height + 1) / 2];
+            let mut v = u.clone();
+            bgra_to_yuv420(frame, &mut y, width, opts, simd, |cx, cy, cu, cv| {
+                u[cy * chroma_width + cx] = cu;
+                v[cy * chroma_width + cx] = cv;
+            });
+            [y, u, v]
+        }
+
+        let mut random = random_bytes(0x51d);
+        // sizes covering several SIMD blocks and a remainder as well as frames too small for them
+        for (width, height) in [(64, 4), (37, 5), (16, 2), (23, 3), (7, 8), (1, 1)] {
+            let data = bgr0(width, height, |_, _| [random(), random(), random()]);
+            for format in 0..3 {
+                let frame = || match format {
+                    0 => PixelProvider::BGR0(width, height, &data),
+                    1 => PixelProvider::RGB0(width, height, &data),
+                    _ => PixelProvider::BGR0S(width / 2, height, 4 * width, &data),
+                };
+                for (matrix, range) in COMBINATIONS {
+                    for chroma_filter in [ChromaFilter::Box, ChromaFilter::Weighted] {
+                        let opts = ConversionOptions {
+                            chroma_filter,
+                            ..opts(matrix, range)
+                        };
+                        let scalar = convert(frame(), opts, false);
+                        let simd = convert(frame(), opts, true);
+                        for (plane, (scalar, simd)) in
+                            ["Y", "U", "V"].iter().zip(scalar.iter().zip(&simd))
+                        {
+                            for (i, (a, b)) in scalar.iter().zip(simd).enumerate() {
+                                assert!(
+                                    a.abs_diff(*b) <= 1,
+                                    "{} sample {} of {:?} {:?} {:?} {:?}: {} != {}",
+                                    plane,
+                                    i,
+                                    frame().size(),
+                                    matrix,
+                                    range,
+                                    chroma_filter,
+                                    a,
+                                    b
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn sse2_matches_avx2() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        let mut random = random_bytes(0xa5);
+        let rows: Vec<Vec<u8>> = (0..2)
+            .map(|_| (0..4 * 40).map(|_| random()).collect())
+            .collect();
+        let rows = [&rows[0][..], &rows[1][..]];
+        let shifts = [16, 8, 0];
+        let weights = x86::Weights {
+            offset: 16.5,
+            r: 0.25,
+            g: 0.5,
+            b: -0.125,
+        };
+        let luma = |simd: x86::Simd| {
+            let mut out = [0; 40];
+            let done = simd.luma_row(rows[0], &mut out, shifts, weights);
+            (done, out)
+        };
+        let chroma = |simd: x86::Simd| {
+            let mut out = [(0, 0); 20];
+            let done = simd.chroma_rows(rows, 20, shifts, [weights; 2], &mut |cx, u, v| {
+                out[cx] = (u, v)
+            });
+            (done, out)
+        };
+        let ((sse2_done, sse2), (avx2_done, avx2)) = (luma(x86::Simd::Sse2), luma(x86::Simd::Avx2));
+        assert_eq!((sse2_done, avx2_done), (40, 40));
+        assert_eq!(sse2, avx2);
+        let ((sse2_done, sse2), (avx2_done, avx2)) =
+            (chroma(x86::Simd::Sse2), chroma(x86::Simd::Avx2));
+        assert_eq!((sse2_done, avx2_done), (20, 16));
+        assert_eq!(sse2[..16], avx2[..16]);
+    }
+
+    const ROTATIONS: [Rotation; 4] = [
+        Rotation::Deg0,
+        Rotation::Deg90,
+        Rotation::Deg180,
+        Rotation::Deg270,
+    ];
+
+    // image whose pixels are all different, so each pixel tells where it came from
+    fn numbered(width: usize, height: usize) -> Vec<u8> {
+        bgr0(width, height, |x, y| [x as u8, y as u8, 0])
+    }
+
+    #[test]
+    fn rotation() {
+        let (width, height) = (3, 2);
+        let data = numbered(width, height);
+        // the top left pixel after rotating clockwise
+        let top_left = [(0, 0), (0, 1), (2, 1), (2, 0)];
+        for (rotation, top_left) in ROTATIONS.into_iter().zip(top_left) {
+            let transform = FrameTransform {
+                rotation,
+                ..Default::default()
+            };
+            let mut buf = Vec::new();
+            let rotated = transform.apply(PixelProvider::BGR0(width, height, &data), &mut buf);
+            let (width_out, height_out) = rotated.size();
+            assert_eq!((width_out, height_out), rotation.size(width, height));
+            let pixel = &rotated.data()[..4];
+            assert_eq!((pixel[2], pixel[1]), top_left, "{:?}", rotation);
+            for y in 0..height {
+                for x in 0..width {
+                    let (x_out, y_out) =
+                        rotation.rotate_pixel(x as i32, y as i32, width as i32, height as i32);
+                    let i = (y_out as usize * width_out + x_out as usize) * 4;
+                    assert_eq!(
+                        (rotated.data()[i + 2], rotated.data()[i + 1]),
+                        (x as u8, y as u8),
+                        "{:?} of {}, {}",
+                        rotation,
+                        x,
+                        y
+                    );
+                }
+            }
+
+            // rotating back gives the original image
+            let back = match rotation {
+                Rotation::Deg90 => Rotation::Deg270,
+                Rotation::Deg270 => Rotation::Deg90,
+                rotation => rotation,
+            };
+            let mut buf_back = Vec::new();
+            let back = FrameTransform {
+                rotation: back,
+                ..Default::default()
+            }
+            .apply(rotated, &mut buf_back);
+            assert_eq!(back.size(), (width, height));
+            assert_eq!(back.data(), &data[..], "{:?} and back", rotation);
+        }
+    }
+
+    #[test]
+    fn unrotate_vector() {
+        // moving right on the video rotated clockwise by 90 degrees is moving up on the screen
+        let expected = [(1.0, 0.0), (0.0, -1.0), (-1.0, 0.0), (0.0, 1.0)];
+        for (rotation, expected) in ROTATIONS.into_iter().zip(expected) {
+            assert_eq!(
+                rotation.unrotate_vector(1.0, 0.0),
+                expected,
+                "{:?}",
+                rotation
+            );
+        }
+    }
+
+    #[test]
+    fn frame_transform() {
+        let (width, height) = (4, 3);
+        let data = numbered(width, height);
+        for rotation in ROTATIONS {
+            for (hflip, vflip) in [(false, false), (true, false), (false, true), (true, true)] {
+                let transform = FrameTransform {
+                    rotation,
+                    hflip,
+                    vflip,
+                };
+                let mut buf = Vec::new();
+                let out = transform.apply(PixelProvider::BGR0(width, height, &data), &mut buf);
+                let (width_out, height_out) = out.size();
+                assert_eq!((width_out, height_out), transform.size(width, height));
+                for y in 0..height {
+                    for x in 0..width {
+                        let (x_out, y_out) = transform.transform_pixel(
+                            x as i32,
+                            y as i32,
+                            width as i32,
+                            height as i32,
+                        );
+                        let i = (y_out as usize * width_out + x_out as usize) * 4;
+                        assert_eq!(
+                            (out.data()[i + 2], out.data()[i + 1]),
+                            (x as u8, y as u8),
+                            "{:?} of {}, {}",
+                            transform,
+                            x,
+                            y
+                        );
+                        // the center of the pixel maps back to where it has been captured
+                        let (px, py) = transform.untransform_point(
+                            (x_out as f64 + 0.5) / width_out as f64,
+                            (y_out as f64 + 0.5) / height_out as f64,
+                        );
+                        assert!((px * width as f64 - x as f64 - 0.5).abs() < 1e-9);
+                        assert!((py * height as f64 - y as f64 - 0.5).abs() < 1e-9);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn flips_mirror_the_video_as_shown() {
+        // flipping horizontally mirrors left and right of the rotated video
+        let transform = FrameTransform {
+            rotation: Rotation::Deg90,
+            hflip: true,
+            vflip: false,
+        };
+        assert_eq!(transform.transform_pixel(0, 0, 4, 3), (0, 0));
+        assert_eq!(transform.untransform_vector(1.0, 0.0), (0.0, 1.0));
+        let transform = FrameTransform {
+            vflip: true,
+            ..transform
+        };
+        assert_eq!(transform.transform_pixel(0, 0, 4, 3), (0, 3));
+        // flipping both ways rotates by 180 degrees, so this is rotating by 270 degrees
+        assert_eq!(transform.untransform_vector(0.0, 1.0), (-1.0, 0.0));
+    }
+
+    #[test]
+    fn letterbox_layout() {
+        // 16:9 into 4:3 adds bars at the top and bottom
+        let layout = LetterboxLayout::new(1920, 1080, 1024, 768);
+        assert_eq!(
+            (layout.width, layout.height, layout.x, layout.y),
+            (1920, 1440, 0, 180)
+        );
+        assert_eq!(layout.content_relative(), (0.0, 0.125, 1.0, 0.75));
+        // 4:3 into 16:9 adds bars to the left and right
+        let layout = LetterboxLayout::new(1024, 768, 1920, 1080);
+        assert_eq!(
+            (layout.width, layout.height, layout.x, layout.y),
+            (1365, 768, 170, 0)
+        );
+        for (content, target) in [((1920, 1080), (1280, 720)), ((7, 3), (0, 0))] {
+            let layout = LetterboxLayout::new(content.0, content.1, target.0, target.1);
+            assert!(!layout.is_padded(), "{:?} into {:?}", content, target);
+        }
+    }
+
+    #[test]
+    fn letterbox() {
+        let data = numbered(4, 2);
+        let letterbox = Letterbox::new(Color { r: 1, g: 2, b: 3 });
+        let mut buf = Vec::new();
+        let (padded, layout) = letterbox.apply(PixelProvider::BGR0(4, 2, &data), (1, 1), &mut buf);
+        assert_eq!(padded.size(), (4, 4));
+        assert_eq!((layout.x, layout.y), (0, 1));
+        let rows: Vec<&[u8]> = padded.data().chunks(16).collect();
+        assert_eq!(rows[0], [3, 2, 1, 255].repeat(4));
+        assert_eq!(rows[1..3].concat(), data);
+        assert_eq!(rows[3], [3, 2, 1, 255].repeat(4));
+    }
+
+    // libx264 encoder converting frames of `size_in` to frames of `size_out` pixels
+    fn encoder(
+        (width_in, height_in): (usize, usize),
+        (width_out, height_out): (usize, usize),
+        options: EncoderOptions,
+    ) -> Box<VideoEncoder> {
+        VideoEncoder::new(width_in, height_in, width_out, height_out, |_| {}, options).unwrap()
+    }
+
+    // the Y, U and V planes of the frame the encoder filled last, without padding
+    fn filled_planes(video_encoder: &mut VideoEncoder) -> [Vec<u8>; 3] {
+        let mut data = [std::ptr::null_mut(); 3];
+        let mut linesize = [0; 3];
+        let mut err = CError::new();
+        let format = unsafe {
+            frame_planes(
+                video_encoder.handle,
+                data.as_mut_ptr(),
+                linesize.as_mut_ptr(),
+                &mut err,
+            )
+        };
+        assert!(!err.is_err(), "{}", err);
+        assert_eq!(YuvFormat::from_c(format), Some(YuvFormat::Yuv420p));
+        let (width, height) = (video_encoder.width_out, video_encoder.height_out);
+        let chroma = ((width + 1) / 2, (height + 1) / 2);
+        let sizes = [(width, height), chroma, chroma];
+        let mut planes: [Vec<u8>; 3] = Default::default();
+        for (i, (w, h)) in sizes.into_iter().enumerate() {
+            let plane = unsafe { plane(data[i], linesize[i], h) };
+            planes[i] = plane
+                .chunks(linesize[i] as usize)
+                .flat_map(|row| &row[..w])
+                .copied()
+                .collect();
+        }
+        planes
+    }
+
+    #[test]
+    fn conversion_threads() {
+        let mut random = random_bytes(2);
+        for (size_in, size_out) in [
+            ((333, 177), (332, 176)),
+            ((641, 361), (320, 180)),
+            ((255, 129), (256, 130)),
+        ] {
+            let data = bgr0(size_in.0, size_in.1, |_, _| [random(), random(), random()]);
+            let convert = |conversion_threads| {
+                let options = EncoderOptions {
+                    conversion_threads,
+                    ..Default::default()
+                };
+                let mut video_encoder = encoder(size_in, size_out, options);
+                let frame = PixelProvider::BGR0(size_in.0, size_in.1, &data);
+                video_encoder.fill(frame).unwrap();
+                filled_planes(&mut video_encoder)
+            };
+            let serial = convert(1);
+            for threads in [2, 3, 8] {
+                assert!(
+                    convert(threads) == serial,
+                    "{:?} to {:?} with {} threads",
+                    size_in,
+                    size_out,
+                    threads
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn downscaling_averages() {
+        let mut random = random_bytes(16);
+        // alternating black and white pixels, which sampling instead of averaging turns black or
+        // white, and random shades of gray
+        let checkerboard = |x: usize, y: usize| [255 * ((x + y) % 2) as u8; 3];
+        let images = [bgr0(16, 8, checkerboard), bgr0(16, 8, |_, _| [random(); 3])];
+        for data in &images {
+            let options = EncoderOptions {
+                color_range: ColorRange::Full,
+                ..Default::default()
+            };
+            let mut video_encoder = encoder((16, 8), (8, 4), options);
+            video_encoder
+                .fill(PixelProvider::BGR0(16, 8, data))
+                .unwrap();
+            let [y, u, v] = filled_planes(&mut video_encoder);
+            // gray in full range is its own luma
+            let gray = |x: usize, y: usize| data[(y * 16 + x) * 4] as f64;
+            for oy in 0..4 {
+                for ox in 0..8 {
+                    let (x, y0) = (2 * ox, 2 * oy);
+                    let mean =
+                        (gray(x, y0) + gray(x + 1, y0) + gray(x, y0 + 1) + gray(x + 1, y0 + 1))
+                            / 4.0;
+                    let luma = y[oy * 8 + ox] as f64;
+                    assert!(
+                        (luma - mean).abs() <= 2.0,
+                        "{} instead of {} at {}, {}",
+                        luma,
+                        mean,
+                        ox,
+                        oy
+                    );
+                }
+            }
+            assert!(u.iter().chain(&v).all(|c| (126..=130).contains(c)));
+        }
+    }
+
+    #[test]
+    fn requested_keyframe() {
+        let chunks = Rc::new(RefCell::new(Vec::new()));
+        let options = EncoderOptions {
+            keyframe_interval: 1000,
+            ..Default::default()
+        };
+        let mut video_encoder = {
+            let chunks = chunks.clone();
+            VideoEncoder::with_chunks(
+                64,
+                64,
+                64,
+                64,
+                move |_, chunk| chunks.borrow_mut().push(chunk),
+                options,
+            )
+            .unwrap()
+        };
+        let mut random = random_bytes(29);
+        let mut encode = |video_encoder: &mut VideoEncoder, i: u64| {
+            let data = bgr0(64, 64, |_, _| [random(), random(), random()]);
+            let captured_at = video_encoder.start_time() + Duration::from_millis(40 * i);
+            chunks.borrow_mut().clear();
+            video_encoder.encode_at(PixelProvider::BGR0(64, 64, &data), captured_at);
+            let chunks = chunks.borrow();
+            assert!(
+                !chunks.is_empty(),
+                "frame {} has not been written right away",
+                i
+            );
+            chunks.contains(&VideoChunk::Keyframe(40 * i as u32))
+        };
+        assert!(
+            encode(&mut video_encoder, 0),
+            "the video starts with a keyframe"
+        );
+        for i in 1..5 {
+            assert!(!encode(&mut video_encoder, i));
+        }
+        video_encoder.request_keyframe();
+        assert!(
+            encode(&mut video_encoder, 5),
+            "the frame after the request is a keyframe"
+        );
+        assert!(!encode(&mut video_encoder, 6));
+    }
+
+    #[test]
+    fn change_detector() {
+        let black = bgr0(4, 4, |_, _| [0, 0, 0]);
+        let white = bgr0(4, 4, |_, _| [255, 255, 255]);
+        let frame = |data| PixelProvider::BGR0(4, 4, data);
+        let mut detector = ChangeDetector::new(None);
+        assert_eq!(detector.check(&frame(&black)), FrameChange::Changed);
+        for _ in 0..10 {
+            assert_eq!(detector.check(&frame(&black)), FrameChange::Unchanged);
+        }
+        assert_eq!(detector.check(&frame(&white)), FrameChange::Changed);
+        detector.reset();
+        assert_eq!(detector.check(&frame(&white)), FrameChange::Changed);
+        // a different stride or size is a different frame even if the data is the same
+        assert_eq!(
+            detector.check(&PixelProvider::BGR0(8, 2, &white)),
+            FrameChange::Changed
+        );
+
+        let mut detector = ChangeDetector::new(Some(3));
+        assert_eq!(detector.check(&frame(&black)), FrameChange::Changed);
+        for _ in 0..2 {
+            let changes: Vec<_> = (0..4).map(|_| detector.check(&frame(&black))).collect();
+            assert_eq!(
+                changes,
+                [
+                    FrameChange::Unchanged,
+                    FrameChange::Unchanged,
+                    FrameChange::Unchanged,
+                    FrameChange::Refresh
+                ]
+            );
+        }
+        assert_eq!(detector.check(&frame(&white)), FrameChange::Changed);
+        assert_eq!(detector.check(&frame(&white)), FrameChange::Unchanged);
+    }
+
+    #[test]
+    fn privacy_regions_are_hidden() {
+        let (width, height) = (8, 4);
+        let white = bgr0(width, height, |_, _| [255, 255, 255]);
+        // the top right quarter of the capturable
+        let region = |style| PrivacyRegion {
+            rect: (0.5, 0.0, 0.5, 0.5),
+            style,
+        };
+        let hidden = |data: &[u8], stride: usize| {
+            let mut hidden = Vec::new();
+            for y in 0..height {
+                for x in 0..width {
+                    let i = y * stride + x * 4;
+                    hidden.push(data[i..i + 3] != [255, 255, 255]);
+                }
+            }
+            hidden
+        };
+
+        let mask = PrivacyMask::new(vec![region(PrivacyStyle::Fill)]);
+        let mut buf = Vec::new();
+        let masked = mask.apply(
+            PixelProvider::BGR0(width, height, &white),
+            (0.0, 0.0, 1.0, 1.0),
+            &mut buf,
+        );
+        assert_eq!(masked.size(), (width, height));
+        let expected: Vec<bool> = (0..width * height)
+            .map(|i| i % width >= 4 && i / width < 2)
+            .collect();
+        assert_eq!(hidden(masked.data(), width * 4), expected);
+        assert!(masked.data()[4 * 4..4 * 4 + 3] == [0, 0, 0]);
+
+        // the frame shows the right half of the capturable, so the region covers the top half of
+        // the frame, padding at the end of rows is kept
+        let stride = width * 4 + 8;
+        let padded: Vec<u8> = white
+            .chunks(width * 4)
+            .flat_map(|row| row.iter().copied().chain([7; 8]))
+            .collect();
+        let masked = mask.apply(
+            PixelProvider::BGR0S(width, height, stride, &padded),
+            (0.5, 0.0, 0.5, 1.0),
+            &mut buf,
+        );
+        let expected: Vec<bool> = (0..width * height).map(|i| i / width < 2).collect();
+        assert_eq!(hidden(masked.data(), stride), expected);
+        assert_eq!(masked.stride(), stride);
+
+        // the frame shows the bottom left quarter, which the region does not overlap
+        let masked = mask.apply(
+            PixelProvider::BGR0(width, height, &white),
+            (0.0, 0.5, 0.5, 0.5),
+            &mut buf,
+        );
+        assert!(masked.data() == white);
+
+        // blurred regions are changed, everything else is not
+        let colors = bgr0(width, height, |x, y| [255 * ((x + y) % 2) as u8, 0, 0]);
+        let mask = PrivacyMask::new(vec![region(PrivacyStyle::Blur)]);
+        let masked = mask.apply(
+            PixelProvider::BGR0(width, height, &colors),
+            (0.0, 0.0, 1.0, 1.0),
+            &mut buf,
+        );
+        for (i, (masked, original)) in masked.data().chunks(4).zip(colors.chunks(4)).enumerate() {
+            if i % width >= 4 && i / width < 2 {
+                assert!(
+                    masked[2] > 0 && masked[2] < 255,
+                    "pixel {} is not blurred",
+                    i
+                );
+            } else {
+                assert_eq!(masked, original, "pixel {} changed", i);
+            }
+        }
+    }
+}
@@ -1,15 +1,37 @@
 use handlebars::Handlebars;
+use hyper::header::{HOST, ORIGIN};
+use hyper::http::uri::Authority;
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{server::conn::AddrStream, Body, Method, Request, Response, Server, StatusCode};
+use hyper::{Body, Method, Request, Response, Server, StatusCode, Uri};
 use serde::Serialize;
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
 use std::sync::mpsc;
 use std::sync::mpsc::SendError;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::sync::mpsc as mpsc_tokio;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 use tracing::{debug, error, info, warn};
 
+use crate::access_tokens::{AccessTokens, ClientPermissions, InputPermission};
+#[cfg(target_os = "linux")]
+use crate::capturable::CaptureBackend;
+use crate::capturable::{
+    encode_png, find_capturable, get_capturables, new_recorder, Capturable, CaptureOptions,
+    Thumbnail,
+};
+use crate::health::HEALTH;
+use crate::protocol::Capabilities;
+use crate::tls::{server_config, TlsFiles};
+
 pub const INDEX_HTML: &str = std::include_str!("../www/templates/index.html");
 pub const ACCESS_HTML: &str = std::include_str!("../www/static/access_code.html");
 pub const STYLE_CSS: &str = std::include_str!("../www/static/style.css");
@@ -21,6 +43,9 @@ struct WebConfig {
     websocket_port: u16,
     uinput_enabled: bool,
     capture_cursor_enabled: bool,
+    cursor_overlay_enabled: bool,
+    clipboard_enabled: bool,
+    audio_enabled: bool,
     log_level: String,
 }
 
@@ -32,6 +57,22 @@ fn response_from_str(s: &str, content_type: &str) -> Response<Body> {
         .unwrap()
 }
 
+fn response_from_bytes(data: Vec<u8>, content_type: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", content_type)
+        .body(data.into())
+        .unwrap()
+}
+
+fn response_error(status: StatusCode, msg: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "text/plain; charset=utf-8")
+        .body(msg.to_string().into())
+        .unwrap()
+}
+
 fn response_not_found() -> Response<Body> {
     Response::builder()
         .status(StatusCode::NOT_FOUND)
@@ -57,6 +98,65 @@ fn response_from_path_or_default(
     }
 }
 
+// tokens can be valid for at most a week, longer times to live are cut down to this
+const MAX_TOKEN_TTL: u64 = 7 * 24 * 60 * 60;
+
+// Time to live in seconds of a token to create, given by the ttl parameter.
+fn parse_token_ttl(ttl: &str) -> Result<u64, String> {
+    match ttl.parse::<u64>() {
+        Ok(0) | Err(_) => Err(format!(
+            "Invalid time to live: '{}', expected a positive number of seconds.",
+            ttl
+        )),
+        Ok(ttl) => Ok(ttl.min(MAX_TOKEN_TTL)),
+    }
+}
+
+// Whether `host`, without port, names this computer by an address Weylus listens at. Other names
+// can be made to resolve to 127.0.0.1 by anyone, see `is_local_request`.
+fn is_local_host(host: &str, bind_addrs: &[SocketAddr]) -> bool {
+    if host.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) => ip.is_loopback() || bind_addrs.iter().any(|addr| addr.ip() == ip),
+        Err(_) => false,
+    }
+}
+
+// Whether the request has been sent by someone using the computer Weylus is running on. Being sent
+// from a loopback address is not enough: any web page open in a browser on this computer can send
+// requests to 127.0.0.1, and by resolving its own domain to 127.0.0.1 (DNS rebinding) even read the
+// responses. Such requests carry the page's domain in Host, and requests sent by pages of other
+// origins name them in Origin, so both have to name this computer.
+fn is_local_request(addr: SocketAddr, req: &Request<Body>, bind_addrs: &[SocketAddr]) -> bool {
+    if !addr.ip().is_loopback() {
+        return false;
+    }
+    let host = match req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(|host| host.parse::<Authority>().ok())
+    {
+        Some(host) if is_local_host(host.host(), bind_addrs) => host,
+        _ => return false,
+    };
+    match req.headers().get(ORIGIN) {
+        // sent by curl or typed into the browser's address bar
+        None => true,
+        Some(origin) => origin
+            .to_str()
+            .ok()
+            .and_then(|origin| origin.parse::<Uri>().ok())
+            .map_or(false, |origin| origin.authority() == Some(&host)),
+    }
+}
+
 async fn serve(
     addr: SocketAddr,
     req: Request<Body>,
@@ -64,29 +164,190 @@ async fn serve(
     _sender: mpsc::Sender<Web2UiMessage>,
 ) -> Result<Response<Body>, hyper::Error> {
     debug!("Got request: {:?}", req);
+    let params = req.uri().query().map_or_else(HashMap::new, |query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect::<HashMap<String, String>>()
+    });
     let mut authed = false;
+    // access code or token the client authenticated with, this is passed on to the websocket
+    let mut client_access_code = None;
     if let Some(access_code) = &context.access_code {
         if req.method() == Method::GET && req.uri().path() == "/" {
-            use url::form_urlencoded;
-            if let Some(query) = req.uri().query() {
-                let params = form_urlencoded::parse(query.as_bytes())
-                    .into_owned()
-                    .collect::<HashMap<String, String>>();
-                if let Some(code) = params.get("access_code") {
-                    if code == access_code {
-                        authed = true;
-                        debug!(address = ?addr, "Web-Client authenticated.");
-                    }
+            if let Some(code) = params.get("access_code") {
+                if code == access_code || context.access_tokens.is_valid(code) {
+                    authed = true;
+                    client_access_code = Some(code.clone());
+                    debug!(address = ?addr, "Web-Client authenticated.");
                 }
             }
         }
     } else {
         authed = true;
     }
-    if req.method() != Method::GET {
+    // requests changing something or giving away the screen have to be sent via POST, so they can
+    // not be triggered by simply opening a link or embedding it in a page
+    let post_only = matches!(
+        req.uri().path(),
+        "/token/create" | "/token/revoke" | "/clients/permission" | "/snapshot"
+    );
+    if post_only && req.method() != Method::POST {
+        return Ok(response_error(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "This has to be requested via POST.",
+        ));
+    }
+    if !post_only && req.method() != Method::GET {
         return Ok(response_not_found());
     }
     match req.uri().path() {
+        // tokens can only be managed from the computer Weylus is running on
+        "/token/create" | "/token/revoke" if is_local_request(addr, &req, &context.bind_addrs) => {
+            if req.uri().path() == "/token/create" {
+                let ttl = match params.get("ttl").map(|ttl| parse_token_ttl(ttl)) {
+                    Some(Ok(ttl)) => ttl,
+                    Some(Err(err)) => return Ok(response_error(StatusCode::BAD_REQUEST, &err)),
+                    None => 300,
+                };
+                let permission = match params
+                    .get("permission")
+                    .map(|p| p.parse::<InputPermission>())
+                {
+                    Some(Ok(permission)) => permission,
+                    Some(Err(err)) => return Ok(response_error(StatusCode::BAD_REQUEST, &err)),
+                    None => InputPermission::Full,
+                };
+                let token = match context
+                    .access_tokens
+                    .create(Duration::from_secs(ttl), permission)
+                {
+                    Ok(token) => token,
+                    Err(err) => return Ok(response_error(StatusCode::BAD_REQUEST, &err)),
+                };
+                info!(
+                    "Created access token valid for {} seconds granting {} input permission.",
+                    ttl, permission
+                );
+                Ok(response_from_str(&token, "text/plain; charset=utf-8"))
+            } else {
+                let revoked = params
+                    .get("token")
+                    .map_or(false, |token| context.access_tokens.revoke(token));
+                Ok(response_from_str(
+                    if revoked {
+                        "Token revoked."
+                    } else {
+                        "No such token."
+                    },
+                    "text/plain; charset=utf-8",
+                ))
+            }
+        }
+        // like tokens, permissions can only be changed from the computer Weylus is running on
+        "/clients" if is_local_request(addr, &req, &context.bind_addrs) => {
+            let clients: String = context
+                .client_permissions
+                .list()
+                .iter()
+                .map(|(client, permission)| format!("{} {}\n", client, permission))
+                .collect();
+            Ok(response_from_str(&clients, "text/plain; charset=utf-8"))
+        }
+        "/clients/permission" if is_local_request(addr, &req, &context.bind_addrs) => {
+            let client = params
+                .get("address")
+                .and_then(|a| a.parse::<SocketAddr>().ok());
+            let permission = params
+                .get("permission")
+                .map_or(Err("No permission given.".to_string()), |p| p.parse());
+            let msg = match (client, permission) {
+                (None, _) => "No or invalid client address given.".to_string(),
+                (_, Err(err)) => err,
+                (Some(client), Ok(permission)) => {
+                    if context.client_permissions.set(&client, permission) {
+                        info!(address = ?client, "Input permission changed to {}.", permission);
+                        format!("Input permission of {} changed to {}.", client, permission)
+                    } else {
+                        "No such client.".to_string()
+                    }
+                }
+            };
+            Ok(response_from_str(&msg, "text/plain; charset=utf-8"))
+        }
+        // like metrics, capabilities are available locally or with the access code
+        "/capabilities"
+            if is_local_request(addr, &req, &context.bind_addrs)
+                || context
+                    .access_code
+                    .as_ref()
+                    .map_or(true, |code| params.get("access_code") == Some(code)) =>
+        {
+            Ok(response_from_str(
+                &serde_json::to_string(&context.capabilities).unwrap(),
+                "application/json",
+            ))
+        }
+        // snapshots are available locally or with the access code
+        "/snapshot"
+            if is_local_request(addr, &req, &context.bind_addrs)
+                || context
+                    .access_code
+                    .as_ref()
+                    .map_or(true, |code| params.get("access_code") == Some(code)) =>
+        {
+            let source = context.snapshot_source.lock().unwrap().clone();
+            let selector = params.get("capturable").cloned();
+            let capture_cursor = params.get("cursor").map_or(false, |c| c == "true");
+            let snapshot = tokio::task::spawn_blocking(move || {
+                source.snapshot(selector.as_deref(), capture_cursor)
+            })
+            .await;
+            Ok(match snapshot {
+                Ok(Ok(png)) => response_from_bytes(png, "image/png"),
+                Ok(Err(err)) => {
+                    debug!("Failed to take snapshot: {}", err);
+                    response_error(StatusCode::INTERNAL_SERVER_ERROR, &err)
+                }
+                Err(err) => {
+                    error!("Snapshot thread panicked: {}", err);
+                    response_error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Failed to take snapshot.",
+                    )
+                }
+            })
+        }
+        // checks may give away what is on the screen, for example that it is black, so they are only
+        // available locally unless requested otherwise
+        "/health"
+            if is_local_request(addr, &req, &context.bind_addrs)
+                || (context.remote_health
+                    && context
+                        .access_code
+                        .as_ref()
+                        .map_or(true, |code| params.get("access_code") == Some(code))) =>
+        {
+            let report = HEALTH.report(context.client_permissions.list().len());
+            let mut response =
+                response_from_str(&serde_json::to_string(&report).unwrap(), "application/json");
+            if !report.healthy {
+                *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            }
+            Ok(response)
+        }
+        // metrics are available locally or with the access code
+        "/metrics"
+            if is_local_request(addr, &req, &context.bind_addrs)
+                || context
+                    .access_code
+                    .as_ref()
+                    .map_or(true, |code| params.get("access_code") == Some(code)) =>
+        {
+            Ok(response_from_str(
+                &crate::metrics::METRICS.render(),
+                "text/plain; version=0.0.4; charset=utf-8",
+            ))
+        }
         "/" => {
             if !authed {
                 return Ok(response_from_path_or_default(
@@ -97,10 +358,13 @@ async fn serve(
             }
             debug!(address = ?addr, "Client connected.");
             let config = WebConfig {
-                access_code: context.access_code.clone(),
+                access_code: client_access_code,
                 websocket_port: context.ws_port,
                 uinput_enabled: cfg!(target_os = "linux"),
                 capture_cursor_enabled: cfg!(not(target_os = "windows")),
+                cursor_overlay_enabled: cfg!(target_os = "linux"),
+                clipboard_enabled: context.clipboard_enabled,
+                audio_enabled: context.capabilities.audio,
                 log_level: crate::log::get_log_level().to_string(),
             };
 
@@ -153,27 +417,97 @@ fn log_send_error<T>(res: Result<(), SendError<T>>) {
     }
 }
 
+/// What `/snapshot` captures from, a single image is captured with a recorder of its own each time.
+#[derive(Clone)]
+pub struct SnapshotSource {
+    #[cfg(target_os = "linux")]
+    pub capture_backend: CaptureBackend,
+    pub extra_capturables: Vec<Box<dyn Capturable>>,
+    // selects what is captured if the request does not
+    pub capturable: Option<String>,
+    // largest width or height, larger images are scaled down
+    pub max_size: Option<usize>,
+}
+
+impl SnapshotSource {
+    // capture the capturable matching `selector`, the whole desktop if there is none, as PNG
+    fn snapshot(&self, selector: Option<&str>, capture_cursor: bool) -> Result<Vec<u8>, String> {
+        // PipeWire requires picking what to capture interactively, which is not possible here
+        let mut capturables = get_capturables(
+            #[cfg(target_os = "linux")]
+            self.capture_backend,
+            #[cfg(target_os = "linux")]
+            false,
+            #[cfg(target_os = "linux")]
+            capture_cursor,
+        );
+        capturables.extend(self.extra_capturables.iter().cloned());
+        let capturable = match selector.or(self.capturable.as_deref()) {
+            Some(selector) => find_capturable(&capturables, selector)
+                .ok_or_else(|| format!("No screen or window matches '{}'.", selector))?,
+            None if !capturables.is_empty() => 0,
+            None => return Err("There is nothing to capture.".to_string()),
+        };
+        let mut recorder = new_recorder(
+            capturables[capturable].as_ref(),
+            CaptureOptions {
+                capture_cursor,
+                crop: None,
+                vsync: false,
+                track_damage: false,
+            },
+        )
+        .map_err(|err| err.to_string())?;
+        let mut frame = Vec::new();
+        let (width, height) = recorder
+            .capture_into(&mut frame)
+            .map_err(|err| err.to_string())?;
+        let png = match self.max_size {
+            Some(max_size) if width > max_size || height > max_size => {
+                let scaled = Thumbnail::scaled(&frame, width, height, max_size);
+                encode_png(&scaled.data, scaled.width, scaled.height)
+            }
+            _ => encode_png(&frame, width, height),
+        };
+        png.map_err(|err| err.to_string())
+    }
+}
+
 struct Context<'a> {
-    bind_addr: SocketAddr,
+    bind_addrs: Vec<SocketAddr>,
     ws_port: u16,
     access_code: Option<String>,
     custom_index_html: Option<String>,
     custom_access_html: Option<String>,
     custom_style_css: Option<String>,
     custom_lib_js: Option<String>,
+    access_tokens: AccessTokens,
+    client_permissions: ClientPermissions,
+    clipboard_enabled: bool,
+    capabilities: Capabilities,
+    remote_health: bool,
+    // locked only to clone it, capturables do not need to be shared between threads
+    snapshot_source: Mutex<SnapshotSource>,
     templates: Handlebars<'a>,
 }
 
 pub fn run(
     sender: mpsc::Sender<Web2UiMessage>,
     receiver: mpsc_tokio::Receiver<Ui2WebMessage>,
-    bind_addr: SocketAddr,
+    bind_addrs: Vec<SocketAddr>,
     ws_port: u16,
     access_code: Option<String>,
     custom_index_html: Option<String>,
     custom_access_html: Option<String>,
     custom_style_css: Option<String>,
     custom_lib_js: Option<String>,
+    access_tokens: AccessTokens,
+    client_permissions: ClientPermissions,
+    clipboard_enabled: bool,
+    capabilities: Capabilities,
+    remote_health: bool,
+    snapshot_source: SnapshotSource,
+    tls_files: Option<TlsFiles>,
 ) -> std::thread::JoinHandle<()> {
     let mut templates = Handlebars::new();
     templates
@@ -181,16 +515,161 @@ pub fn run(
         .unwrap();
 
     let context = Context {
-        bind_addr,
+        bind_addrs,
         ws_port,
         access_code,
         custom_index_html,
         custom_access_html,
         custom_style_css,
         custom_lib_js,
+        access_tokens,
+        client_permissions,
+        clipboard_enabled,
+        capabilities,
+        remote_health,
+        snapshot_source: Mutex::new(snapshot_source),
         templates,
     };
-    std::thread::spawn(move || run_server(context, sender, receiver))
+    std::thread::spawn(move || run_server(context, sender, receiver, tls_files))
+}
+
+fn tls_acceptor(files: &TlsFiles) -> Result<TlsAcceptor, String> {
+    let mut config = server_config(files)?;
+    // hyper serves both, offering HTTP/2 lets browsers pick it
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Connections accepted at any of several addresses, so a single server can listen at all of them.
+struct MultiIncoming(Vec<AddrIncoming>);
+
+impl Accept for MultiIncoming {
+    type Conn = AddrStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        for incoming in self.get_mut().0.iter_mut() {
+            if let Poll::Ready(conn) = Pin::new(incoming).poll_accept(cx) {
+                return Poll::Ready(conn);
+            }
+        }
+        Poll::Pending
+    }
+}
+
+// Connection to the web server, encrypted if it serves HTTPS.
+enum WebConn {
+    Plain(AddrStream),
+    Tls(TlsStream<AddrStream>),
+}
+
+impl WebConn {
+    fn remote_addr(&self) -> SocketAddr {
+        match self {
+            WebConn::Plain(conn) => conn.remote_addr(),
+            WebConn::Tls(conn) => conn.get_ref().0.remote_addr(),
+        }
+    }
+}
+
+impl AsyncRead for WebConn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            WebConn::Plain(conn) => Pin::new(conn).poll_read(cx, buf),
+            WebConn::Tls(conn) => Pin::new(conn).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for WebConn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            WebConn::Plain(conn) => Pin::new(conn).poll_write(cx, buf),
+            WebConn::Tls(conn) => Pin::new(conn).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            WebConn::Plain(conn) => Pin::new(conn).poll_flush(cx),
+            WebConn::Tls(conn) => Pin::new(conn).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            WebConn::Plain(conn) => Pin::new(conn).poll_shutdown(cx),
+            WebConn::Tls(conn) => Pin::new(conn).poll_shutdown(cx),
+        }
+    }
+}
+
+// Connections accepted by `MultiIncoming`, which are only handed to hyper once their TLS handshake
+// is done if the server serves HTTPS. Handshakes go on concurrently, so a slow client does not hold
+// up others.
+struct WebIncoming {
+    incoming: MultiIncoming,
+    tls: Option<TlsAcceptor>,
+    handshakes: Vec<tokio_rustls::Accept<AddrStream>>,
+}
+
+impl Accept for WebIncoming {
+    type Conn = WebConn;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        let tls = match &this.tls {
+            Some(tls) => tls,
+            None => {
+                return Pin::new(&mut this.incoming)
+                    .poll_accept(cx)
+                    .map_ok(WebConn::Plain)
+            }
+        };
+        loop {
+            match Pin::new(&mut this.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(conn))) => this.handshakes.push(tls.accept(conn)),
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+        let mut i = 0;
+        while i < this.handshakes.len() {
+            match Pin::new(&mut this.handshakes[i]).poll(cx) {
+                Poll::Ready(Ok(conn)) => {
+                    this.handshakes.swap_remove(i);
+                    return Poll::Ready(Some(Ok(WebConn::Tls(conn))));
+                }
+                Poll::Ready(Err(err)) => {
+                    this.handshakes.swap_remove(i);
+                    debug!("TLS handshake failed: {}", err);
+                }
+                Poll::Pending => i += 1,
+            }
+        }
+        Poll::Pending
+    }
 }
 
 #[tokio::main]
@@ -198,13 +677,25 @@ async fn run_server(
     context: Context<'static>,
     sender: mpsc::Sender<Web2UiMessage>,
     mut receiver: mpsc_tokio::Receiver<Ui2WebMessage>,
+    tls_files: Option<TlsFiles>,
 ) {
-    let addr = context.bind_addr;
+    let addrs = context.bind_addrs.clone();
     let context = Arc::new(context);
 
     let sender = sender.clone();
     let sender2 = sender.clone();
-    let service = make_service_fn(move |s: &AddrStream| {
+    let tls = match tls_files.as_ref().map(tls_acceptor).transpose() {
+        Ok(tls) => tls,
+        Err(err) => {
+            log_send_error(sender2.send(Web2UiMessage::Error(format!(
+                "Failed to start webserver: {}",
+                err
+            ))));
+            return;
+        }
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    let service = make_service_fn(move |s: &WebConn| {
         let addr = s.remote_addr();
         let context = context.clone();
         let sender = sender.clone();
@@ -215,16 +706,25 @@ async fn run_server(
             }))
         }
     });
-    let server = match Server::try_bind(&addr) {
-        Ok(builder) => builder.serve(service),
-        Err(err) => {
-            log_send_error(sender2.send(Web2UiMessage::Error(format!(
-                "Failed to start webserver: {}",
-                err
-            ))));
-            return;
+    let mut incoming = Vec::new();
+    for addr in &addrs {
+        match AddrIncoming::bind(addr) {
+            Ok(i) => incoming.push(i),
+            Err(err) => {
+                log_send_error(sender2.send(Web2UiMessage::Error(format!(
+                    "Failed to start webserver at {}: {}",
+                    addr, err
+                ))));
+                return;
+            }
         }
+    }
+    let incoming = WebIncoming {
+        incoming: MultiIncoming(incoming),
+        tls,
+        handshakes: Vec::new(),
     };
+    let server = Server::builder(incoming).serve(service);
     let server = server.with_graceful_shutdown(async move {
         loop {
             match receiver.recv().await {
@@ -233,9 +733,108 @@ async fn run_server(
             }
         }
     });
-    info!("Webserver listening at {}...", addr);
+    for addr in &addrs {
+        info!("Webserver listening at {}://{}...", scheme, addr);
+    }
     log_send_error(sender2.send(Web2UiMessage::Start));
     if let Err(err) = server.await {
         error!("Webserver exited error: {}", err)
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(host: &str, origin: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder().method(Method::POST).uri("/token/create");
+        req = req.header(HOST, host);
+        if let Some(origin) = origin {
+            req = req.header(ORIGIN, origin);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn local_requests() {
+        let bind_addrs: [SocketAddr; 1] = ["0.0.0.0:1701".parse().unwrap()];
+        let local: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        for host in [
+            "127.0.0.1:1701",
+            "localhost:1701",
+            "[::1]:1701",
+            "127.0.0.1",
+        ] {
+            assert!(
+                is_local_request(local, &request(host, None), &bind_addrs),
+                "{}",
+                host
+            );
+        }
+        assert!(is_local_request(
+            local,
+            &request("127.0.0.1:1701", Some("http://127.0.0.1:1701")),
+            &bind_addrs
+        ));
+        let v6_local: SocketAddr = "[::1]:50000".parse().unwrap();
+        assert!(is_local_request(
+            v6_local,
+            &request("[::1]:1701", None),
+            &bind_addrs
+        ));
+    }
+
+    #[test]
+    fn rebound_or_foreign_requests() {
+        let bind_addrs: [SocketAddr; 1] = ["0.0.0.0:1701".parse().unwrap()];
+        let local: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        // a page whose domain has been rebound to 127.0.0.1
+        assert!(!is_local_request(
+            local,
+            &request("evil.example:1701", Some("http://evil.example:1701")),
+            &bind_addrs
+        ));
+        assert!(!is_local_request(
+            local,
+            &request("evil.example:1701", None),
+            &bind_addrs
+        ));
+        // a page of another origin sending the request to 127.0.0.1
+        assert!(!is_local_request(
+            local,
+            &request("127.0.0.1:1701", Some("http://evil.example")),
+            &bind_addrs
+        ));
+        assert!(!is_local_request(
+            local,
+            &request("127.0.0.1:1701", Some("null")),
+            &bind_addrs
+        ));
+        // another local server on a different port
+        assert!(!is_local_request(
+            local,
+            &request("127.0.0.1:1701", Some("http://127.0.0.1:8080")),
+            &bind_addrs
+        ));
+        let mut no_host = request("127.0.0.1:1701", None);
+        no_host.headers_mut().remove(HOST);
+        assert!(!is_local_request(local, &no_host, &bind_addrs));
+        // other computers are never local
+        let remote: SocketAddr = "192.168.1.2:50000".parse().unwrap();
+        assert!(!is_local_request(
+            remote,
+            &request("127.0.0.1:1701", None),
+            &bind_addrs
+        ));
+    }
+
+    #[test]
+    fn token_ttl_parsed() {
+        assert_eq!(parse_token_ttl("60"), Ok(60));
+        assert_eq!(parse_token_ttl("18446744073709551615"), Ok(MAX_TOKEN_TTL));
+        assert!(parse_token_ttl("0").is_err());
+        assert!(parse_token_ttl("-1").is_err());
+        assert!(parse_token_ttl("18446744073709551616").is_err());
+        assert!(parse_token_ttl("soon").is_err());
+    }
+}
@@ -0,0 +1,80 @@
+//! Audio sent to a client along with its video.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::audio::{AudioEvent, AudioOptions, AudioStream, CHANNELS, SAMPLE_RATE};
+use crate::protocol::{FrameHeader, FrameType, MessageOutbound};
+
+use super::encoding::send_video_data;
+use super::stats::FrameStats;
+use super::{send_msg, WsWriter};
+
+// Capture audio and send it to the client, timestamped on the clock of the current video so the
+// client plays it along with the frames captured at the same time.
+fn start_audio(options: AudioOptions, sender: &WsWriter, stats: &Arc<FrameStats>) -> AudioStream {
+    let sender = sender.clone();
+    let stats = stats.clone();
+    // packets captured before any video started are timestamped relative to this
+    let started_at = Instant::now();
+    let mut framed = Vec::new();
+    AudioStream::start(options, move |event| match event {
+        AudioEvent::Started => send_msg(
+            &sender,
+            &MessageOutbound::AudioStarted(SAMPLE_RATE, CHANNELS),
+        ),
+        AudioEvent::Stopped(err) => send_msg(&sender, &MessageOutbound::AudioStopped(err)),
+        AudioEvent::Packet(data, captured_at) => {
+            let origin = stats.video_started_at.lock().unwrap().unwrap_or(started_at);
+            // captured before the current video started, there is no frame to play it along with
+            let timestamp = match captured_at.checked_duration_since(origin) {
+                Some(timestamp) => timestamp,
+                None => return,
+            };
+            let header = FrameHeader {
+                frame_type: FrameType::Audio,
+                timestamp_ms: timestamp.as_millis() as u32,
+                width: 0,
+                height: 0,
+            };
+            header.encode(data, &mut framed);
+            send_video_data(&sender, &framed);
+            stats
+                .total_bytes
+                .fetch_add(framed.len() as u64, Ordering::Relaxed);
+        }
+    })
+}
+
+// Audio of one client, only captured while the client wants audio and is connected.
+pub struct ClientAudio {
+    // none if the server does not capture audio
+    options: Option<AudioOptions>,
+    stream: Option<AudioStream>,
+}
+
+impl ClientAudio {
+    pub fn new(options: Option<AudioOptions>) -> Self {
+        Self {
+            options,
+            stream: None,
+        }
+    }
+
+    // Start capturing audio if `enabled` and it is not captured already, otherwise stop.
+    pub fn set_enabled(&mut self, enabled: bool, sender: &WsWriter, stats: &Arc<FrameStats>) {
+        if !enabled {
+            self.stream = None;
+        } else if self.stream.is_none() {
+            self.stream = self
+                .options
+                .clone()
+                .map(|options| start_audio(options, sender, stats));
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.stream = None;
+    }
+}
@@ -0,0 +1,210 @@
+//! Adapting bitrate and quality of the video to the connection of a client.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::WsConfig;
+
+// Adapts the maximum bitrate of the encoder to the throughput of the connection. Sending blocks once
+// the send buffer of the socket is full, so the time it takes to send a frame indicates if the
+// connection is congested. The bitrate is lowered quickly on congestion and raised slowly while
+// the connection keeps up.
+pub struct BitrateController {
+    min_bitrate: u32,
+    max_bitrate: u32,
+    bitrate: u32,
+    last_change: Instant,
+}
+
+impl BitrateController {
+    // sending a frame taking longer than this is considered congestion
+    const CONGESTION_THRESHOLD: Duration = Duration::from_millis(30);
+    const DECREASE_INTERVAL: Duration = Duration::from_millis(250);
+    const INCREASE_INTERVAL: Duration = Duration::from_secs(2);
+
+    pub fn new(min_bitrate: u32, max_bitrate: u32) -> Self {
+        Self {
+            min_bitrate: min_bitrate.min(max_bitrate),
+            max_bitrate,
+            bitrate: max_bitrate,
+            last_change: Instant::now(),
+        }
+    }
+
+    // update the bitrate given the time it took to send the last frame, returns the new bitrate in
+    // kbit/s if it changed
+    pub fn update(&mut self, send_time: Duration) -> Option<u32> {
+        let since_change = self.last_change.elapsed();
+        let bitrate = if send_time > Self::CONGESTION_THRESHOLD {
+            if since_change < Self::DECREASE_INTERVAL {
+                return None;
+            }
+            (self.bitrate / 4 * 3).max(self.min_bitrate)
+        } else {
+            if since_change < Self::INCREASE_INTERVAL {
+                return None;
+            }
+            (self.bitrate + self.max_bitrate / 20).min(self.max_bitrate)
+        };
+        if bitrate == self.bitrate {
+            return None;
+        }
+        self.bitrate = bitrate;
+        self.last_change = Instant::now();
+        Some(bitrate)
+    }
+
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate
+    }
+}
+
+// Keeps the video of a client under a hard bandwidth cap, unlike the BitrateController, which only
+// follows the throughput of the connection. The bytes sent are measured over a sliding window of
+// WINDOW and a frame is only sent if it fits into what is left of the budget of the window, assuming
+// it is as large as the frames sent recently. While frames are dropped the quality is lowered, so
+// they get smaller, and raised again slowly once there is plenty of room. Frames are dropped before
+// they are encoded, so the video stays decodable without sending a keyframe.
+pub struct BandwidthGovernor {
+    // bytes that may be sent within WINDOW
+    budget: f64,
+    // total number of bytes sent whenever a frame has been requested, oldest first
+    samples: VecDeque<(Instant, u64)>,
+    // moving average of the size of the frames sent in bytes
+    frame_size: f64,
+    // the last frame requested has been sent
+    admitted: bool,
+    quality: f64,
+    last_change: Instant,
+}
+
+impl BandwidthGovernor {
+    const WINDOW: Duration = Duration::from_secs(1);
+    const MIN_QUALITY: f64 = 0.2;
+    const DECREASE_INTERVAL: Duration = Duration::from_millis(250);
+    const INCREASE_INTERVAL: Duration = Duration::from_secs(2);
+    // quality is only raised while less than this fraction of the budget is used
+    const INCREASE_BELOW: f64 = 0.5;
+
+    pub fn new(cap: u32) -> Self {
+        Self {
+            budget: cap as f64 * 1000.0 / 8.0 * Self::WINDOW.as_secs_f64(),
+            samples: VecDeque::new(),
+            frame_size: 0.0,
+            admitted: false,
+            quality: 1.0,
+            last_change: Instant::now(),
+        }
+    }
+
+    // bytes sent within the window, given the total number of bytes sent so far
+    fn sent(&self, total_bytes: u64) -> u64 {
+        self.samples
+            .front()
+            .map_or(0, |(_, oldest)| total_bytes.saturating_sub(*oldest))
+    }
+
+    // whether the frame requested now may be sent, given the total number of bytes sent so far
+    pub fn admit(&mut self, total_bytes: u64) -> bool {
+        let now = Instant::now();
+        if let (true, Some((_, last))) = (self.admitted, self.samples.back()) {
+            let size = total_bytes.saturating_sub(*last) as f64;
+            self.frame_size = if self.frame_size == 0.0 {
+                size
+            } else {
+                self.frame_size * 0.8 + size * 0.2
+            };
+        }
+        self.samples.push_back((now, total_bytes));
+        // keep the last sample from before the window, what has been sent since then counts
+        while self
+            .samples
+            .get(1)
+            .map_or(false, |(at, _)| now.duration_since(*at) >= Self::WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        let expected = self.sent(total_bytes) as f64 + self.frame_size;
+        self.admitted = expected <= self.budget;
+        let since_change = self.last_change.elapsed();
+        if !self.admitted && since_change >= Self::DECREASE_INTERVAL {
+            self.set_quality((self.quality * 0.75).max(Self::MIN_QUALITY));
+        } else if self.admitted
+            && expected < self.budget * Self::INCREASE_BELOW
+            && since_change >= Self::INCREASE_INTERVAL
+        {
+            self.set_quality((self.quality + 0.1).min(1.0));
+        }
+        self.admitted
+    }
+
+    fn set_quality(&mut self, quality: f64) {
+        if quality != self.quality {
+            self.quality = quality;
+            self.last_change = Instant::now();
+        }
+    }
+
+    // fraction of the configured quality frames are encoded at
+    pub fn quality(&self) -> f64 {
+        self.quality
+    }
+
+    // kbit/s sent within the window
+    pub fn rate(&self) -> f64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some((_, oldest)), Some((_, newest))) => {
+                newest.saturating_sub(*oldest) as f64 * 8.0 / 1000.0 / Self::WINDOW.as_secs_f64()
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+// Starts the video of a new connection at low quality, so the first frames arrive quickly even on
+// slow connections, and raises the quality step by step as long as frames are sent without
+// congestion, using the same signal as the BitrateController. Quality is given as fraction of the
+// configured quality.
+pub struct QualityRamp {
+    quality: f64,
+    step: f64,
+    interval: Duration,
+    last_change: Instant,
+}
+
+impl QualityRamp {
+    pub fn new(config: &WsConfig) -> Option<Self> {
+        let start = config.quality_ramp_start?.clamp(0.0, 1.0);
+        (start < 1.0 && config.quality_ramp_step > 0.0).then(|| Self {
+            quality: start,
+            step: config.quality_ramp_step,
+            interval: config.quality_ramp_interval,
+            last_change: Instant::now(),
+        })
+    }
+
+    // update the quality given the time it took to send the last frame, returns the new quality if
+    // it changed
+    pub fn update(&mut self, send_time: Duration) -> Option<f64> {
+        // wait for a whole interval without congestion again
+        if send_time > BitrateController::CONGESTION_THRESHOLD {
+            self.last_change = Instant::now();
+            return None;
+        }
+        if self.is_done() || self.last_change.elapsed() < self.interval {
+            return None;
+        }
+        self.quality = (self.quality + self.step).min(1.0);
+        self.last_change = Instant::now();
+        Some(self.quality)
+    }
+
+    pub fn quality(&self) -> f64 {
+        self.quality
+    }
+
+    // full quality has been reached
+    pub fn is_done(&self) -> bool {
+        self.quality >= 1.0
+    }
+}
@@ -0,0 +1,164 @@
+//! Keeping the cursor in view and sending it to clients that draw it themselves.
+
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use crate::capturable::Recorder;
+use crate::protocol::{CursorInfo, MessageOutbound};
+use crate::video::{FrameTransform, LetterboxLayout, PixelProvider};
+
+use super::{send_msg, WsWriter};
+
+// Moves the cropped region along with the cursor to keep it in view. The region only moves once
+// the cursor gets close to its border and is then centered on the cursor, this hysteresis keeps it
+// from jittering along with every small movement of the cursor.
+pub struct CursorFollower {
+    pub crop: (u32, u32, u32, u32),
+}
+
+impl CursorFollower {
+    // fraction of the region's width and height at its borders that makes it move once the cursor
+    // enters it
+    const MARGIN: f64 = 0.15;
+
+    pub fn new(crop: (u32, u32, u32, u32)) -> Self {
+        Self { crop }
+    }
+
+    // Takes the position of the cursor relative to the current region and the size of the whole
+    // image, returns the new region if it moved.
+    pub fn update(
+        &mut self,
+        x: i32,
+        y: i32,
+        (width, height): (usize, usize),
+    ) -> Option<(u32, u32, u32, u32)> {
+        let (crop_x, crop_y, crop_width, crop_height) = self.crop;
+        let new_x = Self::follow(crop_x, crop_width, x, width);
+        let new_y = Self::follow(crop_y, crop_height, y, height);
+        if (new_x, new_y) == (crop_x, crop_y) {
+            return None;
+        }
+        self.crop = (new_x, new_y, crop_width, crop_height);
+        Some(self.crop)
+    }
+
+    // new offset of the region along one axis, clamped to the edges of the image
+    fn follow(offset: u32, size: u32, pos: i32, total: usize) -> u32 {
+        let margin = (size as f64 * Self::MARGIN) as i64;
+        let pos = pos as i64;
+        if pos >= margin && pos < size as i64 - margin {
+            return offset;
+        }
+        let max_offset = (total as i64 - size as i64).max(0);
+        (offset as i64 + pos - size as i64 / 2).clamp(0, max_offset) as u32
+    }
+}
+
+/// Images of cursors changing their shape faster than this, for example animated ones, are only
+/// sent once in this interval.
+const CURSOR_IMAGE_INTERVAL: Duration = Duration::from_millis(50);
+
+// cursor last sent to the client
+#[derive(Clone, Copy)]
+pub struct SentCursor {
+    // serial of the image sent last
+    serial: u64,
+    x: i32,
+    y: i32,
+    image_sent_at: Instant,
+}
+
+// Send the position and if it changed the image of the cursor to the client, so it can draw the
+// cursor on top of the video.
+pub fn send_cursor(
+    recorder: &mut dyn Recorder,
+    sender: &WsWriter,
+    last_cursor: &mut Option<SentCursor>,
+    (frame_width, frame_height): (usize, usize),
+    transform: FrameTransform,
+    letterbox: Option<LetterboxLayout>,
+    scale: f64,
+) {
+    let cursor = match recorder.cursor() {
+        Ok(Some(cursor)) => cursor,
+        Ok(None) => return,
+        Err(err) => {
+            debug!("Failed to get cursor: {}", err);
+            return;
+        }
+    };
+    // a change of the image put off by CURSOR_IMAGE_INTERVAL keeps the old serial, so it is sent
+    // with one of the next frames
+    let send_image = last_cursor.map_or(true, |last| {
+        last.serial != cursor.serial && last.image_sent_at.elapsed() >= CURSOR_IMAGE_INTERVAL
+    });
+    if !send_image && last_cursor.map_or(false, |last| (last.x, last.y) == (cursor.x, cursor.y)) {
+        return;
+    }
+    *last_cursor = Some(match (*last_cursor, send_image) {
+        (Some(last), false) => SentCursor {
+            x: cursor.x,
+            y: cursor.y,
+            ..last
+        },
+        _ => SentCursor {
+            serial: cursor.serial,
+            x: cursor.x,
+            y: cursor.y,
+            image_sent_at: Instant::now(),
+        },
+    });
+    let image = if send_image {
+        // BGRA to RGBA
+        let mut image = cursor.data;
+        image.chunks_exact_mut(4).for_each(|p| p.swap(0, 2));
+        if !transform.is_identity() {
+            let mut transformed = Vec::new();
+            transform.apply(
+                PixelProvider::RGB0(cursor.width, cursor.height, &image),
+                &mut transformed,
+            );
+            image = transformed;
+        }
+        Some(image)
+    } else {
+        None
+    };
+    // the cursor is positioned on the frame before transforming it
+    let (width_captured, height_captured) = transform.size(frame_width, frame_height);
+    let (x, y) = transform.transform_pixel(
+        cursor.x,
+        cursor.y,
+        width_captured as i32,
+        height_captured as i32,
+    );
+    let (hotspot_x, hotspot_y) = transform.transform_pixel(
+        cursor.hotspot_x,
+        cursor.hotspot_y,
+        cursor.width as i32,
+        cursor.height as i32,
+    );
+    let (width, height) = transform.size(cursor.width, cursor.height);
+    // and the bars added to the frame
+    let (x, y, frame_width, frame_height) = match letterbox {
+        Some(l) => (x + l.x as i32, y + l.y as i32, l.width, l.height),
+        None => (x, y, frame_width, frame_height),
+    };
+    send_msg(
+        sender,
+        &MessageOutbound::Cursor(CursorInfo {
+            x: x as f64 / frame_width as f64,
+            y: y as f64 / frame_height as f64,
+            frame_width,
+            frame_height,
+            hotspot_x,
+            hotspot_y,
+            width,
+            height,
+            scale,
+            image,
+        }),
+    );
+}
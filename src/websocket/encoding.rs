@@ -0,0 +1,858 @@
+//! Encoding captured frames as video or JPEG images and sending them to the client.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, debug_span, error, field, info, trace, warn};
+
+use websocket::{Message, WebSocketError};
+
+use crate::cerror::CError;
+use crate::health::HEALTH;
+use crate::jpeg::JpegEncoder;
+use crate::metrics::METRICS;
+use crate::protocol::{FrameHeader, FrameType, MessageOutbound};
+use crate::recording::SessionRecorder;
+use crate::triple_buffer::{triple_buffer, Consumer, Producer};
+use crate::video::{
+    EncoderOptions, OwnedPixels, PixelProvider, VideoChunk, VideoCodec, VideoEncoder, YuvConverter,
+};
+
+use super::bandwidth::{BitrateController, QualityRamp};
+use super::stats::FrameStats;
+use super::{send_msg, WsConfig, WsWriter};
+
+// Tell the client that a new video starts and create the encoder for it, the encoded video is sent
+// to the client right away.
+fn new_video_encoder(
+    sender: &WsWriter,
+    send_time: &Rc<Cell<Duration>>,
+    stats: &Arc<FrameStats>,
+    recorder: Option<&SessionRecorder>,
+    (width_in, height_in, width_out, height_out): (usize, usize, usize, usize),
+    options: EncoderOptions,
+    frame_header: bool,
+) -> Result<Box<VideoEncoder>, CError> {
+    send_msg(
+        sender,
+        &MessageOutbound::VideoMimeType(options.codec.mime_type(options.yuv444).into()),
+    );
+    send_msg(sender, &MessageOutbound::NewVideo);
+    let sender = sender.clone();
+    let send_time = send_time.clone();
+    let stats = stats.clone();
+    // the video restarts with a new segment, which starts with the initialization segment again
+    let segment = recorder.and_then(|recorder| match recorder.next_segment() {
+        Ok(segment) => Some(RefCell::new(segment)),
+        Err(err) => {
+            warn!("Failed to create file to record the video to: {}", err);
+            None
+        }
+    });
+    // holds the header and the data sent after it
+    let framed = RefCell::new(Vec::new());
+    VideoEncoder::with_chunks(
+        width_in,
+        height_in,
+        width_out,
+        height_out,
+        move |data, chunk| {
+            let start = Instant::now();
+            if frame_header {
+                let (frame_type, timestamp_ms) = match chunk {
+                    VideoChunk::Init => (FrameType::Init, 0),
+                    VideoChunk::Keyframe(pts) => (FrameType::Keyframe, pts),
+                    VideoChunk::Delta(pts) => (FrameType::Delta, pts),
+                };
+                let header = FrameHeader {
+                    frame_type,
+                    timestamp_ms,
+                    width: width_out as u32,
+                    height: height_out as u32,
+                };
+                let mut framed = framed.borrow_mut();
+                header.encode(data, &mut framed);
+                send_video_data(&sender, &framed);
+                stats
+                    .total_bytes
+                    .fetch_add(framed.len() as u64, Ordering::Relaxed);
+            } else {
+                send_video_data(&sender, data);
+                stats
+                    .total_bytes
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+            send_time.set(send_time.get() + start.elapsed());
+            stats.bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+            if let Some(segment) = &segment {
+                segment.borrow_mut().write(data);
+            }
+        },
+        options,
+    )
+}
+
+// JPEG quality at `fraction` of the configured quality
+fn ramped_jpeg_quality(jpeg_quality: u8, fraction: f64) -> u8 {
+    1 + ((jpeg_quality.max(1) - 1) as f64 * fraction).round() as u8
+}
+
+pub fn send_video_data(sender: &WsWriter, data: &[u8]) {
+    if let Err(err) = sender
+        .lock()
+        .unwrap()
+        .send(&Message::binary(data), data.len(), true)
+    {
+        match err {
+            WebSocketError::IoError(err) => {
+                // ignore broken pipe errors as those are caused by intentionally shutting down the
+                // websocket
+                if err.kind() == std::io::ErrorKind::BrokenPipe {
+                    debug!("Error sending video: {}", err);
+                } else {
+                    warn!("Error sending video: {}", err);
+                }
+            }
+            _ => warn!("Error sending video: {}", err),
+        }
+    }
+}
+
+// Encodes frames as video and sends it to the client, restarting the encoder whenever the size or
+// options of the frames change.
+pub struct VideoStage {
+    sender: WsWriter,
+    encoder: Option<Box<VideoEncoder>>,
+    bitrate_controller: Option<BitrateController>,
+    // none once full quality has been reached or if the video starts at full quality
+    quality_ramp: Option<QualityRamp>,
+    // fraction of the configured quality the BandwidthGovernor allows, this caps the quality ramp
+    quality_limit: f64,
+    // time spent sending the current frame
+    send_time: Rc<Cell<Duration>>,
+    stats: Arc<FrameStats>,
+    // records every video encoded to a file of its own
+    recorder: Option<Arc<SessionRecorder>>,
+    yuv_converter: Option<Arc<dyn YuvConverter>>,
+    // prefix the video sent with FrameHeaders
+    frame_header: bool,
+    // encoders that failed while encoding, they are skipped for the rest of the session
+    failed_encoders: Vec<&'static str>,
+}
+
+impl VideoStage {
+    fn new(
+        sender: WsWriter,
+        bitrate_controller: Option<BitrateController>,
+        quality_ramp: Option<QualityRamp>,
+        stats: Arc<FrameStats>,
+        recorder: Option<Arc<SessionRecorder>>,
+        yuv_converter: Option<Arc<dyn YuvConverter>>,
+    ) -> Self {
+        Self {
+            sender,
+            encoder: None,
+            bitrate_controller,
+            quality_ramp,
+            quality_limit: 1.0,
+            send_time: Rc::new(Cell::new(Duration::ZERO)),
+            stats,
+            recorder,
+            yuv_converter,
+            frame_header: false,
+            failed_encoders: Vec::new(),
+        }
+    }
+
+    // quality of the ramp, capped by the limit
+    fn quality(&self) -> f64 {
+        self.quality_ramp
+            .as_ref()
+            .map_or(1.0, |quality_ramp| quality_ramp.quality())
+            .min(self.quality_limit)
+    }
+
+    fn set_quality_limit(&mut self, quality_limit: f64) {
+        self.quality_limit = quality_limit;
+        let quality = self.quality();
+        if let Some(encoder) = self.encoder.as_mut() {
+            encoder.set_quality(quality);
+        }
+    }
+
+    fn encode(
+        &mut self,
+        pixel_data: PixelProvider,
+        captured_at: Instant,
+        (width_in, height_in, width_out, height_out): (usize, usize, usize, usize),
+        mut encoder_options: EncoderOptions,
+        keyframe: bool,
+    ) {
+        encoder_options = self
+            .failed_encoders
+            .iter()
+            .fold(encoder_options, |options, encoder| {
+                options.without_encoder(encoder).unwrap_or(options)
+            });
+        // video encoder is not setup or setup for encoding the wrong size: restart it
+        if self.encoder.is_none()
+            || !self
+                .encoder
+                .as_ref()
+                .unwrap()
+                .check_size(width_in, height_in, width_out, height_out)
+        {
+            if self.encoder.is_some() {
+                // the capturable has been resized, for example the screen resolution changed or a
+                // window was resized
+                info!(
+                    "Frame size changed to {}x{}, restarting video encoder with output size \
+                    {}x{}.",
+                    width_in, height_in, width_out, height_out
+                );
+            }
+            let sizes = (width_in, height_in, width_out, height_out);
+            let (sender, send_time, stats) = (&self.sender, &self.send_time, &self.stats);
+            let recorder = self.recorder.as_deref();
+            let frame_header = self.frame_header;
+            let res = match new_video_encoder(
+                sender,
+                send_time,
+                stats,
+                recorder,
+                sizes,
+                encoder_options,
+                frame_header,
+            ) {
+                Err(err) if encoder_options.codec != VideoCodec::H264 => {
+                    warn!(
+                        "Failed to start {} encoder, falling back to H.264: {}",
+                        encoder_options.codec.name(),
+                        err
+                    );
+                    encoder_options.codec = VideoCodec::H264;
+                    new_video_encoder(
+                        sender,
+                        send_time,
+                        stats,
+                        recorder,
+                        sizes,
+                        encoder_options,
+                        frame_header,
+                    )
+                }
+                res => res,
+            };
+            match res {
+                Ok(mut r) => {
+                    // keep the bitrate adapted to the connection
+                    if let Some(bitrate_controller) = &self.bitrate_controller {
+                        r.set_max_bitrate(bitrate_controller.bitrate());
+                    }
+                    if self.quality_ramp.is_some() || self.quality_limit < 1.0 {
+                        r.set_quality(self.quality());
+                    }
+                    r.set_yuv_converter(self.yuv_converter.clone());
+                    *self.stats.encoder.lock().unwrap() = Some(r.encoder_name());
+                    *self.stats.video_started_at.lock().unwrap() = Some(r.start_time());
+                    self.encoder = Some(r);
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    HEALTH.encode.failed(&e);
+                    return;
+                }
+            };
+        }
+        let video_encoder = self.encoder.as_mut().unwrap();
+        if keyframe {
+            video_encoder.request_keyframe();
+        }
+        self.send_time.set(Duration::ZERO);
+        video_encoder.encode_at(pixel_data, captured_at);
+        if video_encoder.has_failed() {
+            // the next frame starts a new video with the next encoder of the chain
+            let name = video_encoder.encoder_name();
+            HEALTH
+                .encode
+                .failed(format!("Encoder {} keeps failing.", name));
+            if encoder_options.without_encoder(name).is_some() {
+                warn!("Encoder {} keeps failing, switching to the next one.", name);
+                self.failed_encoders.push(name);
+            } else {
+                warn!("Encoder {} keeps failing, restarting it.", name);
+            }
+            self.encoder = None;
+            return;
+        }
+        HEALTH.encode.ok();
+        self.stats.sent.fetch_add(1, Ordering::Relaxed);
+        if let Some(bitrate_controller) = &mut self.bitrate_controller {
+            let old_bitrate = bitrate_controller.bitrate();
+            if let Some(bitrate) = bitrate_controller.update(self.send_time.get()) {
+                debug!("Changing video bitrate to {} kbit/s.", bitrate);
+                video_encoder.set_max_bitrate(bitrate);
+                // frames that are in flight may be delayed so much that the client drops them, a
+                // keyframe makes sure the client can resume decoding quickly
+                if bitrate < old_bitrate {
+                    video_encoder.request_keyframe();
+                }
+                send_msg(&self.sender, &MessageOutbound::Bitrate(bitrate));
+            }
+        }
+        if let Some(quality_ramp) = &mut self.quality_ramp {
+            if let Some(quality) = quality_ramp.update(self.send_time.get()) {
+                debug!("Raising video quality to {:.0}%.", quality * 100.0);
+                video_encoder.set_quality(quality.min(self.quality_limit));
+            }
+            if quality_ramp.is_done() {
+                self.quality_ramp = None;
+            }
+        }
+    }
+}
+
+// frame waiting on the encoder thread
+#[derive(Clone)]
+struct EncodeJob {
+    pixels: OwnedPixels,
+    captured_at: Instant,
+    sizes: (usize, usize, usize, usize),
+    options: EncoderOptions,
+    keyframe: bool,
+    // number of resets of the encoder before the frame has been captured, frames left over from
+    // before the last reset are not encoded
+    generation: u64,
+}
+
+// changes to apply on the encoder thread before encoding the next frame
+#[derive(Default)]
+struct EncoderChanges {
+    // the encoder has to be restarted
+    reset: bool,
+    sender: Option<WsWriter>,
+    bitrate_controller: Option<Option<BitrateController>>,
+    frame_header: Option<bool>,
+    quality_limit: Option<f64>,
+}
+
+#[derive(Default)]
+struct EncodeQueueState {
+    // frames waiting if more than one may wait, a single one goes through the triple buffer
+    jobs: VecDeque<EncodeJob>,
+    keyframe: bool,
+    changes: EncoderChanges,
+    // buffer of a frame that has been encoded or dropped, reused for the next one
+    spare: Vec<u8>,
+    generation: u64,
+    closed: bool,
+}
+
+// Hands frames from the video thread to the encoder thread. At most `capacity` frames are held, if
+// the encoder falls behind the oldest one is dropped in favour of the new one, so capturing never
+// waits for the encoder and memory use is bounded. If only a single frame is held, frames go
+// through a triple buffer instead of the list of jobs: the video thread copies each frame into a
+// buffer the encoder thread never touches and publishes it without waiting for the encoder, the
+// lock is only taken to wake the encoder thread.
+struct EncodeQueue {
+    state: Mutex<EncodeQueueState>,
+    available: Condvar,
+    capacity: usize,
+    stats: Arc<FrameStats>,
+}
+
+// create a queue and its ends for the video and the encoder thread
+fn encode_queue(
+    capacity: usize,
+    stats: Arc<FrameStats>,
+) -> (EncodeQueueSender, EncodeQueueReceiver) {
+    let queue = Arc::new(EncodeQueue {
+        state: Mutex::new(EncodeQueueState::default()),
+        available: Condvar::new(),
+        capacity: capacity.max(1),
+        stats,
+    });
+    let (producer, consumer) = if queue.capacity == 1 {
+        let (producer, consumer) = triple_buffer(None);
+        (Some(producer), Some(consumer))
+    } else {
+        (None, None)
+    };
+    (
+        EncodeQueueSender {
+            queue: queue.clone(),
+            latest: producer,
+            generation: 0,
+        },
+        EncodeQueueReceiver {
+            queue,
+            latest: consumer,
+        },
+    )
+}
+
+impl EncodeQueue {
+    fn count_dropped(&self) {
+        trace!("Encoder is falling behind, dropping oldest frame.");
+        METRICS.frames_dropped_queue.inc();
+        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.available.notify_one();
+    }
+}
+
+// end of an EncodeQueue on the video thread
+pub struct EncodeQueueSender {
+    queue: Arc<EncodeQueue>,
+    // frames of a queue holding a single one
+    latest: Option<Producer<Option<EncodeJob>>>,
+    generation: u64,
+}
+
+impl EncodeQueueSender {
+    fn state(&self) -> MutexGuard<'_, EncodeQueueState> {
+        self.queue.state.lock().unwrap()
+    }
+
+    fn push(
+        &mut self,
+        pixel_data: &PixelProvider,
+        captured_at: Instant,
+        sizes: (usize, usize, usize, usize),
+        options: EncoderOptions,
+        keyframe: bool,
+    ) {
+        let latest = match &mut self.latest {
+            Some(latest) => latest,
+            None => {
+                let spare = std::mem::take(&mut self.state().spare);
+                let mut job = EncodeJob {
+                    pixels: OwnedPixels::copy_from(pixel_data, spare),
+                    captured_at,
+                    sizes,
+                    options,
+                    keyframe,
+                    generation: self.generation,
+                };
+                let mut state = self.state();
+                while state.jobs.len() >= self.queue.capacity {
+                    let dropped = state.jobs.pop_front().unwrap();
+                    self.queue.count_dropped();
+                    // the dropped frame may have been due to start a new delta chain, for example
+                    // after the client lost frames, so its successor takes over
+                    job.keyframe |= dropped.keyframe;
+                    state.spare = dropped.pixels.into_data();
+                }
+                state.jobs.push_back(job);
+                METRICS.encode_queue_depth.set(state.jobs.len() as f64);
+                self.queue.available.notify_one();
+                return;
+            }
+        };
+        // the back buffer holds a frame that has been encoded or dropped, reuse its buffer
+        let spare = latest
+            .back()
+            .take()
+            .map_or_else(Vec::new, |job| job.pixels.into_data());
+        *latest.back() = Some(EncodeJob {
+            pixels: OwnedPixels::copy_from(pixel_data, spare),
+            captured_at,
+            sizes,
+            options,
+            keyframe,
+            generation: self.generation,
+        });
+        let mut dropped_keyframe = false;
+        if latest.publish() {
+            // the frame that has been overwritten is the back buffer now
+            self.queue.count_dropped();
+            dropped_keyframe = latest.back().as_ref().map_or(false, |job| {
+                job.keyframe && job.generation == self.generation
+            });
+        }
+        let mut state = self.state();
+        state.keyframe |= dropped_keyframe;
+        METRICS.encode_queue_depth.set(1.0);
+        self.queue.available.notify_one();
+    }
+
+    // drop the frames waiting and restart the encoder with the next one
+    fn reset(&mut self) {
+        self.generation += 1;
+        let mut state = self.state();
+        state.generation = self.generation;
+        state.jobs.clear();
+        state.changes.reset = true;
+        state.keyframe = false;
+        METRICS.encode_queue_depth.set(0.0);
+    }
+}
+
+// end of an EncodeQueue on the encoder thread
+struct EncodeQueueReceiver {
+    queue: Arc<EncodeQueue>,
+    latest: Option<Consumer<Option<EncodeJob>>>,
+}
+
+impl EncodeQueueReceiver {
+    // wait for the next frame, none once the queue has been closed
+    fn pop(&mut self) -> Option<(EncodeJob, EncoderChanges)> {
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if state.closed {
+                return None;
+            }
+            let job = match &mut self.latest {
+                Some(latest) => {
+                    let current = latest.update()
+                        && latest
+                            .front()
+                            .as_ref()
+                            .map_or(false, |job| job.generation == state.generation);
+                    if current {
+                        latest.front_mut().take()
+                    } else {
+                        None
+                    }
+                }
+                None => state.jobs.pop_front(),
+            };
+            if let Some(mut job) = job {
+                METRICS.encode_queue_depth.set(state.jobs.len() as f64);
+                job.keyframe |= std::mem::take(&mut state.keyframe);
+                return Some((job, std::mem::take(&mut state.changes)));
+            }
+            state = self.queue.available.wait(state).unwrap();
+        }
+    }
+
+    // hand back the frame that has been encoded so its buffer is reused
+    fn recycle(&mut self, job: EncodeJob) {
+        match &mut self.latest {
+            Some(latest) => *latest.front_mut() = Some(job),
+            None => self.queue.state.lock().unwrap().spare = job.pixels.into_data(),
+        }
+    }
+}
+
+fn handle_encoding(mut queue: EncodeQueueReceiver, mut stage: VideoStage) {
+    while let Some((job, changes)) = queue.pop() {
+        if let Some(sender) = changes.sender {
+            stage.sender = sender;
+        }
+        if let Some(bitrate_controller) = changes.bitrate_controller {
+            stage.bitrate_controller = bitrate_controller;
+        }
+        if let Some(frame_header) = changes.frame_header {
+            stage.frame_header = frame_header;
+        }
+        if let Some(quality_limit) = changes.quality_limit {
+            stage.set_quality_limit(quality_limit);
+        }
+        if changes.reset {
+            stage.encoder = None;
+        }
+        stage.encode(
+            job.pixels.pixels(),
+            job.captured_at,
+            job.sizes,
+            job.options,
+            job.keyframe,
+        );
+        queue.recycle(job);
+    }
+}
+
+// Where captured frames are encoded: right away on the video thread or, if frames are queued, on
+// an encoder thread of their own.
+pub enum VideoEncoding {
+    Inline(VideoStage),
+    Queued {
+        queue: EncodeQueueSender,
+        // frames have been queued since the encoder has been reset
+        started: bool,
+        // encodes the frames of the queue, stops once the queue is closed
+        thread: Option<JoinHandle<()>>,
+    },
+}
+
+impl VideoEncoding {
+    pub fn new(
+        sender: &WsWriter,
+        bitrate_controller: Option<BitrateController>,
+        quality_ramp: Option<QualityRamp>,
+        capacity: usize,
+        stats: &Arc<FrameStats>,
+        recorder: Option<Arc<SessionRecorder>>,
+        yuv_converter: Option<Arc<dyn YuvConverter>>,
+    ) -> Self {
+        if capacity == 0 {
+            return Self::Inline(VideoStage::new(
+                sender.clone(),
+                bitrate_controller,
+                quality_ramp,
+                stats.clone(),
+                recorder,
+                yuv_converter,
+            ));
+        }
+        let (queue, receiver) = encode_queue(capacity, stats.clone());
+        let thread = {
+            let sender = sender.clone();
+            let stats = stats.clone();
+            spawn(move || {
+                let stage = VideoStage::new(
+                    sender,
+                    bitrate_controller,
+                    quality_ramp,
+                    stats,
+                    recorder,
+                    yuv_converter,
+                );
+                handle_encoding(receiver, stage)
+            })
+        };
+        Self::Queued {
+            queue,
+            started: false,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn encode(
+        &mut self,
+        pixel_data: PixelProvider,
+        captured_at: Instant,
+        sizes: (usize, usize, usize, usize),
+        options: EncoderOptions,
+        keyframe: bool,
+    ) {
+        match self {
+            Self::Inline(stage) => stage.encode(pixel_data, captured_at, sizes, options, keyframe),
+            Self::Queued { queue, started, .. } => {
+                queue.push(&pixel_data, captured_at, sizes, options, keyframe);
+                *started = true;
+            }
+        }
+    }
+
+    // an encoder is running or frames are waiting for one
+    pub fn is_started(&self) -> bool {
+        match self {
+            Self::Inline(stage) => stage.encoder.is_some(),
+            Self::Queued { started, .. } => *started,
+        }
+    }
+
+    pub fn request_keyframe(&mut self) {
+        match self {
+            Self::Inline(stage) => {
+                if let Some(encoder) = stage.encoder.as_mut() {
+                    encoder.request_keyframe();
+                }
+            }
+            Self::Queued { queue, .. } => queue.state().keyframe = true,
+        }
+    }
+
+    // stop the encoder, a new one is started with the next frame
+    pub fn reset(&mut self) {
+        match self {
+            Self::Inline(stage) => stage.encoder = None,
+            Self::Queued { queue, started, .. } => {
+                queue.reset();
+                *started = false;
+            }
+        }
+    }
+
+    pub fn set_sender(&mut self, sender: &WsWriter) {
+        match self {
+            Self::Inline(stage) => stage.sender = sender.clone(),
+            Self::Queued { queue, .. } => queue.state().changes.sender = Some(sender.clone()),
+        }
+    }
+
+    // takes effect with the next video, see FrameHeader
+    pub fn set_frame_header(&mut self, frame_header: bool) {
+        match self {
+            Self::Inline(stage) => stage.frame_header = frame_header,
+            Self::Queued { queue, .. } => queue.state().changes.frame_header = Some(frame_header),
+        }
+    }
+
+    // fraction of the configured quality frames are encoded at, at most
+    pub fn set_quality_limit(&mut self, quality_limit: f64) {
+        match self {
+            Self::Inline(stage) => stage.set_quality_limit(quality_limit),
+            Self::Queued { queue, .. } => queue.state().changes.quality_limit = Some(quality_limit),
+        }
+    }
+
+    pub fn set_bitrate_controller(&mut self, bitrate_controller: Option<BitrateController>) {
+        match self {
+            Self::Inline(stage) => stage.bitrate_controller = bitrate_controller,
+            Self::Queued { queue, .. } => {
+                queue.state().changes.bitrate_controller = Some(bitrate_controller)
+            }
+        }
+    }
+}
+
+impl Drop for VideoEncoding {
+    fn drop(&mut self) {
+        if let Self::Queued { queue, thread, .. } = self {
+            queue.queue.close();
+            // the encoder is released by the thread
+            if let Some(thread) = thread.take() {
+                if thread.join().is_err() {
+                    error!("Encoding thread panicked.");
+                }
+            }
+        }
+    }
+}
+
+// Encodes frames as JPEG images and sends them to the client, the fallback for clients that can
+// not decode the video. The quality ramps up on its own as sending is measured here.
+pub struct JpegStage {
+    // none unless the client asked for the fallback
+    encoder: Option<JpegEncoder>,
+    jpeg_quality: u8,
+    // kept when the fallback is started again, none once full quality has been reached
+    quality_ramp: Option<QualityRamp>,
+    // fraction of the configured quality the BandwidthGovernor allows
+    quality_limit: f64,
+    // images are timestamped relative to this
+    started_at: Instant,
+    framed_jpeg: Vec<u8>,
+    stats: Arc<FrameStats>,
+}
+
+impl JpegStage {
+    pub fn new(config: &WsConfig, stats: Arc<FrameStats>) -> Self {
+        Self {
+            encoder: None,
+            jpeg_quality: config.jpeg_quality,
+            quality_ramp: QualityRamp::new(config),
+            quality_limit: 1.0,
+            started_at: Instant::now(),
+            framed_jpeg: Vec::new(),
+            stats,
+        }
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.encoder.is_some()
+    }
+
+    // quality of the ramp, capped by the limit
+    fn quality(&self) -> u8 {
+        let quality = self
+            .quality_ramp
+            .as_ref()
+            .map_or(1.0, |ramp| ramp.quality())
+            .min(self.quality_limit);
+        ramped_jpeg_quality(self.jpeg_quality, quality)
+    }
+
+    pub fn set_quality_limit(&mut self, quality_limit: f64) {
+        self.quality_limit = quality_limit;
+        let quality = self.quality();
+        if let Some(encoder) = self.encoder.as_mut() {
+            encoder.set_quality(quality);
+        }
+    }
+
+    pub fn start(&mut self) {
+        self.encoder = Some(JpegEncoder::new(self.quality()));
+        self.started_at = Instant::now();
+        *self.stats.video_started_at.lock().unwrap() = Some(self.started_at);
+    }
+
+    pub fn stop(&mut self) {
+        self.encoder = None;
+    }
+
+    // encode a frame scaled to `width_out`x`height_out` and send it, prefixed with a FrameHeader
+    // if `frame_header` is set
+    pub fn encode(
+        &mut self,
+        sender: &WsWriter,
+        pixel_data: PixelProvider,
+        captured_at: Instant,
+        (width_out, height_out): (usize, usize),
+        frame_header: bool,
+    ) {
+        let encoder = match self.encoder.as_mut() {
+            Some(encoder) => encoder,
+            None => return,
+        };
+        let span = debug_span!(
+            "encode",
+            width = width_out,
+            height = height_out,
+            duration_us = field::Empty
+        );
+        let start = Instant::now();
+        let jpeg = match span.in_scope(|| encoder.encode(pixel_data, width_out, height_out)) {
+            Ok(jpeg) => jpeg,
+            Err(err) => {
+                warn!("Failed to encode frame as JPEG: {}", err);
+                HEALTH.encode.failed(&err);
+                return;
+            }
+        };
+        let elapsed = start.elapsed();
+        METRICS.encode.record(elapsed);
+        span.record("duration_us", &(elapsed.as_micros() as u64));
+        drop(span);
+        METRICS.frames_encoded.inc();
+        HEALTH.encode.ok();
+        let stats = &self.stats;
+        stats.sent.fetch_add(1, Ordering::Relaxed);
+        stats.bytes.fetch_add(jpeg.len() as u64, Ordering::Relaxed);
+        let start = Instant::now();
+        if frame_header {
+            let header = FrameHeader {
+                frame_type: FrameType::Jpeg,
+                timestamp_ms: captured_at
+                    .saturating_duration_since(self.started_at)
+                    .as_millis() as u32,
+                width: width_out as u32,
+                height: height_out as u32,
+            };
+            header.encode(jpeg, &mut self.framed_jpeg);
+            send_video_data(sender, &self.framed_jpeg);
+            stats
+                .total_bytes
+                .fetch_add(self.framed_jpeg.len() as u64, Ordering::Relaxed);
+        } else {
+            send_video_data(sender, jpeg);
+            stats
+                .total_bytes
+                .fetch_add(jpeg.len() as u64, Ordering::Relaxed);
+        }
+        let send_time = start.elapsed();
+        METRICS.send.record(send_time);
+        if let Some(quality_ramp) = &mut self.quality_ramp {
+            if let Some(quality) = quality_ramp.update(send_time) {
+                encoder.set_quality(ramped_jpeg_quality(
+                    self.jpeg_quality,
+                    quality.min(self.quality_limit),
+                ));
+            }
+            if quality_ramp.is_done() {
+                self.quality_ramp = None;
+            }
+        }
+    }
+}
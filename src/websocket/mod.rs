@@ -0,0 +1,1954 @@
+use std::collections::HashMap;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::mpsc::{SendError, TryRecvError};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{sleep, spawn, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio_rustls::rustls::ServerConfig;
+use tracing::{debug, error, info, trace, warn};
+
+use websocket::receiver::Reader;
+use websocket::sender::Writer;
+use websocket::stream::sync::AsTcpStream;
+use websocket::sync::server::{IntoWs, Upgrade};
+use websocket::{Message, OwnedMessage, WebSocketError};
+
+use crate::access_tokens::{random_token, AccessTokens, ClientPermissions, InputPermission};
+#[cfg(target_os = "linux")]
+use crate::audio::AudioOptions;
+use crate::capturable::shared::SharedCaptures;
+#[cfg(target_os = "linux")]
+use crate::capturable::CaptureBackend;
+use crate::capturable::{
+    find_capturable, get_capturables, Capturable, CapturableFilter, CaptureMode, CaptureOptions,
+    ThumbnailCache,
+};
+#[cfg(target_os = "linux")]
+use crate::clipboard::X11Clipboard;
+use crate::control::{ClientControls, ControlCommand};
+use crate::health::HEALTH;
+use crate::input::device::{InputDevice, InputDeviceType};
+#[cfg(target_os = "linux")]
+use crate::input::uinput_device::UInputPool;
+use crate::jpeg::JpegEncoder;
+use crate::metrics::METRICS;
+use crate::protocol::{
+    Button, CapturableInfo, ClientConfiguration, ClientHello, InputLatency, InputMapping,
+    KeyboardEvent, MessageInbound, MessageOutbound, PointerEvent, PointerEventType, PointerType,
+    PressureCurve, ServerHello, StylusButtons, WheelEvent, PROTOCOL_VERSION,
+};
+use crate::recording::SessionRecorder;
+use crate::session_limit::{SessionEvent, SessionTimer};
+use crate::tls::{server_config, TlsFiles, WsStream};
+
+use crate::cerror::CErrorCode;
+#[cfg(target_os = "linux")]
+use crate::sched::ThreadScheduling;
+use crate::video::{
+    Color, EncoderOptions, FrameProcessor, FrameTransform, PixelProvider, QualityPreset, Rotation,
+    VideoCodec, YuvConverter,
+};
+
+#[cfg(target_os = "linux")]
+mod audio;
+mod bandwidth;
+mod cursor;
+mod encoding;
+mod pipeline;
+mod settings;
+mod stats;
+mod video;
+
+use video::{handle_video, VideoCommands, VideoConfig};
+
+type WsWriter = Arc<Mutex<WsSender>>;
+type WsClients = Arc<Mutex<HashMap<SocketAddr, WsWriter>>>;
+// address of the client that is allowed to control input if input is exclusive
+type InputOwner = Arc<Mutex<Option<SocketAddr>>>;
+// threads of sessions that lost their connection and wait for the client to reconnect, keyed by
+// session token, `None` once the server shuts down and sessions are not kept anymore
+type Sessions = Arc<Mutex<Option<HashMap<String, mpsc::Sender<Resumption>>>>>;
+// x, y, width, height of the region of the capturable shown in the video relative to its size if
+// the client cropped it, set by the video thread once the crop has been applied
+type VideoCrop = Arc<Mutex<Option<(f64, f64, f64, f64)>>>;
+// x, y, width, height of the capturable within the video relative to its size if bars have been
+// added to keep its aspect ratio, set by the video thread
+type VideoLetterbox = Arc<Mutex<Option<(f64, f64, f64, f64)>>>;
+
+// Sending half of the websocket connection of a client, which counts the bytes sent over it
+// including the framing of the websocket protocol. Every connection counts from zero, so the
+// statistics start over once a client reconnects.
+struct WsSender {
+    writer: Writer<WsStream>,
+    // sent since the last call of take_sent
+    video_bytes: u64,
+    control_bytes: u64,
+    // sent since the connection has been opened
+    total_bytes: u64,
+}
+
+impl WsSender {
+    fn new(writer: Writer<WsStream>) -> Self {
+        Self {
+            writer,
+            video_bytes: 0,
+            control_bytes: 0,
+            total_bytes: 0,
+        }
+    }
+
+    fn send(
+        &mut self,
+        message: &Message,
+        payload_len: usize,
+        video: bool,
+    ) -> Result<(), WebSocketError> {
+        self.writer.send_message(message)?;
+        // frames sent by servers are not masked and have a header of 2, 4 or 10 bytes depending on
+        // the length of the payload
+        let header = match payload_len {
+            0..=125 => 2,
+            126..=65535 => 4,
+            _ => 10,
+        };
+        let size = (header + payload_len) as u64;
+        if video {
+            self.video_bytes += size;
+            METRICS.bytes_sent_video.add(size);
+        } else {
+            self.control_bytes += size;
+            METRICS.bytes_sent_control.add(size);
+        }
+        self.total_bytes += size;
+        Ok(())
+    }
+
+    // video and control bytes sent since the last call, the counters start again from zero
+    fn take_sent(&mut self) -> (u64, u64) {
+        (
+            std::mem::take(&mut self.video_bytes),
+            std::mem::take(&mut self.control_bytes),
+        )
+    }
+}
+
+// connection of a client resuming its session, handed over to the thread of the session
+struct Resumption {
+    receiver: Reader<WsStream>,
+    sender: WsWriter,
+    address: SocketAddr,
+}
+
+/// Events of the websocket server.
+pub enum Ws2UiMessage {
+    /// The server is listening for connections.
+    Start,
+    /// A client requested uinput but /dev/uinput can not be accessed.
+    UInputInaccessible,
+    /// The server failed to start.
+    Error(String),
+}
+
+pub enum Ui2WsMessage {
+    Shutdown,
+}
+
+#[derive(Clone)]
+pub struct WsConfig {
+    // the server listens at all of these
+    pub addresses: Vec<SocketAddr>,
+    pub access_code: Option<String>,
+    pub access_tokens: AccessTokens,
+    pub client_permissions: ClientPermissions,
+    // commands of the control channel for connected clients, see --control
+    pub client_controls: ClientControls,
+    pub encoder_options: EncoderOptions,
+    #[cfg(target_os = "linux")]
+    pub wayland_support: bool,
+    // /dev/uinput could be opened at startup, clients asking for uinput are view-only otherwise
+    #[cfg(target_os = "linux")]
+    pub uinput_available: bool,
+    #[cfg(target_os = "linux")]
+    pub capture_backend: CaptureBackend,
+    // align X11 captures with vertical blanks to avoid tearing
+    #[cfg(target_os = "linux")]
+    pub vsync_capture: bool,
+    // capture audio for clients that enable it, see --audio
+    #[cfg(target_os = "linux")]
+    pub audio: Option<AudioOptions>,
+    pub capture_mode: CaptureMode,
+    // frames per second sent as keyframes while the screen does not change in damage mode
+    pub heartbeat_fps: f64,
+    #[cfg(target_os = "linux")]
+    pub clipboard_sync: bool,
+    // in bytes
+    #[cfg(target_os = "linux")]
+    pub clipboard_max_size: usize,
+    pub skip_unchanged_frames: bool,
+    // number of skipped unchanged frames after which a frame is sent anyway
+    pub unchanged_frames_refresh: Option<u32>,
+    // fraction of a frame that has to change at once for it to be sent as keyframe
+    pub scene_change_threshold: Option<f64>,
+    pub scene_change_cooldown: Duration,
+    // minimum time between two keyframes requested by the client
+    pub keyframe_request_cooldown: Duration,
+    // bits per channel kept of captured frames, none keeps them as they are
+    pub quantize_bits: Option<u8>,
+    // reduce captured frames to 16 bit colors
+    pub low_color: bool,
+    // color the alpha channel of captured frames is flattened onto, none ignores alpha
+    pub alpha_background: Option<Color>,
+    // color fully transparent pixels of captured frames are replaced with, none keeps them
+    pub transparent_fill: Option<Color>,
+    // color of the bars added to keep the aspect ratio of frames, none stretches them
+    pub letterbox: Option<Color>,
+    // factor the cursor is scaled by, in the video and the overlay drawn by clients
+    pub cursor_scale: f64,
+    // largest width or height of the video
+    pub max_video_size: Option<usize>,
+    // fixed factor frames are scaled by instead of fitting them into the screen of the client
+    pub capture_scale: Option<f64>,
+    pub max_fps: Option<f64>,
+    // capture at most at the refresh rate of the capturable, even if max_fps is higher
+    pub sync_to_refresh_rate: bool,
+    pub quality_preset: Option<QualityPreset>,
+    // id or part of the name of the capturable selected for clients that have not chosen one
+    pub capturable: Option<String>,
+    // records the video sent to clients
+    pub recorder: Option<Arc<SessionRecorder>>,
+    // capturables not matching are not offered to clients
+    pub capturable_filter: Option<CapturableFilter>,
+    // given by the application embedding Weylus, listed after the ones found on the system
+    pub extra_capturables: Vec<Box<dyn Capturable>>,
+    // given by the application embedding Weylus, modifies frames before encoding them
+    pub frame_processor: Option<Arc<dyn FrameProcessor>>,
+    // given by the application embedding Weylus, replaces the conversion to YUV
+    pub yuv_converter: Option<Arc<dyn YuvConverter>>,
+    pub jpeg_quality: u8,
+    pub jpeg_max_fps: f64,
+    pub min_bitrate: u32,
+    // hard limit in kbit/s of the video sent to each client, see BandwidthGovernor
+    pub bandwidth_cap: Option<u32>,
+    pub max_clients: Option<usize>,
+    // clients are disconnected once they have been connected for this long
+    pub max_session_duration: Option<Duration>,
+    pub exclusive_input: bool,
+    pub invert_scroll: bool,
+    // pointer moves arriving within this time of each other are merged if more input is waiting,
+    // zero sends every move
+    pub input_coalescing_window: Duration,
+    pub congestion_strategy: CongestionStrategy,
+    // number of unsent bytes in the send buffer of the socket above which the connection is
+    // considered congested
+    pub send_buffer_threshold: usize,
+    // how long the session of a client that lost its connection is kept, zero disables resuming
+    // sessions
+    pub session_grace_period: Duration,
+    // how long the video may be paused before the recorder and encoder are released, zero keeps
+    // them
+    pub pause_release_timeout: Duration,
+    // keep capture and encoder of disconnected clients until their session expires
+    pub keep_capture_warm: bool,
+    // how long the captured image may stay the same before the recorder is restarted, zero never
+    // restarts it
+    pub capture_stall_timeout: Duration,
+    // how long frames of a recorder that just started are held back while they are blank, zero
+    // delivers them right away
+    pub content_timeout: Duration,
+    // number of captured frames that may wait for the encoder thread, zero encodes frames on the
+    // video thread right after capturing them
+    pub encode_queue: usize,
+    // captures shared by all clients, none if every client captures for itself
+    pub shared_captures: Option<SharedCaptures>,
+    // clients connect with WSS instead of WS if given
+    pub tls_files: Option<TlsFiles>,
+    // fraction of the configured quality video starts at after connecting, none starts at full
+    // quality
+    pub quality_ramp_start: Option<f64>,
+    // fraction added to the quality after sending frames for quality_ramp_interval without
+    // congestion
+    pub quality_ramp_step: f64,
+    pub quality_ramp_interval: Duration,
+    // priority and CPUs of the threads capturing and encoding frames
+    #[cfg(target_os = "linux")]
+    pub thread_scheduling: ThreadScheduling,
+}
+
+/// What to do if a client can not keep up with the video stream.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CongestionStrategy {
+    /// Keep sending every frame, sending blocks until the client received enough data. This
+    /// keeps the video smooth but latency increases on slow connections.
+    #[default]
+    Block,
+    /// Drop frames while the send buffer is above the threshold and continue with a keyframe,
+    /// this keeps latency low at the cost of smoothness.
+    Drop,
+}
+
+impl FromStr for CongestionStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "block" => Ok(Self::Block),
+            "drop" => Ok(Self::Drop),
+            _ => Err(format!(
+                "Unknown congestion strategy: '{}', expected 'block' or 'drop'.",
+                s
+            )),
+        }
+    }
+}
+
+// Number of bytes in the send buffer of the socket that have not been sent yet, None if this can
+// not be determined on the current platform.
+#[cfg(target_os = "linux")]
+fn unsent_bytes(stream: &TcpStream) -> Option<usize> {
+    use std::os::raw::{c_int, c_ulong};
+    use std::os::unix::io::AsRawFd;
+    extern "C" {
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+    const SIOCOUTQ: c_ulong = 0x5411;
+    let mut unsent: c_int = 0;
+    if unsafe { ioctl(stream.as_raw_fd(), SIOCOUTQ, &mut unsent as *mut c_int) } < 0 {
+        return None;
+    }
+    Some(unsent as usize)
+}
+
+#[cfg(target_os = "macos")]
+fn unsent_bytes(stream: &TcpStream) -> Option<usize> {
+    use std::os::raw::{c_int, c_uint, c_void};
+    use std::os::unix::io::AsRawFd;
+    extern "C" {
+        fn getsockopt(
+            socket: c_int,
+            level: c_int,
+            name: c_int,
+            value: *mut c_void,
+            len: *mut c_uint,
+        ) -> c_int;
+    }
+    const SOL_SOCKET: c_int = 0xffff;
+    const SO_NWRITE: c_int = 0x1024;
+    let mut unsent: c_int = 0;
+    let mut len = std::mem::size_of::<c_int>() as c_uint;
+    if unsafe {
+        getsockopt(
+            stream.as_raw_fd(),
+            SOL_SOCKET,
+            SO_NWRITE,
+            &mut unsent as *mut c_int as *mut c_void,
+            &mut len,
+        )
+    } < 0
+    {
+        return None;
+    }
+    Some(unsent as usize)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn unsent_bytes(_stream: &TcpStream) -> Option<usize> {
+    None
+}
+
+// Number of bytes received by the socket that have not been read yet, None if this can not be
+// determined on the current platform.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn unread_bytes(stream: &TcpStream) -> Option<usize> {
+    use std::os::raw::{c_int, c_ulong};
+    use std::os::unix::io::AsRawFd;
+    extern "C" {
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+    #[cfg(target_os = "linux")]
+    const FIONREAD: c_ulong = 0x541b;
+    #[cfg(target_os = "macos")]
+    const FIONREAD: c_ulong = 0x4004667f;
+    let mut unread: c_int = 0;
+    if unsafe { ioctl(stream.as_raw_fd(), FIONREAD, &mut unread as *mut c_int) } < 0 {
+        return None;
+    }
+    Some(unread as usize)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn unread_bytes(_stream: &TcpStream) -> Option<usize> {
+    None
+}
+
+fn log_send_error<T>(res: Result<(), SendError<T>>) {
+    if let Err(err) = res {
+        warn!("Websocket: Failed to send message to ui: {}", err);
+    }
+}
+
+// The clipboard of the host shared with clients: text sent by a client is written to the clipboard
+// and whenever the clipboard changes, the text is sent to all subscribed clients.
+#[cfg(target_os = "linux")]
+#[derive(Clone)]
+struct ClipboardShare {
+    sender: mpsc::Sender<String>,
+    subscribers: WsClients,
+}
+
+#[cfg(target_os = "linux")]
+impl ClipboardShare {
+    // the thread syncing the clipboard stops once the share and all its clones are dropped
+    fn new(max_size: usize) -> (Self, JoinHandle<()>) {
+        let (sender, receiver) = mpsc::channel();
+        let subscribers: WsClients = Arc::new(Mutex::new(HashMap::new()));
+        let thread = {
+            let subscribers = subscribers.clone();
+            // the clipboard has its own connection to the X server, which is used only by this
+            // thread
+            spawn(move || handle_clipboard(receiver, subscribers, max_size))
+        };
+        (
+            Self {
+                sender,
+                subscribers,
+            },
+            thread,
+        )
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn handle_clipboard(receiver: mpsc::Receiver<String>, subscribers: WsClients, max_size: usize) {
+    let mut clipboard = match X11Clipboard::new(max_size) {
+        Ok(clipboard) => clipboard,
+        Err(err) => {
+            warn!(
+                "Failed to access clipboard, clipboard sync is disabled: {}",
+                err
+            );
+            return;
+        }
+    };
+    let broadcast = |text: String| {
+        let msg = MessageOutbound::Clipboard(text);
+        let subscribers: Vec<WsWriter> = subscribers.lock().unwrap().values().cloned().collect();
+        for subscriber in &subscribers {
+            send_msg(subscriber, &msg);
+        }
+    };
+    loop {
+        // only the most recent text matters
+        let mut text = None;
+        loop {
+            match receiver.try_recv() {
+                Ok(t) => text = Some(t),
+                Err(TryRecvError::Empty) => break,
+                // stop once the server and all clients are gone
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        if let Some(text) = text {
+            match clipboard.set_text(&text) {
+                // let the other clients know as well
+                Ok(_) => broadcast(text),
+                Err(err) => warn!("Failed to write clipboard: {}", err),
+            }
+        }
+        match clipboard.poll(Duration::from_millis(50)) {
+            Ok(Some(text)) => {
+                trace!("Clipboard changed, sending it to clients.");
+                broadcast(text);
+            }
+            Ok(None) => (),
+            Err(_) => {
+                warn!("Lost connection to the X server, reconnecting clipboard once it is back.");
+                clipboard = loop {
+                    sleep(Duration::from_secs(1));
+                    // text sent in the meantime can not be shared anyway
+                    loop {
+                        match receiver.try_recv() {
+                            Ok(_) => (),
+                            Err(TryRecvError::Empty) => break,
+                            Err(TryRecvError::Disconnected) => return,
+                        }
+                    }
+                    if let Ok(clipboard) = X11Clipboard::new(max_size) {
+                        debug!("Reconnected clipboard.");
+                        break clipboard;
+                    }
+                };
+            }
+        }
+    }
+}
+
+pub fn run(
+    sender: mpsc::Sender<Ws2UiMessage>,
+    receiver: mpsc::Receiver<Ui2WsMessage>,
+    config: WsConfig,
+) -> std::thread::JoinHandle<()> {
+    spawn(move || {
+        let clients: WsClients = Arc::new(Mutex::new(HashMap::new()));
+        let input_owner: InputOwner = Arc::new(Mutex::new(None));
+        let sessions: Sessions = Arc::new(Mutex::new(Some(HashMap::new())));
+        #[cfg(target_os = "linux")]
+        let (clipboard, clipboard_thread) = if config.clipboard_sync {
+            let (clipboard, thread) = ClipboardShare::new(config.clipboard_max_size);
+            (Some(clipboard), Some(thread))
+        } else {
+            (None, None)
+        };
+        // threads of connected clients together with their connection, so they can be woken up
+        // and waited for on shutdown
+        let mut connections: Vec<(Option<TcpStream>, JoinHandle<()>)> = Vec::new();
+        // kept until the server shuts down so reconnecting clients get the same devices again
+        #[cfg(target_os = "linux")]
+        let uinput_pool = UInputPool::new();
+
+        let tls = match config.tls_files.as_ref().map(server_config).transpose() {
+            Ok(tls) => tls.map(Arc::new),
+            Err(err) => {
+                log_send_error(sender.send(Ws2UiMessage::Error(err)));
+                return;
+            }
+        };
+        let mut servers = Vec::new();
+        for address in &config.addresses {
+            match TcpListener::bind(address) {
+                Ok(server) => {
+                    if let Err(err) = server.set_nonblocking(true) {
+                        warn!(
+                            "Could not set websocket to non-blocking, graceful shutdown may be \
+                            impossible now: {}",
+                            err
+                        );
+                    }
+                    if tls.is_some() {
+                        info!("Websocket server listening at {} with TLS...", address);
+                    } else {
+                        info!("Websocket server listening at {}...", address);
+                    }
+                    servers.push(server);
+                }
+                Err(e) => {
+                    log_send_error(sender.send(Ws2UiMessage::Error(format!(
+                        "Failed binding to socket {}: {}",
+                        address, e
+                    ))));
+                    return;
+                }
+            }
+        }
+
+        log_send_error(sender.send(Ws2UiMessage::Start));
+
+        loop {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            connections.retain(|(_, thread)| !thread.is_finished());
+
+            match receiver.try_recv() {
+                Err(TryRecvError::Disconnected) | Ok(Ui2WsMessage::Shutdown) => {
+                    for address in &config.addresses {
+                        info!("Shutting down websocket: {}", address);
+                    }
+                    for client in clients.lock().unwrap().values() {
+                        let client = client.lock().unwrap();
+                        if let Err(err) = client.writer.shutdown_all() {
+                            error!("Could not shutdown websocket client: {}", err);
+                        }
+                    }
+                    // clients that have not been accepted completely are not known by address yet
+                    for stream in connections.iter().filter_map(|(stream, _)| stream.as_ref()) {
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                    // let the threads of parked sessions know there is nothing to wait for anymore
+                    // and keep new ones from being parked
+                    *sessions.lock().unwrap() = None;
+                    // this stops video threads, releasing recorders and encoders, and input devices
+                    for (_, thread) in connections.drain(..) {
+                        if thread.join().is_err() {
+                            error!("Websocket client thread panicked.");
+                        }
+                    }
+                    #[cfg(target_os = "linux")]
+                    {
+                        drop(clipboard);
+                        if let Some(thread) = clipboard_thread {
+                            if thread.join().is_err() {
+                                error!("Clipboard thread panicked.");
+                            }
+                        }
+                    }
+                    return;
+                }
+                _ => {}
+            }
+            for (tcp, _) in servers.iter().filter_map(|server| server.accept().ok()) {
+                let stream = tcp.try_clone().ok();
+                let tls = tls.clone();
+                let clients = clients.clone();
+                let input_owner = input_owner.clone();
+                let sessions = sessions.clone();
+                #[cfg(target_os = "linux")]
+                let clipboard = clipboard.clone();
+                #[cfg(target_os = "linux")]
+                let uinput_pool = uinput_pool.clone();
+                let config = config.clone();
+                let sender = sender.clone();
+                let thread = spawn(move || {
+                    let request = match upgrade(tcp, tls) {
+                        Some(request) => request,
+                        None => return,
+                    };
+                    handle_connection(
+                        request,
+                        clients,
+                        input_owner,
+                        sessions,
+                        #[cfg(target_os = "linux")]
+                        clipboard,
+                        #[cfg(target_os = "linux")]
+                        uinput_pool,
+                        config,
+                        sender,
+                    )
+                });
+                connections.push((stream, thread));
+            }
+        }
+    })
+}
+
+// Read the request of a client to open a websocket, after the TLS handshake if the server has a
+// certificate.
+fn upgrade(tcp: TcpStream, tls: Option<Arc<ServerConfig>>) -> Option<Upgrade<WsStream>> {
+    // connections accepted by a non-blocking listener may be non-blocking as well
+    if let Err(err) = tcp.set_nonblocking(false) {
+        warn!("Failed to set client to blocking mode: {}", err);
+    }
+    let stream = match WsStream::new(tcp, tls) {
+        Ok(stream) => stream,
+        Err(err) => {
+            warn!("Failed to set up TLS for client: {}", err);
+            return None;
+        }
+    };
+    match stream.into_ws() {
+        Ok(request) => Some(request),
+        Err((_, _, _, err)) => {
+            debug!("Not a websocket request: {}", err);
+            None
+        }
+    }
+}
+
+fn handle_connection(
+    request: Upgrade<WsStream>,
+    clients: WsClients,
+    input_owner: InputOwner,
+    sessions: Sessions,
+    #[cfg(target_os = "linux")] clipboard: Option<ClipboardShare>,
+    #[cfg(target_os = "linux")] uinput_pool: UInputPool,
+    config: WsConfig,
+    gui_sender: mpsc::Sender<Ws2UiMessage>,
+) {
+    let client = match request.accept() {
+        Ok(c) => c,
+        Err((_, err)) => {
+            warn!("Failed to accept client: {}", err);
+            return;
+        }
+    };
+
+    let mut peer_addr = match client.peer_addr() {
+        Ok(p) => p,
+        Err(err) => {
+            warn!("Failed to retrieve client address: {}", err);
+            return;
+        }
+    };
+
+    let (mut ws_receiver, ws_sender) = match client.split() {
+        Ok(s) => s,
+        Err(err) => {
+            warn!("Failed to setup connection: {}", err);
+            return;
+        }
+    };
+
+    let mut ws_sender = Arc::new(Mutex::new(WsSender::new(ws_sender)));
+    info!(address = ?peer_addr, "Client connected.");
+
+    let mut ws_handler = WsHandler::new(
+        ws_sender.clone(),
+        config.clone(),
+        gui_sender,
+        peer_addr,
+        input_owner,
+        #[cfg(target_os = "linux")]
+        clipboard,
+        #[cfg(target_os = "linux")]
+        uinput_pool,
+    );
+
+    let grace_period = config.session_grace_period;
+    let mut authed = config.access_code.is_none();
+    if authed {
+        if !admit_client(&clients, config.max_clients, peer_addr, &ws_sender) {
+            return;
+        }
+        config
+            .client_permissions
+            .insert(peer_addr, InputPermission::Full);
+    }
+    let access_code = config.access_code.unwrap_or_else(|| "".into());
+    // a reconnecting client sends its session token instead of the access code
+    let mut first_message = true;
+    loop {
+        let mut resumed_session = None;
+        // used to check if more input is waiting while the receiver is busy reading messages
+        // with TLS this misses records already read from the socket but not decrypted yet
+        let input_stream = ws_receiver.stream.get_ref().as_tcp().try_clone().ok();
+        for msg in ws_receiver.incoming_messages() {
+            match msg {
+                Ok(msg) => {
+                    if first_message {
+                        first_message = false;
+                        if let OwnedMessage::Text(s) = &msg {
+                            if let Ok(MessageInbound::ResumeSession(token)) =
+                                serde_json::from_str(s)
+                            {
+                                resumed_session = sessions
+                                    .lock()
+                                    .unwrap()
+                                    .as_mut()
+                                    .and_then(|sessions| sessions.remove(&token));
+                                if let Some(session) = resumed_session.take() {
+                                    // the session token stands in for the access code
+                                    if !authed
+                                        && !admit_client(
+                                            &clients,
+                                            config.max_clients,
+                                            peer_addr,
+                                            &ws_sender,
+                                        )
+                                    {
+                                        // leave the session to be resumed once there is room
+                                        if let Some(sessions) = sessions.lock().unwrap().as_mut() {
+                                            sessions.insert(token, session);
+                                        }
+                                        return;
+                                    }
+                                    resumed_session = Some(session);
+                                    break;
+                                }
+                                debug!(address = ?peer_addr, "Session expired, starting a new one.");
+                                send_msg(&ws_sender, &MessageOutbound::SessionExpired);
+                                continue;
+                            }
+                        }
+                    }
+                    if !authed {
+                        if let OwnedMessage::Text(pw) = &msg {
+                            let permission = if pw == &access_code {
+                                Some(InputPermission::Full)
+                            } else {
+                                config.access_tokens.consume(pw)
+                            };
+                            if let Some(permission) = permission {
+                                if !admit_client(
+                                    &clients,
+                                    config.max_clients,
+                                    peer_addr,
+                                    &ws_sender,
+                                ) {
+                                    return;
+                                }
+                                authed = true;
+                                config.client_permissions.insert(peer_addr, permission);
+                                info!(
+                                    address = ?peer_addr,
+                                    %permission,
+                                    "WS-Client authenticated!"
+                                );
+                            } else {
+                                warn!(
+                                    address = ?peer_addr,
+                                    access_code = %pw,
+                                    "Authentication failed, wrong access code",
+                                );
+                                return;
+                            }
+                        }
+                    } else {
+                        let more_input = input_stream
+                            .as_ref()
+                            .and_then(unread_bytes)
+                            .map_or(false, |unread| unread > 0);
+                        ws_handler.process(&msg, more_input);
+                    }
+                    if msg.is_close() {
+                        info!(address = ?peer_addr, "Client disconnected.");
+                        break;
+                    }
+                }
+                Err(err) => {
+                    match err {
+                        // this happens on calling shutdown, no need to log this
+                        WebSocketError::NoDataAvailable => (),
+                        _ => warn!("Error reading message from websocket, closing ({})", err),
+                    }
+                    break;
+                }
+            }
+        }
+
+        if let Some(session) = resumed_session {
+            info!(address = ?peer_addr, "Client resumed its session.");
+            // the new handler has to be gone before the session takes over the address
+            drop(ws_handler);
+            let resumption = Resumption {
+                receiver: ws_receiver,
+                sender: ws_sender,
+                address: peer_addr,
+            };
+            if session.send(resumption).is_err() {
+                warn!(address = ?peer_addr, "Failed to resume session.");
+            }
+            return;
+        }
+
+        clients.lock().unwrap().remove(&peer_addr);
+        if ws_handler.session_expired() {
+            // dropping the handler releases the input device and stops capturing
+            info!(address = ?peer_addr, "Session reached its maximum duration.");
+            return;
+        }
+        let token = match ws_handler.session_token() {
+            Some(token) if !grace_period.is_zero() => token.to_string(),
+            _ => return,
+        };
+
+        // keep input device and capture around for a while, so the client can pick up where it
+        // left off if it only lost its connection
+        let (session_sender, session_receiver) = mpsc::channel();
+        match sessions.lock().unwrap().as_mut() {
+            Some(sessions) => sessions.insert(token.clone(), session_sender),
+            // the server is shutting down
+            None => return,
+        };
+        ws_handler.park();
+        // a limited session is not kept beyond its end
+        let keep_for = ws_handler
+            .session_timer
+            .as_ref()
+            .map_or(grace_period, |t| t.remaining().min(grace_period));
+        debug!(address = ?peer_addr, "Keeping session for {:?}.", keep_for);
+        let resumption = match session_receiver.recv_timeout(keep_for) {
+            Ok(resumption) => Some(resumption),
+            // if the session is gone already, a client just took it over and is about to hand over
+            // its connection
+            Err(_) => {
+                let removed = sessions
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .map_or(false, |sessions| sessions.remove(&token).is_some());
+                if removed {
+                    None
+                } else {
+                    session_receiver.recv().ok()
+                }
+            }
+        };
+        match resumption {
+            Some(resumption) => {
+                ws_receiver = resumption.receiver;
+                peer_addr = resumption.address;
+                ws_sender = resumption.sender.clone();
+                ws_handler.resume(resumption.sender, peer_addr);
+            }
+            None => {
+                // dropping the handler releases the input device
+                info!(address = ?peer_addr, "Session expired.");
+                return;
+            }
+        }
+    }
+}
+
+// Count the client as connected unless --max-clients is reached, in which case it is told so and
+// disconnected. Only clients that authenticated are counted, so connecting without knowing the
+// access code does not take up any of the slots.
+fn admit_client(
+    clients: &WsClients,
+    max_clients: Option<usize>,
+    peer_addr: SocketAddr,
+    ws_sender: &WsWriter,
+) -> bool {
+    let mut clients = clients.lock().unwrap();
+    if max_clients.map_or(false, |max| clients.len() >= max) {
+        warn!(address = ?peer_addr, "Maximum number of clients reached, rejecting client.");
+        send_msg(
+            ws_sender,
+            &MessageOutbound::Error("Maximum number of clients reached!".into()),
+        );
+        if let Err(err) = ws_sender.lock().unwrap().writer.shutdown_all() {
+            debug!("Failed to shutdown connection: {}", err);
+        }
+        return false;
+    }
+    clients.insert(peer_addr, ws_sender.clone());
+    true
+}
+
+// Seconds of the duration rounded up, so a session is not announced to be over before it is.
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+// Tell the client of a limited session what happened to it, the client is disconnected once the
+// session ended, which ends the session handler as well.
+fn notify_session_event(sender: Option<&WsWriter>, event: SessionEvent) {
+    let sender = match sender {
+        Some(sender) => sender,
+        // the client is disconnected and still told about its session once it resumes it
+        None => return,
+    };
+    match event {
+        SessionEvent::Remaining(remaining) => send_msg(
+            sender,
+            &MessageOutbound::SessionRemaining(whole_seconds(remaining)),
+        ),
+        SessionEvent::Ending(remaining) => send_msg(
+            sender,
+            &MessageOutbound::SessionEnding(whole_seconds(remaining)),
+        ),
+        SessionEvent::Ended => {
+            send_msg(sender, &MessageOutbound::SessionEnded);
+            if let Err(err) = sender.lock().unwrap().writer.shutdown_all() {
+                debug!("Failed to shutdown connection: {}", err);
+            }
+        }
+    }
+}
+
+fn send_msg(sender: &WsWriter, msg: &MessageOutbound) {
+    let text = serde_json::to_string(msg).unwrap();
+    let len = text.len();
+    if let Err(err) = sender
+        .lock()
+        .unwrap()
+        .send(&Message::text(text), len, false)
+    {
+        warn!("Failed to send message to websocket: {}", err);
+    }
+}
+
+// Map a pointer event on the rotated and flipped video to the captured frame.
+fn untransform_pointer_event(event: &mut PointerEvent, transform: FrameTransform) {
+    (event.x, event.y) = transform.untransform_point(event.x, event.y);
+    let (movement_x, movement_y) =
+        transform.untransform_vector(event.movement_x as f64, event.movement_y as f64);
+    (event.movement_x, event.movement_y) = (movement_x as i64, movement_y as i64);
+    let (tilt_x, tilt_y) = transform.untransform_vector(event.tilt_x as f64, event.tilt_y as f64);
+    (event.tilt_x, event.tilt_y) = (tilt_x as i32, tilt_y as i32);
+    (event.width, event.height) = match transform.rotation {
+        Rotation::Deg90 | Rotation::Deg270 => (event.height, event.width),
+        Rotation::Deg0 | Rotation::Deg180 => (event.width, event.height),
+    };
+}
+
+/// Maximum width and height of thumbnails of capturables in pixels.
+const THUMBNAIL_SIZE: usize = 240;
+/// Thumbnails are taken again once they are older than this, which keeps refreshing the list of
+/// capturables from starting a recorder for each of them every time.
+const THUMBNAIL_MAX_AGE: Duration = Duration::from_secs(10);
+
+struct WsHandler {
+    sender: WsWriter,
+    video_sender: mpsc::Sender<VideoCommands>,
+    // stops once video_sender is dropped
+    video_thread: Option<JoinHandle<()>>,
+    input_device: Option<Box<dyn InputDevice>>,
+    // no input device could be set up for the config of the client, see InputDisabled
+    input_disabled: bool,
+    capturables: Vec<Box<dyn Capturable>>,
+    gui_sender: mpsc::Sender<Ws2UiMessage>,
+    ws_config: WsConfig,
+    #[cfg(target_os = "linux")]
+    capture_cursor: bool,
+    client_name: Option<String>,
+    client_address: SocketAddr,
+    input_owner: InputOwner,
+    pressure_curve: PressureCurve,
+    input_mapping: Option<InputMapping>,
+    stylus_buttons: StylusButtons,
+    #[cfg(target_os = "linux")]
+    clipboard: Option<ClipboardShare>,
+    #[cfg(target_os = "linux")]
+    uinput_pool: UInputPool,
+    // handed to the client once it is set up, allows resuming the session after reconnecting
+    session_token: Option<String>,
+    transform: FrameTransform,
+    // pointer moves held back because more input is waiting, only the latest one of each pointer
+    // is sent once the coalescing window is over or any other input arrives, along with the time
+    // they have been received at
+    pending_moves: Vec<(PointerEvent, Instant)>,
+    pending_since: Option<Instant>,
+    thumbnails: ThumbnailCache,
+    video_crop: VideoCrop,
+    video_letterbox: VideoLetterbox,
+    // capabilities the client announced in its handshake, none for clients that do not send one
+    client_hello: Option<ClientHello>,
+    // set by the client via FilterCapturables, applied on top of the filter of the server
+    capturable_filter: Option<CapturableFilter>,
+    // commands of the control channel, applied before the next message of the client
+    control_receiver: mpsc::Receiver<ControlCommand>,
+    // the configuration the client sent last, the control channel changes only parts of it
+    client_config: Option<ClientConfiguration>,
+    // ends the session after --max-session-duration
+    session_timer: Option<SessionTimer>,
+    // connection the session timer tells the client about its session, none while disconnected
+    session_sender: Arc<Mutex<Option<WsWriter>>>,
+}
+
+impl WsHandler {
+    fn new(
+        sender: WsWriter,
+        config: WsConfig,
+        gui_sender: mpsc::Sender<Ws2UiMessage>,
+        client_address: SocketAddr,
+        input_owner: InputOwner,
+        #[cfg(target_os = "linux")] clipboard: Option<ClipboardShare>,
+        #[cfg(target_os = "linux")] uinput_pool: UInputPool,
+    ) -> Self {
+        let (video_sender, video_receiver) = mpsc::channel::<VideoCommands>();
+        let video_crop: VideoCrop = Arc::new(Mutex::new(None));
+        let video_letterbox: VideoLetterbox = Arc::new(Mutex::new(None));
+        let video_thread = {
+            let sender = sender.clone();
+            let config = config.clone();
+            // offload creating the videostream to another thread to avoid blocking the thread that
+            // is receiving messages from the websocket
+            let video_crop = video_crop.clone();
+            let video_letterbox = video_letterbox.clone();
+            spawn(move || handle_video(video_receiver, sender, config, video_crop, video_letterbox))
+        };
+        let session_sender = Arc::new(Mutex::new(Some(sender.clone())));
+        let session_timer = config.max_session_duration.map(|duration| {
+            let session_sender = session_sender.clone();
+            SessionTimer::start(duration, move |event| {
+                notify_session_event(session_sender.lock().unwrap().as_ref(), event)
+            })
+        });
+        let control_receiver = config
+            .client_controls
+            .insert(client_address, session_timer.as_ref().map(|t| t.handle()));
+
+        Self {
+            sender,
+            video_sender,
+            video_thread: Some(video_thread),
+            input_device: None,
+            input_disabled: false,
+            capturables: vec![],
+            gui_sender,
+            ws_config: config,
+            #[cfg(target_os = "linux")]
+            capture_cursor: false,
+            client_name: None,
+            client_address,
+            input_owner,
+            pressure_curve: PressureCurve::default(),
+            input_mapping: None,
+            stylus_buttons: StylusButtons::default(),
+            #[cfg(target_os = "linux")]
+            clipboard,
+            #[cfg(target_os = "linux")]
+            uinput_pool,
+            session_token: None,
+            transform: FrameTransform::default(),
+            pending_moves: Vec::new(),
+            pending_since: None,
+            thumbnails: ThumbnailCache::new(THUMBNAIL_MAX_AGE),
+            video_crop,
+            video_letterbox,
+            client_hello: None,
+            capturable_filter: None,
+            control_receiver,
+            client_config: None,
+            session_timer,
+            session_sender,
+        }
+    }
+
+    fn session_expired(&self) -> bool {
+        self.session_timer
+            .as_ref()
+            .map_or(false, |t| t.is_expired())
+    }
+
+    // Tell the client how long its session lasts if it is limited.
+    fn send_session_remaining(&self) {
+        if let Some(timer) = &self.session_timer {
+            self.send_msg(&MessageOutbound::SessionRemaining(whole_seconds(
+                timer.remaining(),
+            )));
+        }
+    }
+
+    fn session_token(&self) -> Option<&str> {
+        self.session_token.as_deref()
+    }
+
+    // The connection is lost, stop sending anything to it while waiting for the client to resume
+    // the session.
+    fn park(&mut self) {
+        #[cfg(target_os = "linux")]
+        if let Some(clipboard) = &self.clipboard {
+            clipboard
+                .subscribers
+                .lock()
+                .unwrap()
+                .remove(&self.client_address);
+        }
+        if !self.ws_config.keep_capture_warm {
+            self.video_sender.send(VideoCommands::Suspend).unwrap();
+        }
+        *self.session_sender.lock().unwrap() = None;
+    }
+
+    // Continue the session with the connection of a reconnected client.
+    fn resume(&mut self, sender: WsWriter, client_address: SocketAddr) {
+        {
+            let mut input_owner = self.input_owner.lock().unwrap();
+            if *input_owner == Some(self.client_address) {
+                *input_owner = Some(client_address);
+            }
+        }
+        self.ws_config
+            .client_permissions
+            .moved(&self.client_address, client_address);
+        self.ws_config
+            .client_controls
+            .moved(&self.client_address, client_address);
+        self.client_address = client_address;
+        self.sender = sender.clone();
+        *self.session_sender.lock().unwrap() = Some(sender.clone());
+        self.video_sender
+            .send(VideoCommands::Resume(sender))
+            .unwrap();
+        // send the capturables known already instead of querying them again, on Wayland this
+        // would ask the user to select a screen again
+        self.send_msg(&MessageOutbound::CapturableList(self.capturable_infos()));
+        self.send_msg(&MessageOutbound::SessionResumed);
+        self.send_session_remaining();
+    }
+
+    // Check if this client may send input that requires `required`. If input is exclusive, the
+    // first permitted client sending input gets control until it disconnects, all other clients are
+    // viewers only.
+    fn may_control_input(&self, required: InputPermission) -> bool {
+        if self.input_disabled {
+            return false;
+        }
+        if self.ws_config.client_permissions.get(&self.client_address) < required {
+            return false;
+        }
+        if !self.ws_config.exclusive_input {
+            return true;
+        }
+        let mut input_owner = self.input_owner.lock().unwrap();
+        match *input_owner {
+            Some(owner) => owner == self.client_address,
+            None => {
+                info!(address = ?self.client_address, "Client got control over input.");
+                *input_owner = Some(self.client_address);
+                true
+            }
+        }
+    }
+
+    // Ignore input of the client as no input device could be set up for its config, it can still
+    // view the screen.
+    #[cfg(target_os = "linux")]
+    fn disable_input(&mut self, reason: &str) {
+        warn!(address = ?self.client_address, "{} Input of the client is ignored.", reason);
+        HEALTH.input.failed(reason);
+        self.input_device = None;
+        self.input_disabled = true;
+        self.send_msg(&MessageOutbound::InputDisabled(reason.into()));
+    }
+
+    fn send_msg(&self, msg: &MessageOutbound) {
+        send_msg(&self.sender, msg)
+    }
+
+    // Enqueue a request to send a new video frame.
+    //
+    // This does not do any further work in order not to block receiving messages. `handle_video`
+    // is resposible to do the actual work.
+    fn queue_try_send_video_frame(&mut self) {
+        self.video_sender.send(VideoCommands::TryGetFrame).unwrap();
+    }
+
+    fn process_wheel_event(&mut self, event: &WheelEvent) {
+        match &mut self.input_device {
+            Some(i) => i.send_wheel_event(event),
+            None => warn!("Input device is not initalized, can not process WheelEvent!"),
+        }
+    }
+
+    fn process_pointer_event(&mut self, event: &PointerEvent) {
+        if self.input_device.is_some() {
+            self.input_device
+                .as_mut()
+                .unwrap()
+                .send_pointer_event(event)
+        } else {
+            warn!("Input device is not initalized, can not process PointerEvent!");
+        }
+    }
+
+    fn process_keyboard_event(&mut self, event: &KeyboardEvent) {
+        if self.input_device.is_some() {
+            self.input_device
+                .as_mut()
+                .unwrap()
+                .send_keyboard_event(event)
+        } else {
+            warn!("Input device is not initalized, can not process KeyboardEvent!");
+        }
+    }
+
+    // Record how long it took to inject an input event received at `received_at` and tell the
+    // client if it asked for it by setting `sent_at`.
+    fn input_injected(&self, received_at: Instant, sent_at: Option<f64>) {
+        let processing = received_at.elapsed();
+        METRICS.input.record(processing);
+        if let Some(sent_at) = sent_at {
+            self.send_msg(&MessageOutbound::InputLatency(InputLatency {
+                sent_at,
+                processing: processing.as_secs_f64() * 1000.0,
+            }));
+        }
+    }
+
+    fn process_clipboard(&self, text: String) {
+        #[cfg(target_os = "linux")]
+        if let Some(clipboard) = &self.clipboard {
+            if text.len() > self.ws_config.clipboard_max_size {
+                warn!(
+                    "Ignoring clipboard of client, {} bytes exceed the size limit.",
+                    text.len()
+                );
+            } else if clipboard.sender.send(text).is_err() {
+                warn!("Clipboard is not available!");
+            }
+            return;
+        }
+        debug!(
+            "Clipboard sync is disabled, ignoring clipboard of client ({} bytes).",
+            text.len()
+        );
+    }
+
+    fn capturable_infos(&self) -> Vec<CapturableInfo> {
+        // the capturable given on the command line or selected via the control channel, either
+        // via its id or part of its name
+        let preselected = self
+            .ws_config
+            .client_controls
+            .capturable()
+            .or_else(|| self.ws_config.capturable.clone())
+            .and_then(|selector| find_capturable(&self.capturables, &selector));
+        self.capturables
+            .iter()
+            .enumerate()
+            .map(|(i, c)| CapturableInfo {
+                id: c.id(),
+                name: c.name(),
+                geometry: c.geometry_relative().ok(),
+                scale_factor: c.scale_factor(),
+                preselected: preselected == Some(i),
+                kind: c.kind(),
+                pid: c.pid(),
+                process_name: c.process_name(),
+                minimized: c.minimized(),
+                stacking: c.stacking(),
+            })
+            .collect()
+    }
+
+    fn send_capturable_list(&mut self) {
+        let total = self.update_capturables();
+        self.send_msg(&MessageOutbound::CapturableList(self.capturable_infos()));
+        self.send_msg(&MessageOutbound::CapturableCount(
+            self.capturables.len(),
+            total,
+        ));
+    }
+
+    // Query the capturables again, returns their number before the filter of the client applied.
+    fn update_capturables(&mut self) -> usize {
+        self.capturables = get_capturables(
+            #[cfg(target_os = "linux")]
+            self.ws_config.capture_backend,
+            #[cfg(target_os = "linux")]
+            self.ws_config.wayland_support,
+            #[cfg(target_os = "linux")]
+            self.capture_cursor,
+        );
+        self.capturables
+            .extend(self.ws_config.extra_capturables.iter().cloned());
+        if let Some(filter) = &self.ws_config.capturable_filter {
+            filter.apply(&mut self.capturables);
+        }
+        let total = self.capturables.len();
+        if let Some(filter) = &self.capturable_filter {
+            filter.apply(&mut self.capturables);
+        }
+        total
+    }
+
+    fn filter_capturables(&mut self, filter: Option<String>) {
+        self.capturable_filter = match filter
+            .filter(|f| !f.is_empty())
+            .map(|f| f.parse::<CapturableFilter>())
+        {
+            Some(Ok(filter)) => Some(filter),
+            Some(Err(err)) => {
+                self.send_msg(&MessageOutbound::Error(err));
+                return;
+            }
+            None => None,
+        };
+        self.send_capturable_list();
+    }
+
+    fn send_thumbnail(&mut self, index: usize) {
+        let capturable = match self.capturables.get(index) {
+            Some(capturable) => capturable.as_ref(),
+            None => return,
+        };
+        let thumbnail = match self.thumbnails.get(capturable, THUMBNAIL_SIZE) {
+            Ok(thumbnail) => thumbnail,
+            Err(err) => {
+                debug!(
+                    "Failed to capture thumbnail of {}: {}",
+                    capturable.name(),
+                    err
+                );
+                return;
+            }
+        };
+        // an empty image, for example of a minimized window, can not be encoded
+        if thumbnail.width == 0 || thumbnail.height == 0 {
+            return;
+        }
+        let mut encoder = JpegEncoder::new(80);
+        let url = match encoder.encode(
+            PixelProvider::BGR0(thumbnail.width, thumbnail.height, &thumbnail.data),
+            thumbnail.width,
+            thumbnail.height,
+        ) {
+            Ok(jpeg) => format!("data:image/jpeg;base64,{}", base64::encode(jpeg)),
+            Err(err) => {
+                warn!("Failed to encode thumbnail: {}", err);
+                return;
+            }
+        };
+        self.send_msg(&MessageOutbound::Thumbnail(index, url));
+    }
+
+    // optional features of the protocol supported with the current settings
+    fn features(&self) -> Vec<String> {
+        let mut features = vec![
+            "capture_cursor",
+            "crop",
+            "frame_header",
+            "pause",
+            "privacy_regions",
+            "quality_presets",
+            "set_quality",
+            "stats",
+            "stylus_buttons",
+            "thumbnails",
+        ];
+        #[cfg(target_os = "linux")]
+        if self.clipboard.is_some() {
+            features.push("clipboard");
+        }
+        // only the uinput device reports pens in proximity
+        #[cfg(target_os = "linux")]
+        if self.ws_config.uinput_available {
+            features.push("stylus_hover");
+        }
+        if !self.ws_config.session_grace_period.is_zero() {
+            features.push("sessions");
+        }
+        #[cfg(target_os = "linux")]
+        if self.ws_config.audio.is_some() {
+            features.push("audio");
+        }
+        features.into_iter().map(String::from).collect()
+    }
+
+    fn hello(&mut self, hello: ClientHello) {
+        if hello.version != PROTOCOL_VERSION {
+            warn!(
+                "Client speaks protocol version {}, expected {}.",
+                hello.version, PROTOCOL_VERSION
+            );
+            self.send_msg(&MessageOutbound::HelloRejected(format!(
+                "Unsupported protocol version {}, this server speaks version {}.",
+                hello.version, PROTOCOL_VERSION
+            )));
+            return;
+        }
+        let features = self.features();
+        let missing: Vec<_> = hello
+            .required
+            .iter()
+            .filter(|f| !features.contains(f))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            warn!(
+                "Client requires unsupported features: {}",
+                missing.join(", ")
+            );
+            self.send_msg(&MessageOutbound::HelloRejected(format!(
+                "Unsupported features: {}",
+                missing.join(", ")
+            )));
+            return;
+        }
+        // clients that do not list their decoders are assumed to decode H.264 as all of them did
+        // before the handshake existed
+        let codec = match self.ws_config.encoder_options.codec {
+            VideoCodec::H264 => "h264",
+            VideoCodec::Vp9 => "vp9",
+            VideoCodec::Av1 => "av1",
+        };
+        let codec = if hello.decoders.is_empty() || hello.decodes(codec) {
+            codec.to_string()
+        } else if hello.decodes("h264") {
+            "h264".to_string()
+        } else if hello.decodes("jpeg") {
+            "jpeg".to_string()
+        } else {
+            self.send_msg(&MessageOutbound::HelloRejected(
+                "None of the video formats the client can decode are supported.".to_string(),
+            ));
+            return;
+        };
+        let max_video_size = match (self.ws_config.max_video_size, hello.max_decode_size) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        #[cfg(target_os = "linux")]
+        let uinput = self.ws_config.uinput_available;
+        #[cfg(not(target_os = "linux"))]
+        let uinput = false;
+        self.send_msg(&MessageOutbound::Hello(ServerHello {
+            version: PROTOCOL_VERSION,
+            codec,
+            max_video_size,
+            color_matrix: self.ws_config.encoder_options.color_matrix,
+            color_range: self.ws_config.encoder_options.color_range,
+            uinput,
+            features,
+        }));
+        self.client_hello = Some(hello);
+    }
+
+    fn setup(&mut self, config: ClientConfiguration) {
+        // only authenticated clients get here, so it is safe to share the clipboard from now on
+        #[cfg(target_os = "linux")]
+        if let Some(clipboard) = &self.clipboard {
+            clipboard
+                .subscribers
+                .lock()
+                .unwrap()
+                .insert(self.client_address, self.sender.clone());
+        }
+        self.client_config = Some(config.clone());
+        self.send_session_remaining();
+        let client_name_changed = if self.client_name != config.client_name {
+            self.client_name = config.client_name;
+            true
+        } else {
+            false
+        };
+        if config.capturable_id < self.capturables.len() {
+            let capturable = self.capturables[config.capturable_id].clone();
+
+            #[cfg(target_os = "linux")]
+            {
+                self.capture_cursor = config.capture_cursor;
+            }
+
+            self.input_disabled = false;
+            #[cfg(target_os = "linux")]
+            if config.uinput_support && !self.ws_config.uinput_available {
+                self.disable_input(
+                    "/dev/uinput is not accessible on the host, see the log of Weylus for how to \
+                    fix this. Disable uinput to control the mouse instead.",
+                );
+            } else if config.uinput_support {
+                if self.input_device.as_ref().map_or(true, |d| {
+                    client_name_changed || d.device_type() != InputDeviceType::UInputDevice
+                }) {
+                    let device = self.uinput_pool.get(capturable.clone(), &self.client_name);
+                    match device {
+                        Ok(mut d) => {
+                            d.set_stylus_buttons(self.stylus_buttons);
+                            self.input_device = Some(Box::new(d));
+                        }
+                        Err(e) => {
+                            error!("Failed to create uinput device: {}", e);
+                            if let CErrorCode::UInputNotAccessible = e.to_enum() {
+                                if let Err(err) =
+                                    self.gui_sender.send(Ws2UiMessage::UInputInaccessible)
+                                {
+                                    warn!("Failed to send message to gui thread: {}!", err);
+                                }
+                            }
+                            self.disable_input(
+                                "Failed to create uinput device, see the log of Weylus for \
+                                details. Disable uinput to control the mouse instead.",
+                            );
+                        }
+                    }
+                } else if let Some(d) = self.input_device.as_mut() {
+                    d.set_capturable(capturable.clone());
+                }
+            } else if self.input_device.as_ref().map_or(true, |d| {
+                d.device_type() != InputDeviceType::AutoPilotDevice
+            }) {
+                self.input_device = Some(Box::new(
+                    crate::input::autopilot_device::AutoPilotDevice::new(capturable.clone()),
+                ));
+            } else if let Some(d) = self.input_device.as_mut() {
+                d.set_capturable(capturable.clone());
+            }
+
+            #[cfg(not(target_os = "linux"))]
+            if self.input_device.is_none() {
+                self.input_device = Some(Box::new(
+                    crate::input::autopilot_device::AutoPilotDevice::new(capturable.clone()),
+                ));
+            } else {
+                self.input_device
+                    .as_mut()
+                    .map(|d| d.set_capturable(capturable.clone()));
+            }
+
+            if self.input_device.is_some() && !self.input_disabled {
+                HEALTH.input.ok();
+            }
+
+            self.transform = FrameTransform {
+                rotation: config.rotation,
+                hflip: config.hflip,
+                vflip: config.vflip,
+            };
+            // the handshake limits what the client asks for to what it actually supports
+            let mut max_decode_size = config.max_decode_size;
+            let mut vp9_support = config.vp9_support;
+            let mut av1_support = config.av1_support;
+            let mut jpeg_fallback = config.jpeg_fallback;
+            if let Some(hello) = self
+                .client_hello
+                .as_ref()
+                .filter(|h| !h.decoders.is_empty())
+            {
+                vp9_support &= hello.decodes("vp9");
+                av1_support &= hello.decodes("av1");
+                jpeg_fallback |= !hello.decodes("h264") && hello.decodes("jpeg");
+            }
+            if let Some(size) = self.client_hello.as_ref().and_then(|h| h.max_decode_size) {
+                max_decode_size = Some(max_decode_size.map_or(size, |s| s.min(size)));
+            }
+            let frame_header = self.client_hello.as_ref().map_or(false, |h| h.frame_header);
+            self.video_sender
+                .send(VideoCommands::Start(VideoConfig {
+                    capturable,
+                    capture: CaptureOptions {
+                        capture_cursor: config.capture_cursor,
+                        crop: config.crop,
+                        #[cfg(target_os = "linux")]
+                        vsync: self.ws_config.vsync_capture,
+                        #[cfg(not(target_os = "linux"))]
+                        vsync: false,
+                        track_damage: self.ws_config.capture_mode == CaptureMode::Damage,
+                    },
+                    max_width: config.max_width,
+                    max_height: config.max_height,
+                    max_decode_size,
+                    follow_cursor: config.follow_cursor,
+                    transform: self.transform,
+                    cursor_overlay: config.cursor_overlay,
+                    vp9_support,
+                    av1_support,
+                    jpeg_fallback,
+                    grayscale: config.grayscale,
+                    frame_header,
+                    #[cfg(target_os = "linux")]
+                    audio: config.audio && frame_header && self.ws_config.audio.is_some(),
+                }))
+                .unwrap();
+
+            if self.session_token.is_none() && !self.ws_config.session_grace_period.is_zero() {
+                let token = random_token();
+                self.send_msg(&MessageOutbound::Session(token.clone()));
+                self.session_token = Some(token);
+            }
+        } else {
+            error!("Got invalid id for capturable: {}", config.capturable_id);
+            self.send_msg(&MessageOutbound::ConfigError(
+                "Invalid id for capturable!".to_string(),
+            ));
+        }
+    }
+
+    // Check if a pointer event can be held back and merged with the moves following it. Moves
+    // that press or release buttons are never merged.
+    fn may_coalesce(&self, event: &PointerEvent) -> bool {
+        let window = self.ws_config.input_coalescing_window;
+        !window.is_zero()
+            && matches!(
+                event.event_type,
+                PointerEventType::MOVE | PointerEventType::HOVER
+            )
+            && event.button == Button::NONE
+            && self
+                .pending_since
+                .map_or(true, |since| since.elapsed() < window)
+            && self
+                .pending_moves
+                .iter()
+                .find(|(pending, _)| pending.pointer_id == event.pointer_id)
+                .map_or(true, |(pending, _)| pending.buttons == event.buttons)
+    }
+
+    fn hold_pointer_move(&mut self, event: PointerEvent, received_at: Instant) {
+        match self
+            .pending_moves
+            .iter_mut()
+            .find(|(pending, _)| pending.pointer_id == event.pointer_id)
+        {
+            Some(pending) => *pending = (event, received_at),
+            None => self.pending_moves.push((event, received_at)),
+        }
+        self.pending_since.get_or_insert_with(Instant::now);
+    }
+
+    fn flush_pointer_moves(&mut self) {
+        self.pending_since = None;
+        for (event, received_at) in std::mem::take(&mut self.pending_moves) {
+            self.handle_pointer_event(event, received_at);
+        }
+    }
+
+    fn handle_pointer_event(&mut self, mut event: PointerEvent, received_at: Instant) {
+        // a pressure of 0 means the pen is not in contact and has to stay that way
+        if let PointerType::Pen = event.pointer_type {
+            if event.pressure > 0.0 {
+                event.pressure = self.pressure_curve.apply(event.pressure);
+            }
+        }
+        if let Some((x, y, w, h)) = *self.video_letterbox.lock().unwrap() {
+            // pointers on the bars are moved to the closest edge of the capturable
+            event.x = ((event.x - x) / w).clamp(0.0, 1.0);
+            event.y = ((event.y - y) / h).clamp(0.0, 1.0);
+            event.width /= w;
+            event.height /= h;
+        }
+        if !self.transform.is_identity() {
+            untransform_pointer_event(&mut event, self.transform);
+        }
+        if let Some((x, y, w, h)) = *self.video_crop.lock().unwrap() {
+            event.x = x + event.x * w;
+            event.y = y + event.y * h;
+            event.width *= w;
+            event.height *= h;
+        }
+        if let Some(mapping) = &self.input_mapping {
+            mapping.apply(&mut event);
+        }
+        self.process_pointer_event(&event);
+        self.input_injected(received_at, event.sent_at);
+    }
+
+    fn crop(&mut self, rect: Option<(f64, f64, f64, f64)>) {
+        let rect = rect.filter(|(x, y, w, h)| {
+            [x, y, w, h].iter().all(|v| v.is_finite()) && *w > 0.0 && *h > 0.0
+        });
+        let letterbox = *self.video_letterbox.lock().unwrap();
+        let crop = rect.map(|(x, y, w, h)| {
+            // the rectangle is given relative to the video including the bars
+            let (x, y, w, h) = match letterbox {
+                Some((lx, ly, lw, lh)) => ((x - lx) / lw, (y - ly) / lh, w / lw, h / lh),
+                None => (x, y, w, h),
+            };
+            // relative to the rotated and flipped video
+            let (x0, y0) = self.transform.untransform_point(x, y);
+            let (x1, y1) = self.transform.untransform_point(x + w, y + h);
+            let (x0, x1, y0, y1) = (x0.min(x1), x0.max(x1), y0.min(y1), y0.max(y1));
+            // and the video may show only part of the capturable already
+            let (cx, cy, cw, ch) = self
+                .video_crop
+                .lock()
+                .unwrap()
+                .unwrap_or((0.0, 0.0, 1.0, 1.0));
+            (cx + x0 * cw, cy + y0 * ch, (x1 - x0) * cw, (y1 - y0) * ch)
+        });
+        self.video_sender.send(VideoCommands::Crop(crop)).unwrap();
+    }
+
+    // Apply the commands of the control channel like the messages of the client they correspond to.
+    fn apply_control_commands(&mut self) {
+        while let Ok(command) = self.control_receiver.try_recv() {
+            debug!(?command, address = ?self.client_address, "Applying control command.");
+            match command {
+                ControlCommand::Capturable(selector) => self.select_capturable(&selector),
+                ControlCommand::Crop(crop) => {
+                    self.video_sender.send(VideoCommands::Crop(crop)).unwrap();
+                }
+                ControlCommand::QualityPreset(preset) => {
+                    self.video_sender
+                        .send(VideoCommands::QualityPreset(preset))
+                        .unwrap();
+                }
+                ControlCommand::SetQuality(quality) => {
+                    self.video_sender
+                        .send(VideoCommands::SetQuality(quality))
+                        .unwrap();
+                }
+                ControlCommand::Keyframe => {
+                    self.video_sender
+                        .send(VideoCommands::RequestKeyframe)
+                        .unwrap();
+                }
+                ControlCommand::InputMapping(mapping) => self.set_input_mapping(mapping),
+                // these go to the session timer right away
+                ControlCommand::Session(_) => (),
+            }
+        }
+    }
+
+    fn set_input_mapping(&mut self, mapping: Option<InputMapping>) {
+        match mapping {
+            Some(mapping) if !mapping.is_valid() => {
+                warn!(address = ?self.client_address, ?mapping, "Ignoring invalid input mapping.");
+            }
+            mapping => self.input_mapping = mapping,
+        }
+    }
+
+    // Capture another screen or window with the rest of the configuration the client sent, until
+    // the client selects one itself.
+    fn select_capturable(&mut self, selector: &str) {
+        let mut config = match self.client_config.clone() {
+            Some(config) => config,
+            None => {
+                debug!("Client has not started a video yet, ignoring control command.");
+                return;
+            }
+        };
+        // querying the list again may ask the user to select a screen on Wayland
+        let id = match find_capturable(&self.capturables, selector) {
+            Some(id) => Some(id),
+            None => {
+                self.update_capturables();
+                self.send_msg(&MessageOutbound::CapturableList(self.capturable_infos()));
+                find_capturable(&self.capturables, selector)
+            }
+        };
+        match id {
+            Some(id) => {
+                config.capturable_id = id;
+                // the crop belongs to the capturable selected before
+                config.crop = None;
+                config.follow_cursor = false;
+                self.setup(config);
+            }
+            None => warn!(
+                "No screen or window matches '{}', ignoring control command.",
+                selector
+            ),
+        }
+    }
+
+    // Process a message of the client, `more_input` tells if further messages are waiting already.
+    // In that case pointer moves may be merged with the following ones, which saves sending
+    // events to the input device that are outdated immediately anyway.
+    fn process(&mut self, message: &OwnedMessage, more_input: bool) {
+        let received_at = Instant::now();
+        self.apply_control_commands();
+        let message = match message {
+            OwnedMessage::Text(s) => match serde_json::from_str::<MessageInbound>(s) {
+                Ok(MessageInbound::PointerEvent(event))
+                    if more_input
+                        && self.may_coalesce(&event)
+                        && self.may_control_input(InputPermission::Pointer) =>
+                {
+                    self.hold_pointer_move(event, received_at);
+                    return;
+                }
+                parsed => Some((s, parsed)),
+            },
+            _ => None,
+        };
+        // anything else keeps its order relative to the moves held back
+        self.flush_pointer_moves();
+        match message {
+            Some((s, message)) => {
+                match message {
+                    Ok(message) => {
+                        if let MessageInbound::TryGetFrame = message {
+                        } else {
+                            debug!(
+                                client_message = %s,
+                                address = ?self.client_address,
+                                "Got message from client."
+                            );
+                        }
+                        match message {
+                            MessageInbound::WheelEvent(_) | MessageInbound::PointerEvent(_)
+                                if !self.may_control_input(InputPermission::Pointer) =>
+                            {
+                                trace!("Ignoring input of client without control over input.");
+                            }
+                            MessageInbound::KeyboardEvent(_) | MessageInbound::Clipboard(_)
+                                if !self.may_control_input(InputPermission::Full) =>
+                            {
+                                trace!("Ignoring input of client without control over input.");
+                            }
+                            MessageInbound::WheelEvent(mut event) => {
+                                if self.ws_config.invert_scroll {
+                                    event.dx = -event.dx;
+                                    event.dy = -event.dy;
+                                }
+                                self.process_wheel_event(&event);
+                                self.input_injected(received_at, event.sent_at);
+                            }
+                            MessageInbound::PointerEvent(event) => {
+                                self.handle_pointer_event(event, received_at)
+                            }
+                            MessageInbound::KeyboardEvent(event) => {
+                                self.process_keyboard_event(&event);
+                                self.input_injected(received_at, event.sent_at);
+                            }
+                            MessageInbound::TryGetFrame => self.queue_try_send_video_frame(),
+                            MessageInbound::RequestKeyframe => {
+                                self.video_sender
+                                    .send(VideoCommands::RequestKeyframe)
+                                    .unwrap();
+                            }
+                            MessageInbound::Hello(hello) => self.hello(hello),
+                            MessageInbound::GetCapturableList => self.send_capturable_list(),
+                            MessageInbound::FilterCapturables(filter) => {
+                                self.filter_capturables(filter)
+                            }
+                            MessageInbound::GetThumbnail(index) => self.send_thumbnail(index),
+                            MessageInbound::Crop(rect) => self.crop(rect),
+                            MessageInbound::PrivacyRegions(regions) => {
+                                self.video_sender
+                                    .send(VideoCommands::PrivacyRegions(regions))
+                                    .unwrap();
+                            }
+                            MessageInbound::Config(config) => self.setup(config),
+                            MessageInbound::PressureCurve(curve) => self.pressure_curve = curve,
+                            MessageInbound::InputMapping(mapping) => {
+                                self.set_input_mapping(mapping)
+                            }
+                            MessageInbound::StylusButtons(buttons) => {
+                                self.stylus_buttons = buttons;
+                                if let Some(d) = self.input_device.as_mut() {
+                                    d.set_stylus_buttons(buttons);
+                                }
+                            }
+                            MessageInbound::Clipboard(text) => self.process_clipboard(text),
+                            MessageInbound::QualityPreset(preset) => {
+                                self.video_sender
+                                    .send(VideoCommands::QualityPreset(preset))
+                                    .unwrap();
+                            }
+                            MessageInbound::SetQuality(quality) => {
+                                self.video_sender
+                                    .send(VideoCommands::SetQuality(quality))
+                                    .unwrap();
+                            }
+                            MessageInbound::PauseVideo => {
+                                self.video_sender.send(VideoCommands::PauseVideo).unwrap();
+                            }
+                            MessageInbound::ResumeVideo => {
+                                self.video_sender.send(VideoCommands::ResumeVideo).unwrap();
+                            }
+                            MessageInbound::CaptureCursor(capture_cursor) => {
+                                #[cfg(target_os = "linux")]
+                                {
+                                    self.capture_cursor = capture_cursor;
+                                }
+                                self.video_sender
+                                    .send(VideoCommands::CaptureCursor(capture_cursor))
+                                    .unwrap();
+                            }
+                            // only valid as first message, see handle_connection
+                            MessageInbound::ResumeSession(_) => {
+                                warn!("Client tried to resume a session in an active session.");
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        warn!("Unable to parse message: {} ({})", s, err);
+                        self.send_msg(&MessageOutbound::Error(
+                            "Failed to parse message!".to_string(),
+                        ));
+                    }
+                }
+            }
+            None => (),
+        }
+    }
+}
+
+impl Drop for WsHandler {
+    fn drop(&mut self) {
+        // release control over input so another client can take over
+        {
+            let mut input_owner = self.input_owner.lock().unwrap();
+            if *input_owner == Some(self.client_address) {
+                *input_owner = None;
+            }
+        }
+        self.ws_config
+            .client_permissions
+            .remove(&self.client_address);
+        self.ws_config.client_controls.remove(&self.client_address);
+        #[cfg(target_os = "linux")]
+        if let Some(clipboard) = &self.clipboard {
+            clipboard
+                .subscribers
+                .lock()
+                .unwrap()
+                .remove(&self.client_address);
+        }
+        // closing the channel stops the video thread, wait for it to release recorder and encoder
+        let (closed, _) = mpsc::channel();
+        drop(std::mem::replace(&mut self.video_sender, closed));
+        if let Some(thread) = self.video_thread.take() {
+            if thread.join().is_err() {
+                error!("Video thread panicked.");
+            }
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Steps every captured frame goes through before it is encoded, see `FramePipeline`.
+
+use std::sync::Arc;
+
+use tracing::debug;
+
+use crate::capturable::{clamp_crop, Recorder};
+use crate::video::{
+    process_frame, AlphaFlattener, ColorDepthReducer, FrameProcessor, FrameTransform, Letterbox,
+    LetterboxLayout, PixelProvider, PrivacyMask, Quantizer, TransparentFill,
+};
+
+use super::{VideoLetterbox, WsConfig};
+
+// `size` scaled by `scale` and rounded to the nearest even number, at least 2
+pub fn scaled_even(size: usize, scale: f64) -> usize {
+    ((size as f64 * scale / 2.0).round() as usize * 2).max(2)
+}
+
+// Region of an image of size `width`x`height` given relative to its size in whole pixels, the
+// region is clamped to the image and covers at least one pixel. Returns the region in pixels and
+// relative to the size of the image again.
+pub fn crop_to_pixels(
+    (x, y, w, h): (f64, f64, f64, f64),
+    (width, height): (usize, usize),
+) -> Option<((u32, u32, u32, u32), (f64, f64, f64, f64))> {
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let axis = |start: f64, len: f64, size: usize| {
+        let size = size as f64;
+        let start = (start.clamp(0.0, 1.0) * size).floor().min(size - 1.0);
+        let len = (len * size).round().clamp(1.0, size - start);
+        (start as u32, len as u32)
+    };
+    let (x, w) = axis(x, w, width);
+    let (y, h) = axis(y, h, height);
+    let region = (
+        x as f64 / width as f64,
+        y as f64 / height as f64,
+        w as f64 / width as f64,
+        h as f64 / height as f64,
+    );
+    Some(((x, y, w, h), region))
+}
+
+// Where the next frame captured by `recorder` lies within the whole capturable, x, y, width and
+// height relative to the size of the capturable as captured last, along with the size the frame
+// will have if it is cropped. This covers every crop, whether configured, following the cursor or
+// requested by the client. `None` if the frame is cropped but the capturable has not been captured
+// yet, so the position of the crop is not known.
+pub fn visible_region(
+    recorder: &dyn Recorder,
+) -> Option<((f64, f64, f64, f64), Option<(usize, usize)>)> {
+    let crop = match recorder.crop() {
+        Some(crop) => crop,
+        None => return Some(((0.0, 0.0, 1.0, 1.0), None)),
+    };
+    let (width, height) = recorder.size_uncropped()?;
+    Some(match clamp_crop(crop, (width as u32, height as u32)) {
+        Some((x, y, w, h)) => (
+            (
+                x as f64 / width as f64,
+                y as f64 / height as f64,
+                w as f64 / width as f64,
+                h as f64 / height as f64,
+            ),
+            Some((w as usize, h as usize)),
+        ),
+        // the recorder captures everything instead
+        None => ((0.0, 0.0, 1.0, 1.0), Some((width, height))),
+    })
+}
+
+// Steps every captured frame goes through before it is encoded, in this order, each writing to a
+// buffer of its own. Steps that are not configured leave the frame as it is.
+pub struct FramePipeline {
+    // kept when switching capturables, the client replaces it if the regions do not fit anymore
+    pub privacy_mask: Option<PrivacyMask>,
+    masked_frame: Vec<u8>,
+    transparent_fill: Option<TransparentFill>,
+    filled_frame: Vec<u8>,
+    alpha_flattener: Option<AlphaFlattener>,
+    flattened_frame: Vec<u8>,
+    pub transform: FrameTransform,
+    // frame after rotating or flipping it, unused if it is captured as is
+    transformed_frame: Vec<u8>,
+    quantizer: Option<Quantizer>,
+    quantized_frame: Vec<u8>,
+    color_depth_reducer: ColorDepthReducer,
+    reduced_frame: Vec<u8>,
+    letterbox: Option<Letterbox>,
+    letterboxed_frame: Vec<u8>,
+    frame_processor: Option<Arc<dyn FrameProcessor>>,
+    processed_frame: Vec<u8>,
+    // size of the frame processed last, after transforming it but before adding bars
+    pub captured_size: (usize, usize),
+    // where the frame processed last lies within the video, only if bars have been added
+    pub letterbox_layout: Option<LetterboxLayout>,
+    video_letterbox: VideoLetterbox,
+}
+
+impl FramePipeline {
+    pub fn new(config: &WsConfig, video_letterbox: VideoLetterbox) -> Self {
+        Self {
+            privacy_mask: None,
+            masked_frame: Vec::new(),
+            transparent_fill: config.transparent_fill.map(TransparentFill::new),
+            filled_frame: Vec::new(),
+            alpha_flattener: config.alpha_background.map(AlphaFlattener::new),
+            flattened_frame: Vec::new(),
+            transform: FrameTransform::default(),
+            transformed_frame: Vec::new(),
+            quantizer: config
+                .quantize_bits
+                .filter(|bits| *bits < 8)
+                .map(Quantizer::new),
+            quantized_frame: Vec::new(),
+            color_depth_reducer: ColorDepthReducer::new(),
+            reduced_frame: Vec::new(),
+            letterbox: config.letterbox.map(Letterbox::new),
+            letterboxed_frame: Vec::new(),
+            frame_processor: config.frame_processor.clone(),
+            processed_frame: Vec::new(),
+            captured_size: (0, 0),
+            letterbox_layout: None,
+            video_letterbox,
+        }
+    }
+
+    // Process a captured frame, `visible` is where it lies within the capturable, see
+    // visible_region, and `max_size` the size of the screen of the client. Returns none if the
+    // frame is to be skipped.
+    pub fn apply<'a>(
+        &'a mut self,
+        pixel_data: PixelProvider<'a>,
+        visible: Option<((f64, f64, f64, f64), Option<(usize, usize)>)>,
+        low_color: bool,
+        max_size: (usize, usize),
+    ) -> Option<PixelProvider<'a>> {
+        // regions are relative to the capturable, so they are hidden before the frame is
+        // transformed in any way
+        let pixel_data = match (&self.privacy_mask, visible) {
+            (Some(mask), Some((visible, size)))
+                if size.map_or(true, |size| size == pixel_data.size()) =>
+            {
+                mask.apply(pixel_data, visible, &mut self.masked_frame)
+            }
+            (Some(_), _) => {
+                // the capturable changed its size or was captured for the first time, so where
+                // the regions are is only known with the next frame
+                debug!("Not sure where the privacy regions are, skipping frame.");
+                return None;
+            }
+            (None, _) => pixel_data,
+        };
+        // filled pixels are opaque and left alone by the flattener
+        let pixel_data = match &self.transparent_fill {
+            Some(fill) => fill.apply(pixel_data, &mut self.filled_frame),
+            None => pixel_data,
+        };
+        let pixel_data = match &self.alpha_flattener {
+            Some(flattener) => flattener.apply(pixel_data, &mut self.flattened_frame),
+            None => pixel_data,
+        };
+        let pixel_data = self
+            .transform
+            .apply(pixel_data, &mut self.transformed_frame);
+        let pixel_data = match &self.quantizer {
+            Some(quantizer) => quantizer.apply(pixel_data, &mut self.quantized_frame),
+            None => pixel_data,
+        };
+        let pixel_data = if low_color {
+            self.color_depth_reducer
+                .apply(pixel_data, &mut self.reduced_frame)
+        } else {
+            pixel_data
+        };
+        let (width, height) = pixel_data.size();
+        if width == 0 || height == 0 {
+            // for example the captured window has been minimized, keep showing the last frame
+            // until there is something to show again
+            debug!("Captured frame is empty, skipping it.");
+            return None;
+        }
+        self.captured_size = (width, height);
+        // bars are added to match the aspect ratio of the screen of the client
+        let pixel_data = match &self.letterbox {
+            Some(letterbox) => {
+                let (pixel_data, layout) =
+                    letterbox.apply(pixel_data, max_size, &mut self.letterboxed_frame);
+                let layout = Some(layout).filter(|l| l.is_padded());
+                if layout != self.letterbox_layout {
+                    self.letterbox_layout = layout;
+                    *self.video_letterbox.lock().unwrap() = layout.map(|l| l.content_relative());
+                }
+                pixel_data
+            }
+            None => pixel_data,
+        };
+        Some(match &self.frame_processor {
+            Some(processor) => {
+                process_frame(processor.as_ref(), pixel_data, &mut self.processed_frame)
+            }
+            None => pixel_data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crop_in_pixels() {
+        // the right half of the lower third
+        let (pixels, region) =
+            crop_to_pixels((0.5, 2.0 / 3.0, 0.5, 1.0 / 3.0), (1920, 1080)).unwrap();
+        assert_eq!(pixels, (960, 720, 960, 360));
+        assert_eq!(region, (0.5, 720.0 / 1080.0, 0.5, 360.0 / 1080.0));
+        // clamped to the image
+        let (pixels, _) = crop_to_pixels((-0.5, 0.9, 2.0, 0.5), (100, 50)).unwrap();
+        assert_eq!(pixels, (0, 45, 100, 5));
+        let (pixels, _) = crop_to_pixels((1.0, 1.0, 0.5, 0.5), (100, 50)).unwrap();
+        assert_eq!(pixels, (99, 49, 1, 1));
+        // at least one pixel
+        let (pixels, region) = crop_to_pixels((0.25, 0.5, 0.0, 0.001), (4, 2)).unwrap();
+        assert_eq!(pixels, (1, 1, 1, 1));
+        assert_eq!(region, (0.25, 0.5, 0.25, 0.5));
+        assert_eq!(crop_to_pixels((0.0, 0.0, 1.0, 1.0), (0, 10)), None);
+    }
+
+    // recorder cropping like the X11 one, only what matters for where frames lie
+    struct CroppingRecorder {
+        crop: Option<(u32, u32, u32, u32)>,
+        size: Option<(usize, usize)>,
+    }
+
+    impl Recorder for CroppingRecorder {
+        fn capture(&mut self) -> Result<PixelProvider<'_>, Box<dyn std::error::Error>> {
+            Err("not captured in this test".into())
+        }
+
+        fn crop(&self) -> Option<(u32, u32, u32, u32)> {
+            self.crop
+        }
+
+        fn size_uncropped(&self) -> Option<(usize, usize)> {
+            self.size
+        }
+    }
+
+    #[test]
+    fn visible_region_of_crops() {
+        let recorder = |crop, size| CroppingRecorder { crop, size };
+        let everything = (0.0, 0.0, 1.0, 1.0);
+        assert_eq!(
+            visible_region(&recorder(None, None)),
+            Some((everything, None))
+        );
+        assert_eq!(
+            visible_region(&recorder(None, Some((1920, 1080)))),
+            Some((everything, None))
+        );
+        // whatever set the crop, the config, the cursor or the client
+        assert_eq!(
+            visible_region(&recorder(Some((960, 540, 480, 270)), Some((1920, 1080)))),
+            Some(((0.5, 0.5, 0.25, 0.25), Some((480, 270))))
+        );
+        // clamped to the capturable like the recorder does
+        assert_eq!(
+            visible_region(&recorder(Some((1440, 810, 960, 540)), Some((1920, 1080)))),
+            Some(((0.75, 0.75, 0.25, 0.25), Some((480, 270))))
+        );
+        assert_eq!(
+            visible_region(&recorder(Some((2000, 0, 10, 10)), Some((1920, 1080)))),
+            Some((everything, Some((1920, 1080))))
+        );
+        // not captured yet, so where the crop is relative to the capturable is not known
+        assert_eq!(visible_region(&recorder(Some((0, 0, 10, 10)), None)), None);
+    }
+}
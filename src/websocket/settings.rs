@@ -0,0 +1,161 @@
+//! Quality settings of the video and the pace frames are captured at.
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::protocol::QualitySettings;
+use crate::video::{EncoderOptions, QualityPreset};
+
+use super::bandwidth::BitrateController;
+use super::WsConfig;
+
+// Settings of the video that are either given by the server's config or by a quality preset.
+pub struct VideoSettings {
+    pub max_fps: Option<f64>,
+    pub encoder_options: EncoderOptions,
+    // applied to the maximum resolution requested by the client
+    pub resolution_scale: f64,
+    // reduce frames to 16 bit colors
+    pub low_color: bool,
+    // the bitrate of the encoder is kept below this, in kbit/s
+    bandwidth_cap: Option<u32>,
+}
+
+impl VideoSettings {
+    pub fn new(config: &WsConfig, preset: Option<QualityPreset>) -> Self {
+        let mut settings = match preset {
+            Some(preset) => Self {
+                max_fps: Some(preset.max_fps()),
+                encoder_options: preset.encoder_options(config.encoder_options),
+                resolution_scale: preset.resolution_scale(),
+                low_color: preset.low_color() || config.low_color,
+                bandwidth_cap: config.bandwidth_cap,
+            },
+            None => Self {
+                max_fps: config.max_fps,
+                encoder_options: EncoderOptions {
+                    // there is no chroma detail left to keep
+                    yuv444: config.encoder_options.yuv444 && !config.low_color,
+                    ..config.encoder_options
+                },
+                resolution_scale: 1.0,
+                low_color: config.low_color,
+                bandwidth_cap: config.bandwidth_cap,
+            },
+        };
+        settings.cap_bitrate();
+        settings
+    }
+
+    // aim the encoder at a bitrate below the bandwidth cap, the BandwidthGovernor drops the frames
+    // exceeding it anyway, like keyframes
+    fn cap_bitrate(&mut self) {
+        if let Some(cap) = self.bandwidth_cap {
+            let target = (cap / 5 * 4).max(1);
+            self.encoder_options.max_bitrate = Some(
+                self.encoder_options
+                    .max_bitrate
+                    .map_or(target, |max_bitrate| max_bitrate.min(target)),
+            );
+        }
+    }
+
+    // frame rate to capture at, JPEG images are limited further as each one is a full frame
+    pub fn max_fps(&self, jpeg_max_fps: Option<f64>) -> Option<f64> {
+        match jpeg_max_fps {
+            Some(jpeg_max_fps) => Some(
+                self.max_fps
+                    .map_or(jpeg_max_fps, |fps| fps.min(jpeg_max_fps)),
+            ),
+            None => self.max_fps,
+        }
+    }
+
+    // limits of settings changed by the client
+    const BITRATE_RANGE: (u32, u32) = (100, 100_000);
+    const FPS_RANGE: (f64, f64) = (1.0, 240.0);
+    const SCALE_RANGE: (f64, f64) = (0.1, 1.0);
+
+    // apply settings requested by the client, clamped to sane values, non-finite values are
+    // ignored, returns the settings in effect afterwards
+    pub fn apply(&mut self, requested: QualitySettings) -> QualitySettings {
+        if let Some(bitrate) = requested.bitrate {
+            self.encoder_options.max_bitrate =
+                Some(bitrate.clamp(Self::BITRATE_RANGE.0, Self::BITRATE_RANGE.1));
+        }
+        if let Some(fps) = requested.fps.filter(|fps| fps.is_finite()) {
+            self.max_fps = Some(fps.clamp(Self::FPS_RANGE.0, Self::FPS_RANGE.1));
+        }
+        if let Some(scale) = requested.scale.filter(|scale| scale.is_finite()) {
+            self.resolution_scale = scale.clamp(Self::SCALE_RANGE.0, Self::SCALE_RANGE.1);
+        }
+        self.cap_bitrate();
+        QualitySettings {
+            bitrate: self.encoder_options.max_bitrate,
+            fps: self.max_fps,
+            scale: Some(self.resolution_scale),
+        }
+    }
+
+    // none with constant quality, which does not limit the bitrate
+    pub fn bitrate_controller(&self, min_bitrate: u32) -> Option<BitrateController> {
+        self.encoder_options
+            .max_bitrate
+            .filter(|_| self.encoder_options.constant_quality.is_none())
+            .map(|max_bitrate| BitrateController::new(min_bitrate, max_bitrate))
+    }
+}
+
+// Frame rate to capture at: there is no point in capturing faster than the display refreshes, so
+// without a configured frame rate it is the refresh rate, with `sync` the refresh rate also caps
+// the configured one. If the refresh rate is unknown the configured frame rate applies.
+pub fn pacing_fps(max_fps: Option<f64>, refresh_rate: Option<f64>, sync: bool) -> Option<f64> {
+    match (max_fps, refresh_rate) {
+        (Some(max_fps), Some(refresh_rate)) if sync => Some(max_fps.min(refresh_rate)),
+        (Some(max_fps), _) => Some(max_fps),
+        (None, refresh_rate) => refresh_rate,
+    }
+}
+
+// Limits the rate at which frames are captured by sleeping for the remainder of the time budget of
+// a frame. If a frame took longer than its budget there is no sleep, so delays do not accumulate.
+pub struct FramePacer {
+    frame_duration: Option<Duration>,
+    last_frame: Option<Instant>,
+    fps: f64,
+}
+
+impl FramePacer {
+    pub fn new(max_fps: Option<f64>) -> Self {
+        Self {
+            frame_duration: max_fps
+                .filter(|fps| *fps > 0.0)
+                .map(|fps| Duration::from_secs_f64(1.0 / fps)),
+            last_frame: None,
+            fps: 0.0,
+        }
+    }
+
+    pub fn wait(&mut self) {
+        if let (Some(frame_duration), Some(last_frame)) = (self.frame_duration, self.last_frame) {
+            let elapsed = last_frame.elapsed();
+            if elapsed < frame_duration {
+                sleep(frame_duration - elapsed);
+            }
+        }
+        let now = Instant::now();
+        if let Some(last_frame) = self.last_frame {
+            let dt = (now - last_frame).as_secs_f64();
+            if dt > 0.0 {
+                // exponential moving average to smooth out jitter
+                self.fps = 0.9 * self.fps + 0.1 / dt;
+            }
+        }
+        self.last_frame = Some(now);
+    }
+
+    // frames per second measured over the last couple of frames
+    pub fn fps(&self) -> f64 {
+        self.fps
+    }
+}
@@ -0,0 +1,108 @@
+//! Statistics of the video sent to the client, see `FrameStats`.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::capturable::CaptureMode;
+use crate::protocol::{MessageOutbound, VideoStats};
+
+use super::{send_msg, WsWriter};
+
+/// How often `VideoStats` are sent to the client.
+const STATS_INTERVAL: Duration = Duration::from_secs(2);
+
+// Frames and bytes of the video of one client since the last report, updated by the video and the
+// encoder thread.
+#[derive(Default)]
+pub struct FrameStats {
+    pub sent: AtomicU64,
+    // captured frames that have not been sent as nothing changed, these do not count as dropped
+    pub unchanged: AtomicU64,
+    // requested frames that have not been sent, the reasons are those of METRICS.frames_dropped_*
+    pub dropped: AtomicU64,
+    pub bytes: AtomicU64,
+    // bytes sent including headers, this is not reset by reports
+    pub total_bytes: AtomicU64,
+    // frames are only captured once the screen changed, this is not reset by reports
+    pub damage_tracked: AtomicBool,
+    // ffmpeg encoder of the current video, this is not reset by reports
+    pub encoder: Mutex<Option<&'static str>>,
+    // timestamps of the current video are relative to this, also used for the audio
+    pub video_started_at: Mutex<Option<Instant>>,
+}
+
+impl FrameStats {
+    // statistics since the last report `elapsed` ago, the counters start again from zero
+    fn report(&self, elapsed: Duration) -> VideoStats {
+        let sent = self.sent.swap(0, Ordering::Relaxed);
+        let unchanged = self.unchanged.swap(0, Ordering::Relaxed);
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        let bytes = self.bytes.swap(0, Ordering::Relaxed);
+        let seconds = elapsed.as_secs_f64().max(0.001);
+        let requested = sent + unchanged + dropped;
+        VideoStats {
+            fps: sent as f64 / seconds,
+            drop_rate: if requested == 0 {
+                0.0
+            } else {
+                dropped as f64 / requested as f64
+            },
+            bitrate: (bytes as f64 * 8.0 / 1000.0 / seconds).round() as u32,
+            capture_mode: if self.damage_tracked.load(Ordering::Relaxed) {
+                CaptureMode::Damage
+            } else {
+                CaptureMode::Poll
+            }
+            .as_str()
+            .to_string(),
+            encoder: self.encoder.lock().unwrap().map(String::from),
+            // counted by the sender, which is not known here
+            video_bytes_per_second: 0,
+            control_bytes_per_second: 0,
+            total_bytes: 0,
+        }
+    }
+
+    pub fn reset(&self) {
+        self.report(Duration::ZERO);
+    }
+}
+
+// Sends the FrameStats to the client every STATS_INTERVAL, along with the bytes its sender
+// counted.
+pub struct StatsReporter {
+    sent_at: Instant,
+}
+
+impl StatsReporter {
+    pub fn new() -> Self {
+        Self {
+            sent_at: Instant::now(),
+        }
+    }
+
+    // send a report if the last one is STATS_INTERVAL ago
+    pub fn report(&mut self, stats: &FrameStats, sender: &WsWriter) {
+        let elapsed = self.sent_at.elapsed();
+        if elapsed < STATS_INTERVAL {
+            return;
+        }
+        let mut report = stats.report(elapsed);
+        {
+            let mut sender = sender.lock().unwrap();
+            let (video_bytes, control_bytes) = sender.take_sent();
+            let seconds = elapsed.as_secs_f64();
+            report.video_bytes_per_second = (video_bytes as f64 / seconds).round() as u64;
+            report.control_bytes_per_second = (control_bytes as f64 / seconds).round() as u64;
+            report.total_bytes = sender.total_bytes;
+        }
+        send_msg(sender, &MessageOutbound::Stats(report));
+        self.sent_at = Instant::now();
+    }
+
+    // the counters have been reset, the next report covers the time from now on
+    pub fn reset(&mut self) {
+        self.sent_at = Instant::now();
+    }
+}
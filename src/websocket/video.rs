@@ -0,0 +1,1036 @@
+//! The video thread of a client, which captures, encodes and sends its frames.
+
+use std::sync::atomic::Ordering;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, debug_span, field, trace, warn};
+
+use websocket::stream::sync::AsTcpStream;
+
+use crate::capturable::shared::SharedCaptures;
+use crate::capturable::{new_recorder, Capturable, CaptureError, CaptureOptions, Recorder};
+use crate::health::{FrameSampler, HEALTH};
+use crate::metrics::METRICS;
+use crate::protocol::{MessageOutbound, QualitySettings};
+use crate::video::{
+    ChangeDetector, ContentWaiter, FrameChange, FrameTransform, PixelProvider, PrivacyMask,
+    PrivacyRegion, QualityPreset, SceneChangeDetector, StallWatchdog, VideoCodec,
+};
+
+#[cfg(target_os = "linux")]
+use super::audio::ClientAudio;
+use super::bandwidth::{BandwidthGovernor, QualityRamp};
+use super::cursor::{send_cursor, CursorFollower, SentCursor};
+use super::encoding::{JpegStage, VideoEncoding};
+use super::pipeline::{crop_to_pixels, scaled_even, visible_region, FramePipeline};
+use super::settings::{pacing_fps, FramePacer, VideoSettings};
+use super::stats::{FrameStats, StatsReporter};
+use super::{
+    send_msg, unsent_bytes, CongestionStrategy, VideoCrop, VideoLetterbox, WsConfig, WsWriter,
+};
+
+pub struct VideoConfig {
+    pub capturable: Box<dyn Capturable>,
+    pub capture: CaptureOptions,
+    pub max_width: usize,
+    pub max_height: usize,
+    pub max_decode_size: Option<usize>,
+    pub follow_cursor: bool,
+    pub transform: FrameTransform,
+    pub cursor_overlay: bool,
+    pub vp9_support: bool,
+    pub av1_support: bool,
+    pub jpeg_fallback: bool,
+    pub grayscale: bool,
+    // prefix binary messages with a FrameHeader
+    pub frame_header: bool,
+    // send audio as well, only if the server captures audio and binary messages have headers
+    #[cfg(target_os = "linux")]
+    pub audio: bool,
+}
+
+pub enum VideoCommands {
+    Start(VideoConfig),
+    TryGetFrame,
+    RequestKeyframe,
+    // the client lost its connection, release the screen capture and the encoder until it resumes
+    // its session
+    Suspend,
+    // the client resumed its session with a new connection
+    Resume(WsWriter),
+    QualityPreset(Option<QualityPreset>),
+    SetQuality(QualitySettings),
+    CaptureCursor(bool),
+    PauseVideo,
+    ResumeVideo,
+    // region of the capturable to show relative to its size, see VideoCrop
+    Crop(Option<(f64, f64, f64, f64)>),
+    PrivacyRegions(Vec<PrivacyRegion>),
+}
+
+/// Number of consecutive failed captures after which the recorder is restarted, if capturing still
+/// fails after restarting, screen capture is stopped.
+const MAX_CAPTURE_FAILURES: u32 = 30;
+
+// Create a recorder, which shares its captures with other clients if captures are shared.
+fn open_recorder(
+    shared_captures: Option<&SharedCaptures>,
+    capturable: &dyn Capturable,
+    options: CaptureOptions,
+) -> Result<Box<dyn Recorder>, Box<dyn std::error::Error>> {
+    match shared_captures {
+        Some(shared_captures) => shared_captures.recorder(capturable, options),
+        None => new_recorder(capturable, options),
+    }
+}
+
+// Capture a frame, recording how long that took.
+fn capture_frame(
+    recorder: &mut dyn Recorder,
+) -> Result<(PixelProvider<'_>, Instant), Box<dyn std::error::Error>> {
+    let span = debug_span!(
+        "capture",
+        width = field::Empty,
+        height = field::Empty,
+        duration_us = field::Empty
+    );
+    let start = Instant::now();
+    let pixel_data = span.in_scope(|| recorder.capture_with_time());
+    let elapsed = start.elapsed();
+    METRICS.capture.record(elapsed);
+    span.record("duration_us", &(elapsed.as_micros() as u64));
+    if let Ok((pixel_data, _)) = &pixel_data {
+        let (width, height) = pixel_data.size();
+        span.record("width", &width);
+        span.record("height", &height);
+    }
+    pixel_data
+}
+
+// Checks of the captured frames that tell whether to restart the recorder or to hold back frames.
+struct FrameChecks {
+    // tells /health whether the captured frames are black or frozen
+    frame_sampler: FrameSampler,
+    // only used if stalled recorders are restarted
+    stall_watchdog: Option<StallWatchdog>,
+    // only used if frames are held back until the capture shows something
+    content_waiter: Option<ContentWaiter>,
+}
+
+impl FrameChecks {
+    fn new(config: &WsConfig) -> Self {
+        Self {
+            frame_sampler: FrameSampler::new(),
+            stall_watchdog: Some(config.capture_stall_timeout)
+                .filter(|timeout| !timeout.is_zero())
+                .map(StallWatchdog::new),
+            content_waiter: Some(config.content_timeout)
+                .filter(|timeout| !timeout.is_zero())
+                .map(ContentWaiter::new),
+        }
+    }
+
+    // how long the captured image did not change if the recorder stalled
+    fn stalled(&mut self, pixel_data: &PixelProvider) -> Option<Duration> {
+        self.frame_sampler.sample(pixel_data);
+        self.stall_watchdog
+            .as_mut()
+            .and_then(|watchdog| watchdog.check(pixel_data))
+    }
+
+    // whether the frame is held back as the capture does not show anything yet
+    fn held_back(&mut self, pixel_data: &PixelProvider) -> bool {
+        self.content_waiter
+            .as_mut()
+            .map_or(false, |waiter| !waiter.check(pixel_data))
+    }
+
+    fn reset_watchdog(&mut self) {
+        if let Some(watchdog) = self.stall_watchdog.as_mut() {
+            watchdog.reset();
+        }
+    }
+
+    // a new recorder has been started, its frames are held back until they show something
+    fn start_waiting(&mut self) {
+        if let Some(waiter) = self.content_waiter.as_mut() {
+            waiter.start();
+        }
+    }
+}
+
+// Recorder of the capturable the client selected, which is restarted if capturing fails. The crop
+// of the recorder follows the cursor or is the region the client asked for.
+pub struct Capture {
+    recorder: Option<Box<dyn Recorder>>,
+    // config of the current recorder, required to restart it
+    config: Option<VideoConfig>,
+    failures: u32,
+    checks: FrameChecks,
+    cursor_follower: Option<CursorFollower>,
+    // crop requested by the client relative to the size of the capturable, this takes precedence
+    // over the crop of the config and following the cursor
+    live_crop: Option<(f64, f64, f64, f64)>,
+    video_crop: VideoCrop,
+    shared_captures: Option<SharedCaptures>,
+    cursor_scale: f64,
+}
+
+impl Capture {
+    fn new(config: &WsConfig, video_crop: VideoCrop) -> Self {
+        Self {
+            recorder: None,
+            config: None,
+            failures: 0,
+            checks: FrameChecks::new(config),
+            cursor_follower: None,
+            live_crop: None,
+            video_crop,
+            shared_captures: config.shared_captures.clone(),
+            cursor_scale: config.cursor_scale,
+        }
+    }
+
+    // crop of the config or where the cursor moved it to if it is followed
+    fn crop(&self, config: &VideoConfig) -> Option<(u32, u32, u32, u32)> {
+        self.cursor_follower
+            .as_ref()
+            .map_or(config.capture.crop, |f| Some(f.crop))
+    }
+
+    // Create a new recorder for the capturable of the current config, continuing where the cursor
+    // moved the region to if it is followed.
+    fn restart(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        // see the comment on gstpipewire in VideoThread::start
+        self.recorder = None;
+        let config = self.config.as_ref().unwrap();
+        let options = CaptureOptions {
+            crop: self.crop(config),
+            ..config.capture
+        };
+        let mut recorder = open_recorder(
+            self.shared_captures.as_ref(),
+            config.capturable.as_ref(),
+            options,
+        )?;
+        recorder.set_cursor_scale(self.cursor_scale);
+        self.recorder = Some(recorder);
+        self.checks.start_waiting();
+        Ok(())
+    }
+
+    // Restart the recorder, telling the client if that failed.
+    fn restart_or_fail(&mut self, sender: &WsWriter) {
+        if let Err(err) = self.restart() {
+            warn!("Failed to restart screen cast: {}!", err);
+            send_msg(
+                sender,
+                &MessageOutbound::Error("Failed to capture screen!".into()),
+            );
+        }
+    }
+
+    // Capturing a frame failed, restart the recorder if that happened too often in a row and stop
+    // capturing if it keeps failing.
+    fn failed(&mut self, err: Box<dyn std::error::Error>, sender: &WsWriter) {
+        if self.recorder.as_ref().unwrap().is_gone() {
+            warn!("Capturable is gone, stopping screen capture: {}", err);
+            self.recorder = None;
+            // new connections are opened for every list of capturables, so selecting one again
+            // reconnects once the display server is back
+            let msg = match err.downcast_ref::<CaptureError>() {
+                Some(CaptureError::ConnectionLost(_)) => {
+                    "Lost connection to the display server, refresh the list of capturables once \
+                    it is back!"
+                }
+                _ => "The captured window does not exist anymore!",
+            };
+            send_msg(sender, &MessageOutbound::Error(msg.into()));
+            return;
+        }
+        warn!("Error capturing screen: {}", err);
+        HEALTH.capture.failed(&err);
+        self.failures += 1;
+        if self.failures == MAX_CAPTURE_FAILURES {
+            warn!("Capturing failed repeatedly, restarting screen capture.");
+            if let Err(err) = self.restart() {
+                warn!("Failed to restart screen cast: {}!", err);
+            }
+        }
+        if self.failures >= 2 * MAX_CAPTURE_FAILURES || self.recorder.is_none() {
+            warn!("Capturing failed repeatedly, stopping screen capture.");
+            self.recorder = None;
+            send_msg(
+                sender,
+                &MessageOutbound::Error("Failed to capture screen!".into()),
+            );
+        }
+    }
+
+    // The size of the capturable is only known once something has been captured and may change,
+    // so the crop is applied again for every frame.
+    fn update_crop(&mut self) {
+        let recorder = self.recorder.as_mut().unwrap();
+        if let Some(crop) = self.live_crop {
+            if let Some((pixels, region)) = recorder
+                .size_uncropped()
+                .and_then(|size| crop_to_pixels(crop, size))
+            {
+                recorder.set_crop(Some(pixels));
+                *self.video_crop.lock().unwrap() = Some(region);
+            }
+        } else if let Some(cursor_follower) = self.cursor_follower.as_mut() {
+            if let (Ok(Some(cursor)), Some(size)) = (recorder.cursor(), recorder.size_uncropped()) {
+                if let Some(crop) = cursor_follower.update(cursor.x, cursor.y, size) {
+                    recorder.set_crop(Some(crop));
+                }
+            }
+        }
+    }
+
+    fn set_live_crop(&mut self, crop: Option<(f64, f64, f64, f64)>) {
+        self.live_crop = crop;
+        if self.live_crop.is_none() {
+            if let Some(config) = &self.config {
+                let crop = self.crop(config);
+                if let Some(recorder) = self.recorder.as_mut() {
+                    recorder.set_crop(crop);
+                }
+            }
+            *self.video_crop.lock().unwrap() = None;
+        }
+    }
+}
+
+// Size of the video sent to the client, which scales frames down to fit the screen of the client
+// unless the config scales them.
+struct VideoSize {
+    capture_scale: Option<f64>,
+    max_width: usize,
+    max_height: usize,
+    // largest width or height of the video supported by server and client
+    max_size: Option<usize>,
+    // size of the video last sent to the client
+    sent: (usize, usize),
+}
+
+impl VideoSize {
+    fn new(config: &WsConfig) -> Self {
+        Self {
+            capture_scale: config.capture_scale,
+            max_width: 1920,
+            max_height: 1080,
+            max_size: config.max_video_size,
+            sent: (0, 0),
+        }
+    }
+
+    // size of the video for a processed frame of size `size_in`, `resolution_scale` is applied on
+    // top, see VideoSettings
+    fn scaled(
+        &self,
+        (width_in, height_in): (usize, usize),
+        jpeg: bool,
+        resolution_scale: f64,
+    ) -> (usize, usize) {
+        let scale = match self.capture_scale {
+            // JPEG images are shown at the size of the screen of the client anyway
+            Some(scale) if jpeg => scale.min(1.0),
+            Some(scale) => scale,
+            None => (self.max_width as f64 / width_in as f64)
+                .min(self.max_height as f64 / height_in as f64),
+        } * resolution_scale;
+        // limit video to 4K
+        let scale_max = (3840.0 / width_in as f64).min(2160.0 / height_in as f64);
+        let mut scale = scale.min(scale_max);
+        if let Some(max_size) = self.max_size {
+            scale = scale.min(max_size as f64 / width_in.max(height_in) as f64);
+        }
+        if self.capture_scale.is_some() {
+            // the encoder cuts off odd rows and columns for 4:2:0, so round to even sizes instead,
+            // the scaler of the encoder resamples frames to this size
+            (scaled_even(width_in, scale), scaled_even(height_in, scale))
+        } else if scale < 1.0 {
+            (
+                ((width_in as f64 * scale) as usize).max(1),
+                ((height_in as f64 * scale) as usize).max(1),
+            )
+        } else {
+            (width_in, height_in)
+        }
+    }
+}
+
+// Decides which frames are encoded as keyframes besides those the encoder picks.
+struct Keyframes {
+    scene_change_detector: Option<SceneChangeDetector>,
+    // frames have been dropped because the connection is congested, the next frame has to be a
+    // keyframe
+    dropped_frames: bool,
+    // the client asked for a keyframe within the cooldown of the last one it asked for, it is sent
+    // once the cooldown passed
+    requested: bool,
+    last_requested: Option<Instant>,
+    request_cooldown: Duration,
+}
+
+impl Keyframes {
+    fn new(config: &WsConfig) -> Self {
+        Self {
+            scene_change_detector: config
+                .scene_change_threshold
+                .map(|threshold| SceneChangeDetector::new(threshold, config.scene_change_cooldown)),
+            dropped_frames: false,
+            requested: false,
+            last_requested: None,
+            request_cooldown: config.keyframe_request_cooldown,
+        }
+    }
+
+    fn request(&mut self) {
+        // only this client's encoder is affected, the keyframe is part of the next frame encoded,
+        // requests during the cooldown are merged into a single keyframe
+        if !self.requested {
+            debug!("Client requested a keyframe.");
+        }
+        self.requested = true;
+    }
+
+    fn reset(&mut self) {
+        if let Some(detector) = self.scene_change_detector.as_mut() {
+            detector.reset();
+        }
+    }
+
+    // whether the frame is encoded as a keyframe, `change` being how the frame changed
+    fn check(&mut self, change: FrameChange, pixel_data: &PixelProvider) -> bool {
+        // the client may have dropped frames it could not decode in time, a keyframe lets it catch
+        // up immediately
+        let scene_change = self
+            .scene_change_detector
+            .as_mut()
+            .map_or(false, |detector| detector.check(pixel_data));
+        if scene_change {
+            debug!("Scene changed, sending a keyframe.");
+            METRICS.scene_changes.inc();
+        }
+        let requested = self.requested
+            && self
+                .last_requested
+                .map_or(true, |last| last.elapsed() >= self.request_cooldown);
+        if requested {
+            self.requested = false;
+            self.last_requested = Some(Instant::now());
+            METRICS.keyframes_requested.inc();
+        }
+        std::mem::take(&mut self.dropped_frames)
+            || change == FrameChange::Refresh
+            || scene_change
+            || requested
+    }
+}
+
+// Video of one client: captures a frame whenever the client asks for one, encodes and sends it,
+// and applies the commands of the client to the capture and the encoder.
+struct VideoThread {
+    sender: WsWriter,
+    config: WsConfig,
+    capture: Capture,
+    pipeline: FramePipeline,
+    settings: VideoSettings,
+    frame_pacer: FramePacer,
+    size: VideoSize,
+    // only used if unchanged frames are skipped
+    change_detector: ChangeDetector,
+    keyframes: Keyframes,
+    cursor_overlay: bool,
+    // prefix binary messages with a FrameHeader, requested by the client
+    frame_header: bool,
+    vp9_support: bool,
+    av1_support: bool,
+    // requested by the client, in addition to the server's config
+    grayscale: bool,
+    last_cursor: Option<SentCursor>,
+    // only used if damage is tracked
+    heartbeat_interval: Duration,
+    captured_at_last: Instant,
+    #[cfg(target_os = "linux")]
+    audio: ClientAudio,
+    // counters are reset whenever stats are sent and once the client resumes its session
+    stats: Arc<FrameStats>,
+    stats_reporter: StatsReporter,
+    encoding: VideoEncoding,
+    // only encodes if the client can not decode the video stream
+    jpeg: JpegStage,
+    // only used if the bandwidth is capped
+    bandwidth_governor: Option<BandwidthGovernor>,
+    // fraction of the configured quality the bandwidth governor allows
+    quality_limit: f64,
+    // the session has been resumed and keeps capturing what it captured before
+    resumed: bool,
+    // the client paused the video, the recorder and encoder are released after a while
+    paused_since: Option<Instant>,
+}
+
+impl VideoThread {
+    fn new(
+        sender: WsWriter,
+        config: WsConfig,
+        video_crop: VideoCrop,
+        video_letterbox: VideoLetterbox,
+    ) -> Self {
+        let settings = VideoSettings::new(&config, config.quality_preset);
+        let stats = Arc::new(FrameStats::default());
+        let encoding = VideoEncoding::new(
+            &sender,
+            settings.bitrate_controller(config.min_bitrate),
+            QualityRamp::new(&config),
+            config.encode_queue,
+            &stats,
+            config.recorder.clone(),
+            config.yuv_converter.clone(),
+        );
+        Self {
+            capture: Capture::new(&config, video_crop),
+            pipeline: FramePipeline::new(&config, video_letterbox),
+            frame_pacer: FramePacer::new(settings.max_fps),
+            settings,
+            size: VideoSize::new(&config),
+            change_detector: ChangeDetector::new(config.unchanged_frames_refresh),
+            keyframes: Keyframes::new(&config),
+            cursor_overlay: false,
+            frame_header: false,
+            vp9_support: false,
+            av1_support: false,
+            grayscale: false,
+            last_cursor: None,
+            heartbeat_interval: Duration::from_secs_f64(1.0 / config.heartbeat_fps),
+            captured_at_last: Instant::now(),
+            #[cfg(target_os = "linux")]
+            audio: ClientAudio::new(config.audio.clone()),
+            jpeg: JpegStage::new(&config, stats.clone()),
+            stats,
+            stats_reporter: StatsReporter::new(),
+            encoding,
+            bandwidth_governor: config.bandwidth_cap.map(BandwidthGovernor::new),
+            quality_limit: 1.0,
+            resumed: false,
+            paused_since: None,
+            sender,
+            config,
+        }
+    }
+
+    // Wait for the next command, none once the channel is closed. While the video is paused the
+    // recorder and encoder are released after a while.
+    fn next_command(&mut self, receiver: &mpsc::Receiver<VideoCommands>) -> Option<VideoCommands> {
+        let mut command = loop {
+            let release_in = self
+                .paused_since
+                .filter(|_| {
+                    self.capture.recorder.is_some() && !self.config.pause_release_timeout.is_zero()
+                })
+                .map(|since| {
+                    self.config
+                        .pause_release_timeout
+                        .saturating_sub(since.elapsed())
+                });
+            match release_in {
+                Some(timeout) => match receiver.recv_timeout(timeout) {
+                    Ok(command) => break command,
+                    Err(mpsc::RecvTimeoutError::Timeout) => {
+                        debug!("Video has been paused for a while, stopping screen capture.");
+                        // the recorder is recreated once the video is resumed
+                        self.capture.recorder = None;
+                        self.encoding.reset();
+                    }
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return None,
+                },
+                None => break receiver.recv().ok()?,
+            }
+        };
+        // drop frames if the client is requesting frames at a higher rate than they can be
+        // produced here
+        if let VideoCommands::TryGetFrame = command {
+            loop {
+                match receiver.try_recv() {
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return None,
+                    Ok(VideoCommands::TryGetFrame) => {
+                        METRICS.frames_dropped_busy.inc();
+                        self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Ok(other) => {
+                        command = other;
+                        break;
+                    }
+                }
+            }
+        }
+        Some(command)
+    }
+
+    fn handle_command(&mut self, command: VideoCommands) {
+        self.stats_reporter.report(&self.stats, &self.sender);
+        match command {
+            VideoCommands::TryGetFrame => self.send_frame(),
+            VideoCommands::RequestKeyframe => self.keyframes.request(),
+            VideoCommands::QualityPreset(preset) => {
+                self.settings = VideoSettings::new(&self.config, preset);
+                self.apply_settings();
+            }
+            VideoCommands::SetQuality(requested) => {
+                let applied = self.settings.apply(requested);
+                send_msg(&self.sender, &MessageOutbound::Quality(applied));
+                self.apply_settings();
+            }
+            VideoCommands::CaptureCursor(capture_cursor) => {
+                if let Some(recorder) = self.capture.recorder.as_mut() {
+                    recorder.set_capture_cursor(capture_cursor);
+                }
+                // keep the setting if the recorder is restarted
+                if let Some(config) = self.capture.config.as_mut() {
+                    config.capture.capture_cursor = capture_cursor;
+                }
+            }
+            VideoCommands::Crop(crop) => self.capture.set_live_crop(crop),
+            VideoCommands::PrivacyRegions(regions) => {
+                self.pipeline.privacy_mask =
+                    Some(PrivacyMask::new(regions)).filter(|mask| !mask.is_empty());
+            }
+            VideoCommands::PauseVideo => {
+                if self.paused_since.is_none() {
+                    debug!("Pausing video.");
+                    self.paused_since = Some(Instant::now());
+                }
+            }
+            VideoCommands::ResumeVideo => self.resume_video(),
+            VideoCommands::Suspend => self.suspend(),
+            VideoCommands::Resume(sender) => self.resume(sender),
+            VideoCommands::Start(config) => self.start(config),
+        }
+    }
+
+    // New quality settings, the encoder is restarted with them and the new video starts with a
+    // keyframe.
+    fn apply_settings(&mut self) {
+        self.encoding
+            .set_bitrate_controller(self.settings.bitrate_controller(self.config.min_bitrate));
+        if let Some(config) = &self.capture.config {
+            let max_fps = self
+                .settings
+                .max_fps(self.jpeg.is_started().then_some(self.config.jpeg_max_fps));
+            self.frame_pacer = FramePacer::new(pacing_fps(
+                max_fps,
+                config.capturable.refresh_rate(),
+                self.config.sync_to_refresh_rate,
+            ));
+        }
+        self.encoding.reset();
+    }
+
+    // The next frame is sent even if nothing changed and the client can start decoding with it
+    // right away.
+    fn refresh(&mut self) {
+        self.change_detector.reset();
+        self.keyframes.reset();
+    }
+
+    fn send_cursor(&mut self) {
+        if !self.cursor_overlay {
+            return;
+        }
+        send_cursor(
+            self.capture.recorder.as_mut().unwrap().as_mut(),
+            &self.sender,
+            &mut self.last_cursor,
+            self.pipeline.captured_size,
+            self.pipeline.transform,
+            self.pipeline.letterbox_layout,
+            self.config.cursor_scale,
+        );
+    }
+
+    // frames are not sent if the connection is congested or the bandwidth is used up
+    fn frame_admitted(&mut self) -> bool {
+        if self.config.congestion_strategy == CongestionStrategy::Drop
+            && unsent_bytes(self.sender.lock().unwrap().writer.stream.as_tcp())
+                .map_or(false, |unsent| unsent > self.config.send_buffer_threshold)
+        {
+            if !self.keyframes.dropped_frames {
+                debug!("Connection is congested, dropping frames.");
+                self.keyframes.dropped_frames = true;
+            }
+            METRICS.frames_dropped_congestion.inc();
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        if let Some(governor) = self.bandwidth_governor.as_mut() {
+            let admitted = governor.admit(self.stats.total_bytes.load(Ordering::Relaxed));
+            METRICS.bandwidth_kbps.set(governor.rate());
+            if governor.quality() != self.quality_limit {
+                self.quality_limit = governor.quality();
+                debug!(
+                    "Limiting quality to {:.0}% to stay under the bandwidth cap.",
+                    self.quality_limit * 100.0
+                );
+                self.encoding.set_quality_limit(self.quality_limit);
+                self.jpeg.set_quality_limit(self.quality_limit);
+            }
+            if !admitted {
+                METRICS.frames_dropped_bandwidth.inc();
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        }
+        true
+    }
+
+    // Capture a frame, encode and send it, the client asked for it.
+    fn send_frame(&mut self) {
+        if self.paused_since.is_some() {
+            trace!("Video is paused, not sending video frame.");
+            return;
+        }
+        if self.capture.recorder.is_none() {
+            warn!("Screen capture not initalized, can not send video frame!");
+            return;
+        }
+        if !self.frame_admitted() {
+            return;
+        }
+        self.capture.update_crop();
+        self.frame_pacer.wait();
+        // if damage is tracked, nothing is captured, let alone encoded, unless the screen changed
+        // or the heartbeat is due
+        let damaged = self.capture.recorder.as_mut().unwrap().damaged();
+        self.stats
+            .damage_tracked
+            .store(damaged.is_some(), Ordering::Relaxed);
+        let heartbeat = match damaged {
+            Some(false) if self.encoding.is_started() || self.jpeg.is_started() => {
+                if self.captured_at_last.elapsed() < self.heartbeat_interval {
+                    METRICS.frames_unchanged.inc();
+                    self.stats.unchanged.fetch_add(1, Ordering::Relaxed);
+                    self.send_cursor();
+                    return;
+                }
+                true
+            }
+            _ => false,
+        };
+        // privacy regions are relative to the whole capturable, so they are placed by the crop
+        // applied to the frame about to be captured
+        let visible = visible_region(self.capture.recorder.as_ref().unwrap().as_ref());
+        trace!("Capturing at {:.1} fps.", self.frame_pacer.fps());
+        METRICS.capture_fps.set(self.frame_pacer.fps());
+        let (pixel_data, captured_at) =
+            match capture_frame(self.capture.recorder.as_mut().unwrap().as_mut()) {
+                Ok(captured) => captured,
+                Err(err) => {
+                    self.capture.failed(err, &self.sender);
+                    return;
+                }
+            };
+        self.capture.failures = 0;
+        self.captured_at_last = Instant::now();
+        if let Some(stalled_for) = self.capture.checks.stalled(&pixel_data) {
+            warn!(
+                "Captured image did not change for {:.1}s, restarting screen capture.",
+                stalled_for.as_secs_f64()
+            );
+            self.capture.restart_or_fail(&self.sender);
+            // the client can start decoding with the next frame right away in case it got stuck
+            // as well
+            self.refresh();
+            self.encoding.request_keyframe();
+            return;
+        }
+        if self.capture.checks.held_back(&pixel_data) {
+            return;
+        }
+        let pixel_data = match self.pipeline.apply(
+            pixel_data,
+            visible,
+            self.settings.low_color,
+            (self.size.max_width, self.size.max_height),
+        ) {
+            Some(pixel_data) => pixel_data,
+            None => return,
+        };
+        let (width_in, height_in) = pixel_data.size();
+        let change = if self.config.skip_unchanged_frames {
+            self.change_detector.check(&pixel_data)
+        } else {
+            FrameChange::Changed
+        };
+        // heartbeats are sent as keyframes, whether anything changed or not
+        let change = if heartbeat {
+            FrameChange::Refresh
+        } else {
+            change
+        };
+        if change == FrameChange::Unchanged
+            && (self.encoding.is_started() || self.jpeg.is_started())
+        {
+            METRICS.frames_unchanged.inc();
+            self.stats.unchanged.fetch_add(1, Ordering::Relaxed);
+            self.send_cursor();
+            return;
+        }
+        let (width_out, height_out) = self.size.scaled(
+            (width_in, height_in),
+            self.jpeg.is_started(),
+            self.settings.resolution_scale,
+        );
+        if self.size.sent != (width_out, height_out) {
+            self.size.sent = (width_out, height_out);
+            send_msg(
+                &self.sender,
+                &MessageOutbound::VideoSize(width_out, height_out),
+            );
+        }
+        if self.jpeg.is_started() {
+            self.jpeg.encode(
+                &self.sender,
+                pixel_data,
+                captured_at,
+                (width_out, height_out),
+                self.frame_header,
+            );
+            self.send_cursor();
+            return;
+        }
+        let mut encoder_options = self.settings.encoder_options;
+        encoder_options.grayscale |= self.grayscale;
+        let codec_supported = match encoder_options.codec {
+            VideoCodec::H264 => true,
+            VideoCodec::Vp9 => self.vp9_support,
+            VideoCodec::Av1 => self.av1_support,
+        };
+        if !codec_supported {
+            encoder_options.codec = VideoCodec::H264;
+        }
+        let keyframe = self.keyframes.check(change, &pixel_data);
+        self.encoding.encode(
+            pixel_data,
+            captured_at,
+            (width_in, height_in, width_out, height_out),
+            encoder_options,
+            keyframe,
+        );
+        self.send_cursor();
+    }
+
+    fn resume_video(&mut self) {
+        if self.paused_since.take().is_none() {
+            return;
+        }
+        debug!("Resuming video.");
+        if self.capture.recorder.is_none() && self.capture.config.is_some() {
+            self.capture.restart_or_fail(&self.sender);
+        }
+        // the first frame is sent even if nothing changed in the meantime
+        self.refresh();
+        self.capture.checks.reset_watchdog();
+        self.encoding.request_keyframe();
+    }
+
+    // The client lost its connection, release the screen capture and the encoder.
+    fn suspend(&mut self) {
+        if self.capture.recorder.is_some() {
+            debug!("Client disconnected, stopping screen capture until it comes back.");
+        }
+        // the next Start of the resumed session sets up a recorder for the current size of the
+        // capturable and the new encoder begins with a keyframe
+        self.capture.recorder = None;
+        self.encoding.reset();
+        #[cfg(target_os = "linux")]
+        self.audio.stop();
+    }
+
+    fn resume(&mut self, sender: WsWriter) {
+        self.sender = sender;
+        // the audio is sent to the old connection, it restarts with the next Start
+        #[cfg(target_os = "linux")]
+        self.audio.stop();
+        self.encoding.set_sender(&self.sender);
+        // the encoder sends to the old connection and the client has to start with a new video
+        // anyway
+        self.encoding.reset();
+        self.refresh();
+        self.capture.checks.reset_watchdog();
+        self.last_cursor = None;
+        self.keyframes.dropped_frames = false;
+        self.resumed = true;
+        self.stats.reset();
+        self.stats_reporter.reset();
+        // the page of the client has been reloaded and is shown again
+        self.paused_since = None;
+    }
+
+    fn start(&mut self, config: VideoConfig) {
+        // restarting the recorder of a resumed session may ask the user to select a screen again
+        // on Wayland, so keep it if nothing changed
+        let keep_recorder = self.resumed
+            && self.capture.recorder.is_some()
+            && self.capture.config.as_ref().map_or(false, |c| {
+                c.capturable.id() == config.capturable.id()
+                    && c.capture.capture_cursor == config.capture.capture_cursor
+            });
+        self.resumed = false;
+        if !keep_recorder {
+            // gstpipewire can not handle setting a pipeline's state to Null after another pipeline
+            // has been created and its state has been set to Play.
+            // This line makes sure that there always is only a single recorder and thus single
+            // pipeline in this thread by forcing rust to call the destructor of the current
+            // pipeline here, right before creating a new pipeline.
+            // See: https://gitlab.freedesktop.org/pipewire/pipewire/-/issues/986
+            //
+            // This shouldn't affect other Recorder trait objects.
+            self.capture.recorder = None;
+        }
+        self.refresh();
+        self.capture.checks.reset_watchdog();
+        if !keep_recorder {
+            self.capture.checks.start_waiting();
+        }
+        self.cursor_overlay = config.cursor_overlay;
+        if self.frame_header != config.frame_header {
+            self.frame_header = config.frame_header;
+            self.encoding.set_frame_header(self.frame_header);
+            self.encoding.reset();
+        }
+        self.vp9_support = config.vp9_support;
+        self.av1_support = config.av1_support;
+        #[cfg(target_os = "linux")]
+        self.audio
+            .set_enabled(config.audio, &self.sender, &self.stats);
+        if self.grayscale != config.grayscale {
+            self.grayscale = config.grayscale;
+            self.encoding.reset();
+        }
+        self.last_cursor = None;
+        self.pipeline.transform = config.transform;
+        self.capture.cursor_follower = config
+            .capture
+            .crop
+            .filter(|_| config.follow_cursor)
+            .map(CursorFollower::new);
+        if config.jpeg_fallback {
+            send_msg(
+                &self.sender,
+                &MessageOutbound::VideoMimeType("image/jpeg".into()),
+            );
+            self.encoding.reset();
+            *self.stats.encoder.lock().unwrap() = None;
+            self.jpeg.start();
+        } else {
+            self.jpeg.stop();
+        }
+        let max_fps = self
+            .settings
+            .max_fps(config.jpeg_fallback.then_some(self.config.jpeg_max_fps));
+        self.frame_pacer = FramePacer::new(pacing_fps(
+            max_fps,
+            config.capturable.refresh_rate(),
+            self.config.sync_to_refresh_rate,
+        ));
+        let res = match self.capture.recorder.take() {
+            Some(mut r) if keep_recorder => {
+                r.set_crop(config.capture.crop);
+                Ok(r)
+            }
+            _ => open_recorder(
+                self.capture.shared_captures.as_ref(),
+                config.capturable.as_ref(),
+                config.capture,
+            ),
+        };
+        match res {
+            Ok(mut r) => {
+                r.set_cursor_scale(self.config.cursor_scale);
+                self.capture.recorder = Some(r);
+                self.capture.failures = 0;
+                self.size.max_width = config.max_width;
+                self.size.max_height = config.max_height;
+                self.size.max_size = self
+                    .config
+                    .max_video_size
+                    .into_iter()
+                    .chain(config.max_decode_size)
+                    .min();
+                self.size.sent = (0, 0);
+                self.capture.config = Some(config);
+                send_msg(&self.sender, &MessageOutbound::ConfigOk);
+            }
+            Err(err) => {
+                warn!("Failed to init screen cast: {}!", err);
+                send_msg(
+                    &self.sender,
+                    &MessageOutbound::Error(format!("Failed to init screen cast: {}", err)),
+                )
+            }
+        }
+    }
+}
+
+pub fn handle_video(
+    receiver: mpsc::Receiver<VideoCommands>,
+    sender: WsWriter,
+    config: WsConfig,
+    video_crop: VideoCrop,
+    video_letterbox: VideoLetterbox,
+) {
+    // threads spawned from here on, like the encoding thread and those of recorders, inherit
+    // priority and CPU affinity
+    #[cfg(target_os = "linux")]
+    config.thread_scheduling.apply("video");
+
+    let mut video = VideoThread::new(sender, config, video_crop, video_letterbox);
+    // stop thread once the channel is closed
+    while let Some(command) = video.next_command(&receiver) {
+        video.handle_command(command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaled_video_size() {
+        let size = |capture_scale, max_size| VideoSize {
+            capture_scale,
+            max_width: 1920,
+            max_height: 1080,
+            max_size,
+            sent: (0, 0),
+        };
+        // scaled down to the screen of the client but never up
+        assert_eq!(
+            size(None, None).scaled((3840, 2160), false, 1.0),
+            (1920, 1080)
+        );
+        assert_eq!(
+            size(None, None).scaled((1280, 720), false, 1.0),
+            (1280, 720)
+        );
+        assert_eq!(size(None, None).scaled((1280, 720), false, 0.5), (960, 540));
+        // the largest side is limited to what server and client support
+        assert_eq!(
+            size(None, Some(960)).scaled((3840, 2160), false, 1.0),
+            (960, 540)
+        );
+        // scaled by the config to even sizes, JPEG images are not scaled up
+        assert_eq!(
+            size(Some(1.5), None).scaled((1001, 601), false, 1.0),
+            (1502, 902)
+        );
+        assert_eq!(
+            size(Some(1.5), None).scaled((1001, 601), true, 1.0),
+            (1002, 602)
+        );
+    }
+}
@@ -1,11 +1,32 @@
-use std::net::SocketAddr;
-use std::sync::mpsc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+use std::{error::Error, fmt};
+use structopt::StructOpt;
 use tokio::sync::mpsc as mpsc_tokio;
-use tracing::{error, warn};
+use tracing::{error, info, warn};
 
+use crate::access_tokens::{AccessTokens, ClientPermissions};
+#[cfg(target_os = "linux")]
+use crate::audio::AudioOptions;
+use crate::capturable::shared::SharedCaptures;
+use crate::capturable::Capturable;
+#[cfg(target_os = "linux")]
+use crate::capturable::CaptureBackend;
+#[cfg(target_os = "linux")]
+use crate::config::wayland_session;
 use crate::config::Config;
-use crate::video::EncoderOptions;
-use crate::web::{Ui2WebMessage, Web2UiMessage};
+use crate::control::{ClientControls, ControlSource};
+use crate::mdns::MdnsAdvertisement;
+use crate::protocol::{Capabilities, PROTOCOL_VERSION};
+use crate::recording::SessionRecorder;
+#[cfg(target_os = "linux")]
+use crate::sched::ThreadScheduling;
+use crate::tls::TlsFiles;
+use crate::video::{
+    available_encoders, EncoderOptions, FrameProcessor, QualityPreset, VideoCodec, YuvConverter,
+};
+use crate::web::{SnapshotSource, Ui2WebMessage, Web2UiMessage};
 use crate::websocket::{Ui2WsMessage, Ws2UiMessage, WsConfig};
 
 struct Channels {
@@ -14,17 +35,34 @@ struct Channels {
 }
 
 pub struct Weylus {
+    // offered to clients in addition to the capturables found on the system
+    extra_capturables: Vec<Box<dyn Capturable>>,
+    // modifies frames before they are encoded
+    frame_processor: Option<Arc<dyn FrameProcessor>>,
+    // replaces the conversion to YUV
+    yuv_converter: Option<Arc<dyn YuvConverter>>,
     channels: Option<Channels>,
     ws_thread: Option<std::thread::JoinHandle<()>>,
     web_thread: Option<std::thread::JoinHandle<()>>,
+    // pass messages of the servers on to the callbacks given to start
+    message_threads: Vec<std::thread::JoinHandle<()>>,
+    mdns: Option<MdnsAdvertisement>,
+    // of the control channel, if enabled, which is told to stop reading once the servers stop
+    client_controls: Option<ClientControls>,
 }
 
 impl Weylus {
     pub fn new() -> Self {
         Self {
+            extra_capturables: Vec::new(),
+            frame_processor: None,
+            yuv_converter: None,
             channels: None,
             ws_thread: None,
             web_thread: None,
+            message_threads: Vec::new(),
+            mdns: None,
+            client_controls: None,
         }
     }
 
@@ -57,14 +95,237 @@ impl Weylus {
             try_mediafoundation: config.try_mediafoundation,
             #[cfg(not(target_os = "windows"))]
             try_mediafoundation: false,
+
+            conversion_threads: config.conversion_threads,
+            color_matrix: config.color_matrix,
+            color_range: config.color_range,
+            chroma_filter: config.chroma_filter,
+            yuv444: config.yuv444,
+            grayscale: config.grayscale,
+            codec: config.codec,
+            max_bitrate: config.max_bitrate,
+            constant_quality: config.constant_quality,
+            qp_range: (config.qp_min.is_some() || config.qp_max.is_some()).then(|| {
+                (
+                    config.qp_min.unwrap_or(0),
+                    config.qp_max.unwrap_or(config.codec.max_quantizer()),
+                )
+            }),
+            keyframe_interval: config.keyframe_interval.get(),
+            encoder_threads: config.encoder_threads.get(),
+            low_latency: config.low_latency,
         };
 
+        #[cfg(target_os = "linux")]
+        if let Err(err) = config.capture_backend.check_available() {
+            error!("{}", err);
+            return false;
+        }
+
+        #[cfg(target_os = "linux")]
+        if !config.wayland_support
+            && config.capture_backend == CaptureBackend::Auto
+            && wayland_session()
+        {
+            warn!(
+                "Running inside a Wayland session but Wayland/PipeWire support is disabled, \
+                capturing via X11 will most likely only yield black images. Consider enabling \
+                Wayland/PipeWire support."
+            );
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(screen) = config.x11_screen {
+            let checked = crate::capturable::x11::X11Context::new()
+                .ok_or_else(|| "Failed to open X display.".to_string())
+                .and_then(|mut x11ctx| x11ctx.set_screen(Some(screen)));
+            if let Err(err) = checked {
+                error!("Failed to select --x11-screen {}: {}", screen, err);
+                return false;
+            }
+        }
+        #[cfg(target_os = "linux")]
+        crate::capturable::x11::X11CapturableCache::global().set_screen(config.x11_screen);
+        #[cfg(target_os = "linux")]
+        crate::capturable::x11::set_window_capture_mode(config.x11_window_capture);
+        #[cfg(target_os = "linux")]
+        crate::capturable::x11::set_cursor_blend(config.cursor_blend);
+
+        let mut extra_capturables = self.extra_capturables.clone();
+        #[cfg(target_os = "linux")]
+        if let Some(region) = config.capture_region {
+            let capturable = crate::capturable::x11::X11Context::new()
+                .ok_or_else(|| "Failed to open X display.".to_string())
+                .and_then(|mut x11ctx| {
+                    x11ctx.set_screen(config.x11_screen)?;
+                    x11ctx.region(region).map_err(|err| err.to_string())
+                });
+            match capturable {
+                Ok(capturable) => extra_capturables.push(Box::new(capturable)),
+                Err(err) => {
+                    error!("Failed to set up capture region: {}", err);
+                    return false;
+                }
+            }
+        }
+
+        // shared between webserver and websocket server so tokens created by the former are
+        // accepted by the latter
+        let access_tokens = AccessTokens::new();
+        // shared as well, so permissions of connected clients can be changed via the webserver
+        let client_permissions = ClientPermissions::new();
+        // commands of the control channel are applied by the handler of each client
+        let client_controls = ClientControls::new();
+
+        let snapshot_source = SnapshotSource {
+            #[cfg(target_os = "linux")]
+            capture_backend: config.capture_backend,
+            extra_capturables: extra_capturables.clone(),
+            capturable: config.capturable.clone(),
+            max_size: config.max_video_size,
+        };
+
+        // without access to /dev/uinput clients that enable it can still view the screen
+        #[cfg(target_os = "linux")]
+        let uinput_available = match crate::input::uinput_device::uinput_accessible() {
+            Ok(()) => true,
+            Err(err) => {
+                error!(
+                    "Can not open /dev/uinput: {}. Clients that enable uinput can only view the \
+                    screen, their input is ignored.\n{}",
+                    err,
+                    crate::input::uinput_device::uinput_fix(&err)
+                );
+                false
+            }
+        };
+
+        // clients can only enable audio if it can be captured at all
+        #[cfg(target_os = "linux")]
+        let audio = if !config.audio {
+            None
+        } else if crate::audio::audio_available() {
+            Some(AudioOptions {
+                device: config.audio_device.clone(),
+                volume: config.audio_volume,
+            })
+        } else {
+            error!(
+                "Can not capture audio, the GStreamer plugins pulsesrc and opusenc are required. \
+                They are usually part of gstreamer1.0-pulseaudio and gstreamer1.0-plugins-base."
+            );
+            None
+        };
+
+        let tls_files = config
+            .tls_cert
+            .clone()
+            .zip(config.tls_key.clone())
+            .map(|(cert, key)| TlsFiles { cert, key });
+
+        let capabilities = detect_capabilities(
+            #[cfg(target_os = "linux")]
+            config,
+            #[cfg(target_os = "linux")]
+            uinput_available,
+            tls_files.is_some(),
+        );
+        // NVENC is fast enough to be worth it wherever it works
+        #[cfg(any(target_os = "linux", target_os = "windows"))]
+        let encoder_options =
+            if !encoder_options.try_nvenc && !config.no_nvenc && capabilities.nvenc {
+                info!("NVENC is available, encoding via GPU. Pass --no-nvenc to not use it.");
+                EncoderOptions {
+                    try_nvenc: true,
+                    ..encoder_options
+                }
+            } else {
+                encoder_options
+            };
+        // the websocket server listens where the web server does unless told otherwise
+        let websocket_bind_address = if config.websocket_bind_address.is_empty() {
+            &config.bind_address
+        } else {
+            &config.websocket_bind_address
+        };
         let ws_config = WsConfig {
-            address: SocketAddr::new(config.bind_address, config.websocket_port),
+            addresses: websocket_bind_address
+                .iter()
+                .map(|address| address.socket_addr(config.websocket_port))
+                .collect(),
             access_code: config.access_code.clone(),
+            access_tokens: access_tokens.clone(),
+            client_permissions: client_permissions.clone(),
+            client_controls: client_controls.clone(),
             encoder_options,
             #[cfg(target_os = "linux")]
             wayland_support: config.wayland_support,
+            #[cfg(target_os = "linux")]
+            uinput_available,
+            #[cfg(target_os = "linux")]
+            capture_backend: config.capture_backend,
+            #[cfg(target_os = "linux")]
+            vsync_capture: config.vsync_capture,
+            #[cfg(target_os = "linux")]
+            audio,
+            capture_mode: config.capture_mode,
+            heartbeat_fps: config.heartbeat_fps,
+            #[cfg(target_os = "linux")]
+            clipboard_sync: config.clipboard_sync,
+            #[cfg(target_os = "linux")]
+            clipboard_max_size: config.clipboard_max_size * 1024,
+            skip_unchanged_frames: config.skip_unchanged_frames,
+            unchanged_frames_refresh: config.unchanged_frames_refresh,
+            scene_change_threshold: config.scene_change_threshold,
+            scene_change_cooldown: Duration::from_millis(config.scene_change_cooldown),
+            keyframe_request_cooldown: Duration::from_millis(config.keyframe_request_cooldown),
+            quantize_bits: config.quantize_bits,
+            low_color: config.low_color,
+            alpha_background: config.alpha_background,
+            transparent_fill: config.transparent_fill,
+            letterbox: config.letterbox,
+            cursor_scale: config.cursor_scale,
+            max_video_size: config.max_video_size,
+            capture_scale: config.capture_scale,
+            max_fps: config.max_fps,
+            sync_to_refresh_rate: config.sync_to_refresh_rate,
+            quality_preset: config.quality_preset,
+            capturable: config.capturable.clone(),
+            capturable_filter: config.capturable_filter.clone(),
+            recorder: config
+                .record
+                .clone()
+                .map(|path| Arc::new(SessionRecorder::new(path))),
+            extra_capturables,
+            frame_processor: self.frame_processor.clone(),
+            yuv_converter: self.yuv_converter.clone(),
+            jpeg_quality: config.jpeg_quality,
+            jpeg_max_fps: config.jpeg_max_fps,
+            min_bitrate: config.min_bitrate,
+            bandwidth_cap: config.bandwidth_cap,
+            max_clients: config.max_clients,
+            max_session_duration: config.max_session_duration.map(Duration::from_secs),
+            exclusive_input: config.exclusive_input,
+            invert_scroll: config.invert_scroll,
+            input_coalescing_window: Duration::from_millis(config.input_coalescing_window),
+            congestion_strategy: config.congestion_strategy,
+            send_buffer_threshold: config.send_buffer_threshold * 1024,
+            session_grace_period: Duration::from_secs(config.session_grace_period),
+            pause_release_timeout: Duration::from_secs(config.pause_release_timeout),
+            keep_capture_warm: config.keep_capture_warm,
+            capture_stall_timeout: Duration::from_secs(config.capture_stall_timeout),
+            content_timeout: Duration::from_millis(config.content_timeout),
+            encode_queue: config.encode_queue,
+            shared_captures: config.share_capture.then(SharedCaptures::new),
+            tls_files: tls_files.clone(),
+            quality_ramp_start: config.quality_ramp_start,
+            quality_ramp_step: config.quality_ramp_step,
+            quality_ramp_interval: Duration::from_millis(config.quality_ramp_interval),
+            #[cfg(target_os = "linux")]
+            thread_scheduling: ThreadScheduling {
+                priority: config.capture_priority,
+                cpus: config.capture_cpus.clone(),
+            },
         };
 
         let (sender_ui2ws, receiver_ui2ws) = mpsc::channel();
@@ -96,13 +357,27 @@ impl Weylus {
         let web_thread = crate::web::run(
             sender_web2ui,
             receiver_ui2web,
-            SocketAddr::new(config.bind_address, config.web_port),
+            config
+                .bind_address
+                .iter()
+                .map(|address| address.socket_addr(config.web_port))
+                .collect::<Vec<SocketAddr>>(),
             config.websocket_port,
             config.access_code.clone(),
             config.custom_index_html.clone(),
             config.custom_access_html.clone(),
             config.custom_style_css.clone(),
             config.custom_lib_js.clone(),
+            access_tokens,
+            client_permissions,
+            #[cfg(target_os = "linux")]
+            config.clipboard_sync,
+            #[cfg(not(target_os = "linux"))]
+            false,
+            capabilities,
+            config.remote_health,
+            snapshot_source,
+            tls_files,
         );
         match receiver_web2ui.recv() {
             Ok(Web2UiMessage::Start) => (),
@@ -143,24 +418,53 @@ impl Weylus {
         }
         self.ws_thread = Some(ws_thread);
         self.web_thread = Some(web_thread);
+        if let Some(path) = &config.control {
+            // reading blocks, so the thread is not joined
+            crate::control::run(ControlSource::new(path), client_controls.clone());
+            self.client_controls = Some(client_controls);
+        }
+        if !config.no_mdns {
+            let name = config
+                .mdns_name
+                .clone()
+                .unwrap_or_else(crate::mdns::default_instance_name);
+            match MdnsAdvertisement::new(
+                &name,
+                config.web_port,
+                config.websocket_port,
+                config.access_code.is_some(),
+            ) {
+                Ok(mdns) => self.mdns = Some(mdns),
+                Err(err) => warn!("Failed to advertise Weylus via mDNS: {}", err),
+            }
+        }
         self.channels = Some(Channels {
             sender_ui2ws,
             sender_ui2web,
         });
-        std::thread::spawn(move || {
+        self.message_threads.push(std::thread::spawn(move || {
             for msg in receiver_web2ui.iter() {
                 on_web_message(msg);
             }
-        });
-        std::thread::spawn(move || {
+        }));
+        self.message_threads.push(std::thread::spawn(move || {
             for msg in receiver_ws2ui.iter() {
                 on_ws_message(msg);
             }
-        });
+        }));
         true
     }
 
+    /// Shut down both servers and return once everything they started has been released: the
+    /// threads of clients, capturing and encoding have been joined, which closes recorders and
+    /// encoders and destroys input devices, the sockets are closed and the callbacks given to
+    /// `start` will not be called anymore. Capturables cached for listing them are dropped as well,
+    /// so no connection to the X server is left open by Weylus.
     pub fn stop(&mut self) {
+        self.mdns = None;
+        if let Some(client_controls) = self.client_controls.take() {
+            client_controls.close();
+        }
         if let Some(channels) = self.channels.as_mut() {
             if let Err(err) = channels.sender_ui2ws.send(Ui2WsMessage::Shutdown) {
                 warn!(
@@ -174,6 +478,14 @@ impl Weylus {
         }
         self.wait();
         self.channels = None;
+        // the servers are gone and with them all senders of the messages
+        for t in self.message_threads.drain(..) {
+            if t.join().is_err() {
+                error!("Message callback panicked.");
+            }
+        }
+        #[cfg(target_os = "linux")]
+        crate::capturable::x11::X11CapturableCache::global().clear();
     }
 
     pub fn wait(&mut self) {
@@ -195,3 +507,233 @@ impl Drop for Weylus {
         self.stop();
     }
 }
+
+// detected once on start as this opens hardware encoders and connects to the display server
+fn detect_capabilities(
+    #[cfg(target_os = "linux")] config: &Config,
+    #[cfg(target_os = "linux")] uinput_available: bool,
+    tls: bool,
+) -> Capabilities {
+    let encoders = available_encoders();
+    let mut codecs: Vec<String> = [
+        (VideoCodec::H264, "h264"),
+        (VideoCodec::Vp9, "vp9"),
+        (VideoCodec::Av1, "av1"),
+    ]
+    .iter()
+    .filter(|(codec, _)| codec.is_encoded_by(&encoders))
+    .map(|(_, name)| name.to_string())
+    .collect();
+    // the JPEG fallback does not depend on ffmpeg
+    codecs.push("jpeg".to_string());
+
+    let mut capture_backends = Vec::new();
+    #[cfg(target_os = "linux")]
+    {
+        if config.capture_backend != CaptureBackend::Wayland
+            && CaptureBackend::X11.check_available().is_ok()
+        {
+            capture_backends.push("x11");
+        }
+        if (config.wayland_support || config.capture_backend == CaptureBackend::Wayland)
+            && config.capture_backend != CaptureBackend::X11
+            && CaptureBackend::Wayland.check_available().is_ok()
+        {
+            capture_backends.push("wayland");
+        }
+    }
+    #[cfg(target_os = "macos")]
+    capture_backends.push("core_graphics");
+    #[cfg(target_os = "windows")]
+    capture_backends.push("dxgi");
+
+    #[cfg(target_os = "linux")]
+    let vsync_capture =
+        capture_backends.contains(&"x11") && crate::capturable::x11::vsync_capture_available();
+    #[cfg(not(target_os = "linux"))]
+    let vsync_capture = false;
+    #[cfg(target_os = "linux")]
+    let damage_tracking =
+        capture_backends.contains(&"x11") && crate::capturable::x11::damage_tracking_available();
+    #[cfg(not(target_os = "linux"))]
+    let damage_tracking = false;
+    #[cfg(target_os = "linux")]
+    let audio = config.audio && crate::audio::audio_available();
+    #[cfg(not(target_os = "linux"))]
+    let audio = false;
+    let nvenc = encoders.contains(&"h264_nvenc");
+
+    Capabilities {
+        version: PROTOCOL_VERSION,
+        codecs,
+        encoders: encoders.into_iter().map(String::from).collect(),
+        capture_backends: capture_backends.into_iter().map(String::from).collect(),
+        #[cfg(target_os = "linux")]
+        uinput: uinput_available,
+        #[cfg(not(target_os = "linux"))]
+        uinput: false,
+        #[cfg(target_os = "linux")]
+        clipboard: config.clipboard_sync,
+        #[cfg(not(target_os = "linux"))]
+        clipboard: false,
+        tls,
+        vsync_capture,
+        damage_tracking,
+        audio,
+        nvenc,
+    }
+}
+
+/// Web server serving the client and websocket server streaming the video to clients, this is what
+/// the `weylus` binary runs. The server is stopped once this is dropped.
+pub struct WeylusServer {
+    weylus: Weylus,
+}
+
+impl WeylusServer {
+    /// Configure a new server, everything that is not set on the builder keeps the default of the
+    /// command line options.
+    pub fn builder() -> WeylusServerBuilder {
+        WeylusServerBuilder {
+            config: Config::from_iter(std::iter::once("weylus")),
+            capturables: Vec::new(),
+            frame_processor: None,
+            yuv_converter: None,
+            on_message: Box::new(|_| {}),
+        }
+    }
+
+    /// Block until the server has been shut down.
+    pub fn wait(&mut self) {
+        self.weylus.wait();
+    }
+
+    /// Shut down the server and wait for it to finish, see [`Weylus::stop`] for what is released.
+    pub fn stop(mut self) {
+        self.weylus.stop();
+    }
+}
+
+pub struct WeylusServerBuilder {
+    config: Config,
+    capturables: Vec<Box<dyn Capturable>>,
+    frame_processor: Option<Arc<dyn FrameProcessor>>,
+    yuv_converter: Option<Arc<dyn YuvConverter>>,
+    on_message: Box<dyn FnMut(Ws2UiMessage) + Send>,
+}
+
+impl WeylusServerBuilder {
+    /// Replace all options, for example with the ones given on the command line.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Code clients have to enter before they can connect, by default everyone can connect.
+    pub fn access_code(mut self, access_code: impl Into<String>) -> Self {
+        self.config.access_code = Some(access_code.into());
+        self
+    }
+
+    /// Listen only at this address instead of all IPv4 addresses.
+    pub fn bind_address(mut self, bind_address: IpAddr) -> Self {
+        self.config.bind_address = vec![bind_address.into()];
+        self
+    }
+
+    pub fn web_port(mut self, web_port: u16) -> Self {
+        self.config.web_port = web_port;
+        self
+    }
+
+    pub fn websocket_port(mut self, websocket_port: u16) -> Self {
+        self.config.websocket_port = websocket_port;
+        self
+    }
+
+    /// Screen or window captured for clients that have not selected one, given by its id or a
+    /// part of its name, see [`find_capturable`](crate::capturable::find_capturable).
+    pub fn capturable(mut self, selector: impl Into<String>) -> Self {
+        self.config.capturable = Some(selector.into());
+        self
+    }
+
+    /// Offer a capturable to clients in addition to the screens and windows found on the system, for
+    /// example a [`CompositeCapturable`](crate::capturable::composite::CompositeCapturable) showing
+    /// several windows at once. It can be preselected via [`WeylusServerBuilder::capturable`] too.
+    pub fn add_capturable(mut self, capturable: impl Capturable + 'static) -> Self {
+        self.capturables.push(Box::new(capturable));
+        self
+    }
+
+    /// Modify every frame before it is encoded, for example to add a watermark or blur parts of the
+    /// screen. It runs on the video thread of each client and delays every frame by the time it
+    /// takes, see [`FrameProcessor`].
+    pub fn frame_processor(mut self, processor: impl FrameProcessor + 'static) -> Self {
+        self.frame_processor = Some(Arc::new(processor));
+        self
+    }
+
+    /// Convert frames to YUV with `converter` instead of swscale, the built-in conversion is still
+    /// used for frames it leaves alone, see [`YuvConverter`].
+    pub fn yuv_converter(mut self, converter: impl YuvConverter + 'static) -> Self {
+        self.yuv_converter = Some(Arc::new(converter));
+        self
+    }
+
+    /// Preferred codec, clients that can not decode it get H.264.
+    pub fn codec(mut self, codec: VideoCodec) -> Self {
+        self.config.codec = codec;
+        self
+    }
+
+    pub fn quality_preset(mut self, preset: QualityPreset) -> Self {
+        self.config.quality_preset = Some(preset);
+        self
+    }
+
+    /// Capture only via the given backend instead of choosing automatically, starting fails if it
+    /// is not available.
+    #[cfg(target_os = "linux")]
+    pub fn capture_backend(mut self, backend: CaptureBackend) -> Self {
+        self.config.capture_backend = backend;
+        self
+    }
+
+    /// Advertise the server on the local network via mDNS/DNS-SD, enabled by default.
+    pub fn mdns(mut self, enabled: bool) -> Self {
+        self.config.no_mdns = !enabled;
+        self
+    }
+
+    /// Called on a separate thread for every event of the websocket server.
+    pub fn on_message(mut self, on_message: impl FnMut(Ws2UiMessage) + Send + 'static) -> Self {
+        self.on_message = Box::new(on_message);
+        self
+    }
+
+    /// Start the server, it keeps running in the background until it is stopped.
+    pub fn start(self) -> Result<WeylusServer, StartError> {
+        let mut weylus = Weylus::new();
+        weylus.extra_capturables = self.capturables;
+        weylus.frame_processor = self.frame_processor;
+        weylus.yuv_converter = self.yuv_converter;
+        if weylus.start(&self.config, |_| {}, self.on_message) {
+            Ok(WeylusServer { weylus })
+        } else {
+            Err(StartError)
+        }
+    }
+}
+
+/// The server failed to start, the reason has been logged.
+#[derive(Debug)]
+pub struct StartError;
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to start Weylus, see the log for details.")
+    }
+}
+
+impl Error for StartError {}
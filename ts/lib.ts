@@ -11,17 +11,163 @@ let log_level: LogLevel = LogLevel.ERROR;
 let no_log_messages: boolean = true;
 
 let fps_out: HTMLOutputElement;
+let bitrate_out: HTMLOutputElement;
+let fps_sent_out: HTMLOutputElement;
+let bitrate_sent_out: HTMLOutputElement;
+let dropped_out: HTMLOutputElement;
+let capture_mode_out: HTMLOutputElement;
+let input_latency_out: HTMLOutputElement;
+let codec_out: HTMLOutputElement;
+let encoder_out: HTMLOutputElement;
+let traffic_out: HTMLOutputElement;
+let resolution_out: HTMLOutputElement;
+let session_left_out: HTMLOutputElement;
+// time the session ends at in milliseconds of performance.now(), if it is limited by the server
+let session_end: number | null = null;
 let frame_count = 0;
 let last_fps_calc: number = performance.now();
+let last_latency_probe: number = 0;
+
+// count down the time left of a session limited by the server
+function update_session_end(seconds: number) {
+    let start_countdown = session_end === null;
+    session_end = performance.now() + seconds * 1000;
+    session_left_out.parentElement.classList.remove("hide");
+    let update = () => {
+        let left = Math.max(0, Math.ceil((session_end - performance.now()) / 1000));
+        session_left_out.value = Math.floor(left / 60) + ":" + (left % 60).toString().padStart(2, "0");
+    };
+    update();
+    if (start_countdown)
+        setInterval(update, 1000);
+}
+
+// time to put into sent_at of an input event, which makes the server reply with InputLatency,
+// only one event per second is probed
+function latency_probe(): number | undefined {
+    let now = performance.now();
+    if (now - last_latency_probe < 1000)
+        return undefined;
+    last_latency_probe = now;
+    return now;
+}
 
 let check_video: HTMLInputElement;
 
+// audio is scheduled at least this many seconds ahead, which covers the time it takes to decode
+const AUDIO_BUFFER = 0.05;
+// packets that are off by less than this many seconds are played back to back to avoid clicks
+const AUDIO_JITTER = 0.02;
+// audio is not held back for longer than this many seconds, even if the video lags behind
+const AUDIO_MAX_DELAY = 1;
+
+// Plays the Opus packets the server sends as FrameType.Audio via WebCodecs, which older versions
+// of TypeScript have no types for. `video_time` returns the timestamp of the frame currently
+// shown in seconds, which is on the same clock as the audio, or null if no video is playing.
+class AudioPlayer {
+    context: AudioContext;
+    gain: GainNode;
+    decoder: any;
+    video_time: () => number | null;
+    // time of the audio context at which the audio scheduled last ends
+    next_at: number;
+
+    constructor(sample_rate: number, channels: number, video_time: () => number | null) {
+        this.context = new AudioContext({ latencyHint: "interactive", sampleRate: sample_rate });
+        this.gain = this.context.createGain();
+        this.gain.gain.value = settings.range_audio_volume.valueAsNumber;
+        this.gain.connect(this.context.destination);
+        this.video_time = video_time;
+        this.next_at = 0;
+        let AudioDecoder = (window as any).AudioDecoder;
+        this.decoder = new AudioDecoder({
+            output: (data: any) => this.play(data),
+            error: (err: any) => log(LogLevel.WARN, "Failed to decode audio: " + err),
+        });
+        this.decoder.configure({ codec: "opus", sampleRate: sample_rate, numberOfChannels: channels });
+        // browsers only play audio once the page has been interacted with
+        if (this.context.state == "suspended")
+            document.addEventListener("pointerdown", () => this.context.resume(), { once: true });
+    }
+
+    push(data: ArrayBuffer, timestamp_ms: number) {
+        if (this.decoder.state != "configured")
+            return;
+        let EncodedAudioChunk = (window as any).EncodedAudioChunk;
+        this.decoder.decode(new EncodedAudioChunk({
+            type: "key",
+            timestamp: timestamp_ms * 1000,
+            data: data,
+        }));
+    }
+
+    play(data: any) {
+        let buffer = this.context.createBuffer(data.numberOfChannels, data.numberOfFrames, data.sampleRate);
+        for (let c = 0; c < data.numberOfChannels; ++c) {
+            let channel = new Float32Array(data.numberOfFrames);
+            data.copyTo(channel, { planeIndex: c, format: "f32-planar" });
+            buffer.copyToChannel(channel, c);
+        }
+        let timestamp = data.timestamp / 1e6;
+        data.close();
+        let now = this.context.currentTime;
+        let at = now + AUDIO_BUFFER;
+        let video_time = this.video_time();
+        if (video_time !== null) {
+            // play it once the frame captured at the same time is shown
+            at += timestamp - video_time;
+            // that frame has been shown already
+            if (at < now)
+                return;
+            at = Math.min(at, now + AUDIO_MAX_DELAY);
+        }
+        if (Math.abs(at - this.next_at) < AUDIO_JITTER)
+            at = Math.max(this.next_at, now);
+        let source = this.context.createBufferSource();
+        source.buffer = buffer;
+        source.connect(this.gain);
+        source.start(at);
+        this.next_at = at + buffer.duration;
+    }
+
+    set_volume(volume: number) {
+        this.gain.gain.value = volume;
+    }
+
+    close() {
+        if (this.decoder.state != "closed")
+            this.decoder.close();
+        this.context.close();
+    }
+}
+
+// null unless audio is enabled and the server sends it
+let audio_player: AudioPlayer = null;
+
+function close_audio() {
+    if (audio_player) {
+        audio_player.close();
+        audio_player = null;
+    }
+}
+
 function run(access_code: string, websocket_port: number, level: string) {
     window.onload = () => {
         log_pre = document.getElementById("log") as HTMLPreElement;
         log_pre.textContent = "";
         log_level = LogLevel[level];
         fps_out = document.getElementById("fps") as HTMLOutputElement;
+        bitrate_out = document.getElementById("bitrate") as HTMLOutputElement;
+        fps_sent_out = document.getElementById("fps_sent") as HTMLOutputElement;
+        bitrate_sent_out = document.getElementById("bitrate_sent") as HTMLOutputElement;
+        dropped_out = document.getElementById("dropped") as HTMLOutputElement;
+        capture_mode_out = document.getElementById("capture_mode") as HTMLOutputElement;
+        input_latency_out = document.getElementById("input_latency") as HTMLOutputElement;
+        codec_out = document.getElementById("codec") as HTMLOutputElement;
+        encoder_out = document.getElementById("encoder") as HTMLOutputElement;
+        traffic_out = document.getElementById("traffic") as HTMLOutputElement;
+        resolution_out = document.getElementById("resolution") as HTMLOutputElement;
+        session_left_out = document.getElementById("session_left") as HTMLOutputElement;
         check_video = document.getElementById("enable_video") as HTMLInputElement;
         window.addEventListener("error", (e: ErrorEvent | Event | UIEvent) => {
             if ((e as ErrorEvent).error) {
@@ -69,6 +215,16 @@ function calc_max_video_resolution(scale: number) {
     ];
 }
 
+// Largest width or height of a texture, videos larger than that can not be shown.
+function max_texture_size(): number {
+    try {
+        let gl = document.createElement("canvas").getContext("webgl");
+        return gl ? gl.getParameter(gl.MAX_TEXTURE_SIZE) : null;
+    } catch (e) {
+        return null;
+    }
+}
+
 function fresh_canvas() {
     let canvas_old = document.getElementById("canvas");
     let canvas = document.createElement("canvas");
@@ -97,17 +253,62 @@ function toggle_energysaving(energysaving: boolean) {
     }
 }
 
+// see CapturableInfo in protocol.rs
+interface CapturableInfo {
+    id: string;
+    name: string;
+    geometry: [number, number, number, number] | null;
+    scale_factor: number | null;
+    preselected: boolean;
+    kind: "desktop" | "monitor" | "window" | "region" | null;
+    pid: number | null;
+    process_name: string | null;
+    minimized: boolean | null;
+    stacking: number | null;
+}
+
+// everything known about a capturable in a single line, used as tooltip in the list
+function capturable_description(capturable: CapturableInfo): string {
+    let parts = [];
+    if (capturable.kind)
+        parts.push(capturable.kind[0].toUpperCase() + capturable.kind.slice(1));
+    if (capturable.process_name)
+        parts.push(capturable.process_name + (capturable.pid !== null ? " (pid " + capturable.pid + ")" : ""));
+    else if (capturable.pid !== null)
+        parts.push("pid " + capturable.pid);
+    if (capturable.minimized)
+        parts.push("minimized");
+    if (capturable.geometry) {
+        let [x, y, w, h] = capturable.geometry.map((v) => Math.round(v * 100));
+        parts.push(w + "% x " + h + "% at " + x + "%, " + y + "%");
+    }
+    return parts.join(", ");
+}
+
 class Settings {
     webSocket: WebSocket;
     checks: Map<string, HTMLInputElement>;
     capturable_select: HTMLSelectElement;
+    capturable_preview: HTMLImageElement;
+    capturable_sort_select: HTMLSelectElement;
+    // last list of capturables received, kept to sort it again
+    capturables: CapturableInfo[];
+    quality_preset_select: HTMLSelectElement;
+    rotation_select: HTMLSelectElement;
     frame_update_limit_input: HTMLInputElement;
     frame_update_limit_output: HTMLOutputElement;
     scale_video_input: HTMLInputElement;
     scale_video_output: HTMLOutputElement;
     range_min_pressure: HTMLInputElement;
+    range_pressure_gamma: HTMLInputElement;
+    range_pressure_gamma_output: HTMLOutputElement;
+    stylus_barrel_select: HTMLSelectElement;
+    stylus_eraser_select: HTMLSelectElement;
     check_aggressive_seek: HTMLInputElement;
+    range_audio_volume: HTMLInputElement;
     client_name_input: HTMLInputElement;
+    // id of the capturable selected last time, used to select it again after reconnecting
+    preferred_capturable: string;
     visible: boolean;
     settings: HTMLElement;
 
@@ -115,6 +316,11 @@ class Settings {
         this.webSocket = webSocket;
         this.checks = new Map<string, HTMLInputElement>();
         this.capturable_select = document.getElementById("window") as HTMLSelectElement;
+        this.capturable_preview = document.getElementById("capturable_preview") as HTMLImageElement;
+        this.capturable_sort_select = document.getElementById("capturable_sort") as HTMLSelectElement;
+        this.capturables = [];
+        this.quality_preset_select = document.getElementById("quality_preset") as HTMLSelectElement;
+        this.rotation_select = document.getElementById("rotation") as HTMLSelectElement;
         this.frame_update_limit_input = document.getElementById("frame_update_limit") as HTMLInputElement;
         this.frame_update_limit_input.min = frame_update_scale_inv(1).toString();
         this.frame_update_limit_input.max = frame_update_scale_inv(1000).toString();
@@ -122,6 +328,14 @@ class Settings {
         this.scale_video_input = document.getElementById("scale_video") as HTMLInputElement;
         this.scale_video_output = this.scale_video_input.nextElementSibling as HTMLOutputElement;
         this.range_min_pressure = document.getElementById("min_pressure") as HTMLInputElement;
+        this.range_pressure_gamma = document.getElementById("pressure_gamma") as HTMLInputElement;
+        this.range_pressure_gamma_output = this.range_pressure_gamma.nextElementSibling as HTMLOutputElement;
+        this.stylus_barrel_select = document.getElementById("stylus_barrel") as HTMLSelectElement;
+        this.stylus_eraser_select = document.getElementById("stylus_eraser") as HTMLSelectElement;
+        this.range_pressure_gamma.oninput = (e) => {
+            this.range_pressure_gamma_output.value = this.range_pressure_gamma.value;
+        }
+        this.range_audio_volume = document.getElementById("audio_volume") as HTMLInputElement;
         this.client_name_input = document.getElementById("client_name") as HTMLInputElement;
         this.frame_update_limit_input.oninput = (e) => {
             this.frame_update_limit_output.value = Math.round(frame_update_scale(this.frame_update_limit_input.valueAsNumber)).toString();
@@ -191,16 +405,86 @@ class Settings {
 
         this.frame_update_limit_input.onchange = () => this.save_settings();
         this.range_min_pressure.onchange = () => this.save_settings();
+        this.range_pressure_gamma.onchange = () => { this.save_settings(); this.send_pressure_curve() };
+        this.stylus_barrel_select.onchange = () => { this.save_settings(); this.send_stylus_buttons() };
+        this.stylus_eraser_select.onchange = () => { this.save_settings(); this.send_stylus_buttons() };
 
         // server
         let upd_server_config = () => { this.save_settings(); this.send_server_config() };
         this.checks.get("uinput_support").onchange = upd_server_config;
-        this.checks.get("capture_cursor").onchange = upd_server_config;
+        this.checks.get("capture_cursor").onchange = (e) => {
+            this.save_settings();
+            // this does not restart the video
+            this.webSocket.send(JSON.stringify({ "CaptureCursor": (e.target as HTMLInputElement).checked }));
+        };
+        this.checks.get("cursor_overlay").onchange = (e) => {
+            show_cursor_overlay((e.target as HTMLInputElement).checked);
+            upd_server_config();
+        };
+        this.checks.get("jpeg_fallback").onchange = upd_server_config;
+        this.checks.get("grayscale").onchange = upd_server_config;
+        this.checks.get("enable_audio").onchange = (e) => {
+            if (!(e.target as HTMLInputElement).checked)
+                close_audio();
+            upd_server_config();
+        };
+        // WebCodecs are required to decode the audio
+        if (!("AudioDecoder" in window)) {
+            this.checks.get("enable_audio").checked = false;
+            this.checks.get("enable_audio").disabled = true;
+        }
+        this.range_audio_volume.oninput = () => {
+            if (audio_player)
+                audio_player.set_volume(this.range_audio_volume.valueAsNumber);
+        };
+        this.range_audio_volume.onchange = () => this.save_settings();
+        // without MSE the video stream can not be played at all
+        if (!window.MediaSource) {
+            this.checks.get("jpeg_fallback").checked = true;
+            this.checks.get("jpeg_fallback").disabled = true;
+        }
         this.scale_video_input.onchange = upd_server_config;
         this.client_name_input.onchange = upd_server_config;
+        this.rotation_select.onchange = upd_server_config;
+        this.checks.get("hflip").onchange = upd_server_config;
+        this.checks.get("vflip").onchange = upd_server_config;
+        this.quality_preset_select.onchange = () => { this.save_settings(); this.send_quality_preset() };
 
         document.getElementById("refresh").onclick = () => this.webSocket.send('"GetCapturableList"');
-        this.capturable_select.onchange = () => this.send_server_config();
+        let capturable_filter = document.getElementById("capturable_filter") as HTMLInputElement;
+        capturable_filter.onchange = () => this.webSocket.send(JSON.stringify(
+            { "FilterCapturables": capturable_filter.value ? capturable_filter.value : null }
+        ));
+        this.capturable_sort_select.onchange = () => {
+            this.save_settings();
+            this.onCapturableList(this.capturables);
+        };
+        document.getElementById("crop").onclick = () => {
+            crop_mode = true;
+            this.toggle();
+        };
+        document.getElementById("reset_crop").onclick = () => this.webSocket.send(JSON.stringify({ "Crop": null }));
+
+        // clipboard, only shown if clipboard sync is enabled on the host
+        let clipboard = document.getElementById("clipboard") as HTMLTextAreaElement;
+        let send_clipboard = (text: string) => this.webSocket.send(JSON.stringify({ "Clipboard": text }));
+        document.getElementById("send_clipboard").onclick = () => send_clipboard(clipboard.value);
+        document.addEventListener("paste", (e: ClipboardEvent) => {
+            if (e.target == clipboard || clipboard.closest("section").classList.contains("hide"))
+                return;
+            let text = e.clipboardData.getData("text/plain");
+            if (text) {
+                clipboard.value = text;
+                send_clipboard(text);
+            }
+        });
+        this.capturable_select.onchange = () => {
+            this.save_settings();
+            // the crop belongs to the capturable selected before
+            this.webSocket.send(JSON.stringify({ "Crop": null }));
+            this.send_server_config();
+            this.request_thumbnail();
+        };
     }
 
     send_server_config() {
@@ -208,16 +492,73 @@ class Settings {
         config["capturable_id"] = Number(this.capturable_select.value);
         for (const key of [
             "uinput_support",
-            "capture_cursor"])
+            "capture_cursor",
+            "cursor_overlay",
+            "jpeg_fallback",
+            "grayscale",
+            "hflip",
+            "vflip"])
             config[key] = this.checks.get(key).checked;
+        // ignored by servers that do not capture audio
+        config["audio"] = this.checks.get("enable_audio").checked;
         let [w, h] = calc_max_video_resolution(this.scale_video_input.valueAsNumber);
         config["max_width"] = w;
         config["max_height"] = h;
+        config["max_decode_size"] = max_texture_size();
+        config["rotation"] = this.rotation_select.value;
         if (this.client_name_input.value)
             config["client_name"] = this.client_name_input.value;
+        config["vp9_support"] = window.MediaSource != null &&
+            MediaSource.isTypeSupported('video/mp4; codecs="vp09.00.10.08"');
+        config["av1_support"] = window.MediaSource != null &&
+            MediaSource.isTypeSupported('video/mp4; codecs="av01.0.08M.08"');
         this.webSocket.send(JSON.stringify({ "Config": config }));
     }
 
+    // tell the server what this client is capable of, keep in sync with PROTOCOL_VERSION in
+    // src/protocol.rs
+    send_hello() {
+        let decoders = ["jpeg"];
+        if (window.MediaSource != null) {
+            for (let [name, mime] of [
+                ["h264", 'video/mp4; codecs="avc1.4D403D"'],
+                ["vp9", 'video/mp4; codecs="vp09.00.10.08"'],
+                ["av1", 'video/mp4; codecs="av01.0.08M.08"']])
+                if (MediaSource.isTypeSupported(mime))
+                    decoders.push(name);
+        }
+        this.webSocket.send(JSON.stringify({
+            "Hello": {
+                "version": 1,
+                "decoders": decoders,
+                "max_decode_size": max_texture_size(),
+                "pointer_types": this.pointer_types(),
+                "required": [],
+                "frame_header": true,
+            }
+        }));
+    }
+
+    send_quality_preset() {
+        let preset = this.quality_preset_select.value;
+        this.webSocket.send(JSON.stringify({ "QualityPreset": preset ? preset : null }));
+    }
+
+    send_pressure_curve() {
+        let gamma = this.range_pressure_gamma.valueAsNumber;
+        let curve = gamma == 1 ? "Linear" : { "Gamma": gamma };
+        this.webSocket.send(JSON.stringify({ "PressureCurve": curve }));
+    }
+
+    send_stylus_buttons() {
+        this.webSocket.send(JSON.stringify({
+            "StylusButtons": {
+                "barrel": this.stylus_barrel_select.value,
+                "eraser": this.stylus_eraser_select.value,
+            }
+        }));
+    }
+
     save_settings() {
         let settings = Object(null);
         for (const [key, elem] of this.checks.entries())
@@ -225,7 +566,18 @@ class Settings {
         settings["frame_update_limit"] = frame_update_scale(this.frame_update_limit_input.valueAsNumber).toString();
         settings["scale_video"] = this.scale_video_input.value;
         settings["min_pressure"] = this.range_min_pressure.value;
+        settings["pressure_gamma"] = this.range_pressure_gamma.value;
+        settings["audio_volume"] = this.range_audio_volume.value;
+        settings["stylus_barrel"] = this.stylus_barrel_select.value;
+        settings["stylus_eraser"] = this.stylus_eraser_select.value;
         settings["client_name"] = this.client_name_input.value;
+        settings["quality_preset"] = this.quality_preset_select.value;
+        settings["rotation"] = this.rotation_select.value;
+        settings["capturable_sort"] = this.capturable_sort_select.value;
+        if (this.capturable_select.selectedOptions[0])
+            this.preferred_capturable = this.capturable_select.selectedOptions[0].dataset.id;
+        if (this.preferred_capturable)
+            settings["capturable"] = this.preferred_capturable;
         localStorage.setItem("settings", JSON.stringify(settings));
     }
 
@@ -261,6 +613,23 @@ class Settings {
             if (min_pressure)
                 this.range_min_pressure.value = min_pressure;
 
+            let pressure_gamma = settings["pressure_gamma"];
+            if (pressure_gamma)
+                this.range_pressure_gamma.value = pressure_gamma;
+            this.range_pressure_gamma_output.value = this.range_pressure_gamma.value;
+
+            let audio_volume = settings["audio_volume"];
+            if (audio_volume)
+                this.range_audio_volume.value = audio_volume;
+
+            let stylus_barrel = settings["stylus_barrel"];
+            if (stylus_barrel)
+                this.stylus_barrel_select.value = stylus_barrel;
+
+            let stylus_eraser = settings["stylus_eraser"];
+            if (stylus_eraser)
+                this.stylus_eraser_select.value = stylus_eraser;
+
             if (this.checks.get("lefty").checked) {
                 this.settings.classList.add("lefty");
             }
@@ -277,10 +646,29 @@ class Settings {
                 toggle_energysaving(true);
             }
 
+            if (this.checks.get("cursor_overlay").checked)
+                show_cursor_overlay(true);
+
             let client_name = settings["client_name"];
             if (client_name)
                 this.client_name_input.value = client_name;
 
+            let quality_preset = settings["quality_preset"];
+            if (quality_preset)
+                this.quality_preset_select.value = quality_preset;
+
+            let rotation = settings["rotation"];
+            if (rotation)
+                this.rotation_select.value = rotation;
+
+            let capturable_sort = settings["capturable_sort"];
+            if (capturable_sort)
+                this.capturable_sort_select.value = capturable_sort;
+
+            let capturable = settings["capturable"];
+            if (capturable)
+                this.preferred_capturable = capturable;
+
         } catch {
             log(LogLevel.DEBUG, "Failed to load settings.")
             return;
@@ -306,35 +694,124 @@ class Settings {
         return frame_update_scale(this.frame_update_limit_input.valueAsNumber)
     }
 
+    request_thumbnail() {
+        this.capturable_preview.classList.add("hide");
+        if (this.capturable_select.value !== "")
+            this.webSocket.send(JSON.stringify({ "GetThumbnail": Number(this.capturable_select.value) }));
+    }
+
+    onThumbnail(index: number, url: string) {
+        // the selection may have changed in the meantime
+        if (String(index) !== this.capturable_select.value)
+            return;
+        this.capturable_preview.src = url;
+        this.capturable_preview.classList.remove("hide");
+    }
+
     toggle() {
         this.settings.classList.toggle("hide");
         this.visible = !this.visible;
     }
 
-    onCapturableList(window_names: string[]) {
+    onCapturableList(capturables: CapturableInfo[]) {
+        this.capturables = capturables;
         let current_selection = undefined;
         if (this.capturable_select.selectedOptions[0])
-            current_selection = this.capturable_select.selectedOptions[0].textContent;
+            current_selection = this.capturable_select.selectedOptions[0].dataset.id;
+        // Nothing selected yet, so try to select what has been selected the last time
+        let restore = current_selection === undefined && this.preferred_capturable !== undefined;
+        if (restore)
+            current_selection = this.preferred_capturable;
         let new_index;
+        let preselected_index;
         this.capturable_select.innerText = "";
-        window_names.forEach((name, i) => {
+        // the host lists the desktop and monitors first, then windows topmost first, sorting by
+        // name keeps this grouping and only sorts within each kind
+        let kind_order = ["desktop", "monitor", "region", "window"];
+        let order = capturables.map((_, i) => i);
+        if (this.capturable_sort_select.value == "name")
+            order.sort((a, b) => {
+                let kind_a = kind_order.indexOf(capturables[a].kind);
+                let kind_b = kind_order.indexOf(capturables[b].kind);
+                if (kind_a != kind_b)
+                    return kind_a - kind_b;
+                return capturables[a].name.localeCompare(capturables[b].name);
+            });
+        order.forEach((i) => {
+            let capturable = capturables[i];
             let option = document.createElement("option");
             option.value = String(i);
-            option.innerText = name;
+            option.innerText = capturable.name;
+            if (capturable.process_name)
+                option.innerText += " – " + capturable.process_name;
+            if (capturable.scale_factor && capturable.scale_factor != 1)
+                option.innerText += " (" + Math.round(capturable.scale_factor * 100) + "%)";
+            if (capturable.minimized)
+                option.innerText += " (minimized)";
+            option.title = capturable_description(capturable);
+            option.dataset.id = capturable.id;
             this.capturable_select.appendChild(option);
-            if (name === current_selection)
+            if (capturable.id === current_selection)
                 new_index = i;
+            if (capturable.preselected)
+                preselected_index = i;
         });
-        if (new_index !== undefined)
+        // fall back to what has been selected on the host
+        if (new_index === undefined && (current_selection === undefined || restore)
+            && preselected_index !== undefined) {
+            new_index = preselected_index;
+            restore = true;
+        }
+        if (new_index !== undefined) {
             this.capturable_select.value = String(new_index);
-        else if (current_selection)
+            if (restore)
+                this.send_server_config();
+        } else if (current_selection && !restore)
             // Can't find the window, so don't select anything
             this.capturable_select.value = "";
+        this.request_thumbnail();
     }
 }
 
 let settings: Settings;
 
+// the next rectangle dragged on the video zooms into it instead of sending input
+let crop_mode = false;
+// where dragging the rectangle started, relative to the video and in client coordinates
+let crop_start: { x: number, y: number, client_x: number, client_y: number } = null;
+
+// optional features of the protocol the server announced in its hello
+let server_features: string[] = [];
+
+function handle_crop(event: PointerEvent, event_type: string) {
+    let crop_rect = document.getElementById("crop_rect");
+    let rect = (event.target as HTMLElement).getBoundingClientRect();
+    let x = Math.min(Math.max((event.clientX - rect.left) / rect.width, 0), 1);
+    let y = Math.min(Math.max((event.clientY - rect.top) / rect.height, 0), 1);
+    if (event_type == "pointerdown") {
+        crop_start = { x: x, y: y, client_x: event.clientX, client_y: event.clientY };
+        crop_rect.classList.remove("vanish");
+    }
+    if (crop_start === null)
+        return;
+    crop_rect.style.left = Math.min(crop_start.client_x, event.clientX) + "px";
+    crop_rect.style.top = Math.min(crop_start.client_y, event.clientY) + "px";
+    crop_rect.style.width = Math.abs(event.clientX - crop_start.client_x) + "px";
+    crop_rect.style.height = Math.abs(event.clientY - crop_start.client_y) + "px";
+    if (event_type == "pointerup" || event_type == "pointercancel") {
+        let w = Math.abs(x - crop_start.x);
+        let h = Math.abs(y - crop_start.y);
+        // ignore taps and rectangles too small to be meant
+        if (event_type == "pointerup" && w > 0.01 && h > 0.01)
+            settings.webSocket.send(JSON.stringify(
+                { "Crop": [Math.min(x, crop_start.x), Math.min(y, crop_start.y), w, h] }
+            ));
+        crop_start = null;
+        crop_mode = false;
+        crop_rect.classList.add("vanish");
+    }
+}
+
 class PEvent {
     event_type: string;
     pointer_id: number;
@@ -353,6 +830,8 @@ class PEvent {
     twist: number;
     width: number;
     height: number;
+    tool: string | undefined;
+    sent_at: number | undefined;
 
     constructor(eventType: string, event: PointerEvent, target: HTMLElement) {
         let targetRect = target.getBoundingClientRect();
@@ -376,11 +855,16 @@ class PEvent {
         this.movement_x = event.movementX ? event.movementX : 0;
         this.movement_y = event.movementY ? event.movementY : 0;
         this.pressure = Math.max(event.pressure, settings.range_min_pressure.valueAsNumber);
-        this.tilt_x = event.tiltX;
-        this.tilt_y = event.tiltY;
+        // not every browser reports tilt
+        this.tilt_x = event.tiltX ? event.tiltX : 0;
+        this.tilt_y = event.tiltY ? event.tiltY : 0;
         this.width = event.width / diag_len;
         this.height = event.height / diag_len;
         this.twist = event.twist;
+        // browsers report the eraser end of a pen as eraser button
+        if (event.pointerType == "pen")
+            this.tool = (event.buttons & 32) ? "eraser" : "pen";
+        this.sent_at = latency_probe();
     }
 }
 
@@ -388,6 +872,7 @@ class WEvent {
     dx: number;
     dy: number;
     timestamp: number;
+    sent_at: number | undefined;
 
     constructor(event: WheelEvent) {
         /* The WheelEvent can have different scrolling modes that affect how much scrolling
@@ -405,9 +890,11 @@ class WEvent {
                 break;
             default: // DOM_DELTA_PIXEL
         }
-        this.dx = Math.round(scale * event.deltaX);
-        this.dy = Math.round(scale * event.deltaY);
+        // not rounded, smooth scrolling may produce deltas of less than a pixel
+        this.dx = scale * event.deltaX;
+        this.dy = scale * event.deltaY;
         this.timestamp = Math.round(event.timeStamp * 1000);
+        this.sent_at = latency_probe();
     }
 }
 
@@ -609,6 +1096,7 @@ class PointerHandler {
         video.onpointerup = (e) => this.onEvent(e, "pointerup");
         video.onpointercancel = (e) => this.onEvent(e, "pointercancel");
         video.onpointermove = (e) => this.onEvent(e, "pointermove");
+        video.onpointerleave = (e) => this.onEvent(e, "pointerleave");
 
         let painter: Painter;
         if (!settings.checks.get("energysaving").checked)
@@ -619,11 +1107,13 @@ class PointerHandler {
             canvas.onpointerup = (e) => { this.onEvent(e, "pointerup"); painter.onstop(e); };
             canvas.onpointercancel = (e) => { this.onEvent(e, "pointercancel"); painter.onstop(e); };
             canvas.onpointermove = (e) => { this.onEvent(e, "pointermove"); painter.onmove(e); };
+            canvas.onpointerleave = (e) => this.onEvent(e, "pointerleave");
         } else {
             canvas.onpointerdown = (e) => this.onEvent(e, "pointerdown");
             canvas.onpointerup = (e) => this.onEvent(e, "pointerup");
             canvas.onpointercancel = (e) => this.onEvent(e, "pointercancel");
             canvas.onpointermove = (e) => this.onEvent(e, "pointermove");
+            canvas.onpointerleave = (e) => this.onEvent(e, "pointerleave");
         }
 
         // This is a workaround for the following Safari/WebKit bug:
@@ -640,7 +1130,18 @@ class PointerHandler {
     }
 
     onEvent(event: PointerEvent, event_type: string) {
+        let hover = event.pointerType == "pen" && server_features.includes("stylus_hover");
+        // leaving only matters for pens that can hover, everything else has been released already
+        if (event_type == "pointerleave" && (!hover || crop_mode))
+            return;
+        if (crop_mode) {
+            handle_crop(event, event_type);
+            return;
+        }
         if (this.pointerTypes.includes(event.pointerType)) {
+            // neither the tip nor the eraser touches the screen
+            if (hover && event_type == "pointermove" && (event.buttons & 33) == 0)
+                event_type = "pointerhover";
             this.webSocket.send(
                 JSON.stringify(
                     {
@@ -668,6 +1169,7 @@ class KEvent {
     ctrl: boolean;
     shift: boolean;
     meta: boolean;
+    sent_at: number | undefined;
 
     constructor(event_type: string, event: KeyboardEvent) {
         this.event_type = event_type;
@@ -678,6 +1180,7 @@ class KEvent {
         this.ctrl = event.ctrlKey;
         this.shift = event.shiftKey;
         this.meta = event.metaKey;
+        this.sent_at = latency_probe();
     }
 }
 
@@ -733,17 +1236,64 @@ function frame_timer(webSocket: WebSocket) {
     setTimeout(() => frame_timer(webSocket), settings.frame_update_limit());
 }
 
+// src/protocol.rs
+enum FrameType {
+    Init = 0,
+    Keyframe = 1,
+    Delta = 2,
+    Jpeg = 3,
+    Audio = 4,
+}
+
+interface FrameHeader {
+    type: FrameType;
+    // the payload starts after this many bytes
+    length: number;
+    timestamp_ms: number;
+    width: number;
+    height: number;
+}
+
+// parse the header the server prefixes binary messages with, null if there is none
+function parse_frame_header(data: ArrayBuffer): FrameHeader | null {
+    if (data.byteLength < 8)
+        return null;
+    let view = new DataView(data);
+    // magic bytes "WYLF"
+    if (view.getUint32(0, false) != 0x57594c46)
+        return null;
+    let length = view.getUint16(6, true);
+    if (length < 20 || length > data.byteLength)
+        return null;
+    return {
+        type: view.getUint8(5),
+        length: length,
+        timestamp_ms: view.getUint32(8, true),
+        width: view.getUint32(12, true),
+        height: view.getUint32(16, true),
+    };
+}
+
 function handle_messages(
     webSocket: WebSocket,
     video: HTMLVideoElement,
     onConfigOk: Function,
     onConfigError: Function,
     onCapturableList: Function,
+    onSessionStart: Function,
 ) {
     let mediaSource: MediaSource = null;
     let sourceBuffer: SourceBuffer = null;
+    // sent by the server before each new video
+    let video_mime_type = 'video/mp4; codecs="avc1.4D403D"';
     let queue = [];
+    // object URL of the JPEG image currently shown if the JPEG fallback is used
+    let poster_url: string = null;
+    let poster_size = [0, 0];
     const MAX_BUFFER_LENGTH = 20;  // In seconds
+    // fragments are dropped until the next keyframe after the buffer has been cleared, this is only
+    // known if the server prefixes them with a frame header
+    let wait_for_keyframe = false;
     function upd_buf() {
         if (sourceBuffer == null)
             return;
@@ -761,10 +1311,14 @@ function handle_messages(
                     sourceBuffer.appendBuffer(queue.shift());
                 } catch (err) {
                     log(LogLevel.DEBUG, "Error appending to sourceBuffer:" + err);
-                    // Drop everything, and try to pick up the stream again
+                    // Drop everything, and try to pick up the stream again, this requires a
+                    // keyframe as the following frames depend on the dropped ones
                     if (sourceBuffer.updating)
                         sourceBuffer.abort();
                     sourceBuffer.remove(0, Infinity);
+                    queue = [];
+                    wait_for_keyframe = server_features.includes("frame_header");
+                    webSocket.send('"RequestKeyframe"');
                 }
             }
         }
@@ -774,11 +1328,17 @@ function handle_messages(
             let msg = JSON.parse(event.data);
             if (typeof msg == "string") {
                 if (msg == "NewVideo") {
+                    if (poster_url) {
+                        video.removeAttribute("poster");
+                        URL.revokeObjectURL(poster_url);
+                        poster_url = null;
+                        poster_size = [0, 0];
+                    }
                     mediaSource = new MediaSource();
                     sourceBuffer = null;
                     video.src = URL.createObjectURL(mediaSource);
                     mediaSource.addEventListener("sourceopen", (_) => {
-                        let mimeType = 'video/mp4; codecs="avc1.4D403D"';
+                        let mimeType = video_mime_type;
                         if (!MediaSource.isTypeSupported(mimeType))
                             mimeType = "video/mp4";
                         sourceBuffer = mediaSource.addSourceBuffer(mimeType);
@@ -789,22 +1349,160 @@ function handle_messages(
                     })
                 } else if (msg == "ConfigOk") {
                     onConfigOk();
+                } else if (msg == "SessionResumed") {
+                    onSessionStart(true);
+                } else if (msg == "SessionExpired") {
+                    sessionStorage.removeItem("session_token");
+                    onSessionStart(false);
+                } else if (msg == "SessionEnded") {
+                    // the server closes the connection, there is nothing to resume
+                    sessionStorage.removeItem("session_token");
+                    update_session_end(0);
+                    log(LogLevel.ERROR, "The session reached its maximum duration.");
                 }
             } else if (typeof msg == "object") {
                 if ("CapturableList" in msg)
                     onCapturableList(msg["CapturableList"]);
+                else if ("CapturableCount" in msg) {
+                    let [matched, total] = msg["CapturableCount"];
+                    (document.getElementById("capturable_count") as HTMLOutputElement).value =
+                        matched < total ? matched + " of " + total : "";
+                }
+                else if ("Cursor" in msg)
+                    update_cursor_overlay(msg["Cursor"]);
+                else if ("Bitrate" in msg)
+                    bitrate_out.value = msg["Bitrate"].toString();
+                else if ("Quality" in msg) {
+                    console.debug("Video settings in effect:", msg["Quality"]);
+                    let bitrate = msg["Quality"].bitrate;
+                    if (bitrate != null)
+                        bitrate_out.value = bitrate.toString();
+                }
+                else if ("Stats" in msg) {
+                    // frames the server could not send over the connection, so a lagging video
+                    // is not mistaken for a frozen page
+                    let stats = msg["Stats"];
+                    fps_sent_out.value = (Math.round(stats.fps * 10) / 10).toString();
+                    bitrate_sent_out.value = stats.bitrate.toString();
+                    dropped_out.value = Math.round(stats.drop_rate * 100).toString();
+                    capture_mode_out.value = stats.capture_mode;
+                    encoder_out.value = stats.encoder || "-";
+                    let kb = (bytes: number) => Math.round(bytes / 1000).toString();
+                    traffic_out.value = kb(stats.video_bytes_per_second) + " video, "
+                        + kb(stats.control_bytes_per_second) + " control, "
+                        + (Math.round(stats.total_bytes / 1e5) / 10) + " MB total";
+                }
+                else if ("InputLatency" in msg) {
+                    // the rest of the round trip is spent on the network
+                    let latency = msg["InputLatency"];
+                    let round_trip = performance.now() - latency.sent_at;
+                    input_latency_out.value = Math.round(round_trip) + " (server "
+                        + (Math.round(latency.processing * 10) / 10) + ")";
+                }
+                else if ("SessionRemaining" in msg)
+                    update_session_end(msg["SessionRemaining"]);
+                else if ("SessionEnding" in msg) {
+                    update_session_end(msg["SessionEnding"]);
+                    log(LogLevel.ERROR, "The session ends in " + msg["SessionEnding"] + " seconds.");
+                }
+                else if ("VideoSize" in msg)
+                    resolution_out.value = msg["VideoSize"][0] + "x" + msg["VideoSize"][1];
+                else if ("Clipboard" in msg)
+                    update_clipboard(msg["Clipboard"]);
+                else if ("Thumbnail" in msg)
+                    settings.onThumbnail(msg["Thumbnail"][0], msg["Thumbnail"][1]);
+                else if ("Session" in msg)
+                    // survives reloading the page but not closing the tab
+                    sessionStorage.setItem("session_token", msg["Session"]);
+                else if ("VideoMimeType" in msg) {
+                    video_mime_type = msg["VideoMimeType"];
+                    if (video_mime_type == "image/jpeg") {
+                        // the images are shown as poster of the video element, so the video
+                        // itself has to go
+                        mediaSource = null;
+                        sourceBuffer = null;
+                        queue = [];
+                        video.removeAttribute("src");
+                        video.load();
+                        codec_out.value = "JPEG";
+                    } else if (video_mime_type.includes("vp09"))
+                        codec_out.value = "VP9";
+                    else if (video_mime_type.includes("av01"))
+                        codec_out.value = "AV1";
+                    else
+                        codec_out.value = "H.264";
+                }
+                else if ("Hello" in msg) {
+                    console.debug("Server capabilities:", msg["Hello"]);
+                    server_features = msg["Hello"].features || [];
+                }
+                else if ("HelloRejected" in msg)
+                    onConfigError(msg["HelloRejected"]);
                 else if ("Error" in msg)
                     alert(msg["Error"]);
                 else if ("ConfigError" in msg) {
                     onConfigError(msg["ConfigError"]);
                 }
+                else if ("AudioStarted" in msg) {
+                    let [sample_rate, channels] = msg["AudioStarted"];
+                    close_audio();
+                    // the timestamp of the frame shown, JPEG images are shown as soon as they
+                    // arrive, so audio is played right away as well
+                    audio_player = new AudioPlayer(sample_rate, channels, () =>
+                        video_mime_type == "image/jpeg" || video.paused || video.readyState < 2
+                            ? null : video.currentTime);
+                }
+                else if ("AudioStopped" in msg)
+                    log(LogLevel.WARN, "Audio stopped, retrying: " + msg["AudioStopped"]);
+                else if ("InputDisabled" in msg)
+                    log(LogLevel.ERROR, "Input disabled, only viewing the screen: " + msg["InputDisabled"]);
             }
 
             return;
         }
 
-        // not a string -> got a video frame
-        queue.push(event.data);
+        // not a string -> got a video frame, a JPEG image or audio
+        let data: ArrayBuffer = event.data;
+        if (server_features.includes("frame_header")) {
+            let header = parse_frame_header(data);
+            if (header === null) {
+                log(LogLevel.WARN, "Dropping binary message without valid frame header.");
+                return;
+            }
+            data = data.slice(header.length);
+            if (header.type == FrameType.Audio) {
+                if (audio_player)
+                    audio_player.push(data, header.timestamp_ms);
+                return;
+            }
+            let size = header.width + "x" + header.height;
+            if (resolution_out.value != size)
+                resolution_out.value = size;
+            if (header.type == FrameType.Init || header.type == FrameType.Keyframe)
+                wait_for_keyframe = false;
+            else if (header.type == FrameType.Delta && wait_for_keyframe)
+                return;
+        }
+        if (video_mime_type == "image/jpeg") {
+            let url = URL.createObjectURL(new Blob([data], { type: "image/jpeg" }));
+            let img = new Image();
+            img.onload = () => {
+                video.poster = url;
+                if (poster_url)
+                    URL.revokeObjectURL(poster_url);
+                poster_url = url;
+                // the size of the video element depends on the size of the poster
+                if (poster_size[0] != img.width || poster_size[1] != img.height) {
+                    poster_size = [img.width, img.height];
+                    requestAnimationFrame(() => stretch_video());
+                }
+            };
+            img.onerror = () => URL.revokeObjectURL(url);
+            img.src = url;
+            frame_count += 1;
+            return;
+        }
+        queue.push(data);
         upd_buf();
         frame_count += 1;
 
@@ -826,7 +1524,7 @@ function handle_messages(
 
 function check_apis() {
     let apis = {
-        "MediaSource": "This browser doesn't support MSE required to playback video stream, try upgrading!",
+        "MediaSource": "This browser doesn't support MSE required to playback video stream, falling back to JPEG images, try upgrading!",
         "PointerEvent": "This browser doesn't support PointerEvents, input will not work, try upgrading!",
     };
     for (let n in apis) {
@@ -871,12 +1569,24 @@ function init(access_code: string, websocket_port: number) {
         toggle_fullscreen_btn.parentElement.removeChild(toggle_fullscreen_btn);
     }
 
+    let reconnecting = false;
     let handle_disconnect = (msg: string) => {
         document.body.onclick = video.onclick = (e) => {
             e.stopPropagation();
             if (window.confirm(msg + " Reload page?"))
                 location.reload();
         }
+        // the server keeps the session for a while, reload the page as soon as it is reachable
+        // again to resume it
+        if (reconnecting || !sessionStorage.getItem("session_token"))
+            return;
+        reconnecting = true;
+        log(LogLevel.WARN, msg + " Reconnecting...");
+        let reconnect = () => fetch(location.href, { method: "HEAD", cache: "no-store" }).then(
+            () => location.reload(),
+            () => setTimeout(reconnect, 1000)
+        );
+        setTimeout(reconnect, 1000);
     }
     webSocket.onerror = () => handle_disconnect("Lost connection.");
     webSocket.onclose = () => handle_disconnect("Connection closed.");
@@ -901,16 +1611,86 @@ function init(access_code: string, websocket_port: number) {
         }
     },
         (err) => alert(err),
-        (window_names) => settings.onCapturableList(window_names)
+        (capturables) => settings.onCapturableList(capturables),
+        (resumed: boolean) => start_session(resumed)
     );
     window.onunload = () => { webSocket.close(); }
-    webSocket.onopen = function(event) {
-        if (access_code)
-            webSocket.send(access_code);
+    // no frames are requested while the page is hidden, stopping capture saves battery on both ends
+    document.onvisibilitychange = () => {
+        if (authed && webSocket.readyState === webSocket.OPEN)
+            webSocket.send(document.hidden ? '"PauseVideo"' : '"ResumeVideo"');
+    }
+    let start_session = (resumed: boolean) => {
+        // a resumed session is authenticated already and the server sent the list of capturables
+        // on its own, restoring the selection from it configures the video
+        if (!resumed) {
+            if (access_code)
+                webSocket.send(access_code);
+            settings.send_hello();
+            webSocket.send('"GetCapturableList"');
+            settings.send_server_config();
+        }
         authed = true;
-        webSocket.send('"GetCapturableList"');
-        settings.send_server_config();
+        settings.send_pressure_curve();
+        settings.send_stylus_buttons();
+        if (settings.quality_preset_select.value)
+            settings.send_quality_preset();
     }
+    webSocket.onopen = function(event) {
+        let session_token = sessionStorage.getItem("session_token");
+        if (session_token)
+            webSocket.send(JSON.stringify({ "ResumeSession": session_token }));
+        else
+            start_session(false);
+    }
+}
+
+function update_clipboard(text: string) {
+    (document.getElementById("clipboard") as HTMLTextAreaElement).value = text;
+    // this requires a secure context and may be denied, the text can still be copied manually then
+    if (navigator.clipboard && navigator.clipboard.writeText)
+        navigator.clipboard.writeText(text).catch(
+            (err) => log(LogLevel.DEBUG, "Failed to write clipboard: " + err)
+        );
+}
+
+let last_cursor = null;
+
+function show_cursor_overlay(show: boolean) {
+    document.getElementById("cursor_overlay").classList.toggle("vanish", !show);
+    if (!show)
+        last_cursor = null;
+}
+
+function update_cursor_overlay(cursor) {
+    let overlay = document.getElementById("cursor_overlay") as HTMLCanvasElement;
+    // the image is only sent if it changed
+    if (cursor.image) {
+        overlay.width = cursor.width;
+        overlay.height = cursor.height;
+        if (cursor.width > 0 && cursor.height > 0)
+            overlay.getContext("2d").putImageData(
+                new ImageData(new Uint8ClampedArray(cursor.image), cursor.width, cursor.height), 0, 0
+            );
+    }
+    last_cursor = cursor;
+    position_cursor_overlay();
+}
+
+function position_cursor_overlay() {
+    if (last_cursor === null)
+        return;
+    let video = document.getElementById("video") as HTMLVideoElement;
+    let overlay = document.getElementById("cursor_overlay") as HTMLCanvasElement;
+    // the bounding rect already takes the transformation done by stretch_video into account
+    let rect = video.getBoundingClientRect();
+    // the cursor is scaled around its hotspot by the factor the server has been configured with
+    let scale_x = rect.width / last_cursor.frame_width * last_cursor.scale;
+    let scale_y = rect.height / last_cursor.frame_height * last_cursor.scale;
+    overlay.style.left = (rect.left + last_cursor.x * rect.width - last_cursor.hotspot_x * scale_x) + "px";
+    overlay.style.top = (rect.top + last_cursor.y * rect.height - last_cursor.hotspot_y * scale_y) + "px";
+    overlay.style.width = last_cursor.width * scale_x + "px";
+    overlay.style.height = last_cursor.height * scale_y + "px";
 }
 
 // object-fit: fill; <-- this is unfortunately not supported on iOS, so we use the following
@@ -923,4 +1703,5 @@ function stretch_video() {
         let scale = Math.min(document.body.clientWidth / video.clientWidth, document.body.clientHeight / video.clientHeight);
         video.style.transform = "scale(" + scale + ")";
     }
+    position_cursor_overlay();
 }
@@ -4,19 +4,22 @@ function die {
     # cleanup to ensure restarting this script doesn't fail because
     # of ports that are still in use
     kill $(jobs -p) > /dev/null 2>&1
-    rm -f index_tls.html
     exit $1
 }
 
-# generate certificate if it doesn't exist yet
-if [ ! -e weylus.pem ]
+# TLS_CERT and TLS_KEY can be used to specify the paths to an existing certificate and its private
+# key in PEM format, for example one issued by your own CA. If unset a self-signed certificate is
+# generated.
+if [ -z "$TLS_CERT" ] || [ -z "$TLS_KEY" ]
 then
-    openssl req -batch -newkey rsa:4096 -sha256 -keyout weylus.key -nodes -x509 -days 365 \
-        -subj="/CN=Weylus" -out weylus.crt
-
-    # combine into a pem file as this is everything hitch needs
-    cat weylus.key weylus.crt > weylus.pem
-    rm weylus.key weylus.crt
+    TLS_CERT=weylus.crt
+    TLS_KEY=weylus.key
+    # generate certificate if it doesn't exist yet
+    if [ ! -e weylus.crt ] || [ ! -e weylus.key ]
+    then
+        openssl req -batch -newkey rsa:4096 -sha256 -keyout weylus.key -nodes -x509 -days 365 \
+            -subj="/CN=Weylus" -out weylus.crt || die 1
+    fi
 fi
 
 # WEYLUS can be used to determine which version of Weylus to run
@@ -49,28 +52,13 @@ fi
 # cleanup on CTRL+C
 trap die SIGINT
 
-# The TLS proxy will be set up as follows:
-# Proxy all incoming traffic from ports 1701 and 9001 to 1702 and
-# 9002 on which the actual instance of Weylus is running.
-#
-# This means the websocket port that Weylus encodes into the
-# index.html is the unencrypted port 9002 which is changed to the
-# encrypted version on port 9001 by specifiying a custom index html.
-$WEYLUS --print-index-html | sed 's/{{websocket_port}}/9001/' > index_tls.html
-
-# start Weylus listening only on the local interface
-$WEYLUS --custom-index-html index_tls.html \
-    --bind-address 127.0.0.1 \
-    --web-port 1702 \
-    --websocket-port 9002 \
+# Weylus serves the web page via HTTPS on port 1701 and the websocket via WSS on port 9001 itself.
+$WEYLUS --tls-cert "$TLS_CERT" \
+    --tls-key "$TLS_KEY" \
+    --bind-address 0.0.0.0 \
+    --web-port 1701 \
+    --websocket-port 9001 \
     --access-code "$ACCESS_CODE" \
     --no-gui &
 
-# start the proxy
-hitch --frontend=[0.0.0.0]:1701 --backend=[127.0.0.1]:1702 \
-    --daemon=off --tls-protos="TLSv1.2 TLSv1.3" weylus.pem &
-
-hitch --frontend=[0.0.0.0]:9001 --backend=[127.0.0.1]:9002 \
-    --daemon=off --tls-protos="TLSv1.2 TLSv1.3" weylus.pem &
-
 wait